@@ -0,0 +1,188 @@
+use lsp_types::Url;
+use serde_json::Value;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::{AgentConfig, ChatMessage, Config},
+    custom_requests::agent::ProposedEdit,
+    mcp,
+    memory_backends::{ContextAndCodePrompt, Prompt},
+    tools::{self, ToolContext, ToolDefinition, ToolRequest, ToolTurn, BUILTIN_TOOLS},
+    transformer_backends::TransformerBackend,
+    usage::TokenUsage,
+    workspace_edit::FileChange,
+};
+
+const SYSTEM_MESSAGE: &str = "You are a coding agent working in the user's workspace. Use the \
+tools to read files, search the code and list the symbols of files until you understand what \
+needs to change. Propose every change with apply_edit, the user reviews the edits before they \
+are applied. When you are done, answer with a short summary of what you found or changed.";
+
+// When the model has to answer instead of calling more tools
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    // How many responses may call tools
+    pub max_steps: usize,
+    // How many tokens the responses may use in total
+    pub max_tokens: Option<u64>,
+}
+
+impl Budget {
+    fn is_spent(&self, steps: usize, usage: Option<TokenUsage>) -> bool {
+        steps >= self.max_steps
+            || self.max_tokens.is_some_and(|max_tokens| {
+                usage.is_some_and(|usage| {
+                    usage.prompt_tokens + usage.completion_tokens >= max_tokens
+                })
+            })
+    }
+}
+
+pub struct AgentOutcome {
+    pub answer: String,
+    pub edits: Vec<ProposedEdit>,
+    // The files the edits change
+    pub changes: Vec<FileChange>,
+    // How many times the model was called
+    pub steps: usize,
+    pub usage: Option<TokenUsage>,
+}
+
+fn add_usage(total: Option<TokenUsage>, step: Option<TokenUsage>) -> Option<TokenUsage> {
+    match (total, step) {
+        (Some(total), Some(step)) => Some(TokenUsage::new(
+            total.prompt_tokens + step.prompt_tokens,
+            total.completion_tokens + step.completion_tokens,
+        )),
+        (total, step) => total.or(step),
+    }
+}
+
+// Calls the model, runs the tools it calls and sends the results back until it answers or the
+// budget is spent
+pub async fn run(
+    transformer_backend: &(dyn TransformerBackend + Send + Sync),
+    prompt: &Prompt,
+    params: &Value,
+    tools: &[ToolDefinition],
+    budget: Budget,
+    context: &mut ToolContext<'_>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<AgentOutcome> {
+    let mut turns: Vec<ToolTurn> = vec![];
+    let mut usage: Option<TokenUsage> = None;
+    loop {
+        let request = ToolRequest {
+            tools,
+            turns: &turns,
+            must_answer: budget.is_spent(turns.len(), usage),
+        };
+        let response = transformer_backend
+            .do_generate_with_tools(prompt, params.clone(), &request, cancel)
+            .await?;
+        usage = add_usage(usage, response.usage);
+        if response.calls.is_empty() || request.must_answer {
+            return Ok(AgentOutcome {
+                answer: response.generated_text,
+                edits: std::mem::take(&mut context.edits),
+                changes: context.take_changes()?,
+                steps: turns.len() + 1,
+                usage,
+            });
+        }
+        let mut results = vec![];
+        for call in &response.calls {
+            if cancel.is_cancelled() {
+                anyhow::bail!("the generation was cancelled");
+            }
+            results.push(context.run(call).await?);
+        }
+        turns.push(ToolTurn {
+            text: response.generated_text,
+            calls: response.calls,
+            results,
+        });
+    }
+}
+
+// The tools of the agent config, all of the built-in ones and the ones of the MCP servers when
+// none are set
+pub async fn get_tools(
+    agent_config: &AgentConfig,
+    config: &Config,
+) -> anyhow::Result<Vec<ToolDefinition>> {
+    if !agent_config.tools.is_empty() {
+        return tools::get_definitions(&agent_config.tools, config).await;
+    }
+    let mut definitions: Vec<ToolDefinition> = BUILTIN_TOOLS
+        .iter()
+        .map(|name| tools::get_definition(name))
+        .collect::<anyhow::Result<_>>()?;
+    definitions.extend(mcp::get_all_definitions(config).await);
+    Ok(definitions)
+}
+
+fn format_workspace_folders(config: &Config) -> String {
+    let format_folder = |folder: &String| match Url::parse(folder).map(|url| url.to_file_path()) {
+        Ok(Ok(path)) => format!("- {}", path.display()),
+        _ => format!("- {folder}"),
+    };
+    let folders: Vec<String> = config
+        .get_workspace_folders()
+        .iter()
+        .map(format_folder)
+        .collect();
+    format!("Workspace folders:\n{}", folders.join("\n"))
+}
+
+// The prompt with the goal as the code and the workspace folders and the document as the
+// context, and the parameters with the default messages when none are configured
+pub fn build_request(
+    agent_config: &AgentConfig,
+    config: &Config,
+    goal: &str,
+    document: Option<(&str, &str)>,
+) -> anyhow::Result<(Prompt, Value)> {
+    let mut context = format_workspace_folders(config);
+    if let Some((uri, text)) = document {
+        context.push_str(&format!("\n\nThe open document {uri}:\n{text}"));
+    }
+    let mut params = serde_json::to_value(&agent_config.parameters)?;
+    if params.get("messages").is_none() {
+        let mut messages = vec![];
+        // Anthropic takes the system prompt in `system`
+        if params.get("system").is_none() {
+            messages.push(ChatMessage::new(
+                "system".to_string(),
+                SYSTEM_MESSAGE.to_string(),
+            ));
+        }
+        messages.push(ChatMessage::new(
+            "user".to_string(),
+            "{CONTEXT}\n\nGoal: {CODE}".to_string(),
+        ));
+        params["messages"] = serde_json::to_value(messages)?;
+    }
+    let prompt = Prompt::ContextAndCode(ContextAndCodePrompt::new(context, goal.to_owned()));
+    Ok((prompt, params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agent_budget_is_spent() {
+        let budget = Budget {
+            max_steps: 3,
+            max_tokens: Some(100),
+        };
+        assert!(!budget.is_spent(2, Some(TokenUsage::new(50, 10))));
+        assert!(budget.is_spent(3, None));
+        assert!(budget.is_spent(1, Some(TokenUsage::new(90, 10))));
+        assert_eq!(
+            add_usage(Some(TokenUsage::new(1, 2)), Some(TokenUsage::new(3, 4))),
+            Some(TokenUsage::new(4, 6))
+        );
+    }
+}
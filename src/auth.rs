@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tokio::process::Command;
+
+use crate::config::KeyringEntry;
+
+// Commands often print short lived tokens, e.g. `gcloud auth print-access-token`, so they run
+// again once the token they printed is this old
+const COMMAND_TOKEN_TTL: Duration = Duration::from_secs(10 * 60);
+
+// A failed read is returned to the requests that follow it for this long instead of being retried
+// on each of them
+const FAILURE_TTL: Duration = Duration::from_secs(30);
+
+// Keyring reads and commands that take longer than this are given up on, e.g. when they wait for
+// a passphrase nobody can type
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A token, or the error reading it, and when it was read
+type Slot = Arc<tokio::sync::Mutex<Option<(Result<String, String>, Instant)>>>;
+
+// Keyring entries and commands are only read when a request needs them, as reading them may ask
+// for a passphrase
+static TOKENS: Lazy<Mutex<HashMap<String, Slot>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Requests for the same token wait on its slot while it is read, the others go on
+async fn get_cached(
+    key: String,
+    ttl: Option<Duration>,
+    read: impl Future<Output = anyhow::Result<String>>,
+) -> anyhow::Result<String> {
+    let slot = TOKENS.lock().entry(key).or_default().clone();
+    let mut cached = slot.lock().await;
+    if let Some((token, read_at)) = cached.as_ref() {
+        let ttl = if token.is_ok() {
+            ttl
+        } else {
+            Some(FAILURE_TTL)
+        };
+        if ttl.map_or(true, |ttl| read_at.elapsed() < ttl) {
+            return token.clone().map_err(anyhow::Error::msg);
+        }
+    }
+    let token = match tokio::time::timeout(READ_TIMEOUT, read).await {
+        Ok(token) => token.map_err(|e| format!("{e:#}")),
+        Err(_) => Err(format!("timed out after {}s", READ_TIMEOUT.as_secs())),
+    };
+    *cached = Some((token.clone(), Instant::now()));
+    token.map_err(anyhow::Error::msg)
+}
+
+async fn read_keyring(entry: &KeyringEntry) -> anyhow::Result<String> {
+    let (service, user) = (entry.service.clone(), entry.user.clone());
+    // The keyring is read through blocking calls
+    tokio::task::spawn_blocking(move || {
+        keyring::Entry::new(&service, &user)?
+            .get_password()
+            .with_context(|| {
+                format!("error reading the keyring entry of service `{service}` and user `{user}`")
+            })
+    })
+    .await?
+}
+
+async fn run_command(command: &str) -> anyhow::Result<String> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    // The command is killed if it times out
+    let output = Command::new(shell)
+        .args([flag, command])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("error running `auth_token_command`: {command}"))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "`auth_token_command` {command} failed with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    let stdout = String::from_utf8(output.stdout)?;
+    let token = stdout.lines().next().unwrap_or_default().trim();
+    anyhow::ensure!(
+        !token.is_empty(),
+        "`auth_token_command` {command} printed no token"
+    );
+    Ok(token.to_owned())
+}
+
+// The token from the first source that is set, None when none are
+pub async fn get_auth_token(
+    env_var_name: Option<&str>,
+    token: Option<&str>,
+    keyring: Option<&KeyringEntry>,
+    command: Option<&str>,
+) -> anyhow::Result<Option<String>> {
+    if let Some(env_var_name) = env_var_name {
+        Ok(Some(std::env::var(env_var_name).with_context(|| {
+            format!("error reading the `{env_var_name}` env var")
+        })?))
+    } else if let Some(token) = token {
+        Ok(Some(token.to_owned()))
+    } else if let Some(entry) = keyring {
+        get_cached(
+            format!("keyring:{}:{}", entry.service, entry.user),
+            None,
+            read_keyring(entry),
+        )
+        .await
+        .map(Some)
+    } else if let Some(command) = command {
+        get_cached(
+            format!("command:{command}"),
+            Some(COMMAND_TOKEN_TTL),
+            run_command(command),
+        )
+        .await
+        .map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn auth_token_command() -> anyhow::Result<()> {
+        assert_eq!(
+            get_auth_token(None, None, None, Some("echo sk-test"))
+                .await?
+                .as_deref(),
+            Some("sk-test")
+        );
+        assert_eq!(
+            get_auth_token(None, Some("sk-config"), None, Some("echo sk-test"))
+                .await?
+                .as_deref(),
+            Some("sk-config")
+        );
+        assert!(get_auth_token(None, None, None, Some("exit 3"))
+            .await
+            .is_err());
+        assert!(get_auth_token(None, None, None, None).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auth_token_expires() -> anyhow::Result<()> {
+        let read = |token: &'static str| async move { Ok(token.to_owned()) };
+        let ttl = Some(Duration::ZERO);
+        assert_eq!(
+            get_cached("test:ttl".to_string(), ttl, read("a")).await?,
+            "a"
+        );
+        assert_eq!(
+            get_cached("test:ttl".to_string(), ttl, read("b")).await?,
+            "b"
+        );
+        assert_eq!(
+            get_cached("test:ttl".to_string(), None, read("c")).await?,
+            "b"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auth_token_failures_are_cached() -> anyhow::Result<()> {
+        let key = || "test:failure".to_string();
+        let failed = get_cached(key(), None, async { anyhow::bail!("no token") }).await;
+        assert!(failed.is_err());
+        // Not read again until the failure expires
+        let token = get_cached(key(), None, async { Ok("a".to_string()) }).await;
+        assert_eq!(token.unwrap_err().to_string(), "no token");
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use lsp_server::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::error;
+
+use crate::{
+    custom_requests::usage::UsageResult,
+    headless::{self, Client},
+    workspace_config,
+};
+
+const USAGE: &str =
+    "usage: lsp-ai bench --corpus <cases.jsonl> --config <file> [--models <key,key>] [--json]";
+
+pub struct Args {
+    corpus: PathBuf,
+    config: PathBuf,
+    // All configured models when empty
+    models: Vec<String>,
+    json: bool,
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut corpus = None;
+    let mut config = None;
+    let mut models = vec![];
+    let mut json = false;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("{arg} needs a value\n{USAGE}"))
+        };
+        match arg.as_str() {
+            "--corpus" => corpus = Some(PathBuf::from(value()?)),
+            "--config" => config = Some(PathBuf::from(value()?)),
+            "--models" => models = value()?.split(',').map(str::to_owned).collect(),
+            "--json" => json = true,
+            _ => anyhow::bail!("unknown argument {arg}\n{USAGE}"),
+        }
+    }
+    Ok(Args {
+        corpus: corpus.with_context(|| format!("missing --corpus\n{USAGE}"))?,
+        config: config.with_context(|| format!("missing --config\n{USAGE}"))?,
+        models,
+        json,
+    })
+}
+
+// One line of the corpus. Files are relative to the corpus.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Case {
+    file: PathBuf,
+    // `line:column`, 1-based
+    position: String,
+    expected: String,
+    language: Option<String>,
+}
+
+fn load_corpus(path: &Path) -> anyhow::Result<Vec<Case>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("error reading {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let mut case: Case = serde_json::from_str(line)
+                .with_context(|| format!("invalid case on line {}", i + 1))?;
+            case.file = dir.join(&case.file);
+            Ok(case)
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Report {
+    model: String,
+    cases: usize,
+    errors: usize,
+    p50_ms: u64,
+    p90_ms: u64,
+    p99_ms: u64,
+    // Of all cases, failed ones count as misses
+    exact_match: f64,
+    edit_similarity: f64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost: f64,
+}
+
+// Nearest rank over sorted durations
+fn percentile(sorted: &[Duration], percentile: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (percentile * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1].as_millis() as u64
+}
+
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + usize::from(ca != cb);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// 1 minus the edit distance normalized by the longer text, whitespace at the ends is ignored
+fn edit_similarity(completion: &str, expected: &str) -> f64 {
+    let a: Vec<char> = completion.trim().chars().collect();
+    let b: Vec<char> = expected.trim().chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.;
+    }
+    1. - levenshtein(&a, &b) as f64 / longest as f64
+}
+
+// Only the model being benchmarked answers, fresh for every request
+fn get_model_options(options: &Value, model: &str) -> anyhow::Result<Value> {
+    let mut options = options.clone();
+    let object = options
+        .as_object_mut()
+        .context("the configuration must be an object")?;
+    object.remove("routes");
+    object.remove("fallback");
+    let completion = object
+        .entry("completion")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .context("completion must be an object")?;
+    completion.insert("model".to_string(), json!(model));
+    completion.insert("debounce_ms".to_string(), json!(0));
+    completion.insert("cache".to_string(), json!({ "max_entries": 0 }));
+    Ok(options)
+}
+
+fn bench_model(
+    serve: fn(Connection) -> anyhow::Result<()>,
+    options: &Value,
+    model: &str,
+    cases: &[Case],
+) -> anyhow::Result<Report> {
+    let mut client = Client::start(
+        serve,
+        &headless::get_root_uri()?,
+        get_model_options(options, model)?,
+    )?;
+    let mut report = Report {
+        model: model.to_owned(),
+        cases: cases.len(),
+        ..Default::default()
+    };
+    let mut durations = vec![];
+    let (mut exact_matches, mut similarity) = (0, 0.);
+    for case in cases {
+        let result = (|| -> anyhow::Result<(Duration, String)> {
+            let uri = client.open(&case.file, case.language.as_deref())?;
+            let (line, column) = headless::parse_position(&case.position)?;
+            let start = Instant::now();
+            let result = client.request(
+                "textDocument/completion",
+                headless::text_document_position(&uri, line, column),
+            )?;
+            let duration = start.elapsed();
+            let completion = headless::get_completion_text(result)?
+                .into_iter()
+                .next()
+                .unwrap_or_default();
+            Ok((duration, completion))
+        })();
+        match result {
+            Ok((duration, completion)) => {
+                durations.push(duration);
+                if completion.trim() == case.expected.trim() {
+                    exact_matches += 1;
+                }
+                similarity += edit_similarity(&completion, &case.expected);
+            }
+            Err(e) => {
+                error!("{model} failed on {}: {e:?}", case.file.display());
+                report.errors += 1;
+            }
+        }
+    }
+    durations.sort();
+    report.p50_ms = percentile(&durations, 50);
+    report.p90_ms = percentile(&durations, 90);
+    report.p99_ms = percentile(&durations, 99);
+    if !cases.is_empty() {
+        report.exact_match = exact_matches as f64 / cases.len() as f64;
+        report.edit_similarity = similarity / cases.len() as f64;
+    }
+
+    let usage: UsageResult = serde_json::from_value(client.request("lsp-ai/usage", json!({}))?)?;
+    if let Some(totals) = usage.session.models.get(model) {
+        report.prompt_tokens = totals.prompt_tokens;
+        report.completion_tokens = totals.completion_tokens;
+        report.cost = totals.cost;
+    }
+    client.shutdown()?;
+    Ok(report)
+}
+
+fn print_reports(reports: &[Report]) {
+    println!(
+        "{:<24} {:>6} {:>6} {:>8} {:>8} {:>8} {:>7} {:>7} {:>10} {:>10} {:>8}",
+        "model",
+        "cases",
+        "errors",
+        "p50 ms",
+        "p90 ms",
+        "p99 ms",
+        "exact",
+        "sim",
+        "prompt tok",
+        "compl tok",
+        "cost"
+    );
+    for r in reports {
+        println!(
+            "{:<24} {:>6} {:>6} {:>8} {:>8} {:>8} {:>6.1}% {:>7.3} {:>10} {:>10} {:>8.4}",
+            r.model,
+            r.cases,
+            r.errors,
+            r.p50_ms,
+            r.p90_ms,
+            r.p99_ms,
+            r.exact_match * 100.,
+            r.edit_similarity,
+            r.prompt_tokens,
+            r.completion_tokens,
+            r.cost
+        );
+    }
+}
+
+// Replays the corpus against each model with the completion pipeline an editor would use
+pub fn run(args: Args, serve: fn(Connection) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let cases = load_corpus(&args.corpus)?;
+    let options = workspace_config::load(&args.config)?;
+    let models = if args.models.is_empty() {
+        let mut models: Vec<String> = options
+            .get("models")
+            .and_then(Value::as_object)
+            .context("the configuration has no models")?
+            .keys()
+            .cloned()
+            .collect();
+        models.sort();
+        models
+    } else {
+        args.models
+    };
+    let reports = models
+        .iter()
+        .map(|model| bench_model(serve, &options, model, &cases))
+        .collect::<anyhow::Result<Vec<Report>>>()?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    } else {
+        print_reports(&reports);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bench_scores() {
+        let durations: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&durations, 50), 5);
+        assert_eq!(percentile(&durations, 90), 9);
+        assert_eq!(percentile(&durations, 99), 10);
+        assert_eq!(percentile(&[], 50), 0);
+
+        assert_eq!(edit_similarity("  return x\n", "return x"), 1.);
+        assert_eq!(edit_similarity("return y", "return x"), 1. - 1. / 8.);
+        assert_eq!(edit_similarity("", "abcd"), 0.);
+
+        let options = json!({
+            "models": {"model1": {}},
+            "completion": {"model": "model2", "parameters": {"max_tokens": 8}},
+            "routes": [{"model": "model2"}]
+        });
+        let options = get_model_options(&options, "model1").unwrap();
+        assert_eq!(options["completion"]["model"], "model1");
+        assert_eq!(options["completion"]["parameters"]["max_tokens"], 8);
+        assert!(options.get("routes").is_none());
+    }
+}
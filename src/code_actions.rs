@@ -0,0 +1,535 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use anyhow::Context;
+use lsp_server::{Connection, Message, Notification, Request, RequestId};
+use lsp_types::{
+    ApplyWorkspaceEditParams, CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams,
+    Command, CreateFile, CreateFileOptions, Diagnostic, DocumentChangeOperation, DocumentChanges,
+    MessageType, OneOf, OptionalVersionedTextDocumentIdentifier, Position, Range, ResourceOp,
+    ShowMessageParams, TextDocumentEdit, TextDocumentIdentifier, TextEdit, Url, WorkspaceEdit,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::{Action, ActionOutput, Config, TestConvention};
+use crate::udiff::diff_to_edits;
+
+// The command the code actions run with workspace/executeCommand
+pub const RUN_ACTION_COMMAND: &str = "lsp-ai.runAction";
+
+static NEXT_REQUEST_ID: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunActionArguments {
+    // The title of the action in the `actions` config
+    pub title: String,
+    pub text_document: TextDocumentIdentifier,
+    pub range: Range,
+    // The diagnostics to fix
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+fn get_kind(action: &Action) -> Option<CodeActionKind> {
+    match action.output {
+        ActionOutput::Replace | ActionOutput::Diff => Some(CodeActionKind::REFACTOR_REWRITE),
+        ActionOutput::InsertBefore => Some(CodeActionKind::REFACTOR),
+        ActionOutput::Message => None,
+        ActionOutput::Tests => Some(CodeActionKind::SOURCE),
+        ActionOutput::Fix => Some(CodeActionKind::QUICKFIX),
+    }
+}
+
+// The diagnostics the client sent with the request and forwarded earlier that touch the range
+fn get_diagnostics(params: &CodeActionParams, forwarded: &[Diagnostic]) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+    for diagnostic in params.context.diagnostics.iter().chain(forwarded) {
+        let overlaps = diagnostic.range.start <= params.range.end
+            && diagnostic.range.end >= params.range.start;
+        let duplicate = diagnostics
+            .iter()
+            .any(|d| d.range == diagnostic.range && d.message == diagnostic.message);
+        if overlaps && !duplicate {
+            diagnostics.push(diagnostic.clone());
+        }
+    }
+    diagnostics
+}
+
+// The whole lines the diagnostics are on
+fn get_diagnostics_range(diagnostics: &[Diagnostic]) -> Option<Range> {
+    let start = diagnostics.iter().map(|d| d.range.start.line).min()?;
+    let end = diagnostics.iter().map(|d| d.range.end.line).max()?;
+    Some(Range::new(
+        Position::new(start, 0),
+        Position::new(end + 1, 0),
+    ))
+}
+
+fn format_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|d| format!("line {}: {}", d.range.start.line + 1, d.message))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Replaces `{DIAGNOSTICS}` in the messages of the action's parameters
+pub fn insert_diagnostics(params: &mut Value, diagnostics: &[Diagnostic]) {
+    let diagnostics = format_diagnostics(diagnostics);
+    let Some(messages) = params.get_mut("messages").and_then(Value::as_array_mut) else {
+        return;
+    };
+    for message in messages {
+        if let Some(Value::String(content)) = message.get_mut("content") {
+            *content = content.replace("{DIAGNOSTICS}", &diagnostics);
+        }
+    }
+}
+
+// The configured actions for the range. Fixes are offered when the range has diagnostics and the
+// other actions when something is selected.
+pub fn get_code_actions(
+    config: &Config,
+    params: &CodeActionParams,
+    forwarded_diagnostics: &[Diagnostic],
+) -> Vec<CodeActionOrCommand> {
+    let diagnostics = get_diagnostics(params, forwarded_diagnostics);
+    config
+        .config
+        .actions
+        .iter()
+        .filter(|action| match &params.context.only {
+            // Clients ask for specific kinds when the user runs e.g. "Refactor..."
+            Some(only) => get_kind(action)
+                .is_some_and(|kind| only.iter().any(|o| kind.as_str().starts_with(o.as_str()))),
+            None => true,
+        })
+        .filter_map(|action| {
+            let (range, diagnostics) = if action.output == ActionOutput::Fix {
+                (get_diagnostics_range(&diagnostics)?, diagnostics.clone())
+            } else if params.range.start != params.range.end {
+                (params.range, vec![])
+            } else {
+                return None;
+            };
+            let arguments = RunActionArguments {
+                title: action.title.clone(),
+                text_document: params.text_document.clone(),
+                range,
+                diagnostics: diagnostics.clone(),
+            };
+            Some(CodeActionOrCommand::CodeAction(CodeAction {
+                title: action.title.clone(),
+                kind: get_kind(action),
+                diagnostics: (!diagnostics.is_empty()).then_some(diagnostics),
+                command: Some(Command::new(
+                    action.title.clone(),
+                    RUN_ACTION_COMMAND.to_string(),
+                    Some(vec![serde_json::to_value(arguments).unwrap()]),
+                )),
+                ..Default::default()
+            }))
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ActionResult {
+    Edit(WorkspaceEdit),
+    Message(String),
+}
+
+// The position after the last character of `text`
+pub fn get_end_position(text: &str) -> Position {
+    let lines: Vec<&str> = text.split('\n').collect();
+    Position::new(
+        lines.len() as u32 - 1,
+        lines.last().map_or(0, |line| line.chars().count()) as u32,
+    )
+}
+
+// The test file for `uri` and its text if it already exists
+fn get_tests_target(
+    uri: &Url,
+    document: &str,
+    convention: Option<&TestConvention>,
+) -> anyhow::Result<(Url, Option<String>)> {
+    let Some(path) = convention.and_then(|convention| convention.path.as_ref()) else {
+        return Ok((uri.clone(), Some(document.to_owned())));
+    };
+    let file_name = uri
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .context("the document has no file name")?;
+    let (name, ext) = file_name.rsplit_once('.').unwrap_or((file_name, ""));
+    let target = uri.join(&path.replace("{name}", name).replace("{ext}", ext))?;
+    if target == *uri {
+        return Ok((target, Some(document.to_owned())));
+    }
+    let existing = target
+        .to_file_path()
+        .ok()
+        .and_then(|path| std::fs::read_to_string(path).ok());
+    Ok((target, existing))
+}
+
+// Adds the tests to the end of the existing test file or module, or creates the test file
+fn build_tests_edit(
+    target: Url,
+    existing: Option<&str>,
+    module: Option<&str>,
+    generated_text: String,
+) -> WorkspaceEdit {
+    let mut new_text = generated_text;
+    if !new_text.ends_with('\n') {
+        new_text.push('\n');
+    }
+    let Some(existing) = existing else {
+        let create = ResourceOp::Create(CreateFile {
+            uri: target.clone(),
+            options: Some(CreateFileOptions {
+                overwrite: Some(false),
+                ignore_if_exists: Some(true),
+            }),
+            annotation_id: None,
+        });
+        let edit = TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier {
+                uri: target,
+                version: None,
+            },
+            edits: vec![OneOf::Left(TextEdit::new(
+                Range::new(Position::new(0, 0), Position::new(0, 0)),
+                new_text,
+            ))],
+        };
+        return WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Op(create),
+                DocumentChangeOperation::Edit(edit),
+            ])),
+            ..Default::default()
+        };
+    };
+
+    // The closing brace of the module is the last line that is only a closing brace
+    let lines: Vec<&str> = existing.split('\n').collect();
+    let module_end = module.and_then(|module| {
+        let start = lines.iter().position(|line| line.trim() == module.trim())?;
+        let end = lines.iter().rposition(|line| line.trim() == "}")?;
+        (end > start).then_some(end)
+    });
+    let (position, new_text) = match module_end {
+        Some(end) => (Position::new(end as u32, 0), format!("\n{new_text}")),
+        None if existing.is_empty() || existing.ends_with('\n') => {
+            (get_end_position(existing), format!("\n{new_text}"))
+        }
+        None => (get_end_position(existing), format!("\n\n{new_text}")),
+    };
+    WorkspaceEdit::new(HashMap::from([(
+        target,
+        vec![TextEdit::new(Range::new(position, position), new_text)],
+    )]))
+}
+
+pub fn build_action_result(
+    output: ActionOutput,
+    uri: Url,
+    range: Range,
+    document: &str,
+    convention: Option<&TestConvention>,
+    generated_text: String,
+) -> anyhow::Result<ActionResult> {
+    let edit = match output {
+        ActionOutput::Replace => TextEdit::new(range, generated_text),
+        // The range is whole lines so the replacement has to end the last one
+        ActionOutput::Fix => {
+            let mut new_text = generated_text;
+            if !new_text.ends_with('\n') {
+                new_text.push('\n');
+            }
+            TextEdit::new(range, new_text)
+        }
+        ActionOutput::InsertBefore => {
+            let mut new_text = generated_text;
+            if !new_text.ends_with('\n') {
+                new_text.push('\n');
+            }
+            let start = Position::new(range.start.line, 0);
+            TextEdit::new(Range::new(start, start), new_text)
+        }
+        ActionOutput::Message => return Ok(ActionResult::Message(generated_text)),
+        ActionOutput::Diff => {
+            let edits = diff_to_edits(document, &generated_text)?;
+            return Ok(ActionResult::Edit(WorkspaceEdit::new(HashMap::from([(
+                uri, edits,
+            )]))));
+        }
+        ActionOutput::Tests => {
+            let (target, existing) = get_tests_target(&uri, document, convention)?;
+            let module = convention.and_then(|convention| convention.module.as_deref());
+            return Ok(ActionResult::Edit(build_tests_edit(
+                target,
+                existing.as_deref(),
+                module,
+                generated_text,
+            )));
+        }
+    };
+    Ok(ActionResult::Edit(WorkspaceEdit::new(HashMap::from([(
+        uri,
+        vec![edit],
+    )]))))
+}
+
+// Applies the edit with workspace/applyEdit or shows the message with window/showMessage
+pub fn send_action_result(
+    connection: &Connection,
+    title: &str,
+    result: ActionResult,
+) -> anyhow::Result<()> {
+    let message = match result {
+        ActionResult::Edit(edit) => {
+            let id = format!(
+                "lsp-ai/applyEdit/{}",
+                NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+            );
+            Message::Request(Request::new(
+                RequestId::from(id),
+                "workspace/applyEdit".to_string(),
+                ApplyWorkspaceEditParams {
+                    label: Some(title.to_string()),
+                    edit,
+                },
+            ))
+        }
+        ActionResult::Message(message) => Message::Notification(Notification::new(
+            "window/showMessage".to_string(),
+            ShowMessageParams {
+                typ: MessageType::INFO,
+                message,
+            },
+        )),
+    };
+    connection.sender.send(message)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_get_code_actions() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {},
+                "actions": [
+                    {
+                        "title": "Explain this code",
+                        "model": "model1",
+                        "output": "message"
+                    },
+                    {
+                        "title": "Refactor for readability",
+                        "model": "model1"
+                    }
+                ]
+            }
+        });
+        let config = Config::new(args).unwrap();
+        let uri = Url::parse("file:///test.py").unwrap();
+        let mut params: CodeActionParams = serde_json::from_value(json!({
+            "textDocument": { "uri": uri },
+            "range": {
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": 2, "character": 0 }
+            },
+            "context": { "diagnostics": [] }
+        }))
+        .unwrap();
+        let actions = get_code_actions(&config, &params, &[]);
+        assert_eq!(actions.len(), 2);
+        let CodeActionOrCommand::CodeAction(action) = &actions[1] else {
+            panic!("expected a code action");
+        };
+        let command = action.command.as_ref().unwrap();
+        assert_eq!(command.command, RUN_ACTION_COMMAND);
+        let arguments: RunActionArguments =
+            serde_json::from_value(command.arguments.as_ref().unwrap()[0].clone()).unwrap();
+        assert_eq!(arguments.title, "Refactor for readability");
+        assert_eq!(arguments.range, params.range);
+
+        params.context.only = Some(vec![CodeActionKind::REFACTOR]);
+        assert_eq!(get_code_actions(&config, &params, &[]).len(), 1);
+
+        params.range.end = params.range.start;
+        assert!(get_code_actions(&config, &params, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_get_fix_code_actions() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {},
+                "actions": [
+                    {
+                        "title": "Fix with AI",
+                        "model": "model1",
+                        "output": "fix"
+                    }
+                ]
+            }
+        });
+        let config = Config::new(args).unwrap();
+        let params: CodeActionParams = serde_json::from_value(json!({
+            "textDocument": { "uri": "file:///test.py" },
+            "range": {
+                "start": { "line": 3, "character": 2 },
+                "end": { "line": 3, "character": 2 }
+            },
+            "context": { "diagnostics": [] }
+        }))
+        .unwrap();
+        assert!(get_code_actions(&config, &params, &[]).is_empty());
+
+        let diagnostic = |start: u32, end: u32, message: &str| {
+            Diagnostic::new_simple(
+                Range::new(Position::new(start, 0), Position::new(end, 4)),
+                message.to_string(),
+            )
+        };
+        let forwarded = vec![
+            diagnostic(2, 3, "undefined name `x`"),
+            diagnostic(3, 4, "unused variable `y`"),
+            diagnostic(8, 8, "missing return type"),
+        ];
+        let actions = get_code_actions(&config, &params, &forwarded);
+        let CodeActionOrCommand::CodeAction(action) = &actions[0] else {
+            panic!("expected a code action");
+        };
+        assert_eq!(action.kind, Some(CodeActionKind::QUICKFIX));
+        let arguments: RunActionArguments = serde_json::from_value(
+            action.command.as_ref().unwrap().arguments.as_ref().unwrap()[0].clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            arguments.range,
+            Range::new(Position::new(2, 0), Position::new(5, 0))
+        );
+        let mut params = json!({
+            "messages": [
+                { "role": "user", "content": "Fix:\n{DIAGNOSTICS}\n{CODE}" }
+            ]
+        });
+        insert_diagnostics(&mut params, &arguments.diagnostics);
+        assert_eq!(
+            params["messages"][0]["content"],
+            "Fix:\nline 3: undefined name `x`\nline 4: unused variable `y`\n{CODE}"
+        );
+    }
+
+    #[test]
+    fn test_build_action_result() {
+        let uri = Url::parse("file:///test.py").unwrap();
+        let range = Range::new(Position::new(1, 4), Position::new(3, 0));
+        let result = build_action_result(
+            ActionOutput::InsertBefore,
+            uri.clone(),
+            range,
+            "",
+            None,
+            "# Adds two numbers".to_string(),
+        )
+        .unwrap();
+        let start = Position::new(1, 0);
+        assert_eq!(
+            result,
+            ActionResult::Edit(WorkspaceEdit::new(HashMap::from([(
+                uri.clone(),
+                vec![TextEdit::new(
+                    Range::new(start, start),
+                    "# Adds two numbers\n".to_string()
+                )]
+            )])))
+        );
+        assert_eq!(
+            build_action_result(
+                ActionOutput::Message,
+                uri,
+                range,
+                "",
+                None,
+                "Explained".to_string()
+            )
+            .unwrap(),
+            ActionResult::Message("Explained".to_string())
+        );
+    }
+
+    #[test]
+    fn test_get_tests_target() {
+        let uri = Url::parse("file:///project/src/math.py").unwrap();
+        let convention = TestConvention {
+            path: Some("../tests/test_{name}.{ext}".to_string()),
+            module: None,
+        };
+        let (target, existing) = get_tests_target(&uri, "", Some(&convention)).unwrap();
+        assert_eq!(target.as_str(), "file:///project/tests/test_math.py");
+        assert!(existing.is_none());
+        let (target, existing) = get_tests_target(&uri, "def add(): pass", None).unwrap();
+        assert_eq!(target, uri);
+        assert_eq!(existing.as_deref(), Some("def add(): pass"));
+    }
+
+    #[test]
+    fn test_build_tests_edit() {
+        let uri = Url::parse("file:///project/src/lib.rs").unwrap();
+        let existing = "fn add() {}\n\n#[cfg(test)]\nmod tests {\n    fn a() {}\n}\n";
+        let edit = build_tests_edit(
+            uri.clone(),
+            Some(existing),
+            Some("mod tests {"),
+            "    #[test]\n    fn test_add() {}".to_string(),
+        );
+        let position = Position::new(5, 0);
+        assert_eq!(
+            edit.changes.unwrap()[&uri],
+            vec![TextEdit::new(
+                Range::new(position, position),
+                "\n    #[test]\n    fn test_add() {}\n".to_string()
+            )]
+        );
+
+        let edit = build_tests_edit(uri.clone(), Some("fn add() {}"), None, "tests".to_string());
+        let position = Position::new(0, 11);
+        assert_eq!(
+            edit.changes.unwrap()[&uri],
+            vec![TextEdit::new(
+                Range::new(position, position),
+                "\n\ntests\n".to_string()
+            )]
+        );
+
+        let edit = build_tests_edit(uri, None, None, "tests".to_string());
+        let Some(DocumentChanges::Operations(operations)) = edit.document_changes else {
+            panic!("expected the test file to be created");
+        };
+        assert!(matches!(
+            operations[0],
+            DocumentChangeOperation::Op(ResourceOp::Create(_))
+        ));
+    }
+}
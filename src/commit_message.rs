@@ -0,0 +1,142 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use lsp_types::{
+    CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams, Command, Position, Range,
+    TextDocumentIdentifier, TextEdit, Url, WorkspaceEdit,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    config::{ChatMessage, CommitMessage, Config},
+    git,
+    memory_backends::{ContextAndCodePrompt, Prompt},
+};
+
+// The command the commit message code action runs with workspace/executeCommand
+pub const GENERATE_COMMIT_MESSAGE_COMMAND: &str = "lsp-ai.generateCommitMessage";
+
+const TITLE: &str = "Generate commit message";
+
+const SYSTEM_MESSAGE: &str = "You write git commit messages in the Conventional Commits format. \
+The first line is `type(scope): summary`, at most 72 characters, where the type is one of feat, \
+fix, docs, style, refactor, perf, test, build, ci or chore and the scope is optional. When the \
+summary does not say it all, a blank line and a short body explaining what changed and why \
+follow. Match the style of the recent commits. Answer with the commit message only.";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateCommitMessageArguments {
+    // The commit message buffer, the message is inserted at its start
+    pub text_document: TextDocumentIdentifier,
+}
+
+fn is_commit_message(uri: &Url) -> bool {
+    uri.path().ends_with("/COMMIT_EDITMSG")
+}
+
+// Offered in commit message buffers when `commit_message` is configured
+pub fn get_code_action(config: &Config, params: &CodeActionParams) -> Option<CodeActionOrCommand> {
+    config.config.commit_message.as_ref()?;
+    if !is_commit_message(&params.text_document.uri) {
+        return None;
+    }
+    let kind = CodeActionKind::SOURCE;
+    if let Some(only) = &params.context.only {
+        if !only.iter().any(|o| kind.as_str().starts_with(o.as_str())) {
+            return None;
+        }
+    }
+    let arguments = GenerateCommitMessageArguments {
+        text_document: params.text_document.clone(),
+    };
+    Some(CodeActionOrCommand::CodeAction(CodeAction {
+        title: TITLE.to_string(),
+        kind: Some(kind),
+        command: Some(Command::new(
+            TITLE.to_string(),
+            GENERATE_COMMIT_MESSAGE_COMMAND.to_string(),
+            Some(vec![serde_json::to_value(arguments).unwrap()]),
+        )),
+        ..Default::default()
+    }))
+}
+
+// The prompt with the staged diff as the code and the recent commits as the context, and the
+// parameters with the default messages when none are configured
+pub async fn build_request(
+    commit_message: &CommitMessage,
+    dir: &Path,
+) -> anyhow::Result<(Prompt, Value)> {
+    let diff = git::get_staged_diff(dir)
+        .await
+        .with_context(|| format!("no staged changes in {}", dir.display()))?;
+    let commits = git::get_recent_commits(dir, commit_message.max_commits).await;
+    let mut params = serde_json::to_value(&commit_message.parameters)?;
+    if params.get("messages").is_none() {
+        params["messages"] = serde_json::to_value(vec![
+            ChatMessage::new("system".to_string(), SYSTEM_MESSAGE.to_string()),
+            ChatMessage::new(
+                "user".to_string(),
+                "Recent commits:\n{CONTEXT}\n\nStaged changes:\n{CODE}".to_string(),
+            ),
+        ])?;
+    }
+    let prompt = Prompt::ContextAndCode(ContextAndCodePrompt::new(commits.join("\n\n"), diff));
+    Ok((prompt, params))
+}
+
+// Drops the code block models like to wrap messages in and separates the subject from the body
+// with one blank line as git expects
+pub fn format_commit_message(text: &str) -> String {
+    let text = text.trim();
+    let text = match text.strip_prefix("```") {
+        Some(rest) => {
+            let rest = rest
+                .split_once('\n')
+                .map_or("", |(_, rest)| rest)
+                .trim_end();
+            rest.strip_suffix("```").unwrap_or(rest).trim()
+        }
+        None => text,
+    };
+    let (subject, body) = text.split_once('\n').unwrap_or((text, ""));
+    let body = body.trim();
+    if body.is_empty() {
+        subject.trim().to_owned()
+    } else {
+        format!("{}\n\n{body}", subject.trim())
+    }
+}
+
+// Inserts the message above what the buffer has, e.g. the comments git adds
+pub fn build_edit(uri: Url, message: &str) -> WorkspaceEdit {
+    let start = Position::new(0, 0);
+    WorkspaceEdit::new(HashMap::from([(
+        uri,
+        vec![TextEdit::new(
+            Range::new(start, start),
+            format!("{message}\n"),
+        )],
+    )]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_commit_messages() {
+        assert_eq!(
+            format_commit_message(
+                "```text\nfix(parser): keep the last line\nIt was skipped.\n```\n"
+            ),
+            "fix(parser): keep the last line\n\nIt was skipped."
+        );
+        assert_eq!(
+            format_commit_message("  feat: add a parser\n\n\n"),
+            "feat: add a parser"
+        );
+    }
+}
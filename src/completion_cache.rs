@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use lsp_types::{DidChangeTextDocumentParams, TextDocumentPositionParams};
+use parking_lot::Mutex;
+use serde_json::Value;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{config, memory_backends::Prompt, transformer_worker::CompletionCandidate};
+
+struct Entry {
+    uri: String,
+    // The lines of the document the prompt was built from
+    start_line: u32,
+    end_line: u32,
+    candidates: Vec<CompletionCandidate>,
+    inserted: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<CacheKey, Entry>,
+    // Counts the uses so the least recently used entry is known even when they happen at once
+    clock: u64,
+}
+
+// The key of a completion from a model, the cursor position is not part of it so moving away and
+// back reuses the completion
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey(u64);
+
+impl CacheKey {
+    pub fn new(model: &str, prompt: &Prompt, params: &Value) -> Self {
+        let (prefix, suffix) = match prompt {
+            Prompt::ContextAndCode(prompt) => (&prompt.context, &prompt.code),
+            Prompt::FIM(prompt) => (&prompt.prompt, &prompt.suffix),
+        };
+        let prefix_hash = xxh3_64(prefix.as_bytes());
+        let suffix_hash = xxh3_64(suffix.as_bytes());
+        let params_hash = xxh3_64(params.to_string().as_bytes());
+        let model_hash = xxh3_64(model.as_bytes());
+        Self(xxh3_64(
+            format!("{model_hash:x}:{prefix_hash:x}:{suffix_hash:x}:{params_hash:x}").as_bytes(),
+        ))
+    }
+}
+
+// The lines above and below the cursor the prompt covers
+fn get_prompt_lines(prompt: &Prompt) -> (u32, u32) {
+    let (before, after) = match prompt {
+        Prompt::ContextAndCode(prompt) => match prompt.code.split_once("<CURSOR>") {
+            Some((before, after)) => (before, after),
+            None => (prompt.code.as_str(), ""),
+        },
+        Prompt::FIM(prompt) => (prompt.prompt.as_str(), prompt.suffix.as_str()),
+    };
+    let count = |text: &str| text.matches('\n').count() as u32;
+    (count(before), count(after))
+}
+
+// Completions for prompts that were already sent to the model. Entries are dropped when they
+// expire, when the cache is full and they are the least recently used, and when the lines of the
+// document they were built from change.
+#[derive(Default)]
+pub struct CompletionCache {
+    entries: Mutex<Entries>,
+}
+
+impl std::fmt::Debug for CompletionCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompletionCache")
+            .field("entries", &self.entries.lock().entries.len())
+            .finish()
+    }
+}
+
+impl CompletionCache {
+    pub fn get(
+        &self,
+        key: CacheKey,
+        config: &config::CompletionCache,
+    ) -> Option<Vec<CompletionCandidate>> {
+        let mut entries = self.entries.lock();
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries.entries.get_mut(&key)?;
+        if entry.inserted.elapsed() > Duration::from_secs(config.ttl_secs) {
+            entries.entries.remove(&key);
+            return None;
+        }
+        entry.last_used = clock;
+        Some(entry.candidates.clone())
+    }
+
+    pub fn insert(
+        &self,
+        key: CacheKey,
+        position: &TextDocumentPositionParams,
+        prompt: &Prompt,
+        candidates: Vec<CompletionCandidate>,
+        config: &config::CompletionCache,
+    ) {
+        if config.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entries = &mut entries.entries;
+        let ttl = Duration::from_secs(config.ttl_secs);
+        entries.retain(|_, entry| entry.inserted.elapsed() <= ttl);
+        while entries.len() >= config.max_entries {
+            let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+        let (before, after) = get_prompt_lines(prompt);
+        let line = position.position.line;
+        entries.insert(
+            key,
+            Entry {
+                uri: position.text_document.uri.to_string(),
+                start_line: line.saturating_sub(before),
+                end_line: line.saturating_add(after),
+                candidates,
+                inserted: Instant::now(),
+                last_used: clock,
+            },
+        );
+    }
+
+    // Changes above an entry only move it, changes in it drop it
+    pub fn invalidate(&self, params: &DidChangeTextDocumentParams) {
+        let uri = params.text_document.uri.to_string();
+        let entries = &mut self.entries.lock().entries;
+        for change in &params.content_changes {
+            let Some(range) = change.range else {
+                entries.retain(|_, entry| entry.uri != uri);
+                continue;
+            };
+            let added_lines = change.text.matches('\n').count() as i64;
+            let delta = added_lines - (range.end.line as i64 - range.start.line as i64);
+            entries.retain(|_, entry| {
+                if entry.uri != uri || range.start.line > entry.end_line {
+                    return true;
+                }
+                if range.end.line < entry.start_line {
+                    entry.start_line = (entry.start_line as i64 + delta).max(0) as u32;
+                    entry.end_line = (entry.end_line as i64 + delta).max(0) as u32;
+                    return true;
+                }
+                false
+            });
+        }
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_backends::FIMPrompt;
+    use lsp_types::{
+        Position, Range, TextDocumentContentChangeEvent, TextDocumentIdentifier,
+        VersionedTextDocumentIdentifier,
+    };
+    use serde_json::json;
+
+    fn position(line: u32) -> TextDocumentPositionParams {
+        TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: "file:///filler.py".parse().unwrap(),
+            },
+            position: Position { line, character: 0 },
+        }
+    }
+
+    fn change(start_line: u32, end_line: u32, text: &str) -> DidChangeTextDocumentParams {
+        DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: "file:///filler.py".parse().unwrap(),
+                version: 1,
+            },
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: Position {
+                        line: start_line,
+                        character: 0,
+                    },
+                    end: Position {
+                        line: end_line,
+                        character: 0,
+                    },
+                }),
+                range_length: None,
+                text: text.to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn completion_cache() {
+        let config = config::CompletionCache {
+            max_entries: 2,
+            ttl_secs: 60,
+        };
+        let cache = CompletionCache::default();
+        let prompt = Prompt::FIM(FIMPrompt::new("a\nb\nc".to_string(), "\nd\ne".to_string()));
+        let key = CacheKey::new("model", &prompt, &json!({}));
+        assert_ne!(
+            key,
+            CacheKey::new("model", &prompt, &json!({ "max_tokens": 10 }))
+        );
+        assert_ne!(key, CacheKey::new("fallback", &prompt, &json!({})));
+        assert!(cache.get(key, &config).is_none());
+
+        // The prompt covers lines 8 to 12
+        cache.insert(
+            key,
+            &position(10),
+            &prompt,
+            vec![CompletionCandidate::new("x".to_string())],
+            &config,
+        );
+        assert_eq!(cache.get(key, &config).unwrap()[0].insert_text, "x");
+
+        // Adding a line above moves the entry to lines 9 to 13
+        cache.invalidate(&change(0, 0, "\n"));
+        cache.invalidate(&change(14, 15, ""));
+        assert!(cache.get(key, &config).is_some());
+        cache.invalidate(&change(13, 13, "y"));
+        assert!(cache.get(key, &config).is_none());
+
+        // The least recently used entry is evicted when the cache is full
+        for (i, text) in ["1", "2", "3"].into_iter().enumerate() {
+            let key = CacheKey::new("model", &prompt, &json!({ "i": i }));
+            cache.insert(
+                key,
+                &position(10),
+                &prompt,
+                vec![CompletionCandidate::new(text.to_string())],
+                &config,
+            );
+        }
+        assert!(cache
+            .get(CacheKey::new("model", &prompt, &json!({ "i": 0 })), &config)
+            .is_none());
+        assert!(cache
+            .get(CacheKey::new("model", &prompt, &json!({ "i": 2 })), &config)
+            .is_some());
+    }
+}
@@ -0,0 +1,125 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use lsp_types::{Position, Url};
+
+use crate::{
+    config::{CompletionRules, CursorRegion},
+    splitters::get_region,
+    utils::position_to_offset,
+};
+
+// Globs without a `/` match the file name in any directory
+fn build_glob_set(globs: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        let pattern = if glob.contains('/') {
+            glob.clone()
+        } else {
+            format!("**/{glob}")
+        };
+        builder.add(Glob::new(&pattern).with_context(|| format!("invalid glob `{glob}`"))?);
+    }
+    Ok(builder.build()?)
+}
+
+// Whether a completion may be requested for the document at the position
+pub fn allows(
+    rules: &CompletionRules,
+    uri: &str,
+    language_id: Option<&str>,
+    text: &str,
+    position: Position,
+) -> anyhow::Result<bool> {
+    let language_allowed = match language_id {
+        Some(language_id) => {
+            (rules.language_ids.is_empty() || rules.language_ids.iter().any(|l| l == language_id))
+                && !rules.exclude_language_ids.iter().any(|l| l == language_id)
+        }
+        None => rules.language_ids.is_empty(),
+    };
+    if !language_allowed {
+        return Ok(false);
+    }
+
+    if !rules.include.is_empty() || !rules.exclude.is_empty() {
+        let path = Url::parse(uri)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .unwrap_or_else(|| PathBuf::from(uri));
+        if !rules.include.is_empty() && !build_glob_set(&rules.include)?.is_match(&path) {
+            return Ok(false);
+        }
+        if build_glob_set(&rules.exclude)?.is_match(&path) {
+            return Ok(false);
+        }
+    }
+
+    let offset = position_to_offset(text, position);
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    if text[line_start..offset].trim_start().chars().count() < rules.min_prefix_length {
+        return Ok(false);
+    }
+
+    Ok(match get_region(uri, text, offset) {
+        Some(CursorRegion::Comment) => rules.in_comments,
+        Some(CursorRegion::String) => rules.in_strings,
+        Some(CursorRegion::Code) | None => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completion_rules_allow_and_deny() -> anyhow::Result<()> {
+        let rules = CompletionRules {
+            exclude_language_ids: vec!["markdown".to_string()],
+            exclude: vec![".env".to_string(), "**/generated/**".to_string()],
+            min_prefix_length: 2,
+            ..Default::default()
+        };
+        let text = "fn main() {\n    // todo\n    le\n}\n";
+        let allows = |uri: &str, language_id: &str, line: u32, character: u32| {
+            allows(
+                &rules,
+                uri,
+                Some(language_id),
+                text,
+                Position::new(line, character),
+            )
+        };
+        assert!(allows("file:///src/main.rs", "rust", 2, 6)?);
+        assert!(!allows("file:///README.md", "markdown", 2, 6)?);
+        assert!(!allows("file:///src/.env", "dotenv", 2, 6)?);
+        assert!(!allows("file:///src/generated/main.rs", "rust", 2, 6)?);
+        // Too short and in a comment
+        assert!(!allows("file:///src/main.rs", "rust", 2, 5)?);
+        assert!(!allows("file:///src/main.rs", "rust", 1, 11)?);
+
+        let rules = CompletionRules {
+            language_ids: vec!["rust".to_string()],
+            in_comments: true,
+            ..Default::default()
+        };
+        let position = Position::new(1, 11);
+        assert!(allows(
+            &rules,
+            "file:///src/main.rs",
+            Some("rust"),
+            text,
+            position
+        )?);
+        assert!(!allows(
+            &rules,
+            "file:///a.py",
+            Some("python"),
+            text,
+            position
+        )?);
+        assert!(!allows(&rules, "file:///a.py", None, text, position)?);
+        Ok(())
+    }
+}
@@ -1,7 +1,13 @@
 use anyhow::{Context, Result};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+use tracing::error;
+
+use crate::workspace_config;
 
 pub type Kwargs = HashMap<String, Value>;
 
@@ -9,46 +15,234 @@ const fn max_requests_per_second_default() -> f32 {
     1.
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+const fn max_attempts_default() -> u32 {
+    3
+}
+
+const fn backoff_base_ms_default() -> u64 {
+    500
+}
+
+const fn max_backoff_ms_default() -> u64 {
+    30_000
+}
+
+// Requests that fail with 429, a 5xx status or a connection error are retried with exponential
+// backoff and jitter
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Retry {
+    // Including the first attempt, 1 disables retries
+    #[serde(default = "max_attempts_default")]
+    pub max_attempts: u32,
+    // The delay before the first retry, doubled for each one after it
+    #[serde(default = "backoff_base_ms_default")]
+    pub backoff_base_ms: u64,
+    // Also caps the delay a `Retry-After` header asks for
+    #[serde(default = "max_backoff_ms_default")]
+    pub max_backoff_ms: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_attempts: max_attempts_default(),
+            backoff_base_ms: backoff_base_ms_default(),
+            max_backoff_ms: max_backoff_ms_default(),
+        }
+    }
+}
+
+const fn post_process_step_default() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
 pub struct PostProcess {
+    // Keeps only the content of the first markdown code block when the response to a `messages`
+    // prompt has one
+    #[serde(default = "post_process_step_default")]
+    pub extract_code_block: bool,
     pub remove_duplicate_start: bool,
     pub remove_duplicate_end: bool,
+    // Removes lines at the end of the response that repeat the lines after the cursor
+    #[serde(default = "post_process_step_default")]
+    pub remove_suffix_overlap: bool,
+    // Drops the last line of a multi line response when it was cut off
+    #[serde(default = "post_process_step_default")]
+    pub strip_partial_lines: bool,
+    // Closes brackets the response opens on its last line and drops closing brackets the code
+    // after the cursor already has
+    #[serde(default = "post_process_step_default")]
+    pub balance_brackets: bool,
+    // Shifts the lines of the response to the indentation of the code at the cursor, with its
+    // tabs or spaces
+    #[serde(default = "post_process_step_default")]
+    pub reindent: bool,
+    // Set with `line_mode` in the completion parameters it replaces this one, e.g. for a route
+    #[serde(default)]
+    pub line_mode: LineMode,
 }
 
 impl Default for PostProcess {
     fn default() -> Self {
         Self {
+            extract_code_block: true,
             remove_duplicate_start: true,
             remove_duplicate_end: true,
+            remove_suffix_overlap: true,
+            strip_partial_lines: true,
+            balance_brackets: true,
+            reindent: true,
+            line_mode: LineMode::default(),
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+// Whether responses are cut after their first line
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema)]
+pub enum LineMode {
+    #[serde(rename = "single")]
+    Single,
+    #[default]
+    #[serde(rename = "multi")]
+    Multi,
+    // One line in the middle of a statement, more at the start of a line or at the end of a line
+    // opening a block, with the block syntax of the document's language
+    #[serde(rename = "auto")]
+    Auto,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, JsonSchema)]
 pub enum ValidMemoryBackend {
     #[serde(rename = "file_store")]
     FileStore(FileStore),
     #[serde(rename = "postgresml")]
     PostgresML(PostgresML),
+    #[serde(rename = "qdrant")]
+    Qdrant(Qdrant),
+    #[serde(rename = "vector_index")]
+    VectorIndex(VectorIndex),
+    #[serde(rename = "sqlite")]
+    Sqlite(Sqlite),
+    #[serde(rename = "pgvector")]
+    Pgvector(Pgvector),
+    #[cfg(feature = "lancedb")]
+    #[serde(rename = "lancedb")]
+    LanceDB(LanceDB),
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, JsonSchema)]
+#[serde(tag = "type")]
+pub enum ValidEmbeddingModel {
+    #[serde(rename = "open_ai")]
+    OpenAI(OpenAIEmbeddingModel),
+    #[serde(rename = "ollama")]
+    Ollama(OllamaEmbeddingModel),
+    #[serde(rename = "cohere")]
+    Cohere(CohereEmbeddingModel),
+    #[cfg(feature = "llama_cpp")]
+    #[serde(rename = "llama_cpp")]
+    LLaMACPP(LLaMACPP),
+}
+
+impl ValidEmbeddingModel {
+    // Identifies the model the embeddings in an index were created with
+    pub fn name(&self) -> &str {
+        match self {
+            Self::OpenAI(model) => &model.model,
+            Self::Ollama(model) => &model.model,
+            Self::Cohere(model) => &model.model,
+            #[cfg(feature = "llama_cpp")]
+            Self::LLaMACPP(model) => model
+                .file_path
+                .as_deref()
+                .or(model.name.as_deref())
+                .unwrap_or_default(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, PartialEq, JsonSchema)]
 #[serde(tag = "type")]
 pub enum ValidModel {
     #[cfg(feature = "llama_cpp")]
     #[serde(rename = "llama_cpp")]
     LLaMACPP(LLaMACPP),
+    #[cfg(feature = "candle")]
+    #[serde(rename = "candle")]
+    Candle(Candle),
     #[serde(rename = "open_ai")]
     OpenAI(OpenAI),
+    #[serde(rename = "azure_open_ai")]
+    AzureOpenAI(AzureOpenAI),
+    #[serde(rename = "open_ai_compatible")]
+    OpenAICompatible(OpenAICompatible),
     #[serde(rename = "anthropic")]
     Anthropic(Anthropic),
-    #[serde(rename = "mistral_fim")]
+    #[serde(rename = "mistral_fim", alias = "mistral")]
     MistralFIM(MistralFIM),
     #[serde(rename = "ollama")]
     Ollama(Ollama),
+    #[serde(rename = "gemini")]
+    Gemini(Gemini),
+    #[serde(rename = "command")]
+    Command(Command),
+    #[serde(rename = "tgi")]
+    TGI(TGI),
+    #[serde(rename = "vllm")]
+    VLLM(VLLM),
+    #[serde(rename = "cohere")]
+    Cohere(Cohere),
+}
+
+impl ValidModel {
+    pub fn max_concurrent_requests(&self) -> Option<usize> {
+        match self {
+            #[cfg(feature = "llama_cpp")]
+            ValidModel::LLaMACPP(llama_cpp) => llama_cpp.max_concurrent_requests,
+            #[cfg(feature = "candle")]
+            ValidModel::Candle(candle) => candle.max_concurrent_requests,
+            ValidModel::OpenAI(open_ai) => open_ai.max_concurrent_requests,
+            ValidModel::AzureOpenAI(azure_open_ai) => azure_open_ai.max_concurrent_requests,
+            ValidModel::OpenAICompatible(open_ai_compatible) => {
+                open_ai_compatible.max_concurrent_requests
+            }
+            ValidModel::Anthropic(anthropic) => anthropic.max_concurrent_requests,
+            ValidModel::MistralFIM(mistral_fim) => mistral_fim.max_concurrent_requests,
+            ValidModel::Ollama(ollama) => ollama.max_concurrent_requests,
+            ValidModel::Gemini(gemini) => gemini.max_concurrent_requests,
+            ValidModel::Command(command) => command.max_concurrent_requests,
+            ValidModel::TGI(tgi) => tgi.max_concurrent_requests,
+            ValidModel::VLLM(vllm) => vllm.max_concurrent_requests,
+            ValidModel::Cohere(cohere) => cohere.max_concurrent_requests,
+        }
+    }
+
+    pub fn request_timeout_ms(&self) -> Option<u64> {
+        match self {
+            #[cfg(feature = "llama_cpp")]
+            ValidModel::LLaMACPP(llama_cpp) => llama_cpp.request_timeout_ms,
+            #[cfg(feature = "candle")]
+            ValidModel::Candle(candle) => candle.request_timeout_ms,
+            ValidModel::OpenAI(open_ai) => open_ai.request_timeout_ms,
+            ValidModel::AzureOpenAI(azure_open_ai) => azure_open_ai.request_timeout_ms,
+            ValidModel::OpenAICompatible(open_ai_compatible) => {
+                open_ai_compatible.request_timeout_ms
+            }
+            ValidModel::Anthropic(anthropic) => anthropic.request_timeout_ms,
+            ValidModel::MistralFIM(mistral_fim) => mistral_fim.request_timeout_ms,
+            ValidModel::Ollama(ollama) => ollama.request_timeout_ms,
+            ValidModel::Gemini(gemini) => gemini.request_timeout_ms,
+            ValidModel::Command(command) => command.request_timeout_ms,
+            ValidModel::TGI(tgi) => tgi.request_timeout_ms,
+            ValidModel::VLLM(vllm) => vllm.request_timeout_ms,
+            ValidModel::Cohere(cohere) => cohere.request_timeout_ms,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct ChatMessage {
     pub role: String,
@@ -65,7 +259,7 @@ impl ChatMessage {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, PartialEq, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Chat {
     pub completion: Option<Vec<ChatMessage>>,
@@ -74,7 +268,7 @@ pub struct Chat {
     pub chat_format: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
 #[allow(clippy::upper_case_acronyms)]
 #[serde(deny_unknown_fields)]
 pub struct FIM {
@@ -83,30 +277,464 @@ pub struct FIM {
     pub end: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+const fn chunk_size_default() -> usize {
+    1500
+}
+
+const fn chunk_overlap_default() -> usize {
+    0
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TreeSitter {
+    // The maximum size of a chunk in bytes
+    #[serde(default = "chunk_size_default")]
+    pub chunk_size: usize,
+    // Only used when falling back to the text splitter for unsupported languages or oversized
+    // nodes
+    #[serde(default = "chunk_overlap_default")]
+    pub chunk_overlap: usize,
+}
+
+impl Default for TreeSitter {
+    fn default() -> Self {
+        Self {
+            chunk_size: chunk_size_default(),
+            chunk_overlap: chunk_overlap_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TextSplitter {
+    // The maximum size of a chunk in bytes
+    #[serde(default = "chunk_size_default")]
+    pub chunk_size: usize,
+    #[serde(default = "chunk_overlap_default")]
+    pub chunk_overlap: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, JsonSchema)]
+#[serde(tag = "type")]
+pub enum ValidSplitter {
+    #[serde(rename = "tree_sitter")]
+    TreeSitter(TreeSitter),
+    #[serde(rename = "text_splitter")]
+    TextSplitter(TextSplitter),
+}
+
+impl Default for ValidSplitter {
+    fn default() -> Self {
+        ValidSplitter::TreeSitter(TreeSitter::default())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct PostgresML {
     pub database_url: Option<String>,
     #[serde(default)]
     pub crawl: bool,
+    #[serde(default)]
+    pub splitter: ValidSplitter,
 }
 
-#[derive(Clone, Debug, Deserialize, Default)]
+fn qdrant_url_default() -> String {
+    "http://localhost:6333".to_string()
+}
+
+fn qdrant_collection_name_default() -> String {
+    "lsp-ai".to_string()
+}
+
+const fn search_limit_default() -> usize {
+    5
+}
+
+const fn rrf_weight_default() -> f32 {
+    1.
+}
+
+const fn rrf_k_default() -> f32 {
+    60.
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HybridSearch {
+    // How much the rank of a chunk in each search counts towards its fused rank
+    #[serde(default = "rrf_weight_default")]
+    pub vector_weight: f32,
+    #[serde(default = "rrf_weight_default")]
+    pub keyword_weight: f32,
+    // The reciprocal rank fusion constant, higher values flatten the difference between ranks
+    #[serde(default = "rrf_k_default")]
+    pub rrf_k: f32,
+}
+
+const fn max_file_size_default() -> u64 {
+    1_000_000
+}
+
+const fn watch_default() -> bool {
+    true
+}
+
+const fn docs_search_limit_default() -> usize {
+    2
+}
+
+const fn max_pages_default() -> usize {
+    100
+}
+
+// Documentation indexed with the workspace, e.g. a docs.rs dump or a wiki export, and retrieved
+// on top of the chunks of code
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Docs {
+    // http(s) URLs or local directories and files of markdown, HTML and text. The pages a URL
+    // links to under it are fetched too.
+    pub sources: Vec<String>,
+    // The number of documentation chunks retrieved per prompt
+    #[serde(default = "docs_search_limit_default")]
+    pub search_limit: usize,
+    // The most pages fetched for each URL
+    #[serde(default = "max_pages_default")]
+    pub max_pages: usize,
+}
+
+// Walks the workspace on startup and indexes every file, not only the opened ones. Honors
+// .gitignore, .ignore and .lspaiignore files and skips hidden and binary files.
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Crawl {
+    // Larger files are skipped, in bytes
+    #[serde(default = "max_file_size_default")]
+    pub max_file_size: u64,
+    // Keeps the index up to date with changes other tools make to the files, e.g. git checkout.
+    // Only the ignore files at the root of the workspace are honored for these.
+    #[serde(default = "watch_default")]
+    pub watch: bool,
+    pub docs: Option<Docs>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Qdrant {
+    #[serde(default = "qdrant_url_default")]
+    pub url: String,
+    pub api_key_env_var_name: Option<String>,
+    pub api_key: Option<String>,
+    #[serde(default = "qdrant_collection_name_default")]
+    pub collection_name: String,
+    pub embedding_model: ValidEmbeddingModel,
+    #[serde(default)]
+    pub splitter: ValidSplitter,
+    // The number of chunks retrieved per prompt
+    #[serde(default = "search_limit_default")]
+    pub search_limit: usize,
+    // Only retrieve chunks from files in the same language as the file being edited
+    #[serde(default)]
+    pub filter_by_language: bool,
+    // Only retrieve chunks from files under these directories
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Only retrieve chunks from files in the same workspace folder as the file being edited
+    #[serde(default)]
+    pub filter_by_workspace_folder: bool,
+    // Also retrieve chunks by keyword and fuse the results with the vector search
+    pub hybrid_search: Option<HybridSearch>,
+    pub rerank: Option<ValidRerankModel>,
+    pub context_packing: Option<ContextPacking>,
+    pub crawl: Option<Crawl>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VectorIndex {
+    pub embedding_model: ValidEmbeddingModel,
+    #[serde(default)]
+    pub splitter: ValidSplitter,
+    // The number of chunks retrieved per prompt
+    #[serde(default = "search_limit_default")]
+    pub search_limit: usize,
+    // Only retrieve chunks from files in the same language as the file being edited
+    #[serde(default)]
+    pub filter_by_language: bool,
+    // Only retrieve chunks from files under these directories
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Only retrieve chunks from files in the same workspace folder as the file being edited
+    #[serde(default)]
+    pub filter_by_workspace_folder: bool,
+    // Also retrieve chunks by keyword and fuse the results with the vector search
+    pub hybrid_search: Option<HybridSearch>,
+    pub rerank: Option<ValidRerankModel>,
+    pub context_packing: Option<ContextPacking>,
+    pub crawl: Option<Crawl>,
+    // Where the index is persisted, defaults to lsp-ai's cache directory
+    pub cache_dir: Option<String>,
+}
+
+// A vector and full-text index in a SQLite database, one file per workspace
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Sqlite {
+    pub embedding_model: ValidEmbeddingModel,
+    #[serde(default)]
+    pub splitter: ValidSplitter,
+    // The number of chunks retrieved per prompt
+    #[serde(default = "search_limit_default")]
+    pub search_limit: usize,
+    // Only retrieve chunks from files in the same language as the file being edited
+    #[serde(default)]
+    pub filter_by_language: bool,
+    // Only retrieve chunks from files under these directories
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Only retrieve chunks from files in the same workspace folder as the file being edited
+    #[serde(default)]
+    pub filter_by_workspace_folder: bool,
+    // Also retrieve chunks with the database's full-text index and fuse the results with the
+    // vector search
+    pub hybrid_search: Option<HybridSearch>,
+    pub rerank: Option<ValidRerankModel>,
+    pub context_packing: Option<ContextPacking>,
+    pub crawl: Option<Crawl>,
+    // The database file, defaults to one for the workspace in lsp-ai's cache directory
+    pub database_path: Option<String>,
+}
+
+const fn max_connections_default() -> u32 {
+    5
+}
+
+// Chunks and embeddings in a Postgres database with the pgvector extension
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Pgvector {
+    // Defaults to the DATABASE_URL environment variable
+    pub database_url: Option<String>,
+    // The most connections kept open to the database
+    #[serde(default = "max_connections_default")]
+    pub max_connections: u32,
+    pub embedding_model: ValidEmbeddingModel,
+    #[serde(default)]
+    pub splitter: ValidSplitter,
+    // The number of chunks retrieved per prompt
+    #[serde(default = "search_limit_default")]
+    pub search_limit: usize,
+    // Only retrieve chunks from files in the same language as the file being edited
+    #[serde(default)]
+    pub filter_by_language: bool,
+    // Only retrieve chunks from files under these directories
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Only retrieve chunks from files in the same workspace folder as the file being edited
+    #[serde(default)]
+    pub filter_by_workspace_folder: bool,
+    // Also retrieve chunks by keyword and fuse the results with the vector search
+    pub hybrid_search: Option<HybridSearch>,
+    pub rerank: Option<ValidRerankModel>,
+    pub context_packing: Option<ContextPacking>,
+    pub crawl: Option<Crawl>,
+}
+
+// Chunks and embeddings in a Lance dataset in the workspace, other tools can open it to inspect
+// the index
+#[cfg(feature = "lancedb")]
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LanceDB {
+    pub embedding_model: ValidEmbeddingModel,
+    #[serde(default)]
+    pub splitter: ValidSplitter,
+    // The number of chunks retrieved per prompt
+    #[serde(default = "search_limit_default")]
+    pub search_limit: usize,
+    // Only retrieve chunks from files in the same language as the file being edited
+    #[serde(default)]
+    pub filter_by_language: bool,
+    // Only retrieve chunks from files under these directories
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Only retrieve chunks from files in the same workspace folder as the file being edited
+    #[serde(default)]
+    pub filter_by_workspace_folder: bool,
+    // Also retrieve chunks by keyword and fuse the results with the vector search
+    pub hybrid_search: Option<HybridSearch>,
+    pub rerank: Option<ValidRerankModel>,
+    pub context_packing: Option<ContextPacking>,
+    pub crawl: Option<Crawl>,
+    // The database directory, defaults to .lsp-ai/lancedb in the workspace
+    pub path: Option<String>,
+}
+
+fn keyring_service_default() -> String {
+    "lsp-ai".to_string()
+}
+
+// An entry of the OS keychain, e.g. the macOS Keychain or the Secret Service on Linux
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct KeyringEntry {
+    #[serde(default = "keyring_service_default")]
+    pub service: String,
+    pub user: String,
+}
+
+// Any API compatible with OpenAI's embeddings endpoint
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct OpenAIEmbeddingModel {
+    pub endpoint: String,
+    pub model: String,
+    pub auth_token_env_var_name: Option<String>,
+    pub auth_token: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show openai`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct OllamaEmbeddingModel {
+    // The embeddings endpoint, default: 'http://localhost:11434/api/embeddings'
+    pub endpoint: Option<String>,
+    // The model name
+    pub model: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CohereEmbeddingModel {
+    // The embed endpoint, default: 'https://api.cohere.com/v2/embed'
+    pub endpoint: Option<String>,
+    // The model name, e.g. 'embed-english-v3.0'
+    pub model: String,
+    pub auth_token_env_var_name: Option<String>,
+    pub auth_token: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show cohere`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
+}
+
+const fn top_n_default() -> usize {
+    5
+}
+
+// An API compatible with Cohere's or Voyage's rerank endpoint. Local servers like llama.cpp's
+// and Text Embeddings Inference have one compatible with Cohere's.
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RerankModel {
+    // The provider's endpoint when not set
+    pub endpoint: Option<String>,
+    pub model: String,
+    pub auth_token_env_var_name: Option<String>,
+    pub auth_token: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show cohere`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
+    // The number of chunks kept of the ones retrieved
+    #[serde(default = "top_n_default")]
+    pub top_n: usize,
+}
+
+// Orders the retrieved chunks by how relevant a cross-encoder finds them to the code around the
+// cursor, before they are packed into the prompt
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(tag = "type")]
+pub enum ValidRerankModel {
+    #[serde(rename = "cohere")]
+    Cohere(RerankModel),
+    #[serde(rename = "voyage")]
+    Voyage(RerankModel),
+}
+
+#[derive(Clone, Debug, Deserialize, Default, PartialEq, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct FileStore {
     #[serde(default)]
     pub crawl: bool,
+    pub context_packing: Option<ContextPacking>,
 }
 
-const fn n_gpu_layers_default() -> u32 {
-    1000
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, JsonSchema)]
+pub enum ContextSource {
+    // The function, class, etc. the cursor is in
+    #[serde(rename = "current_function")]
+    CurrentFunction,
+    // The definitions next to it, nearest first
+    #[serde(rename = "sibling_symbols")]
+    SiblingSymbols,
+    // The signatures and doc comments of what the file imports, looked up in the open files
+    #[serde(rename = "imported_symbols")]
+    ImportedSymbols,
+    // The signatures of the identifiers around the cursor from the language's `language_servers`
+    #[serde(rename = "language_server")]
+    LanguageServer,
+    // The latest edits across all documents as diff hunks, newest first
+    #[serde(rename = "recent_edits")]
+    RecentEdits,
+    // The chunks found by searching the index, most relevant first
+    #[serde(rename = "retrieved_chunks")]
+    RetrievedChunks,
+    // The other open files, most recently accessed first
+    #[serde(rename = "open_files")]
+    OpenFiles,
+    // The latest commit messages and the staged and unstaged changes of the file's repository
+    #[serde(rename = "git")]
+    Git,
+}
+
+const fn max_recent_edits_default() -> usize {
+    5
+}
+
+const fn max_commits_default() -> usize {
+    5
+}
+
+fn context_priorities_default() -> Vec<ContextSource> {
+    vec![
+        ContextSource::CurrentFunction,
+        ContextSource::SiblingSymbols,
+        ContextSource::ImportedSymbols,
+        ContextSource::LanguageServer,
+        ContextSource::RecentEdits,
+        ContextSource::RetrievedChunks,
+        ContextSource::OpenFiles,
+    ]
+}
+
+// Fills the prompt from the sources in order until `max_context_length` is used up, instead of
+// with the text around the cursor
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ContextPacking {
+    // Sources that are not listed are left out
+    #[serde(default = "context_priorities_default")]
+    pub priorities: Vec<ContextSource>,
+    // How many of the recent edits are packed at most
+    #[serde(default = "max_recent_edits_default")]
+    pub max_recent_edits: usize,
+    // How many commit messages `git` packs at most
+    #[serde(default = "max_commits_default")]
+    pub max_commits: usize,
 }
 
-const fn n_ctx_default() -> u32 {
+const fn n_gpu_layers_default() -> u32 {
     1000
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Ollama {
     // The generate endpoint, default: 'http://localhost:11434/api/generate'
@@ -118,48 +746,177 @@ pub struct Ollama {
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retry: Retry,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct MistralFIM {
     // The auth token env var name
     pub auth_token_env_var_name: Option<String>,
     pub auth_token: Option<String>,
-    // The fim endpoint
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show openai`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
+    // The fim endpoint used for FIM prompts, default: 'https://api.mistral.ai/v1/fim/completions'
     pub fim_endpoint: Option<String>,
+    // The chat endpoint used when `messages` are given, default:
+    // 'https://api.mistral.ai/v1/chat/completions'
+    pub chat_endpoint: Option<String>,
     // The model name
     pub model: String,
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retry: Retry,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct LLaMACPP {
     // Which model to use
     pub repository: Option<String>,
     pub name: Option<String>,
     pub file_path: Option<String>,
+    // Only look for `repository` and `name` in the local Hugging Face cache. Also enabled by
+    // setting the `HF_HUB_OFFLINE` env var to `1` or `true`.
+    #[serde(default)]
+    pub offline: bool,
     // The layers to put on the GPU
     #[serde(default = "n_gpu_layers_default")]
     pub n_gpu_layers: u32,
-    // The context size
-    #[serde(default = "n_ctx_default")]
-    pub n_ctx: u32,
+    // The GPU the model is put on when its layers are not split across GPUs, and the one holding
+    // the scratch buffers when they are
+    #[serde(default)]
+    pub main_gpu: u32,
+    // Uses flash attention which needs less memory for long contexts. Not every GPU backend
+    // supports it.
+    #[serde(default)]
+    pub flash_attention: bool,
+    // The context size, the model's training context size up to 8192 tokens when not set
+    pub n_ctx: Option<u32>,
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    // A small model with the same vocabulary that drafts tokens for this one to check
+    pub draft_model: Option<DraftModel>,
+    // Frees the memory of the model when it was not used for this long, it is loaded again on the
+    // next request
+    pub unload_after_idle_secs: Option<u64>,
+    // Runs inference in a child process that is restarted when it crashes, so a crash in
+    // llama.cpp only fails the requests in flight instead of taking down the server
+    #[serde(default)]
+    pub isolate: bool,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, JsonSchema)]
+pub enum CandleDevice {
+    // CUDA or Metal when lsp-ai is built with them and a GPU is found, the CPU otherwise
+    #[default]
+    #[serde(rename = "auto")]
+    Auto,
+    #[serde(rename = "cpu")]
+    Cpu,
+    #[serde(rename = "cuda")]
+    Cuda,
+    #[serde(rename = "metal")]
+    Metal,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, JsonSchema)]
+pub enum CandleDType {
+    #[serde(rename = "f32")]
+    F32,
+    #[serde(rename = "f16")]
+    F16,
+    #[serde(rename = "bf16")]
+    BF16,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, JsonSchema)]
+pub enum CandleArchitecture {
+    #[serde(rename = "starcoder2")]
+    StarCoder2,
+    #[serde(rename = "qwen2")]
+    Qwen2,
+}
+
+// Runs safetensors models with candle
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Candle {
+    // Which model to use, a Hugging Face repository or a local directory with its
+    // `config.json`, `tokenizer.json` and safetensors weights
+    pub repository: Option<String>,
+    pub revision: Option<String>,
+    pub directory: Option<String>,
+    // Only look for `repository` in the local Hugging Face cache. Also enabled by setting the
+    // `HF_HUB_OFFLINE` env var to `1` or `true`.
+    #[serde(default)]
+    pub offline: bool,
+    // Read from the `model_type` in `config.json` when not set
+    pub architecture: Option<CandleArchitecture>,
+    #[serde(default)]
+    pub device: CandleDevice,
+    // The GPU the model is put on
+    #[serde(default)]
+    pub gpu: usize,
+    // bf16 on CUDA and f32 otherwise when not set
+    pub dtype: Option<CandleDType>,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+}
+
+const fn n_draft_default() -> usize {
+    8
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DraftModel {
+    // Which model to use, found like the main model
+    pub repository: Option<String>,
+    pub name: Option<String>,
+    pub file_path: Option<String>,
+    // The layers to put on the GPU
+    #[serde(default = "n_gpu_layers_default")]
+    pub n_gpu_layers: u32,
+    // How many tokens are drafted at a time
+    #[serde(default = "n_draft_default")]
+    pub n_draft: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct OpenAI {
     // The auth token env var name
     pub auth_token_env_var_name: Option<String>,
     // The auth token
     pub auth_token: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show openai`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
     // The completions endpoint
     pub completions_endpoint: Option<String>,
     // The chat endpoint
@@ -167,82 +924,1127 @@ pub struct OpenAI {
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retry: Retry,
     // The model name
     pub model: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+fn azure_api_version_default() -> String {
+    "2024-02-01".to_string()
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, JsonSchema)]
+pub enum OpenAICompatiblePreset {
+    #[serde(rename = "groq")]
+    Groq,
+    #[serde(rename = "together")]
+    Together,
+    #[serde(rename = "fireworks")]
+    Fireworks,
+    #[serde(rename = "open_router")]
+    OpenRouter,
+    #[serde(rename = "xai")]
+    XAI,
+    #[serde(rename = "deepseek")]
+    DeepSeek,
+    #[serde(rename = "moonshot")]
+    Moonshot,
+}
+
+impl OpenAICompatiblePreset {
+    pub fn base_url(&self) -> &'static str {
+        match self {
+            Self::Groq => "https://api.groq.com/openai/v1",
+            Self::Together => "https://api.together.xyz/v1",
+            Self::Fireworks => "https://api.fireworks.ai/inference/v1",
+            Self::OpenRouter => "https://openrouter.ai/api/v1",
+            Self::XAI => "https://api.x.ai/v1",
+            Self::DeepSeek => "https://api.deepseek.com/v1",
+            Self::Moonshot => "https://api.moonshot.ai/v1",
+        }
+    }
+
+    pub fn auth_token_env_var_name(&self) -> &'static str {
+        match self {
+            Self::Groq => "GROQ_API_KEY",
+            Self::Together => "TOGETHER_API_KEY",
+            Self::Fireworks => "FIREWORKS_API_KEY",
+            Self::OpenRouter => "OPENROUTER_API_KEY",
+            Self::XAI => "XAI_API_KEY",
+            Self::DeepSeek => "DEEPSEEK_API_KEY",
+            Self::Moonshot => "MOONSHOT_API_KEY",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
 #[serde(deny_unknown_fields)]
-pub struct Anthropic {
-    // The auth token env var name
+pub struct OpenAICompatible {
+    // Fills in the base url and the auth token env var name of a known provider
+    pub preset: Option<OpenAICompatiblePreset>,
+    // The url the `/completions` and `/chat/completions` endpoints are under, e.g.
+    // 'http://localhost:8000/v1'. Overrides the one from the preset.
+    pub base_url: Option<String>,
+    // The header the token is sent in, e.g. 'x-api-key'. Uses bearer auth when not set.
+    pub auth_header_name: Option<String>,
+    // The auth token env var name, default: the one from the preset
     pub auth_token_env_var_name: Option<String>,
+    // The auth token
     pub auth_token: Option<String>,
-    // The completions endpoint
-    pub completions_endpoint: Option<String>,
-    // The chat endpoint
-    pub chat_endpoint: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show openai`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retry: Retry,
     // The model name
     pub model: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct Completion {
-    // The model key to use
-    pub model: String,
-    // Args are deserialized by the backend using them
-    #[serde(default)]
-    pub parameters: Kwargs,
-    // Parameters for post processing
-    #[serde(default)]
-    pub post_process: PostProcess,
-}
-
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
 #[serde(deny_unknown_fields)]
-pub struct ValidConfig {
+pub struct AzureOpenAI {
+    // The auth token env var name
+    pub auth_token_env_var_name: Option<String>,
+    // The auth token
+    pub auth_token: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show openai`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
+    // The resource endpoint, e.g. 'https://my-resource.openai.azure.com'
+    pub endpoint: String,
+    // The name of the deployment to use
+    pub deployment: String,
+    // The REST API version
+    #[serde(default = "azure_api_version_default")]
+    pub api_version: String,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retry: Retry,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Anthropic {
+    // The auth token env var name
+    pub auth_token_env_var_name: Option<String>,
+    pub auth_token: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show openai`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
+    // The completions endpoint
+    pub completions_endpoint: Option<String>,
+    // The chat endpoint
+    pub chat_endpoint: Option<String>,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retry: Retry,
+    // The model name
+    pub model: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Gemini {
+    // The auth token env var name
+    pub auth_token_env_var_name: Option<String>,
+    // The auth token
+    pub auth_token: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show openai`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
+    // The base endpoint, default: 'https://generativelanguage.googleapis.com/v1beta'
+    pub endpoint: Option<String>,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retry: Retry,
+    // The model name
+    pub model: String,
+}
+
+// A program lsp-ai starts that answers requests as JSON lines on stdin and stdout
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Command {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Set for the program on top of the env of lsp-ai
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    // Whether the program runs the model on this machine, prompts sent to other machines have
+    // secrets redacted when redaction is enabled
+    #[serde(default)]
+    pub local: bool,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+}
+
+// Hugging Face text-generation-inference
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TGI {
+    // The server, default: 'http://localhost:8080'
+    pub endpoint: Option<String>,
+    // Only needed for servers behind auth like Inference Endpoints
+    pub auth_token_env_var_name: Option<String>,
+    pub auth_token: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show tgi`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
+    // Whether the server runs on this machine, prompts sent to other machines have secrets
+    // redacted when redaction is enabled
+    #[serde(default)]
+    pub local: bool,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retry: Retry,
+}
+
+// The Chat API of Cohere
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Cohere {
+    // The auth token env var name
+    pub auth_token_env_var_name: Option<String>,
+    pub auth_token: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show cohere`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
+    // The chat endpoint, default: 'https://api.cohere.com/v2/chat'
+    pub chat_endpoint: Option<String>,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retry: Retry,
+    // The model name, e.g. 'command-r-plus'
+    pub model: String,
+}
+
+// The OpenAI compatible server of vLLM. Takes the guided decoding parameters and keeps the start
+// of prompts the same between requests so the prefix cache of the server is hit.
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VLLM {
+    // The url the `/completions` and `/chat/completions` endpoints are under, default:
+    // 'http://localhost:8000/v1'
+    pub base_url: Option<String>,
+    // Only needed for servers started with `--api-key`
+    pub auth_token_env_var_name: Option<String>,
+    pub auth_token: Option<String>,
+    // Read from the OS keychain
+    pub auth_token_keyring: Option<KeyringEntry>,
+    // A command like `pass show vllm`, the first line it prints is the token
+    pub auth_token_command: Option<String>,
+    // Whether the server runs on this machine, prompts sent to other machines have secrets
+    // redacted when redaction is enabled
+    #[serde(default)]
+    pub local: bool,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub max_requests_per_second: f32,
+    // How many requests are sent to the model at once, the rest wait. Unlimited when not set.
+    pub max_concurrent_requests: Option<usize>,
+    // Replaces the timeout of the kind of request for this model
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retry: Retry,
+    // The model name the server was started with
+    pub model: String,
+}
+
+const fn max_entries_default() -> usize {
+    128
+}
+
+const fn ttl_secs_default() -> u64 {
+    300
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CompletionCache {
+    // The number of completions kept, 0 disables the cache
+    #[serde(default = "max_entries_default")]
+    pub max_entries: usize,
+    // How long a completion is reused for
+    #[serde(default = "ttl_secs_default")]
+    pub ttl_secs: u64,
+}
+
+impl Default for CompletionCache {
+    fn default() -> Self {
+        Self {
+            max_entries: max_entries_default(),
+            ttl_secs: ttl_secs_default(),
+        }
+    }
+}
+
+const fn max_queued_requests_default() -> usize {
+    4
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct Completion {
+    // The model key to use
+    pub model: String,
+    // Args are deserialized by the backend using them
+    #[serde(default)]
+    pub parameters: Kwargs,
+    // Parameters for post processing
+    #[serde(default)]
+    pub post_process: PostProcess,
+    // How long completion requests wait for a newer request for the same document before they
+    // are sent to the model
+    #[serde(default)]
+    pub debounce_ms: u64,
+    // Reuses completions for prompts that were already sent to the model
+    #[serde(default)]
+    pub cache: CompletionCache,
+    // How many completion requests for different documents wait to be sent, the oldest is
+    // dropped when more arrive
+    #[serde(default = "max_queued_requests_default")]
+    pub max_queued_requests: usize,
+    // Completions are only requested where these allow them, everywhere when not set
+    pub rules: Option<CompletionRules>,
+    // Leaves out completions the model is not confident about
+    pub confidence: Option<ConfidenceFilter>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ConfidenceFilter {
+    // The lowest mean log probability of the tokens of a completion, e.g. `-1.0`. Only backends
+    // that score their completions, OpenAI compatible APIs and llama.cpp, are filtered.
+    pub min_logprob: f32,
+    #[serde(default)]
+    pub action: LowConfidenceAction,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, JsonSchema)]
+pub enum LowConfidenceAction {
+    // Nothing is shown rather than a bad completion
+    #[default]
+    #[serde(rename = "drop")]
+    Drop,
+    // Listed after the other completions
+    #[serde(rename = "demote")]
+    Demote,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CompletionRules {
+    // The languageIds completions are requested in, all of them when empty
+    #[serde(default)]
+    pub language_ids: Vec<String>,
+    #[serde(default)]
+    pub exclude_language_ids: Vec<String>,
+    // Globs of the documents completions are requested in, all of them when empty. Globs without
+    // a `/` are matched against the file name, e.g. `.env` or `COMMIT_EDITMSG`, the others
+    // against the whole path.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    // How many characters other than leading whitespace the line needs before the cursor
+    #[serde(default)]
+    pub min_prefix_length: usize,
+    // Whether completions are requested with the cursor in a comment or a string. Only known
+    // for languages with a tree-sitter grammar.
+    #[serde(default)]
+    pub in_comments: bool,
+    #[serde(default)]
+    pub in_strings: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, JsonSchema)]
+pub enum RequestKind {
+    #[serde(rename = "completion")]
+    Completion,
+    // Both textDocument/generation and textDocument/generationStream
+    #[serde(rename = "generation")]
+    Generation,
+    // lsp-ai/agent, the whole run with every model call and tool
+    #[serde(rename = "agent")]
+    Agent,
+}
+
+const fn completion_timeout_ms_default() -> u64 {
+    2000
+}
+
+const fn generation_timeout_ms_default() -> u64 {
+    60_000
+}
+
+const fn agent_timeout_ms_default() -> u64 {
+    300_000
+}
+
+// By kind of request, models can set their own `request_timeout_ms`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RequestTimeouts {
+    // Completions and inline completions
+    #[serde(default = "completion_timeout_ms_default")]
+    pub completion_ms: u64,
+    // Generations, chat and code actions
+    #[serde(default = "generation_timeout_ms_default")]
+    pub generation_ms: u64,
+    #[serde(default = "agent_timeout_ms_default")]
+    pub agent_ms: u64,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            completion_ms: completion_timeout_ms_default(),
+            generation_ms: generation_timeout_ms_default(),
+            agent_ms: agent_timeout_ms_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    // The languageIds the route applies to, all of them when empty
+    #[serde(default)]
+    pub language_ids: Vec<String>,
+    // The kind of request the route applies to, all of them when not set
+    pub request: Option<RequestKind>,
+    // What the cursor is in, e.g. a comment for prose-style completions, anywhere when not set
+    pub region: Option<CursorRegion>,
+    // The model key to use
+    pub model: String,
+    // Replaces the completion parameters as different models usually need different ones
+    pub parameters: Option<Kwargs>,
+}
+
+impl Route {
+    fn matches(
+        &self,
+        request: RequestKind,
+        language_id: Option<&str>,
+        region: CursorRegion,
+    ) -> bool {
+        self.request.map_or(true, |r| r == request)
+            && self.region.map_or(true, |r| r == region)
+            && (self.language_ids.is_empty()
+                || language_id.is_some_and(|l| self.language_ids.iter().any(|id| id == l)))
+    }
+}
+
+// Only known for languages with a tree-sitter grammar, the cursor is in code for the others
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, JsonSchema)]
+pub enum CursorRegion {
+    #[serde(rename = "code")]
+    Code,
+    #[serde(rename = "comment")]
+    Comment,
+    #[serde(rename = "string")]
+    String,
+}
+
+const fn entropy_threshold_default() -> Option<f32> {
+    Some(4.)
+}
+
+// A regex compiled when the config is parsed so invalid ones are reported right away
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern(pub regex::Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        regex::Regex::new(&pattern).map(Self)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Redaction {
+    // Regexes redacted on top of the built in ones. When a pattern has a capture group only the
+    // group is redacted.
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub patterns: Vec<Pattern>,
+    // Long tokens with more bits of Shannon entropy per character are redacted. Set to null to
+    // only use the patterns.
+    #[serde(default = "entropy_threshold_default")]
+    pub entropy_threshold: Option<f32>,
+}
+
+const fn fuel_default() -> u64 {
+    100_000_000
+}
+
+const fn max_memory_mb_default() -> usize {
+    64
+}
+
+// A WebAssembly module exporting `memory`, `alloc` and any of the hooks `pre_prompt`,
+// `post_completion` and `context_filter`
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Plugin {
+    // The `.wasm` file
+    pub path: String,
+    // Roughly the instructions a hook may run before it is stopped
+    #[serde(default = "fuel_default")]
+    pub fuel: u64,
+    // The memory a hook may use, it fails when it grows past it
+    #[serde(default = "max_memory_mb_default")]
+    pub max_memory_mb: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Http {
+    // e.g. 'http://proxy.corp:3128', default: the `HTTPS_PROXY`, `HTTP_PROXY` or `ALL_PROXY` env var
+    pub proxy: Option<String>,
+    // Hosts, domains and IP ranges not sent through the proxy, default: the `NO_PROXY` env var
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    // A PEM file of root certificates trusted on top of the system ones
+    pub ca_cert_path: Option<String>,
+}
+
+// Prices in the currency of the provider's price list
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Pricing {
+    pub prompt_per_million_tokens: f64,
+    pub completion_per_million_tokens: f64,
+}
+
+// Requests over a budget fail so the model's fallbacks, e.g. a local model, are used instead
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    pub max_requests_per_minute: Option<usize>,
+    // Compared against today's cost of the model, which needs its pricing
+    pub daily_spend_cap: Option<f64>,
+}
+
+const fn log_interval_secs_default() -> u64 {
+    3600
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UsageConfig {
+    // Keyed by model key, models without pricing are tracked at no cost
+    #[serde(default)]
+    pub pricing: HashMap<String, Pricing>,
+    // Keyed by model key
+    #[serde(default)]
+    pub budgets: HashMap<String, Budget>,
+    // How often the totals are logged, 0 disables the log lines
+    #[serde(default = "log_interval_secs_default")]
+    pub log_interval_secs: u64,
+}
+
+const fn max_size_mb_default() -> u64 {
+    10
+}
+
+const fn max_files_default() -> usize {
+    3
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Log {
+    // Defaults to a file per workspace in the lsp-ai data directory
+    pub path: Option<String>,
+    // The file is rotated when it grows past this
+    #[serde(default = "max_size_mb_default")]
+    pub max_size_mb: u64,
+    // The number of rotated files kept next to the log file
+    #[serde(default = "max_files_default")]
+    pub max_files: usize,
+    // Filter directives like `LSP_AI_LOG`, e.g. 'lsp_ai=debug', default: the `LSP_AI_LOG` env var
+    pub level: Option<String>,
+}
+
+fn service_name_default() -> String {
+    "lsp-ai".to_string()
+}
+
+fn otlp_level_default() -> String {
+    "info".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Otlp {
+    // The OTLP/HTTP traces endpoint, e.g. 'http://localhost:4318/v1/traces'
+    pub endpoint: String,
+    // Sent with every export, e.g. for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default = "service_name_default")]
+    pub service_name: String,
+    // Filter directives for the exported spans, independent of the log level
+    #[serde(default = "otlp_level_default")]
+    pub level: String,
+}
+
+fn metrics_host_default() -> String {
+    "127.0.0.1".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Metrics {
+    // Only reachable from this machine by default
+    #[serde(default = "metrics_host_default")]
+    pub host: String,
+    pub port: u16,
+}
+
+const fn max_history_default() -> usize {
+    20
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ChatConfig {
+    // The model key to use when the request does not pick one
+    pub model: String,
+    // Args are deserialized by the backend using them. The messages are sent before the
+    // conversation, `{CONTEXT}` and `{CODE}` in them are filled in from the memory backend when
+    // the request has a position.
+    #[serde(default)]
+    pub parameters: Kwargs,
+    // Where conversations are saved, defaults to the lsp-ai data directory
+    pub history_dir: Option<String>,
+    // The number of previous messages sent with each request
+    #[serde(default = "max_history_default")]
+    pub max_history: usize,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Default, JsonSchema)]
+pub enum ActionOutput {
+    // Replaces the selection with the generated text
+    #[default]
+    #[serde(rename = "replace")]
+    Replace,
+    // Inserts the generated text on the lines above the selection
+    #[serde(rename = "insert_before")]
+    InsertBefore,
+    // Shows the generated text with window/showMessage
+    #[serde(rename = "message")]
+    Message,
+    // Adds the generated tests to the test file the language's test convention points to
+    #[serde(rename = "tests")]
+    Tests,
+    // Offered as a quick fix for diagnostics and replaces the lines they are on.
+    // `{DIAGNOSTICS}` in the messages is replaced with the diagnostic messages.
+    #[serde(rename = "fix")]
+    Fix,
+    // Applies the unified diff the model answers with to the document. Instructions for the
+    // format are added to the system message.
+    #[serde(rename = "diff")]
+    Diff,
+}
+
+const fn max_identifiers_default() -> usize {
+    5
+}
+
+const fn language_server_timeout_ms_default() -> u64 {
+    500
+}
+
+// A language server lsp-ai starts and is the client of, e.g. `rust-analyzer`
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageServer {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub initialization_options: Option<Value>,
+    // How many identifiers around the cursor are looked up, nearest first
+    #[serde(default = "max_identifiers_default")]
+    pub max_identifiers: usize,
+    // How long the lookups for one prompt may take
+    #[serde(default = "language_server_timeout_ms_default")]
+    pub timeout_ms: u64,
+}
+
+// A Model Context Protocol server started as a subprocess that talks over its stdin and stdout
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct McpStdioServer {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Set for the server on top of the env of lsp-ai
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+// A Model Context Protocol server lsp-ai connects to with server-sent events
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct McpSseServer {
+    // The endpoint of the event stream, e.g. `http://localhost:8000/sse`
+    pub url: String,
+    // Sent with every request, e.g. `{"Authorization": "Bearer ${TOKEN}"}`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(tag = "type")]
+pub enum McpServer {
+    #[serde(rename = "stdio")]
+    Stdio(McpStdioServer),
+    #[serde(rename = "sse")]
+    Sse(McpSseServer),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TestConvention {
+    // The test file relative to the source file. `{name}` and `{ext}` are replaced with the
+    // source file's name and extension. Tests go in the source file when not set.
+    pub path: Option<String>,
+    // The line opening the test module, e.g. `mod tests {`. When the test file has one the tests
+    // are inserted before the module's closing brace instead of at the end of the file.
+    pub module: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Action {
+    // Shown in the editor's code action menu
+    pub title: String,
+    // The model key to use
+    pub model: String,
+    // Args are deserialized by the backend using them. `{CODE}` in the messages is replaced with
+    // the selection and `{CONTEXT}` with the document it is in. With backends that support tools,
+    // `tools` lists the tools the model may call before answering, e.g. `read_file` and
+    // `search_workspace`, and `max_tool_steps` how many times.
+    #[serde(default)]
+    pub parameters: Kwargs,
+    #[serde(default)]
+    pub output: ActionOutput,
+}
+
+// Writes commit messages for the staged changes with lsp-ai/generateCommitMessage
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CommitMessage {
+    // The model key to use
+    pub model: String,
+    // Args are deserialized by the backend using them. `{CODE}` in the messages is replaced with
+    // the staged diff and `{CONTEXT}` with the latest commit messages. Messages asking for a
+    // conventional commit are used when none are set.
+    #[serde(default)]
+    pub parameters: Kwargs,
+    // How many of the latest commit messages are in `{CONTEXT}`
+    #[serde(default = "max_commits_default")]
+    pub max_commits: usize,
+}
+
+const fn max_steps_default() -> usize {
+    10
+}
+
+// Runs goals with lsp-ai/agent, the model calling tools to read and search the workspace and to
+// propose edits
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    // The model key to use when the request does not pick one
+    pub model: String,
+    // Args are deserialized by the backend using them. `{CODE}` in the messages is replaced with
+    // the goal and `{CONTEXT}` with the workspace folders and the document of the request.
+    // Messages for a coding agent are used when none are set.
+    #[serde(default)]
+    pub parameters: Kwargs,
+    // The tools the model may call, all of the built-in ones and the ones of the `mcp_servers`
+    // when empty
+    #[serde(default)]
+    pub tools: Vec<String>,
+    // How many responses may call tools before the model has to answer
+    #[serde(default = "max_steps_default")]
+    pub max_steps: usize,
+    // Once the responses used this many tokens the model has to answer
+    pub max_tokens: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ValidConfig {
     pub memory: ValidMemoryBackend,
     pub models: HashMap<String, ValidModel>,
     pub completion: Option<Completion>,
+    // Checked in order, requests no route matches use the model from the completion config or
+    // the request itself
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    // The models a request is retried with, in order, when the model it was sent to fails
+    #[serde(default)]
+    pub fallback: HashMap<String, Vec<String>>,
+    // Offered as code actions on selections
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    // Where generated tests go, keyed by languageId
+    #[serde(default)]
+    pub test_conventions: HashMap<String, TestConvention>,
+    pub chat: Option<ChatConfig>,
+    pub commit_message: Option<CommitMessage>,
+    pub agent: Option<AgentConfig>,
+    // Redacts secrets from prompts sent to remote backends
+    pub redaction: Option<Redaction>,
+    // Proxy and certificates for requests to the models and embedding APIs
+    pub http: Option<Http>,
+    // Token usage is always tracked, this adds costs and periodic log lines
+    pub usage: Option<UsageConfig>,
+    // Writes JSON log lines to a rotating file on top of stderr
+    pub log: Option<Log>,
+    // Exports the tracing spans, e.g. the time spent building prompts and in the models
+    pub otlp: Option<Otlp>,
+    // Serves Prometheus metrics on http://host:port/metrics
+    pub metrics: Option<Metrics>,
+    // How long requests to the models may take before they are cancelled
+    #[serde(default)]
+    pub request_timeouts: RequestTimeouts,
+    // Asked about the identifiers around the cursor, keyed by languageId
+    #[serde(default)]
+    pub language_servers: HashMap<String, LanguageServer>,
+    // Their tools can be called by the agent and actions as `{server}__{tool}`, or all of them
+    // with the name of the server
+    #[serde(default)]
+    pub mcp_servers: HashMap<String, McpServer>,
+    // Run in order at the hooks they export
+    #[serde(default)]
+    pub plugins: Vec<Plugin>,
+    // Lets the workspace config file set every option, by default it may only set the ones that
+    // cannot run commands, read secrets or lift limits, and its prompt files must be in the
+    // workspace
+    #[serde(default)]
+    pub trust_workspace_config: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Default)]
 pub struct ValidClientParams {
-    #[serde(alias = "rootURI")]
-    _root_uri: Option<String>,
-    _workspace_folders: Option<Vec<String>>,
+    #[serde(alias = "rootUri", alias = "rootURI")]
+    root_uri: Option<String>,
+    #[serde(alias = "workspaceFolders")]
+    workspace_folders: Option<Vec<lsp_types::WorkspaceFolder>>,
+}
+
+// Expands `${NAME}` to the value of the env var `NAME`, `$${` is a literal `${`
+fn interpolate_string(text: &str, path: &str) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('$') {
+        result.push_str(&rest[..index]);
+        rest = &rest[index..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .with_context(|| format!("unclosed `${{` in `{path}`"))?;
+            let name = &after[..end];
+            let value = std::env::var(name)
+                .with_context(|| format!("the env var `{name}` used in `{path}` is not set"))?;
+            result.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+// Interpolates env vars in every string of the configuration, the path is used in errors
+fn interpolate_env_vars(value: &mut Value, path: &str) -> Result<()> {
+    match value {
+        Value::String(text) if text.contains('$') => *text = interpolate_string(text, path)?,
+        Value::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                interpolate_env_vars(value, &format!("{path}[{i}]"))?;
+            }
+        }
+        Value::Object(values) => {
+            for (key, value) in values.iter_mut() {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                interpolate_env_vars(value, &path)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+// Errors name the option that is wrong instead of only the serde error
+fn parse(options: Value) -> Result<ValidConfig> {
+    serde_path_to_error::deserialize(options).map_err(|e| match e.path().to_string() {
+        path if path == "." => anyhow::anyhow!("invalid configuration: {}", e.inner()),
+        path => anyhow::anyhow!("invalid configuration at `{path}`: {}", e.inner()),
+    })
+}
+
+// The JSON Schema of the initializationOptions, printed by `--emit-config-schema`
+pub fn get_schema() -> Result<Value> {
+    Ok(serde_json::to_value(schemars::schema_for!(ValidConfig))?)
+}
+
+// Checks a `.lsp-ai.toml` / `.lsp-ai.json` or a JSON file of initializationOptions, used by
+// `--validate-config`
+pub fn validate_file(path: &Path) -> Result<()> {
+    let mut options = workspace_config::load(path)?;
+    interpolate_env_vars(&mut options, "")?;
+    parse(options)?;
+    Ok(())
 }
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub config: ValidConfig,
-    _client_params: ValidClientParams,
+    client_params: ValidClientParams,
+    // The options the client sent, kept so the workspace config can be merged in again on reload
+    user_options: Value,
 }
 
 impl Config {
     pub fn new(mut args: Value) -> Result<Self> {
-        // Validate that the models specfied are there so we can unwrap
-        let configuration_args = args
+        let user_options = args
             .as_object_mut()
             .context("Server configuration must be a JSON object")?
-            .remove("initializationOptions");
-        let valid_args = match configuration_args {
-            Some(configuration_args) => serde_json::from_value(configuration_args)?,
-            None => anyhow::bail!("lsp-ai does not currently provide a default configuration. Please pass a configuration. See https://github.com/SilasMarvin/lsp-ai for configuration options and examples"),
-        };
+            .remove("initializationOptions")
+            .unwrap_or(Value::Null);
         let client_params: ValidClientParams = serde_json::from_value(args)?;
+        Self::build(user_options, client_params)
+    }
+
+    // The workspace config file overrides the options sent by the client. Env vars are only
+    // interpolated in the options of the client, a workspace file could otherwise read them.
+    fn build(user_options: Value, client_params: ValidClientParams) -> Result<Self> {
+        let mut interpolated = user_options.clone();
+        interpolate_env_vars(&mut interpolated, "")?;
+        let mut options = interpolated.clone();
+        let workspace_path = client_params
+            .root_uri
+            .as_deref()
+            .and_then(workspace_config::find);
+        if let Some(path) = &workspace_path {
+            match workspace_config::load(path) {
+                Ok(workspace_options) => {
+                    let workspace_options = workspace_config::filter(
+                        path,
+                        workspace_options,
+                        workspace_config::is_trusted(&interpolated),
+                    );
+                    if options.is_null() {
+                        options = workspace_options;
+                    } else {
+                        workspace_config::merge(&mut options, workspace_options);
+                    }
+                }
+                Err(e) => error!("ignoring {}: {e:#}", path.display()),
+            }
+        }
+        if options.is_null() {
+            anyhow::bail!("lsp-ai does not currently provide a default configuration. Please pass a configuration. See https://github.com/SilasMarvin/lsp-ai for configuration options and examples")
+        }
+        // Validate that the models specfied are there so we can unwrap
+        let config = match (parse(options), &workspace_path) {
+            (Ok(config), _) => config,
+            // A workspace file that breaks the configuration is ignored
+            (Err(e), Some(path)) if !interpolated.is_null() => {
+                error!("ignoring {}: {e:#}", path.display());
+                parse(interpolated)?
+            }
+            (Err(e), _) => return Err(e),
+        };
         Ok(Self {
-            config: valid_args,
-            _client_params: client_params,
+            config,
+            client_params,
+            user_options,
         })
     }
 
+    // Builds the configuration sent with workspace/didChangeConfiguration. Clients either send
+    // the options themselves or nest them under `lsp-ai`.
+    pub fn update(&self, mut settings: Value) -> Result<Self> {
+        let settings = match settings.get_mut("lsp-ai") {
+            Some(settings) => settings.take(),
+            None => settings,
+        };
+        Self::build(settings, self.client_params.clone())
+    }
+
+    // Rebuilds the configuration after the workspace config file changed
+    pub fn reload(&self) -> Result<Self> {
+        Self::build(self.user_options.clone(), self.client_params.clone())
+    }
+
     ///////////////////////////////////////
     // Helpers for the backends ///////////
     ///////////////////////////////////////
 
+    pub fn get_root_uri(&self) -> Option<&str> {
+        self.client_params.root_uri.as_deref()
+    }
+
+    // The roots of the workspace, the rootUri when the client did not send any workspaceFolders
+    pub fn get_workspace_folders(&self) -> Vec<String> {
+        match &self.client_params.workspace_folders {
+            Some(folders) => folders.iter().map(|f| f.uri.to_string()).collect(),
+            None => self.get_root_uri().into_iter().map(str::to_owned).collect(),
+        }
+    }
+
+    pub fn change_workspace_folders(&mut self, event: &lsp_types::WorkspaceFoldersChangeEvent) {
+        let root = self
+            .get_root_uri()
+            .and_then(|uri| lsp_types::Url::parse(uri).ok());
+        let folders = self.client_params.workspace_folders.get_or_insert_with(|| {
+            root.into_iter()
+                .map(|uri| lsp_types::WorkspaceFolder {
+                    name: uri.path().to_owned(),
+                    uri,
+                })
+                .collect()
+        });
+        folders.retain(|folder| !event.removed.iter().any(|f| f.uri == folder.uri));
+        for folder in &event.added {
+            if !folders.iter().any(|f| f.uri == folder.uri) {
+                folders.push(folder.clone());
+            }
+        }
+    }
+
+    pub fn get_language_servers(&self) -> &HashMap<String, LanguageServer> {
+        &self.config.language_servers
+    }
+
+    pub fn get_mcp_servers(&self) -> &HashMap<String, McpServer> {
+        &self.config.mcp_servers
+    }
+
+    pub fn get_plugins(&self) -> &[Plugin] {
+        &self.config.plugins
+    }
+
+    pub fn has_routes(&self) -> bool {
+        !self.config.routes.is_empty()
+    }
+
+    pub fn has_region_routes(&self) -> bool {
+        self.config
+            .routes
+            .iter()
+            .any(|route| route.region.is_some())
+    }
+
+    pub fn get_route(
+        &self,
+        request: RequestKind,
+        language_id: Option<&str>,
+        region: CursorRegion,
+    ) -> Option<&Route> {
+        self.config
+            .routes
+            .iter()
+            .find(|route| route.matches(request, language_id, region))
+    }
+
+    pub fn get_fallbacks(&self, model: &str) -> &[String] {
+        self.config
+            .fallback
+            .get(model)
+            .map(|fallbacks| fallbacks.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn get_action(&self, title: &str) -> Option<&Action> {
+        self.config
+            .actions
+            .iter()
+            .find(|action| action.title == title)
+    }
+
+    pub fn get_test_convention(&self, language_id: Option<&str>) -> Option<&TestConvention> {
+        self.config.test_conventions.get(language_id?)
+    }
+
     pub fn is_completions_enabled(&self) -> bool {
         self.config.completion.is_some()
     }
@@ -251,6 +2053,59 @@ impl Config {
         self.config.completion.as_ref().map(|x| &x.post_process)
     }
 
+    pub fn get_completion_rules(&self) -> Option<&CompletionRules> {
+        self.config.completion.as_ref()?.rules.as_ref()
+    }
+
+    pub fn get_completion_confidence(&self) -> Option<&ConfidenceFilter> {
+        self.config.completion.as_ref()?.confidence.as_ref()
+    }
+
+    pub fn get_completion_cache(&self) -> Option<&CompletionCache> {
+        self.config.completion.as_ref().map(|x| &x.cache)
+    }
+
+    pub fn get_pricing(&self, model: &str) -> Option<Pricing> {
+        self.config.usage.as_ref()?.pricing.get(model).copied()
+    }
+
+    pub fn get_budget(&self, model: &str) -> Option<Budget> {
+        self.config.usage.as_ref()?.budgets.get(model).copied()
+    }
+
+    pub fn get_usage_log_interval(&self) -> Option<Duration> {
+        match self.config.usage.as_ref()?.log_interval_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn get_request_timeout(&self, model: &str, kind: RequestKind) -> Duration {
+        let timeouts = &self.config.request_timeouts;
+        Duration::from_millis(
+            self.config
+                .models
+                .get(model)
+                .and_then(ValidModel::request_timeout_ms)
+                .unwrap_or(match kind {
+                    RequestKind::Completion => timeouts.completion_ms,
+                    RequestKind::Generation => timeouts.generation_ms,
+                    RequestKind::Agent => timeouts.agent_ms,
+                }),
+        )
+    }
+
+    pub fn get_completion_max_queued_requests(&self) -> usize {
+        self.config
+            .completion
+            .as_ref()
+            .map_or(max_queued_requests_default(), |x| x.max_queued_requests)
+    }
+
+    pub fn get_completion_debounce(&self) -> Duration {
+        Duration::from_millis(self.config.completion.as_ref().map_or(0, |x| x.debounce_ms))
+    }
+
     pub fn get_completion_transformer_max_requests_per_second(&self) -> anyhow::Result<f32> {
         match &self
             .config
@@ -271,40 +2126,302 @@ impl Config {
             })? {
             #[cfg(feature = "llama_cpp")]
             ValidModel::LLaMACPP(llama_cpp) => Ok(llama_cpp.max_requests_per_second),
+            #[cfg(feature = "candle")]
+            ValidModel::Candle(candle) => Ok(candle.max_requests_per_second),
             ValidModel::OpenAI(open_ai) => Ok(open_ai.max_requests_per_second),
+            ValidModel::AzureOpenAI(azure_open_ai) => Ok(azure_open_ai.max_requests_per_second),
+            ValidModel::OpenAICompatible(open_ai_compatible) => {
+                Ok(open_ai_compatible.max_requests_per_second)
+            }
             ValidModel::Anthropic(anthropic) => Ok(anthropic.max_requests_per_second),
             ValidModel::MistralFIM(mistral_fim) => Ok(mistral_fim.max_requests_per_second),
             ValidModel::Ollama(ollama) => Ok(ollama.max_requests_per_second),
+            ValidModel::Gemini(gemini) => Ok(gemini.max_requests_per_second),
+            ValidModel::Command(command) => Ok(command.max_requests_per_second),
+            ValidModel::TGI(tgi) => Ok(tgi.max_requests_per_second),
+            ValidModel::VLLM(vllm) => Ok(vllm.max_requests_per_second),
+            ValidModel::Cohere(cohere) => Ok(cohere.max_requests_per_second),
+        }
+    }
+}
+
+// This makes testing much easier.
+#[cfg(test)]
+impl Config {
+    pub fn default_with_file_store_without_models() -> Self {
+        Self {
+            config: ValidConfig {
+                memory: ValidMemoryBackend::FileStore(FileStore::default()),
+                models: HashMap::new(),
+                completion: None,
+                routes: vec![],
+                fallback: HashMap::new(),
+                actions: vec![],
+                test_conventions: HashMap::new(),
+                chat: None,
+                commit_message: None,
+                agent: None,
+                redaction: None,
+                http: None,
+                usage: None,
+                log: None,
+                otlp: None,
+                metrics: None,
+                request_timeouts: RequestTimeouts::default(),
+                language_servers: HashMap::new(),
+                mcp_servers: HashMap::new(),
+                plugins: vec![],
+                trust_workspace_config: false,
+            },
+            client_params: ValidClientParams {
+                root_uri: None,
+                workspace_folders: None,
+            },
+            user_options: Value::Null,
         }
     }
-}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    #[cfg(feature = "llama_cpp")]
+    fn llama_cpp_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "llama_cpp",
+                        "repository": "TheBloke/deepseek-coder-6.7B-instruct-GGUF",
+                        "name": "deepseek-coder-6.7b-instruct.Q5_K_S.gguf",
+                        "n_ctx": 2048,
+                        "n_gpu_layers": 35
+                    }
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {
+                        "fim": {
+                            "start": "<fim_prefix>",
+                            "middle": "<fim_suffix>",
+                            "end": "<fim_middle>"
+                        },
+                        "max_context": 1024,
+                        "max_new_tokens": 32,
+                    }
+                }
+            }
+        });
+        Config::new(args).unwrap();
+    }
+
+    #[test]
+    fn ollama_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "ollama",
+                        "model": "llama3"
+                    }
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {
+                        "max_context": 1024,
+                        "options": {
+                            "num_predict": 32
+                        }
+                    },
+                    "post_process": {
+                        "remove_duplicate_start": true,
+                        "remove_duplicate_end": true,
+                    }
+                }
+            }
+        });
+        Config::new(args).unwrap();
+    }
+
+    #[test]
+    fn open_ai_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "open_ai",
+                        "completions_endpoint": "https://api.fireworks.ai/inference/v1/completions",
+                        "model": "accounts/fireworks/models/llama-v2-34b-code",
+                        "auth_token_env_var_name": "FIREWORKS_API_KEY",
+                    },
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {
+                        "messages": [
+                            {
+                                "role": "system",
+                                "content": "Test",
+                            },
+                            {
+                                "role": "user",
+                                "content": "Test {CONTEXT} - {CODE}"
+                            }
+                        ],
+                        "max_new_tokens": 32,
+                    }
+                }
+            }
+        });
+        Config::new(args).unwrap();
+    }
+
+    #[test]
+    fn anthropic_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "anthropic",
+                        "completions_endpoint": "https://api.anthropic.com/v1/messages",
+                        "model": "claude-3-haiku-20240307",
+                        "auth_token_env_var_name": "ANTHROPIC_API_KEY",
+                    },
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {
+                        "system": "Test",
+                        "messages": [
+                            {
+                                "role": "user",
+                                "content": "Test {CONTEXT} - {CODE}"
+                            }
+                        ],
+                        "max_new_tokens": 32,
+                    }
+                }
+            }
+        });
+        Config::new(args).unwrap();
+    }
+
+    #[test]
+    fn gemini_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "gemini",
+                        "model": "gemini-1.5-flash",
+                        "auth_token_env_var_name": "GEMINI_API_KEY",
+                    },
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {
+                        "system_instruction": "Test",
+                        "messages": [
+                            {
+                                "role": "user",
+                                "content": "Test {CONTEXT} - {CODE}"
+                            }
+                        ],
+                        "max_tokens": 32,
+                    }
+                }
+            }
+        });
+        Config::new(args).unwrap();
+    }
 
-// This makes testing much easier.
-#[cfg(test)]
-impl Config {
-    pub fn default_with_file_store_without_models() -> Self {
-        Self {
-            config: ValidConfig {
-                memory: ValidMemoryBackend::FileStore(FileStore { crawl: false }),
-                models: HashMap::new(),
-                completion: None,
-            },
-            _client_params: ValidClientParams {
-                _root_uri: None,
-                _workspace_folders: None,
-            },
-        }
+    #[test]
+    fn azure_open_ai_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "azure_open_ai",
+                        "endpoint": "https://my-resource.openai.azure.com",
+                        "deployment": "gpt-35-turbo",
+                        "auth_token_env_var_name": "AZURE_OPENAI_API_KEY",
+                    },
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {
+                        "messages": [
+                            {
+                                "role": "user",
+                                "content": "Test {CONTEXT} - {CODE}"
+                            }
+                        ],
+                        "max_tokens": 32,
+                    }
+                }
+            }
+        });
+        Config::new(args).unwrap();
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use serde_json::json;
+    #[test]
+    fn open_ai_compatible_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "open_ai_compatible",
+                        "preset": "groq",
+                        "model": "llama-3.1-8b-instant"
+                    },
+                    "model2": {
+                        "type": "open_ai_compatible",
+                        "base_url": "http://localhost:8000/v1",
+                        "auth_header_name": "x-api-key",
+                        "auth_token": "token",
+                        "model": "local"
+                    },
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {}
+                }
+            }
+        });
+        let config = Config::new(args).unwrap();
+        let ValidModel::OpenAICompatible(model1) = &config.config.models["model1"] else {
+            panic!("model1 should be open_ai_compatible");
+        };
+        assert_eq!(model1.preset, Some(OpenAICompatiblePreset::Groq));
+    }
 
     #[test]
     #[cfg(feature = "llama_cpp")]
-    fn llama_cpp_config() {
+    fn llama_cpp_file_path_config() {
         let args = json!({
             "initializationOptions": {
                 "memory": {
@@ -313,22 +2430,15 @@ mod test {
                 "models": {
                     "model1": {
                         "type": "llama_cpp",
-                        "repository": "TheBloke/deepseek-coder-6.7B-instruct-GGUF",
-                        "name": "deepseek-coder-6.7b-instruct.Q5_K_S.gguf",
-                        "n_ctx": 2048,
-                        "n_gpu_layers": 35
+                        "file_path": "/models/stable-code-3b-Q5_K_M.gguf",
+                        "offline": true,
+                        "n_ctx": 2048
                     }
                 },
                 "completion": {
                     "model": "model1",
                     "parameters": {
-                        "fim": {
-                            "start": "<fim_prefix>",
-                            "middle": "<fim_suffix>",
-                            "end": "<fim_middle>"
-                        },
-                        "max_context": 1024,
-                        "max_new_tokens": 32,
+                        "max_tokens": 32
                     }
                 }
             }
@@ -337,11 +2447,16 @@ mod test {
     }
 
     #[test]
-    fn ollama_config() {
+    fn postgresml_splitter_config() {
         let args = json!({
             "initializationOptions": {
                 "memory": {
-                    "file_store": {}
+                    "postgresml": {
+                        "splitter": {
+                            "type": "tree_sitter",
+                            "chunk_size": 1000
+                        }
+                    }
                 },
                 "models": {
                     "model1": {
@@ -351,88 +2466,394 @@ mod test {
                 },
                 "completion": {
                     "model": "model1",
-                    "parameters": {
-                        "max_context": 1024,
-                        "options": {
-                            "num_predict": 32
+                    "parameters": {}
+                }
+            }
+        });
+        let config = Config::new(args).unwrap();
+        let ValidMemoryBackend::PostgresML(postgresml) = config.config.memory else {
+            panic!("expected the postgresml memory backend")
+        };
+        let ValidSplitter::TreeSitter(tree_sitter) = postgresml.splitter else {
+            panic!("expected the tree_sitter splitter")
+        };
+        assert_eq!(tree_sitter.chunk_size, 1000);
+    }
+
+    #[test]
+    fn qdrant_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "qdrant": {
+                        "collection_name": "test",
+                        "embedding_model": {
+                            "type": "open_ai",
+                            "endpoint": "http://localhost:11434/v1/embeddings",
+                            "model": "nomic-embed-text"
+                        },
+                        "filter_by_language": true,
+                        "paths": ["/home/user/project/src"],
+                        "hybrid_search": {
+                            "keyword_weight": 0.5
+                        },
+                        "rerank": {
+                            "type": "cohere",
+                            "model": "rerank-v3.5",
+                            "top_n": 3
                         }
-                    },
-                    "post_process": {
-                        "remove_duplicate_start": true,
-                        "remove_duplicate_end": true,
                     }
+                },
+                "models": {
+                    "model1": {
+                        "type": "ollama",
+                        "model": "llama3"
+                    }
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {}
                 }
             }
         });
-        Config::new(args).unwrap();
+        let config = Config::new(args).unwrap();
+        let ValidMemoryBackend::Qdrant(qdrant) = config.config.memory else {
+            panic!("expected the qdrant memory backend")
+        };
+        assert_eq!(qdrant.url, "http://localhost:6333");
+        assert_eq!(qdrant.search_limit, 5);
+        let hybrid_search = qdrant.hybrid_search.unwrap();
+        assert_eq!(hybrid_search.keyword_weight, 0.5);
+        assert_eq!(hybrid_search.rrf_k, 60.);
+        let Some(ValidRerankModel::Cohere(rerank)) = qdrant.rerank else {
+            panic!("expected the cohere rerank model")
+        };
+        assert_eq!(rerank.top_n, 3);
     }
 
     #[test]
-    fn open_ai_config() {
+    fn vector_index_config() {
+        let args = json!({
+            "rootUri": "file:///home/user/project",
+            "initializationOptions": {
+                "memory": {
+                    "vector_index": {
+                        "embedding_model": {
+                            "type": "open_ai",
+                            "endpoint": "http://localhost:11434/v1/embeddings",
+                            "model": "nomic-embed-text"
+                        },
+                        "splitter": {
+                            "type": "text_splitter",
+                            "chunk_size": 500
+                        }
+                    }
+                },
+                "models": {
+                    "model1": {
+                        "type": "ollama",
+                        "model": "llama3"
+                    }
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {}
+                }
+            }
+        });
+        let config = Config::new(args).unwrap();
+        assert_eq!(config.get_root_uri(), Some("file:///home/user/project"));
+        let ValidMemoryBackend::VectorIndex(vector_index) = config.config.memory else {
+            panic!("expected the vector_index memory backend")
+        };
+        assert_eq!(vector_index.embedding_model.name(), "nomic-embed-text");
+        assert!(vector_index.cache_dir.is_none());
+    }
+
+    #[test]
+    fn update_config() {
         let args = json!({
+            "rootUri": "file:///home/user/project",
             "initializationOptions": {
                 "memory": {
                     "file_store": {}
                 },
                 "models": {
                     "model1": {
-                        "type": "open_ai",
-                        "completions_endpoint": "https://api.fireworks.ai/inference/v1/completions",
-                        "model": "accounts/fireworks/models/llama-v2-34b-code",
-                        "auth_token_env_var_name": "FIREWORKS_API_KEY",
+                        "type": "ollama",
+                        "model": "llama3"
+                    }
+                }
+            }
+        });
+        let config = Config::new(args).unwrap();
+        let new_config = config
+            .update(json!({
+                "lsp-ai": {
+                    "memory": {
+                        "file_store": {}
+                    },
+                    "models": {
+                        "model1": {
+                            "type": "ollama",
+                            "model": "codellama"
+                        }
+                    }
+                }
+            }))
+            .unwrap();
+        assert_eq!(new_config.get_root_uri(), Some("file:///home/user/project"));
+        assert_eq!(new_config.config.memory, config.config.memory);
+        assert_ne!(
+            new_config.config.models["model1"],
+            config.config.models["model1"]
+        );
+    }
+
+    #[test]
+    fn routes_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "fim": {
+                        "type": "ollama",
+                        "model": "deepseek-coder"
                     },
+                    "claude": {
+                        "type": "anthropic",
+                        "chat_endpoint": "https://api.anthropic.com/v1/messages",
+                        "model": "claude-3-haiku-20240307",
+                        "auth_token_env_var_name": "ANTHROPIC_API_KEY"
+                    }
                 },
                 "completion": {
-                    "model": "model1",
-                    "parameters": {
-                        "messages": [
-                            {
-                                "role": "system",
-                                "content": "Test",
-                            },
-                            {
-                                "role": "user",
-                                "content": "Test {CONTEXT} - {CODE}"
+                    "model": "claude",
+                    "parameters": {}
+                },
+                "routes": [
+                    {
+                        "language_ids": ["python"],
+                        "request": "completion",
+                        "region": "comment",
+                        "model": "claude"
+                    },
+                    {
+                        "language_ids": ["python"],
+                        "request": "completion",
+                        "model": "fim",
+                        "parameters": {
+                            "fim": {
+                                "start": "<fim_prefix>",
+                                "middle": "<fim_suffix>",
+                                "end": "<fim_middle>"
                             }
-                        ],
-                        "max_new_tokens": 32,
+                        }
+                    },
+                    {
+                        "request": "generation",
+                        "model": "claude"
                     }
+                ],
+                "fallback": {
+                    "claude": ["fim"]
                 }
             }
         });
-        Config::new(args).unwrap();
+        let config = Config::new(args).unwrap();
+        let route = config
+            .get_route(RequestKind::Completion, Some("python"), CursorRegion::Code)
+            .unwrap();
+        assert_eq!(route.model, "fim");
+        assert!(route.parameters.is_some());
+        let route = config
+            .get_route(
+                RequestKind::Completion,
+                Some("python"),
+                CursorRegion::Comment,
+            )
+            .unwrap();
+        assert_eq!(route.model, "claude");
+        assert!(config.has_region_routes());
+        assert!(config
+            .get_route(RequestKind::Completion, Some("rust"), CursorRegion::Code)
+            .is_none());
+        assert_eq!(
+            config
+                .get_route(RequestKind::Generation, Some("python"), CursorRegion::Code)
+                .unwrap()
+                .model,
+            "claude"
+        );
+        assert_eq!(config.get_fallbacks("claude"), ["fim"]);
+        assert!(config.get_fallbacks("fim").is_empty());
     }
 
     #[test]
-    fn anthropic_config() {
+    fn actions_config() {
         let args = json!({
             "initializationOptions": {
                 "memory": {
                     "file_store": {}
                 },
                 "models": {
-                    "model1": {
+                    "claude": {
                         "type": "anthropic",
-                        "completions_endpoint": "https://api.anthropic.com/v1/messages",
+                        "chat_endpoint": "https://api.anthropic.com/v1/messages",
                         "model": "claude-3-haiku-20240307",
-                        "auth_token_env_var_name": "ANTHROPIC_API_KEY",
+                        "auth_token_env_var_name": "ANTHROPIC_API_KEY"
+                    }
+                },
+                "actions": [
+                    {
+                        "title": "Explain this code",
+                        "model": "claude",
+                        "parameters": {
+                            "messages": [
+                                {
+                                    "role": "user",
+                                    "content": "Explain this code:\n{CODE}"
+                                }
+                            ]
+                        },
+                        "output": "message"
+                    },
+                    {
+                        "title": "Refactor for readability",
+                        "model": "claude"
+                    },
+                    {
+                        "title": "Generate unit tests",
+                        "model": "claude",
+                        "output": "tests"
+                    }
+                ],
+                "test_conventions": {
+                    "python": {
+                        "path": "test_{name}.{ext}"
                     },
+                    "rust": {
+                        "module": "mod tests {"
+                    }
+                }
+            }
+        });
+        let config = Config::new(args).unwrap();
+        let action = config.get_action("Explain this code").unwrap();
+        assert_eq!(action.output, ActionOutput::Message);
+        assert!(action.parameters.contains_key("messages"));
+        assert_eq!(
+            config
+                .get_action("Refactor for readability")
+                .unwrap()
+                .output,
+            ActionOutput::Replace
+        );
+        assert!(config.get_action("Write a docstring").is_none());
+        assert_eq!(
+            config.get_test_convention(Some("python")).unwrap().path,
+            Some("test_{name}.{ext}".to_string())
+        );
+        assert!(config.get_test_convention(Some("go")).is_none());
+        assert!(config.get_test_convention(None).is_none());
+    }
+
+    #[test]
+    fn env_var_interpolation() {
+        std::env::set_var("LSP_AI_TEST_INTERPOLATION", "sk-test");
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "open_ai",
+                        "chat_endpoint": "https://api.openai.com/v1/chat/completions",
+                        "model": "gpt-4o",
+                        "auth_token": "${LSP_AI_TEST_INTERPOLATION}-$${KEPT}"
+                    }
+                }
+            }
+        });
+        let config = Config::new(args).unwrap();
+        let ValidModel::OpenAI(model) = &config.config.models["model1"] else {
+            panic!("expected an OpenAI model");
+        };
+        assert_eq!(model.auth_token.as_deref(), Some("sk-test-${KEPT}"));
+
+        let error = Config::new(json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "open_ai",
+                        "model": "gpt-4o",
+                        "auth_token": "${LSP_AI_TEST_MISSING}"
+                    }
+                }
+            }
+        }))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the env var `LSP_AI_TEST_MISSING` used in `models.model1.auth_token` is not set"
+        );
+    }
+
+    #[test]
+    fn config_errors_and_schema() {
+        let error = Config::new(json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
                 },
+                "models": {},
                 "completion": {
                     "model": "model1",
-                    "parameters": {
-                        "system": "Test",
-                        "messages": [
-                            {
-                                "role": "user",
-                                "content": "Test {CONTEXT} - {CODE}"
-                            }
-                        ],
-                        "max_new_tokens": 32,
-                    }
+                    "debounce_ms": "soon"
                 }
             }
+        }))
+        .unwrap_err();
+        assert!(error.to_string().starts_with(
+            "invalid configuration at `completion.debounce_ms`: invalid type: string"
+        ));
+
+        let schema = get_schema().unwrap();
+        assert!(schema["properties"]["models"].is_object());
+        assert!(schema["required"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("memory")));
+    }
+
+    #[test]
+    fn workspace_folders() {
+        let mut config = Config::new(json!({
+            "rootUri": "file:///home/user/a",
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {}
+            }
+        }))
+        .unwrap();
+        assert_eq!(config.get_workspace_folders(), vec!["file:///home/user/a"]);
+
+        let folder = |uri: &str| lsp_types::WorkspaceFolder {
+            uri: lsp_types::Url::parse(uri).unwrap(),
+            name: uri.to_owned(),
+        };
+        config.change_workspace_folders(&lsp_types::WorkspaceFoldersChangeEvent {
+            added: vec![folder("file:///home/user/b")],
+            removed: vec![folder("file:///home/user/a")],
         });
-        Config::new(args).unwrap();
+        assert_eq!(config.get_workspace_folders(), vec!["file:///home/user/b"]);
     }
 }
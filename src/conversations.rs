@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use tracing::error;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::config::{ChatMessage, Config};
+
+fn get_dir(config: &Config) -> Option<PathBuf> {
+    let history_dir = match config
+        .config
+        .chat
+        .as_ref()
+        .and_then(|chat| chat.history_dir.as_ref())
+    {
+        Some(history_dir) => PathBuf::from(history_dir),
+        None => directories::ProjectDirs::from("", "", "lsp-ai")?
+            .data_dir()
+            .join("chats"),
+    };
+    // Each workspace gets its own conversations
+    let name = xxh3_64(config.get_root_uri().unwrap_or("global").as_bytes());
+    Some(history_dir.join(format!("{name:x}")))
+}
+
+fn load(path: &Path) -> Option<Vec<ChatMessage>> {
+    let contents = std::fs::read(path).ok()?;
+    match serde_json::from_slice(&contents) {
+        Ok(messages) => Some(messages),
+        Err(e) => {
+            error!("error loading the conversation {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+// Chat conversations keyed by id. They are persisted to disk so they survive restarts, or only
+// kept in memory when there is no data directory.
+#[derive(Debug)]
+pub struct Conversations {
+    dir: Option<PathBuf>,
+    conversations: Mutex<HashMap<String, Vec<ChatMessage>>>,
+}
+
+impl Conversations {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            dir: get_dir(config),
+            conversations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn new_id() -> String {
+        format!("{:x}", rand::random::<u64>())
+    }
+
+    fn get_path(&self, id: &str) -> anyhow::Result<Option<PathBuf>> {
+        // The id ends up in a path so it cannot be allowed to point anywhere else
+        anyhow::ensure!(
+            !id.is_empty()
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "conversation ids may only contain ASCII letters, digits, `-` and `_`"
+        );
+        Ok(self.dir.as_ref().map(|dir| dir.join(format!("{id}.json"))))
+    }
+
+    // The messages of the conversation, empty for a new one
+    pub fn get(&self, id: &str) -> anyhow::Result<Vec<ChatMessage>> {
+        let path = self.get_path(id)?;
+        let mut conversations = self.conversations.lock();
+        if let Some(messages) = conversations.get(id) {
+            return Ok(messages.clone());
+        }
+        let messages = path.and_then(|path| load(&path)).unwrap_or_default();
+        conversations.insert(id.to_owned(), messages.clone());
+        Ok(messages)
+    }
+
+    pub fn append(&self, id: &str, messages: Vec<ChatMessage>) -> anyhow::Result<()> {
+        let path = self.get_path(id)?;
+        let contents = {
+            let mut conversations = self.conversations.lock();
+            let conversation = conversations.entry(id.to_owned()).or_default();
+            conversation.extend(messages);
+            serde_json::to_vec(conversation)?
+        };
+        let Some(path) = path else {
+            return Ok(());
+        };
+        std::fs::create_dir_all(path.parent().context("invalid conversation path")?)?;
+        // Write to a temporary file first so a crash never leaves a partial conversation behind
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn conversations_persist() -> anyhow::Result<()> {
+        let history_dir =
+            std::env::temp_dir().join(format!("lsp-ai-chats-{}", Conversations::new_id()));
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {},
+                "chat": {
+                    "model": "model1",
+                    "history_dir": history_dir
+                }
+            }
+        });
+        let config = Config::new(args)?;
+        let conversations = Conversations::new(&config);
+        assert!(conversations.get("abc")?.is_empty());
+        conversations.append(
+            "abc",
+            vec![
+                ChatMessage::new("user".to_string(), "Hi".to_string()),
+                ChatMessage::new("assistant".to_string(), "Hello!".to_string()),
+            ],
+        )?;
+        assert!(conversations.get("../abc").is_err());
+
+        // A new instance loads the conversation from disk
+        let conversations = Conversations::new(&config);
+        let messages = conversations.get("abc")?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].content, "Hello!");
+        std::fs::remove_dir_all(history_dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use lsp_types::{TextDocumentIdentifier, WorkspaceEdit};
+use serde::{Deserialize, Serialize};
+
+pub enum Agent {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentParams {
+    // What the agent should do, e.g. "rename the `Config` struct to `Settings`"
+    pub goal: String,
+    // The document the goal is about, its text is sent with the goal
+    pub text_document: Option<TextDocumentIdentifier>,
+    // The model key to use, the one from the agent config when not set
+    pub model: Option<String>,
+}
+
+// Replaces `old_text` with `new_text`, an empty `old_text` creates the file
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProposedEdit {
+    pub uri: String,
+    pub old_text: String,
+    pub new_text: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentResult {
+    pub answer: String,
+    // Not applied, in the order the model proposed them
+    pub edits: Vec<ProposedEdit>,
+    // All of the edits, for clients to preview and apply at once with workspace/applyEdit. Not
+    // set when there are no edits.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workspace_edit: Option<WorkspaceEdit>,
+    // A unified diff of the edited files
+    pub diff: String,
+    // How many times the model was called
+    pub steps: usize,
+    // The fallback model that served the request when the requested model failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<String>,
+}
+
+impl lsp_types::request::Request for Agent {
+    type Params = AgentParams;
+    type Result = AgentResult;
+    const METHOD: &'static str = "lsp-ai/agent";
+}
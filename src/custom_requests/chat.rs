@@ -0,0 +1,50 @@
+use lsp_types::TextDocumentPositionParams;
+use serde::{Deserialize, Serialize};
+
+pub enum Chat {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatParams {
+    // The conversation to continue, a new one is started when not set
+    pub conversation_id: Option<String>,
+    pub message: String,
+    // Context from the memory backend is added when set
+    pub text_document_position: Option<TextDocumentPositionParams>,
+    // The model key to use, the one from the chat config when not set
+    pub model: Option<String>,
+    // Sends the response as it is generated with lsp-ai/chatStream notifications
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatResult {
+    pub conversation_id: String,
+    pub message: String,
+    // The fallback model that served the request when the requested model failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<String>,
+}
+
+impl lsp_types::request::Request for Chat {
+    type Params = ChatParams;
+    type Result = ChatResult;
+    const METHOD: &'static str = "lsp-ai/chat";
+}
+
+pub enum ChatStream {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatStreamParams {
+    pub conversation_id: String,
+    // The text generated since the last notification
+    pub text: String,
+}
+
+impl lsp_types::notification::Notification for ChatStream {
+    type Params = ChatStreamParams;
+    const METHOD: &'static str = "lsp-ai/chatStream";
+}
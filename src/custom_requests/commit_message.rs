@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+
+pub enum GenerateCommitMessage {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateCommitMessageParams {
+    // A directory in the repository, the rootUri when not set
+    pub uri: Option<String>,
+    // The model key to use, the one from the commit_message config when not set
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateCommitMessageResult {
+    pub message: String,
+    // The fallback model that served the request when the requested model failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<String>,
+}
+
+impl lsp_types::request::Request for GenerateCommitMessage {
+    type Params = GenerateCommitMessageParams;
+    type Result = GenerateCommitMessageResult;
+    const METHOD: &'static str = "lsp-ai/generateCommitMessage";
+}
@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+// Sent by clients when the user accepts or dismisses a completion
+pub enum CompletionAccepted {}
+
+pub enum CompletionRejected {}
+
+// The `data` of the completion item, clients send it back as is
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletionFeedbackParams {
+    // The model key of the model that served the completion
+    pub model: String,
+    pub language_id: Option<String>,
+}
+
+impl lsp_types::notification::Notification for CompletionAccepted {
+    type Params = CompletionFeedbackParams;
+    const METHOD: &'static str = "lsp-ai/completionAccepted";
+}
+
+impl lsp_types::notification::Notification for CompletionRejected {
+    type Params = CompletionFeedbackParams;
+    const METHOD: &'static str = "lsp-ai/completionRejected";
+}
@@ -12,8 +12,8 @@ pub struct GenerationParams {
     // This field was "mixed-in" from TextDocumentPositionParams
     #[serde(flatten)]
     pub text_document_position: TextDocumentPositionParams,
-    // The model key to use
-    pub model: String,
+    // The model key to use, picked by the routes in the config when not set
+    pub model: Option<String>,
     #[serde(default)]
     // Args are deserialized by the backend using them
     pub parameters: Value,
@@ -26,6 +26,12 @@ pub struct GenerationParams {
 #[serde(rename_all = "camelCase")]
 pub struct GenerateResult {
     pub generated_text: String,
+    // The parsed JSON when the parameters ask for structured output with `response_format`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub structured_output: Option<Value>,
+    // The fallback model that served the request when the requested model failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<String>,
 }
 
 impl lsp_types::request::Request for Generation {
@@ -1,5 +1,6 @@
 use lsp_types::{ProgressToken, TextDocumentPositionParams};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 pub enum GenerationStream {}
 
@@ -11,6 +12,11 @@ pub struct GenerationStreamParams {
     // This field was "mixed-in" from TextDocumentPositionParams
     #[serde(flatten)]
     pub text_document_position: TextDocumentPositionParams,
+    // The model key to use, picked by the routes in the config when not set
+    pub model: Option<String>,
+    #[serde(default)]
+    // Args are deserialized by the backend using them
+    pub parameters: Value,
 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
@@ -18,6 +24,9 @@ pub struct GenerationStreamParams {
 pub struct GenerationStreamResult {
     pub generated_text: String,
     pub partial_result_token: ProgressToken,
+    // The fallback model that served the request when the requested model failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<String>,
 }
 
 impl lsp_types::request::Request for GenerationStream {
@@ -0,0 +1,62 @@
+use lsp_types::{Range, TextDocumentPositionParams, WorkDoneProgressParams};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// textDocument/inlineCompletion from LSP 3.18
+pub enum InlineCompletion {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectedCompletionInfo {
+    // The range that will be replaced if the selected completion item is accepted
+    pub range: Range,
+    // The text the range will be replaced with
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionContext {
+    // 1 when explicitly invoked, 2 when triggered automatically while typing
+    pub trigger_kind: u8,
+    // Set when a completion item is selected in the editor's completion widget
+    pub selected_completion_info: Option<SelectedCompletionInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionParams {
+    // This field was "mixed-in" from TextDocumentPositionParams
+    #[serde(flatten)]
+    pub text_document_position: TextDocumentPositionParams,
+    pub context: InlineCompletionContext,
+    #[serde(flatten)]
+    pub work_done_progress_params: WorkDoneProgressParams,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionItem {
+    // Replaces `range` when the item is accepted
+    pub insert_text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter_text: Option<String>,
+    // Defaults to inserting at the cursor when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range>,
+    // Not part of LSP, sent back with lsp-ai/completionAccepted and lsp-ai/completionRejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionList {
+    pub items: Vec<InlineCompletionItem>,
+}
+
+impl lsp_types::request::Request for InlineCompletion {
+    type Params = InlineCompletionParams;
+    type Result = InlineCompletionList;
+    const METHOD: &'static str = "textDocument/inlineCompletion";
+}
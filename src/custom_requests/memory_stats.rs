@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub enum MemoryStats {}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileStatus {
+    pub uri: String,
+    pub language: Option<String>,
+    pub chunks: usize,
+    // Why the file could not be indexed the last time it was tried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryStatsResult {
+    pub chunks: usize,
+    // The bytes the index takes up, None when the backend does not know
+    pub index_size: Option<u64>,
+    // Milliseconds since the Unix epoch, None when nothing was indexed since the server started
+    pub last_indexed: Option<u64>,
+    // Whether the workspace or documentation is being crawled
+    pub indexing: bool,
+    pub files: Vec<FileStatus>,
+}
+
+impl lsp_types::request::Request for MemoryStats {
+    // Any parameters are ignored
+    type Params = Value;
+    type Result = MemoryStatsResult;
+    const METHOD: &'static str = "lsp-ai/memoryStats";
+}
@@ -1,2 +1,15 @@
+pub mod agent;
+pub mod chat;
+pub mod commit_message;
+pub mod completion_feedback;
 pub mod generation;
 pub mod generation_stream;
+pub mod inline_completion;
+pub mod memory_stats;
+pub mod preview_prompt;
+pub mod raw_generation;
+pub mod ready;
+pub mod reindex;
+pub mod search;
+pub mod set_log_level;
+pub mod usage;
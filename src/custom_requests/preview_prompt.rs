@@ -0,0 +1,50 @@
+use lsp_types::TextDocumentPositionParams;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::transformer_backends::RenderedPrompt;
+
+pub enum PreviewPrompt {}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+pub enum PreviewKind {
+    // Uses the model and parameters of the completion config or its routes
+    #[default]
+    #[serde(rename = "completion")]
+    Completion,
+    // Uses the model and parameters of the request like textDocument/generation
+    #[serde(rename = "generation")]
+    Generation,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewPromptParams {
+    // This field was "mixed-in" from TextDocumentPositionParams
+    #[serde(flatten)]
+    pub text_document_position: TextDocumentPositionParams,
+    #[serde(default)]
+    pub kind: PreviewKind,
+    // Only for generations, picked by the routes in the config when not set
+    pub model: Option<String>,
+    // Only for generations
+    #[serde(default)]
+    pub parameters: Value,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewPromptResult {
+    pub model: String,
+    // The context retrieved by the memory backend, None for FIM prompts
+    pub context: Option<String>,
+    // What is sent to the backend, after redaction
+    #[serde(flatten)]
+    pub prompt: RenderedPrompt,
+}
+
+impl lsp_types::request::Request for PreviewPrompt {
+    type Params = PreviewPromptParams;
+    type Result = PreviewPromptResult;
+    const METHOD: &'static str = "lsp-ai/previewPrompt";
+}
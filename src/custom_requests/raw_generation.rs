@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{config::ChatMessage, usage::TokenUsage};
+
+pub enum RawGeneration {}
+
+// A generation for messages or a prompt the client built itself instead of one built from a
+// document, like the OpenAI APIs take them
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RawGenerationParams {
+    // The model key to use
+    pub model: String,
+    // Sent to chat models
+    pub messages: Option<Vec<ChatMessage>>,
+    // Sent as is when there are no messages
+    pub prompt: Option<String>,
+    #[serde(default)]
+    // Args are deserialized by the backend using them
+    pub parameters: Value,
+    // Adds the chunks of the workspace most relevant to the last message or the prompt
+    #[serde(default)]
+    pub workspace_context: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RawGenerationResult {
+    pub generated_text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+    // The fallback model that served the request when the requested model failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<String>,
+}
+
+impl lsp_types::request::Request for RawGeneration {
+    type Params = RawGenerationParams;
+    type Result = RawGenerationResult;
+    const METHOD: &'static str = "lsp-ai/rawGeneration";
+}
@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+pub enum Ready {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadyParams {
+    // The model keys that loaded
+    pub models: Vec<String>,
+    // The model keys that failed to load, requests for them fail until the configuration changes
+    pub failed: Vec<String>,
+}
+
+// Sent once the models finished loading in the background. Requests before it are answered
+// without asking a model.
+impl lsp_types::notification::Notification for Ready {
+    type Params = ReadyParams;
+    const METHOD: &'static str = "lsp-ai/ready";
+}
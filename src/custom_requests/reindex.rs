@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+pub enum Reindex {}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReindexParams {
+    // Embed every file again instead of only the ones that changed
+    #[serde(default)]
+    pub force: bool,
+}
+
+impl lsp_types::request::Request for Reindex {
+    type Params = ReindexParams;
+    // Answered once indexing starts, it runs in the background
+    type Result = ();
+    const METHOD: &'static str = "lsp-ai/reindex";
+}
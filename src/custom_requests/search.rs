@@ -0,0 +1,39 @@
+use lsp_types::Range;
+use serde::{Deserialize, Serialize};
+
+pub enum WorkspaceSearch {}
+
+const fn top_k_default() -> usize {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceSearchParams {
+    pub query: String,
+    #[serde(default = "top_k_default")]
+    pub top_k: usize,
+    // Only search files with this languageId
+    pub language: Option<String>,
+    // Only search files under these directories
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchMatch {
+    pub uri: String,
+    pub text: String,
+    // The symbols the chunk is in, e.g. its function or the headings of a page
+    pub symbols: Vec<String>,
+    // The lines of the chunk, None when it can not be found in the file anymore
+    pub range: Option<Range>,
+}
+
+impl lsp_types::request::Request for WorkspaceSearch {
+    type Params = WorkspaceSearchParams;
+    // Most relevant first
+    type Result = Vec<SearchMatch>;
+    const METHOD: &'static str = "lsp-ai/search";
+}
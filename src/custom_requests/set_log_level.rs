@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+pub enum SetLogLevel {}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetLogLevelParams {
+    // Filter directives like `LSP_AI_LOG`, e.g. 'debug' or 'lsp_ai=trace'
+    pub level: String,
+}
+
+impl lsp_types::request::Request for SetLogLevel {
+    type Params = SetLogLevelParams;
+    type Result = ();
+    const METHOD: &'static str = "lsp-ai/setLogLevel";
+}
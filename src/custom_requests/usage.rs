@@ -0,0 +1,56 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub enum Usage {}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Acceptance {
+    pub accepted: u64,
+    pub rejected: u64,
+    // The share of the completions with feedback that were accepted, None without feedback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // Zero for models without pricing in the usage config
+    pub cost: f64,
+    // As reported by clients with lsp-ai/completionAccepted and lsp-ai/completionRejected
+    #[serde(default)]
+    pub acceptance: Acceptance,
+    // Keyed by languageId
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub languages: HashMap<String, Acceptance>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageReport {
+    pub total: UsageTotals,
+    // Keyed by model key
+    pub models: HashMap<String, UsageTotals>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageResult {
+    // Since the server started
+    pub session: UsageReport,
+    // Keyed by UTC date formatted as YYYY-MM-DD
+    pub days: BTreeMap<String, UsageReport>,
+}
+
+impl lsp_types::request::Request for Usage {
+    // Any parameters are ignored
+    type Params = Value;
+    type Result = UsageResult;
+    const METHOD: &'static str = "lsp-ai/usage";
+}
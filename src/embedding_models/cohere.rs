@@ -0,0 +1,83 @@
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::auth::get_auth_token;
+use crate::config;
+use crate::http_client::get_client;
+
+use super::EmbeddingBackend;
+
+#[derive(Deserialize)]
+struct CohereEmbeddings {
+    float: Vec<Vec<f32>>,
+}
+
+#[derive(Deserialize)]
+struct CohereEmbedResponse {
+    embeddings: Option<CohereEmbeddings>,
+    // Only set for errors
+    message: Option<Value>,
+}
+
+pub struct Cohere {
+    configuration: config::CohereEmbeddingModel,
+}
+
+impl Cohere {
+    pub fn new(configuration: config::CohereEmbeddingModel) -> Self {
+        Self { configuration }
+    }
+
+    // The models embed the documents searched and the queries searching them differently
+    async fn embed_as(&self, texts: &[String], input_type: &str) -> anyhow::Result<Vec<Vec<f32>>> {
+        let token = get_auth_token(
+            self.configuration.auth_token_env_var_name.as_deref(),
+            self.configuration.auth_token.as_deref(),
+            self.configuration.auth_token_keyring.as_ref(),
+            self.configuration.auth_token_command.as_deref(),
+        ).await?
+        .context("set `auth_token_env_var_name`, `auth_token`, `auth_token_keyring` or `auth_token_command` to use Cohere embeddings")?;
+        let res: CohereEmbedResponse = get_client()
+            .post(
+                self.configuration
+                    .endpoint
+                    .as_deref()
+                    .unwrap_or("https://api.cohere.com/v2/embed"),
+            )
+            .bearer_auth(token)
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .json(&json!({
+                "model": self.configuration.model,
+                "texts": texts,
+                "input_type": input_type,
+                "embedding_types": ["float"]
+            }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        match res.embeddings {
+            Some(embeddings) => Ok(embeddings.float),
+            None => anyhow::bail!(
+                "Unknown error while requesting embeddings: {:?}",
+                res.message
+            ),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmbeddingBackend for Cohere {
+    async fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.embed_as(texts, "search_document").await
+    }
+
+    async fn embed_query(&self, query: String) -> anyhow::Result<Vec<f32>> {
+        self.embed_as(&[query], "search_query")
+            .await?
+            .pop()
+            .context("no embedding returned for the query")
+    }
+}
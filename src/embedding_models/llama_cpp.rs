@@ -0,0 +1,29 @@
+use std::sync::Arc;
+
+use crate::config;
+use crate::transformer_backends::llama_cpp;
+
+use super::EmbeddingBackend;
+
+// A local GGUF model
+pub struct LLaMACPP {
+    model: Arc<llama_cpp::LLaMACPP>,
+}
+
+impl LLaMACPP {
+    pub fn new(configuration: config::LLaMACPP) -> anyhow::Result<Self> {
+        Ok(Self {
+            model: Arc::new(llama_cpp::LLaMACPP::new(configuration)?),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmbeddingBackend for LLaMACPP {
+    async fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        // Embedding runs on the CPU / GPU so keep it off the async runtime
+        let model = self.model.clone();
+        let texts = texts.to_vec();
+        tokio::task::spawn_blocking(move || model.embed(&texts)).await?
+    }
+}
@@ -0,0 +1,86 @@
+use crate::config::ValidEmbeddingModel;
+
+mod cohere;
+#[cfg(feature = "llama_cpp")]
+mod llama_cpp;
+mod ollama;
+mod open_ai;
+
+// How many texts are sent per embedding request
+const BATCH_SIZE: usize = 32;
+
+#[async_trait::async_trait]
+pub trait EmbeddingBackend {
+    // Embeds at most `BATCH_SIZE` texts, the embeddings are in the order of the texts
+    async fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>>;
+
+    async fn embed(&self, texts: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(BATCH_SIZE) {
+            let batch_embeddings = self.embed_batch(batch).await?;
+            anyhow::ensure!(
+                batch_embeddings.len() == batch.len(),
+                "requested {} embeddings but received {}",
+                batch.len(),
+                batch_embeddings.len()
+            );
+            embeddings.extend(batch_embeddings);
+        }
+        Ok(embeddings)
+    }
+
+    // Models embedding search queries differently from the documents searched override this
+    async fn embed_query(&self, query: String) -> anyhow::Result<Vec<f32>> {
+        self.embed_batch(&[query])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("no embedding returned for the query"))
+    }
+}
+
+impl TryFrom<ValidEmbeddingModel> for Box<dyn EmbeddingBackend + Send + Sync> {
+    type Error = anyhow::Error;
+
+    fn try_from(configuration: ValidEmbeddingModel) -> Result<Self, Self::Error> {
+        Ok(match configuration {
+            ValidEmbeddingModel::OpenAI(configuration) => {
+                Box::new(open_ai::OpenAI::new(configuration))
+            }
+            ValidEmbeddingModel::Ollama(configuration) => {
+                Box::new(ollama::Ollama::new(configuration))
+            }
+            ValidEmbeddingModel::Cohere(configuration) => {
+                Box::new(cohere::Cohere::new(configuration))
+            }
+            #[cfg(feature = "llama_cpp")]
+            ValidEmbeddingModel::LLaMACPP(configuration) => {
+                Box::new(llama_cpp::LLaMACPP::new(configuration)?)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Embeds each text as its length
+    struct Lengths;
+
+    #[async_trait::async_trait]
+    impl EmbeddingBackend for Lengths {
+        async fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+            assert!(texts.len() <= BATCH_SIZE);
+            Ok(texts.iter().map(|text| vec![text.len() as f32]).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn embeds_in_batches() -> anyhow::Result<()> {
+        let texts: Vec<String> = (0..BATCH_SIZE * 2 + 1).map(|i| "a".repeat(i)).collect();
+        let embeddings = Lengths.embed(texts).await?;
+        assert_eq!(embeddings.len(), BATCH_SIZE * 2 + 1);
+        assert_eq!(embeddings[BATCH_SIZE * 2], vec![(BATCH_SIZE * 2) as f32]);
+        Ok(())
+    }
+}
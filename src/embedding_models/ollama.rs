@@ -0,0 +1,59 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config;
+use crate::http_client::get_client;
+
+use super::EmbeddingBackend;
+
+#[derive(Deserialize)]
+struct OllamaEmbeddingResponse {
+    embedding: Option<Vec<f32>>,
+    error: Option<Value>,
+}
+
+pub struct Ollama {
+    configuration: config::OllamaEmbeddingModel,
+}
+
+impl Ollama {
+    pub fn new(configuration: config::OllamaEmbeddingModel) -> Self {
+        Self { configuration }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmbeddingBackend for Ollama {
+    // The endpoint embeds one text per request
+    async fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let client = get_client();
+        let endpoint = self
+            .configuration
+            .endpoint
+            .as_deref()
+            .unwrap_or("http://localhost:11434/api/embeddings");
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for text in texts {
+            let res: OllamaEmbeddingResponse = client
+                .post(endpoint)
+                .header("Content-Type", "application/json")
+                .header("Accept", "application/json")
+                .json(&json!({
+                    "model": self.configuration.model,
+                    "prompt": text
+                }))
+                .send()
+                .await?
+                .json()
+                .await?;
+            if let Some(error) = res.error {
+                anyhow::bail!("{:?}", error.to_string())
+            }
+            embeddings.push(
+                res.embedding
+                    .ok_or_else(|| anyhow::anyhow!("Unknown error while requesting embeddings"))?,
+            );
+        }
+        Ok(embeddings)
+    }
+}
@@ -0,0 +1,72 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::auth::get_auth_token;
+use crate::config;
+use crate::http_client::get_client;
+
+use super::EmbeddingBackend;
+
+#[derive(Deserialize)]
+struct OpenAIEmbedding {
+    embedding: Vec<f32>,
+    index: usize,
+}
+
+#[derive(Deserialize)]
+struct OpenAIEmbeddingsResponse {
+    data: Option<Vec<OpenAIEmbedding>>,
+    error: Option<Value>,
+}
+
+pub struct OpenAI {
+    configuration: config::OpenAIEmbeddingModel,
+}
+
+impl OpenAI {
+    pub fn new(configuration: config::OpenAIEmbeddingModel) -> Self {
+        Self { configuration }
+    }
+
+    async fn get_token(&self) -> anyhow::Result<Option<String>> {
+        get_auth_token(
+            self.configuration.auth_token_env_var_name.as_deref(),
+            self.configuration.auth_token.as_deref(),
+            self.configuration.auth_token_keyring.as_ref(),
+            self.configuration.auth_token_command.as_deref(),
+        )
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl EmbeddingBackend for OpenAI {
+    async fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let client = get_client();
+        let mut request = client
+            .post(&self.configuration.endpoint)
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json");
+        // Local servers typically do not require a token
+        if let Some(token) = self.get_token().await? {
+            request = request.bearer_auth(token);
+        }
+        let res: OpenAIEmbeddingsResponse = request
+            .json(&json!({
+                "model": self.configuration.model,
+                "input": texts
+            }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        if let Some(error) = res.error {
+            anyhow::bail!("{:?}", error.to_string())
+        }
+        let mut data = res
+            .data
+            .ok_or_else(|| anyhow::anyhow!("Unknown error while requesting embeddings"))?;
+        data.sort_by_key(|embedding| embedding.index);
+        Ok(data.into_iter().map(|e| e.embedding).collect())
+    }
+}
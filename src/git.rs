@@ -0,0 +1,127 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use lsp_types::Url;
+use tokio::process::Command;
+use tracing::info;
+
+// Diffs of large repositories can take a while, prompts should not wait on them
+const GIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Separates the commits in the output of `git log`
+const RECORD_SEPARATOR: char = '\u{1e}';
+
+// The directory of the file at `uri`, git finds the repository from there
+pub fn get_dir(uri: &str) -> Option<PathBuf> {
+    let path = Url::parse(uri).ok()?.to_file_path().ok()?;
+    Some(path.parent()?.to_owned())
+}
+
+// The directory `uri` is or is in, with `.git` swapped for the work tree it belongs to as git
+// cannot diff from there. Commit messages are edited in `.git/COMMIT_EDITMSG`.
+pub fn get_work_tree(uri: &str) -> Option<PathBuf> {
+    let path = Url::parse(uri).ok()?.to_file_path().ok()?;
+    let dir = if path.is_dir() {
+        path
+    } else {
+        path.parent()?.to_owned()
+    };
+    match dir.file_name() {
+        Some(name) if name == ".git" => Some(dir.parent()?.to_owned()),
+        _ => Some(dir),
+    }
+}
+
+// The output of git run in `dir`. None when git is not installed, `dir` is not in a repository or
+// git fails or is too slow.
+async fn run(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(GIT_TIMEOUT, output).await {
+        Ok(Ok(output)) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => {
+            info!("running git: {e}");
+            None
+        }
+        Err(_) => {
+            info!("git {} timed out", args.join(" "));
+            None
+        }
+    }
+}
+
+async fn get_changes(dir: &Path, staged: bool) -> Option<String> {
+    let mut args = vec!["diff", "--no-color", "--no-ext-diff"];
+    if staged {
+        args.push("--cached");
+    }
+    let diff = run(dir, &args).await?;
+    let diff = diff.trim_end();
+    (!diff.is_empty()).then(|| diff.to_owned())
+}
+
+// What `git commit` would commit. None when nothing is staged.
+pub async fn get_staged_diff(dir: &Path) -> Option<String> {
+    get_changes(dir, true).await
+}
+
+// The staged and the unstaged changes, each under a heading. None when there are none.
+pub async fn get_diff(dir: &Path) -> Option<String> {
+    let (staged, unstaged) = tokio::join!(get_changes(dir, true), get_changes(dir, false));
+    let diff = [("Staged changes", staged), ("Unstaged changes", unstaged)]
+        .into_iter()
+        .filter_map(|(heading, diff)| Some(format!("{heading}:\n{}", diff?)))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    (!diff.is_empty()).then_some(diff)
+}
+
+// The messages of the last `n` commits of the current branch, newest first
+pub async fn get_recent_commits(dir: &Path, n: usize) -> Vec<String> {
+    if n == 0 {
+        return vec![];
+    }
+    let format = format!("--format=%B{RECORD_SEPARATOR}");
+    match run(dir, &["log", &format!("-{n}"), "--no-color", &format]).await {
+        Some(log) => split_log(&log),
+        None => vec![],
+    }
+}
+
+fn split_log(log: &str) -> Vec<String> {
+    log.split(RECORD_SEPARATOR)
+        .map(str::trim)
+        .filter(|message| !message.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_commit_messages() {
+        let log = "Fix the parser\n\nIt skipped the last line.\n\u{1e}\nAdd a parser\n\u{1e}\n";
+        assert_eq!(
+            split_log(log),
+            vec![
+                "Fix the parser\n\nIt skipped the last line.",
+                "Add a parser"
+            ]
+        );
+        assert!(split_log("").is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use lsp_server::{Connection, Message, Notification, Request, RequestId};
+use lsp_types::{CompletionResponse, CompletionTextEdit, Url};
+use serde_json::{json, Value};
+
+use crate::{
+    custom_requests::{generation::GenerateResult, ready::Ready},
+    workspace_config,
+};
+
+const USAGE: &str = "usage: lsp-ai <complete|generate> --file <file> --position <line:column> [--config <file>] [--model <key>] [--parameters <json>] [--language <id>] [--json]";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Complete,
+    Generate,
+}
+
+#[derive(Debug)]
+pub struct Args {
+    command: Command,
+    file: PathBuf,
+    // 1-based like editors and compiler messages show them
+    line: u32,
+    column: u32,
+    config: Option<PathBuf>,
+    model: Option<String>,
+    parameters: Value,
+    language: Option<String>,
+    json: bool,
+}
+
+pub fn parse_position(position: &str) -> anyhow::Result<(u32, u32)> {
+    let (line, column) = position
+        .split_once(':')
+        .with_context(|| format!("invalid position {position}, expected <line:column>"))?;
+    let line: u32 = line.parse().context("invalid line")?;
+    let column: u32 = column.parse().context("invalid column")?;
+    if line == 0 || column == 0 {
+        anyhow::bail!("lines and columns start at 1");
+    }
+    Ok((line, column))
+}
+
+pub fn parse_args(
+    command: Command,
+    mut args: impl Iterator<Item = String>,
+) -> anyhow::Result<Args> {
+    let mut file = None;
+    let mut position = None;
+    let mut config = None;
+    let mut model = None;
+    let mut parameters = json!({});
+    let mut language = None;
+    let mut json = false;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("{arg} needs a value\n{USAGE}"))
+        };
+        match arg.as_str() {
+            "--file" => file = Some(PathBuf::from(value()?)),
+            "--position" => position = Some(parse_position(&value()?)?),
+            "--config" => config = Some(PathBuf::from(value()?)),
+            "--model" => model = Some(value()?),
+            "--parameters" => {
+                parameters = serde_json::from_str(&value()?).context("invalid --parameters")?
+            }
+            "--language" => language = Some(value()?),
+            "--json" => json = true,
+            _ => anyhow::bail!("unknown argument {arg}\n{USAGE}"),
+        }
+    }
+    let (line, column) = position.with_context(|| format!("missing --position\n{USAGE}"))?;
+    Ok(Args {
+        command,
+        file: file.with_context(|| format!("missing --file\n{USAGE}"))?,
+        line,
+        column,
+        config,
+        model,
+        parameters,
+        language,
+        json,
+    })
+}
+
+// Clients usually send these, a few memory backends and splitters look at them
+fn get_language_id(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+    {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "jsx" | "mjs" | "cjs" => "javascript",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "typescriptreact",
+        "go" => "go",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => "cpp",
+        "java" => "java",
+        "md" => "markdown",
+        _ => "plaintext",
+    }
+}
+
+// Plays the client's side of the connection to a server running in this process
+pub struct Client {
+    connection: Connection,
+    server: JoinHandle<anyhow::Result<()>>,
+    next_id: i32,
+}
+
+impl Client {
+    pub fn start(
+        serve: fn(Connection) -> anyhow::Result<()>,
+        root_uri: &Url,
+        options: Value,
+    ) -> anyhow::Result<Self> {
+        let (connection, server_connection) = Connection::memory();
+        let mut client = Self {
+            connection,
+            server: thread::spawn(move || serve(server_connection)),
+            next_id: 0,
+        };
+        let initialized = client
+            .request(
+                "initialize",
+                json!({
+                    "processId": null,
+                    "rootUri": root_uri,
+                    "capabilities": {},
+                    "initializationOptions": options
+                }),
+            )
+            .and_then(|_| client.notify("initialized", json!({})))
+            .and_then(|_| client.wait_for_ready());
+        match initialized {
+            Ok(()) => Ok(client),
+            Err(e) => Err(client.close().err().unwrap_or(e)),
+        }
+    }
+
+    pub fn request(&mut self, method: &str, params: Value) -> anyhow::Result<Value> {
+        let id = RequestId::from(self.next_id);
+        self.next_id += 1;
+        self.connection.sender.send(Message::Request(Request::new(
+            id.clone(),
+            method.to_string(),
+            params,
+        )))?;
+        // Anything else the server sends, like log messages, is not interesting here
+        loop {
+            match self.connection.receiver.recv()? {
+                Message::Response(response) if response.id == id => {
+                    if let Some(error) = response.error {
+                        anyhow::bail!("{method} failed: {}", error.message);
+                    }
+                    return Ok(response.result.unwrap_or_default());
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    // Requests are not sent to the models before they are loaded
+    fn wait_for_ready(&self) -> anyhow::Result<()> {
+        loop {
+            if let Message::Notification(notification) = self.connection.receiver.recv()? {
+                if notification.method == <Ready as lsp_types::notification::Notification>::METHOD {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    pub fn notify(&self, method: &str, params: Value) -> anyhow::Result<()> {
+        self.connection
+            .sender
+            .send(Message::Notification(Notification::new(
+                method.to_string(),
+                params,
+            )))?;
+        Ok(())
+    }
+
+    // Sends the file like an editor does when it is opened
+    pub fn open(&self, path: &Path, language: Option<&str>) -> anyhow::Result<Url> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("error reading {}", path.display()))?;
+        let uri = Url::from_file_path(path)
+            .map_err(|_| anyhow::anyhow!("invalid file path {}", path.display()))?;
+        self.notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": {
+                    "uri": uri,
+                    "languageId": language.unwrap_or(get_language_id(path)),
+                    "version": 0,
+                    "text": text
+                }
+            }),
+        )?;
+        Ok(uri)
+    }
+
+    pub fn shutdown(mut self) -> anyhow::Result<()> {
+        let result = self
+            .request("shutdown", Value::Null)
+            .and_then(|_| self.notify("exit", Value::Null));
+        self.close().and(result)
+    }
+
+    // Dropping the client's side ends the server if it is still running
+    pub fn close(self) -> anyhow::Result<()> {
+        drop(self.connection);
+        match self.server.join() {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!("the server panicked"),
+        }
+    }
+}
+
+// The workspace config file of the current directory applies like it would in an editor
+pub fn get_root_uri() -> anyhow::Result<Url> {
+    Url::from_directory_path(std::env::current_dir()?)
+        .map_err(|_| anyhow::anyhow!("invalid working directory"))
+}
+
+// Takes 1-based lines and columns
+pub fn text_document_position(uri: &Url, line: u32, column: u32) -> Value {
+    json!({
+        "textDocument": { "uri": uri },
+        "position": { "line": line - 1, "character": column - 1 }
+    })
+}
+
+pub fn get_completion_text(result: Value) -> anyhow::Result<Vec<String>> {
+    let items = match serde_json::from_value::<Option<CompletionResponse>>(result)? {
+        Some(CompletionResponse::Array(items)) => items,
+        Some(CompletionResponse::List(list)) => list.items,
+        None => vec![],
+    };
+    Ok(items
+        .into_iter()
+        .map(|item| match item.text_edit {
+            Some(CompletionTextEdit::Edit(edit)) => edit.new_text,
+            Some(CompletionTextEdit::InsertAndReplace(edit)) => edit.new_text,
+            None => item.insert_text.unwrap_or(item.label),
+        })
+        .collect())
+}
+
+fn print_result(args: &Args, result: Value, duration: Duration) -> anyhow::Result<()> {
+    if args.json {
+        let output = json!({ "result": result, "durationMs": duration.as_millis() as u64 });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+    match args.command {
+        Command::Complete => {
+            let completions = get_completion_text(result)?;
+            if completions.is_empty() {
+                println!("no completions");
+            }
+            for (i, completion) in completions.iter().enumerate() {
+                if i > 0 {
+                    println!("---");
+                }
+                println!("{completion}");
+            }
+        }
+        Command::Generate => {
+            let result: GenerateResult = serde_json::from_value(result)?;
+            println!("{}", result.generated_text);
+            if let Some(served_by) = result.served_by {
+                println!("served by {served_by}");
+            }
+        }
+    }
+    println!("took {} ms", duration.as_millis());
+    Ok(())
+}
+
+// Runs one request against the server in this process without an editor, for trying out
+// configurations and prompts in scripts and CI
+pub fn run(args: Args, serve: fn(Connection) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let path = args
+        .file
+        .canonicalize()
+        .with_context(|| format!("error reading {}", args.file.display()))?;
+    let options = match &args.config {
+        Some(config) => workspace_config::load(config)?,
+        None => Value::Null,
+    };
+    let mut client = Client::start(serve, &get_root_uri()?, options)?;
+    let result = (|| -> anyhow::Result<()> {
+        let uri = client.open(&path, args.language.as_deref())?;
+        let params = text_document_position(&uri, args.line, args.column);
+        let (method, params) = match args.command {
+            Command::Complete => ("textDocument/completion", params),
+            Command::Generate => {
+                let mut params = params;
+                params["model"] = json!(args.model);
+                params["parameters"] = args.parameters.clone();
+                ("textDocument/generation", params)
+            }
+        };
+        let start = Instant::now();
+        let result = client.request(method, params)?;
+        print_result(&args, result, start.elapsed())
+    })();
+    match result {
+        Ok(()) => client.shutdown(),
+        Err(e) => {
+            let _ = client.close();
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_headless_args() -> anyhow::Result<()> {
+        let args = parse_args(
+            Command::Generate,
+            [
+                "--file",
+                "foo.py",
+                "--position",
+                "120:8",
+                "--model",
+                "model1",
+                "--parameters",
+                "{\"max_tokens\": 32}",
+            ]
+            .into_iter()
+            .map(String::from),
+        )?;
+        assert_eq!((args.line, args.column), (120, 8));
+        assert_eq!(args.model.as_deref(), Some("model1"));
+        assert_eq!(args.parameters, json!({"max_tokens": 32}));
+        assert!(!args.json);
+
+        assert!(parse_args(
+            Command::Complete,
+            ["--file", "foo.py"].into_iter().map(String::from)
+        )
+        .is_err());
+        assert!(parse_position("0:1").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use reqwest::{Certificate, Client, NoProxy, Proxy};
+
+use crate::config::Http;
+
+// Shared by every request so the http config applies to all of them and connections are reused
+static CLIENT: Lazy<RwLock<Client>> = Lazy::new(|| RwLock::new(Client::new()));
+
+pub fn get_client() -> Client {
+    CLIENT.read().clone()
+}
+
+// Checks the config without applying it
+pub fn validate(config: Option<&Http>) -> anyhow::Result<()> {
+    build_client(&config.cloned().unwrap_or_default())?;
+    Ok(())
+}
+
+pub fn configure(config: Option<&Http>) -> anyhow::Result<()> {
+    *CLIENT.write() = build_client(&config.cloned().unwrap_or_default())?;
+    Ok(())
+}
+
+fn get_env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+}
+
+fn get_proxy(config: &Http) -> anyhow::Result<Option<Proxy>> {
+    let Some(url) = config.proxy.clone().or_else(|| {
+        get_env_var(&[
+            "HTTPS_PROXY",
+            "https_proxy",
+            "HTTP_PROXY",
+            "http_proxy",
+            "ALL_PROXY",
+            "all_proxy",
+        ])
+    }) else {
+        return Ok(None);
+    };
+    let no_proxy = if config.no_proxy.is_empty() {
+        get_env_var(&["NO_PROXY", "no_proxy"])
+    } else {
+        Some(config.no_proxy.join(","))
+    };
+    let proxy = Proxy::all(&url).with_context(|| format!("invalid proxy url: {url}"))?;
+    Ok(Some(proxy.no_proxy(
+        no_proxy.as_deref().and_then(NoProxy::from_string),
+    )))
+}
+
+// The certificates of a PEM bundle, reqwest only reads the first one of a file
+fn split_pem(pem: &str) -> Vec<&str> {
+    pem.split_inclusive("-----END CERTIFICATE-----")
+        .filter(|certificate| certificate.contains("-----BEGIN CERTIFICATE-----"))
+        .collect()
+}
+
+fn build_client(config: &Http) -> anyhow::Result<Client> {
+    let mut builder = Client::builder();
+    if let Some(proxy) = get_proxy(config)? {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(path) = &config.ca_cert_path {
+        let pem = std::fs::read_to_string(path)
+            .with_context(|| format!("error reading `ca_cert_path`: {path}"))?;
+        let certificates = split_pem(&pem);
+        anyhow::ensure!(
+            !certificates.is_empty(),
+            "no PEM certificates found in `ca_cert_path`: {path}"
+        );
+        for certificate in certificates {
+            builder = builder.add_root_certificate(Certificate::from_pem(certificate.as_bytes())?);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_client() -> anyhow::Result<()> {
+        let pem = "# corp\n-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----\n";
+        let certificates = split_pem(pem);
+        assert_eq!(certificates.len(), 2);
+        assert!(certificates[1]
+            .trim()
+            .starts_with("-----BEGIN CERTIFICATE-----\nMIIC"));
+
+        let config = Http {
+            proxy: Some("http://proxy.corp:3128".to_string()),
+            no_proxy: vec!["localhost".to_string(), "10.0.0.0/8".to_string()],
+            ca_cert_path: None,
+        };
+        assert!(get_proxy(&config)?.is_some());
+        build_client(&config)?;
+
+        let config = Http {
+            ca_cert_path: Some("/does/not/exist.pem".to_string()),
+            ..Http::default()
+        };
+        assert!(build_client(&config).is_err());
+        Ok(())
+    }
+}
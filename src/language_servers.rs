@@ -0,0 +1,452 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::BufReader,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use lsp_server::{Message, Notification, Request, RequestId, Response};
+use lsp_types::{GotoDefinitionResponse, Hover, HoverContents, MarkedString, Position};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use tokio::{sync::oneshot, time::Instant};
+use tracing::{error, info};
+
+use crate::config::{self, Config};
+
+// How long the server gets to answer initialize
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The lines searched for identifiers, the cursor's and the ones above it
+const IDENTIFIER_LINES: usize = 3;
+
+// Looking these up only returns the docs of the language
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "class", "const", "continue", "def", "else", "enum", "false",
+    "fn", "for", "from", "function", "if", "impl", "import", "in", "let", "match", "mut", "new",
+    "None", "null", "pub", "return", "self", "Self", "struct", "true", "use", "var", "while",
+];
+
+type Pending = Arc<Mutex<HashMap<RequestId, oneshot::Sender<Response>>>>;
+
+// A language server lsp-ai is the client of
+struct Client {
+    child: Mutex<Child>,
+    stdin: Arc<Mutex<ChildStdin>>,
+    pending: Pending,
+    next_id: AtomicI32,
+    // The versions of the documents sent to the server
+    versions: Mutex<HashMap<String, i32>>,
+}
+
+fn send(stdin: &Mutex<ChildStdin>, message: Message) -> anyhow::Result<()> {
+    Ok(message.write(&mut *stdin.lock())?)
+}
+
+// Answers what the server asks the client. Settings are left to the server's defaults.
+fn answer(request: Request) -> Response {
+    let result = match request.method.as_str() {
+        "workspace/configuration" => {
+            let items = request.params["items"]
+                .as_array()
+                .map_or(0, |items| items.len());
+            Value::Array(vec![Value::Null; items])
+        }
+        _ => Value::Null,
+    };
+    Response::new_ok(request.id, result)
+}
+
+impl Client {
+    async fn start(
+        configuration: &config::LanguageServer,
+        root_uri: Option<&str>,
+        workspace_folders: &[String],
+    ) -> anyhow::Result<Self> {
+        let mut child = Command::new(&configuration.command)
+            .args(&configuration.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("starting `{}`", configuration.command))?;
+        let stdin = Arc::new(Mutex::new(child.stdin.take().context("no stdin")?));
+        let mut stdout = BufReader::new(child.stdout.take().context("no stdout")?);
+        let pending: Pending = Arc::default();
+
+        let reader_pending = pending.clone();
+        let reader_stdin = stdin.clone();
+        thread::spawn(move || loop {
+            match Message::read(&mut stdout) {
+                Ok(Some(Message::Response(response))) => {
+                    if let Some(tx) = reader_pending.lock().remove(&response.id) {
+                        let _ = tx.send(response);
+                    }
+                }
+                Ok(Some(Message::Request(request))) => {
+                    if send(&reader_stdin, Message::Response(answer(request))).is_err() {
+                        break;
+                    }
+                }
+                Ok(Some(Message::Notification(_))) => (),
+                // Waiting requests fail once their senders are dropped
+                Ok(None) | Err(_) => {
+                    reader_pending.lock().clear();
+                    break;
+                }
+            }
+        });
+
+        let client = Self {
+            child: Mutex::new(child),
+            stdin,
+            pending,
+            next_id: AtomicI32::new(0),
+            versions: Mutex::new(HashMap::new()),
+        };
+        client
+            .request(
+                "initialize",
+                json!({
+                    "processId": std::process::id(),
+                    "rootUri": root_uri,
+                    "workspaceFolders": workspace_folders
+                        .iter()
+                        .map(|uri| json!({"uri": uri, "name": uri}))
+                        .collect::<Vec<_>>(),
+                    "initializationOptions": configuration.initialization_options,
+                    "capabilities": {
+                        "textDocument": {
+                            "hover": {"contentFormat": ["markdown", "plaintext"]},
+                            "definition": {"linkSupport": true}
+                        }
+                    }
+                }),
+                Instant::now() + INITIALIZE_TIMEOUT,
+            )
+            .await
+            .context("initializing")?;
+        client.notify("initialized", json!({}))?;
+        Ok(client)
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(self.child.lock().try_wait(), Ok(None))
+    }
+
+    fn notify(&self, method: &str, params: Value) -> anyhow::Result<()> {
+        send(
+            &self.stdin,
+            Message::Notification(Notification::new(method.to_owned(), params)),
+        )
+    }
+
+    async fn request(
+        &self,
+        method: &str,
+        params: Value,
+        deadline: Instant,
+    ) -> anyhow::Result<Value> {
+        let id = RequestId::from(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().insert(id.clone(), tx);
+        send(
+            &self.stdin,
+            Message::Request(Request::new(id.clone(), method.to_owned(), params)),
+        )?;
+        let response = match tokio::time::timeout_at(deadline, rx).await {
+            Ok(response) => response.context("the language server exited")?,
+            Err(_) => {
+                self.pending.lock().remove(&id);
+                self.notify("$/cancelRequest", json!({ "id": id }))?;
+                anyhow::bail!("{method} timed out");
+            }
+        };
+        if let Some(error) = response.error {
+            anyhow::bail!("{method} failed: {}", error.message);
+        }
+        Ok(response.result.unwrap_or(Value::Null))
+    }
+
+    // Sends the text of the document so the server answers for what is being edited
+    fn sync_document(&self, uri: &str, language_id: &str, text: &str) -> anyhow::Result<()> {
+        let mut versions = self.versions.lock();
+        match versions.get_mut(uri) {
+            Some(version) => {
+                *version += 1;
+                self.notify(
+                    "textDocument/didChange",
+                    json!({
+                        "textDocument": {"uri": uri, "version": *version},
+                        "contentChanges": [{"text": text}]
+                    }),
+                )
+            }
+            None => {
+                versions.insert(uri.to_owned(), 1);
+                self.notify(
+                    "textDocument/didOpen",
+                    json!({
+                        "textDocument": {"uri": uri, "languageId": language_id, "version": 1, "text": text}
+                    }),
+                )
+            }
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = self.child.get_mut().kill();
+    }
+}
+
+// The identifiers on the cursor's line and the lines above it, nearest to the cursor first
+fn get_identifiers(
+    text: &str,
+    position: Position,
+    max_identifiers: usize,
+) -> Vec<(String, Position)> {
+    let cursor_line = position.line as usize;
+    let mut identifiers: Vec<(String, Position)> = vec![];
+    let lines = text
+        .lines()
+        .enumerate()
+        .skip(cursor_line.saturating_sub(IDENTIFIER_LINES - 1))
+        .take(cursor_line.min(IDENTIFIER_LINES - 1) + 1)
+        .collect::<Vec<_>>();
+    for (line, text) in lines.into_iter().rev() {
+        let chars: Vec<char> = text.chars().collect();
+        let mut found = vec![];
+        let mut i = 0;
+        while i < chars.len() {
+            let is_start = (chars[i].is_alphabetic() || chars[i] == '_')
+                && (i == 0 || !(chars[i - 1].is_alphanumeric() || chars[i - 1] == '_'));
+            if !is_start {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            found.push((chars[start..i].iter().collect::<String>(), start));
+        }
+        if line == cursor_line {
+            let cursor = position.character as usize;
+            found.sort_by_key(|(_, start)| start.abs_diff(cursor));
+        } else {
+            found.reverse();
+        }
+        for (name, start) in found {
+            if !KEYWORDS.contains(&name.as_str()) && !identifiers.iter().any(|(n, _)| *n == name) {
+                identifiers.push((name, Position::new(line as u32, start as u32)));
+            }
+        }
+    }
+    identifiers.truncate(max_identifiers);
+    identifiers
+}
+
+// Servers put the signature in code blocks before the docs, which follow a `---`
+fn get_hover_signature(hover: Hover) -> Option<String> {
+    let text = match hover.contents {
+        HoverContents::Scalar(MarkedString::LanguageString(code)) => return Some(code.value),
+        HoverContents::Scalar(MarkedString::String(text)) => text,
+        HoverContents::Array(contents) => match contents.into_iter().next()? {
+            MarkedString::LanguageString(code) => return Some(code.value),
+            MarkedString::String(text) => text,
+        },
+        HoverContents::Markup(markup) => markup.value,
+    };
+    let text = text.split("\n---").next().unwrap_or_default();
+    let code: Vec<&str> = text
+        .split("```")
+        .skip(1)
+        .step_by(2)
+        .map(|block| block.split_once('\n').map_or("", |(_, code)| code).trim())
+        .filter(|code| !code.is_empty())
+        .collect();
+    let signature = if code.is_empty() {
+        text.trim().to_owned()
+    } else {
+        code.join("\n")
+    };
+    (!signature.is_empty()).then_some(signature)
+}
+
+// The line the identifier is defined on
+fn get_definition_line(
+    definition: GotoDefinitionResponse,
+    uri: &str,
+    text: &str,
+) -> Option<String> {
+    let (target, line) = match definition {
+        GotoDefinitionResponse::Scalar(location) => (location.uri, location.range.start.line),
+        GotoDefinitionResponse::Array(locations) => {
+            let location = locations.into_iter().next()?;
+            (location.uri, location.range.start.line)
+        }
+        GotoDefinitionResponse::Link(links) => {
+            let link = links.into_iter().next()?;
+            (link.target_uri, link.target_selection_range.start.line)
+        }
+    };
+    let file = if target.as_str() == uri {
+        text.to_owned()
+    } else {
+        std::fs::read_to_string(target.to_file_path().ok()?).ok()?
+    };
+    let line = file.lines().nth(line as usize)?.trim();
+    (!line.is_empty()).then(|| line.to_owned())
+}
+
+// The language servers of the languages configured in `language_servers`, started when first
+// needed and restarted when they exit
+pub struct LanguageServers {
+    configurations: HashMap<String, config::LanguageServer>,
+    root_uri: Option<String>,
+    workspace_folders: Vec<String>,
+    clients: tokio::sync::Mutex<HashMap<String, Arc<Client>>>,
+    // Not tried again after they failed to start
+    failed: Mutex<HashSet<String>>,
+}
+
+impl LanguageServers {
+    pub fn new(configuration: &Config) -> Self {
+        Self {
+            configurations: configuration.get_language_servers().clone(),
+            root_uri: configuration.get_root_uri().map(|uri| uri.to_owned()),
+            workspace_folders: configuration.get_workspace_folders(),
+            clients: tokio::sync::Mutex::new(HashMap::new()),
+            failed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    async fn get_client(&self, language_id: &str) -> Option<Arc<Client>> {
+        let configuration = self.configurations.get(language_id)?;
+        if self.failed.lock().contains(language_id) {
+            return None;
+        }
+        let mut clients = self.clients.lock().await;
+        if let Some(client) = clients
+            .get(language_id)
+            .filter(|client| client.is_running())
+        {
+            return Some(client.clone());
+        }
+        info!(
+            "starting the {language_id} language server `{}`",
+            configuration.command
+        );
+        match Client::start(
+            configuration,
+            self.root_uri.as_deref(),
+            &self.workspace_folders,
+        )
+        .await
+        {
+            Ok(client) => {
+                let client = Arc::new(client);
+                clients.insert(language_id.to_owned(), client.clone());
+                Some(client)
+            }
+            Err(e) => {
+                error!("the {language_id} language server failed to start: {e:?}");
+                clients.remove(language_id);
+                self.failed.lock().insert(language_id.to_owned());
+                None
+            }
+        }
+    }
+
+    // The signatures of the identifiers around `position` with hover, or the lines they are
+    // defined on when hover has nothing. Empty for languages without a language server.
+    pub async fn get_signatures(
+        &self,
+        uri: &str,
+        language_id: &str,
+        text: &str,
+        position: Position,
+    ) -> Vec<String> {
+        let Some(configuration) = self.configurations.get(language_id) else {
+            return vec![];
+        };
+        let Some(client) = self.get_client(language_id).await else {
+            return vec![];
+        };
+        if let Err(e) = client.sync_document(uri, language_id, text) {
+            error!("sending {uri} to the {language_id} language server: {e}");
+            return vec![];
+        }
+        let deadline = Instant::now() + Duration::from_millis(configuration.timeout_ms);
+        let mut signatures: Vec<String> = vec![];
+        for (name, position) in get_identifiers(text, position, configuration.max_identifiers) {
+            let params = json!({"textDocument": {"uri": uri}, "position": position});
+            let signature = match client
+                .request("textDocument/hover", params.clone(), deadline)
+                .await
+                .and_then(|hover| Ok(serde_json::from_value::<Option<Hover>>(hover)?))
+            {
+                Ok(Some(hover)) => get_hover_signature(hover),
+                Ok(None) => client
+                    .request("textDocument/definition", params, deadline)
+                    .await
+                    .and_then(|definition| {
+                        Ok(serde_json::from_value::<Option<GotoDefinitionResponse>>(
+                            definition,
+                        )?)
+                    })
+                    .ok()
+                    .flatten()
+                    .and_then(|definition| get_definition_line(definition, uri, text)),
+                Err(e) => {
+                    info!("looking up `{name}`: {e}");
+                    // Past the deadline every other lookup times out too
+                    if Instant::now() >= deadline {
+                        break;
+                    }
+                    None
+                }
+            };
+            if let Some(signature) = signature.filter(|s| !signatures.contains(s)) {
+                signatures.push(signature);
+            }
+        }
+        signatures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{MarkupContent, MarkupKind};
+
+    #[test]
+    fn finds_identifiers_and_signatures() {
+        let text = "fn main() {\n    let total = add(1, 2);\n    print(total.abs)\n}\n";
+        let identifiers: Vec<String> = get_identifiers(text, Position::new(2, 16), 3)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(identifiers, vec!["abs", "total", "print"]);
+
+        let hover = Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: "```rust\nmy_crate\n```\n\n```rust\npub fn add(a: i32, b: i32) -> i32\n```\n\n---\n\nAdds ```a``` and ```b```".to_string(),
+            }),
+            range: None,
+        };
+        assert_eq!(
+            get_hover_signature(hover).as_deref(),
+            Some("my_crate\npub fn add(a: i32, b: i32) -> i32")
+        );
+    }
+}
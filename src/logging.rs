@@ -0,0 +1,297 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace::Tracer, Resource};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{
+    filter::Filtered,
+    fmt::{
+        self,
+        format::{Format, Json, JsonFields},
+    },
+    layer::{Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::config::{Log, Otlp};
+
+type OtlpLayer = Option<OpenTelemetryLayer<Registry, Tracer>>;
+type WithOtlp = Layered<
+    Filtered<reload::Layer<OtlpLayer, Registry>, reload::Layer<EnvFilter, Registry>, Registry>,
+    Registry,
+>;
+type FileLayer = Option<fmt::Layer<WithOtlp, JsonFields, Format<Json>, Mutex<RotatingFile>>>;
+
+// The log level, the log file and the exporter can all change after the subscriber is installed.
+// Spans are exported with their own filter so exporting them does not fill the logs.
+struct Handles {
+    filter: reload::Handle<EnvFilter, WithOtlp>,
+    file: reload::Handle<FileLayer, WithOtlp>,
+    otlp: reload::Handle<OtlpLayer, Registry>,
+    otlp_filter: reload::Handle<EnvFilter, Registry>,
+}
+
+static HANDLES: OnceCell<Handles> = OnceCell::new();
+
+// The batch exporter runs on tokio and the main loop does not
+static OTLP_RUNTIME: OnceCell<tokio::runtime::Runtime> = OnceCell::new();
+
+// Logs to stderr with the level from the `LSP_AI_LOG` environment variable
+// If the variables value is malformed or missing, sets the default log level to ERROR
+pub fn init() {
+    let (otlp, otlp_handle) = reload::Layer::new(None);
+    let (otlp_filter, otlp_filter_handle) = reload::Layer::new(EnvFilter::new("off"));
+    let (file, file_handle) = reload::Layer::new(None);
+    let (filter, filter_handle) = reload::Layer::new(EnvFilter::from_env("LSP_AI_LOG"));
+    tracing_subscriber::registry()
+        .with(otlp.with_filter(otlp_filter))
+        .with(
+            fmt::layer()
+                .with_writer(io::stderr)
+                .with_ansi(false)
+                .without_time()
+                .and_then(file)
+                .with_filter(filter),
+        )
+        .init();
+    let _ = HANDLES.set(Handles {
+        filter: filter_handle,
+        file: file_handle,
+        otlp: otlp_handle,
+        otlp_filter: otlp_filter_handle,
+    });
+}
+
+fn get_handles() -> anyhow::Result<&'static Handles> {
+    HANDLES.get().context("logging is not initialized")
+}
+
+// Takes filter directives like `LSP_AI_LOG`, e.g. `debug` or `lsp_ai=trace`
+pub fn set_level(level: &str) -> anyhow::Result<()> {
+    get_handles()?.filter.reload(get_filter(level)?)?;
+    Ok(())
+}
+
+fn get_path(config: &Log, root_uri: Option<&str>) -> anyhow::Result<PathBuf> {
+    match &config.path {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => {
+            // Each workspace gets its own log file
+            let name = xxh3_64(root_uri.unwrap_or("global").as_bytes());
+            Ok(directories::ProjectDirs::from("", "", "lsp-ai")
+                .context("unable to find the data directory")?
+                .data_dir()
+                .join("logs")
+                .join(format!("{name:x}.log")))
+        }
+    }
+}
+
+fn open_file(config: &Log, root_uri: Option<&str>) -> anyhow::Result<RotatingFile> {
+    let path = get_path(config, root_uri)?;
+    RotatingFile::open(
+        path.clone(),
+        config.max_size_mb * 1024 * 1024,
+        config.max_files,
+    )
+    .with_context(|| format!("error opening the log file {}", path.display()))
+}
+
+fn get_filter(level: &str) -> anyhow::Result<EnvFilter> {
+    EnvFilter::try_new(level).with_context(|| format!("invalid log level: {level}"))
+}
+
+// Checks the config without applying it, the log file is created if it does not exist
+pub fn validate(config: Option<&Log>, root_uri: Option<&str>) -> anyhow::Result<()> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+    open_file(config, root_uri)?;
+    if let Some(level) = &config.level {
+        get_filter(level)?;
+    }
+    Ok(())
+}
+
+pub fn configure(config: Option<&Log>, root_uri: Option<&str>) -> anyhow::Result<()> {
+    let handles = get_handles()?;
+    let Some(config) = config else {
+        handles.file.reload(None)?;
+        return Ok(());
+    };
+    let file = open_file(config, root_uri)?;
+    handles
+        .file
+        .reload(Some(fmt::layer().json().with_writer(Mutex::new(file))))?;
+    if let Some(level) = &config.level {
+        set_level(level)?;
+    }
+    Ok(())
+}
+
+pub fn validate_otlp(config: Option<&Otlp>) -> anyhow::Result<()> {
+    if let Some(config) = config {
+        get_filter(&config.level).context("invalid otlp level")?;
+    }
+    Ok(())
+}
+
+pub fn configure_otlp(config: Option<&Otlp>) -> anyhow::Result<()> {
+    let handles = get_handles()?;
+    let Some(config) = config else {
+        handles.otlp_filter.reload(EnvFilter::new("off"))?;
+        handles.otlp.reload(None)?;
+        opentelemetry::global::shutdown_tracer_provider();
+        return Ok(());
+    };
+    let filter = get_filter(&config.level).context("invalid otlp level")?;
+    let runtime = OTLP_RUNTIME.get_or_try_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+    })?;
+    let _guard = runtime.enter();
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&config.endpoint)
+                .with_headers(config.headers.clone()),
+        )
+        .with_trace_config(
+            opentelemetry_sdk::trace::config().with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )])),
+        )
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+    handles
+        .otlp
+        .reload(Some(tracing_opentelemetry::layer().with_tracer(tracer)))?;
+    handles.otlp_filter.reload(filter)?;
+    Ok(())
+}
+
+// Exports the spans that are still buffered
+pub fn shutdown() {
+    if OTLP_RUNTIME.get().is_some() {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+// Appends to `path`. When it would grow past `max_bytes` it is renamed to `path.1`, `path.1` to
+// `path.2` and so on, dropping the oldest past `max_files`.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn get_rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            // A missing rotated file only means there have not been that many rotations yet
+            for index in (1..self.max_files).rev() {
+                let _ = std::fs::rename(
+                    self.get_rotated_path(index),
+                    self.get_rotated_path(index + 1),
+                );
+            }
+            std::fs::rename(&self.path, self.get_rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_log_file() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("lsp-ai-{:x}", rand::random::<u64>()));
+        let path = dir.join("lsp-ai.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2)?;
+        for line in ["line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+            file.write_all(line.as_bytes())?;
+        }
+        file.flush()?;
+        assert_eq!(std::fs::read_to_string(&path)?, "line 4\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("lsp-ai.log.1"))?,
+            "line 3\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("lsp-ai.log.2"))?,
+            "line 2\n"
+        );
+        assert!(!dir.join("lsp-ai.log.3").exists());
+
+        // Appends to the existing file
+        let mut file = RotatingFile::open(path.clone(), 20, 2)?;
+        file.write_all(b"line 5\n")?;
+        file.flush()?;
+        assert_eq!(std::fs::read_to_string(&path)?, "line 4\nline 5\n");
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -1,33 +1,94 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use lsp_server::{Connection, ExtractError, Message, Notification, Request, RequestId};
+use lsp_server::{
+    Connection, ErrorCode, ExtractError, Message, Notification, Request, RequestId, Response,
+};
 use lsp_types::{
-    request::Completion, CompletionOptions, DidChangeTextDocumentParams, DidOpenTextDocumentParams,
-    RenameFilesParams, ServerCapabilities, TextDocumentSyncKind,
+    notification::Exit,
+    request::{CodeActionRequest, Completion, ExecuteCommand, Shutdown},
+    CancelParams, CodeActionProviderCapability, CompletionOptions, Diagnostic,
+    DidChangeConfigurationParams, DidChangeTextDocumentParams, DidChangeWorkspaceFoldersParams,
+    DidOpenTextDocumentParams, ExecuteCommandOptions, NumberOrString, OneOf,
+    PublishDiagnosticsParams, RenameFilesParams, ServerCapabilities, TextDocumentSyncKind, Url,
+    WorkspaceFoldersServerCapabilities, WorkspaceServerCapabilities,
 };
 use std::{
     collections::HashMap,
+    path::Path,
     sync::{mpsc, Arc},
     thread,
 };
 use tracing::error;
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
+mod agent;
+mod auth;
+mod bench;
+mod code_actions;
+mod commit_message;
+mod completion_cache;
+mod completion_rules;
 mod config;
+mod conversations;
 mod custom_requests;
+mod embedding_models;
+mod git;
+mod headless;
+mod http_client;
+mod language_servers;
+mod logging;
+mod mcp;
+mod mcp_server;
 mod memory_backends;
 mod memory_worker;
-#[cfg(feature = "llama_cpp")]
+mod metrics;
+mod plugins;
+mod post_process;
+mod process_settings;
+mod progress;
+mod prompt_files;
+mod proxy;
+mod redaction;
+mod rerank_models;
+mod splitters;
+mod structured_output;
+#[cfg(any(feature = "llama_cpp", feature = "candle"))]
 mod template;
+mod tokenizer;
+mod tools;
 mod transformer_backends;
 mod transformer_worker;
+mod transport;
+mod udiff;
+mod usage;
 mod utils;
+mod workspace_config;
+mod workspace_edit;
 
+use completion_cache::CompletionCache;
 use config::Config;
-use custom_requests::generation::Generation;
+use conversations::Conversations;
+use custom_requests::{
+    agent::Agent,
+    chat::Chat,
+    commit_message::GenerateCommitMessage,
+    completion_feedback::{CompletionAccepted, CompletionFeedbackParams, CompletionRejected},
+    generation::Generation,
+    inline_completion::InlineCompletion,
+    memory_stats::MemoryStats,
+    preview_prompt::PreviewPrompt,
+    raw_generation::RawGeneration,
+    reindex::Reindex,
+    search::WorkspaceSearch,
+    set_log_level::SetLogLevel,
+    usage::Usage,
+};
 use memory_backends::MemoryBackend;
-use transformer_backends::TransformerBackend;
-use transformer_worker::{CompletionRequest, GenerationRequest, WorkerRequest};
+use transformer_worker::{
+    AgentRequest, ChatRequest, CommitMessageRequest, CompletionRequest, ExecuteCommandRequest,
+    GenerationRequest, InlineCompletionRequest, PreviewPromptRequest, RawGenerationRequest,
+    WorkerRequest,
+};
+use transport::Transport;
 
 use crate::{
     custom_requests::generation_stream::GenerationStream,
@@ -51,85 +112,217 @@ where
 }
 
 fn main() -> Result<()> {
-    // Builds a tracing subscriber from the `LSP_AI_LOG` environment variable
-    // If the variables value is malformed or missing, sets the default log level to ERROR
-    FmtSubscriber::builder()
-        .with_writer(std::io::stderr)
-        .with_ansi(false)
-        .without_time()
-        .with_env_filter(EnvFilter::from_env("LSP_AI_LOG"))
-        .init();
-
-    let (connection, io_threads) = Connection::stdio();
-    let server_capabilities = serde_json::to_value(ServerCapabilities {
+    // Flags for checking configurations and picking the transport, lsp-ai talks LSP over stdio by
+    // default
+    let mut cli_args = std::env::args().skip(1);
+    let mut transport = Transport::Stdio;
+    match cli_args.next().as_deref() {
+        Some("complete") => {
+            let args = headless::parse_args(headless::Command::Complete, cli_args)?;
+            logging::init();
+            return headless::run(args, serve);
+        }
+        Some("generate") => {
+            let args = headless::parse_args(headless::Command::Generate, cli_args)?;
+            logging::init();
+            return headless::run(args, serve);
+        }
+        Some("bench") => {
+            let args = bench::parse_args(cli_args)?;
+            logging::init();
+            return bench::run(args, serve);
+        }
+        Some("--mcp") => {
+            let args = mcp_server::parse_args(cli_args)?;
+            logging::init();
+            return mcp_server::run(args, serve);
+        }
+        Some("--proxy") => {
+            let args = proxy::parse_args(cli_args)?;
+            logging::init();
+            return proxy::run(args, serve);
+        }
+        #[cfg(feature = "llama_cpp")]
+        Some(transformer_backends::llama_cpp::worker::WORKER_COMMAND) => {
+            logging::init();
+            return transformer_backends::llama_cpp::worker::run();
+        }
+        Some("--emit-config-schema") => {
+            println!("{}", serde_json::to_string_pretty(&config::get_schema()?)?);
+            return Ok(());
+        }
+        Some("--validate-config") => {
+            let path = cli_args
+                .next()
+                .context("usage: lsp-ai --validate-config <file>")?;
+            config::validate_file(Path::new(&path))?;
+            println!("{path} is a valid configuration");
+            return Ok(());
+        }
+        Some("--tcp") => {
+            transport = Transport::Tcp(cli_args.next().context("usage: lsp-ai --tcp <address>")?)
+        }
+        Some("--socket") => {
+            transport = Transport::Socket(cli_args.next().context("usage: lsp-ai --socket <path>")?)
+        }
+        Some("--websocket") => {
+            transport = Transport::WebSocket(
+                cli_args
+                    .next()
+                    .context("usage: lsp-ai --websocket <address>")?,
+            )
+        }
+        _ => (),
+    }
+
+    logging::init();
+    transport::run(transport, serve)?;
+    logging::shutdown();
+    Ok(())
+}
+
+// Serves one client from initialize to exit
+fn serve(connection: Connection) -> Result<()> {
+    let mut server_capabilities = serde_json::to_value(ServerCapabilities {
         completion_provider: Some(CompletionOptions::default()),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        execute_command_provider: Some(ExecuteCommandOptions {
+            commands: vec![
+                code_actions::RUN_ACTION_COMMAND.to_string(),
+                commit_message::GENERATE_COMMIT_MESSAGE_COMMAND.to_string(),
+            ],
+            ..Default::default()
+        }),
         text_document_sync: Some(lsp_types::TextDocumentSyncCapability::Kind(
             TextDocumentSyncKind::INCREMENTAL,
         )),
+        workspace: Some(WorkspaceServerCapabilities {
+            workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                supported: Some(true),
+                change_notifications: Some(OneOf::Left(true)),
+            }),
+            file_operations: None,
+        }),
         ..Default::default()
     })?;
+    // lsp-types does not have the LSP 3.18 inline completion capability yet
+    server_capabilities["inlineCompletionProvider"] = serde_json::json!({});
     let initialization_args = connection.initialize(server_capabilities)?;
 
-    main_loop(connection, initialization_args)?;
-    io_threads.join()?;
-    Ok(())
+    // Wrap the connection for sharing between threads
+    let connection = Arc::new(connection);
+    let result = main_loop(connection.clone(), initialization_args);
+    // Releases the connection so the transport can close it
+    progress::remove(&connection);
+    result
 }
 
-fn main_loop(connection: Connection, args: serde_json::Value) -> Result<()> {
-    // Build our configuration
-    let config = Config::new(args)?;
+fn main_loop(connection: Arc<Connection>, args: serde_json::Value) -> Result<()> {
+    // Only report progress if the client asked for it
+    if args
+        .pointer("/capabilities/window/workDoneProgress")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+    {
+        progress::add(connection.clone());
+    }
 
-    // Wrap the connection for sharing between threads
-    let connection = Arc::new(connection);
+    // Build our configuration
+    let mut config = Config::new(args)?;
+    // Merged with the settings of the other clients, they apply until the client disconnects
+    let process_settings = process_settings::Client::connect();
+    process_settings.configure(&config)?;
 
     // Our channel we use to communicate with our transformer worker
     // let last_worker_request = Arc::new(Mutex::new(None));
     let (transformer_tx, transformer_rx) = mpsc::channel();
 
+    // The channel we use to send configuration updates to our transformer worker
+    let (config_tx, config_rx) = mpsc::channel();
+
     // The channel we use to communicate with our memory worker
     let (memory_tx, memory_rx) = mpsc::channel();
 
     // Setup the transformer worker
     let memory_backend: Box<dyn MemoryBackend + Send + Sync> = config.clone().try_into()?;
-    thread::spawn(move || memory_worker::run(memory_backend, memory_rx));
-
-    // Setup our transformer worker
-    // let transformer_backend: Box<dyn TransformerBackend + Send + Sync> =
-    //     config.clone().try_into()?;
-    let transformer_backends: HashMap<String, Box<dyn TransformerBackend + Send + Sync>> = config
-        .config
-        .models
-        .clone()
-        .into_iter()
-        .map(|(key, value)| Ok((key, value.try_into()?)))
-        .collect::<anyhow::Result<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>>>()?;
+    let thread_connection = connection.clone();
+    let memory_worker =
+        thread::spawn(move || memory_worker::run(memory_backend, memory_rx, thread_connection));
+
+    // Setup our transformer worker, it loads the models in the background
     let thread_connection = connection.clone();
     let thread_memory_tx = memory_tx.clone();
     let thread_config = config.clone();
-    thread::spawn(move || {
+    let transformer_worker = thread::spawn(move || {
         transformer_worker::run(
-            transformer_backends,
             thread_memory_tx,
             transformer_rx,
+            config_rx,
             thread_connection,
             thread_config,
         )
     });
 
-    for msg in &connection.receiver {
+    // Chat conversations are kept here so they outlive configuration changes that do not touch them
+    let mut conversations = Arc::new(Conversations::new(&config));
+
+    // Completions are cached here so they can be invalidated when documents change
+    let completion_cache = Arc::new(CompletionCache::default());
+
+    // Diagnostics from other servers the client forwards with textDocument/publishDiagnostics
+    let mut diagnostics: HashMap<Url, Vec<Diagnostic>> = HashMap::new();
+
+    // The workspace config file is watched so edits to it apply without a restart. The sender is
+    // kept so the channel never disconnects when there is no workspace to watch, the watcher
+    // stops once the connection ends.
+    let (reload_tx, reload_rx) = crossbeam_channel::unbounded();
+    let _watcher = config
+        .get_root_uri()
+        .map(|root_uri| workspace_config::watch(root_uri, reload_tx.clone()));
+
+    let shutdown_id = loop {
+        let msg = crossbeam_channel::select! {
+            recv(connection.receiver) -> msg => match msg {
+                Ok(msg) => msg,
+                Err(_) => break None,
+            },
+            recv(reload_rx) -> _ => {
+                update_config(
+                    config.reload(),
+                    &process_settings,
+                    &mut config,
+                    &mut conversations,
+                    &completion_cache,
+                    &memory_tx,
+                    &config_tx,
+                )?;
+                continue;
+            }
+        };
         match msg {
             Message::Request(req) => {
-                if connection.handle_shutdown(&req)? {
-                    return Ok(());
+                if request_is::<Shutdown>(&req) {
+                    break Some(req.id);
                 }
                 if request_is::<Completion>(&req) {
                     match cast::<Completion>(req) {
                         Ok((id, params)) => {
-                            let completion_request = CompletionRequest::new(id, params);
+                            let completion_request =
+                                CompletionRequest::new(id, params, completion_cache.clone());
                             transformer_tx.send(WorkerRequest::Completion(completion_request))?;
                         }
                         Err(err) => error!("{err:?}"),
                     }
+                } else if request_is::<InlineCompletion>(&req) {
+                    match cast::<InlineCompletion>(req) {
+                        Ok((id, params)) => {
+                            let inline_completion_request =
+                                InlineCompletionRequest::new(id, params, completion_cache.clone());
+                            transformer_tx
+                                .send(WorkerRequest::InlineCompletion(inline_completion_request))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
                 } else if request_is::<Generation>(&req) {
                     match cast::<Generation>(req) {
                         Ok((id, params)) => {
@@ -148,24 +341,279 @@ fn main_loop(connection: Connection, args: serde_json::Value) -> Result<()> {
                         }
                         Err(err) => error!("{err:?}"),
                     }
+                } else if request_is::<Chat>(&req) {
+                    match cast::<Chat>(req) {
+                        Ok((id, params)) => {
+                            let chat_request = ChatRequest::new(id, params, conversations.clone());
+                            transformer_tx.send(WorkerRequest::Chat(chat_request))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<CodeActionRequest>(&req) {
+                    match cast::<CodeActionRequest>(req) {
+                        Ok((id, params)) => {
+                            // Listing the actions is cheap, they only run when executed
+                            let forwarded_diagnostics = diagnostics
+                                .get(&params.text_document.uri)
+                                .map(Vec::as_slice)
+                                .unwrap_or_default();
+                            let mut actions = code_actions::get_code_actions(
+                                &config,
+                                &params,
+                                forwarded_diagnostics,
+                            );
+                            actions.extend(commit_message::get_code_action(&config, &params));
+                            connection
+                                .sender
+                                .send(Message::Response(Response::new_ok(id, actions)))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<Usage>(&req) {
+                    match cast::<Usage>(req) {
+                        Ok((id, _)) => {
+                            let usage = usage::get_usage();
+                            connection
+                                .sender
+                                .send(Message::Response(Response::new_ok(id, usage)))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<SetLogLevel>(&req) {
+                    match cast::<SetLogLevel>(req) {
+                        Ok((id, params)) => {
+                            let response = match logging::set_level(&params.level) {
+                                Ok(()) => Response::new_ok(id, ()),
+                                Err(e) => Response::new_err(
+                                    id,
+                                    ErrorCode::InvalidParams as i32,
+                                    e.to_string(),
+                                ),
+                            };
+                            connection.sender.send(Message::Response(response))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<ExecuteCommand>(&req) {
+                    match cast::<ExecuteCommand>(req) {
+                        Ok((id, params)) => {
+                            let execute_command_request = ExecuteCommandRequest::new(id, params);
+                            transformer_tx
+                                .send(WorkerRequest::ExecuteCommand(execute_command_request))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<GenerateCommitMessage>(&req) {
+                    match cast::<GenerateCommitMessage>(req) {
+                        Ok((id, params)) => {
+                            let commit_message_request = CommitMessageRequest::new(id, params);
+                            transformer_tx
+                                .send(WorkerRequest::CommitMessage(commit_message_request))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<PreviewPrompt>(&req) {
+                    match cast::<PreviewPrompt>(req) {
+                        Ok((id, params)) => {
+                            let preview_prompt_request = PreviewPromptRequest::new(id, params);
+                            transformer_tx
+                                .send(WorkerRequest::PreviewPrompt(preview_prompt_request))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<Agent>(&req) {
+                    match cast::<Agent>(req) {
+                        Ok((id, params)) => {
+                            transformer_tx
+                                .send(WorkerRequest::Agent(AgentRequest::new(id, params)))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<RawGeneration>(&req) {
+                    match cast::<RawGeneration>(req) {
+                        Ok((id, params)) => {
+                            transformer_tx.send(WorkerRequest::RawGeneration(
+                                RawGenerationRequest::new(id, params),
+                            ))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<MemoryStats>(&req) {
+                    match cast::<MemoryStats>(req) {
+                        Ok((id, _)) => {
+                            memory_tx.send(memory_worker::WorkerRequest::MemoryStats(id))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<Reindex>(&req) {
+                    match cast::<Reindex>(req) {
+                        Ok((id, params)) => {
+                            memory_tx.send(memory_worker::WorkerRequest::Reindex(id, params))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<WorkspaceSearch>(&req) {
+                    match cast::<WorkspaceSearch>(req) {
+                        Ok((id, params)) => {
+                            memory_tx.send(memory_worker::WorkerRequest::Search(id, params))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
                 } else {
-                    error!("lsp-ai currently only supports textDocument/completion, textDocument/generation and textDocument/generationStream")
+                    error!("lsp-ai currently only supports textDocument/completion, textDocument/inlineCompletion, textDocument/codeAction, workspace/executeCommand, textDocument/generation, textDocument/generationStream, lsp-ai/chat, lsp-ai/generateCommitMessage, lsp-ai/previewPrompt, lsp-ai/agent, lsp-ai/rawGeneration, lsp-ai/memoryStats, lsp-ai/reindex, lsp-ai/search, lsp-ai/usage and lsp-ai/setLogLevel")
                 }
             }
             Message::Notification(not) => {
-                if notification_is::<lsp_types::notification::DidOpenTextDocument>(&not) {
+                if notification_is::<lsp_types::notification::Cancel>(&not) {
+                    let params: CancelParams = serde_json::from_value(not.params)?;
+                    let id = match params.id {
+                        NumberOrString::Number(id) => RequestId::from(id),
+                        NumberOrString::String(id) => RequestId::from(id),
+                    };
+                    transformer_tx.send(WorkerRequest::Cancel(id))?;
+                } else if notification_is::<lsp_types::notification::DidOpenTextDocument>(&not) {
                     let params: DidOpenTextDocumentParams = serde_json::from_value(not.params)?;
                     memory_tx.send(memory_worker::WorkerRequest::DidOpenTextDocument(params))?;
                 } else if notification_is::<lsp_types::notification::DidChangeTextDocument>(&not) {
                     let params: DidChangeTextDocumentParams = serde_json::from_value(not.params)?;
+                    completion_cache.invalidate(&params);
                     memory_tx.send(memory_worker::WorkerRequest::DidChangeTextDocument(params))?;
+                } else if notification_is::<lsp_types::notification::PublishDiagnostics>(&not) {
+                    let params: PublishDiagnosticsParams = serde_json::from_value(not.params)?;
+                    diagnostics.insert(params.uri, params.diagnostics);
                 } else if notification_is::<lsp_types::notification::DidRenameFiles>(&not) {
                     let params: RenameFilesParams = serde_json::from_value(not.params)?;
                     memory_tx.send(memory_worker::WorkerRequest::DidRenameFiles(params))?;
+                } else if notification_is::<lsp_types::notification::DidChangeWorkspaceFolders>(
+                    &not,
+                ) {
+                    let params: DidChangeWorkspaceFoldersParams =
+                        serde_json::from_value(not.params)?;
+                    // Kept so a replacement memory backend indexes the current folders
+                    config.change_workspace_folders(&params.event);
+                    memory_tx.send(memory_worker::WorkerRequest::DidChangeWorkspaceFolders(
+                        params,
+                    ))?;
+                } else if notification_is::<CompletionAccepted>(&not)
+                    || notification_is::<CompletionRejected>(&not)
+                {
+                    let accepted = notification_is::<CompletionAccepted>(&not);
+                    // Feedback from clients is not worth stopping the server for
+                    match serde_json::from_value::<CompletionFeedbackParams>(not.params) {
+                        Ok(params) => usage::record_feedback(
+                            &params.model,
+                            params.language_id.as_deref(),
+                            accepted,
+                        ),
+                        Err(e) => error!("invalid completion feedback: {e}"),
+                    }
+                } else if notification_is::<lsp_types::notification::DidChangeConfiguration>(&not) {
+                    let params: DidChangeConfigurationParams = serde_json::from_value(not.params)?;
+                    // Some clients only notify that the configuration changed without sending it
+                    if params.settings.is_null() {
+                        continue;
+                    }
+                    update_config(
+                        config.update(params.settings),
+                        &process_settings,
+                        &mut config,
+                        &mut conversations,
+                        &completion_cache,
+                        &memory_tx,
+                        &config_tx,
+                    )?;
                 }
             }
             _ => (),
         }
+    };
+
+    // Nothing new reaches the workers. The transformer worker finishes or cancels what is in
+    // flight, then the memory worker writes what is pending, it gets requests from the former.
+    drop(transformer_tx);
+    drop(config_tx);
+    drop(memory_tx);
+    for (name, worker) in [
+        ("transformer", transformer_worker),
+        ("memory", memory_worker),
+    ] {
+        if worker.join().is_err() {
+            error!("the {name} worker panicked");
+        }
+    }
+    usage::save();
+
+    let Some(id) = shutdown_id else {
+        return Ok(());
+    };
+    connection
+        .sender
+        .send(Message::Response(Response::new_ok(id, ())))?;
+    for msg in &connection.receiver {
+        match msg {
+            Message::Notification(not) if notification_is::<Exit>(&not) => break,
+            Message::Request(req) => {
+                connection.sender.send(Message::Response(Response::new_err(
+                    req.id,
+                    ErrorCode::InvalidRequest as i32,
+                    "the server is shutting down".to_string(),
+                )))?;
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// Sends the new configuration to the workers. Invalid configurations are ignored so a typo does
+// not take down the server.
+fn update_config(
+    new_config: Result<Config>,
+    process_settings: &process_settings::Client,
+    config: &mut Config,
+    conversations: &mut Arc<Conversations>,
+    completion_cache: &CompletionCache,
+    memory_tx: &mpsc::Sender<memory_worker::WorkerRequest>,
+    config_tx: &mpsc::Sender<Config>,
+) -> Result<()> {
+    let new_config = match new_config {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            error!("invalid configuration: {e}");
+            return Ok(());
+        }
+    };
+    // Everything is checked before any of it is applied. A new memory backend is built here and
+    // started by the memory worker.
+    if let Err(e) = process_settings::validate(&new_config) {
+        error!("invalid configuration: {e}");
+        return Ok(());
+    }
+    let memory_backend: Option<Box<dyn MemoryBackend + Send + Sync>> =
+        if new_config.config.memory != config.config.memory {
+            match new_config.clone().try_into() {
+                Ok(memory_backend) => Some(memory_backend),
+                Err(e) => {
+                    error!("invalid configuration: {e}");
+                    return Ok(());
+                }
+            }
+        } else {
+            None
+        };
+    if let Err(e) = process_settings.configure(&new_config) {
+        error!("invalid configuration: {e}");
+        return Ok(());
+    }
+    if let Some(memory_backend) = memory_backend {
+        memory_tx.send(memory_worker::WorkerRequest::ReplaceBackend(memory_backend))?;
+    }
+    config_tx.send(new_config.clone())?;
+    // The models or their parameters may have changed
+    completion_cache.clear();
+    if new_config.config.chat != config.config.chat {
+        *conversations = Arc::new(Conversations::new(&new_config));
     }
+    *config = new_config;
     Ok(())
 }
@@ -0,0 +1,531 @@
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use lsp_types::Url;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, Command},
+    sync::oneshot,
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{Config, McpServer, McpSseServer, McpStdioServer},
+    http_client,
+    tools::ToolDefinition,
+};
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+// How long the server gets to answer initialize and to list its tools
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a tool call may take
+const CALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Between the name of the server and the name of the tool in the names the model sees.
+// OpenAI only allows letters, digits, `_` and `-` in them.
+pub const SEPARATOR: &str = "__";
+
+type Pending = Arc<Mutex<HashMap<i64, oneshot::Sender<Value>>>>;
+
+#[derive(Debug, Clone, Deserialize)]
+struct McpTool {
+    name: String,
+    description: Option<String>,
+    #[serde(rename = "inputSchema")]
+    input_schema: Value,
+}
+
+#[derive(Deserialize)]
+struct ListToolsResult {
+    tools: Vec<McpTool>,
+    #[serde(rename = "nextCursor")]
+    next_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum Content {
+    #[serde(rename = "text")]
+    Text { text: String },
+    #[serde(rename = "resource")]
+    Resource { resource: Value },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct CallToolResult {
+    #[serde(default)]
+    content: Vec<Content>,
+    #[serde(rename = "isError", default)]
+    is_error: bool,
+}
+
+enum Transport {
+    Stdio {
+        child: Mutex<Child>,
+        stdin: Arc<tokio::sync::Mutex<ChildStdin>>,
+    },
+    // Messages are posted to the endpoint the event stream sent, responses come in on the stream
+    Sse {
+        endpoint: Url,
+        headers: HashMap<String, String>,
+        reader: JoinHandle<()>,
+    },
+}
+
+// Subprocesses are killed when their child is dropped
+impl Drop for Transport {
+    fn drop(&mut self) {
+        if let Transport::Sse { reader, .. } = self {
+            reader.abort();
+        }
+    }
+}
+
+async fn write_line(stdin: &tokio::sync::Mutex<ChildStdin>, message: &Value) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    let mut stdin = stdin.lock().await;
+    stdin.write_all(&line).await?;
+    Ok(stdin.flush().await?)
+}
+
+async fn post(
+    endpoint: &Url,
+    headers: &HashMap<String, String>,
+    message: &Value,
+) -> anyhow::Result<()> {
+    let mut request = http_client::get_client()
+        .post(endpoint.clone())
+        .json(message);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+// Hands responses to the requests waiting for them and returns the answer to requests of the
+// server. Only ping is supported.
+fn handle_message(message: Value, pending: &Pending) -> Option<Value> {
+    let id = message.get("id")?.clone();
+    match message.get("method").and_then(Value::as_str) {
+        Some("ping") => Some(json!({"jsonrpc": "2.0", "id": id, "result": {}})),
+        Some(method) => Some(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": -32601, "message": format!("{method} is not supported")}
+        })),
+        None => {
+            if let Some(tx) = id.as_i64().and_then(|id| pending.lock().remove(&id)) {
+                let _ = tx.send(message);
+            }
+            None
+        }
+    }
+}
+
+fn start_stdio(server: &McpStdioServer, pending: Pending) -> anyhow::Result<Transport> {
+    let mut child = Command::new(&server.command)
+        .args(&server.args)
+        .envs(&server.env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("starting `{}`", server.command))?;
+    // What the server logs ends up in the logs of lsp-ai
+    let mut stderr = BufReader::new(child.stderr.take().context("no stderr")?).lines();
+    let command = server.command.clone();
+    tokio::spawn(async move {
+        while let Ok(Some(line)) = stderr.next_line().await {
+            warn!("MCP server `{command}`: {line}");
+        }
+    });
+    let stdin = Arc::new(tokio::sync::Mutex::new(
+        child.stdin.take().context("no stdin")?,
+    ));
+    let mut lines = BufReader::new(child.stdout.take().context("no stdout")?).lines();
+    let reader_stdin = stdin.clone();
+    tokio::spawn(async move {
+        // Waiting requests fail once their senders are dropped
+        while let Ok(Some(line)) = lines.next_line().await {
+            let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                continue;
+            };
+            if let Some(answer) = handle_message(message, &pending) {
+                if write_line(&reader_stdin, &answer).await.is_err() {
+                    break;
+                }
+            }
+        }
+        pending.lock().clear();
+    });
+    Ok(Transport::Stdio {
+        child: Mutex::new(child),
+        stdin,
+    })
+}
+
+// The event and the data of a server-sent event
+fn parse_event(event: &str) -> (String, String) {
+    let mut name = "message".to_string();
+    let mut data: Vec<&str> = vec![];
+    for line in event.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            name = value.trim().to_owned();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+    (name, data.join("\n"))
+}
+
+async fn start_sse(server: &McpSseServer, pending: Pending) -> anyhow::Result<Transport> {
+    let url = Url::parse(&server.url).with_context(|| format!("invalid url: {}", server.url))?;
+    let mut request = http_client::get_client()
+        .get(url.clone())
+        .header("Accept", "text/event-stream");
+    for (name, value) in &server.headers {
+        request = request.header(name, value);
+    }
+    let mut response = request.send().await?.error_for_status()?;
+    let (endpoint_tx, endpoint_rx) = oneshot::channel();
+    let headers = server.headers.clone();
+    let reader = tokio::spawn(async move {
+        let mut endpoint_tx = Some(endpoint_tx);
+        let mut endpoint: Option<Url> = None;
+        // Chunks can end in the middle of a char, only whole lines are decoded
+        let mut buffer = vec![];
+        // The lines of the event read so far
+        let mut lines = String::new();
+        while let Ok(Some(chunk)) = response.chunk().await {
+            buffer.extend_from_slice(&chunk);
+            while let Some(index) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=index).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches(['\n', '\r']);
+                // A blank line ends the event
+                if !line.is_empty() {
+                    lines.push_str(line);
+                    lines.push('\n');
+                    continue;
+                }
+                let event = std::mem::take(&mut lines);
+                match parse_event(&event) {
+                    (name, data) if name == "endpoint" => {
+                        let Ok(url) = url.join(&data) else {
+                            continue;
+                        };
+                        endpoint = Some(url.clone());
+                        if let Some(tx) = endpoint_tx.take() {
+                            let _ = tx.send(url);
+                        }
+                    }
+                    (name, data) if name == "message" => {
+                        let Ok(message) = serde_json::from_str::<Value>(&data) else {
+                            continue;
+                        };
+                        let answer = handle_message(message, &pending);
+                        if let (Some(answer), Some(endpoint)) = (answer, &endpoint) {
+                            if let Err(e) = post(endpoint, &headers, &answer).await {
+                                error!("answering the MCP server: {e}");
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        pending.lock().clear();
+    });
+    let endpoint = tokio::time::timeout(INITIALIZE_TIMEOUT, endpoint_rx)
+        .await
+        .context("the MCP server sent no endpoint")?
+        .context("the MCP server closed the event stream")?;
+    Ok(Transport::Sse {
+        endpoint,
+        headers: server.headers.clone(),
+        reader,
+    })
+}
+
+// An MCP server lsp-ai is the client of
+struct Client {
+    transport: Transport,
+    pending: Pending,
+    next_id: AtomicI64,
+    // Listed when the server starts
+    tools: Vec<McpTool>,
+}
+
+impl Client {
+    async fn start(server: &McpServer) -> anyhow::Result<Self> {
+        let pending = Pending::default();
+        let transport = match server {
+            McpServer::Stdio(server) => start_stdio(server, pending.clone())?,
+            McpServer::Sse(server) => start_sse(server, pending.clone()).await?,
+        };
+        let mut client = Self {
+            transport,
+            pending,
+            next_id: AtomicI64::new(0),
+            tools: vec![],
+        };
+        client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {"name": "lsp-ai", "version": env!("CARGO_PKG_VERSION")}
+                }),
+                INITIALIZE_TIMEOUT,
+            )
+            .await
+            .context("initializing")?;
+        client
+            .send(&json!({"jsonrpc": "2.0", "method": "notifications/initialized"}))
+            .await?;
+        client.tools = client.list_tools().await.context("listing the tools")?;
+        Ok(client)
+    }
+
+    fn is_running(&self) -> bool {
+        match &self.transport {
+            Transport::Stdio { child, .. } => matches!(child.lock().try_wait(), Ok(None)),
+            Transport::Sse { reader, .. } => !reader.is_finished(),
+        }
+    }
+
+    async fn send(&self, message: &Value) -> anyhow::Result<()> {
+        match &self.transport {
+            Transport::Stdio { stdin, .. } => write_line(stdin, message).await,
+            Transport::Sse {
+                endpoint, headers, ..
+            } => post(endpoint, headers, message).await,
+        }
+    }
+
+    async fn request(
+        &self,
+        method: &str,
+        params: Value,
+        timeout: Duration,
+    ) -> anyhow::Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().insert(id, tx);
+        self.send(&json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}))
+            .await?;
+        let mut response = match tokio::time::timeout(timeout, rx).await {
+            Ok(response) => response.context("the MCP server exited")?,
+            Err(_) => {
+                self.pending.lock().remove(&id);
+                self.send(&json!({
+                    "jsonrpc": "2.0",
+                    "method": "notifications/cancelled",
+                    "params": {"requestId": id, "reason": "timed out"}
+                }))
+                .await?;
+                anyhow::bail!("{method} timed out");
+            }
+        };
+        if let Some(error) = response.get("error") {
+            let message = error.get("message").and_then(Value::as_str);
+            anyhow::bail!("{method} failed: {}", message.unwrap_or("unknown error"));
+        }
+        Ok(response["result"].take())
+    }
+
+    async fn list_tools(&self) -> anyhow::Result<Vec<McpTool>> {
+        let mut tools = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({"cursor": cursor}),
+                None => json!({}),
+            };
+            let result: ListToolsResult = serde_json::from_value(
+                self.request("tools/list", params, INITIALIZE_TIMEOUT)
+                    .await?,
+            )?;
+            tools.extend(result.tools);
+            match result.next_cursor {
+                Some(next_cursor) => cursor = Some(next_cursor),
+                None => return Ok(tools),
+            }
+        }
+    }
+
+    async fn call_tool(&self, name: &str, arguments: Value) -> anyhow::Result<String> {
+        let result: CallToolResult = serde_json::from_value(
+            self.request(
+                "tools/call",
+                json!({"name": name, "arguments": arguments}),
+                CALL_TIMEOUT,
+            )
+            .await?,
+        )?;
+        let content: Vec<String> = result
+            .content
+            .into_iter()
+            .map(|content| match content {
+                Content::Text { text } => text,
+                Content::Resource { resource } => match resource.get("text") {
+                    Some(Value::String(text)) => text.clone(),
+                    _ => "[binary resource]".to_string(),
+                },
+                Content::Other => "[non-text content]".to_string(),
+            })
+            .collect();
+        let content = content.join("\n");
+        // Sent to the model as an error result
+        if result.is_error {
+            anyhow::bail!(content);
+        }
+        Ok(content)
+    }
+}
+
+// The servers of every connected client by name, started when first needed and restarted when
+// they exit or their configuration changes. Servers with another configuration are kept while a
+// call of another client is using them.
+static CLIENTS: Lazy<tokio::sync::Mutex<HashMap<String, Vec<(McpServer, Arc<Client>)>>>> =
+    Lazy::new(|| tokio::sync::Mutex::new(HashMap::new()));
+
+// The servers are shared between the clients, so they run on a runtime that outlives the one of
+// the client that started them
+static RUNTIME: OnceCell<tokio::runtime::Runtime> = OnceCell::new();
+
+async fn get_client(name: &str, server: &McpServer) -> anyhow::Result<Arc<Client>> {
+    let mut clients = CLIENTS.lock().await;
+    let running = clients.entry(name.to_owned()).or_default();
+    running.retain(|(running, client)| {
+        client.is_running() && (running == server || Arc::strong_count(client) > 1)
+    });
+    if let Some((_, client)) = running.iter().find(|(running, _)| running == server) {
+        return Ok(client.clone());
+    }
+    info!("starting the MCP server `{name}`");
+    let runtime = RUNTIME.get_or_try_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+    })?;
+    let start_server = server.clone();
+    let client = Arc::new(
+        runtime
+            .spawn(async move { Client::start(&start_server).await })
+            .await?
+            .with_context(|| format!("starting the MCP server `{name}`"))?,
+    );
+    running.push((server.clone(), client.clone()));
+    Ok(client)
+}
+
+// `{server}__{tool}` to the server and the tool
+pub fn split_name(name: &str) -> Option<(&str, &str)> {
+    name.split_once(SEPARATOR)
+}
+
+// The tools of the server as the model sees them. With a tool name only that tool.
+pub async fn get_definitions(
+    config: &Config,
+    server_name: &str,
+    tool_name: Option<&str>,
+) -> anyhow::Result<Vec<ToolDefinition>> {
+    let server = config
+        .get_mcp_servers()
+        .get(server_name)
+        .with_context(|| format!("`{server_name}` not found in `mcp_servers` config"))?;
+    let client = get_client(server_name, server).await?;
+    let definitions: Vec<ToolDefinition> = client
+        .tools
+        .iter()
+        .filter(|tool| tool_name.map_or(true, |name| tool.name == name))
+        .map(|tool| ToolDefinition {
+            name: format!("{server_name}{SEPARATOR}{}", tool.name),
+            description: tool.description.clone().unwrap_or_default(),
+            input_schema: tool.input_schema.clone(),
+        })
+        .collect();
+    if let (Some(tool_name), true) = (tool_name, definitions.is_empty()) {
+        anyhow::bail!("the MCP server `{server_name}` has no tool `{tool_name}`");
+    }
+    Ok(definitions)
+}
+
+// The definitions of the tools of every configured server. Servers that fail to start are left
+// out.
+pub async fn get_all_definitions(config: &Config) -> Vec<ToolDefinition> {
+    let mut definitions = vec![];
+    for name in config.get_mcp_servers().keys() {
+        match get_definitions(config, name, None).await {
+            Ok(server_definitions) => definitions.extend(server_definitions),
+            Err(e) => error!("{e:?}"),
+        }
+    }
+    definitions
+}
+
+pub async fn call_tool(config: &Config, name: &str, input: Value) -> anyhow::Result<String> {
+    let (server_name, tool_name) =
+        split_name(name).with_context(|| format!("unknown tool `{name}`"))?;
+    let server = config
+        .get_mcp_servers()
+        .get(server_name)
+        .with_context(|| format!("unknown tool `{name}`"))?;
+    get_client(server_name, server)
+        .await?
+        .call_tool(tool_name, input)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handles_mcp_messages() {
+        let pending = Pending::default();
+        let (tx, mut rx) = oneshot::channel();
+        pending.lock().insert(3, tx);
+        let response = json!({"jsonrpc": "2.0", "id": 3, "result": {"tools": []}});
+        assert_eq!(handle_message(response.clone(), &pending), None);
+        assert_eq!(rx.try_recv().ok(), Some(response));
+        assert_eq!(
+            handle_message(
+                json!({"jsonrpc": "2.0", "id": "a", "method": "ping"}),
+                &pending
+            ),
+            Some(json!({"jsonrpc": "2.0", "id": "a", "result": {}}))
+        );
+        assert_eq!(
+            parse_event("event: endpoint\ndata: /messages?session_id=1\n\n"),
+            ("endpoint".to_string(), "/messages?session_id=1".to_string())
+        );
+        assert_eq!(
+            split_name("github__create_issue"),
+            Some(("github", "create_issue"))
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use lsp_server::Connection;
+use lsp_types::Url;
+use serde_json::{json, Value};
+use tracing::error;
+
+use crate::{
+    custom_requests::{generation::GenerateResult, search::SearchMatch},
+    headless::{self, Client},
+    workspace_config,
+};
+
+const USAGE: &str = "usage: lsp-ai --mcp [--config <file>]";
+
+// The other versions clients ask for are answered with this one
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+const SEARCH_TOOL: &str = "search_workspace";
+const COMPLETE_TOOL: &str = "complete";
+const GENERATE_TOOL: &str = "generate";
+
+pub struct Args {
+    config: Option<PathBuf>,
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut config = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                config = Some(PathBuf::from(
+                    args.next()
+                        .with_context(|| format!("{arg} needs a value\n{USAGE}"))?,
+                ))
+            }
+            _ => anyhow::bail!("unknown argument {arg}\n{USAGE}"),
+        }
+    }
+    Ok(Args { config })
+}
+
+fn get_tools() -> Value {
+    let position = json!({
+        "path": {"type": "string", "description": "The file, relative to the working directory of lsp-ai"},
+        "line": {"type": "integer", "description": "1-based"},
+        "column": {"type": "integer", "description": "1-based"},
+        "language": {"type": "string", "description": "The languageId of the file, guessed from its extension when not set"}
+    });
+    let mut generate = position.clone();
+    generate["model"] = json!({"type": "string", "description": "The model key, the one of the completion config when not set"});
+    generate["parameters"] = json!({"type": "object", "description": "The parameters for the model, like the ones of the models config"});
+    json!([
+        {
+            "name": SEARCH_TOOL,
+            "description": "Search the code and documentation of the project for chunks relevant to the query, with the index lsp-ai keeps of the workspace.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "query": {"type": "string", "description": "What to search for, in natural language or code"},
+                    "top_k": {"type": "integer", "description": "How many chunks to return, defaults to 10"},
+                    "language": {"type": "string", "description": "Only search files with this languageId"},
+                    "paths": {"type": "array", "items": {"type": "string"}, "description": "Only search files under these directories"}
+                },
+                "required": ["query"]
+            }
+        },
+        {
+            "name": COMPLETE_TOOL,
+            "description": "Complete the code at a position of a file with the context of the project.",
+            "inputSchema": {"type": "object", "properties": position, "required": ["path", "line", "column"]}
+        },
+        {
+            "name": GENERATE_TOOL,
+            "description": "Generate code for a position of a file with the context of the project.",
+            "inputSchema": {"type": "object", "properties": generate, "required": ["path", "line", "column"]}
+        }
+    ])
+}
+
+// The LSP request a tool call is sent as and the file that is opened for it first
+#[derive(Debug, PartialEq)]
+struct LspRequest {
+    open: Option<(PathBuf, Option<String>)>,
+    method: &'static str,
+    params: Value,
+}
+
+fn get_u32(arguments: &Value, name: &str) -> anyhow::Result<u32> {
+    let value = arguments
+        .get(name)
+        .and_then(Value::as_u64)
+        .with_context(|| format!("`{name}` must be a positive integer"))?;
+    anyhow::ensure!(value > 0, "`{name}` starts at 1");
+    Ok(value as u32)
+}
+
+fn build_request(name: &str, arguments: &Value) -> anyhow::Result<LspRequest> {
+    if name == SEARCH_TOOL {
+        let mut params = arguments.clone();
+        if let Some(top_k) = params.as_object_mut().and_then(|p| p.remove("top_k")) {
+            params["topK"] = top_k;
+        }
+        return Ok(LspRequest {
+            open: None,
+            method: "lsp-ai/search",
+            params,
+        });
+    }
+    let path = arguments
+        .get("path")
+        .and_then(Value::as_str)
+        .context("`path` must be a string")?;
+    let path = Path::new(path)
+        .canonicalize()
+        .with_context(|| format!("error reading {path}"))?;
+    let uri = Url::from_file_path(&path)
+        .map_err(|_| anyhow::anyhow!("invalid file path {}", path.display()))?;
+    let mut params = headless::text_document_position(
+        &uri,
+        get_u32(arguments, "line")?,
+        get_u32(arguments, "column")?,
+    );
+    let method = match name {
+        COMPLETE_TOOL => "textDocument/completion",
+        GENERATE_TOOL => {
+            params["model"] = arguments.get("model").cloned().unwrap_or_default();
+            params["parameters"] = arguments
+                .get("parameters")
+                .cloned()
+                .unwrap_or_else(|| json!({}));
+            "textDocument/generation"
+        }
+        _ => anyhow::bail!("unknown tool `{name}`"),
+    };
+    let language = arguments
+        .get("language")
+        .and_then(Value::as_str)
+        .map(str::to_owned);
+    Ok(LspRequest {
+        open: Some((path, language)),
+        method,
+        params,
+    })
+}
+
+fn format_result(name: &str, result: Value) -> anyhow::Result<String> {
+    match name {
+        SEARCH_TOOL => {
+            let matches: Vec<SearchMatch> = serde_json::from_value(result)?;
+            if matches.is_empty() {
+                return Ok("Nothing relevant was found".to_string());
+            }
+            let matches: Vec<String> = matches
+                .into_iter()
+                .map(|m| match m.range {
+                    Some(range) => format!(
+                        "{}:{}-{}\n{}",
+                        m.uri,
+                        range.start.line + 1,
+                        range.end.line,
+                        m.text
+                    ),
+                    None => format!("{}\n{}", m.uri, m.text),
+                })
+                .collect();
+            Ok(matches.join("\n\n"))
+        }
+        COMPLETE_TOOL => Ok(headless::get_completion_text(result)?.join("\n---\n")),
+        _ => Ok(serde_json::from_value::<GenerateResult>(result)?.generated_text),
+    }
+}
+
+fn call_tool(client: &mut Client, params: &Value) -> anyhow::Result<String> {
+    let name = params
+        .get("name")
+        .and_then(Value::as_str)
+        .context("missing the tool name")?;
+    let arguments = params
+        .get("arguments")
+        .cloned()
+        .unwrap_or_else(|| json!({}));
+    let request = build_request(name, &arguments)?;
+    if let Some((path, language)) = &request.open {
+        client.open(path, language.as_deref())?;
+    }
+    format_result(name, client.request(request.method, request.params)?)
+}
+
+// The result of an MCP request, or the JSON-RPC error
+fn handle_request(client: &mut Client, method: &str, params: &Value) -> Result<Value, Value> {
+    match method {
+        "initialize" => Ok(json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {"tools": {}},
+            "serverInfo": {"name": "lsp-ai", "version": env!("CARGO_PKG_VERSION")}
+        })),
+        "ping" => Ok(json!({})),
+        "tools/list" => Ok(json!({ "tools": get_tools() })),
+        // Failed calls are results so the model sees why
+        "tools/call" => Ok(match call_tool(client, params) {
+            Ok(text) => json!({"content": [{"type": "text", "text": text}], "isError": false}),
+            Err(e) => {
+                json!({"content": [{"type": "text", "text": format!("{e:#}")}], "isError": true})
+            }
+        }),
+        _ => Err(json!({"code": -32601, "message": format!("{method} is not supported")})),
+    }
+}
+
+// Serves the tools over MCP on stdin and stdout with a server running in this process, so chat
+// apps can search the project with the index lsp-ai builds and complete and generate code in it
+pub fn run(args: Args, serve: fn(Connection) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let options = match &args.config {
+        Some(config) => workspace_config::load(config)?,
+        None => Value::Null,
+    };
+    let mut client = Client::start(serve, &headless::get_root_uri()?, options)?;
+    let mut stdout = std::io::stdout();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: Value = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("invalid MCP message: {e}");
+                continue;
+            }
+        };
+        // Notifications need no answer
+        let (Some(id), Some(method)) = (
+            message.get("id"),
+            message.get("method").and_then(Value::as_str),
+        ) else {
+            continue;
+        };
+        let params = message.get("params").cloned().unwrap_or_default();
+        let response = match handle_request(&mut client, method, &params) {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(error) => json!({"jsonrpc": "2.0", "id": id, "error": error}),
+        };
+        writeln!(stdout, "{response}")?;
+        stdout.flush()?;
+    }
+    client.shutdown()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_lsp_requests_for_mcp_tools() -> anyhow::Result<()> {
+        let request = build_request(SEARCH_TOOL, &json!({"query": "config", "top_k": 3}))?;
+        assert_eq!(request.method, "lsp-ai/search");
+        assert_eq!(request.params, json!({"query": "config", "topK": 3}));
+
+        let request = build_request(
+            GENERATE_TOOL,
+            &json!({"path": "src/main.rs", "line": 2, "column": 1, "model": "model1"}),
+        )?;
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/main.rs")
+            .canonicalize()?;
+        assert_eq!(request.open, Some((path, None)));
+        assert_eq!(
+            request.params["position"],
+            json!({"line": 1, "character": 0})
+        );
+        assert_eq!(request.params["model"], "model1");
+
+        assert!(build_request(COMPLETE_TOOL, &json!({"path": "src/main.rs", "line": 0})).is_err());
+        assert!(build_request(
+            "unknown",
+            &json!({"path": "src/main.rs", "line": 1, "column": 1})
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use std::path::{Path, PathBuf};
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    WalkBuilder,
+};
+use tracing::error;
+
+// Like a .gitignore, for files that are committed but not worth indexing
+pub const IGNORE_FILE: &str = ".lspaiignore";
+
+// Git looks this far into a file to tell whether it is binary
+const BINARY_CHECK_LENGTH: usize = 8000;
+
+// The files under `root` to index. Hidden files and those the .gitignore, .ignore and
+// .lspaiignore files exclude are skipped, as are files over `max_file_size` bytes.
+pub fn walk(root: &Path, max_file_size: u64) -> Vec<PathBuf> {
+    WalkBuilder::new(root)
+        .require_git(false)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .build()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                error!("error crawling {}: {e}", root.display());
+                None
+            }
+        })
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .filter(|entry| {
+            entry
+                .metadata()
+                .is_ok_and(|metadata| metadata.len() <= max_file_size)
+        })
+        .map(|entry| entry.into_path())
+        .collect()
+}
+
+// Tells whether a changed file is one `walk` would have found. Only the ignore files at the root
+// are honored.
+pub struct Filter {
+    root: PathBuf,
+    gitignore: Gitignore,
+    max_file_size: u64,
+}
+
+impl Filter {
+    pub fn new(root: &Path, max_file_size: u64) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        for name in [".gitignore", ".ignore", IGNORE_FILE] {
+            let path = root.join(name);
+            if path.exists() {
+                if let Some(e) = builder.add(path) {
+                    error!("error reading {name}: {e}");
+                }
+            }
+        }
+        let gitignore = builder.build().unwrap_or_else(|e| {
+            error!("error reading the ignore files of {}: {e}", root.display());
+            Gitignore::empty()
+        });
+        Self {
+            root: root.to_owned(),
+            gitignore,
+            max_file_size,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Files that were removed are included so they are removed from the index
+    pub fn includes(&self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        let hidden = relative
+            .components()
+            .any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+        if hidden {
+            return false;
+        }
+        if let Ok(metadata) = std::fs::metadata(path) {
+            if !metadata.is_file() || metadata.len() > self.max_file_size {
+                return false;
+            }
+        }
+        !self
+            .gitignore
+            .matched_path_or_any_parents(path, false)
+            .is_ignore()
+    }
+}
+
+// The text of the file, None for binary files and files that are not UTF-8
+pub fn read_text(bytes: Vec<u8>) -> Option<String> {
+    if bytes[..bytes.len().min(BINARY_CHECK_LENGTH)].contains(&0) {
+        return None;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// The languageId editors give files with the extension of `path`, so crawled files can be
+// filtered by language like opened ones
+pub fn get_language_id(path: &Path) -> Option<&'static str> {
+    Some(match path.extension()?.to_str()? {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "javascriptreact",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "typescriptreact",
+        "go" => "go",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => "cpp",
+        "java" => "java",
+        "rb" => "ruby",
+        "lua" => "lua",
+        "md" => "markdown",
+        "toml" => "toml",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "sh" => "shellscript",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walks_the_workspace() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("lsp-ai-crawl-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(root.join("src"))?;
+        std::fs::create_dir_all(root.join("target"))?;
+        std::fs::write(root.join(".gitignore"), "target/\n")?;
+        std::fs::write(root.join(IGNORE_FILE), "*.lock\n")?;
+        std::fs::write(root.join("src/main.rs"), "fn main() {}\n")?;
+        std::fs::write(root.join("src/big.rs"), "a".repeat(200))?;
+        std::fs::write(root.join("target/out.rs"), "fn main() {}\n")?;
+        std::fs::write(root.join("Cargo.lock"), "version = 3\n")?;
+
+        let mut files: Vec<PathBuf> = walk(&root, 100)
+            .into_iter()
+            .map(|path| path.strip_prefix(&root).unwrap().to_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec![PathBuf::from("src/main.rs")]);
+
+        let filter = Filter::new(&root, 100);
+        assert!(filter.includes(&root.join("src/main.rs")));
+        assert!(filter.includes(&root.join("src/removed.rs")));
+        assert!(!filter.includes(&root.join("src/big.rs")));
+        assert!(!filter.includes(&root.join("target/out.rs")));
+        assert!(!filter.includes(&root.join("Cargo.lock")));
+        assert!(!filter.includes(&root.join(".gitignore")));
+
+        assert_eq!(
+            read_text(b"fn main() {}".to_vec()).as_deref(),
+            Some("fn main() {}")
+        );
+        assert!(read_text(vec![0x7f, b'E', b'L', b'F', 0, 0]).is_none());
+        assert_eq!(get_language_id(Path::new("src/main.rs")), Some("rust"));
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use std::path::{Path, PathBuf};
+
+use lsp_types::Url;
+
+use crate::http_client::get_client;
+
+use super::crawl;
+
+// Elements whose content is not part of the text of a page
+const SKIPPED_ELEMENTS: &[&str] = &["head", "script", "style", "nav", "footer", "svg"];
+
+// Elements that start a new line
+const BLOCK_ELEMENTS: &[&str] = &[
+    "p",
+    "div",
+    "br",
+    "tr",
+    "li",
+    "pre",
+    "section",
+    "article",
+    "table",
+    "ul",
+    "ol",
+    "dt",
+    "dd",
+    "blockquote",
+];
+
+fn is_html(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("html" | "htm")
+    )
+}
+
+fn is_doc(path: &Path) -> bool {
+    is_html(path)
+        || matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("md" | "markdown" | "txt" | "rst")
+        )
+}
+
+// The documentation files in the directory or file at `path`
+pub fn walk(path: &Path, max_file_size: u64) -> Vec<PathBuf> {
+    crawl::walk(path, max_file_size)
+        .into_iter()
+        .filter(|path| is_doc(path))
+        .collect()
+}
+
+// The text of the documentation file, HTML is converted to markdown
+pub fn read_file(path: &Path) -> Option<String> {
+    let text = std::fs::read(path).ok().and_then(crawl::read_text)?;
+    Some(if is_html(path) {
+        html_to_markdown(&text)
+    } else {
+        text
+    })
+}
+
+// The text of the page at `url` and the pages under it that it links to
+pub async fn fetch(url: &Url) -> anyhow::Result<(String, Vec<Url>)> {
+    let response = get_client()
+        .get(url.as_str())
+        .send()
+        .await?
+        .error_for_status()?;
+    let html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("html"));
+    let text = response.text().await?;
+    if !html {
+        return Ok((text, vec![]));
+    }
+    Ok((html_to_markdown(&text), get_links(&text, url)))
+}
+
+// The links of the page to pages under the same path, without fragments
+fn get_links(html: &str, base: &Url) -> Vec<Url> {
+    let base_str = base.as_str();
+    let prefix = base_str.rfind('/').map_or(base_str, |i| &base_str[..=i]);
+    let mut links = vec![];
+    let mut rest = html;
+    while let Some(index) = rest.find("href=") {
+        rest = &rest[index + 5..];
+        let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        let Some(end) = rest[1..].find(quote) else {
+            break;
+        };
+        let href = decode_entities(&rest[1..end + 1]);
+        rest = &rest[end + 1..];
+        let Ok(mut url) = base.join(&href) else {
+            continue;
+        };
+        url.set_fragment(None);
+        if url.as_str().starts_with(prefix) && !links.contains(&url) {
+            links.push(url);
+        }
+    }
+    links
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+// Keeps the text and headings of the page so it can be split like markdown
+pub fn html_to_markdown(html: &str) -> String {
+    let mut markdown = String::with_capacity(html.len() / 2);
+    // The element whose content is being skipped
+    let mut skipping: Option<String> = None;
+    let mut in_pre = false;
+    let mut rest = html;
+    while let Some(index) = rest.find('<') {
+        if skipping.is_none() {
+            push_text(&mut markdown, &rest[..index], in_pre);
+        }
+        rest = &rest[index + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        let closing = tag.starts_with('/');
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if let Some(skipped) = &skipping {
+            if closing && *skipped == name {
+                skipping = None;
+            }
+            continue;
+        }
+        if !closing && !tag.ends_with('/') && SKIPPED_ELEMENTS.contains(&name.as_str()) {
+            skipping = Some(name);
+            continue;
+        }
+        match name.as_str() {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                new_line(&mut markdown);
+                if !closing {
+                    let level = name[1..].parse().unwrap_or(1);
+                    markdown.push_str(&"#".repeat(level));
+                    markdown.push(' ');
+                }
+            }
+            "pre" => {
+                new_line(&mut markdown);
+                markdown.push_str("```\n");
+                in_pre = !closing;
+            }
+            "li" if !closing => {
+                new_line(&mut markdown);
+                markdown.push_str("- ");
+            }
+            name if BLOCK_ELEMENTS.contains(&name) => new_line(&mut markdown),
+            _ => (),
+        }
+    }
+    if skipping.is_none() {
+        push_text(&mut markdown, rest, in_pre);
+    }
+    markdown.trim().to_owned() + "\n"
+}
+
+fn push_text(markdown: &mut String, text: &str, in_pre: bool) {
+    let text = decode_entities(text);
+    if in_pre {
+        markdown.push_str(&text);
+        return;
+    }
+    for word in text.split_whitespace() {
+        if !markdown.is_empty() && !markdown.ends_with([' ', '\n']) {
+            markdown.push(' ');
+        }
+        markdown.push_str(word);
+    }
+}
+
+fn new_line(markdown: &mut String) {
+    if !markdown.is_empty() && !markdown.ends_with('\n') {
+        markdown.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_html_and_finds_links() {
+        let html = r#"<html><head><title>t</title></head><body>
+            <nav><a href="/other">Other</a></nav>
+            <h1>Serde</h1><p>A <b>framework</b> for serializing &amp; deserializing.</p>
+            <h2>Derive</h2><ul><li>Serialize</li><li>Deserialize</li></ul>
+            <pre>#[derive(Serialize)]
+struct A;</pre>
+            <a href="de/index.html#section">de</a><a href='https://example.com/'>x</a>
+            <script>let a = "<p>";</script></body></html>"#;
+        assert_eq!(
+            html_to_markdown(html),
+            "# Serde\nA framework for serializing & deserializing.\n## Derive\n- Serialize\n- \
+             Deserialize\n```\n#[derive(Serialize)]\nstruct A;\n```\nde x\n"
+        );
+
+        let base = Url::parse("https://docs.rs/serde/latest/serde/index.html").unwrap();
+        let links: Vec<String> = get_links(html, &base)
+            .iter()
+            .map(|url| url.to_string())
+            .collect();
+        assert_eq!(
+            links,
+            vec!["https://docs.rs/serde/latest/serde/de/index.html"]
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use anyhow::Context;
 use indexmap::IndexSet;
-use lsp_types::TextDocumentPositionParams;
+use lsp_types::{Range, TextDocumentItem, TextDocumentPositionParams};
 use parking_lot::Mutex;
 use ropey::Rope;
 use serde_json::Value;
@@ -8,35 +8,67 @@ use std::collections::HashMap;
 use tracing::instrument;
 
 use crate::{
-    config::{self, Config},
+    config::{self, Config, ContextPacking, ContextSource},
+    git,
+    language_servers::LanguageServers,
+    splitters::{get_imported_names, get_signatures},
+    tokenizer::Tokenizer,
     utils::tokens_to_estimated_characters,
 };
 
-use super::{ContextAndCodePrompt, FIMPrompt, MemoryBackend, MemoryRunParams, Prompt, PromptType};
+use super::{
+    fit_prompt, format_chunk,
+    packing::{pack, Sources},
+    recent_edits::{Edit, RecentEdits},
+    MemoryBackend, MemoryRunParams, Prompt, PromptType,
+};
+
+// The recent commits and the diff of the repository `dir` is in, as they are packed
+pub async fn get_git_context(dir: &std::path::Path, max_commits: usize) -> Vec<String> {
+    let (commits, diff) = tokio::join!(
+        git::get_recent_commits(dir, max_commits),
+        git::get_diff(dir)
+    );
+    let commits =
+        (!commits.is_empty()).then(|| format!("Recent commits:\n{}", commits.join("\n\n")));
+    commits.into_iter().chain(diff).collect()
+}
 
 pub struct FileStore {
     _crawl: bool,
     _config: Config,
+    context_packing: Option<config::ContextPacking>,
     file_map: Mutex<HashMap<String, Rope>>,
+    language_ids: Mutex<HashMap<String, String>>,
     accessed_files: Mutex<IndexSet<String>>,
+    recent_edits: RecentEdits,
+    language_servers: LanguageServers,
 }
 
 impl FileStore {
     pub fn new(file_store_config: config::FileStore, config: Config) -> Self {
         Self {
             _crawl: file_store_config.crawl,
+            language_servers: LanguageServers::new(&config),
             _config: config,
+            context_packing: file_store_config.context_packing,
             file_map: Mutex::new(HashMap::new()),
+            language_ids: Mutex::new(HashMap::new()),
             accessed_files: Mutex::new(IndexSet::new()),
+            recent_edits: RecentEdits::default(),
         }
     }
 
     pub fn new_without_crawl(config: Config) -> Self {
         Self {
             _crawl: false,
+            language_servers: LanguageServers::new(&config),
             _config: config,
+            context_packing: None,
             file_map: Mutex::new(HashMap::new()),
+            language_ids: Mutex::new(HashMap::new()),
             accessed_files: Mutex::new(IndexSet::new()),
+            recent_edits: RecentEdits::default(),
         }
     }
 
@@ -80,6 +112,15 @@ impl FileStore {
         Ok((rope, cursor_index))
     }
 
+    pub fn get_file_contents(&self, uri: &str) -> anyhow::Result<String> {
+        Ok(self
+            .file_map
+            .lock()
+            .get(uri)
+            .context("Error file not found")?
+            .to_string())
+    }
+
     pub fn get_characters_around_position(
         &self,
         position: &TextDocumentPositionParams,
@@ -103,64 +144,193 @@ impl FileStore {
         Ok(rope_slice.to_string())
     }
 
-    pub fn build_code(
+    // The code before and after the cursor with the most recently accessed files before it. It
+    // is longer than fits into `max_tokens` so the tokenizer decides where it is cut.
+    pub fn get_code_around_position(
+        &self,
+        position: &TextDocumentPositionParams,
+        max_tokens: usize,
+    ) -> anyhow::Result<(String, String)> {
+        // Even code full of long tokens rarely has more characters per token than this
+        let characters = tokens_to_estimated_characters(max_tokens) * 2;
+        let (rope, cursor_index) = self.get_rope_for_position(position, characters)?;
+        let start = cursor_index.saturating_sub(characters);
+        let end = rope.len_chars().min(cursor_index + characters);
+        let prefix = rope
+            .get_slice(start..cursor_index)
+            .context("Error getting rope slice")?;
+        let suffix = rope
+            .get_slice(cursor_index..end)
+            .context("Error getting rope slice")?;
+        Ok((prefix.to_string(), suffix.to_string()))
+    }
+
+    // Packs the prompt from the document, the other open files, the recent edits, the language
+    // server and the retrieved `chunks`
+    pub async fn pack(
         &self,
         position: &TextDocumentPositionParams,
         prompt_type: PromptType,
-        params: MemoryRunParams,
+        params: &MemoryRunParams,
+        tokenizer: &(dyn Tokenizer + Send + Sync),
+        context_packing: &ContextPacking,
+        chunks: &[String],
     ) -> anyhow::Result<Prompt> {
-        let (mut rope, cursor_index) =
-            self.get_rope_for_position(position, params.max_context_length)?;
-
-        Ok(match prompt_type {
-            PromptType::ContextAndCode => {
-                if params.messages.is_some() {
-                    let max_length = tokens_to_estimated_characters(params.max_context_length);
-                    let start = cursor_index.saturating_sub(max_length / 2);
-                    let end = rope
-                        .len_chars()
-                        .min(cursor_index + (max_length - (cursor_index - start)));
-
-                    rope.insert(cursor_index, "<CURSOR>");
-                    let rope_slice = rope
-                        .get_slice(start..end + "<CURSOR>".chars().count())
-                        .context("Error getting rope slice")?;
-                    Prompt::ContextAndCode(ContextAndCodePrompt::new(
-                        "".to_string(),
-                        rope_slice.to_string(),
-                    ))
-                } else {
-                    let start = cursor_index
-                        .saturating_sub(tokens_to_estimated_characters(params.max_context_length));
-                    let rope_slice = rope
-                        .get_slice(start..cursor_index)
-                        .context("Error getting rope slice")?;
-                    Prompt::ContextAndCode(ContextAndCodePrompt::new(
-                        "".to_string(),
-                        rope_slice.to_string(),
-                    ))
-                }
-            }
-            PromptType::FIM => {
-                let max_length = tokens_to_estimated_characters(params.max_context_length);
-                let start = cursor_index.saturating_sub(max_length / 2);
-                let end = rope
-                    .len_chars()
-                    .min(cursor_index + (max_length - (cursor_index - start)));
-                let prefix = rope
-                    .get_slice(start..cursor_index)
-                    .context("Error getting rope slice")?;
-                let suffix = rope
-                    .get_slice(cursor_index..end)
-                    .context("Error getting rope slice")?;
-                Prompt::FIM(FIMPrompt::new(prefix.to_string(), suffix.to_string()))
+        let uri = position.text_document.uri.as_str();
+        let (text, cursor) = {
+            let file_map = self.file_map.lock();
+            let rope = file_map.get(uri).context("Error file not found")?;
+            let cursor = rope.line_to_char(position.position.line as usize)
+                + position.position.character as usize;
+            (rope.to_string(), rope.try_char_to_byte(cursor)?)
+        };
+        let open_files: Vec<(String, String)> = {
+            let file_map = self.file_map.lock();
+            self.accessed_files
+                .lock()
+                .iter()
+                .filter(|f| *f != uri)
+                .filter_map(|f| Some((f.clone(), file_map.get(f)?.to_string())))
+                .collect()
+        };
+        // Parsing every open file is only worth it when the imports are packed
+        let names = if context_packing
+            .priorities
+            .contains(&ContextSource::ImportedSymbols)
+        {
+            get_imported_names(uri, &text)
+        } else {
+            vec![]
+        };
+        let imports: Vec<String> = if names.is_empty() {
+            vec![]
+        } else {
+            open_files
+                .iter()
+                .flat_map(|(uri, file)| {
+                    get_signatures(uri, file, &names)
+                        .into_iter()
+                        .map(|(name, signature)| format_chunk(Some(uri), &[&name], &signature))
+                })
+                .collect()
+        };
+        let edits: Vec<String> = self
+            .recent_edits
+            .get(context_packing.max_recent_edits)
+            .iter()
+            .map(Edit::to_hunk)
+            .collect();
+        let language_id = self.get_language_id(uri);
+        let signatures = match language_id {
+            Some(language_id)
+                if context_packing
+                    .priorities
+                    .contains(&ContextSource::LanguageServer) =>
+            {
+                self.language_servers
+                    .get_signatures(uri, &language_id, &text, position.position)
+                    .await
             }
-        })
+            _ => vec![],
+        };
+        let git = match git::get_dir(uri)
+            .filter(|_| context_packing.priorities.contains(&ContextSource::Git))
+        {
+            Some(dir) => get_git_context(&dir, context_packing.max_commits).await,
+            None => vec![],
+        };
+        Ok(pack(
+            tokenizer,
+            prompt_type,
+            params,
+            &context_packing.priorities,
+            &Sources {
+                uri,
+                text: &text,
+                cursor,
+                chunks,
+                open_files: &open_files,
+                edits: &edits,
+                imports: &imports,
+                signatures: &signatures,
+                git: &git,
+            },
+        ))
+    }
+
+    pub async fn build_code(
+        &self,
+        position: &TextDocumentPositionParams,
+        prompt_type: PromptType,
+        params: MemoryRunParams,
+        tokenizer: &(dyn Tokenizer + Send + Sync),
+    ) -> anyhow::Result<Prompt> {
+        if let Some(context_packing) = &self.context_packing {
+            return self
+                .pack(
+                    position,
+                    prompt_type,
+                    &params,
+                    tokenizer,
+                    context_packing,
+                    &[],
+                )
+                .await;
+        }
+        let (prefix, suffix) =
+            self.get_code_around_position(position, params.max_context_length)?;
+        Ok(fit_prompt(
+            tokenizer,
+            prompt_type,
+            &params,
+            &prefix,
+            &suffix,
+            "",
+        ))
     }
 }
 
 #[async_trait::async_trait]
 impl MemoryBackend for FileStore {
+    #[instrument(skip(self))]
+    fn get_opened_text_documents(&self) -> Vec<TextDocumentItem> {
+        let file_map = self.file_map.lock();
+        let language_ids = self.language_ids.lock();
+        self.accessed_files
+            .lock()
+            .iter()
+            .rev()
+            .filter_map(|uri| {
+                Some(TextDocumentItem {
+                    uri: uri.parse().ok()?,
+                    language_id: language_ids.get(uri).cloned().unwrap_or_default(),
+                    version: 0,
+                    text: file_map.get(uri)?.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    #[instrument(skip(self))]
+    fn get_language_id(&self, uri: &str) -> Option<String> {
+        self.language_ids.lock().get(uri).cloned()
+    }
+
+    #[instrument(skip(self))]
+    fn get_text(&self, uri: &str, range: Option<&Range>) -> anyhow::Result<String> {
+        let file_map = self.file_map.lock();
+        let rope = file_map.get(uri).context("Error file not found")?;
+        let Some(range) = range else {
+            return Ok(rope.to_string());
+        };
+        let start = rope.line_to_char(range.start.line as usize) + range.start.character as usize;
+        let end = rope.line_to_char(range.end.line as usize) + range.end.character as usize;
+        Ok(rope
+            .get_slice(start..end)
+            .context("Error getting rope slice")?
+            .to_string())
+    }
+
     #[instrument(skip(self))]
     async fn get_filter_text(
         &self,
@@ -180,15 +350,17 @@ impl MemoryBackend for FileStore {
         Ok(line)
     }
 
-    #[instrument(skip(self))]
+    #[instrument(skip(self, tokenizer))]
     async fn build_prompt(
         &self,
         position: &TextDocumentPositionParams,
         prompt_type: PromptType,
         params: Value,
+        tokenizer: &(dyn Tokenizer + Send + Sync),
     ) -> anyhow::Result<Prompt> {
         let params: MemoryRunParams = serde_json::from_value(params)?;
-        self.build_code(position, prompt_type, params)
+        self.build_code(position, prompt_type, params, tokenizer)
+            .await
     }
 
     #[instrument(skip(self))]
@@ -199,6 +371,9 @@ impl MemoryBackend for FileStore {
         let rope = Rope::from_str(&params.text_document.text);
         let uri = params.text_document.uri.to_string();
         self.file_map.lock().insert(uri.clone(), rope);
+        self.language_ids
+            .lock()
+            .insert(uri.clone(), params.text_document.language_id);
         self.accessed_files.lock().shift_insert(0, uri);
         Ok(())
     }
@@ -220,8 +395,17 @@ impl MemoryBackend for FileStore {
                     rope.line_to_char(range.start.line as usize) + range.start.character as usize;
                 let end_index =
                     rope.line_to_char(range.end.line as usize) + range.end.character as usize;
+                let before = rope.clone();
                 rope.remove(start_index..end_index);
                 rope.insert(start_index, &change.text);
+                self.recent_edits.record(
+                    &uri,
+                    &before,
+                    rope,
+                    range.start.line as usize,
+                    range.end.line as usize,
+                    change.text.matches('\n').count(),
+                );
             } else {
                 *rope = Rope::from_str(&change.text);
             }
@@ -235,7 +419,13 @@ impl MemoryBackend for FileStore {
         for file_rename in params.files {
             let mut file_map = self.file_map.lock();
             if let Some(rope) = file_map.remove(&file_rename.old_uri) {
-                file_map.insert(file_rename.new_uri, rope);
+                file_map.insert(file_rename.new_uri.clone(), rope);
+            }
+            self.recent_edits
+                .rename(&file_rename.old_uri, &file_rename.new_uri);
+            let mut language_ids = self.language_ids.lock();
+            if let Some(language_id) = language_ids.remove(&file_rename.old_uri) {
+                language_ids.insert(file_rename.new_uri, language_id);
             }
         }
         Ok(())
@@ -245,6 +435,10 @@ impl MemoryBackend for FileStore {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        memory_backends::{ContextAndCodePrompt, FIMPrompt},
+        tokenizer::EstimatedTokenizer,
+    };
     use lsp_types::{
         DidOpenTextDocumentParams, FileRename, Position, Range, RenameFilesParams,
         TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem,
@@ -292,6 +486,25 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn can_get_text() -> anyhow::Result<()> {
+        let text_document = generate_filler_text_document(None, Some("Here is\nthe document body"));
+        let params = lsp_types::DidOpenTextDocumentParams { text_document };
+        let file_store = generate_base_file_store()?;
+        file_store.opened_text_document(params).await?;
+        let range = Range::new(Position::new(0, 5), Position::new(1, 3));
+        assert_eq!(
+            file_store.get_text("file://filler/", Some(&range))?,
+            "is\nthe"
+        );
+        assert_eq!(
+            file_store.get_text("file://filler/", None)?,
+            "Here is\nthe document body"
+        );
+        assert!(file_store.get_text("file://missing/", None).is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn can_rename_document() -> anyhow::Result<()> {
         let params = lsp_types::DidOpenTextDocumentParams {
@@ -415,6 +628,7 @@ The end with a trailing new line
                 },
                 PromptType::ContextAndCode,
                 json!({}),
+                &EstimatedTokenizer,
             )
             .await?;
         let prompt: ContextAndCodePrompt = prompt.try_into()?;
@@ -435,6 +649,7 @@ The end with a trailing new line
                 },
                 PromptType::FIM,
                 json!({}),
+                &EstimatedTokenizer,
             )
             .await?;
         let prompt: FIMPrompt = prompt.try_into()?;
@@ -466,6 +681,7 @@ The end with a trailing new line
                 json!({
                     "messages": []
                 }),
+                &EstimatedTokenizer,
             )
             .await?;
         let prompt: ContextAndCodePrompt = prompt.try_into()?;
@@ -511,6 +727,7 @@ The end with a trailing new line
                 },
                 PromptType::ContextAndCode,
                 json!({}),
+                &EstimatedTokenizer,
             )
             .await?;
         let prompt: ContextAndCodePrompt = prompt.try_into()?;
@@ -543,6 +760,7 @@ The end with a trailing new line
                 },
                 PromptType::ContextAndCode,
                 json!({"messages": []}),
+                &EstimatedTokenizer,
             )
             .await?;
         let prompt: ContextAndCodePrompt = prompt.try_into()?;
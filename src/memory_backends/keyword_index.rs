@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+
+use parking_lot::RwLock;
+
+use crate::splitters::Chunk;
+
+use super::vector_memory::{get_directories, SearchFilter, SearchResult};
+
+// The standard BM25 parameters
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+// Splits text into lowercase terms. Identifiers are kept whole so exact matches score highest and
+// are also split on `_` and camelCase boundaries so their parts match too.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut terms = vec![];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+    {
+        terms.push(word.to_lowercase());
+        let mut parts = vec![];
+        for part in word.split('_').filter(|part| !part.is_empty()) {
+            let mut start = 0;
+            let chars: Vec<(usize, char)> = part.char_indices().collect();
+            for window in chars.windows(2) {
+                let ((_, a), (i, b)) = (window[0], window[1]);
+                if a.is_lowercase() && b.is_uppercase() {
+                    parts.push(&part[start..i]);
+                    start = i;
+                }
+            }
+            parts.push(&part[start..]);
+        }
+        if parts.len() > 1 {
+            terms.extend(parts.into_iter().map(str::to_lowercase));
+        }
+    }
+    terms
+}
+
+struct Document {
+    text: String,
+    symbols: Vec<String>,
+    term_frequencies: HashMap<String, u32>,
+    length: usize,
+}
+
+struct File {
+    language: Option<String>,
+    directories: Vec<String>,
+    documents: Vec<Document>,
+}
+
+#[derive(Default)]
+struct Index {
+    files: HashMap<String, File>,
+    // The number of documents each term appears in
+    document_frequencies: HashMap<String, usize>,
+    n_documents: usize,
+    total_length: usize,
+}
+
+impl Index {
+    fn remove(&mut self, uri: &str) {
+        let Some(file) = self.files.remove(uri) else {
+            return;
+        };
+        for document in file.documents {
+            for term in document.term_frequencies.keys() {
+                if let Some(frequency) = self.document_frequencies.get_mut(term) {
+                    *frequency -= 1;
+                    if *frequency == 0 {
+                        self.document_frequencies.remove(term);
+                    }
+                }
+            }
+            self.n_documents -= 1;
+            self.total_length -= document.length;
+        }
+    }
+}
+
+// An in-memory BM25 index over the same chunks as the vector index so exact identifier matches
+// vector search misses can still be retrieved
+#[derive(Default)]
+pub struct KeywordIndex {
+    index: RwLock<Index>,
+}
+
+impl KeywordIndex {
+    pub fn upsert(&self, uri: &str, language: Option<&str>, chunks: &[Chunk]) {
+        let documents: Vec<Document> = chunks
+            .iter()
+            .map(|chunk| {
+                let terms = tokenize(&chunk.text);
+                let mut term_frequencies = HashMap::new();
+                for term in &terms {
+                    *term_frequencies.entry(term.clone()).or_insert(0) += 1;
+                }
+                Document {
+                    text: chunk.text.clone(),
+                    symbols: chunk.symbols.clone(),
+                    term_frequencies,
+                    length: terms.len(),
+                }
+            })
+            .collect();
+        let mut index = self.index.write();
+        index.remove(uri);
+        for document in &documents {
+            for term in document.term_frequencies.keys() {
+                *index.document_frequencies.entry(term.clone()).or_insert(0) += 1;
+            }
+            index.n_documents += 1;
+            index.total_length += document.length;
+        }
+        index.files.insert(
+            uri.to_owned(),
+            File {
+                language: language.map(str::to_owned),
+                directories: get_directories(uri),
+                documents,
+            },
+        );
+    }
+
+    pub fn delete(&self, uri: &str) {
+        self.index.write().remove(uri)
+    }
+
+    pub fn search(
+        &self,
+        query: &str,
+        filter: &SearchFilter<'_>,
+        limit: usize,
+    ) -> Vec<SearchResult> {
+        let index = self.index.read();
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+        let n_documents = index.n_documents as f32;
+        let average_length = index.total_length as f32 / n_documents.max(1.);
+        let idfs: Vec<(&str, f32)> = terms
+            .iter()
+            .filter_map(|term| {
+                let frequency = *index.document_frequencies.get(term)? as f32;
+                let idf = ((n_documents - frequency + 0.5) / (frequency + 0.5) + 1.).ln();
+                Some((term.as_str(), idf))
+            })
+            .collect();
+        if idfs.is_empty() {
+            return vec![];
+        }
+
+        let mut scored: Vec<(f32, &str, &Document)> = index
+            .files
+            .iter()
+            .filter(|(_, file)| match filter.language {
+                Some(language) => file.language.as_deref() == Some(language),
+                None => true,
+            })
+            .filter(|(_, file)| {
+                filter.directories.is_empty()
+                    || file
+                        .directories
+                        .iter()
+                        .any(|d| filter.directories.contains(d))
+            })
+            .filter(|(_, file)| match filter.workspace_folder {
+                Some(workspace_folder) => file.directories.iter().any(|d| d == workspace_folder),
+                None => true,
+            })
+            .flat_map(|(uri, file)| file.documents.iter().map(move |document| (uri, document)))
+            .filter_map(|(uri, document)| {
+                let length_norm = 1. - B + B * document.length as f32 / average_length.max(1.);
+                let score: f32 = idfs
+                    .iter()
+                    .filter_map(|(term, idf)| {
+                        let tf = *document.term_frequencies.get(*term)? as f32;
+                        Some(idf * tf * (K1 + 1.) / (tf + K1 * length_norm))
+                    })
+                    .sum();
+                (score > 0.).then_some((score, uri.as_str(), document))
+            })
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(limit)
+            .map(|(_, uri, document)| SearchResult {
+                uri: uri.to_owned(),
+                text: document.text.clone(),
+                symbols: document.symbols.clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(text: &str) -> Chunk {
+        Chunk {
+            text: text.to_owned(),
+            range: 0..text.len(),
+            symbols: vec![],
+        }
+    }
+
+    #[test]
+    fn keyword_index_tokenizes_identifiers() {
+        assert_eq!(
+            tokenize("getFileContents(file_store)"),
+            vec![
+                "getfilecontents",
+                "get",
+                "file",
+                "contents",
+                "file_store",
+                "file",
+                "store"
+            ]
+        );
+    }
+
+    #[test]
+    fn keyword_index_ranks_exact_matches() {
+        let index = KeywordIndex::default();
+        index.upsert(
+            "file:///project/a.rs",
+            Some("rust"),
+            &[
+                chunk("fn get_file_contents() {}"),
+                chunk("fn open_file() {}"),
+            ],
+        );
+        index.upsert(
+            "file:///project/b.py",
+            Some("python"),
+            &[chunk("def get_contents(): pass")],
+        );
+        let filter = SearchFilter {
+            language: None,
+            directories: &[],
+            workspace_folder: None,
+        };
+        let results = index.search("get_file_contents", &filter, 3);
+        assert_eq!(results[0].text, "fn get_file_contents() {}");
+        assert_eq!(results.len(), 3);
+
+        let filter = SearchFilter {
+            language: Some("python"),
+            directories: &[],
+            workspace_folder: None,
+        };
+        assert_eq!(index.search("get_file_contents", &filter, 3).len(), 1);
+
+        index.delete("file:///project/b.py");
+        assert!(index.search("def", &filter, 3).is_empty());
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use arrow_array::{
+    cast::AsArray,
+    types::{Float32Type, UInt64Type},
+    FixedSizeListArray, RecordBatch, RecordBatchIterator, StringArray, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use futures::TryStreamExt;
+use lancedb::{
+    index::Index,
+    query::{ExecutableQuery, QueryBase},
+    Connection, DistanceType, Table,
+};
+use lsp_types::Url;
+use tokio::sync::{Mutex, OnceCell};
+use tracing::info;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{config, splitters::Chunk};
+
+use super::vector_memory::{
+    get_directories, EmbeddedFile, SearchFilter, SearchResult, VectorStore,
+};
+
+// Vector search scans every chunk until there are this many, an ANN index is built then
+const INDEX_THRESHOLD: usize = 10_000;
+
+// Separates the directories of a file in its `directories` column so they can be matched with
+// LIKE
+const DIRECTORY_SEPARATOR: char = '|';
+
+pub struct LanceDB {
+    path: PathBuf,
+    // Each embedding model gets its own table, the embeddings are not comparable
+    table_name: String,
+    connection: OnceCell<Connection>,
+    // Created with the dimensions of the first embeddings
+    table: Mutex<Option<Table>>,
+    // Whether the ANN index was built or found to exist
+    indexed: AtomicBool,
+}
+
+fn get_path(configuration: &config::LanceDB, root_uri: Option<&str>) -> anyhow::Result<PathBuf> {
+    if let Some(path) = &configuration.path {
+        return Ok(PathBuf::from(path));
+    }
+    let root = root_uri
+        .and_then(|root_uri| Url::parse(root_uri).ok())
+        .and_then(|root_uri| root_uri.to_file_path().ok());
+    Ok(match root {
+        Some(root) => root.join(".lsp-ai").join("lancedb"),
+        None => directories::ProjectDirs::from("", "", "lsp-ai")
+            .context("unable to find the cache directory")?
+            .cache_dir()
+            .join("lancedb"),
+    })
+}
+
+// The bytes of the files under `path`
+fn get_dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => get_dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn get_schema(dimensions: usize) -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("uri", DataType::Utf8, false),
+        Field::new("language", DataType::Utf8, true),
+        Field::new("hash", DataType::UInt64, false),
+        Field::new("directories", DataType::Utf8, false),
+        Field::new("text", DataType::Utf8, false),
+        // A JSON array
+        Field::new("symbols", DataType::Utf8, false),
+        Field::new(
+            "vector",
+            DataType::FixedSizeList(
+                Arc::new(Field::new("item", DataType::Float32, true)),
+                dimensions as i32,
+            ),
+            false,
+        ),
+    ]))
+}
+
+// A SQL string literal
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn join_directories(directories: &[String]) -> String {
+    let mut joined = String::from(DIRECTORY_SEPARATOR);
+    for directory in directories {
+        joined.push_str(directory);
+        joined.push(DIRECTORY_SEPARATOR);
+    }
+    joined
+}
+
+// `_` and `%` in directory names are matched literally
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '_' | '%') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn matches_directory(directory: &str) -> String {
+    let pattern = format!(
+        "%{DIRECTORY_SEPARATOR}{}{DIRECTORY_SEPARATOR}%",
+        escape_like(directory)
+    );
+    format!("directories LIKE {} ESCAPE '\\'", quote(&pattern))
+}
+
+// The filter as a SQL predicate, None when it keeps every chunk
+fn get_predicate(filter: &SearchFilter<'_>) -> Option<String> {
+    let mut predicates = vec![];
+    if let Some(language) = filter.language {
+        predicates.push(format!("language = {}", quote(language)));
+    }
+    if !filter.directories.is_empty() {
+        let any: Vec<String> = filter
+            .directories
+            .iter()
+            .map(|directory| matches_directory(directory))
+            .collect();
+        predicates.push(format!("({})", any.join(" OR ")));
+    }
+    if let Some(workspace_folder) = filter.workspace_folder {
+        predicates.push(matches_directory(workspace_folder));
+    }
+    (!predicates.is_empty()).then(|| predicates.join(" AND "))
+}
+
+fn get_results(batches: &[RecordBatch]) -> anyhow::Result<Vec<SearchResult>> {
+    let mut results = vec![];
+    for batch in batches {
+        let column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .map(|column| column.as_string::<i32>().clone())
+                .with_context(|| format!("missing the {name} column"))
+        };
+        let (uris, texts, symbols) = (column("uri")?, column("text")?, column("symbols")?);
+        for i in 0..batch.num_rows() {
+            results.push(SearchResult {
+                uri: uris.value(i).to_owned(),
+                text: texts.value(i).to_owned(),
+                symbols: serde_json::from_str(symbols.value(i)).unwrap_or_default(),
+            });
+        }
+    }
+    Ok(results)
+}
+
+impl LanceDB {
+    pub fn new(configuration: &config::LanceDB, root_uri: Option<&str>) -> anyhow::Result<Self> {
+        let path = get_path(configuration, root_uri)?;
+        let model = configuration.embedding_model.name();
+        Ok(Self {
+            path,
+            table_name: format!("chunks_{:x}", xxh3_64(model.as_bytes())),
+            connection: OnceCell::new(),
+            table: Mutex::new(None),
+            indexed: AtomicBool::new(false),
+        })
+    }
+
+    async fn get_connection(&self) -> anyhow::Result<&Connection> {
+        self.connection
+            .get_or_try_init(|| async {
+                std::fs::create_dir_all(&self.path)?;
+                let path = self.path.to_str().context("the path is not valid UTF-8")?;
+                anyhow::Ok(lancedb::connect(path).execute().await?)
+            })
+            .await
+    }
+
+    // The table, created for embeddings with `dimensions` when it does not exist yet
+    async fn get_table(&self, dimensions: Option<usize>) -> anyhow::Result<Option<Table>> {
+        let mut table = self.table.lock().await;
+        if let Some(table) = &*table {
+            return Ok(Some(table.clone()));
+        }
+        let connection = self.get_connection().await?;
+        let names = connection.table_names().execute().await?;
+        let opened = if names.contains(&self.table_name) {
+            connection.open_table(&self.table_name).execute().await?
+        } else if let Some(dimensions) = dimensions {
+            info!(
+                "creating the {} table in {}",
+                self.table_name,
+                self.path.display()
+            );
+            connection
+                .create_empty_table(&self.table_name, get_schema(dimensions))
+                .execute()
+                .await?
+        } else {
+            return Ok(None);
+        };
+        *table = Some(opened.clone());
+        Ok(Some(opened))
+    }
+}
+
+#[async_trait::async_trait]
+impl VectorStore for LanceDB {
+    async fn upsert(
+        &self,
+        uri: &str,
+        language: Option<&str>,
+        hash: u64,
+        chunks: Vec<Chunk>,
+        embeddings: Vec<Vec<f32>>,
+    ) -> anyhow::Result<()> {
+        let Some(dimensions) = embeddings.first().map(Vec::len) else {
+            return self.delete(uri).await;
+        };
+        let table = self
+            .get_table(Some(dimensions))
+            .await?
+            .context("the table was not created")?;
+        let n = chunks.len();
+        let schema = get_schema(dimensions);
+        let directories = join_directories(&get_directories(uri));
+        let symbols: Vec<String> = chunks
+            .iter()
+            .map(|chunk| serde_json::to_string(&chunk.symbols))
+            .collect::<Result<_, _>>()?;
+        let vectors = FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+            embeddings
+                .into_iter()
+                .map(|embedding| Some(embedding.into_iter().map(Some))),
+            dimensions as i32,
+        );
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec![uri; n])),
+                Arc::new(StringArray::from(vec![language; n])),
+                Arc::new(UInt64Array::from(vec![hash; n])),
+                Arc::new(StringArray::from(vec![directories.as_str(); n])),
+                Arc::new(StringArray::from_iter_values(
+                    chunks.iter().map(|chunk| chunk.text.as_str()),
+                )),
+                Arc::new(StringArray::from(symbols)),
+                Arc::new(vectors),
+            ],
+        )?;
+        table.delete(&format!("uri = {}", quote(uri))).await?;
+        table
+            .add(Box::new(RecordBatchIterator::new(vec![Ok(batch)], schema)))
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, uri: &str) -> anyhow::Result<()> {
+        if let Some(table) = self.get_table(None).await? {
+            table.delete(&format!("uri = {}", quote(uri))).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_embedded(&self, uri: &str) -> anyhow::Result<Option<EmbeddedFile>> {
+        let Some(table) = self.get_table(None).await? else {
+            return Ok(None);
+        };
+        let batches: Vec<RecordBatch> = table
+            .query()
+            .only_if(format!("uri = {}", quote(uri)))
+            .execute()
+            .await?
+            .try_collect()
+            .await?;
+        let mut embedded: Option<EmbeddedFile> = None;
+        for batch in &batches {
+            let (Some(hashes), Some(texts), Some(vectors)) = (
+                batch.column_by_name("hash"),
+                batch.column_by_name("text"),
+                batch.column_by_name("vector"),
+            ) else {
+                anyhow::bail!("the {} table is missing columns", self.table_name);
+            };
+            let hashes = hashes.as_primitive::<UInt64Type>();
+            let texts = texts.as_string::<i32>();
+            let vectors = vectors.as_fixed_size_list();
+            for i in 0..batch.num_rows() {
+                let vector = vectors.value(i);
+                let embedding = vector.as_primitive::<Float32Type>().values().to_vec();
+                embedded
+                    .get_or_insert_with(|| EmbeddedFile {
+                        hash: Some(hashes.value(i)),
+                        embeddings: Default::default(),
+                    })
+                    .embeddings
+                    .insert(xxh3_64(texts.value(i).as_bytes()), embedding);
+            }
+        }
+        Ok(embedded)
+    }
+
+    async fn search(
+        &self,
+        embedding: Vec<f32>,
+        filter: SearchFilter<'_>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let Some(table) = self.get_table(None).await? else {
+            return Ok(vec![]);
+        };
+        let mut query = table
+            .query()
+            .nearest_to(embedding)?
+            .distance_type(DistanceType::Cosine)
+            .limit(limit);
+        if let Some(predicate) = get_predicate(&filter) {
+            query = query.only_if(predicate);
+        }
+        let batches: Vec<RecordBatch> = query.execute().await?.try_collect().await?;
+        get_results(&batches)
+    }
+
+    // Builds the ANN index once there are enough chunks for vector search to be slow without it
+    async fn flush(&self) -> anyhow::Result<()> {
+        if self.indexed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let Some(table) = self.get_table(None).await? else {
+            return Ok(());
+        };
+        if table.count_rows(None).await? < INDEX_THRESHOLD {
+            return Ok(());
+        }
+        self.indexed.store(true, Ordering::Relaxed);
+        let has_index = !table.list_indices().await?.is_empty();
+        if !has_index {
+            info!("building the vector index of {}", self.table_name);
+            table
+                .create_index(&["vector"], Index::Auto)
+                .execute()
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn get_size(&self) -> anyhow::Result<Option<u64>> {
+        let path = self.path.clone();
+        Ok(Some(
+            tokio::task::spawn_blocking(move || get_dir_size(&path)).await?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lancedb_builds_predicates() {
+        let directories = vec!["/home/user/it's".to_string(), "/tmp".to_string()];
+        let filter = SearchFilter {
+            language: Some("rust"),
+            directories: &directories,
+            workspace_folder: Some("/home/user"),
+        };
+        assert_eq!(
+            get_predicate(&filter).unwrap(),
+            "language = 'rust' AND (directories LIKE '%|/home/user/it''s|%' ESCAPE '\\' OR \
+             directories LIKE '%|/tmp|%' ESCAPE '\\') AND directories LIKE '%|/home/user|%' \
+             ESCAPE '\\'"
+        );
+        assert_eq!(
+            join_directories(&get_directories("file:///home/user/a.rs")),
+            "|/home|/home/user|"
+        );
+        let filter = SearchFilter {
+            language: None,
+            directories: &[],
+            workspace_folder: None,
+        };
+        assert!(get_predicate(&filter).is_none());
+    }
+
+    #[test]
+    fn lancedb_escapes_like_wildcards() {
+        // Unescaped, `_` would also match e.g. `/home/my-project`
+        assert_eq!(
+            matches_directory("/home/my_project/100%"),
+            "directories LIKE '%|/home/my\\_project/100\\%|%' ESCAPE '\\'"
+        );
+        assert_eq!(
+            matches_directory("C:\\Users"),
+            "directories LIKE '%|C:\\\\Users|%' ESCAPE '\\'"
+        );
+    }
+}
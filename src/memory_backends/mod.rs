@@ -1,33 +1,96 @@
 use lsp_types::{
-    DidChangeTextDocumentParams, DidOpenTextDocumentParams, RenameFilesParams,
-    TextDocumentPositionParams,
+    DidChangeTextDocumentParams, DidChangeWorkspaceFoldersParams, DidOpenTextDocumentParams, Range,
+    RenameFilesParams, TextDocumentItem, TextDocumentPositionParams,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::config::{ChatMessage, Config, ValidMemoryBackend};
+use crate::{
+    config::{ChatMessage, Config, ValidMemoryBackend},
+    custom_requests::{
+        memory_stats::{FileStatus, MemoryStatsResult},
+        search::{SearchMatch, WorkspaceSearchParams},
+    },
+    tokenizer::{fit, Keep, Part, Tokenizer},
+};
+
+use self::vector_memory::{SearchParams, VectorMemory};
 
+mod crawl;
+mod docs;
 pub mod file_store;
+mod keyword_index;
+#[cfg(feature = "lancedb")]
+mod lancedb;
+mod packing;
+mod pgvector;
 mod postgresml;
+mod qdrant;
+mod recent_edits;
+mod sqlite;
+mod vector_index;
+mod vector_memory;
 
 const fn max_context_length_default() -> usize {
     1024
 }
 
+const fn prefix_ratio_default() -> f32 {
+    0.25
+}
+
+const fn suffix_ratio_default() -> f32 {
+    0.25
+}
+
+const fn context_ratio_default() -> f32 {
+    0.5
+}
+
 #[derive(Clone, Debug)]
 pub enum PromptType {
     ContextAndCode,
     FIM,
 }
 
+// How the context window is divided between the code before the cursor, the code after it and
+// the retrieved context. The room a part does not need goes to the others.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContextRatios {
+    #[serde(default = "prefix_ratio_default")]
+    pub prefix: f32,
+    #[serde(default = "suffix_ratio_default")]
+    pub suffix: f32,
+    #[serde(default = "context_ratio_default")]
+    pub context: f32,
+}
+
+impl Default for ContextRatios {
+    fn default() -> Self {
+        Self {
+            prefix: prefix_ratio_default(),
+            suffix: suffix_ratio_default(),
+            context: context_ratio_default(),
+        }
+    }
+}
+
 #[derive(Clone, Deserialize)]
 pub struct MemoryRunParams {
     pub messages: Option<Vec<ChatMessage>>,
+    // In tokens of the model the prompt is for
     #[serde(default = "max_context_length_default")]
     pub max_context_length: usize,
+    #[serde(default)]
+    pub ratios: ContextRatios,
+    // Cuts code before the cursor that does not fit where the start of the prompt stays the same
+    // while typing, for servers caching prompt prefixes
+    #[serde(default)]
+    pub stable_prefix: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ContextAndCodePrompt {
     pub context: String,
     pub code: String,
@@ -39,7 +102,7 @@ impl ContextAndCodePrompt {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct FIMPrompt {
     pub prompt: String,
     pub suffix: String,
@@ -54,7 +117,7 @@ impl FIMPrompt {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Prompt {
     FIM(FIMPrompt),
     ContextAndCode(ContextAndCodePrompt),
@@ -104,6 +167,123 @@ impl<'a> TryFrom<&'a Prompt> for &'a FIMPrompt {
     }
 }
 
+// Fits the code around the cursor and the retrieved context into the context window. Prompts
+// without messages are completed from the prefix, they leave out the suffix.
+fn fit_prompt(
+    tokenizer: &(dyn Tokenizer + Send + Sync),
+    prompt_type: PromptType,
+    params: &MemoryRunParams,
+    prefix: &str,
+    suffix: &str,
+    context: &str,
+) -> Prompt {
+    let ratios = &params.ratios;
+    let suffix = if uses_suffix(&prompt_type, params) {
+        suffix
+    } else {
+        ""
+    };
+    let parts = fit(
+        tokenizer,
+        params.max_context_length,
+        &[
+            Part {
+                text: prefix,
+                ratio: ratios.prefix,
+                keep: Keep::End,
+            },
+            Part {
+                text: suffix,
+                ratio: ratios.suffix,
+                keep: Keep::Start,
+            },
+            // Retrieved chunks are ordered from most to least relevant
+            Part {
+                text: context,
+                ratio: ratios.context,
+                keep: Keep::Start,
+            },
+        ],
+    );
+    let prefix = if params.stable_prefix && parts[0].len() < prefix.len() {
+        stabilize_prefix(parts[0])
+    } else {
+        parts[0]
+    };
+    assemble_prompt(prompt_type, params, prefix, parts[1], parts[2].to_owned())
+}
+
+// On average every this many lines is one the prefix may start at
+const PREFIX_ANCHOR_LINES: u64 = 16;
+
+// Whether the line is one to start the prefix at. It only depends on the line so the prefix
+// keeps starting at it while the cursor moves, until the line no longer fits.
+fn is_prefix_anchor(line: &str) -> bool {
+    // FNV-1a, the hashers of std may change between releases
+    let hash = line.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash % PREFIX_ANCHOR_LINES == 0
+}
+
+// Drops at most a quarter of the prefix to start at an anchor line, or else at the first whole
+// line
+fn stabilize_prefix(prefix: &str) -> &str {
+    let max_dropped = prefix.len() / 4;
+    let mut line_starts = prefix
+        .match_indices('\n')
+        .map(|(i, _)| i + 1)
+        .take_while(|start| *start <= max_dropped)
+        .peekable();
+    let Some(&first) = line_starts.peek() else {
+        return prefix;
+    };
+    let start = line_starts
+        .find(|start| {
+            let line = prefix[*start..].split('\n').next().unwrap_or_default();
+            is_prefix_anchor(line)
+        })
+        .unwrap_or(first);
+    &prefix[start..]
+}
+
+fn uses_suffix(prompt_type: &PromptType, params: &MemoryRunParams) -> bool {
+    !matches!(prompt_type, PromptType::ContextAndCode) || params.messages.is_some()
+}
+
+fn assemble_prompt(
+    prompt_type: PromptType,
+    params: &MemoryRunParams,
+    prefix: &str,
+    suffix: &str,
+    context: String,
+) -> Prompt {
+    match prompt_type {
+        PromptType::ContextAndCode if params.messages.is_some() => Prompt::ContextAndCode(
+            ContextAndCodePrompt::new(context, format!("{prefix}<CURSOR>{suffix}")),
+        ),
+        PromptType::ContextAndCode => {
+            Prompt::ContextAndCode(ContextAndCodePrompt::new(context, prefix.to_owned()))
+        }
+        PromptType::FIM if context.is_empty() => {
+            Prompt::FIM(FIMPrompt::new(prefix.to_owned(), suffix.to_owned()))
+        }
+        PromptType::FIM => Prompt::FIM(FIMPrompt::new(
+            format!("{context}\n\n{prefix}"),
+            suffix.to_owned(),
+        )),
+    }
+}
+
+// Labels a retrieved chunk with where it is from so the model can make sense of it
+fn format_chunk(uri: Option<&str>, symbols: &[&str], text: &str) -> String {
+    match (uri, symbols.is_empty()) {
+        (Some(uri), false) => format!("{uri} ({}):\n{text}", symbols.join(", ")),
+        (Some(uri), true) => format!("{uri}:\n{text}"),
+        (None, _) => text.to_owned(),
+    }
+}
+
 #[async_trait::async_trait]
 pub trait MemoryBackend {
     async fn init(&self) -> anyhow::Result<()> {
@@ -115,16 +295,57 @@ pub trait MemoryBackend {
         params: DidChangeTextDocumentParams,
     ) -> anyhow::Result<()>;
     async fn renamed_files(&self, params: RenameFilesParams) -> anyhow::Result<()>;
+    async fn changed_workspace_folders(
+        &self,
+        _params: DidChangeWorkspaceFoldersParams,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
     async fn build_prompt(
         &self,
         position: &TextDocumentPositionParams,
         prompt_type: PromptType,
         params: Value,
+        tokenizer: &(dyn Tokenizer + Send + Sync),
     ) -> anyhow::Result<Prompt>;
     async fn get_filter_text(
         &self,
         position: &TextDocumentPositionParams,
     ) -> anyhow::Result<String>;
+    // The open documents ordered from least to most recently accessed so a replacement backend
+    // can be brought up to date by opening them in order
+    fn get_opened_text_documents(&self) -> Vec<TextDocumentItem>;
+    fn get_language_id(&self, uri: &str) -> Option<String>;
+    // The text of the document in `range`, or all of it when no range is given
+    fn get_text(&self, uri: &str, range: Option<&Range>) -> anyhow::Result<String>;
+    // Called before the server exits to write anything still pending
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+    // What is indexed, backends without an index report the opened documents
+    async fn get_stats(&self) -> anyhow::Result<MemoryStatsResult> {
+        let files = self
+            .get_opened_text_documents()
+            .into_iter()
+            .map(|text_document| FileStatus {
+                uri: text_document.uri.to_string(),
+                language: Some(text_document.language_id),
+                ..Default::default()
+            })
+            .collect();
+        Ok(MemoryStatsResult {
+            files,
+            ..Default::default()
+        })
+    }
+    // Indexes the workspace again in the background, `force` embeds the unchanged files too
+    async fn reindex(&self, _force: bool) -> anyhow::Result<()> {
+        anyhow::bail!("the memory backend does not keep an index")
+    }
+    // Searches the index directly instead of for a prompt
+    async fn search(&self, _params: WorkspaceSearchParams) -> anyhow::Result<Vec<SearchMatch>> {
+        anyhow::bail!("the memory backend does not keep an index")
+    }
 }
 
 impl TryFrom<Config> for Box<dyn MemoryBackend + Send + Sync> {
@@ -138,6 +359,109 @@ impl TryFrom<Config> for Box<dyn MemoryBackend + Send + Sync> {
             ValidMemoryBackend::PostgresML(postgresml_config) => Ok(Box::new(
                 postgresml::PostgresML::new(postgresml_config, configuration)?,
             )),
+            ValidMemoryBackend::Qdrant(qdrant_config) => {
+                let search_params = SearchParams {
+                    limit: qdrant_config.search_limit,
+                    filter_by_language: qdrant_config.filter_by_language,
+                    paths: qdrant_config.paths.clone(),
+                    filter_by_workspace_folder: qdrant_config.filter_by_workspace_folder,
+                    hybrid_search: qdrant_config.hybrid_search.clone(),
+                    rerank: qdrant_config.rerank.clone(),
+                };
+                Ok(Box::new(VectorMemory::new(
+                    Box::new(qdrant::Qdrant::new(qdrant_config.clone())),
+                    qdrant_config.splitter,
+                    qdrant_config.embedding_model,
+                    search_params,
+                    qdrant_config.context_packing,
+                    qdrant_config.crawl,
+                    configuration,
+                )?))
+            }
+            ValidMemoryBackend::VectorIndex(vector_index_config) => {
+                let store = vector_index::VectorIndex::new(
+                    &vector_index_config,
+                    configuration.get_root_uri(),
+                )?;
+                let search_params = SearchParams {
+                    limit: vector_index_config.search_limit,
+                    filter_by_language: vector_index_config.filter_by_language,
+                    paths: vector_index_config.paths,
+                    filter_by_workspace_folder: vector_index_config.filter_by_workspace_folder,
+                    hybrid_search: vector_index_config.hybrid_search,
+                    rerank: vector_index_config.rerank,
+                };
+                Ok(Box::new(VectorMemory::new(
+                    Box::new(store),
+                    vector_index_config.splitter,
+                    vector_index_config.embedding_model,
+                    search_params,
+                    vector_index_config.context_packing,
+                    vector_index_config.crawl,
+                    configuration,
+                )?))
+            }
+            #[cfg(feature = "lancedb")]
+            ValidMemoryBackend::LanceDB(lancedb_config) => {
+                let store = lancedb::LanceDB::new(&lancedb_config, configuration.get_root_uri())?;
+                let search_params = SearchParams {
+                    limit: lancedb_config.search_limit,
+                    filter_by_language: lancedb_config.filter_by_language,
+                    paths: lancedb_config.paths,
+                    filter_by_workspace_folder: lancedb_config.filter_by_workspace_folder,
+                    hybrid_search: lancedb_config.hybrid_search,
+                    rerank: lancedb_config.rerank,
+                };
+                Ok(Box::new(VectorMemory::new(
+                    Box::new(store),
+                    lancedb_config.splitter,
+                    lancedb_config.embedding_model,
+                    search_params,
+                    lancedb_config.context_packing,
+                    lancedb_config.crawl,
+                    configuration,
+                )?))
+            }
+            ValidMemoryBackend::Pgvector(pgvector_config) => {
+                let store = pgvector::Pgvector::new(&pgvector_config)?;
+                let search_params = SearchParams {
+                    limit: pgvector_config.search_limit,
+                    filter_by_language: pgvector_config.filter_by_language,
+                    paths: pgvector_config.paths,
+                    filter_by_workspace_folder: pgvector_config.filter_by_workspace_folder,
+                    hybrid_search: pgvector_config.hybrid_search,
+                    rerank: pgvector_config.rerank,
+                };
+                Ok(Box::new(VectorMemory::new(
+                    Box::new(store),
+                    pgvector_config.splitter,
+                    pgvector_config.embedding_model,
+                    search_params,
+                    pgvector_config.context_packing,
+                    pgvector_config.crawl,
+                    configuration,
+                )?))
+            }
+            ValidMemoryBackend::Sqlite(sqlite_config) => {
+                let store = sqlite::Sqlite::new(&sqlite_config, configuration.get_root_uri())?;
+                let search_params = SearchParams {
+                    limit: sqlite_config.search_limit,
+                    filter_by_language: sqlite_config.filter_by_language,
+                    paths: sqlite_config.paths,
+                    filter_by_workspace_folder: sqlite_config.filter_by_workspace_folder,
+                    hybrid_search: sqlite_config.hybrid_search,
+                    rerank: sqlite_config.rerank,
+                };
+                Ok(Box::new(VectorMemory::new(
+                    Box::new(store),
+                    sqlite_config.splitter,
+                    sqlite_config.embedding_model,
+                    search_params,
+                    sqlite_config.context_packing,
+                    sqlite_config.crawl,
+                    configuration,
+                )?))
+            }
         }
     }
 }
@@ -167,3 +491,31 @@ impl Prompt {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_prefixes() {
+        let text: String = (0..200).map(|i| format!("line {i}\n")).collect();
+        // Cuts of the text as the cursor moves on, the start is the same for most of them
+        let starts: Vec<&str> = (0..40)
+            .map(|i| stabilize_prefix(&text[100 + i..]))
+            .map(|prefix| prefix.split('\n').next().unwrap())
+            .collect();
+        assert!(starts.windows(2).filter(|w| w[0] != w[1]).count() <= 4);
+        let anchor = text
+            .lines()
+            .skip(20)
+            .find(|line| is_prefix_anchor(line))
+            .unwrap();
+        assert!(starts.contains(&anchor));
+
+        // At most a quarter is dropped, and a prefix of one line is kept as is
+        let prefix = stabilize_prefix(&text[100..]);
+        assert!(prefix.len() >= text[100..].len() * 3 / 4);
+        assert!(text.ends_with(prefix));
+        assert_eq!(stabilize_prefix("def f():"), "def f():");
+    }
+}
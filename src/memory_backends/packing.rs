@@ -0,0 +1,260 @@
+use std::ops::Range;
+
+use crate::{
+    config::ContextSource,
+    splitters::get_surrounding_definitions,
+    tokenizer::{fit, truncate, Keep, Part, Tokenizer},
+};
+
+use super::{assemble_prompt, format_chunk, uses_suffix, MemoryRunParams, Prompt, PromptType};
+
+// What a prompt can be packed from
+pub struct Sources<'a> {
+    pub uri: &'a str,
+    pub text: &'a str,
+    // The byte offset of the cursor in `text`
+    pub cursor: usize,
+    // Formatted and ordered from most to least relevant
+    pub chunks: &'a [String],
+    // The uris and texts of the other open files, most recently accessed first
+    pub open_files: &'a [(String, String)],
+    // Diff hunks, newest first
+    pub edits: &'a [String],
+    // The signatures of the imported symbols, formatted
+    pub imports: &'a [String],
+    // The signatures of the identifiers around the cursor, from the language server
+    pub signatures: &'a [String],
+    // The recent commits and the diff of the repository
+    pub git: &'a [String],
+}
+
+struct Packer<'a> {
+    tokenizer: &'a (dyn Tokenizer + Send + Sync),
+    text: &'a str,
+    params: &'a MemoryRunParams,
+    remaining: usize,
+    // The code is kept in one piece around the cursor so it reads like the file does
+    code: Range<usize>,
+    with_suffix: bool,
+    context: Vec<String>,
+}
+
+impl Packer<'_> {
+    fn count(&self, text: &str) -> usize {
+        self.tokenizer.count_tokens(text)
+    }
+
+    // Grows the code to cover `range`, or as much of it as is left room for when `partially`
+    fn extend_code(&mut self, range: Range<usize>, partially: bool) {
+        let text = self.text;
+        let end = if self.with_suffix {
+            range.end.max(self.code.end)
+        } else {
+            self.code.end
+        };
+        let before = &text[range.start.min(self.code.start)..self.code.start];
+        let after = &text[self.code.end..end];
+        let tokens = self.count(before) + self.count(after);
+        let (before, after) = if tokens <= self.remaining {
+            (before, after)
+        } else if partially {
+            let parts = fit(
+                self.tokenizer,
+                self.remaining,
+                &[
+                    Part {
+                        text: before,
+                        ratio: self.params.ratios.prefix,
+                        keep: Keep::End,
+                    },
+                    Part {
+                        text: after,
+                        ratio: self.params.ratios.suffix,
+                        keep: Keep::Start,
+                    },
+                ],
+            );
+            (parts[0], parts[1])
+        } else {
+            return;
+        };
+        self.remaining = self
+            .remaining
+            .saturating_sub(self.count(before) + self.count(after));
+        self.code = self.code.start - before.len()..self.code.end + after.len();
+    }
+
+    fn add_context(&mut self, text: &str) -> bool {
+        let tokens = self.count(text);
+        if tokens > self.remaining {
+            return false;
+        }
+        self.remaining -= tokens;
+        self.context.push(text.to_owned());
+        true
+    }
+}
+
+// Fills `max_context_length` from the sources in the order of `priorities`. Code around the
+// cursor that does not fit whole is cut, other sources are skipped except for the last open file
+// that is cut to the room left.
+pub fn pack(
+    tokenizer: &(dyn Tokenizer + Send + Sync),
+    prompt_type: PromptType,
+    params: &MemoryRunParams,
+    priorities: &[ContextSource],
+    sources: &Sources,
+) -> Prompt {
+    let text = sources.text;
+    let cursor = sources.cursor;
+    let definitions = get_surrounding_definitions(sources.uri, text, cursor);
+    let mut packer = Packer {
+        tokenizer,
+        text,
+        params,
+        remaining: params.max_context_length,
+        code: cursor..cursor,
+        with_suffix: uses_suffix(&prompt_type, params),
+        context: vec![],
+    };
+    for source in priorities {
+        match source {
+            ContextSource::CurrentFunction => {
+                let current = match &definitions {
+                    Some(definitions) => definitions.current.clone().unwrap_or_else(|| {
+                        // Between definitions the line the cursor is on stands in for one
+                        let start = text[..cursor].rfind('\n').map_or(0, |i| i + 1);
+                        let end = text[cursor..].find('\n').map_or(text.len(), |i| cursor + i);
+                        start..end
+                    }),
+                    // Without a grammar there is no telling where the function ends
+                    None => 0..text.len(),
+                };
+                packer.extend_code(current, true);
+            }
+            ContextSource::SiblingSymbols => {
+                for sibling in definitions.iter().flat_map(|d| d.siblings.iter()) {
+                    packer.extend_code(sibling.clone(), false);
+                }
+            }
+            ContextSource::ImportedSymbols => {
+                for import in sources.imports {
+                    packer.add_context(import);
+                }
+            }
+            ContextSource::LanguageServer => {
+                for signature in sources.signatures {
+                    packer.add_context(signature);
+                }
+            }
+            ContextSource::RecentEdits => {
+                for edit in sources.edits {
+                    packer.add_context(edit);
+                }
+            }
+            ContextSource::RetrievedChunks => {
+                for chunk in sources.chunks {
+                    packer.add_context(chunk);
+                }
+            }
+            ContextSource::OpenFiles => {
+                for (uri, file) in sources.open_files {
+                    let file = format_chunk(Some(uri), &[], file);
+                    if !packer.add_context(&file) {
+                        let file = truncate(tokenizer, &file, packer.remaining, Keep::Start);
+                        if !file.is_empty() {
+                            packer.add_context(file);
+                        }
+                        break;
+                    }
+                }
+            }
+            ContextSource::Git => {
+                for git in sources.git {
+                    if !packer.add_context(git) {
+                        // Diffs are often long, their start is still worth having
+                        let git = truncate(tokenizer, git, packer.remaining, Keep::Start);
+                        if !git.is_empty() {
+                            packer.add_context(git);
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    assemble_prompt(
+        prompt_type,
+        params,
+        &text[packer.code.start..cursor],
+        &text[cursor..packer.code.end],
+        packer.context.join("\n\n"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory_backends::ContextAndCodePrompt, tokenizer::EstimatedTokenizer};
+
+    fn pack_code(priorities: &[ContextSource], max_context_length: usize) -> ContextAndCodePrompt {
+        let text = "fn one() {\n    1;\n}\n\nfn two() {\n    2;\n}\n\nfn three() {\n    3;\n}\n";
+        let params: MemoryRunParams = serde_json::from_value(serde_json::json!({
+            "messages": [],
+            "max_context_length": max_context_length
+        }))
+        .unwrap();
+        let prompt = pack(
+            &EstimatedTokenizer,
+            PromptType::ContextAndCode,
+            &params,
+            priorities,
+            &Sources {
+                uri: "file:///test.rs",
+                text,
+                cursor: 37,
+                chunks: &["fn chunk() {}".to_string(), "a ".repeat(100)],
+                open_files: &[("file:///open.rs".to_string(), "fn open() {}".to_string())],
+                edits: &[],
+                imports: &[],
+                signatures: &[],
+                git: &[],
+            },
+        );
+        prompt.try_into().unwrap()
+    }
+
+    #[test]
+    fn packs_in_priority_order() {
+        let prompt = pack_code(&[ContextSource::CurrentFunction], 1024);
+        assert_eq!(prompt.code, "fn two() {\n    2<CURSOR>;\n}");
+        assert_eq!(prompt.context, "");
+
+        let prompt = pack_code(
+            &[
+                ContextSource::CurrentFunction,
+                ContextSource::SiblingSymbols,
+                ContextSource::OpenFiles,
+            ],
+            1024,
+        );
+        assert!(prompt.code.starts_with("fn one()"));
+        assert!(prompt.code.ends_with("3;\n}"));
+        assert_eq!(prompt.context, "file:///open.rs:\nfn open() {}");
+
+        // The long chunk does not fit and nothing is left for the code
+        let prompt = pack_code(
+            &[
+                ContextSource::RetrievedChunks,
+                ContextSource::OpenFiles,
+                ContextSource::CurrentFunction,
+            ],
+            25,
+        );
+        assert_eq!(
+            prompt.context,
+            "fn chunk() {}\n\nfile:///open.rs:\nfn open() {}"
+        );
+        assert_eq!(prompt.code, "<CURSOR>");
+    }
+}
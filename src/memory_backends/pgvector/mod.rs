@@ -0,0 +1,342 @@
+use anyhow::Context;
+use sqlx::{
+    postgres::{PgPoolOptions, PgRow},
+    PgPool, Row,
+};
+use tokio::sync::OnceCell;
+use tracing::info;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{config, splitters::Chunk};
+
+use super::vector_memory::{
+    get_directories, EmbeddedFile, SearchFilter, SearchResult, VectorStore,
+};
+
+// Held while creating the tables so lsp-ai instances sharing a database do not race
+const BOOTSTRAP_LOCK: i64 = 0x6c73_705f_6169;
+
+// Chunks and embeddings in a Postgres database with the pgvector extension. Vectors are sent and
+// read as text so no type mapping is needed for them.
+pub struct Pgvector {
+    database_url: String,
+    max_connections: u32,
+    // Each embedding model gets its own tables, the embeddings are not comparable
+    files_table: String,
+    chunks_table: String,
+    pool: OnceCell<PgPool>,
+    // The chunks table is created with the dimensions of the first embeddings
+    chunks_ready: OnceCell<()>,
+}
+
+fn get_database_url(configuration: &config::Pgvector) -> anyhow::Result<String> {
+    if let Some(database_url) = &configuration.database_url {
+        return Ok(database_url.clone());
+    }
+    std::env::var("DATABASE_URL")
+        .context("the pgvector backend needs `database_url` or the DATABASE_URL env var")
+}
+
+// A pgvector literal like `[1,0.5]`
+fn to_vector(embedding: &[f32]) -> String {
+    let values: Vec<String> = embedding.iter().map(f32::to_string).collect();
+    format!("[{}]", values.join(","))
+}
+
+fn from_vector(vector: &str) -> anyhow::Result<Vec<f32>> {
+    let values = vector.trim().trim_start_matches('[').trim_end_matches(']');
+    if values.is_empty() {
+        return Ok(vec![]);
+    }
+    Ok(values
+        .split(',')
+        .map(|value| value.trim().parse())
+        .collect::<Result<_, _>>()?)
+}
+
+// Every parameter is always bound, a NULL or empty one keeps every chunk
+const FILTER_CONDITIONS: &str = "($2::text IS NULL OR f.language = $2) \
+     AND (cardinality($3::text[]) = 0 OR f.directories && $3) \
+     AND ($4::text IS NULL OR $4 = ANY (f.directories))";
+
+fn get_results(rows: Vec<PgRow>) -> anyhow::Result<Vec<SearchResult>> {
+    rows.into_iter()
+        .map(|row| {
+            let symbols: String = row.try_get("symbols")?;
+            Ok(SearchResult {
+                uri: row.try_get("uri")?,
+                text: row.try_get("text")?,
+                symbols: serde_json::from_str(&symbols).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+impl Pgvector {
+    pub fn new(configuration: &config::Pgvector) -> anyhow::Result<Self> {
+        let model = xxh3_64(configuration.embedding_model.name().as_bytes());
+        Ok(Self {
+            database_url: get_database_url(configuration)?,
+            max_connections: configuration.max_connections,
+            files_table: format!("lsp_ai_files_{model:x}"),
+            chunks_table: format!("lsp_ai_chunks_{model:x}"),
+            pool: OnceCell::new(),
+            chunks_ready: OnceCell::new(),
+        })
+    }
+
+    // Connects and creates the extension and files table the first time it is called
+    async fn get_pool(&self) -> anyhow::Result<&PgPool> {
+        self.pool
+            .get_or_try_init(|| async {
+                let pool = PgPoolOptions::new()
+                    .max_connections(self.max_connections)
+                    .connect(&self.database_url)
+                    .await?;
+                let mut transaction = pool.begin().await?;
+                sqlx::query("SELECT pg_advisory_xact_lock($1)")
+                    .bind(BOOTSTRAP_LOCK)
+                    .execute(&mut *transaction)
+                    .await?;
+                sqlx::query("CREATE EXTENSION IF NOT EXISTS vector")
+                    .execute(&mut *transaction)
+                    .await?;
+                sqlx::query(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} (uri TEXT PRIMARY KEY, language TEXT, \
+                     hash BIGINT NOT NULL, directories TEXT[] NOT NULL)",
+                    self.files_table
+                ))
+                .execute(&mut *transaction)
+                .await?;
+                transaction.commit().await?;
+                anyhow::Ok(pool)
+            })
+            .await
+    }
+
+    async fn create_chunks_table(&self, pool: &PgPool, dimensions: usize) -> anyhow::Result<()> {
+        self.chunks_ready
+            .get_or_try_init(|| async {
+                let chunks = &self.chunks_table;
+                let mut transaction = pool.begin().await?;
+                sqlx::query("SELECT pg_advisory_xact_lock($1)")
+                    .bind(BOOTSTRAP_LOCK)
+                    .execute(&mut *transaction)
+                    .await?;
+                let exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+                    .bind(chunks)
+                    .fetch_one(&mut *transaction)
+                    .await?;
+                if !exists {
+                    info!("creating the {chunks} table for {dimensions} dimensional embeddings");
+                    for statement in [
+                        format!(
+                            "CREATE TABLE {chunks} (id BIGSERIAL PRIMARY KEY, \
+                             uri TEXT NOT NULL REFERENCES {} (uri) ON DELETE CASCADE, \
+                             text TEXT NOT NULL, symbols TEXT NOT NULL, \
+                             embedding vector({dimensions}) NOT NULL)",
+                            self.files_table
+                        ),
+                        format!("CREATE INDEX {chunks}_uri ON {chunks} (uri)"),
+                        format!(
+                            "CREATE INDEX {chunks}_embedding ON {chunks} \
+                             USING hnsw (embedding vector_cosine_ops)"
+                        ),
+                    ] {
+                        sqlx::query(&statement).execute(&mut *transaction).await?;
+                    }
+                }
+                transaction.commit().await?;
+                anyhow::Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    // Whether the chunks table exists, reads do not create it
+    async fn has_chunks_table(&self, pool: &PgPool) -> anyhow::Result<bool> {
+        if self.chunks_ready.initialized() {
+            return Ok(true);
+        }
+        let exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+            .bind(&self.chunks_table)
+            .fetch_one(pool)
+            .await?;
+        if exists {
+            let _ = self.chunks_ready.set(());
+        }
+        Ok(exists)
+    }
+}
+
+#[async_trait::async_trait]
+impl VectorStore for Pgvector {
+    async fn upsert(
+        &self,
+        uri: &str,
+        language: Option<&str>,
+        hash: u64,
+        chunks: Vec<Chunk>,
+        embeddings: Vec<Vec<f32>>,
+    ) -> anyhow::Result<()> {
+        let Some(dimensions) = embeddings.first().map(Vec::len) else {
+            return self.delete(uri).await;
+        };
+        let pool = self.get_pool().await?;
+        self.create_chunks_table(pool, dimensions).await?;
+        let symbols: Vec<String> = chunks
+            .iter()
+            .map(|chunk| serde_json::to_string(&chunk.symbols))
+            .collect::<Result<_, _>>()?;
+        let texts: Vec<String> = chunks.into_iter().map(|chunk| chunk.text).collect();
+        let vectors: Vec<String> = embeddings.iter().map(|e| to_vector(e)).collect();
+
+        let mut transaction = pool.begin().await?;
+        sqlx::query(&format!(
+            "INSERT INTO {} (uri, language, hash, directories) VALUES ($1, $2, $3, $4) \
+             ON CONFLICT (uri) DO UPDATE SET language = EXCLUDED.language, \
+             hash = EXCLUDED.hash, directories = EXCLUDED.directories",
+            self.files_table
+        ))
+        .bind(uri)
+        .bind(language)
+        .bind(hash as i64)
+        .bind(get_directories(uri))
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query(&format!("DELETE FROM {} WHERE uri = $1", self.chunks_table))
+            .bind(uri)
+            .execute(&mut *transaction)
+            .await?;
+        // All the chunks in one statement
+        sqlx::query(&format!(
+            "INSERT INTO {} (uri, text, symbols, embedding) \
+             SELECT $1, t.text, t.symbols, t.embedding::vector \
+             FROM UNNEST($2::text[], $3::text[], $4::text[]) AS t (text, symbols, embedding)",
+            self.chunks_table
+        ))
+        .bind(uri)
+        .bind(texts)
+        .bind(symbols)
+        .bind(vectors)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, uri: &str) -> anyhow::Result<()> {
+        // Removes its chunks too
+        sqlx::query(&format!("DELETE FROM {} WHERE uri = $1", self.files_table))
+            .bind(uri)
+            .execute(self.get_pool().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_embedded(&self, uri: &str) -> anyhow::Result<Option<EmbeddedFile>> {
+        let pool = self.get_pool().await?;
+        if !self.has_chunks_table(pool).await? {
+            return Ok(None);
+        }
+        let hash: Option<i64> = sqlx::query_scalar(&format!(
+            "SELECT hash FROM {} WHERE uri = $1",
+            self.files_table
+        ))
+        .bind(uri)
+        .fetch_optional(pool)
+        .await?;
+        let Some(hash) = hash else {
+            return Ok(None);
+        };
+        let rows = sqlx::query(&format!(
+            "SELECT text, embedding::text AS embedding FROM {} WHERE uri = $1",
+            self.chunks_table
+        ))
+        .bind(uri)
+        .fetch_all(pool)
+        .await?;
+        let mut embeddings = std::collections::HashMap::new();
+        for row in rows {
+            let text: String = row.try_get("text")?;
+            let embedding: String = row.try_get("embedding")?;
+            embeddings.insert(xxh3_64(text.as_bytes()), from_vector(&embedding)?);
+        }
+        Ok(Some(EmbeddedFile {
+            hash: Some(hash as u64),
+            embeddings,
+        }))
+    }
+
+    async fn search(
+        &self,
+        embedding: Vec<f32>,
+        filter: SearchFilter<'_>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let pool = self.get_pool().await?;
+        if !self.has_chunks_table(pool).await? {
+            return Ok(vec![]);
+        }
+        // <=> is the cosine distance
+        let rows = sqlx::query(&format!(
+            "SELECT c.uri, c.text, c.symbols FROM {} c JOIN {} f ON f.uri = c.uri \
+             WHERE {FILTER_CONDITIONS} ORDER BY c.embedding <=> $1::vector LIMIT $5",
+            self.chunks_table, self.files_table
+        ))
+        .bind(to_vector(&embedding))
+        .bind(filter.language)
+        .bind(filter.directories.to_vec())
+        .bind(filter.workspace_folder)
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+        get_results(rows)
+    }
+
+    // Includes the vector index
+    async fn get_size(&self) -> anyhow::Result<Option<u64>> {
+        let pool = self.get_pool().await?;
+        let tables = if self.has_chunks_table(pool).await? {
+            vec![self.files_table.clone(), self.chunks_table.clone()]
+        } else {
+            vec![self.files_table.clone()]
+        };
+        let size: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(SUM(pg_total_relation_size(t::regclass)), 0)::bigint \
+             FROM UNNEST($1::text[]) AS t",
+        )
+        .bind(tables)
+        .fetch_one(pool)
+        .await?;
+        Ok(Some(size as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pgvector_converts_vectors() -> anyhow::Result<()> {
+        let embedding = vec![1.0, -0.5, 0.000125];
+        assert_eq!(to_vector(&embedding), "[1,-0.5,0.000125]");
+        assert_eq!(from_vector(&to_vector(&embedding))?, embedding);
+        assert_eq!(from_vector("[1, 2.5]")?, vec![1.0, 2.5]);
+        assert!(from_vector("[]")?.is_empty());
+        assert!(from_vector("[1,a]").is_err());
+
+        let configuration: config::Pgvector = serde_json::from_value(serde_json::json!({
+            "database_url": "postgres://localhost/lsp_ai",
+            "embedding_model": {
+                "type": "ollama",
+                "model": "nomic-embed-text"
+            }
+        }))?;
+        let store = Pgvector::new(&configuration)?;
+        assert_eq!(store.database_url, "postgres://localhost/lsp_ai");
+        assert_eq!(store.max_connections, 5);
+        assert!(store.files_table.starts_with("lsp_ai_files_"));
+        Ok(())
+    }
+}
@@ -1,22 +1,28 @@
 use std::{
-    sync::mpsc::{self, Sender},
+    sync::{
+        mpsc::{self, Sender},
+        Arc,
+    },
     time::Duration,
 };
 
 use anyhow::Context;
-use lsp_types::TextDocumentPositionParams;
-use pgml::{Collection, Pipeline};
+use lsp_types::{Range, TextDocumentPositionParams, Url};
+use pgml::{types::Json, Collection, Pipeline};
 use serde_json::{json, Value};
 use tokio::time;
-use tracing::instrument;
+use tracing::{info_span, instrument, Instrument};
 
 use crate::{
     config::{self, Config},
+    splitters::Splitter,
+    tokenizer::Tokenizer,
     utils::tokens_to_estimated_characters,
 };
 
 use super::{
-    file_store::FileStore, ContextAndCodePrompt, MemoryBackend, MemoryRunParams, Prompt, PromptType,
+    file_store::FileStore, format_chunk, ContextAndCodePrompt, MemoryBackend, MemoryRunParams,
+    Prompt, PromptType,
 };
 
 pub struct PostgresML {
@@ -26,6 +32,54 @@ pub struct PostgresML {
     pipeline: Pipeline,
     debounce_tx: Sender<String>,
     added_pipeline: bool,
+    splitter: Arc<dyn Splitter + Send + Sync>,
+}
+
+// Each chunk is stored as its own document so the splitter decides the chunk boundaries
+fn split_into_documents(splitter: &dyn Splitter, uri: &str, text: &str) -> Vec<Json> {
+    splitter
+        .split(uri, text)
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            json!({
+                "id": format!("{uri}#{i}"),
+                "uri": uri,
+                "text": chunk.text,
+                "start_byte": chunk.range.start,
+                "end_byte": chunk.range.end,
+                "symbols": chunk.symbols
+            })
+            .into()
+        })
+        .collect()
+}
+
+// Replaces the chunks of the file at `uri`
+async fn upsert_file(
+    collection: &mut Collection,
+    splitter: &dyn Splitter,
+    uri: &str,
+    text: &str,
+) -> anyhow::Result<()> {
+    collection
+        .delete_documents(json!({ "uri": { "$eq": uri } }).into())
+        .await?;
+    collection
+        .upsert_documents(split_into_documents(splitter, uri, text), None)
+        .await
+}
+
+fn format_result(result: &Json) -> anyhow::Result<String> {
+    let chunk = result["chunk"]
+        .as_str()
+        .context("PGML - Error getting chunk from vector search")?;
+    let document = &result["document"];
+    let symbols: Vec<&str> = document["symbols"]
+        .as_array()
+        .map(|symbols| symbols.iter().filter_map(|s| s.as_str()).collect())
+        .unwrap_or_default();
+    Ok(format_chunk(document["uri"].as_str(), &symbols, chunk))
 }
 
 impl PostgresML {
@@ -41,20 +95,17 @@ impl PostgresML {
         };
         // TODO: Think on the naming of the collection
         // Maybe filter on metadata or I'm not sure
-        let collection = Collection::new("test-lsp-ai-3", Some(database_url))?;
+        let collection = Collection::new("test-lsp-ai-4", Some(database_url))?;
+        let splitter: Arc<dyn Splitter + Send + Sync> = Arc::from(
+            Box::<dyn Splitter + Send + Sync>::from(postgresml_config.splitter),
+        );
         // TODO: Review the pipeline
+        // The documents are already split so each is embedded whole
         let pipeline = Pipeline::new(
             "v1",
             Some(
                 json!({
                     "text": {
-                        "splitter": {
-                            "model": "recursive_character",
-                            "parameters": {
-                                "chunk_size": 1500,
-                                "chunk_overlap": 40
-                            }
-                        },
                         "semantic_search": {
                             "model": "intfloat/e5-small",
                         }
@@ -69,6 +120,7 @@ impl PostgresML {
             .enable_all()
             .build()?;
         let mut task_collection = collection.clone();
+        let task_splitter = splitter.clone();
         let (debounce_tx, debounce_rx) = mpsc::channel::<String>();
         runtime.spawn(async move {
             let duration = Duration::from_millis(500);
@@ -86,22 +138,13 @@ impl PostgresML {
                     if file_paths.is_empty() {
                         continue;
                     }
-                    let documents = file_paths
-                        .into_iter()
-                        .map(|path| {
-                            let text = std::fs::read_to_string(&path)
-                                .unwrap_or_else(|_| panic!("Error reading path: {}", path));
-                            json!({
-                                "id": path,
-                                "text": text
-                            })
-                            .into()
-                        })
-                        .collect();
-                    task_collection
-                        .upsert_documents(documents, None)
-                        .await
-                        .expect("PGML - Error adding pipeline to collection");
+                    for path in file_paths {
+                        let text = std::fs::read_to_string(&path)
+                            .unwrap_or_else(|_| panic!("Error reading path: {}", path));
+                        upsert_file(&mut task_collection, &*task_splitter, &path, &text)
+                            .await
+                            .expect("PGML - Error upserting documents");
+                    }
                     file_paths = Vec::new();
                 }
             }
@@ -113,12 +156,28 @@ impl PostgresML {
             pipeline,
             debounce_tx,
             added_pipeline: false,
+            splitter,
         })
     }
 }
 
 #[async_trait::async_trait]
 impl MemoryBackend for PostgresML {
+    #[instrument(skip(self))]
+    fn get_opened_text_documents(&self) -> Vec<lsp_types::TextDocumentItem> {
+        self.file_store.get_opened_text_documents()
+    }
+
+    #[instrument(skip(self))]
+    fn get_language_id(&self, uri: &str) -> Option<String> {
+        self.file_store.get_language_id(uri)
+    }
+
+    #[instrument(skip(self))]
+    fn get_text(&self, uri: &str, range: Option<&Range>) -> anyhow::Result<String> {
+        self.file_store.get_text(uri, range)
+    }
+
     #[instrument(skip(self))]
     async fn get_filter_text(
         &self,
@@ -127,12 +186,13 @@ impl MemoryBackend for PostgresML {
         self.file_store.get_filter_text(position).await
     }
 
-    #[instrument(skip(self))]
+    #[instrument(skip(self, tokenizer))]
     async fn build_prompt(
         &self,
         position: &TextDocumentPositionParams,
         prompt_type: PromptType,
         params: Value,
+        tokenizer: &(dyn Tokenizer + Send + Sync),
     ) -> anyhow::Result<Prompt> {
         let params: MemoryRunParams = serde_json::from_value(params)?;
         let query = self
@@ -154,22 +214,19 @@ impl MemoryBackend for PostgresML {
                 .into(),
                 &self.pipeline,
             )
+            .instrument(info_span!("memory_lookup"))
             .await?;
         let context = res
-            .into_iter()
-            .map(|c| {
-                c["chunk"]
-                    .as_str()
-                    .map(|t| t.to_owned())
-                    .context("PGML - Error getting chunk from vector search")
-            })
+            .iter()
+            .map(format_result)
             .collect::<anyhow::Result<Vec<String>>>()?
             .join("\n\n");
         let mut file_store_params = params.clone();
         file_store_params.max_context_length = 512;
         let code = self
             .file_store
-            .build_code(position, prompt_type, file_store_params)?;
+            .build_code(position, prompt_type, file_store_params, tokenizer)
+            .await?;
         let code: ContextAndCodePrompt = code.try_into()?;
         let code = code.code;
         let max_characters = tokens_to_estimated_characters(params.max_context_length);
@@ -198,15 +255,7 @@ impl MemoryBackend for PostgresML {
                 .await
                 .expect("PGML - Error adding pipeline to collection");
         }
-        task_collection
-            .upsert_documents(
-                vec![json!({
-                    "id": path,
-                    "text": text
-                })
-                .into()],
-                None,
-            )
+        upsert_file(&mut task_collection, &*self.splitter, &path, &text)
             .await
             .expect("PGML - Error upserting documents");
         self.file_store.opened_text_document(params).await
@@ -227,27 +276,16 @@ impl MemoryBackend for PostgresML {
         let mut task_collection = self.collection.clone();
         let task_params = params.clone();
         for file in task_params.files {
+            let old_path = Url::parse(&file.old_uri)?.path().to_owned();
+            let new_path = Url::parse(&file.new_uri)?.path().to_owned();
             task_collection
-                .delete_documents(
-                    json!({
-                        "id": file.old_uri
-                    })
-                    .into(),
-                )
+                .delete_documents(json!({ "uri": { "$eq": old_path } }).into())
                 .await
                 .expect("PGML - Error deleting file");
-            let text = std::fs::read_to_string(&file.new_uri).expect("PGML - Error reading file");
-            task_collection
-                .upsert_documents(
-                    vec![json!({
-                        "id": file.new_uri,
-                        "text": text
-                    })
-                    .into()],
-                    None,
-                )
+            let text = std::fs::read_to_string(&new_path).expect("PGML - Error reading file");
+            upsert_file(&mut task_collection, &*self.splitter, &new_path, &text)
                 .await
-                .expect("PGML - Error adding pipeline to collection");
+                .expect("PGML - Error upserting documents");
         }
         self.file_store.renamed_files(params).await
     }
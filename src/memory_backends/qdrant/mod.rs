@@ -0,0 +1,222 @@
+use reqwest::Method;
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{config, http_client::get_client, splitters::Chunk};
+
+use super::vector_memory::{get_directories, SearchFilter, SearchResult, VectorStore};
+
+// Stores the chunks of the indexed files in a Qdrant collection
+pub struct Qdrant {
+    client: reqwest::Client,
+    configuration: config::Qdrant,
+    // The collection is created once the size of the embeddings is known
+    collection_ready: OnceCell<()>,
+}
+
+impl Qdrant {
+    pub fn new(configuration: config::Qdrant) -> Self {
+        Self {
+            client: get_client(),
+            configuration,
+            collection_ready: OnceCell::new(),
+        }
+    }
+
+    fn get_api_key(&self) -> anyhow::Result<Option<String>> {
+        if let Some(env_var_name) = &self.configuration.api_key_env_var_name {
+            Ok(Some(std::env::var(env_var_name)?))
+        } else {
+            Ok(self.configuration.api_key.clone())
+        }
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> anyhow::Result<Value> {
+        let mut request = self.client.request(
+            method,
+            format!(
+                "{}/collections/{}{path}",
+                self.configuration.url.trim_end_matches('/'),
+                self.configuration.collection_name
+            ),
+        );
+        if let Some(api_key) = self.get_api_key()? {
+            request = request.header("api-key", api_key);
+        }
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let res = request.send().await?;
+        let status = res.status();
+        let mut res: Value = res.json().await?;
+        if !status.is_success() {
+            anyhow::bail!("Qdrant - {status}: {}", res["status"])
+        }
+        Ok(res["result"].take())
+    }
+
+    // Whether the collection exists, remembering once it does
+    async fn collection_exists(&self) -> anyhow::Result<bool> {
+        if self.collection_ready.initialized() {
+            return Ok(true);
+        }
+        let res = self.request(Method::GET, "/exists", None).await?;
+        let exists = res["exists"].as_bool() == Some(true);
+        if exists {
+            let _ = self.collection_ready.set(());
+        }
+        Ok(exists)
+    }
+
+    async fn ensure_collection(&self, vector_size: usize) -> anyhow::Result<()> {
+        if self.collection_exists().await? {
+            return Ok(());
+        }
+        self.collection_ready
+            .get_or_try_init(|| async {
+                self.request(
+                    Method::PUT,
+                    "",
+                    Some(json!({
+                        "vectors": {
+                            "size": vector_size,
+                            "distance": "Cosine"
+                        }
+                    })),
+                )
+                .await?;
+                // Index the fields searches are filtered by
+                for field_name in ["uri", "language", "directories"] {
+                    self.request(
+                        Method::PUT,
+                        "/index?wait=true",
+                        Some(json!({
+                            "field_name": field_name,
+                            "field_schema": "keyword"
+                        })),
+                    )
+                    .await?;
+                }
+                anyhow::Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl VectorStore for Qdrant {
+    async fn upsert(
+        &self,
+        uri: &str,
+        language: Option<&str>,
+        hash: u64,
+        chunks: Vec<Chunk>,
+        embeddings: Vec<Vec<f32>>,
+    ) -> anyhow::Result<()> {
+        let Some(vector_size) = embeddings.first().map(|e| e.len()) else {
+            return self.delete(uri).await;
+        };
+        self.ensure_collection(vector_size).await?;
+        self.delete(uri).await?;
+
+        let directories = get_directories(uri);
+        let points: Vec<Value> = chunks
+            .into_iter()
+            .zip(embeddings)
+            .enumerate()
+            .map(|(i, (chunk, vector))| {
+                json!({
+                    "id": xxh3_64(format!("{uri}#{i}").as_bytes()),
+                    "vector": vector,
+                    "payload": {
+                        "uri": uri,
+                        "language": language,
+                        "file_hash": hash,
+                        "directories": directories,
+                        "text": chunk.text,
+                        "start_byte": chunk.range.start,
+                        "end_byte": chunk.range.end,
+                        "symbols": chunk.symbols
+                    }
+                })
+            })
+            .collect();
+        self.request(
+            Method::PUT,
+            "/points?wait=true",
+            Some(json!({ "points": points })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, uri: &str) -> anyhow::Result<()> {
+        if !self.collection_exists().await? {
+            return Ok(());
+        }
+        self.request(
+            Method::POST,
+            "/points/delete?wait=true",
+            Some(json!({
+                "filter": {
+                    "must": [{ "key": "uri", "match": { "value": uri } }]
+                }
+            })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        embedding: Vec<f32>,
+        filter: SearchFilter<'_>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        self.ensure_collection(embedding.len()).await?;
+
+        let mut must = vec![];
+        if let Some(language) = filter.language {
+            must.push(json!({ "key": "language", "match": { "value": language } }));
+        }
+        if !filter.directories.is_empty() {
+            must.push(json!({ "key": "directories", "match": { "any": filter.directories } }));
+        }
+        if let Some(workspace_folder) = filter.workspace_folder {
+            must.push(json!({ "key": "directories", "match": { "value": workspace_folder } }));
+        }
+        let res = self
+            .request(
+                Method::POST,
+                "/points/search",
+                Some(json!({
+                    "vector": embedding,
+                    "limit": limit,
+                    "with_payload": true,
+                    "filter": { "must": must }
+                })),
+            )
+            .await?;
+        let Value::Array(points) = res else {
+            anyhow::bail!("Qdrant - unexpected search response: {res}")
+        };
+        Ok(points
+            .into_iter()
+            .map(|mut point| {
+                let payload = &mut point["payload"];
+                SearchResult {
+                    uri: payload["uri"].as_str().unwrap_or_default().to_owned(),
+                    text: payload["text"].as_str().unwrap_or_default().to_owned(),
+                    symbols: serde_json::from_value(payload["symbols"].take()).unwrap_or_default(),
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+use ropey::Rope;
+
+// The number of edits remembered across all documents
+const CAPACITY: usize = 32;
+
+// The lines an edit changed, as they were before and after it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edit {
+    pub uri: String,
+    // The lines the edit covers now, zero based and inclusive
+    pub start_line: usize,
+    pub end_line: usize,
+    pub before: String,
+    pub after: String,
+}
+
+impl Edit {
+    // A unified diff hunk of the edit, without the lines it left as they were
+    pub fn to_hunk(&self) -> String {
+        let before: Vec<&str> = self.before.lines().collect();
+        let after: Vec<&str> = self.after.lines().collect();
+        let common_start = before
+            .iter()
+            .zip(&after)
+            .take_while(|(b, a)| b == a)
+            .count();
+        let common_end = before[common_start..]
+            .iter()
+            .rev()
+            .zip(after[common_start..].iter().rev())
+            .take_while(|(b, a)| b == a)
+            .count();
+        let mut hunk = format!(
+            "{} @@ line {}",
+            self.uri,
+            self.start_line + common_start + 1
+        );
+        for line in &before[common_start..before.len() - common_end] {
+            hunk.push_str("\n-");
+            hunk.push_str(line);
+        }
+        for line in &after[common_start..after.len() - common_end] {
+            hunk.push_str("\n+");
+            hunk.push_str(line);
+        }
+        hunk
+    }
+}
+
+// The lines `start..end` of the rope
+fn get_lines(rope: &Rope, start: usize, end: usize) -> String {
+    let end = end.min(rope.len_lines());
+    if start >= end {
+        return String::new();
+    }
+    rope.slice(rope.line_to_char(start)..rope.line_to_char(end))
+        .to_string()
+}
+
+// A rolling window of the latest edits, newest first. Changes next to the newest edit are merged
+// into it so typing out a line is one edit and not one per keystroke.
+#[derive(Default)]
+pub struct RecentEdits {
+    edits: Mutex<VecDeque<Edit>>,
+}
+
+impl RecentEdits {
+    // Records a change of the lines `start_line..=end_line` of `before` to `inserted_lines`
+    // lines in `after`
+    pub fn record(
+        &self,
+        uri: &str,
+        before: &Rope,
+        after: &Rope,
+        start_line: usize,
+        end_line: usize,
+        inserted_lines: usize,
+    ) {
+        let mut edits = self.edits.lock();
+        let moved = |line: usize| (line + inserted_lines).saturating_sub(end_line - start_line);
+        let (mut first, mut last) = (start_line, end_line);
+        let mut lines_before = get_lines(before, start_line, end_line + 1);
+        if let Some(newest) = edits.front().filter(|newest| {
+            newest.uri == uri
+                && start_line <= newest.end_line + 1
+                && end_line + 1 >= newest.start_line
+        }) {
+            first = first.min(newest.start_line);
+            last = last.max(newest.end_line);
+            lines_before = get_lines(before, first, newest.start_line)
+                + &newest.before
+                + &get_lines(before, newest.end_line + 1, last + 1);
+            edits.pop_front();
+        }
+        // Edits further down the document moved with the lines
+        for edit in edits
+            .iter_mut()
+            .filter(|edit| edit.uri == uri && edit.start_line > end_line)
+        {
+            edit.start_line = moved(edit.start_line);
+            edit.end_line = moved(edit.end_line);
+        }
+        let last = moved(last);
+        edits.push_front(Edit {
+            uri: uri.to_owned(),
+            start_line: first,
+            end_line: last,
+            before: lines_before,
+            after: get_lines(after, first, last + 1),
+        });
+        edits.truncate(CAPACITY);
+    }
+
+    // The newest `n` edits that still change something
+    pub fn get(&self, n: usize) -> Vec<Edit> {
+        self.edits
+            .lock()
+            .iter()
+            .filter(|edit| edit.before != edit.after)
+            .take(n)
+            .cloned()
+            .collect()
+    }
+
+    pub fn rename(&self, old_uri: &str, new_uri: &str) {
+        for edit in self
+            .edits
+            .lock()
+            .iter_mut()
+            .filter(|edit| edit.uri == old_uri)
+        {
+            edit.uri = new_uri.to_owned();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Replaces the chars `start..end` and records the change the way the file store does
+    fn change(edits: &RecentEdits, rope: &mut Rope, start: usize, end: usize, text: &str) {
+        let before = rope.clone();
+        rope.remove(start..end);
+        rope.insert(start, text);
+        edits.record(
+            "file:///test.rs",
+            &before,
+            rope,
+            before.char_to_line(start),
+            before.char_to_line(end),
+            text.matches('\n').count(),
+        );
+    }
+
+    #[test]
+    fn merges_nearby_changes() {
+        let edits = RecentEdits::default();
+        let mut rope = Rope::from_str("let a = 1;\nlet b = 2;\n\n\nlet c = 3;\n");
+        // Typed char by char
+        change(&edits, &mut rope, 4, 5, "x");
+        change(&edits, &mut rope, 5, 5, "y");
+        change(&edits, &mut rope, 11, 11, "\nlet d = 4;");
+        let recent = edits.get(5);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(
+            recent[0].to_hunk(),
+            "file:///test.rs @@ line 1\n-let a = 1;\n+let xy = 1;\n+let d = 4;"
+        );
+
+        // Far enough away to be an edit of its own
+        change(&edits, &mut rope, 44, 45, "5");
+        let recent = edits.get(5);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(
+            recent[0].to_hunk(),
+            "file:///test.rs @@ line 6\n-let c = 3;\n+let c = 5;"
+        );
+
+        // The edits further down move with the lines
+        change(&edits, &mut rope, 34, 34, "\n");
+        let recent = edits.get(5);
+        assert_eq!(recent[0].to_hunk(), "file:///test.rs @@ line 5\n+");
+        assert_eq!(recent[1].start_line, 6);
+        assert_eq!(recent[2].start_line, 0);
+    }
+}
@@ -0,0 +1,468 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Transaction};
+use tracing::info;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{config, splitters::Chunk};
+
+use super::vector_memory::{
+    get_directories, EmbeddedFile, SearchFilter, SearchResult, VectorStore,
+};
+
+// Each one moves the schema to the next version, the version of a database is its index in here
+// plus one. Only add to the end.
+const MIGRATIONS: &[&str] = &[r#"
+CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+CREATE TABLE files (uri TEXT PRIMARY KEY, language TEXT, hash INTEGER NOT NULL);
+CREATE TABLE directories (
+    uri TEXT NOT NULL REFERENCES files (uri) ON DELETE CASCADE,
+    directory TEXT NOT NULL
+);
+CREATE INDEX directories_directory ON directories (directory);
+CREATE INDEX directories_uri ON directories (uri);
+CREATE TABLE chunks (
+    id INTEGER PRIMARY KEY,
+    uri TEXT NOT NULL REFERENCES files (uri) ON DELETE CASCADE,
+    text TEXT NOT NULL,
+    symbols TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    norm REAL NOT NULL
+);
+CREATE INDEX chunks_uri ON chunks (uri);
+CREATE VIRTUAL TABLE chunks_fts USING fts5 (
+    text, content = 'chunks', content_rowid = 'id', tokenize = "unicode61 tokenchars '_'"
+);
+CREATE TRIGGER chunks_insert AFTER INSERT ON chunks BEGIN
+    INSERT INTO chunks_fts (rowid, text) VALUES (new.id, new.text);
+END;
+CREATE TRIGGER chunks_delete AFTER DELETE ON chunks BEGIN
+    INSERT INTO chunks_fts (chunks_fts, rowid, text) VALUES ('delete', old.id, old.text);
+END;
+"#];
+
+// The most terms of the query searched for in the full-text index
+const MAX_QUERY_TERMS: usize = 64;
+
+// A vector and full-text index in a SQLite database. Searches use their own connection so they
+// are not blocked by writes.
+pub struct Sqlite {
+    path: PathBuf,
+    writer: Mutex<Connection>,
+    reader: Mutex<Connection>,
+}
+
+fn get_path(configuration: &config::Sqlite, root_uri: Option<&str>) -> anyhow::Result<PathBuf> {
+    if let Some(database_path) = &configuration.database_path {
+        return Ok(PathBuf::from(database_path));
+    }
+    let cache_dir = directories::ProjectDirs::from("", "", "lsp-ai")
+        .context("unable to find the cache directory")?
+        .cache_dir()
+        .join("sqlite");
+    // Each workspace gets its own database
+    let name = xxh3_64(root_uri.unwrap_or("global").as_bytes());
+    Ok(cache_dir.join(format!("{name:x}.sqlite3")))
+}
+
+fn open(path: &Path) -> anyhow::Result<Connection> {
+    let connection = Connection::open(path)?;
+    connection.busy_timeout(std::time::Duration::from_secs(5))?;
+    connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    connection.pragma_update(None, "foreign_keys", true)?;
+    Ok(connection)
+}
+
+fn migrate(connection: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version >= MIGRATIONS.len() {
+        return Ok(());
+    }
+    let transaction = connection.transaction()?;
+    for migration in &MIGRATIONS[version..] {
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    transaction.commit()?;
+    info!(
+        "migrated the sqlite database to version {}",
+        MIGRATIONS.len()
+    );
+    Ok(())
+}
+
+// The embeddings are not comparable with ones from a different model
+fn set_model(connection: &mut Connection, model: &str) -> anyhow::Result<()> {
+    let transaction = connection.transaction()?;
+    let current: Option<String> = transaction
+        .query_row("SELECT value FROM meta WHERE key = 'model'", [], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    if current.as_deref() != Some(model) {
+        transaction.execute("DELETE FROM files", [])?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('model', ?1)",
+            [model],
+        )?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+fn to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+fn norm(embedding: &[f32]) -> f32 {
+    embedding.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+// The conditions on the chunks `c` of the files `f` the filter keeps, with their parameters
+fn get_conditions(filter: &SearchFilter<'_>) -> (String, Vec<String>) {
+    let mut conditions = vec!["1".to_string()];
+    let mut values = vec![];
+    if let Some(language) = filter.language {
+        conditions.push("f.language = ?".to_string());
+        values.push(language.to_owned());
+    }
+    if !filter.directories.is_empty() {
+        let placeholders = vec!["?"; filter.directories.len()].join(", ");
+        conditions.push(format!(
+            "c.uri IN (SELECT uri FROM directories WHERE directory IN ({placeholders}))"
+        ));
+        values.extend(filter.directories.iter().cloned());
+    }
+    if let Some(workspace_folder) = filter.workspace_folder {
+        conditions.push("c.uri IN (SELECT uri FROM directories WHERE directory = ?)".to_string());
+        values.push(workspace_folder.to_owned());
+    }
+    (conditions.join(" AND "), values)
+}
+
+// Matches chunks with any of the words of the query
+fn get_match_query(query: &str) -> Option<String> {
+    let mut terms: Vec<&str> = vec![];
+    for word in query
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+    {
+        if !terms.contains(&word) {
+            terms.push(word);
+        }
+        if terms.len() == MAX_QUERY_TERMS {
+            break;
+        }
+    }
+    (!terms.is_empty()).then(|| {
+        terms
+            .iter()
+            .map(|term| format!("\"{term}\""))
+            .collect::<Vec<_>>()
+            .join(" OR ")
+    })
+}
+
+fn insert_file(
+    transaction: &Transaction,
+    uri: &str,
+    language: Option<&str>,
+    hash: u64,
+    chunks: Vec<Chunk>,
+    embeddings: Vec<Vec<f32>>,
+) -> anyhow::Result<()> {
+    // Removes its directories and chunks too
+    transaction.execute("DELETE FROM files WHERE uri = ?1", [uri])?;
+    transaction.execute(
+        "INSERT INTO files (uri, language, hash) VALUES (?1, ?2, ?3)",
+        params![uri, language, hash as i64],
+    )?;
+    let mut statement =
+        transaction.prepare_cached("INSERT INTO directories (uri, directory) VALUES (?1, ?2)")?;
+    for directory in get_directories(uri) {
+        statement.execute(params![uri, directory])?;
+    }
+    let mut statement = transaction.prepare_cached(
+        "INSERT INTO chunks (uri, text, symbols, embedding, norm) VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+    for (chunk, embedding) in chunks.into_iter().zip(embeddings) {
+        statement.execute(params![
+            uri,
+            chunk.text,
+            serde_json::to_string(&chunk.symbols)?,
+            to_blob(&embedding),
+            norm(&embedding)
+        ])?;
+    }
+    Ok(())
+}
+
+impl Sqlite {
+    pub fn new(configuration: &config::Sqlite, root_uri: Option<&str>) -> anyhow::Result<Self> {
+        let path = get_path(configuration, root_uri)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut writer = open(&path)?;
+        migrate(&mut writer)?;
+        set_model(&mut writer, configuration.embedding_model.name())?;
+        let reader = open(&path)?;
+        Ok(Self {
+            path,
+            writer: Mutex::new(writer),
+            reader: Mutex::new(reader),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl VectorStore for Sqlite {
+    async fn upsert(
+        &self,
+        uri: &str,
+        language: Option<&str>,
+        hash: u64,
+        chunks: Vec<Chunk>,
+        embeddings: Vec<Vec<f32>>,
+    ) -> anyhow::Result<()> {
+        let mut writer = self.writer.lock();
+        let transaction = writer.transaction()?;
+        insert_file(&transaction, uri, language, hash, chunks, embeddings)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    async fn delete(&self, uri: &str) -> anyhow::Result<()> {
+        self.writer
+            .lock()
+            .execute("DELETE FROM files WHERE uri = ?1", [uri])?;
+        Ok(())
+    }
+
+    async fn get_embedded(&self, uri: &str) -> anyhow::Result<Option<EmbeddedFile>> {
+        let reader = self.reader.lock();
+        let hash: Option<i64> = reader
+            .query_row("SELECT hash FROM files WHERE uri = ?1", [uri], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let Some(hash) = hash else {
+            return Ok(None);
+        };
+        let mut statement =
+            reader.prepare_cached("SELECT text, embedding FROM chunks WHERE uri = ?1")?;
+        let embeddings = statement
+            .query_map([uri], |row| {
+                let text: String = row.get(0)?;
+                let embedding: Vec<u8> = row.get(1)?;
+                Ok((xxh3_64(text.as_bytes()), from_blob(&embedding)))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(Some(EmbeddedFile {
+            hash: Some(hash as u64),
+            embeddings,
+        }))
+    }
+
+    async fn search(
+        &self,
+        embedding: Vec<f32>,
+        filter: SearchFilter<'_>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let query_norm = norm(&embedding);
+        let (conditions, values) = get_conditions(&filter);
+        let reader = self.reader.lock();
+        let mut statement = reader.prepare(&format!(
+            "SELECT c.uri, c.text, c.symbols, c.embedding, c.norm FROM chunks c \
+             JOIN files f ON f.uri = c.uri WHERE {conditions}"
+        ))?;
+        let mut rows = statement.query(params_from_iter(values))?;
+        let mut scored: Vec<(f32, SearchResult)> = vec![];
+        while let Some(row) = rows.next()? {
+            let blob: &[u8] = row.get_ref(3)?.as_blob()?;
+            let entry = from_blob(blob);
+            if entry.len() != embedding.len() {
+                continue;
+            }
+            let dot: f32 = entry.iter().zip(&embedding).map(|(a, b)| a * b).sum();
+            let entry_norm: f32 = row.get(4)?;
+            let score = dot / (entry_norm * query_norm).max(f32::EPSILON);
+            // Only the best are kept so every chunk is not held in memory
+            if scored.len() == limit && scored.last().is_some_and(|(s, _)| *s >= score) {
+                continue;
+            }
+            let symbols: String = row.get(2)?;
+            scored.push((
+                score,
+                SearchResult {
+                    uri: row.get(0)?,
+                    text: row.get(1)?,
+                    symbols: serde_json::from_str(&symbols)?,
+                },
+            ));
+            scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+            scored.truncate(limit);
+        }
+        Ok(scored.into_iter().map(|(_, result)| result).collect())
+    }
+
+    fn has_keyword_search(&self) -> bool {
+        true
+    }
+
+    // The database and its write-ahead log
+    async fn get_size(&self) -> anyhow::Result<Option<u64>> {
+        let mut wal_path = self.path.clone().into_os_string();
+        wal_path.push("-wal");
+        let size = [self.path.as_os_str(), wal_path.as_os_str()]
+            .into_iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        Ok(Some(size))
+    }
+
+    async fn keyword_search(
+        &self,
+        query: &str,
+        filter: &SearchFilter<'_>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let Some(match_query) = get_match_query(query) else {
+            return Ok(vec![]);
+        };
+        let (conditions, mut values) = get_conditions(filter);
+        values.insert(0, match_query);
+        let reader = self.reader.lock();
+        let mut statement = reader.prepare(&format!(
+            "SELECT c.uri, c.text, c.symbols FROM chunks_fts \
+             JOIN chunks c ON c.id = chunks_fts.rowid JOIN files f ON f.uri = c.uri \
+             WHERE chunks_fts MATCH ? AND {conditions} ORDER BY bm25(chunks_fts) LIMIT {limit}"
+        ))?;
+        let results = statement
+            .query_map(params_from_iter(values), |row| {
+                let symbols: String = row.get(2)?;
+                Ok(SearchResult {
+                    uri: row.get(0)?,
+                    text: row.get(1)?,
+                    symbols: serde_json::from_str(&symbols).unwrap_or_default(),
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(text: &str) -> Chunk {
+        Chunk {
+            text: text.to_owned(),
+            range: 0..text.len(),
+            symbols: vec!["main".to_owned()],
+        }
+    }
+
+    fn test_database(database_path: &Path, model: &str) -> Sqlite {
+        let configuration: config::Sqlite = serde_json::from_value(serde_json::json!({
+            "embedding_model": {
+                "type": "open_ai",
+                "endpoint": "http://localhost:8080/v1/embeddings",
+                "model": model
+            },
+            "database_path": database_path
+        }))
+        .unwrap();
+        Sqlite::new(&configuration, None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sqlite_searches_and_persists() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("lsp-ai-sqlite-{:x}", rand::random::<u64>()));
+        let database_path = dir.join("index.sqlite3");
+        let database = test_database(&database_path, "test-model");
+        database
+            .upsert(
+                "file:///project/src/a.rs",
+                Some("rust"),
+                1,
+                vec![
+                    chunk("fn get_file_contents() {}"),
+                    chunk("fn open_file() {}"),
+                ],
+                vec![vec![1.0, 0.0], vec![0.0, 1.0]],
+            )
+            .await?;
+        database
+            .upsert(
+                "file:///project/tests/b.py",
+                Some("python"),
+                2,
+                vec![chunk("def get_contents(): pass")],
+                vec![vec![0.9, 0.1]],
+            )
+            .await?;
+
+        let filter = SearchFilter {
+            language: None,
+            directories: &[],
+            workspace_folder: None,
+        };
+        let results = database.search(vec![1.0, 0.0], filter, 2).await?;
+        let texts: Vec<&str> = results.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec!["fn get_file_contents() {}", "def get_contents(): pass"]
+        );
+        assert_eq!(results[0].symbols, vec!["main"]);
+
+        let filter = SearchFilter {
+            language: None,
+            directories: &[],
+            workspace_folder: Some("/project/tests"),
+        };
+        let results = database.keyword_search("get_contents", &filter, 3).await?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].uri, "file:///project/tests/b.py");
+
+        database.delete("file:///project/tests/b.py").await?;
+        assert!(database
+            .keyword_search("get_contents", &filter, 3)
+            .await?
+            .is_empty());
+        drop(database);
+
+        let database = test_database(&database_path, "test-model");
+        let embedded = database
+            .get_embedded("file:///project/src/a.rs")
+            .await?
+            .unwrap();
+        assert_eq!(embedded.hash, Some(1));
+        assert_eq!(
+            embedded.embeddings[&xxh3_64(b"fn open_file() {}")],
+            vec![0.0, 1.0]
+        );
+        assert!(database.get_size().await?.is_some_and(|size| size > 0));
+        drop(database);
+
+        // Switching models drops the embeddings
+        let database = test_database(&database_path, "other-model");
+        assert!(database
+            .get_embedded("file:///project/src/a.rs")
+            .await?
+            .is_none());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+};
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{config, splitters::Chunk};
+
+use super::vector_memory::{
+    get_directories, EmbeddedFile, SearchFilter, SearchResult, VectorStore,
+};
+
+// Bump when the format of the persisted index changes
+const VERSION: usize = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    text: String,
+    symbols: Vec<String>,
+    embedding: Vec<f32>,
+    // The norm of the embedding so searches only compute dot products
+    norm: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct File {
+    language: Option<String>,
+    // The hash of the file's text, missing in indexes from older versions
+    #[serde(default)]
+    hash: Option<u64>,
+    directories: Vec<String>,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Files {
+    version: usize,
+    // The embedding model the index was built with
+    model: String,
+    files: HashMap<String, File>,
+}
+
+struct Inner {
+    path: PathBuf,
+    files: RwLock<Files>,
+    dirty: AtomicBool,
+}
+
+// Clients attached to the same server share the index of a workspace instead of each loading
+// and saving their own copy
+static OPEN: Lazy<Mutex<HashMap<PathBuf, Weak<Inner>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// A flat vector index kept in memory and persisted to disk so it survives restarts
+pub struct VectorIndex {
+    inner: Arc<Inner>,
+}
+
+fn get_path(
+    configuration: &config::VectorIndex,
+    root_uri: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let cache_dir = match &configuration.cache_dir {
+        Some(cache_dir) => PathBuf::from(cache_dir),
+        None => directories::ProjectDirs::from("", "", "lsp-ai")
+            .context("unable to find the cache directory")?
+            .cache_dir()
+            .join("vector_index"),
+    };
+    // Each workspace gets its own index
+    let name = xxh3_64(root_uri.unwrap_or("global").as_bytes());
+    Ok(cache_dir.join(format!("{name:x}.json")))
+}
+
+fn load(path: &Path, model: &str) -> Option<Files> {
+    let contents = std::fs::read(path).ok()?;
+    match serde_json::from_slice::<Files>(&contents) {
+        Ok(files) if files.version == VERSION && files.model == model => {
+            info!("loaded the vector index from {}", path.display());
+            Some(files)
+        }
+        // The embeddings are not comparable with ones from a different model
+        Ok(_) => None,
+        Err(e) => {
+            error!(
+                "error loading the vector index from {}: {e}",
+                path.display()
+            );
+            None
+        }
+    }
+}
+
+fn norm(embedding: &[f32]) -> f32 {
+    embedding.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+impl VectorIndex {
+    pub fn new(
+        configuration: &config::VectorIndex,
+        root_uri: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let path = get_path(configuration, root_uri)?;
+        let model = configuration.embedding_model.name().to_owned();
+        let mut open = OPEN.lock();
+        open.retain(|_, inner| inner.strong_count() > 0);
+        if let Some(inner) = open.get(&path).and_then(Weak::upgrade) {
+            // An index built with another model is replaced, its embeddings are not comparable
+            if inner.files.read().model == model {
+                return Ok(Self { inner });
+            }
+        }
+        let files = load(&path, &model).unwrap_or_else(|| Files {
+            version: VERSION,
+            model,
+            files: HashMap::new(),
+        });
+        let inner = Arc::new(Inner {
+            path: path.clone(),
+            files: RwLock::new(files),
+            dirty: AtomicBool::new(false),
+        });
+        open.insert(path, Arc::downgrade(&inner));
+        Ok(Self { inner })
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let contents = serde_json::to_vec(&*self.inner.files.read())?;
+        let path = &self.inner.path;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Write to a temporary file first so a crash never leaves a partial index behind
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl VectorStore for VectorIndex {
+    async fn upsert(
+        &self,
+        uri: &str,
+        language: Option<&str>,
+        hash: u64,
+        chunks: Vec<Chunk>,
+        embeddings: Vec<Vec<f32>>,
+    ) -> anyhow::Result<()> {
+        let entries = chunks
+            .into_iter()
+            .zip(embeddings)
+            .map(|(chunk, embedding)| Entry {
+                text: chunk.text,
+                symbols: chunk.symbols,
+                norm: norm(&embedding),
+                embedding,
+            })
+            .collect();
+        self.inner.files.write().files.insert(
+            uri.to_owned(),
+            File {
+                language: language.map(str::to_owned),
+                hash: Some(hash),
+                directories: get_directories(uri),
+                entries,
+            },
+        );
+        self.inner.dirty.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn delete(&self, uri: &str) -> anyhow::Result<()> {
+        if self.inner.files.write().files.remove(uri).is_some() {
+            self.inner.dirty.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    async fn get_embedded(&self, uri: &str) -> anyhow::Result<Option<EmbeddedFile>> {
+        let files = self.inner.files.read();
+        Ok(files.files.get(uri).map(|file| EmbeddedFile {
+            hash: file.hash,
+            embeddings: file
+                .entries
+                .iter()
+                .map(|entry| (xxh3_64(entry.text.as_bytes()), entry.embedding.clone()))
+                .collect(),
+        }))
+    }
+
+    async fn search(
+        &self,
+        embedding: Vec<f32>,
+        filter: SearchFilter<'_>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let query_norm = norm(&embedding);
+        let files = self.inner.files.read();
+        let mut scored: Vec<(f32, &str, &Entry)> = files
+            .files
+            .iter()
+            .filter(|(_, file)| match filter.language {
+                Some(language) => file.language.as_deref() == Some(language),
+                None => true,
+            })
+            .filter(|(_, file)| {
+                filter.directories.is_empty()
+                    || file
+                        .directories
+                        .iter()
+                        .any(|d| filter.directories.contains(d))
+            })
+            .filter(|(_, file)| match filter.workspace_folder {
+                Some(workspace_folder) => file.directories.iter().any(|d| d == workspace_folder),
+                None => true,
+            })
+            .flat_map(|(uri, file)| file.entries.iter().map(move |entry| (uri, entry)))
+            .filter(|(_, entry)| entry.embedding.len() == embedding.len())
+            .map(|(uri, entry)| {
+                let dot: f32 = entry
+                    .embedding
+                    .iter()
+                    .zip(&embedding)
+                    .map(|(a, b)| a * b)
+                    .sum();
+                let score = dot / (entry.norm * query_norm).max(f32::EPSILON);
+                (score, uri.as_str(), entry)
+            })
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        Ok(scored
+            .into_iter()
+            .take(limit)
+            .map(|(_, uri, entry)| SearchResult {
+                uri: uri.to_owned(),
+                text: entry.text.clone(),
+                symbols: entry.symbols.clone(),
+            })
+            .collect())
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        if self.inner.dirty.swap(false, Ordering::Relaxed) {
+            if let Err(e) = self.save() {
+                self.inner.dirty.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // The size of the saved index, it is not saved until the first flush
+    async fn get_size(&self) -> anyhow::Result<Option<u64>> {
+        Ok(std::fs::metadata(&self.inner.path)
+            .ok()
+            .map(|metadata| metadata.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(text: &str) -> Chunk {
+        Chunk {
+            text: text.to_owned(),
+            range: 0..text.len(),
+            symbols: vec![],
+        }
+    }
+
+    fn test_index(cache_dir: &Path) -> VectorIndex {
+        let configuration: config::VectorIndex = serde_json::from_value(serde_json::json!({
+            "embedding_model": {
+                "type": "open_ai",
+                "endpoint": "http://localhost:8080/v1/embeddings",
+                "model": "test-model"
+            },
+            "cache_dir": cache_dir
+        }))
+        .unwrap();
+        VectorIndex::new(&configuration, Some("file:///home/user/project")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn vector_index_searches_and_persists() -> anyhow::Result<()> {
+        let cache_dir = std::env::temp_dir().join(format!("lsp-ai-test-{}", std::process::id()));
+        let index = test_index(&cache_dir);
+        index
+            .upsert(
+                "file:///home/user/project/src/a.rs",
+                Some("rust"),
+                1,
+                vec![chunk("a"), chunk("b")],
+                vec![vec![1.0, 0.0], vec![0.0, 1.0]],
+            )
+            .await?;
+        index
+            .upsert(
+                "file:///home/user/project/tests/c.py",
+                Some("python"),
+                2,
+                vec![chunk("c")],
+                vec![vec![0.9, 0.1]],
+            )
+            .await?;
+
+        let filter = SearchFilter {
+            language: None,
+            directories: &[],
+            workspace_folder: None,
+        };
+        let results = index.search(vec![1.0, 0.0], filter, 2).await?;
+        let texts: Vec<&str> = results.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, vec!["a", "c"]);
+
+        let directories = vec!["/home/user/project/tests".to_string()];
+        let filter = SearchFilter {
+            language: Some("rust"),
+            directories: &directories,
+            workspace_folder: None,
+        };
+        assert!(index.search(vec![1.0, 0.0], filter, 2).await?.is_empty());
+        let filter = SearchFilter {
+            language: None,
+            directories: &[],
+            workspace_folder: Some("/home/user/project/tests"),
+        };
+        let results = index.search(vec![1.0, 0.0], filter, 2).await?;
+        assert_eq!(results[0].text, "c");
+        assert_eq!(results.len(), 1);
+
+        // A second client shares the open index
+        let shared = test_index(&cache_dir);
+        assert!(Arc::ptr_eq(&index.inner, &shared.inner));
+        drop(shared);
+
+        index.flush().await?;
+        drop(index);
+        let index = test_index(&cache_dir);
+        let embedded = index
+            .get_embedded("file:///home/user/project/src/a.rs")
+            .await?
+            .unwrap();
+        assert_eq!(embedded.hash, Some(1));
+        assert_eq!(embedded.embeddings[&xxh3_64(b"b")], vec![0.0, 1.0]);
+        let results = index
+            .search(
+                vec![0.0, 1.0],
+                SearchFilter {
+                    language: Some("rust"),
+                    directories: &[],
+                    workspace_folder: None,
+                },
+                1,
+            )
+            .await?;
+        std::fs::remove_dir_all(&cache_dir)?;
+        assert_eq!(results[0].text, "b");
+        Ok(())
+    }
+}
@@ -0,0 +1,1335 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use lsp_types::{Position, Range, TextDocumentPositionParams, Url};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use parking_lot::Mutex;
+use serde_json::Value;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info_span, instrument, Instrument};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    config::{
+        self, Config, ContextPacking, Crawl, Docs, HybridSearch, Plugin, ValidEmbeddingModel,
+        ValidRerankModel, ValidSplitter,
+    },
+    custom_requests::{
+        memory_stats::{FileStatus, MemoryStatsResult},
+        search::{SearchMatch, WorkspaceSearchParams},
+    },
+    embedding_models::EmbeddingBackend,
+    plugins,
+    progress::ProgressReporter,
+    rerank_models::RerankModel,
+    splitters::{Chunk, MarkdownSplitter, Splitter},
+    tokenizer::Tokenizer,
+};
+
+use super::{
+    crawl, docs, file_store::FileStore, fit_prompt, format_chunk, keyword_index::KeywordIndex,
+    MemoryBackend, MemoryRunParams, Prompt, PromptType,
+};
+
+#[derive(Clone, Default)]
+pub struct SearchResult {
+    pub uri: String,
+    pub text: String,
+    pub symbols: Vec<String>,
+}
+
+pub struct SearchFilter<'a> {
+    // Only return chunks from files in this language
+    pub language: Option<&'a str>,
+    // Only return chunks from files under any of these directories
+    pub directories: &'a [String],
+    // Only return chunks from files under this workspace folder, a directory like the above
+    pub workspace_folder: Option<&'a str>,
+}
+
+// The embeddings of a file's chunks
+pub struct EmbeddedFile {
+    // The hash of the file's text, None when it is not known
+    pub hash: Option<u64>,
+    // By the hash of the chunk's text
+    pub embeddings: HashMap<u64, Vec<f32>>,
+}
+
+// Where the memory backends built on `VectorMemory` store their chunks and embeddings
+#[async_trait::async_trait]
+pub trait VectorStore {
+    // Replaces the chunks stored for the file at `uri`, `hash` is the hash of its text
+    async fn upsert(
+        &self,
+        uri: &str,
+        language: Option<&str>,
+        hash: u64,
+        chunks: Vec<Chunk>,
+        embeddings: Vec<Vec<f32>>,
+    ) -> anyhow::Result<()>;
+    async fn delete(&self, uri: &str) -> anyhow::Result<()>;
+    // What was stored for the file at `uri` in an earlier session, so files that did not change
+    // since are not embedded again
+    async fn get_embedded(&self, _uri: &str) -> anyhow::Result<Option<EmbeddedFile>> {
+        Ok(None)
+    }
+    async fn search(
+        &self,
+        embedding: Vec<f32>,
+        filter: SearchFilter<'_>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SearchResult>>;
+    // Stores with a full-text index search it for hybrid search instead of the in-memory keyword
+    // index
+    fn has_keyword_search(&self) -> bool {
+        false
+    }
+    async fn keyword_search(
+        &self,
+        _query: &str,
+        _filter: &SearchFilter<'_>,
+        _limit: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        Ok(vec![])
+    }
+    // Called once there are no more pending changes
+    async fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+    // The bytes the index takes up, None when the store does not know
+    async fn get_size(&self) -> anyhow::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+pub struct SearchParams {
+    pub limit: usize,
+    pub filter_by_language: bool,
+    pub paths: Vec<String>,
+    pub filter_by_workspace_folder: bool,
+    pub hybrid_search: Option<HybridSearch>,
+    pub rerank: Option<ValidRerankModel>,
+}
+
+// The directories containing the file so searches can be filtered by path
+pub fn get_directories(uri: &str) -> Vec<String> {
+    let Ok(url) = Url::parse(uri) else {
+        return vec![];
+    };
+    let path = url.path();
+    path.match_indices('/')
+        .map(|(i, _)| i)
+        .filter(|i| *i > 0)
+        .map(|i| path[..i].to_owned())
+        .collect()
+}
+
+// The path of the innermost workspace folder the file at `uri` is in, so searches can be scoped
+// to it like to the directories above
+pub fn get_workspace_folder(workspace_folders: &[String], uri: &str) -> Option<String> {
+    let path = Url::parse(uri).ok()?.path().to_owned();
+    workspace_folders
+        .iter()
+        .filter_map(|folder| Url::parse(folder).ok())
+        .map(|folder| folder.path().trim_end_matches('/').to_owned())
+        .filter(|folder| path.starts_with(&format!("{folder}/")))
+        .max_by_key(|folder| folder.len())
+}
+
+// Orders the results by their relevance to the query and keeps the `top_n`. The results are kept
+// in the order they were retrieved when reranking fails.
+async fn rerank(
+    rerank_model: &RerankModel,
+    query: &str,
+    mut results: Vec<SearchResult>,
+) -> Vec<SearchResult> {
+    let documents: Vec<String> = results.iter().map(|r| r.text.clone()).collect();
+    match rerank_model.rerank(query, &documents).await {
+        Ok(order) => order
+            .into_iter()
+            .map(|i| std::mem::take(&mut results[i]))
+            .collect(),
+        Err(e) => {
+            error!("error reranking the retrieved chunks: {e}");
+            results.truncate(rerank_model.top_n());
+            results
+        }
+    }
+}
+
+// Merges ranked lists of results using weighted reciprocal rank fusion
+fn fuse_results(
+    lists: Vec<(f32, Vec<SearchResult>)>,
+    rrf_k: f32,
+    limit: usize,
+) -> Vec<SearchResult> {
+    let mut fused: Vec<(f32, SearchResult)> = vec![];
+    for (weight, results) in lists {
+        for (rank, result) in results.into_iter().enumerate() {
+            let score = weight / (rrf_k + rank as f32 + 1.);
+            match fused
+                .iter_mut()
+                .find(|(_, r)| r.uri == result.uri && r.text == result.text)
+            {
+                Some((fused_score, _)) => *fused_score += score,
+                None => fused.push((score, result)),
+            }
+        }
+    }
+    // The sort is stable so ties keep the order of the earlier lists
+    fused.sort_by(|a, b| b.0.total_cmp(&a.0));
+    fused
+        .into_iter()
+        .take(limit)
+        .map(|(_, result)| result)
+        .collect()
+}
+
+// The range covering the lines of `chunk`, found by its text since the stores do not keep
+// where chunks start
+pub fn get_line_range(text: &str, chunk: &str) -> Option<Range> {
+    let start = text.find(chunk)?;
+    let start_line = text[..start].matches('\n').count() as u32;
+    let end_line = start_line + chunk.trim_end_matches('\n').matches('\n').count() as u32;
+    Some(Range {
+        start: Position::new(start_line, 0),
+        end: Position::new(end_line + 1, 0),
+    })
+}
+
+// The language of the chunks of the documentation so they can be searched separately
+const DOCS_LANGUAGE: &str = "documentation";
+
+// How long a document has to go without changes before it is indexed
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+// Documents that keep changing are indexed at least this often so the index stays fresh
+const MAX_DEBOUNCE: Duration = Duration::from_secs(5);
+
+// The documents waiting to be indexed with when they first and last changed
+#[derive(Default)]
+struct Pending {
+    changes: HashMap<String, (Instant, Instant)>,
+}
+
+impl Pending {
+    fn change(&mut self, uri: String, now: Instant) {
+        self.changes
+            .entry(uri)
+            .and_modify(|(_, last)| *last = now)
+            .or_insert((now, now));
+    }
+
+    // The documents that are due for indexing, all of them when `all`
+    fn take_due(&mut self, now: Instant, all: bool) -> Vec<String> {
+        let due: Vec<String> = self
+            .changes
+            .iter()
+            .filter(|(_, (first, last))| {
+                all || now - *last >= DEBOUNCE || now - *first >= MAX_DEBOUNCE
+            })
+            .map(|(uri, _)| uri.clone())
+            .collect();
+        for uri in &due {
+            self.changes.remove(uri);
+        }
+        due
+    }
+}
+
+// Reuses the embeddings of the chunks that did not change. Returns the embeddings for all
+// `hashes`, and the indices of the chunks still to embed in place of the `None`s.
+fn reuse_embeddings(
+    cached: Option<&HashMap<u64, Vec<f32>>>,
+    hashes: &[u64],
+) -> (Vec<Option<Vec<f32>>>, Vec<usize>) {
+    let embeddings: Vec<Option<Vec<f32>>> = hashes
+        .iter()
+        .map(|hash| cached.and_then(|cached| cached.get(hash)).cloned())
+        .collect();
+    let missing = (0..hashes.len())
+        .filter(|i| embeddings[*i].is_none())
+        .collect();
+    (embeddings, missing)
+}
+
+struct Index {
+    store: Box<dyn VectorStore + Send + Sync>,
+    splitter: Box<dyn Splitter + Send + Sync>,
+    embedding_model: Box<dyn EmbeddingBackend + Send + Sync>,
+    rerank_model: Option<RerankModel>,
+    search_params: SearchParams,
+    // Splits the documentation, it is kept as markdown
+    docs_splitter: MarkdownSplitter,
+    docs: Option<Docs>,
+    keyword_index: Option<KeywordIndex>,
+    // The languageId of each opened file
+    languages: Mutex<HashMap<String, String>>,
+    // The embeddings of the chunks of each indexed file
+    embedded: Mutex<HashMap<String, EmbeddedFile>>,
+    // The uris of the roots of the workspace
+    workspace_folders: Mutex<Vec<String>>,
+    // Why each file that could not be indexed failed the last time
+    errors: Mutex<HashMap<String, String>>,
+    last_indexed: Mutex<Option<SystemTime>>,
+    // The number of crawls running
+    crawling: AtomicUsize,
+}
+
+// Counts a crawl as running while it is alive
+struct Crawling<'a>(&'a AtomicUsize);
+
+impl<'a> Crawling<'a> {
+    fn new(crawling: &'a AtomicUsize) -> Self {
+        crawling.fetch_add(1, Ordering::Relaxed);
+        Self(crawling)
+    }
+}
+
+impl Drop for Crawling<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Index {
+    async fn upsert_file(&self, uri: &str, text: &str) -> anyhow::Result<()> {
+        let chunks = self.splitter.split(uri, text);
+        self.upsert_chunks(uri, text, chunks).await
+    }
+
+    async fn upsert_doc(&self, uri: &str, text: &str) -> anyhow::Result<()> {
+        self.languages
+            .lock()
+            .insert(uri.to_owned(), DOCS_LANGUAGE.to_owned());
+        let chunks = self.docs_splitter.split(uri, text);
+        self.upsert_chunks(uri, text, chunks).await
+    }
+
+    // Records the outcome for the stats
+    async fn upsert_chunks(&self, uri: &str, text: &str, chunks: Vec<Chunk>) -> anyhow::Result<()> {
+        let result = self.embed_chunks(uri, text, chunks).await;
+        match &result {
+            Ok(()) => {
+                self.errors.lock().remove(uri);
+                *self.last_indexed.lock() = Some(SystemTime::now());
+            }
+            Err(e) => {
+                self.errors.lock().insert(uri.to_owned(), e.to_string());
+            }
+        }
+        result
+    }
+
+    async fn embed_chunks(&self, uri: &str, text: &str, chunks: Vec<Chunk>) -> anyhow::Result<()> {
+        if chunks.is_empty() {
+            return self.delete_file(uri).await;
+        }
+        let language = self.languages.lock().get(uri).cloned();
+        if let Some(keyword_index) = &self.keyword_index {
+            keyword_index.upsert(uri, language.as_deref(), &chunks);
+        }
+
+        let hash = xxh3_64(text.as_bytes());
+        let embedded = self.embedded.lock().remove(uri);
+        let embedded = match embedded {
+            Some(embedded) => Some(embedded),
+            None => self.store.get_embedded(uri).await?,
+        };
+        let embedded = match embedded {
+            // The store already has the file as it is
+            Some(embedded) if embedded.hash == Some(hash) => {
+                self.embedded.lock().insert(uri.to_owned(), embedded);
+                return Ok(());
+            }
+            embedded => embedded,
+        };
+        // Only chunks that changed are embedded again
+        let hashes: Vec<u64> = chunks.iter().map(|c| xxh3_64(c.text.as_bytes())).collect();
+        let (mut embeddings, missing) = reuse_embeddings(
+            embedded.as_ref().map(|embedded| &embedded.embeddings),
+            &hashes,
+        );
+        if !missing.is_empty() {
+            let new_embeddings = self
+                .embedding_model
+                .embed(missing.iter().map(|i| chunks[*i].text.clone()).collect())
+                .await?;
+            anyhow::ensure!(
+                new_embeddings.len() == missing.len(),
+                "expected {} embeddings, got {}",
+                missing.len(),
+                new_embeddings.len()
+            );
+            for (i, embedding) in missing.into_iter().zip(new_embeddings) {
+                embeddings[i] = Some(embedding);
+            }
+        }
+        let embeddings: Vec<Vec<f32>> = embeddings.into_iter().flatten().collect();
+        self.embedded.lock().insert(
+            uri.to_owned(),
+            EmbeddedFile {
+                hash: Some(hash),
+                embeddings: hashes.into_iter().zip(embeddings.iter().cloned()).collect(),
+            },
+        );
+        self.store
+            .upsert(uri, language.as_deref(), hash, chunks, embeddings)
+            .await
+    }
+
+    async fn delete_file(&self, uri: &str) -> anyhow::Result<()> {
+        self.embedded.lock().remove(uri);
+        self.errors.lock().remove(uri);
+        if let Some(keyword_index) = &self.keyword_index {
+            keyword_index.delete(uri);
+        }
+        self.store.delete(uri).await
+    }
+
+    // Removes the files under the workspace folder at `root_uri` that are not in another one
+    async fn delete_workspace_folder(&self, root_uri: &str) {
+        let workspace_folders = self.workspace_folders.lock().clone();
+        let uris: Vec<String> = self
+            .embedded
+            .lock()
+            .keys()
+            .filter(|uri| {
+                get_workspace_folder(&[root_uri.to_owned()], uri).is_some()
+                    && get_workspace_folder(&workspace_folders, uri).is_none()
+            })
+            .cloned()
+            .collect();
+        for uri in uris {
+            if let Err(e) = self.delete_file(&uri).await {
+                error!("error deleting {uri}: {e}")
+            }
+        }
+    }
+
+    async fn index_file(&self, file_store: &FileStore, uri: &str) {
+        let result = match file_store.get_file_contents(uri) {
+            Ok(text) => self.upsert_file(uri, &text).await,
+            // Files that are not open changed on disk
+            Err(_) => self.index_from_disk(uri).await,
+        };
+        if let Err(e) = result {
+            error!("error indexing {uri}: {e}")
+        }
+    }
+
+    // Removes files that no longer exist or are no longer text
+    async fn index_from_disk(&self, uri: &str) -> anyhow::Result<()> {
+        let path = Url::parse(uri)?
+            .to_file_path()
+            .map_err(|_| anyhow::anyhow!("not a local file"))?;
+        match std::fs::read(&path).ok().and_then(crawl::read_text) {
+            Some(text) => {
+                if let Some(language_id) = crawl::get_language_id(&path) {
+                    self.languages
+                        .lock()
+                        .entry(uri.to_owned())
+                        .or_insert_with(|| language_id.to_owned());
+                }
+                self.upsert_file(uri, &text).await
+            }
+            None => self.delete_file(uri).await,
+        }
+    }
+
+    // Indexes the files of the workspace that are not open, the opened ones are indexed from what
+    // the editor has
+    async fn crawl(
+        &self,
+        file_store: &FileStore,
+        root: PathBuf,
+        max_file_size: u64,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let _crawling = Crawling::new(&self.crawling);
+        let progress =
+            ProgressReporter::begin("lsp-ai", Some("Indexing the workspace".to_string()));
+        let files = tokio::task::spawn_blocking(move || crawl::walk(&root, max_file_size)).await?;
+        let mut indexed = 0;
+        let mut percentage = 0;
+        for (i, path) in files.iter().enumerate() {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let Ok(uri) = Url::from_file_path(path) else {
+                continue;
+            };
+            let uri = uri.to_string();
+            // Every opened file has a languageId
+            if file_store.get_language_id(&uri).is_some() {
+                continue;
+            }
+            let Some(text) = std::fs::read(path).ok().and_then(crawl::read_text) else {
+                continue;
+            };
+            if let Some(language_id) = crawl::get_language_id(path) {
+                self.languages
+                    .lock()
+                    .entry(uri.clone())
+                    .or_insert_with(|| language_id.to_owned());
+            }
+            match self.upsert_file(&uri, &text).await {
+                Ok(()) => indexed += 1,
+                Err(e) => error!("error indexing {uri}: {e}"),
+            }
+            let done = (i + 1) * 100 / files.len();
+            if done > percentage {
+                percentage = done;
+                progress.report(
+                    format!("{}/{} files", i + 1, files.len()),
+                    Some(done as u32),
+                );
+            }
+        }
+        let flushed = self.store.flush().await;
+        progress.end(Some(format!("Indexed {indexed} files")));
+        flushed
+    }
+
+    // Indexes the pages and files of the documentation sources
+    async fn crawl_docs(
+        &self,
+        docs: &Docs,
+        max_file_size: u64,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let _crawling = Crawling::new(&self.crawling);
+        let progress =
+            ProgressReporter::begin("lsp-ai", Some("Indexing the documentation".to_string()));
+        let mut indexed = 0;
+        for source in &docs.sources {
+            let path = match Url::parse(source) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {
+                    indexed += self
+                        .crawl_site(url, docs.max_pages, cancel, &progress)
+                        .await;
+                    continue;
+                }
+                Ok(url) if url.scheme() == "file" => match url.to_file_path() {
+                    Ok(path) => path,
+                    Err(_) => {
+                        error!("the documentation source is not a local directory: {source}");
+                        continue;
+                    }
+                },
+                _ => PathBuf::from(source),
+            };
+            let files =
+                tokio::task::spawn_blocking(move || docs::walk(&path, max_file_size)).await?;
+            for path in files {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let (Ok(uri), Some(text)) = (Url::from_file_path(&path), docs::read_file(&path))
+                else {
+                    continue;
+                };
+                match self.upsert_doc(uri.as_str(), &text).await {
+                    Ok(()) => indexed += 1,
+                    Err(e) => error!("error indexing {uri}: {e}"),
+                }
+                progress.report(format!("{indexed} pages"), None);
+            }
+        }
+        let flushed = self.store.flush().await;
+        progress.end(Some(format!("Indexed {indexed} pages")));
+        flushed
+    }
+
+    // Fetches the page at `url` and the pages under it it links to, returns how many were indexed
+    async fn crawl_site(
+        &self,
+        url: Url,
+        max_pages: usize,
+        cancel: &CancellationToken,
+        progress: &ProgressReporter,
+    ) -> usize {
+        let mut indexed = 0;
+        let mut fetched = 0;
+        let mut seen = HashSet::from([url.clone()]);
+        let mut queue = VecDeque::from([url]);
+        while let Some(url) = queue.pop_front() {
+            if cancel.is_cancelled() || fetched >= max_pages {
+                break;
+            }
+            fetched += 1;
+            let (text, links) = match docs::fetch(&url).await {
+                Ok(page) => page,
+                Err(e) => {
+                    error!("error fetching {url}: {e}");
+                    continue;
+                }
+            };
+            for link in links {
+                if seen.insert(link.clone()) {
+                    queue.push_back(link);
+                }
+            }
+            match self.upsert_doc(url.as_str(), &text).await {
+                Ok(()) => indexed += 1,
+                Err(e) => error!("error indexing {url}: {e}"),
+            }
+            progress.report(format!("{url}"), None);
+        }
+        indexed
+    }
+
+    async fn embed_query(&self, query: &str) -> anyhow::Result<Vec<f32>> {
+        self.embedding_model.embed_query(query.to_owned()).await
+    }
+
+    // Vector search, fused with keyword search when hybrid search is on
+    async fn search_chunks(
+        &self,
+        query: &str,
+        embedding: Vec<f32>,
+        filter: SearchFilter<'_>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let Some(hybrid_search) = &self.search_params.hybrid_search else {
+            return self.store.search(embedding, filter, limit).await;
+        };
+        // Retrieve more candidates than needed so the fusion has something to rank
+        let keyword_results = match &self.keyword_index {
+            Some(keyword_index) => keyword_index.search(query, &filter, limit * 2),
+            None => self.store.keyword_search(query, &filter, limit * 2).await?,
+        };
+        let vector_results = self.store.search(embedding, filter, limit * 2).await?;
+        Ok(fuse_results(
+            vec![
+                (hybrid_search.vector_weight, vector_results),
+                (hybrid_search.keyword_weight, keyword_results),
+            ],
+            hybrid_search.rrf_k,
+            limit,
+        ))
+    }
+
+    // Not filtered by the config like the searches for prompts, and reranking would cut the
+    // results to the rerank model's top_n
+    async fn search_workspace(
+        &self,
+        params: &WorkspaceSearchParams,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let embedding = self.embed_query(&params.query).await?;
+        let directories: Vec<String> = params
+            .paths
+            .iter()
+            .map(|path| path.trim_end_matches('/').to_owned())
+            .collect();
+        let filter = SearchFilter {
+            language: params.language.as_deref(),
+            directories: &directories,
+            workspace_folder: None,
+        };
+        self.search_chunks(&params.query, embedding, filter, params.top_k)
+            .await
+    }
+
+    async fn search(&self, query: String, uri: &str) -> anyhow::Result<Vec<SearchResult>> {
+        let embedding = self.embed_query(&query).await?;
+        let language = self.languages.lock().get(uri).cloned();
+        let workspace_folder = if self.search_params.filter_by_workspace_folder {
+            get_workspace_folder(&self.workspace_folders.lock(), uri)
+        } else {
+            None
+        };
+        let directories: Vec<String> = self
+            .search_params
+            .paths
+            .iter()
+            .map(|path| path.trim_end_matches('/').to_owned())
+            .collect();
+        let filter = SearchFilter {
+            language: language
+                .as_deref()
+                .filter(|_| self.search_params.filter_by_language),
+            directories: &directories,
+            workspace_folder: workspace_folder.as_deref(),
+        };
+        let mut results = self
+            .search_chunks(&query, embedding.clone(), filter, self.search_params.limit)
+            .await?;
+        // The documentation is searched on its own so the filters for the code do not exclude it
+        if let Some(docs) = &self.docs {
+            let filter = SearchFilter {
+                language: Some(DOCS_LANGUAGE),
+                directories: &[],
+                workspace_folder: None,
+            };
+            for result in self
+                .store
+                .search(embedding, filter, docs.search_limit)
+                .await?
+            {
+                if !results
+                    .iter()
+                    .any(|r| r.uri == result.uri && r.text == result.text)
+                {
+                    results.push(result);
+                }
+            }
+        }
+        if let Some(rerank_model) = &self.rerank_model {
+            results = rerank(rerank_model, &query, results)
+                .instrument(info_span!("rerank"))
+                .await;
+        }
+        Ok(results)
+    }
+}
+
+// A memory backend that retrieves context by embedding the code around the cursor and searching
+// for similar chunks of the indexed files
+pub struct VectorMemory {
+    file_store: Arc<FileStore>,
+    index: Arc<Index>,
+    context_packing: Option<ContextPacking>,
+    crawl: Option<Crawl>,
+    // Filter the retrieved chunks
+    plugins: Vec<Plugin>,
+    // Stops the crawls on shutdown, each workspace folder's is a child so it can be stopped
+    // when the folder is removed
+    crawl_cancel: CancellationToken,
+    crawls: Mutex<HashMap<String, CancellationToken>>,
+    docs_crawl: Mutex<Option<CancellationToken>>,
+    // Sends the files changed outside the editor to the debouncer, dropped on shutdown
+    watcher: Mutex<Option<RecommendedWatcher>>,
+    // The files of each watched workspace folder that are indexed
+    filters: Arc<Mutex<Vec<crawl::Filter>>>,
+    // Both taken on shutdown, the debouncer indexes what is pending once its sender is dropped
+    debounce_tx: Mutex<Option<Sender<String>>>,
+    debouncer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl VectorMemory {
+    pub fn new(
+        store: Box<dyn VectorStore + Send + Sync>,
+        splitter: ValidSplitter,
+        embedding_model: ValidEmbeddingModel,
+        search_params: SearchParams,
+        context_packing: Option<ContextPacking>,
+        crawl: Option<Crawl>,
+        configuration: Config,
+    ) -> anyhow::Result<Self> {
+        let workspace_folders = configuration.get_workspace_folders();
+        let plugins = configuration.get_plugins().to_vec();
+        let file_store = Arc::new(FileStore::new_without_crawl(configuration));
+        let (chunk_size, chunk_overlap) = match &splitter {
+            ValidSplitter::TreeSitter(c) => (c.chunk_size, c.chunk_overlap),
+            ValidSplitter::TextSplitter(c) => (c.chunk_size, c.chunk_overlap),
+        };
+        let keyword_index = (search_params.hybrid_search.is_some() && !store.has_keyword_search())
+            .then(KeywordIndex::default);
+        let index = Arc::new(Index {
+            store,
+            splitter: splitter.into(),
+            docs_splitter: MarkdownSplitter::new(config::TextSplitter {
+                chunk_size,
+                chunk_overlap,
+            }),
+            docs: crawl.as_ref().and_then(|crawl| crawl.docs.clone()),
+            embedding_model: embedding_model.try_into()?,
+            rerank_model: search_params.rerank.clone().map(RerankModel::new),
+            keyword_index,
+            search_params,
+            languages: Mutex::new(HashMap::new()),
+            embedded: Mutex::new(HashMap::new()),
+            workspace_folders: Mutex::new(workspace_folders),
+            errors: Mutex::new(HashMap::new()),
+            last_indexed: Mutex::new(None),
+            crawling: AtomicUsize::new(0),
+        });
+
+        // Setup up a debouncer for changed text documents, each is indexed once it stops changing
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let task_index = index.clone();
+        let task_file_store = file_store.clone();
+        let (debounce_tx, debounce_rx) = mpsc::channel::<String>();
+        let debouncer = std::thread::spawn(move || {
+            runtime.block_on(async move {
+                let mut pending = Pending::default();
+                loop {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    // Stops once the memory backend is dropped so a shared index is not kept alive
+                    let disconnected = loop {
+                        match debounce_rx.try_recv() {
+                            Ok(uri) => pending.change(uri, Instant::now()),
+                            Err(mpsc::TryRecvError::Empty) => break false,
+                            Err(mpsc::TryRecvError::Disconnected) => break true,
+                        }
+                    };
+                    let due = pending.take_due(Instant::now(), disconnected);
+                    if !due.is_empty() {
+                        for uri in due {
+                            task_index.index_file(&task_file_store, &uri).await;
+                        }
+                        if let Err(e) = task_index.store.flush().await {
+                            error!("error flushing the vector store: {e}")
+                        }
+                    }
+                    if disconnected {
+                        return;
+                    }
+                }
+            })
+        });
+
+        Ok(Self {
+            file_store,
+            index,
+            context_packing,
+            crawl,
+            plugins,
+            crawl_cancel: CancellationToken::new(),
+            crawls: Mutex::new(HashMap::new()),
+            docs_crawl: Mutex::new(None),
+            watcher: Mutex::new(None),
+            filters: Arc::new(Mutex::new(vec![])),
+            debounce_tx: Mutex::new(Some(debounce_tx)),
+            debouncer: Mutex::new(Some(debouncer)),
+        })
+    }
+}
+
+// Sends the uris of the files that change on disk and pass one of the `filters`, editors save
+// opened files so these come through too. Watching stops when the watcher is dropped.
+fn watch(
+    filters: Arc<Mutex<Vec<crawl::Filter>>>,
+    tx: Sender<String>,
+) -> anyhow::Result<RecommendedWatcher> {
+    let tx = Mutex::new(tx);
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) if !event.kind.is_access() => event,
+            Ok(_) => return,
+            Err(e) => {
+                error!("error watching the workspace: {e}");
+                return;
+            }
+        };
+        let filters = filters.lock();
+        for path in event
+            .paths
+            .iter()
+            .filter(|path| filters.iter().any(|filter| filter.includes(path)))
+        {
+            if let Ok(uri) = Url::from_file_path(path) {
+                let _ = tx.lock().send(uri.to_string());
+            }
+        }
+    })?;
+    Ok(watcher)
+}
+
+impl VectorMemory {
+    // Starts crawling and watching the workspace folder at `root_uri` in the background
+    fn add_workspace_folder(&self, root_uri: &str) -> anyhow::Result<()> {
+        let Some(crawl) = &self.crawl else {
+            return Ok(());
+        };
+        let root = Url::parse(root_uri)?.to_file_path().map_err(|_| {
+            anyhow::anyhow!("the workspace folder is not a local directory: {root_uri}")
+        })?;
+        // Started before the crawl so no change is missed while it runs
+        if let Some(watcher) = &mut *self.watcher.lock() {
+            self.filters
+                .lock()
+                .push(crawl::Filter::new(&root, crawl.max_file_size));
+            watcher.watch(&root, RecursiveMode::Recursive)?;
+        }
+        self.start_crawl(root_uri, root, crawl.max_file_size);
+        Ok(())
+    }
+
+    // Crawls the workspace folder in the background, its crawl still running is stopped
+    fn start_crawl(&self, root_uri: &str, root: PathBuf, max_file_size: u64) {
+        let index = self.index.clone();
+        let file_store = self.file_store.clone();
+        let cancel = self.crawl_cancel.child_token();
+        if let Some(previous) = self
+            .crawls
+            .lock()
+            .insert(root_uri.to_owned(), cancel.clone())
+        {
+            previous.cancel();
+        }
+        tokio::spawn(async move {
+            if let Err(e) = index.crawl(&file_store, root, max_file_size, &cancel).await {
+                error!("error crawling the workspace: {e}")
+            }
+        });
+    }
+
+    // Crawls the documentation sources in the background, the crawl still running is stopped
+    fn start_docs_crawl(&self, docs: Docs, max_file_size: u64) {
+        let index = self.index.clone();
+        let cancel = self.crawl_cancel.child_token();
+        if let Some(previous) = self.docs_crawl.lock().replace(cancel.clone()) {
+            previous.cancel();
+        }
+        tokio::spawn(async move {
+            if let Err(e) = index.crawl_docs(&docs, max_file_size, &cancel).await {
+                error!("error crawling the documentation: {e}")
+            }
+        });
+    }
+
+    // Stops crawling and watching the workspace folder at `root_uri`
+    fn remove_workspace_folder(&self, root_uri: &str) -> anyhow::Result<()> {
+        if let Some(cancel) = self.crawls.lock().remove(root_uri) {
+            cancel.cancel();
+        }
+        let root = Url::parse(root_uri)?.to_file_path().map_err(|_| {
+            anyhow::anyhow!("the workspace folder is not a local directory: {root_uri}")
+        })?;
+        if let Some(watcher) = &mut *self.watcher.lock() {
+            self.filters.lock().retain(|filter| filter.root() != root);
+            watcher.unwatch(&root)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VectorMemory {
+    fn drop(&mut self) {
+        self.crawl_cancel.cancel();
+    }
+}
+
+#[async_trait::async_trait]
+impl MemoryBackend for VectorMemory {
+    // Starts crawling and watching the workspace folders in the background
+    #[instrument(skip(self))]
+    async fn init(&self) -> anyhow::Result<()> {
+        let Some(crawl) = &self.crawl else {
+            return Ok(());
+        };
+        if crawl.watch {
+            if let Some(debounce_tx) = self.debounce_tx.lock().clone() {
+                *self.watcher.lock() = Some(watch(self.filters.clone(), debounce_tx)?);
+            }
+        }
+        let workspace_folders = self.index.workspace_folders.lock().clone();
+        for root_uri in workspace_folders {
+            if let Err(e) = self.add_workspace_folder(&root_uri) {
+                error!("error indexing {root_uri}: {e}")
+            }
+        }
+        if let Some(docs) = crawl.docs.clone() {
+            self.start_docs_crawl(docs, crawl.max_file_size);
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn search(&self, params: WorkspaceSearchParams) -> anyhow::Result<Vec<SearchMatch>> {
+        let results = self.index.search_workspace(&params).await?;
+        // Files are read once however many of their chunks matched
+        let mut texts: HashMap<String, Option<String>> = HashMap::new();
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                let text = texts.entry(result.uri.clone()).or_insert_with(|| {
+                    self.file_store
+                        .get_file_contents(&result.uri)
+                        .ok()
+                        .or_else(|| {
+                            let path = Url::parse(&result.uri).ok()?.to_file_path().ok()?;
+                            std::fs::read(path).ok().and_then(crawl::read_text)
+                        })
+                });
+                SearchMatch {
+                    range: text
+                        .as_deref()
+                        .and_then(|text| get_line_range(text, &result.text)),
+                    uri: result.uri,
+                    text: result.text,
+                    symbols: result.symbols,
+                }
+            })
+            .collect())
+    }
+
+    #[instrument(skip(self))]
+    async fn changed_workspace_folders(
+        &self,
+        params: lsp_types::DidChangeWorkspaceFoldersParams,
+    ) -> anyhow::Result<()> {
+        for folder in params.event.removed {
+            let root_uri = folder.uri.to_string();
+            self.index
+                .workspace_folders
+                .lock()
+                .retain(|uri| *uri != root_uri);
+            if let Err(e) = self.remove_workspace_folder(&root_uri) {
+                error!("error removing {root_uri}: {e}")
+            }
+            self.index.delete_workspace_folder(&root_uri).await;
+        }
+        for folder in params.event.added {
+            let root_uri = folder.uri.to_string();
+            {
+                let mut workspace_folders = self.index.workspace_folders.lock();
+                if workspace_folders.contains(&root_uri) {
+                    continue;
+                }
+                workspace_folders.push(root_uri.clone());
+            }
+            if let Err(e) = self.add_workspace_folder(&root_uri) {
+                error!("error indexing {root_uri}: {e}")
+            }
+        }
+        self.index.store.flush().await
+    }
+
+    #[instrument(skip(self))]
+    fn get_opened_text_documents(&self) -> Vec<lsp_types::TextDocumentItem> {
+        self.file_store.get_opened_text_documents()
+    }
+
+    #[instrument(skip(self))]
+    fn get_language_id(&self, uri: &str) -> Option<String> {
+        self.file_store.get_language_id(uri)
+    }
+
+    #[instrument(skip(self))]
+    fn get_text(&self, uri: &str, range: Option<&Range>) -> anyhow::Result<String> {
+        self.file_store.get_text(uri, range)
+    }
+
+    #[instrument(skip(self))]
+    async fn get_filter_text(
+        &self,
+        position: &TextDocumentPositionParams,
+    ) -> anyhow::Result<String> {
+        self.file_store.get_filter_text(position).await
+    }
+
+    #[instrument(skip(self, tokenizer))]
+    async fn build_prompt(
+        &self,
+        position: &TextDocumentPositionParams,
+        prompt_type: PromptType,
+        params: Value,
+        tokenizer: &(dyn Tokenizer + Send + Sync),
+    ) -> anyhow::Result<Prompt> {
+        let params: MemoryRunParams = serde_json::from_value(params)?;
+        let query = self
+            .file_store
+            .get_characters_around_position(position, 512)?;
+        let results = self
+            .index
+            .search(query, position.text_document.uri.as_str())
+            .instrument(info_span!("memory_lookup"))
+            .await?;
+        let chunks: Vec<String> = results
+            .iter()
+            .map(|result| {
+                let symbols: Vec<&str> = result.symbols.iter().map(|s| s.as_str()).collect();
+                format_chunk(Some(&result.uri), &symbols, &result.text)
+            })
+            .collect();
+        let chunks =
+            plugins::filter_context(&self.plugins, position.text_document.uri.as_str(), chunks)?;
+        if let Some(context_packing) = &self.context_packing {
+            return self
+                .file_store
+                .pack(
+                    position,
+                    prompt_type,
+                    &params,
+                    tokenizer,
+                    context_packing,
+                    &chunks,
+                )
+                .await;
+        }
+
+        let context = chunks.join("\n\n");
+        let (prefix, suffix) = self
+            .file_store
+            .get_code_around_position(position, params.max_context_length)?;
+        Ok(fit_prompt(
+            tokenizer,
+            prompt_type,
+            &params,
+            &prefix,
+            &suffix,
+            &context,
+        ))
+    }
+
+    #[instrument(skip(self))]
+    async fn opened_text_document(
+        &self,
+        params: lsp_types::DidOpenTextDocumentParams,
+    ) -> anyhow::Result<()> {
+        let uri = params.text_document.uri.to_string();
+        self.index
+            .languages
+            .lock()
+            .insert(uri.clone(), params.text_document.language_id.clone());
+        self.file_store.opened_text_document(params).await?;
+        self.index.index_file(&self.file_store, &uri).await;
+        self.index.store.flush().await
+    }
+
+    #[instrument(skip(self))]
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        self.crawl_cancel.cancel();
+        self.watcher.lock().take();
+        self.debounce_tx.lock().take();
+        let Some(debouncer) = self.debouncer.lock().take() else {
+            return Ok(());
+        };
+        tokio::task::spawn_blocking(move || debouncer.join())
+            .await?
+            .map_err(|_| anyhow::anyhow!("the vector index debouncer panicked"))
+    }
+
+    #[instrument(skip(self))]
+    async fn changed_text_document(
+        &self,
+        params: lsp_types::DidChangeTextDocumentParams,
+    ) -> anyhow::Result<()> {
+        let uri = params.text_document.uri.to_string();
+        self.file_store.changed_text_document(params).await?;
+        if let Some(debounce_tx) = &*self.debounce_tx.lock() {
+            debounce_tx.send(uri)?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn renamed_files(&self, params: lsp_types::RenameFilesParams) -> anyhow::Result<()> {
+        self.file_store.renamed_files(params.clone()).await?;
+        for file in params.files {
+            {
+                let mut languages = self.index.languages.lock();
+                if let Some(language) = languages.remove(&file.old_uri) {
+                    languages.insert(file.new_uri.clone(), language);
+                }
+            }
+            let embedded = self.index.embedded.lock().remove(&file.old_uri);
+            if let Err(e) = self.index.delete_file(&file.old_uri).await {
+                error!("error deleting {}: {e}", file.old_uri)
+            }
+            // The chunks of a renamed file do not have to be embedded again, but the store does
+            // not have it under the new uri yet
+            if let Some(embedded) = embedded {
+                self.index.embedded.lock().insert(
+                    file.new_uri.clone(),
+                    EmbeddedFile {
+                        hash: None,
+                        ..embedded
+                    },
+                );
+            }
+            self.index.index_file(&self.file_store, &file.new_uri).await;
+        }
+        self.index.store.flush().await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_stats(&self) -> anyhow::Result<MemoryStatsResult> {
+        let mut files: Vec<FileStatus> = {
+            let languages = self.index.languages.lock();
+            let errors = self.index.errors.lock();
+            let embedded = self.index.embedded.lock();
+            let status = |uri: &String, chunks| FileStatus {
+                uri: uri.clone(),
+                language: languages.get(uri).cloned(),
+                chunks,
+                error: errors.get(uri).cloned(),
+            };
+            embedded
+                .iter()
+                .map(|(uri, embedded)| status(uri, embedded.embeddings.len()))
+                .chain(
+                    errors
+                        .keys()
+                        .filter(|uri| !embedded.contains_key(*uri))
+                        .map(|uri| status(uri, 0)),
+                )
+                .collect()
+        };
+        files.sort_by(|a, b| a.uri.cmp(&b.uri));
+        let last_indexed = *self.index.last_indexed.lock();
+        Ok(MemoryStatsResult {
+            chunks: files.iter().map(|file| file.chunks).sum(),
+            index_size: self.index.store.get_size().await?,
+            last_indexed: last_indexed
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|since| since.as_millis() as u64),
+            indexing: self.index.crawling.load(Ordering::Relaxed) > 0,
+            files,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn reindex(&self, force: bool) -> anyhow::Result<()> {
+        if force {
+            // Without what was stored for them every file is embedded again
+            let uris: Vec<String> = self.index.embedded.lock().keys().cloned().collect();
+            for uri in uris {
+                if let Err(e) = self.index.delete_file(&uri).await {
+                    error!("error deleting {uri}: {e}")
+                }
+            }
+            self.index.store.flush().await?;
+        }
+        // The crawls skip the opened documents
+        let index = self.index.clone();
+        let file_store = self.file_store.clone();
+        tokio::spawn(async move {
+            for text_document in file_store.get_opened_text_documents() {
+                index
+                    .index_file(&file_store, text_document.uri.as_str())
+                    .await;
+            }
+            if let Err(e) = index.store.flush().await {
+                error!("error flushing the vector store: {e}")
+            }
+        });
+        let Some(crawl) = &self.crawl else {
+            return Ok(());
+        };
+        let workspace_folders = self.index.workspace_folders.lock().clone();
+        for root_uri in workspace_folders {
+            match Url::parse(&root_uri).map(|url| url.to_file_path()) {
+                Ok(Ok(root)) => self.start_crawl(&root_uri, root, crawl.max_file_size),
+                _ => error!("the workspace folder is not a local directory: {root_uri}"),
+            }
+        }
+        if let Some(docs) = crawl.docs.clone() {
+            self.start_docs_crawl(docs, crawl.max_file_size);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(text: &str) -> SearchResult {
+        SearchResult {
+            uri: "file:///a.rs".to_string(),
+            text: text.to_string(),
+            symbols: vec![],
+        }
+    }
+
+    #[test]
+    fn vector_memory_gets_line_ranges() {
+        let text = "fn a() {}\n\nfn b() {\n    a()\n}\n";
+        assert_eq!(
+            get_line_range(text, "fn b() {\n    a()\n}\n"),
+            Some(Range {
+                start: Position::new(2, 0),
+                end: Position::new(5, 0),
+            })
+        );
+        assert_eq!(get_line_range(text, "fn c() {}"), None);
+    }
+
+    #[test]
+    fn vector_memory_fuses_results() {
+        let fused = fuse_results(
+            vec![
+                (1., vec![result("a"), result("b"), result("c")]),
+                (1., vec![result("c"), result("d")]),
+            ],
+            60.,
+            3,
+        );
+        let texts: Vec<&str> = fused.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, vec!["c", "a", "b"]);
+    }
+
+    #[test]
+    fn vector_memory_debounces_changes() {
+        let start = Instant::now();
+        let mut pending = Pending::default();
+        pending.change("file:///a.rs".to_string(), start);
+        pending.change("file:///b.rs".to_string(), start);
+        pending.change(
+            "file:///b.rs".to_string(),
+            start + Duration::from_millis(400),
+        );
+        let due = pending.take_due(start + DEBOUNCE, false);
+        assert_eq!(due, vec!["file:///a.rs"]);
+        assert!(pending.take_due(start + DEBOUNCE, false).is_empty());
+        assert_eq!(
+            pending.take_due(start + DEBOUNCE, true),
+            vec!["file:///b.rs"]
+        );
+
+        // Typing without a pause is still indexed every MAX_DEBOUNCE
+        for i in 0..50 {
+            pending.change(
+                "file:///a.rs".to_string(),
+                start + Duration::from_millis(i * 100),
+            );
+        }
+        assert_eq!(
+            pending.take_due(start + MAX_DEBOUNCE, false),
+            vec!["file:///a.rs"]
+        );
+    }
+
+    #[test]
+    fn vector_memory_reuses_embeddings() {
+        let cached = HashMap::from([(1, vec![1.]), (2, vec![2.])]);
+        let (embeddings, missing) = reuse_embeddings(Some(&cached), &[2, 3, 1]);
+        assert_eq!(embeddings, vec![Some(vec![2.]), None, Some(vec![1.])]);
+        assert_eq!(missing, vec![1]);
+    }
+
+    #[test]
+    fn vector_memory_gets_directories() {
+        assert_eq!(
+            get_directories("file:///home/user/project/main.rs"),
+            vec!["/home", "/home/user", "/home/user/project"]
+        );
+    }
+
+    #[test]
+    fn vector_memory_gets_workspace_folders() {
+        let workspace_folders = vec![
+            "file:///home/user/project/".to_string(),
+            "file:///home/user/project/vendor/lib".to_string(),
+        ];
+        assert_eq!(
+            get_workspace_folder(&workspace_folders, "file:///home/user/project/main.rs")
+                .as_deref(),
+            Some("/home/user/project")
+        );
+        assert_eq!(
+            get_workspace_folder(
+                &workspace_folders,
+                "file:///home/user/project/vendor/lib/a.rs"
+            )
+            .as_deref(),
+            Some("/home/user/project/vendor/lib")
+        );
+        assert!(get_workspace_folder(&workspace_folders, "file:///home/user/other.rs").is_none());
+    }
+}
@@ -1,20 +1,36 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
+use lsp_server::{Connection, ErrorCode, Message, RequestId, Response};
 use lsp_types::{
-    DidChangeTextDocumentParams, DidOpenTextDocumentParams, RenameFilesParams,
-    TextDocumentPositionParams,
+    DidChangeTextDocumentParams, DidChangeWorkspaceFoldersParams, DidOpenTextDocumentParams, Range,
+    RenameFilesParams, TextDocumentPositionParams,
 };
 use serde_json::Value;
-use tracing::error;
+use tokio::task::JoinHandle;
+use tracing::{error, Instrument, Span};
 
-use crate::memory_backends::{MemoryBackend, Prompt, PromptType};
+use crate::{
+    custom_requests::{
+        reindex::ReindexParams,
+        search::{SearchMatch, WorkspaceSearchParams},
+    },
+    memory_backends::{MemoryBackend, Prompt, PromptType},
+    progress,
+    tokenizer::SharedTokenizer,
+};
+
+// How long tasks still running get to finish when the server shuts down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(Debug)]
 pub struct PromptRequest {
     position: TextDocumentPositionParams,
     prompt_type: PromptType,
     params: Value,
+    // The prompt is fit into the context window of the model it is for
+    tokenizer: SharedTokenizer,
     tx: tokio::sync::oneshot::Sender<Prompt>,
+    // The span of the request the prompt is for
+    span: Span,
 }
 
 impl PromptRequest {
@@ -22,13 +38,16 @@ impl PromptRequest {
         position: TextDocumentPositionParams,
         prompt_type: PromptType,
         params: Value,
+        tokenizer: SharedTokenizer,
         tx: tokio::sync::oneshot::Sender<Prompt>,
     ) -> Self {
         Self {
             position,
             prompt_type,
             params,
+            tokenizer,
             tx,
+            span: Span::current(),
         }
     }
 }
@@ -48,17 +67,93 @@ impl FilterRequest {
     }
 }
 
+#[derive(Debug)]
+pub struct LanguageIdRequest {
+    uri: String,
+    tx: tokio::sync::oneshot::Sender<Option<String>>,
+}
+
+impl LanguageIdRequest {
+    pub fn new(uri: String, tx: tokio::sync::oneshot::Sender<Option<String>>) -> Self {
+        Self { uri, tx }
+    }
+}
+
+#[derive(Debug)]
+pub struct TextRequest {
+    uri: String,
+    range: Option<Range>,
+    tx: tokio::sync::oneshot::Sender<String>,
+}
+
+impl TextRequest {
+    pub fn new(
+        uri: String,
+        range: Option<Range>,
+        tx: tokio::sync::oneshot::Sender<String>,
+    ) -> Self {
+        Self { uri, range, tx }
+    }
+}
+
+// The text of opened documents, None for other files
+#[derive(Debug)]
+pub struct FileTextRequest {
+    uri: String,
+    tx: tokio::sync::oneshot::Sender<Option<String>>,
+}
+
+impl FileTextRequest {
+    pub fn new(uri: String, tx: tokio::sync::oneshot::Sender<Option<String>>) -> Self {
+        Self { uri, tx }
+    }
+}
+
+#[derive(Debug)]
+pub struct SearchRequest {
+    params: WorkspaceSearchParams,
+    tx: tokio::sync::oneshot::Sender<anyhow::Result<Vec<SearchMatch>>>,
+}
+
+impl SearchRequest {
+    pub fn new(
+        params: WorkspaceSearchParams,
+        tx: tokio::sync::oneshot::Sender<anyhow::Result<Vec<SearchMatch>>>,
+    ) -> Self {
+        Self { params, tx }
+    }
+}
+
 pub enum WorkerRequest {
     FilterText(FilterRequest),
+    LanguageId(LanguageIdRequest),
+    Text(TextRequest),
+    FileText(FileTextRequest),
+    SearchMatches(SearchRequest),
     Prompt(PromptRequest),
     DidOpenTextDocument(DidOpenTextDocumentParams),
     DidChangeTextDocument(DidChangeTextDocumentParams),
     DidRenameFiles(RenameFilesParams),
+    DidChangeWorkspaceFolders(DidChangeWorkspaceFoldersParams),
+    // Answered on the connection
+    MemoryStats(RequestId),
+    Reindex(RequestId, ReindexParams),
+    Search(RequestId, WorkspaceSearchParams),
+    // Sent when the client changes the memory configuration
+    ReplaceBackend(Box<dyn MemoryBackend + Send + Sync>),
+}
+
+fn to_response<T: serde::Serialize>(id: RequestId, result: anyhow::Result<T>) -> Response {
+    match result {
+        Ok(result) => Response::new_ok(id, result),
+        Err(e) => Response::new_err(id, ErrorCode::RequestFailed as i32, e.to_string()),
+    }
 }
 
 async fn do_task(
     request: WorkerRequest,
     memory_backend: Arc<Box<dyn MemoryBackend + Send + Sync>>,
+    connection: &Connection,
 ) -> anyhow::Result<()> {
     match request {
         WorkerRequest::FilterText(params) => {
@@ -68,9 +163,43 @@ async fn do_task(
                 .send(filter_text)
                 .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
         }
+        WorkerRequest::LanguageId(params) => {
+            let language_id = memory_backend.get_language_id(&params.uri);
+            params
+                .tx
+                .send(language_id)
+                .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
+        }
+        WorkerRequest::Text(params) => {
+            let text = memory_backend.get_text(&params.uri, params.range.as_ref())?;
+            params
+                .tx
+                .send(text)
+                .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
+        }
+        WorkerRequest::FileText(params) => {
+            let text = memory_backend.get_text(&params.uri, None).ok();
+            params
+                .tx
+                .send(text)
+                .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
+        }
+        WorkerRequest::SearchMatches(params) => {
+            let matches = memory_backend.search(params.params).await;
+            params
+                .tx
+                .send(matches)
+                .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
+        }
         WorkerRequest::Prompt(params) => {
             let prompt = memory_backend
-                .build_prompt(&params.position, params.prompt_type, params.params)
+                .build_prompt(
+                    &params.position,
+                    params.prompt_type,
+                    params.params,
+                    &*params.tokenizer,
+                )
+                .instrument(params.span)
                 .await?;
             params
                 .tx
@@ -84,35 +213,123 @@ async fn do_task(
             memory_backend.changed_text_document(params).await?;
         }
         WorkerRequest::DidRenameFiles(params) => memory_backend.renamed_files(params).await?,
+        WorkerRequest::DidChangeWorkspaceFolders(params) => {
+            memory_backend.changed_workspace_folders(params).await?;
+        }
+        WorkerRequest::MemoryStats(id) => {
+            let response = to_response(id, memory_backend.get_stats().await);
+            connection.sender.send(Message::Response(response))?;
+        }
+        WorkerRequest::Reindex(id, params) => {
+            let response = to_response(id, memory_backend.reindex(params.force).await);
+            connection.sender.send(Message::Response(response))?;
+        }
+        WorkerRequest::Search(id, params) => {
+            let response = to_response(id, memory_backend.search(params).await);
+            connection.sender.send(Message::Response(response))?;
+        }
+        WorkerRequest::ReplaceBackend(_) => {
+            anyhow::bail!("backend replacements are not dispatched")
+        }
     }
     anyhow::Ok(())
 }
 
+// Starts the new backend with the documents the current one knows about. Errors are only logged,
+// like when the first backend starts.
+fn start_backend(
+    memory_backend: &(dyn MemoryBackend + Send + Sync),
+    new_memory_backend: &(dyn MemoryBackend + Send + Sync),
+    runtime: &tokio::runtime::Runtime,
+) {
+    runtime.block_on(async {
+        if let Err(e) = new_memory_backend.init().await {
+            error!("error initializing the memory backend: {e}");
+        }
+        for text_document in memory_backend.get_opened_text_documents() {
+            if let Err(e) = new_memory_backend
+                .opened_text_document(DidOpenTextDocumentParams { text_document })
+                .await
+            {
+                error!("error opening a document in the new memory backend: {e}");
+            }
+        }
+    });
+}
+
 fn do_run(
     memory_backend: Box<dyn MemoryBackend + Send + Sync>,
     rx: std::sync::mpsc::Receiver<WorkerRequest>,
+    connection: Arc<Connection>,
 ) -> anyhow::Result<()> {
-    let memory_backend = Arc::new(memory_backend);
+    let mut memory_backend = Arc::new(memory_backend);
+    // Indexing is reported to this client only
+    progress::enter(&connection);
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(4)
+        .on_thread_start(progress::enter_on_thread_start(&connection))
         .enable_all()
         .build()?;
-    loop {
-        let request = rx.recv()?;
+    if let Err(e) = runtime.block_on(memory_backend.init()) {
+        error!("error initializing the memory backend: {e}");
+    }
+    let mut tasks: Vec<JoinHandle<()>> = vec![];
+    // Ends when the server shuts down
+    while let Ok(request) = rx.recv() {
+        if let WorkerRequest::ReplaceBackend(new_memory_backend) = request {
+            start_backend(
+                memory_backend.as_ref().as_ref(),
+                new_memory_backend.as_ref(),
+                &runtime,
+            );
+            let old_memory_backend =
+                std::mem::replace(&mut memory_backend, Arc::new(new_memory_backend));
+            // Tasks already running keep using the old backend, it is shut down once they finish
+            let old_tasks = std::mem::take(&mut tasks);
+            tasks.push(runtime.spawn(async move {
+                for task in old_tasks {
+                    let _ = task.await;
+                }
+                if let Err(e) = old_memory_backend.shutdown().await {
+                    error!("error shutting down the replaced memory backend: {e}");
+                }
+            }));
+            continue;
+        }
         let thread_memory_backend = memory_backend.clone();
-        runtime.spawn(async move {
-            if let Err(e) = do_task(request, thread_memory_backend).await {
+        let thread_connection = connection.clone();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(runtime.spawn(async move {
+            if let Err(e) = do_task(request, thread_memory_backend, &thread_connection).await {
                 error!("error in memory worker task: {e}")
             }
-        });
+        }));
     }
+
+    // Changes still being applied are waited for so the backend writes all of them
+    runtime.block_on(async {
+        let finished = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+            for task in tasks {
+                let _ = task.await;
+            }
+        })
+        .await;
+        if finished.is_err() {
+            error!("memory worker tasks did not finish before the shutdown");
+        }
+        if let Err(e) = memory_backend.shutdown().await {
+            error!("error shutting down the memory backend: {e}");
+        }
+    });
+    Ok(())
 }
 
 pub fn run(
     memory_backend: Box<dyn MemoryBackend + Send + Sync>,
     rx: std::sync::mpsc::Receiver<WorkerRequest>,
+    connection: Arc<Connection>,
 ) {
-    if let Err(e) = do_run(memory_backend, rx) {
+    if let Err(e) = do_run(memory_backend, rx, connection) {
         error!("error in memory worker: {e}")
     }
 }
@@ -0,0 +1,341 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tracing::{error, info};
+
+use crate::{config, usage::TokenUsage};
+
+// Upper bounds in seconds, local models answer in milliseconds and remote chat models in tens of
+// seconds
+const BUCKETS: [f64; 11] = [0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30., 60., 120.];
+
+static METRICS: Lazy<Mutex<Metrics>> = Lazy::new(|| Mutex::new(Metrics::default()));
+static SERVER: Lazy<Mutex<Option<Server>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Default)]
+struct Histogram {
+    // Not cumulative, they are summed up when rendered
+    counts: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bucket| value <= *bucket) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Keyed by model key and request type
+type Labels = (String, &'static str);
+
+#[derive(Default)]
+struct Metrics {
+    requests: BTreeMap<Labels, u64>,
+    errors: BTreeMap<Labels, u64>,
+    durations: BTreeMap<Labels, Histogram>,
+    prompt_tokens: BTreeMap<String, u64>,
+    completion_tokens: BTreeMap<String, u64>,
+    cache_hits: u64,
+    cache_misses: u64,
+}
+
+// `kind` is one of completion, generation or generation_stream
+pub fn record_request(model: &str, kind: &'static str, duration: Duration, ok: bool) {
+    let mut metrics = METRICS.lock();
+    let labels = (model.to_owned(), kind);
+    *metrics.requests.entry(labels.clone()).or_default() += 1;
+    if !ok {
+        *metrics.errors.entry(labels.clone()).or_default() += 1;
+    }
+    metrics
+        .durations
+        .entry(labels)
+        .or_default()
+        .observe(duration.as_secs_f64());
+}
+
+pub fn record_tokens(model: &str, usage: TokenUsage) {
+    let mut metrics = METRICS.lock();
+    *metrics.prompt_tokens.entry(model.to_owned()).or_default() += usage.prompt_tokens;
+    *metrics
+        .completion_tokens
+        .entry(model.to_owned())
+        .or_default() += usage.completion_tokens;
+}
+
+pub fn record_cache(hit: bool) {
+    let mut metrics = METRICS.lock();
+    if hit {
+        metrics.cache_hits += 1;
+    } else {
+        metrics.cache_misses += 1;
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn write_counters(out: &mut String, name: &str, help: &str, counters: &BTreeMap<Labels, u64>) {
+    write_header(out, name, "counter", help);
+    for ((model, kind), value) in counters {
+        let _ = writeln!(
+            out,
+            "{name}{{model=\"{}\",type=\"{kind}\"}} {value}",
+            escape(model)
+        );
+    }
+}
+
+fn write_token_counters(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    counters: &BTreeMap<String, u64>,
+) {
+    write_header(out, name, "counter", help);
+    for (model, value) in counters {
+        let _ = writeln!(out, "{name}{{model=\"{}\"}} {value}", escape(model));
+    }
+}
+
+// The Prometheus text exposition format
+fn render() -> String {
+    let metrics = METRICS.lock();
+    let mut out = String::new();
+    write_counters(
+        &mut out,
+        "lsp_ai_requests_total",
+        "Requests sent to the models.",
+        &metrics.requests,
+    );
+    write_counters(
+        &mut out,
+        "lsp_ai_request_errors_total",
+        "Requests to the models that failed.",
+        &metrics.errors,
+    );
+
+    let name = "lsp_ai_request_duration_seconds";
+    write_header(
+        &mut out,
+        name,
+        "histogram",
+        "How long the models took to answer.",
+    );
+    for ((model, kind), histogram) in &metrics.durations {
+        let labels = format!("model=\"{}\",type=\"{kind}\"", escape(model));
+        let mut cumulative = 0;
+        for (bucket, count) in BUCKETS.iter().zip(histogram.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels},le=\"{bucket}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+            histogram.count
+        );
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", histogram.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", histogram.count);
+    }
+
+    write_token_counters(
+        &mut out,
+        "lsp_ai_prompt_tokens_total",
+        "Prompt tokens sent to the models, estimated when the model does not report them.",
+        &metrics.prompt_tokens,
+    );
+    write_token_counters(
+        &mut out,
+        "lsp_ai_completion_tokens_total",
+        "Tokens generated by the models, estimated when the model does not report them.",
+        &metrics.completion_tokens,
+    );
+
+    write_header(
+        &mut out,
+        "lsp_ai_completion_cache_requests_total",
+        "counter",
+        "Completion cache lookups.",
+    );
+    let _ = writeln!(
+        out,
+        "lsp_ai_completion_cache_requests_total{{result=\"hit\"}} {}",
+        metrics.cache_hits
+    );
+    let _ = writeln!(
+        out,
+        "lsp_ai_completion_cache_requests_total{{result=\"miss\"}} {}",
+        metrics.cache_misses
+    );
+    out
+}
+
+fn handle_connection(stream: TcpStream) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+struct Server {
+    config: config::Metrics,
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    fn start(config: &config::Metrics) -> anyhow::Result<Self> {
+        let listener =
+            TcpListener::bind((config.host.as_str(), config.port)).with_context(|| {
+                format!(
+                    "error binding the metrics port {}:{}",
+                    config.host, config.port
+                )
+            })?;
+        let address = listener.local_addr()?;
+        info!("serving metrics on http://{address}/metrics");
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = handle_connection(stream) {
+                            error!("error serving metrics: {e}")
+                        }
+                    }
+                    Err(e) => error!("error accepting a metrics connection: {e}"),
+                }
+            }
+        });
+        Ok(Self {
+            config: config.clone(),
+            address,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Server {
+    // The listener blocks in accept so it is woken up with a connection of our own, and joined so
+    // the port is free again for a new server
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = TcpStream::connect(self.address);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Checks the address resolves, whether the port is free is only known when the server starts
+pub fn validate(config: Option<&config::Metrics>) -> anyhow::Result<()> {
+    if let Some(config) = config {
+        (config.host.as_str(), config.port)
+            .to_socket_addrs()
+            .with_context(|| format!("invalid metrics address {}:{}", config.host, config.port))?;
+    }
+    Ok(())
+}
+
+pub fn configure(config: Option<&config::Metrics>) -> anyhow::Result<()> {
+    let mut server = SERVER.lock();
+    // The server is kept when the configuration did not change
+    if server.as_ref().map(|server| &server.config) == config {
+        return Ok(());
+    }
+    // Stops the current server first as the new one usually binds the same port
+    *server = None;
+    if let Some(config) = config {
+        *server = Some(Server::start(config)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn metrics() -> anyhow::Result<()> {
+        record_request(
+            "metrics_model",
+            "completion",
+            Duration::from_millis(80),
+            true,
+        );
+        record_request("metrics_model", "completion", Duration::from_secs(3), false);
+        record_tokens("metrics_model", TokenUsage::new(100, 20));
+        record_cache(true);
+
+        let server = Server::start(&config::Metrics {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+        })?;
+        let mut stream = TcpStream::connect(server.address)?;
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response
+            .contains("lsp_ai_requests_total{model=\"metrics_model\",type=\"completion\"} 2"));
+        assert!(response.contains(
+            "lsp_ai_request_errors_total{model=\"metrics_model\",type=\"completion\"} 1"
+        ));
+        assert!(response.contains(
+            "lsp_ai_request_duration_seconds_bucket{model=\"metrics_model\",type=\"completion\",le=\"0.1\"} 1"
+        ));
+        assert!(response.contains(
+            "lsp_ai_request_duration_seconds_bucket{model=\"metrics_model\",type=\"completion\",le=\"+Inf\"} 2"
+        ));
+        assert!(response.contains("lsp_ai_prompt_tokens_total{model=\"metrics_model\"} 100"));
+        drop(server);
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use wasmi::{Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::{config::Plugin, memory_backends::Prompt};
+
+// Modules are compiled once, every hook call gets a fresh instance so calls can not see each
+// other's data
+static MODULES: Lazy<Mutex<HashMap<String, (Engine, Module)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn wasm_error(e: impl std::fmt::Display) -> anyhow::Error {
+    anyhow::anyhow!("{e}")
+}
+
+fn get_module(path: &str) -> anyhow::Result<(Engine, Module)> {
+    let mut modules = MODULES.lock();
+    if let Some(module) = modules.get(path) {
+        return Ok(module.clone());
+    }
+    let bytes = std::fs::read(path).with_context(|| format!("error reading plugin {path}"))?;
+    let mut wasm_config = wasmi::Config::default();
+    wasm_config.consume_fuel(true);
+    let engine = Engine::new(&wasm_config);
+    let module = Module::new(&engine, &bytes[..])
+        .map_err(wasm_error)
+        .with_context(|| format!("error compiling plugin {path}"))?;
+    modules.insert(path.to_owned(), (engine.clone(), module.clone()));
+    Ok((engine, module))
+}
+
+// The hook gets the JSON input written to memory it allocates with `alloc(len)` and returns
+// where its JSON output is as `ptr << 32 | len`. None when the plugin does not export the hook.
+fn call_hook(plugin: &Plugin, hook: &str, input: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let (engine, module) = get_module(&plugin.path)?;
+    let limits = StoreLimitsBuilder::new()
+        .memory_size(plugin.max_memory_mb.saturating_mul(1024 * 1024))
+        .build();
+    let mut store = Store::new(&engine, limits);
+    store.limiter(|limits| limits);
+    store.add_fuel(plugin.fuel).map_err(wasm_error)?;
+    let linker = <Linker<StoreLimits>>::new(&engine);
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .and_then(|instance| instance.start(&mut store))
+        .map_err(wasm_error)?;
+    if instance.get_func(&store, hook).is_none() {
+        return Ok(None);
+    }
+    let memory = instance
+        .get_memory(&store, "memory")
+        .context("the plugin does not export `memory`")?;
+    let alloc = instance
+        .get_typed_func::<i32, i32>(&store, "alloc")
+        .map_err(wasm_error)?;
+    let run = instance
+        .get_typed_func::<(i32, i32), i64>(&store, hook)
+        .map_err(wasm_error)?;
+    let len = i32::try_from(input.len())?;
+    let ptr = alloc.call(&mut store, len).map_err(wasm_error)?;
+    memory
+        .write(&mut store, ptr as u32 as usize, input)
+        .map_err(wasm_error)?;
+    let output = run.call(&mut store, (ptr, len)).map_err(wasm_error)? as u64;
+    // Checked against the memory before anything is allocated for it
+    let (start, len) = ((output >> 32) as usize, (output & 0xffff_ffff) as usize);
+    let bytes = memory
+        .data(&store)
+        .get(start..start.saturating_add(len))
+        .context("the plugin returned output outside its memory")?;
+    Ok(Some(bytes.to_vec()))
+}
+
+// Passes the value through the hook of every plugin exporting it, in order
+fn run_hook<T: Serialize + DeserializeOwned>(
+    plugins: &[Plugin],
+    hook: &str,
+    mut value: T,
+) -> anyhow::Result<T> {
+    for plugin in plugins {
+        let input = serde_json::to_vec(&value)?;
+        let output = call_hook(plugin, hook, &input)
+            .with_context(|| format!("error running `{hook}` of plugin {}", plugin.path))?;
+        if let Some(output) = output {
+            value = serde_json::from_slice(&output)
+                .with_context(|| format!("invalid output of `{hook}` of plugin {}", plugin.path))?;
+        }
+    }
+    Ok(value)
+}
+
+#[derive(Deserialize, Serialize)]
+struct PrePrompt {
+    prompt: Prompt,
+    params: Value,
+}
+
+// Runs before the prompt is redacted and sent to the model
+pub fn pre_prompt(
+    plugins: &[Plugin],
+    prompt: &mut Prompt,
+    params: &mut Value,
+) -> anyhow::Result<()> {
+    if plugins.is_empty() {
+        return Ok(());
+    }
+    let input = PrePrompt {
+        prompt: prompt.clone(),
+        params: params.clone(),
+    };
+    let output = run_hook(plugins, "pre_prompt", input)?;
+    *prompt = output.prompt;
+    *params = output.params;
+    Ok(())
+}
+
+#[derive(Deserialize, Serialize)]
+struct PostCompletion {
+    text: String,
+    // `completion` or `generation`
+    kind: String,
+}
+
+// Runs on completions and generations after they are post processed
+pub fn post_completion(plugins: &[Plugin], text: String, kind: &str) -> anyhow::Result<String> {
+    if plugins.is_empty() {
+        return Ok(text);
+    }
+    let input = PostCompletion {
+        text,
+        kind: kind.to_owned(),
+    };
+    Ok(run_hook(plugins, "post_completion", input)?.text)
+}
+
+#[derive(Deserialize, Serialize)]
+struct ContextFilter {
+    // The document the prompt is built for
+    uri: String,
+    // Formatted and ordered from most to least relevant, plugins may drop and reorder them
+    chunks: Vec<String>,
+}
+
+// Runs on the chunks retrieved from the index before they are packed into the prompt
+pub fn filter_context(
+    plugins: &[Plugin],
+    uri: &str,
+    chunks: Vec<String>,
+) -> anyhow::Result<Vec<String>> {
+    if plugins.is_empty() {
+        return Ok(chunks);
+    }
+    let input = ContextFilter {
+        uri: uri.to_owned(),
+        chunks,
+    };
+    Ok(run_hook(plugins, "context_filter", input)?.chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_backends::FIMPrompt;
+    use serde_json::json;
+
+    // `post_completion` always answers with the same text, `context_filter` never returns
+    const PLUGIN: &str = r#"(module
+        (memory (export "memory") 1)
+        (global $next (mut i32) (i32.const 1024))
+        (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+        (data (i32.const 0) "{\"text\": \"from the plugin\"}")
+        (func (export "post_completion") (param i32 i32) (result i64)
+            (i64.const 27))
+        (func (export "context_filter") (param i32 i32) (result i64)
+            (loop $forever (br $forever))
+            (i64.const 0)))"#;
+
+    #[test]
+    fn runs_plugin_hooks() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("lsp-ai-plugin-{}.wasm", std::process::id()));
+        std::fs::write(&path, wat::parse_str(PLUGIN)?)?;
+        let plugins = vec![Plugin {
+            path: path.to_string_lossy().into_owned(),
+            fuel: 1_000_000,
+            max_memory_mb: 1,
+        }];
+
+        assert_eq!(
+            post_completion(&plugins, "fn main() {}".to_string(), "completion")?,
+            "from the plugin"
+        );
+        // Hooks the plugin does not export leave the value as is
+        let mut prompt = Prompt::FIM(FIMPrompt::new("def ".to_string(), String::new()));
+        let mut params = json!({"max_tokens": 4});
+        pre_prompt(&plugins, &mut prompt, &mut params)?;
+        assert!(matches!(prompt, Prompt::FIM(fim) if fim.prompt == "def "));
+        assert_eq!(params, json!({"max_tokens": 4}));
+        // Running out of fuel stops the hook
+        assert!(filter_context(&plugins, "file:///a.py", vec!["chunk".to_string()]).is_err());
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn limits_plugin_memory() -> anyhow::Result<()> {
+        let run = |name: &str, module: &str| -> anyhow::Result<String> {
+            let path = std::env::temp_dir()
+                .join(format!("lsp-ai-plugin-{name}-{}.wasm", std::process::id()));
+            std::fs::write(&path, wat::parse_str(module)?)?;
+            let plugins = vec![Plugin {
+                path: path.to_string_lossy().into_owned(),
+                fuel: 1_000_000,
+                max_memory_mb: 1,
+            }];
+            let result = post_completion(&plugins, String::new(), "completion");
+            std::fs::remove_file(path)?;
+            result
+        };
+        // 4 GiB of output from a 64 KiB memory
+        let out_of_bounds = r#"(module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) (i32.const 0))
+            (func (export "post_completion") (param i32 i32) (result i64)
+                (i64.const 0xffffffff)))"#;
+        assert!(run("out-of-bounds", out_of_bounds).is_err());
+        // 2 MiB of memory with a 1 MiB limit
+        let too_large = r#"(module
+            (memory (export "memory") 32)
+            (func (export "alloc") (param i32) (result i32) (i32.const 0))
+            (func (export "post_completion") (param i32 i32) (result i64)
+                (i64.const 0)))"#;
+        assert!(run("too-large", too_large).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,730 @@
+use crate::{
+    config::{LineMode, PostProcess},
+    memory_backends::Prompt,
+};
+
+// Lines ending with these open a block, `:` in Python and `=>` for closures and match arms
+const BLOCK_OPENERS: &[&str] = &["{", ":", "=>"];
+// And lines ending with these in Ruby, Lua and shell scripts
+const BLOCK_KEYWORDS: &[&str] = &["do", "then", "else", "begin"];
+
+fn post_process_start(response: String, front: &str) -> String {
+    let mut front_match = response.len();
+    loop {
+        if response.len() == 0 || front.ends_with(&response[..front_match]) {
+            break;
+        } else {
+            front_match -= 1;
+        }
+    }
+    if front_match > 0 {
+        (&response[front_match..]).to_owned()
+    } else {
+        response
+    }
+}
+
+fn post_process_end(response: String, back: &str) -> String {
+    let mut back_match = 0;
+    loop {
+        if back_match == response.len() {
+            break;
+        } else if back.starts_with(&response[back_match..]) {
+            break;
+        } else {
+            back_match += 1;
+        }
+    }
+    if back_match > 0 {
+        (&response[..back_match]).to_owned()
+    } else {
+        response
+    }
+}
+
+// Chat models wrap code in markdown fences and often explain it before and after
+fn extract_code_block(response: String) -> String {
+    let Some(start) = response.find("```") else {
+        return response;
+    };
+    // The opening fence is followed by the language up to the end of the line
+    let Some(newline) = response[start..].find('\n') else {
+        // A stray fence at the end of the response
+        if response[start + 3..].trim().is_empty() {
+            return response[..start].trim_end().to_owned();
+        }
+        return response;
+    };
+    let content = &response[start + newline + 1..];
+    let mut end = content.len();
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        if line.trim_start().starts_with("```") {
+            end = offset;
+            break;
+        }
+        offset += line.len();
+    }
+    // When the closing fence is missing the response was cut off and the rest is code
+    content[..end].trim_end_matches(['\n', '\r']).to_owned()
+}
+
+fn is_opener(c: char) -> bool {
+    matches!(c, '(' | '[' | '{')
+}
+
+fn is_closer(c: char) -> bool {
+    matches!(c, ')' | ']' | '}')
+}
+
+fn get_closer(opener: char) -> char {
+    match opener {
+        '(' => ')',
+        '[' => ']',
+        _ => '}',
+    }
+}
+
+struct Scan {
+    // The brackets outside of string literals with their byte offsets
+    brackets: Vec<(usize, char)>,
+    // Whether the text ends inside a string literal
+    in_string: bool,
+}
+
+// A rough scan that only knows about double quoted and backtick strings, single quotes are
+// lifetimes in too many languages to be treated as strings
+fn scan(text: &str) -> Scan {
+    let mut brackets = vec![];
+    let mut quote = None;
+    let mut escaped = false;
+    let mut skip = 0;
+    for (i, c) in text.char_indices() {
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q || c == '\n' {
+                    quote = None;
+                }
+            }
+            // Markdown fences are not strings
+            None if text[i..].starts_with("```") => skip = 2,
+            None if c == '"' || c == '`' => quote = Some(c),
+            None if is_opener(c) || is_closer(c) => brackets.push((i, c)),
+            None => (),
+        }
+    }
+    Scan {
+        brackets,
+        in_string: quote.is_some(),
+    }
+}
+
+// The bracket depth at `offset` and the lowest depth after it. Closing brackets opened before the
+// response make the depth negative.
+fn get_depths(brackets: &[(usize, char)], offset: usize) -> (i32, i32) {
+    let mut depth = 0;
+    let mut depth_at_offset = None;
+    let mut min_after = i32::MAX;
+    for (i, c) in brackets {
+        if *i >= offset && depth_at_offset.is_none() {
+            depth_at_offset = Some(depth);
+            min_after = depth;
+        }
+        depth += if is_opener(*c) { 1 } else { -1 };
+        if depth_at_offset.is_some() {
+            min_after = min_after.min(depth);
+        }
+    }
+    let depth_at_offset = depth_at_offset.unwrap_or(depth);
+    (depth_at_offset, min_after.min(depth_at_offset))
+}
+
+// Models that see the code after the cursor often write it again at the end of the response
+fn remove_suffix_overlap(response: String, back: &str) -> String {
+    let back_lines: Vec<&str> = back
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    let lines: Vec<&str> = response.split_inclusive('\n').collect();
+    let brackets = scan(&response).brackets;
+    let mut offset = 0;
+    for (i, line) in lines.iter().enumerate() {
+        let rest: Vec<&str> = lines[i..]
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .collect();
+        if !line.trim().is_empty() && back_lines.starts_with(&rest) {
+            // Keep lines that close brackets the rest of the response opened
+            let (depth, min_after) = get_depths(&brackets, offset);
+            if depth <= 0 || min_after >= depth {
+                return response[..offset].trim_end_matches(['\n', '\r']).to_owned();
+            }
+        }
+        offset += line.len();
+    }
+    response
+}
+
+// Tabs count as this many columns
+const TAB_WIDTH: usize = 4;
+
+// How the document indents its code
+struct IndentStyle {
+    tabs: bool,
+    // In columns
+    unit: usize,
+}
+
+fn get_indent(line: &str) -> &str {
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+fn count_columns(indent: &str) -> usize {
+    indent
+        .chars()
+        .map(|c| if c == '\t' { TAB_WIDTH } else { 1 })
+        .sum()
+}
+
+// Tabs when more lines start with one than with a space, the smallest indentation is the unit
+fn get_indent_style(text: &str) -> IndentStyle {
+    let indents: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(get_indent)
+        .filter(|indent| !indent.is_empty())
+        .collect();
+    let tabs = indents.iter().filter(|i| i.starts_with('\t')).count();
+    if tabs * 2 > indents.len() {
+        return IndentStyle {
+            tabs: true,
+            unit: TAB_WIDTH,
+        };
+    }
+    let unit = indents
+        .iter()
+        .map(|indent| count_columns(indent))
+        .min()
+        .unwrap_or(TAB_WIDTH)
+        .min(8);
+    IndentStyle { tabs: false, unit }
+}
+
+fn render_indent(columns: usize, style: &IndentStyle) -> String {
+    if style.tabs {
+        "\t".repeat(columns / TAB_WIDTH) + &" ".repeat(columns % TAB_WIDTH)
+    } else {
+        " ".repeat(columns)
+    }
+}
+
+// Chat models often indent the response from the first column or indent its first line again
+fn reindent(response: String, front: &str, back: &str) -> String {
+    let before = &front[front.rfind('\n').map_or(0, |i| i + 1)..];
+    let mut lines: Vec<String> = response.split('\n').map(str::to_owned).collect();
+    let style = get_indent_style(&format!("{front}{back}"));
+    let mut delta: isize = 0;
+    if !before.is_empty() && before.trim().is_empty() && !get_indent(&lines[0]).is_empty() {
+        // The document already indents the first line, at the first column the indentation of
+        // the response is the only one
+        let indent = count_columns(get_indent(&lines[0]));
+        lines[0] = lines[0].trim_start_matches([' ', '\t']).to_owned();
+        delta = count_columns(before) as isize - indent as isize;
+    } else if let Some(next) = lines[1..].iter().find(|line| !line.trim().is_empty()) {
+        let base = count_columns(get_indent(before)) as isize;
+        let unit = style.unit as isize;
+        let actual = count_columns(get_indent(next)) as isize;
+        // The next line is likely one level in, one level out or at the level of the cursor
+        if ![base - unit, base, base + unit].contains(&actual) {
+            let first = format!("{before}{}", lines[0]);
+            let next = next.trim_start();
+            let target = if BLOCK_OPENERS.iter().any(|o| first.trim_end().ends_with(o)) {
+                base + unit
+            } else if next.starts_with(is_closer) || next.starts_with("end") {
+                base - unit
+            } else {
+                base
+            };
+            delta = target - actual;
+        }
+    }
+    for line in &mut lines[1..] {
+        if line.trim().is_empty() {
+            line.clear();
+            continue;
+        }
+        let columns = (count_columns(get_indent(line)) as isize + delta).max(0) as usize;
+        *line = render_indent(columns, &style) + line.trim_start_matches([' ', '\t']);
+    }
+    lines.join("\n")
+}
+
+// What ends a line opening a block in a language, the endings and the keywords. Other languages
+// get BLOCK_OPENERS and BLOCK_KEYWORDS.
+fn get_block_openers(
+    language_id: &str,
+) -> Option<(&'static [&'static str], &'static [&'static str])> {
+    match language_id {
+        // `:` ends type annotations and `=>` is a comparison in Python
+        "python" => Some((&[":"], &[])),
+        "rust" | "kotlin" | "scala" | "swift" | "dart" => Some((&["{", "=>", "->"], &[])),
+        // `:` ends the labels of switch statements
+        "c" | "cpp" | "csharp" | "go" | "java" | "javascript" | "javascriptreact"
+        | "typescript" | "typescriptreact" | "php" => Some((&["{", "=>", ":"], &[])),
+        "ruby" => Some((&["{", "|"], &["do", "then", "else", "begin"])),
+        "lua" => Some((&[], &["do", "then", "else", "repeat"])),
+        "shellscript" => Some((&["{"], &["do", "then", "else"])),
+        "elixir" => Some((&["->"], &["do", "else"])),
+        _ => None,
+    }
+}
+
+// Whether the cursor is in the middle of a statement rather than at the start of a line or at the
+// end of a line opening a block with at most its closing brackets after the cursor
+fn is_mid_statement(front: &str, back: &str, language_id: Option<&str>) -> bool {
+    let before = front[front.rfind('\n').map_or(0, |i| i + 1)..].trim();
+    if before.is_empty() {
+        return false;
+    }
+    let after = back.split('\n').next().unwrap_or_default().trim();
+    let last_word = before
+        .rsplit(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default();
+    let (openers, keywords) = language_id
+        .and_then(get_block_openers)
+        .unwrap_or((BLOCK_OPENERS, BLOCK_KEYWORDS));
+    let opens_block =
+        openers.iter().any(|opener| before.ends_with(opener)) || keywords.contains(&last_word);
+    !(opens_block && after.chars().all(is_closer))
+}
+
+// Multi line responses that hit the token limit end in the middle of a line
+fn strip_partial_line(response: String) -> String {
+    let Some(last_newline) = response.rfind('\n') else {
+        return response;
+    };
+    let last_line = &response[last_newline + 1..];
+    if last_line.trim().is_empty() || response[..last_newline].trim().is_empty() {
+        return response;
+    }
+    let scan = scan(last_line);
+    let depth: i32 = scan
+        .brackets
+        .iter()
+        .map(|(_, c)| if is_opener(*c) { 1 } else { -1 })
+        .sum();
+    let partial = scan.in_string
+        || depth > 0
+        || last_line
+            .trim_end()
+            .ends_with(['.', '=', '+', '-', '*', '/', '&', '|', '\\']);
+    if partial {
+        response[..last_newline].trim_end_matches('\r').to_owned()
+    } else {
+        response
+    }
+}
+
+fn balance_brackets(response: String, back: &str) -> String {
+    let scan = scan(&response);
+    if scan.in_string {
+        return response;
+    }
+    let mut open = vec![];
+    // Closing brackets for brackets opened before the cursor
+    let mut unmatched = vec![];
+    for (i, c) in scan.brackets {
+        if is_opener(c) {
+            open.push((i, c));
+            continue;
+        }
+        match open.last() {
+            Some((_, opener)) if get_closer(*opener) == c => {
+                open.pop();
+            }
+            // The model lost track of the brackets so nothing after this can be trusted
+            Some(_) => return balance_brackets(response[..i].to_owned(), back),
+            None => unmatched.push((i, c)),
+        }
+    }
+
+    // The code after the cursor starts by closing the brackets opened before it, closing them
+    // again at the end of the response duplicates them
+    let tail_start = response
+        .trim_end_matches(|c: char| c.is_whitespace() || is_closer(c))
+        .len();
+    let duplicates: Vec<usize> = unmatched
+        .iter()
+        .zip(back.chars().filter(|c| !c.is_whitespace()))
+        .take_while(|((i, c), back_c)| *i >= tail_start && c == back_c)
+        .map(|((i, _), _)| *i)
+        .collect();
+
+    // Brackets opened on the last line are closed unless they start a block
+    let last_line_start = response.rfind('\n').map_or(0, |i| i + 1);
+    let closers: String = open
+        .iter()
+        .rev()
+        .take_while(|(i, c)| {
+            *i >= last_line_start && !(*c == '{' && response[i + 1..].trim().is_empty())
+        })
+        .map(|(_, c)| get_closer(*c))
+        .collect();
+
+    let mut response: String = response
+        .char_indices()
+        .filter(|(i, _)| !duplicates.contains(i))
+        .map(|(_, c)| c)
+        .collect();
+    response.push_str(&closers);
+    response
+}
+
+// Some basic post processing that will clean up duplicate characters at the front and back and
+// repair responses that repeat the code after the cursor or leave brackets unbalanced
+pub fn post_process_response(
+    response: String,
+    prompt: &Prompt,
+    language_id: Option<&str>,
+    config: &PostProcess,
+) -> String {
+    let (front, back) = match prompt {
+        Prompt::ContextAndCode(context_and_code) => {
+            if context_and_code.code.contains("<CURSOR>") {
+                let mut split = context_and_code.code.split("<CURSOR>");
+                (split.next().unwrap(), split.next())
+            } else {
+                (context_and_code.code.as_str(), None)
+            }
+        }
+        Prompt::FIM(fim) => (fim.prompt.as_str(), Some(fim.suffix.as_str())),
+    };
+    let response = if config.extract_code_block {
+        extract_code_block(response)
+    } else {
+        response
+    };
+    let response = if config.reindent {
+        reindent(response, front, back.unwrap_or_default())
+    } else {
+        response
+    };
+    let mut response = if config.remove_duplicate_start {
+        post_process_start(response, front)
+    } else {
+        response
+    };
+    if let Some(back) = back {
+        if config.remove_duplicate_end {
+            response = post_process_end(response, back);
+        }
+        if config.remove_suffix_overlap {
+            response = remove_suffix_overlap(response, back);
+        }
+    }
+    let single_line = match config.line_mode {
+        LineMode::Single => true,
+        LineMode::Multi => false,
+        LineMode::Auto => is_mid_statement(front, back.unwrap_or_default(), language_id),
+    };
+    if single_line {
+        if let Some(newline) = response.find('\n') {
+            response.truncate(newline);
+            response = response.trim_end_matches('\r').to_owned();
+        }
+    }
+    if config.strip_partial_lines {
+        response = strip_partial_line(response);
+    }
+    if config.balance_brackets {
+        response = balance_brackets(response, back.unwrap_or_default());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_backends::{ContextAndCodePrompt, FIMPrompt};
+
+    #[test]
+    fn test_post_process_fim() {
+        let config = PostProcess::default();
+
+        let prompt = Prompt::FIM(FIMPrompt {
+            prompt: "test 1234 ".to_string(),
+            suffix: "ttabc".to_string(),
+        });
+        let response = "4 zz tta".to_string();
+        let new_response = post_process_response(response.clone(), &prompt, None, &config);
+        assert_eq!(new_response, "zz ");
+
+        let prompt = Prompt::FIM(FIMPrompt {
+            prompt: "test".to_string(),
+            suffix: "test".to_string(),
+        });
+        let response = "zzzz".to_string();
+        let new_response = post_process_response(response.clone(), &prompt, None, &config);
+        assert_eq!(new_response, "zzzz");
+    }
+
+    #[test]
+    fn test_post_process_context_and_code() {
+        let config = PostProcess::default();
+
+        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt {
+            context: "".to_string(),
+            code: "tt ".to_string(),
+        });
+        let response = "tt abc".to_string();
+        let new_response = post_process_response(response.clone(), &prompt, None, &config);
+        assert_eq!(new_response, "abc");
+
+        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt {
+            context: "".to_string(),
+            code: "ff".to_string(),
+        });
+        let response = "zz".to_string();
+        let new_response = post_process_response(response.clone(), &prompt, None, &config);
+        assert_eq!(new_response, "zz");
+
+        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt {
+            context: "".to_string(),
+            code: "tt <CURSOR> tt".to_string(),
+        });
+        let response = "tt abc tt".to_string();
+        let new_response = post_process_response(response.clone(), &prompt, None, &config);
+        assert_eq!(new_response, "abc");
+
+        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt {
+            context: "".to_string(),
+            code: "d<CURSOR>d".to_string(),
+        });
+        let response = "zz".to_string();
+        let new_response = post_process_response(response.clone(), &prompt, None, &config);
+        assert_eq!(new_response, "zz");
+    }
+
+    fn fim(prompt: &str, suffix: &str) -> Prompt {
+        Prompt::FIM(FIMPrompt {
+            prompt: prompt.to_string(),
+            suffix: suffix.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_post_process_suffix_overlap() {
+        let config = PostProcess::default();
+
+        let prompt = fim("fn f() {\n    ", "\n}\n\nfn g() {}\n");
+        let response = "x += 1;\n    }".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "x += 1;"
+        );
+
+        // The closing brace belongs to the block the response opened
+        let response = "if y {\n        z();\n    }".to_string();
+        assert_eq!(
+            post_process_response(response.clone(), &prompt, None, &config),
+            response
+        );
+    }
+
+    #[test]
+    fn test_post_process_partial_lines() {
+        let config = PostProcess::default();
+        let prompt = fim("fn f() {\n    ", "\n}");
+
+        let response = "a();\n    b.c(".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "a();"
+        );
+
+        let response = "a();\n    let s = \"abc".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "a();"
+        );
+
+        let response = "a();\n    b();".to_string();
+        assert_eq!(
+            post_process_response(response.clone(), &prompt, None, &config),
+            response
+        );
+    }
+
+    #[test]
+    fn test_post_process_balance_brackets() {
+        let config = PostProcess::default();
+        let prompt = fim("let x = foo(", ")\n");
+
+        let response = "bar(1, 2".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "bar(1, 2)"
+        );
+
+        let response = "bar(1)) ".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "bar(1) "
+        );
+
+        let response = "bar[1)".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "bar[1]"
+        );
+
+        // Brackets in strings are ignored
+        let response = "\"(\", 1".to_string();
+        assert_eq!(
+            post_process_response(response.clone(), &prompt, None, &config),
+            response
+        );
+
+        let prompt = fim("fn f() ", "");
+        let response = "{".to_string();
+        assert_eq!(post_process_response(response, &prompt, None, &config), "{");
+    }
+
+    #[test]
+    fn test_post_process_line_mode() {
+        let config = PostProcess {
+            line_mode: LineMode::Auto,
+            ..PostProcess::default()
+        };
+        let response = "a + b;\n    c();".to_string();
+
+        // In the middle of a statement
+        let prompt = fim("fn f() {\n    let x = ", "\n}");
+        assert_eq!(
+            post_process_response(response.clone(), &prompt, None, &config),
+            "a + b;"
+        );
+        // At the start of a line and in an empty block
+        let prompt = fim("fn f() {\n    ", "\n}");
+        assert_eq!(
+            post_process_response(response.clone(), &prompt, None, &config),
+            response
+        );
+        let prompt = fim("fn f() {", "}");
+        assert_eq!(
+            post_process_response(response.clone(), &prompt, None, &config),
+            response
+        );
+        let prompt = fim("def f():", "");
+        assert_eq!(
+            post_process_response(response.clone(), &prompt, None, &config),
+            response
+        );
+
+        let config = PostProcess {
+            line_mode: LineMode::Single,
+            ..PostProcess::default()
+        };
+        let prompt = fim("fn f() {", "}");
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "a + b;"
+        );
+    }
+
+    #[test]
+    fn test_line_mode_languages() {
+        // A type annotation in Rust, a block in languages with switch labels
+        assert!(is_mid_statement("let x:", "", Some("rust")));
+        assert!(!is_mid_statement("case 1:", "", Some("java")));
+        assert!(!is_mid_statement("def f():", "", Some("python")));
+        assert!(!is_mid_statement("items.each do |item|", "", Some("ruby")));
+        assert!(is_mid_statement("x = a do", "", Some("python")));
+        assert!(!is_mid_statement("for i in x; do", "", Some("shellscript")));
+        // Languages without their own rules get the generic ones
+        assert!(!is_mid_statement("let x:", "", Some("plaintext")));
+        assert!(!is_mid_statement("let x:", "", None));
+    }
+
+    #[test]
+    fn test_post_process_reindent() {
+        let config = PostProcess::default();
+
+        // Indented from the first column
+        let prompt = fim("class A:\n    def f(self):\n        ", "");
+        let response = "x = 1\nreturn x".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "x = 1\n        return x"
+        );
+
+        // The first line is indented again
+        let prompt = fim("fn f() {\n    ", "\n}");
+        let response = "        a();\n        if b {\n            c();\n        }".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "a();\n    if b {\n        c();\n    }"
+        );
+
+        // With the tabs of the document
+        let prompt = fim("fn f() {\n\tif x {\n\t\t", "\n\t}\n}");
+        let response = "a();\nb();".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "a();\n\t\tb();"
+        );
+
+        // At the first column the indentation of the first line is kept
+        let prompt = fim("fn f() {\n", "\n}");
+        let response = "    a();\n    b();".to_string();
+        assert_eq!(
+            post_process_response(response.clone(), &prompt, None, &config),
+            response
+        );
+    }
+
+    #[test]
+    fn test_post_process_code_block() {
+        let config = PostProcess::default();
+        let prompt = fim("fn add(a: i32, b: i32) -> i32 {\n    ", "\n}");
+
+        let response = "Here is the code:\n```rust\na + b\n```\nThis adds the numbers.".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "a + b"
+        );
+
+        let response = "```\na + b".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "a + b"
+        );
+
+        let response = "a + b\n```".to_string();
+        assert_eq!(
+            post_process_response(response, &prompt, None, &config),
+            "a + b"
+        );
+
+        let config = PostProcess {
+            extract_code_block: false,
+            ..PostProcess::default()
+        };
+        let response = "```\na + b\n```".to_string();
+        assert_eq!(
+            post_process_response(response.clone(), &prompt, None, &config),
+            response
+        );
+    }
+}
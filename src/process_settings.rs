@@ -0,0 +1,169 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    config::{self, Config, Http, Log, Otlp},
+    http_client, logging, metrics,
+};
+
+// The options that configure the whole process, there is one http client, log file, exporter and
+// metrics server however many clients are connected
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Settings {
+    http: Option<Http>,
+    // With the rootUri the default log path is derived from
+    log: Option<(Log, Option<String>)>,
+    otlp: Option<Otlp>,
+    metrics: Option<config::Metrics>,
+}
+
+impl Settings {
+    fn new(config: &Config) -> Self {
+        Self {
+            http: config.config.http.clone(),
+            log: config
+                .config
+                .log
+                .clone()
+                .map(|log| (log, config.get_root_uri().map(str::to_owned))),
+            otlp: config.config.otlp.clone(),
+            metrics: config.config.metrics.clone(),
+        }
+    }
+
+    // Checks the options without applying any of them
+    fn validate(&self) -> anyhow::Result<()> {
+        http_client::validate(self.http.as_ref())?;
+        if let Some((log, root_uri)) = &self.log {
+            logging::validate(Some(log), root_uri.as_deref())?;
+        }
+        logging::validate_otlp(self.otlp.as_ref())?;
+        metrics::validate(self.metrics.as_ref())
+    }
+}
+
+// Checks the http, log, otlp and metrics options of the config without applying them
+pub fn validate(config: &Config) -> anyhow::Result<()> {
+    Settings::new(config).validate()
+}
+
+#[derive(Default)]
+struct State {
+    // In the order the clients connected
+    clients: Vec<(u64, Settings)>,
+    // None until the first client is configured
+    applied: Option<Settings>,
+}
+
+impl State {
+    // For each option, the first client that set it wins
+    fn first<T: Clone>(&self, get: impl Fn(&Settings) -> &Option<T>) -> Option<T> {
+        self.clients
+            .iter()
+            .find_map(|(_, settings)| get(settings).clone())
+    }
+
+    fn merge(&self) -> Settings {
+        Settings {
+            http: self.first(|settings| &settings.http),
+            log: self.first(|settings| &settings.log),
+            otlp: self.first(|settings| &settings.otlp),
+            metrics: self.first(|settings| &settings.metrics),
+        }
+    }
+
+    // Only the options that changed are applied again
+    fn apply(&mut self) -> anyhow::Result<()> {
+        let merged = self.merge();
+        let applied = self.applied.get_or_insert_with(|| Settings {
+            // The http client starts without the proxy of the env, so it is always configured
+            http: Some(Http::default()),
+            ..Default::default()
+        });
+        if merged.http != applied.http {
+            http_client::configure(merged.http.as_ref())?;
+            applied.http = merged.http.clone();
+        }
+        if merged.log != applied.log {
+            let (log, root_uri) = match &merged.log {
+                Some((log, root_uri)) => (Some(log), root_uri.as_deref()),
+                None => (None, None),
+            };
+            logging::configure(log, root_uri)?;
+            applied.log = merged.log.clone();
+        }
+        if merged.otlp != applied.otlp {
+            logging::configure_otlp(merged.otlp.as_ref())?;
+            applied.otlp = merged.otlp.clone();
+        }
+        if merged.metrics != applied.metrics {
+            metrics::configure(merged.metrics.as_ref())?;
+            applied.metrics = merged.metrics.clone();
+        }
+        Ok(())
+    }
+}
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| Mutex::new(State::default()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// The settings of one connected client. They stop applying when it is dropped and the next
+// client that set them takes over.
+pub struct Client {
+    id: u64,
+}
+
+impl Client {
+    pub fn connect() -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    // Keeps the settings the client had when the new ones fail to apply
+    pub fn configure(&self, config: &Config) -> anyhow::Result<()> {
+        let settings = Settings::new(config);
+        settings.validate()?;
+        let mut state = STATE.lock();
+        let previous = match state.clients.iter_mut().find(|(id, _)| *id == self.id) {
+            Some((_, current)) => Some(std::mem::replace(current, settings.clone())),
+            None => {
+                state.clients.push((self.id, settings.clone()));
+                None
+            }
+        };
+        if let Err(e) = state.apply() {
+            match previous {
+                Some(previous) => {
+                    if let Some((_, current)) =
+                        state.clients.iter_mut().find(|(id, _)| *id == self.id)
+                    {
+                        *current = previous;
+                    }
+                }
+                None => state.clients.retain(|(id, _)| *id != self.id),
+            }
+            if let Err(e) = state.apply() {
+                error!("error restoring the configuration: {e:?}");
+            }
+            return Err(e);
+        }
+        if state.merge() != settings {
+            info!("the http, log, otlp or metrics options of a client that connected earlier apply to this one too");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let mut state = STATE.lock();
+        state.clients.retain(|(id, _)| *id != self.id);
+        if let Err(e) = state.apply() {
+            error!("error applying the configuration of the other clients: {e:?}");
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Weak,
+    },
+};
+
+use lsp_server::{Connection, Message, Notification, Request, RequestId};
+use lsp_types::{
+    NumberOrString, ProgressParams, ProgressParamsValue, ProgressToken, WorkDoneProgress,
+    WorkDoneProgressBegin, WorkDoneProgressCreateParams, WorkDoneProgressEnd,
+    WorkDoneProgressReport,
+};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use tracing::error;
+
+// The clients that support `window/workDoneProgress`. Removed when the client disconnects so the
+// connection can close.
+static CONNECTIONS: Lazy<RwLock<Vec<Arc<Connection>>>> = Lazy::new(|| RwLock::new(Vec::new()));
+static NEXT_TOKEN: AtomicU32 = AtomicU32::new(0);
+
+thread_local! {
+    // The client the threads of its workers serve, work started on them is reported to it only
+    static CONNECTION: RefCell<Option<Weak<Connection>>> = const { RefCell::new(None) };
+}
+
+pub fn add(connection: Arc<Connection>) {
+    CONNECTIONS.write().push(connection);
+}
+
+pub fn remove(connection: &Arc<Connection>) {
+    CONNECTIONS
+        .write()
+        .retain(|other| !Arc::ptr_eq(other, connection));
+}
+
+// Reports the work started on the current thread to the client
+pub fn enter(connection: &Arc<Connection>) {
+    let connection = Arc::downgrade(connection);
+    CONNECTION.with(|current| *current.borrow_mut() = Some(connection));
+}
+
+// For `on_thread_start` of the runtimes of the workers of the client
+pub fn enter_on_thread_start(connection: &Arc<Connection>) -> impl Fn() + Send + Sync + 'static {
+    let connection = Arc::downgrade(connection);
+    move || CONNECTION.with(|current| *current.borrow_mut() = Some(connection.clone()))
+}
+
+// The client of the current thread if it supports work done progress
+fn get_connection() -> Option<Arc<Connection>> {
+    let connection = CONNECTION.with(|current| current.borrow().as_ref()?.upgrade())?;
+    CONNECTIONS
+        .read()
+        .iter()
+        .any(|other| Arc::ptr_eq(other, &connection))
+        .then_some(connection)
+}
+
+fn send(connection: &Connection, message: Message) {
+    if let Err(e) = connection.sender.send(message) {
+        error!("sending work done progress: {e}");
+    }
+}
+
+// Reports the progress of long running work to the client. Does nothing if the client does not
+// support work done progress.
+pub struct ProgressReporter {
+    connection: Option<Arc<Connection>>,
+    token: ProgressToken,
+}
+
+impl ProgressReporter {
+    pub fn begin(title: &str, message: Option<String>) -> Self {
+        let id = format!(
+            "lsp-ai/progress/{}",
+            NEXT_TOKEN.fetch_add(1, Ordering::Relaxed)
+        );
+        let reporter = Self {
+            connection: get_connection(),
+            token: NumberOrString::String(id.clone()),
+        };
+        if let Some(connection) = &reporter.connection {
+            send(
+                connection,
+                Message::Request(Request::new(
+                    RequestId::from(id.clone()),
+                    "window/workDoneProgress/create".to_string(),
+                    WorkDoneProgressCreateParams {
+                        token: reporter.token.clone(),
+                    },
+                )),
+            );
+        }
+        reporter.send_progress(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: title.to_string(),
+            cancellable: Some(false),
+            message,
+            percentage: None,
+        }));
+        reporter
+    }
+
+    pub fn report(&self, message: String, percentage: Option<u32>) {
+        self.send_progress(WorkDoneProgress::Report(WorkDoneProgressReport {
+            cancellable: Some(false),
+            message: Some(message),
+            percentage,
+        }));
+    }
+
+    pub fn end(mut self, message: Option<String>) {
+        self.send_end(message);
+    }
+
+    // The client stops showing the progress, it is not sent anything after it
+    fn send_end(&mut self, message: Option<String>) {
+        self.send_progress(WorkDoneProgress::End(WorkDoneProgressEnd { message }));
+        self.connection = None;
+    }
+
+    fn send_progress(&self, progress: WorkDoneProgress) {
+        if let Some(connection) = &self.connection {
+            send(
+                connection,
+                Message::Notification(Notification::new(
+                    "$/progress".to_string(),
+                    ProgressParams {
+                        token: self.token.clone(),
+                        value: ProgressParamsValue::WorkDone(progress),
+                    },
+                )),
+            );
+        }
+    }
+}
+
+// Work that fails before it reports its end still ends the progress
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        if self.connection.is_some() {
+            self.send_end(None);
+        }
+    }
+}
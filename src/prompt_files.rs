@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_json::Value;
+
+use crate::config::Config;
+
+struct CachedFile {
+    modified: SystemTime,
+    contents: String,
+}
+
+// Files are read again when they change on disk so edits apply without restarting
+static FILES: Lazy<Mutex<HashMap<PathBuf, CachedFile>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Relative paths are resolved against the workspace root and then the lsp-ai config directory
+fn find(path: &str, config: &Config) -> anyhow::Result<PathBuf> {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return Ok(path.to_owned());
+    }
+    let root = config
+        .get_root_uri()
+        .and_then(|root_uri| root_uri.parse::<lsp_types::Url>().ok())
+        .and_then(|root_uri| root_uri.to_file_path().ok());
+    let config_dir = directories::ProjectDirs::from("", "", "lsp-ai")
+        .map(|project_dirs| project_dirs.config_dir().to_owned());
+    root.into_iter()
+        .chain(config_dir)
+        .map(|dir| dir.join(path))
+        .find(|path| path.is_file())
+        .with_context(|| format!("prompt file `{}` not found", path.display()))
+}
+
+fn read(path: &Path) -> anyhow::Result<String> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    let mut files = FILES.lock();
+    if let Some(file) = files.get(path).filter(|file| file.modified == modified) {
+        return Ok(file.contents.clone());
+    }
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("reading prompt file `{}`", path.display()))?;
+    files.insert(
+        path.to_owned(),
+        CachedFile {
+            modified,
+            contents: contents.clone(),
+        },
+    );
+    Ok(contents)
+}
+
+fn read_field(
+    object: &mut serde_json::Map<String, Value>,
+    key: &str,
+    config: &Config,
+) -> anyhow::Result<Option<String>> {
+    let Some(path) = object.remove(key) else {
+        return Ok(None);
+    };
+    let path = path
+        .as_str()
+        .with_context(|| format!("`{key}` must be a string"))?;
+    Ok(Some(read(&find(path, config)?)?))
+}
+
+// Replaces `chat_template_file` with `chat_template` and `content_file` in the messages with
+// `content`, read from the files they point to
+pub fn resolve_prompt_files(params: &mut Value, config: &Config) -> anyhow::Result<()> {
+    let Some(object) = params.as_object_mut() else {
+        return Ok(());
+    };
+    if let Some(chat_template) = read_field(object, "chat_template_file", config)? {
+        object.insert("chat_template".to_string(), Value::String(chat_template));
+    }
+    let Some(messages) = object.get_mut("messages").and_then(Value::as_array_mut) else {
+        return Ok(());
+    };
+    for message in messages.iter_mut().filter_map(Value::as_object_mut) {
+        if let Some(content) = read_field(message, "content_file", config)? {
+            message.insert("content".to_string(), Value::String(content));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn resolves_prompt_files() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("lsp-ai-prompts-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(root.join("prompts"))?;
+        std::fs::write(root.join("prompts/chat.jinja"), "{{ messages }}")?;
+        std::fs::write(root.join("prompts/system.md"), "You are a coding assistant")?;
+        let config = Config::new(json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {}
+            },
+            "rootUri": lsp_types::Url::from_file_path(&root).unwrap()
+        }))?;
+
+        let mut params = json!({
+            "chat_template_file": "prompts/chat.jinja",
+            "messages": [
+                { "role": "system", "content_file": "prompts/system.md" },
+                { "role": "user", "content": "{CODE}" }
+            ]
+        });
+        resolve_prompt_files(&mut params, &config)?;
+        assert_eq!(
+            params,
+            json!({
+                "chat_template": "{{ messages }}",
+                "messages": [
+                    { "role": "system", "content": "You are a coding assistant" },
+                    { "role": "user", "content": "{CODE}" }
+                ]
+            })
+        );
+
+        let mut params = json!({ "chat_template_file": "prompts/missing.jinja" });
+        assert!(resolve_prompt_files(&mut params, &config).is_err());
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
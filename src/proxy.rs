@@ -0,0 +1,467 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use lsp_server::Connection;
+use serde_json::{json, Map, Value};
+use tracing::{error, info};
+
+use crate::{
+    config::ChatMessage,
+    custom_requests::raw_generation::{RawGenerationParams, RawGenerationResult},
+    headless::{self, Client},
+    workspace_config,
+};
+
+const USAGE: &str = "usage: lsp-ai --proxy <address> [--config <file>] [--workspace-context]";
+
+// Fields of the OpenAI requests that are not parameters for the models
+const REQUEST_FIELDS: &[&str] = &[
+    "model",
+    "messages",
+    "prompt",
+    "stream",
+    "stream_options",
+    "n",
+    "user",
+    "tools",
+    "tool_choice",
+];
+
+pub struct Args {
+    address: String,
+    config: Option<PathBuf>,
+    // Adds the most relevant chunks of the workspace to every request
+    workspace_context: bool,
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let address = args.next().context(USAGE)?;
+    let mut config = None;
+    let mut workspace_context = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                config = Some(PathBuf::from(
+                    args.next()
+                        .with_context(|| format!("{arg} needs a value\n{USAGE}"))?,
+                ))
+            }
+            "--workspace-context" => workspace_context = true,
+            _ => anyhow::bail!("unknown argument {arg}\n{USAGE}"),
+        }
+    }
+    Ok(Args {
+        address,
+        config,
+        workspace_context,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Endpoint {
+    ChatCompletions,
+    Completions,
+}
+
+// Bodies are JSON requests, larger ones are refused before they are read
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+struct HttpResponse {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl HttpResponse {
+    fn json(status: &'static str, body: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    // Errors in the shape the OpenAI API sends them
+    fn error(status: &'static str, message: String) -> Self {
+        Self::json(
+            status,
+            json!({"error": {"message": message, "type": "invalid_request_error"}}),
+        )
+    }
+}
+
+// Browsers send the Origin of the page, only pages served from this machine may call the models
+pub fn is_local_origin(origin: &str) -> bool {
+    lsp_types::Url::parse(origin)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .is_some_and(|host| matches!(host.as_str(), "localhost" | "127.0.0.1" | "[::1]"))
+}
+
+// A request refused before its body is read gets the response to send
+fn read_request(stream: &TcpStream) -> anyhow::Result<Result<HttpRequest, HttpResponse>> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("invalid request line: {request_line}");
+    };
+    let mut content_length = 0;
+    let mut content_type = None;
+    let mut origin = None;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().context("invalid Content-Length")?;
+            } else if name.eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.to_owned());
+            }
+        }
+    }
+    if let Some(origin) = origin.filter(|origin| !is_local_origin(origin)) {
+        return Ok(Err(HttpResponse::error(
+            "403 Forbidden",
+            format!("requests from {origin} are not allowed"),
+        )));
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Ok(Err(HttpResponse::error(
+            "413 Payload Too Large",
+            format!("the body must be at most {MAX_BODY_BYTES} bytes"),
+        )));
+    }
+    // Browsers send other content types without asking first
+    let is_json = content_type.as_deref().is_some_and(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+    });
+    if content_length > 0 && !is_json {
+        return Ok(Err(HttpResponse::error(
+            "415 Unsupported Media Type",
+            "the body must be application/json".to_string(),
+        )));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Ok(HttpRequest {
+        method: method.to_owned(),
+        // Query strings are ignored
+        path: path.split('?').next().unwrap_or_default().to_owned(),
+        body,
+    }))
+}
+
+fn write_response(mut stream: &TcpStream, response: HttpResponse) -> anyhow::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    Ok(())
+}
+
+// Content can be a string or a list of parts of which only the text is kept
+fn get_text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part.get("text").and_then(Value::as_str))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+// The OpenAI request as a raw generation and whether it asked for a stream
+fn to_raw_generation(
+    endpoint: Endpoint,
+    body: Value,
+    workspace_context: bool,
+) -> anyhow::Result<(RawGenerationParams, bool)> {
+    let Value::Object(body) = body else {
+        anyhow::bail!("the body must be a JSON object");
+    };
+    let model = body
+        .get("model")
+        .and_then(Value::as_str)
+        .context("`model` must be the key of a model in the `models` config")?
+        .to_owned();
+    let stream = body.get("stream").and_then(Value::as_bool).unwrap_or(false);
+    let (messages, prompt) = match endpoint {
+        Endpoint::ChatCompletions => {
+            let messages = body
+                .get("messages")
+                .and_then(Value::as_array)
+                .context("`messages` must be a list")?
+                .iter()
+                .map(|message| {
+                    let role = message.get("role").and_then(Value::as_str);
+                    ChatMessage::new(
+                        role.unwrap_or("user").to_owned(),
+                        get_text(message.get("content").unwrap_or(&Value::Null)),
+                    )
+                })
+                .collect();
+            (Some(messages), None)
+        }
+        Endpoint::Completions => {
+            let prompt = match body.get("prompt") {
+                Some(Value::String(prompt)) => prompt.clone(),
+                Some(Value::Array(prompts)) => prompts
+                    .first()
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_owned(),
+                _ => anyhow::bail!("`prompt` must be a string"),
+            };
+            (None, Some(prompt))
+        }
+    };
+    let parameters: Map<String, Value> = body
+        .into_iter()
+        .filter(|(key, _)| !REQUEST_FIELDS.contains(&key.as_str()))
+        .collect();
+    let params = RawGenerationParams {
+        model,
+        messages,
+        prompt,
+        parameters: Value::Object(parameters),
+        workspace_context,
+    };
+    Ok((params, stream))
+}
+
+// The response in the shape of the OpenAI API. Streams get the whole text as one event.
+fn to_openai_response(
+    endpoint: Endpoint,
+    model: &str,
+    result: RawGenerationResult,
+    stream: bool,
+) -> HttpResponse {
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (object, choice) = match (endpoint, stream) {
+        (Endpoint::ChatCompletions, false) => (
+            "chat.completion",
+            json!({"index": 0, "message": {"role": "assistant", "content": result.generated_text}, "finish_reason": "stop"}),
+        ),
+        (Endpoint::ChatCompletions, true) => (
+            "chat.completion.chunk",
+            json!({"index": 0, "delta": {"role": "assistant", "content": result.generated_text}, "finish_reason": "stop"}),
+        ),
+        (Endpoint::Completions, _) => (
+            "text_completion",
+            json!({"index": 0, "text": result.generated_text, "finish_reason": "stop"}),
+        ),
+    };
+    let mut body = json!({
+        "id": format!("lsp-ai-{created}"),
+        "object": object,
+        "created": created,
+        "model": result.served_by.as_deref().unwrap_or(model),
+        "choices": [choice]
+    });
+    if let Some(usage) = result.usage {
+        body["usage"] = json!({
+            "prompt_tokens": usage.prompt_tokens,
+            "completion_tokens": usage.completion_tokens,
+            "total_tokens": usage.prompt_tokens + usage.completion_tokens
+        });
+    }
+    if !stream {
+        return HttpResponse::json("200 OK", body);
+    }
+    HttpResponse {
+        status: "200 OK",
+        content_type: "text/event-stream",
+        body: format!("data: {body}\n\ndata: [DONE]\n\n"),
+    }
+}
+
+fn handle_request(
+    client: &mut Client,
+    request: HttpRequest,
+    models: &[String],
+    workspace_context: bool,
+) -> HttpResponse {
+    let endpoint = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/v1/models") => {
+            let data: Vec<Value> = models
+                .iter()
+                .map(|model| json!({"id": model, "object": "model", "owned_by": "lsp-ai"}))
+                .collect();
+            return HttpResponse::json("200 OK", json!({"object": "list", "data": data}));
+        }
+        ("POST", "/v1/chat/completions") => Endpoint::ChatCompletions,
+        ("POST", "/v1/completions") => Endpoint::Completions,
+        (method, path) => {
+            return HttpResponse::error("404 Not Found", format!("{method} {path} not found"))
+        }
+    };
+    let parsed = serde_json::from_slice(&request.body)
+        .map_err(anyhow::Error::from)
+        .and_then(|body| to_raw_generation(endpoint, body, workspace_context));
+    let (params, stream) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return HttpResponse::error("400 Bad Request", format!("{e:#}")),
+    };
+    let model = params.model.clone();
+    let result = client
+        .request(
+            "lsp-ai/rawGeneration",
+            serde_json::to_value(params).unwrap(),
+        )
+        .and_then(|result| Ok(serde_json::from_value::<RawGenerationResult>(result)?));
+    match result {
+        Ok(result) => to_openai_response(endpoint, &model, result, stream),
+        Err(e) => HttpResponse::error("502 Bad Gateway", format!("{e:#}")),
+    }
+}
+
+// The model keys of the config file and the workspace config of the working directory
+fn get_models(options: &Value) -> anyhow::Result<Vec<String>> {
+    let mut options = options.clone();
+    if let Some(path) = workspace_config::find(headless::get_root_uri()?.as_str()) {
+        // Ignored like the server ignores it when it is invalid
+        match workspace_config::load(&path) {
+            Ok(workspace_options) => {
+                let trusted = workspace_config::is_trusted(&options);
+                let workspace_options = workspace_config::filter(&path, workspace_options, trusted);
+                workspace_config::merge(&mut options, workspace_options);
+            }
+            Err(e) => error!("ignoring {}: {e:#}", path.display()),
+        }
+    }
+    let mut models: Vec<String> = options
+        .get("models")
+        .and_then(Value::as_object)
+        .map(|models| models.keys().cloned().collect())
+        .unwrap_or_default();
+    models.sort();
+    Ok(models)
+}
+
+// Serves the OpenAI chat completions and completions APIs on the address with a server running
+// in this process, so other tools can use the models, fallbacks and context of lsp-ai. Requests
+// are answered one at a time.
+pub fn run(args: Args, serve: fn(Connection) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let options = match &args.config {
+        Some(config) => workspace_config::load(config)?,
+        None => Value::Null,
+    };
+    let models = get_models(&options)?;
+    let listener = TcpListener::bind(&args.address)
+        .with_context(|| format!("error binding {}", args.address))?;
+    let mut client = Client::start(serve, &headless::get_root_uri()?, options)?;
+    info!(
+        "serving the OpenAI API on http://{}/v1",
+        listener.local_addr()?
+    );
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("error accepting a proxy connection: {e}");
+                continue;
+            }
+        };
+        let result = stream
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| read_request(&stream))
+            .and_then(|request| {
+                let response = match request {
+                    Ok(request) => {
+                        handle_request(&mut client, request, &models, args.workspace_context)
+                    }
+                    Err(refused) => refused,
+                };
+                write_response(&stream, response)
+            });
+        if let Err(e) = result {
+            error!("error serving a proxy request: {e}");
+        }
+    }
+    client.shutdown()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage::TokenUsage;
+
+    #[test]
+    fn translates_openai_requests() -> anyhow::Result<()> {
+        let body = json!({
+            "model": "model1",
+            "messages": [
+                {"role": "system", "content": "Be brief"},
+                {"role": "user", "content": [{"type": "text", "text": "Hi"}]}
+            ],
+            "stream": true,
+            "max_tokens": 64
+        });
+        let (params, stream) = to_raw_generation(Endpoint::ChatCompletions, body, true)?;
+        assert!(stream);
+        assert_eq!(params.model, "model1");
+        assert_eq!(
+            params.messages,
+            Some(vec![
+                ChatMessage::new("system".to_string(), "Be brief".to_string()),
+                ChatMessage::new("user".to_string(), "Hi".to_string()),
+            ])
+        );
+        assert_eq!(params.parameters, json!({"max_tokens": 64}));
+        assert!(to_raw_generation(Endpoint::Completions, json!({"model": "m"}), false).is_err());
+
+        let result = RawGenerationResult {
+            generated_text: "Hello".to_string(),
+            usage: Some(TokenUsage::new(3, 1)),
+            served_by: None,
+        };
+        let response = to_openai_response(Endpoint::Completions, "model1", result, false);
+        let body: Value = serde_json::from_str(&response.body)?;
+        assert_eq!(body["choices"][0]["text"], "Hello");
+        assert_eq!(body["usage"]["total_tokens"], 4);
+        Ok(())
+    }
+
+    #[test]
+    fn allows_local_origins() {
+        assert!(is_local_origin("http://localhost:3000"));
+        assert!(is_local_origin("http://127.0.0.1"));
+        assert!(is_local_origin("http://[::1]:8080"));
+        assert!(!is_local_origin("https://example.com"));
+        assert!(!is_local_origin("http://localhost.example.com"));
+        assert!(!is_local_origin("null"));
+    }
+}
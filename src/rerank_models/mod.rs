@@ -0,0 +1,132 @@
+use std::collections::HashSet;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::auth::get_auth_token;
+use crate::config::{self, ValidRerankModel};
+use crate::http_client::get_client;
+
+const COHERE_ENDPOINT: &str = "https://api.cohere.com/v2/rerank";
+const VOYAGE_ENDPOINT: &str = "https://api.voyageai.com/v1/rerank";
+
+#[derive(Deserialize)]
+struct RerankResult {
+    index: usize,
+    relevance_score: f32,
+}
+
+// Cohere returns `results`, Voyage `data`
+#[derive(Deserialize)]
+struct RerankResponse {
+    #[serde(alias = "data")]
+    results: Option<Vec<RerankResult>>,
+    #[serde(alias = "detail", alias = "message")]
+    error: Option<Value>,
+}
+
+pub struct RerankModel {
+    configuration: ValidRerankModel,
+}
+
+impl RerankModel {
+    pub fn new(configuration: ValidRerankModel) -> Self {
+        Self { configuration }
+    }
+
+    fn get_config(&self) -> &config::RerankModel {
+        match &self.configuration {
+            ValidRerankModel::Cohere(configuration) | ValidRerankModel::Voyage(configuration) => {
+                configuration
+            }
+        }
+    }
+
+    pub fn top_n(&self) -> usize {
+        self.get_config().top_n
+    }
+
+    // The indexes of the `top_n` documents most relevant to the query, most relevant first
+    pub async fn rerank(&self, query: &str, documents: &[String]) -> anyhow::Result<Vec<usize>> {
+        if documents.is_empty() {
+            return Ok(vec![]);
+        }
+        let configuration = self.get_config();
+        let top_n = configuration.top_n.min(documents.len());
+        let (endpoint, body) = match &self.configuration {
+            ValidRerankModel::Cohere(_) => (
+                COHERE_ENDPOINT,
+                json!({
+                    "model": configuration.model,
+                    "query": query,
+                    "documents": documents,
+                    "top_n": top_n
+                }),
+            ),
+            ValidRerankModel::Voyage(_) => (
+                VOYAGE_ENDPOINT,
+                json!({
+                    "model": configuration.model,
+                    "query": query,
+                    "documents": documents,
+                    "top_k": top_n
+                }),
+            ),
+        };
+        let mut request = get_client()
+            .post(configuration.endpoint.as_deref().unwrap_or(endpoint))
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json");
+        // Local servers typically do not require a token
+        if let Some(token) = get_auth_token(
+            configuration.auth_token_env_var_name.as_deref(),
+            configuration.auth_token.as_deref(),
+            configuration.auth_token_keyring.as_ref(),
+            configuration.auth_token_command.as_deref(),
+        )
+        .await?
+        {
+            request = request.bearer_auth(token);
+        }
+        let res: RerankResponse = request.json(&body).send().await?.json().await?;
+        if let Some(error) = res.error {
+            anyhow::bail!("{:?}", error.to_string())
+        }
+        let results = res
+            .results
+            .ok_or_else(|| anyhow::anyhow!("Unknown error while reranking"))?;
+        Ok(order_by_score(results, documents.len(), top_n))
+    }
+}
+
+// Some servers return every document in the order they were sent
+fn order_by_score(mut results: Vec<RerankResult>, length: usize, top_n: usize) -> Vec<usize> {
+    let mut seen = HashSet::new();
+    results.retain(|result| result.index < length && seen.insert(result.index));
+    results.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+    results
+        .into_iter()
+        .map(|result| result.index)
+        .take(top_n)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_reranked_documents() -> anyhow::Result<()> {
+        let res: RerankResponse = serde_json::from_value(json!({
+            "data": [
+                {"index": 0, "relevance_score": 0.1},
+                {"index": 1, "relevance_score": 0.9},
+                {"index": 5, "relevance_score": 1.0},
+                {"index": 2, "relevance_score": 0.5},
+                {"index": 1, "relevance_score": 0.9}
+            ]
+        }))?;
+        assert_eq!(order_by_score(res.results.unwrap(), 3, 2), vec![1, 2]);
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use crate::config;
+
+use super::{Chunk, Splitter, TextSplitter};
+
+// Splits markdown into its sections so chunks do not straddle headings. The symbols of a chunk
+// are the headings it is under, sections larger than `chunk_size` are split further at line
+// endings.
+pub struct MarkdownSplitter {
+    text_splitter: TextSplitter,
+}
+
+impl MarkdownSplitter {
+    pub fn new(config: config::TextSplitter) -> Self {
+        Self {
+            text_splitter: TextSplitter::new(config),
+        }
+    }
+}
+
+// The level and title of an ATX heading like `## Title`
+fn get_heading(line: &str) -> Option<(usize, &str)> {
+    let line = line.trim_end();
+    let level = line.chars().take_while(|c| *c == '#').count();
+    let rest = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+    Some((level, rest.trim().trim_end_matches('#').trim_end()))
+}
+
+impl Splitter for MarkdownSplitter {
+    fn split(&self, _uri: &str, text: &str) -> Vec<Chunk> {
+        let mut chunks = vec![];
+        // The headings the current section is under by level
+        let mut headings: Vec<(usize, String)> = vec![];
+        let mut section_start = 0;
+        let mut in_code_block = false;
+        let mut offset = 0;
+        for line in text.split_inclusive('\n') {
+            let start = offset;
+            offset += line.len();
+            if line.trim_start().starts_with("```") {
+                in_code_block = !in_code_block;
+                continue;
+            }
+            if in_code_block {
+                continue;
+            }
+            let Some((level, title)) = get_heading(line) else {
+                continue;
+            };
+            let symbols: Vec<String> = headings.iter().map(|(_, h)| h.clone()).collect();
+            chunks.extend(
+                self.text_splitter
+                    .split_range(text, section_start..start, &symbols),
+            );
+            headings.retain(|(l, _)| *l < level);
+            headings.push((level, title.to_owned()));
+            section_start = start;
+        }
+        let symbols: Vec<String> = headings.iter().map(|(_, h)| h.clone()).collect();
+        chunks.extend(
+            self.text_splitter
+                .split_range(text, section_start..text.len(), &symbols),
+        );
+        chunks.retain(|chunk| !chunk.text.trim().is_empty());
+        chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_splitter_splits_at_headings() {
+        let splitter = MarkdownSplitter::new(config::TextSplitter {
+            chunk_size: 1000,
+            chunk_overlap: 0,
+        });
+        let text =
+            "intro\n# Install\nrun it\n## Linux\n```sh\n# not a heading\n```\n# Usage\ncall it\n";
+        let chunks = splitter.split("README.md", text);
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "intro\n",
+                "# Install\nrun it\n",
+                "## Linux\n```sh\n# not a heading\n```\n",
+                "# Usage\ncall it\n"
+            ]
+        );
+        assert_eq!(chunks[2].symbols, vec!["Install", "Linux"]);
+        assert_eq!(chunks[3].symbols, vec!["Usage"]);
+    }
+}
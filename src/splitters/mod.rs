@@ -0,0 +1,46 @@
+use std::ops::Range;
+
+use crate::config::ValidSplitter;
+
+mod markdown_splitter;
+mod text_splitter;
+mod tree_sitter_splitter;
+
+pub use markdown_splitter::MarkdownSplitter;
+pub use text_splitter::TextSplitter;
+pub use tree_sitter_splitter::{
+    get_imported_names, get_outline, get_region, get_signatures, get_surrounding_definitions,
+    OutlineSymbol, TreeSitter,
+};
+
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub text: String,
+    // The byte range of the chunk in the file
+    pub range: Range<usize>,
+    // The names of the functions, classes, etc. the chunk contains or is nested in
+    pub symbols: Vec<String>,
+}
+
+impl Chunk {
+    fn new(text: &str, range: Range<usize>, symbols: Vec<String>) -> Self {
+        Self {
+            text: text[range.clone()].to_owned(),
+            range,
+            symbols,
+        }
+    }
+}
+
+pub trait Splitter {
+    fn split(&self, uri: &str, text: &str) -> Vec<Chunk>;
+}
+
+impl From<ValidSplitter> for Box<dyn Splitter + Send + Sync> {
+    fn from(value: ValidSplitter) -> Self {
+        match value {
+            ValidSplitter::TreeSitter(config) => Box::new(TreeSitter::new(config)),
+            ValidSplitter::TextSplitter(config) => Box::new(TextSplitter::new(config)),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use crate::config;
+
+use super::{Chunk, Splitter};
+
+// Splits text into chunks of at most `chunk_size` bytes, preferring to break at line endings
+pub struct TextSplitter {
+    chunk_size: usize,
+    chunk_overlap: usize,
+}
+
+impl TextSplitter {
+    pub fn new(config: config::TextSplitter) -> Self {
+        Self {
+            chunk_size: config.chunk_size.max(1),
+            chunk_overlap: config.chunk_overlap,
+        }
+    }
+
+    pub fn new_with_chunk_size(chunk_size: usize, chunk_overlap: usize) -> Self {
+        Self::new(config::TextSplitter {
+            chunk_size,
+            chunk_overlap,
+        })
+    }
+
+    // Splits `text[range]` into chunks with ranges relative to the start of `text`
+    pub fn split_range(
+        &self,
+        text: &str,
+        range: std::ops::Range<usize>,
+        symbols: &[String],
+    ) -> Vec<Chunk> {
+        let mut chunks = vec![];
+        let mut start = range.start;
+        while start < range.end {
+            let mut end = floor_char_boundary(text, (start + self.chunk_size).min(range.end));
+            if end < range.end {
+                if let Some(index) = text[start..end].rfind('\n') {
+                    end = start + index + 1;
+                }
+            }
+            // The chunk size is smaller than the character at `start`
+            if end <= start {
+                end = ceil_char_boundary(text, start + 1);
+            }
+            chunks.push(Chunk::new(text, start..end, symbols.to_vec()));
+            if end >= range.end {
+                break;
+            }
+            let next = floor_char_boundary(text, end.saturating_sub(self.chunk_overlap));
+            start = if next > start { next } else { end };
+        }
+        chunks
+    }
+}
+
+impl Splitter for TextSplitter {
+    fn split(&self, _uri: &str, text: &str) -> Vec<Chunk> {
+        self.split_range(text, 0..text.len(), &[])
+    }
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_splitter_splits_at_line_endings() {
+        let splitter = TextSplitter::new_with_chunk_size(12, 0);
+        let chunks = splitter.split("test.txt", "line one\nline two\nline three");
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["line one\n", "line two\n", "line three"]);
+        assert_eq!(chunks[1].range, 9..18);
+    }
+
+    #[test]
+    fn text_splitter_overlaps_chunks() {
+        let splitter = TextSplitter::new_with_chunk_size(4, 2);
+        let chunks = splitter.split("test.txt", "abcdefgh");
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["abcd", "cdef", "efgh"]);
+    }
+}
@@ -0,0 +1,552 @@
+use std::ops::Range;
+
+use tracing::error;
+use tree_sitter::{Language, Node, Parser, Tree};
+
+use crate::config::{self, CursorRegion};
+
+use super::{Chunk, Splitter, TextSplitter};
+
+// Splits code at the boundaries of its syntax tree nodes so functions, classes, etc. are kept
+// whole where possible. Falls back to the text splitter for unsupported languages and for nodes
+// too big to fit in a chunk.
+pub struct TreeSitter {
+    chunk_size: usize,
+    text_splitter: TextSplitter,
+}
+
+impl TreeSitter {
+    pub fn new(config: config::TreeSitter) -> Self {
+        Self {
+            chunk_size: config.chunk_size.max(1),
+            text_splitter: TextSplitter::new_with_chunk_size(
+                config.chunk_size,
+                config.chunk_overlap,
+            ),
+        }
+    }
+
+    fn split_with_tree_sitter(&self, language: Language, text: &str) -> Option<Vec<Chunk>> {
+        let mut parser = Parser::new();
+        if let Err(e) = parser.set_language(&language) {
+            error!("setting the tree-sitter language: {e}");
+            return None;
+        }
+        let tree = parser.parse(text, None)?;
+        let mut chunks = vec![];
+        self.split_node(tree.root_node(), text, &mut vec![], &mut chunks);
+        Some(chunks)
+    }
+
+    // Greedily packs consecutive children of `node` into chunks, recursing into children that
+    // are too big on their own
+    fn split_node(&self, node: Node, text: &str, scope: &mut Vec<String>, chunks: &mut Vec<Chunk>) {
+        let mut current: Option<(Range<usize>, Vec<String>)> = None;
+        let mut cursor = node.walk();
+        for child in node.children(&mut cursor) {
+            let range = child.start_byte()..child.end_byte();
+            if range.len() > self.chunk_size {
+                if let Some((range, symbols)) = current.take() {
+                    chunks.push(Chunk::new(text, range, symbols));
+                }
+                let symbol = get_symbol(child, text);
+                scope.extend(symbol.clone());
+                if child.child_count() > 0 {
+                    self.split_node(child, text, scope, chunks);
+                } else {
+                    chunks.extend(self.text_splitter.split_range(text, range, scope));
+                }
+                if symbol.is_some() {
+                    scope.pop();
+                }
+                continue;
+            }
+
+            match &mut current {
+                Some((current_range, symbols))
+                    if range.end - current_range.start <= self.chunk_size =>
+                {
+                    current_range.end = range.end;
+                    get_symbols(child, text, symbols);
+                }
+                _ => {
+                    if let Some((range, symbols)) = current.take() {
+                        chunks.push(Chunk::new(text, range, symbols));
+                    }
+                    let mut symbols = scope.clone();
+                    get_symbols(child, text, &mut symbols);
+                    current = Some((range, symbols));
+                }
+            }
+        }
+        if let Some((range, symbols)) = current {
+            chunks.push(Chunk::new(text, range, symbols));
+        }
+    }
+}
+
+impl Splitter for TreeSitter {
+    fn split(&self, uri: &str, text: &str) -> Vec<Chunk> {
+        get_language(uri)
+            .and_then(|language| self.split_with_tree_sitter(language, text))
+            .unwrap_or_else(|| self.text_splitter.split(uri, text))
+    }
+}
+
+// The kinds of nodes whose names are recorded as symbols
+const DEFINITION_KINDS: &[&str] = &["definition", "declaration", "item", "type_spec"];
+
+// The name of the function, class, etc. the node defines
+fn get_symbol(node: Node, text: &str) -> Option<String> {
+    let kind = node.kind();
+    if !DEFINITION_KINDS.iter().any(|k| kind.contains(k)) {
+        return None;
+    }
+    let mut name = node
+        .child_by_field_name("name")
+        // Rust impl blocks are named by the type they implement
+        .or_else(|| {
+            (kind == "impl_item")
+                .then(|| node.child_by_field_name("type"))
+                .flatten()
+        })
+        .or_else(|| node.child_by_field_name("declarator"))?;
+    // C and C++ nest the name in declarators
+    while let Some(declarator) = name.child_by_field_name("declarator") {
+        name = declarator;
+    }
+    name.utf8_text(text.as_bytes()).ok().map(|s| s.to_owned())
+}
+
+// The symbols defined by the node or, for nodes that do not define anything themselves like
+// blocks and decorators, by its descendants
+fn get_symbols(node: Node, text: &str, symbols: &mut Vec<String>) {
+    if let Some(symbol) = get_symbol(node, text) {
+        symbols.push(symbol);
+        return;
+    }
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        get_symbols(child, text, symbols);
+    }
+}
+
+// The byte ranges of the innermost definition around a position and of the definitions next to it
+pub struct SurroundingDefinitions {
+    // None between definitions
+    pub current: Option<Range<usize>>,
+    // Nearest first
+    pub siblings: Vec<Range<usize>>,
+}
+
+// None for languages without a tree-sitter grammar
+pub fn get_surrounding_definitions(
+    uri: &str,
+    text: &str,
+    offset: usize,
+) -> Option<SurroundingDefinitions> {
+    let tree = parse(uri, text)?;
+    let mut node = tree.root_node().descendant_for_byte_range(offset, offset)?;
+    let current = loop {
+        if get_symbol(node, text).is_some() {
+            break Some(node);
+        }
+        match node.parent() {
+            Some(parent) => node = parent,
+            None => break None,
+        }
+    };
+    let parent = current
+        .and_then(|current| current.parent())
+        .unwrap_or(tree.root_node());
+    let range = current.map_or(offset..offset, |current| {
+        current.start_byte()..current.end_byte()
+    });
+    let mut cursor = parent.walk();
+    let mut siblings: Vec<Range<usize>> = parent
+        .named_children(&mut cursor)
+        .filter(|child| Some(*child) != current && get_symbol(*child, text).is_some())
+        .map(|child| child.start_byte()..child.end_byte())
+        .collect();
+    siblings.sort_by_key(|sibling| {
+        if sibling.start >= range.end {
+            sibling.start - range.end
+        } else {
+            range.start.saturating_sub(sibling.end)
+        }
+    });
+    Some(SurroundingDefinitions {
+        current: current.is_some().then_some(range),
+        siblings,
+    })
+}
+
+fn get_node_region(node: Node) -> Option<CursorRegion> {
+    let kind = node.kind();
+    if kind.contains("comment") {
+        Some(CursorRegion::Comment)
+    } else if kind.contains("string") || kind == "char_literal" {
+        Some(CursorRegion::String)
+    } else {
+        None
+    }
+}
+
+// What the cursor is in. None for languages without a tree-sitter grammar.
+pub fn get_region(uri: &str, text: &str, offset: usize) -> Option<CursorRegion> {
+    let tree = parse(uri, text)?;
+    let offset = offset.min(text.len());
+    if offset == 0 {
+        return Some(CursorRegion::Code);
+    }
+    // The character before the cursor, so the cursor right after a closing quote is not in the
+    // string and the cursor at the end of a line comment is in the comment
+    let mut node = tree
+        .root_node()
+        .descendant_for_byte_range(offset - 1, offset)?;
+    loop {
+        // The code in template strings and f-strings
+        if node.kind().contains("substitution") || node.kind() == "interpolation" {
+            return Some(CursorRegion::Code);
+        }
+        if let Some(region) = get_node_region(node) {
+            // Strings and comments are made of parts like `string_content`
+            while let Some(parent) = node
+                .parent()
+                .filter(|p| get_node_region(*p) == Some(region))
+            {
+                node = parent;
+            }
+            let end = node.end_byte();
+            let closed = offset >= end
+                && (region == CursorRegion::String
+                    || text[..end].ends_with("*/")
+                    || text[..end].ends_with('\n'));
+            return Some(if closed { CursorRegion::Code } else { region });
+        }
+        node = match node.parent() {
+            Some(parent) => parent,
+            None => return Some(CursorRegion::Code),
+        };
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct OutlineSymbol {
+    pub name: String,
+    // How many definitions it is nested in
+    pub depth: usize,
+    // 0-based
+    pub line: usize,
+}
+
+// The definitions of the file in order. None for languages without a tree-sitter grammar.
+pub fn get_outline(uri: &str, text: &str) -> Option<Vec<OutlineSymbol>> {
+    let tree = parse(uri, text)?;
+    let mut outline = vec![];
+    collect_outline(tree.root_node(), text, 0, &mut outline);
+    Some(outline)
+}
+
+fn collect_outline(node: Node, text: &str, depth: usize, outline: &mut Vec<OutlineSymbol>) {
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        match get_symbol(child, text) {
+            Some(name) => {
+                outline.push(OutlineSymbol {
+                    name,
+                    depth,
+                    line: child.start_position().row,
+                });
+                collect_outline(child, text, depth + 1, outline);
+            }
+            None => collect_outline(child, text, depth, outline),
+        }
+    }
+}
+
+// The kinds of nodes that import names from other files
+const IMPORT_KINDS: &[&str] = &[
+    "use_declaration",
+    "import_statement",
+    "import_from_statement",
+    "import_declaration",
+];
+
+// The names of the modules and symbols the file imports, sorted
+pub fn get_imported_names(uri: &str, text: &str) -> Vec<String> {
+    let Some(tree) = parse(uri, text) else {
+        return vec![];
+    };
+    let mut names = vec![];
+    collect_imported_names(tree.root_node(), text, false, &mut names);
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn collect_imported_names(node: Node, text: &str, in_import: bool, names: &mut Vec<String>) {
+    let in_import = in_import || IMPORT_KINDS.contains(&node.kind());
+    if in_import && node.child_count() == 0 && node.kind().ends_with("identifier") {
+        names.extend(node.utf8_text(text.as_bytes()).ok().map(|s| s.to_owned()));
+    }
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        collect_imported_names(child, text, in_import, names);
+    }
+}
+
+// The signatures and doc comments of the definitions named any of `names` by name
+pub fn get_signatures(uri: &str, text: &str, names: &[String]) -> Vec<(String, String)> {
+    let Some(tree) = parse(uri, text) else {
+        return vec![];
+    };
+    let mut signatures = vec![];
+    collect_signatures(tree.root_node(), text, names, &mut signatures);
+    signatures
+}
+
+fn collect_signatures(
+    node: Node,
+    text: &str,
+    names: &[String],
+    signatures: &mut Vec<(String, String)>,
+) {
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        match get_symbol(child, text) {
+            Some(name) if names.contains(&name) => {
+                signatures.push((name, get_signature(child, text)));
+            }
+            _ => collect_signatures(child, text, names, signatures),
+        }
+    }
+}
+
+// The definition without the code of its body. Classes, impl blocks, etc. keep the signatures of
+// their members and definitions without code like structs are kept whole.
+fn get_signature(node: Node, text: &str) -> String {
+    // From the start of the line, with the comments and attributes right above
+    let mut start = node;
+    while let Some(previous) = start.prev_named_sibling().filter(|previous| {
+        (previous.kind().contains("comment") || previous.kind() == "attribute_item")
+            && previous.end_position().row + 1 >= start.start_position().row
+    }) {
+        start = previous;
+    }
+    let line_start = |node: Node| {
+        let start = node.start_byte();
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        if text[line_start..start].trim().is_empty() {
+            line_start
+        } else {
+            start
+        }
+    };
+    let start = line_start(start);
+    let Some(body) = node.child_by_field_name("body") else {
+        return text[start..node.end_byte()].to_owned();
+    };
+    let mut signature = text[start..body.start_byte()].trim_end().to_owned();
+    // Python keeps docstrings in the body
+    let docstring = body.named_child(0).filter(|statement| {
+        statement.kind() == "expression_statement"
+            && statement
+                .named_child(0)
+                .is_some_and(|child| child.kind() == "string")
+    });
+    if let Some(docstring) = docstring {
+        signature.push('\n');
+        signature.push_str(&text[line_start(docstring)..docstring.end_byte()]);
+    }
+    let mut cursor = body.walk();
+    let members: Vec<String> = body
+        .named_children(&mut cursor)
+        .filter(|member| get_symbol(*member, text).is_some())
+        .map(|member| get_signature(member, text))
+        .collect();
+    let is_code = body.kind().contains("block") || body.kind() == "compound_statement";
+    if members.is_empty() && !is_code && docstring.is_none() {
+        return text[start..node.end_byte()].to_owned();
+    }
+    for member in members {
+        signature.push('\n');
+        signature.push_str(&member);
+    }
+    signature
+}
+
+fn parse(uri: &str, text: &str) -> Option<Tree> {
+    let mut parser = Parser::new();
+    parser.set_language(&get_language(uri)?).ok()?;
+    parser.parse(text, None)
+}
+
+fn get_language(uri: &str) -> Option<Language> {
+    let extension = uri.rsplit_once('.')?.1;
+    Some(match extension {
+        "rs" => tree_sitter_rust::language(),
+        "py" => tree_sitter_python::language(),
+        "js" | "jsx" | "mjs" | "cjs" => tree_sitter_javascript::language(),
+        "ts" | "mts" | "cts" => tree_sitter_typescript::language_typescript(),
+        "tsx" => tree_sitter_typescript::language_tsx(),
+        "go" => tree_sitter_go::language(),
+        "c" | "h" => tree_sitter_c::language(),
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => tree_sitter_cpp::language(),
+        "java" => tree_sitter_java::language(),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_sitter_splits_at_function_boundaries() {
+        let splitter = TreeSitter::new(config::TreeSitter {
+            chunk_size: 40,
+            chunk_overlap: 0,
+        });
+        let text = "fn one() {\n    1;\n}\n\nfn two() {\n    2;\n}\n\nfn three() {\n    3;\n}\n";
+        let chunks = splitter.split("file:///test.rs", text);
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "fn one() {\n    1;\n}\n\nfn two() {\n    2;\n}",
+                "fn three() {\n    3;\n}"
+            ]
+        );
+        assert_eq!(chunks[0].symbols, vec!["one", "two"]);
+        assert_eq!(chunks[1].symbols, vec!["three"]);
+    }
+
+    #[test]
+    fn tree_sitter_records_the_enclosing_symbols() {
+        let splitter = TreeSitter::new(config::TreeSitter {
+            chunk_size: 30,
+            chunk_overlap: 0,
+        });
+        let text = "class Test:\n    def one(self):\n        pass\n";
+        let chunks = splitter.split("test.py", text);
+        assert!(chunks
+            .iter()
+            .any(|c| c.symbols == vec!["Test".to_string(), "one".to_string()]));
+    }
+
+    #[test]
+    fn tree_sitter_falls_back_to_the_text_splitter() {
+        let splitter = TreeSitter::new(config::TreeSitter {
+            chunk_size: 5,
+            chunk_overlap: 0,
+        });
+        let chunks = splitter.split("test.unknown", "abc\ndef\n");
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["abc\n", "def\n"]);
+    }
+
+    #[test]
+    fn finds_the_surrounding_definitions() {
+        let text = "fn one() {\n    1;\n}\n\nfn two() {\n    2;\n}\n\nfn three() {\n    3;\n}\n";
+        let definitions = get_surrounding_definitions("file:///test.rs", text, 30).unwrap();
+        assert_eq!(definitions.current, Some(21..40));
+        assert_eq!(definitions.siblings, vec![0..19, 42..63]);
+
+        let definitions = get_surrounding_definitions("file:///test.rs", text, 20).unwrap();
+        assert_eq!(definitions.current, None);
+        assert_eq!(definitions.siblings, vec![0..19, 21..40, 42..63]);
+
+        assert!(get_surrounding_definitions("test.unknown", text, 0).is_none());
+    }
+
+    #[test]
+    fn finds_the_region_of_the_cursor() {
+        let text = "fn main() {\n    // a comment\n    let s = \"text\";\n    /* block */ s\n}\n";
+        let region = |needle: &str| {
+            let offset = text.find(needle).unwrap() + needle.len();
+            get_region("file:///test.rs", text, offset)
+        };
+        assert_eq!(region("// a com"), Some(CursorRegion::Comment));
+        assert_eq!(region("// a comment"), Some(CursorRegion::Comment));
+        assert_eq!(region("\"te"), Some(CursorRegion::String));
+        assert_eq!(region("\"text\""), Some(CursorRegion::Code));
+        assert_eq!(region("/* blo"), Some(CursorRegion::Comment));
+        assert_eq!(region("/* block */"), Some(CursorRegion::Code));
+        assert_eq!(region("let s"), Some(CursorRegion::Code));
+
+        let text = "def f():\n    \"\"\"Docs\"\"\"\n    return f\"{f}\"\n";
+        let offset = text.find("Do").unwrap() + 2;
+        assert_eq!(
+            get_region("test.py", text, offset),
+            Some(CursorRegion::String)
+        );
+        let offset = text.find("{f").unwrap() + 2;
+        assert_eq!(
+            get_region("test.py", text, offset),
+            Some(CursorRegion::Code)
+        );
+        assert!(get_region("test.unknown", text, 0).is_none());
+    }
+
+    #[test]
+    fn outlines_files() {
+        let text = "class Circle:\n    def grow(self):\n        pass\n\n\ndef area():\n    pass\n";
+        let symbol = |name: &str, depth, line| OutlineSymbol {
+            name: name.to_string(),
+            depth,
+            line,
+        };
+        assert_eq!(
+            get_outline("file:///shapes.py", text),
+            Some(vec![
+                symbol("Circle", 0, 0),
+                symbol("grow", 1, 1),
+                symbol("area", 0, 5)
+            ])
+        );
+        assert_eq!(get_outline("file:///notes.txt", text), None);
+    }
+
+    #[test]
+    fn finds_imports_and_their_signatures() {
+        assert_eq!(
+            get_imported_names("file:///main.rs", "use crate::shapes::{area, Circle};\n"),
+            vec!["Circle", "area", "shapes"]
+        );
+
+        let text = r#"# The area of the shape
+def area(shape):
+    return 3.14 * shape.r ** 2
+
+
+class Circle:
+    """A circle."""
+
+    def __init__(self, r):
+        self.r = r
+
+    def grow(self, by):
+        """Grows it."""
+        self.r += by
+
+
+def other():
+    pass
+"#;
+        let names = vec!["Circle".to_string(), "area".to_string()];
+        assert_eq!(
+            get_signatures("file:///shapes.py", text, &names),
+            vec![
+                (
+                    "area".to_string(),
+                    "# The area of the shape\ndef area(shape):".to_string()
+                ),
+                (
+                    "Circle".to_string(),
+                    "class Circle:\n    \"\"\"A circle.\"\"\"\n    def __init__(self, r):\n    def grow(self, by):\n        \"\"\"Grows it.\"\"\"".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use anyhow::Context;
+use serde_json::Value;
+
+// Some models wrap JSON in a code block even when asked for JSON only
+fn strip_code_block(text: &str) -> &str {
+    let text = text.trim();
+    match text
+        .strip_prefix("```")
+        .and_then(|text| text.strip_suffix("```"))
+    {
+        Some(inner) => inner.trim_start_matches("json").trim(),
+        None => text,
+    }
+}
+
+fn validate(schema: &Value, output: &Value) -> anyhow::Result<()> {
+    let schema = jsonschema::JSONSchema::compile(schema)
+        .map_err(|e| anyhow::anyhow!("invalid `response_format` schema: {e}"))?;
+    if let Err(errors) = schema.validate(output) {
+        let errors: Vec<String> = errors
+            .map(|error| format!("{}: {error}", error.instance_path))
+            .collect();
+        anyhow::bail!(
+            "the response does not match the `response_format` schema: {}",
+            errors.join(", ")
+        );
+    }
+    Ok(())
+}
+
+// The generated JSON when the params ask for structured output with `response_format`, checked
+// against its schema as not every API enforces it
+pub fn parse_structured_output(params: &Value, text: &str) -> anyhow::Result<Option<Value>> {
+    let Some(response_format) = params.get("response_format") else {
+        return Ok(None);
+    };
+    let schema = match response_format.get("type").and_then(Value::as_str) {
+        Some("json_schema") => {
+            let schema = response_format.pointer("/json_schema/schema");
+            Some(schema.context("`response_format` must set `json_schema.schema`")?)
+        }
+        Some("json_object") => None,
+        _ => return Ok(None),
+    };
+    let output: Value =
+        serde_json::from_str(strip_code_block(text)).context("the response is not valid JSON")?;
+    if let Some(schema) = schema {
+        validate(schema, &output)?;
+    }
+    Ok(Some(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_structured_output() -> anyhow::Result<()> {
+        let params = json!({
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "edits",
+                    "schema": {
+                        "type": "object",
+                        "properties": {"edits": {"type": "array", "items": {"type": "string"}}},
+                        "required": ["edits"]
+                    }
+                }
+            }
+        });
+        assert_eq!(
+            parse_structured_output(&params, "```json\n{\"edits\": [\"a\"]}\n```")?,
+            Some(json!({"edits": ["a"]}))
+        );
+        assert!(parse_structured_output(&params, "{\"edits\": [1]}").is_err());
+        assert!(parse_structured_output(&params, "not json").is_err());
+        assert_eq!(parse_structured_output(&json!({}), "text")?, None);
+        Ok(())
+    }
+}
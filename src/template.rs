@@ -29,7 +29,11 @@ pub fn apply_chat_template(
             _ => anyhow::bail!(e.to_string()),
         },
     };
-    Ok(template.render(
-        context!(messages => chat_messages, bos_token => bos_token, eos_token => eos_token),
-    )?)
+    // Templates from Hugging Face only add the assistant's header with `add_generation_prompt`
+    Ok(template.render(context!(
+        messages => chat_messages,
+        bos_token => bos_token,
+        eos_token => eos_token,
+        add_generation_prompt => true
+    ))?)
 }
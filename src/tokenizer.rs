@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+// Counts tokens like the model does so prompts fill its context window without going over
+pub trait Tokenizer {
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+pub type SharedTokenizer = Arc<dyn Tokenizer + Send + Sync>;
+
+// For models that cannot be tokenized locally. The text is split like the pre-tokenizers of BPE
+// tokenizers such as tiktoken split it, and each piece is estimated from its length.
+pub struct EstimatedTokenizer;
+
+impl Tokenizer for EstimatedTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        let mut tokens = 0;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            let mut length = 1;
+            let mut take_while = |f: fn(char) -> bool| {
+                while chars.next_if(|c| f(*c)).is_some() {
+                    length += 1;
+                }
+            };
+            tokens += if c.is_ascii_alphabetic() {
+                take_while(|c| c.is_ascii_alphabetic());
+                length.div_ceil(4)
+            } else if c.is_ascii_digit() {
+                take_while(|c| c.is_ascii_digit());
+                length.div_ceil(3)
+            } else if c == ' ' && chars.peek().is_some_and(char::is_ascii_alphanumeric) {
+                // Merged into the word that follows
+                0
+            } else if c.is_whitespace() {
+                take_while(char::is_whitespace);
+                1
+            } else {
+                // Punctuation, and other scripts which rarely get more than a char per token
+                1
+            };
+        }
+        tokens
+    }
+}
+
+// Which end of a part is kept when it is truncated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Keep {
+    Start,
+    End,
+}
+
+// The longest start or end of `text` within `max_tokens`. Text is only cut between chars.
+pub fn truncate<'a>(
+    tokenizer: &(dyn Tokenizer + Send + Sync),
+    text: &'a str,
+    max_tokens: usize,
+    keep: Keep,
+) -> &'a str {
+    if tokenizer.count_tokens(text) <= max_tokens {
+        return text;
+    }
+    let boundaries: Vec<usize> = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain([text.len()])
+        .collect();
+    let slice = |n: usize| match keep {
+        Keep::Start => &text[..boundaries[n]],
+        Keep::End => &text[boundaries[boundaries.len() - 1 - n]..],
+    };
+    // The most chars that fit, token counts grow with the text
+    let (mut low, mut high) = (0, boundaries.len() - 1);
+    while low < high {
+        let middle = (low + high + 1) / 2;
+        if tokenizer.count_tokens(slice(middle)) <= max_tokens {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    slice(low)
+}
+
+// A part of the prompt competing for the context window
+pub struct Part<'a> {
+    pub text: &'a str,
+    pub ratio: f32,
+    pub keep: Keep,
+}
+
+// Divides `max_tokens` between the parts by their ratios and truncates each to its share. Room a
+// part does not need goes to the parts that need more, earlier parts first.
+pub fn fit<'a>(
+    tokenizer: &(dyn Tokenizer + Send + Sync),
+    max_tokens: usize,
+    parts: &[Part<'a>],
+) -> Vec<&'a str> {
+    let counts: Vec<usize> = parts
+        .iter()
+        .map(|part| tokenizer.count_tokens(part.text))
+        .collect();
+    let total_ratio: f32 = parts.iter().map(|part| part.ratio.max(0.)).sum();
+    let mut budgets: Vec<usize> = parts
+        .iter()
+        .map(|part| {
+            (max_tokens as f32 * part.ratio.max(0.) / total_ratio.max(f32::EPSILON)) as usize
+        })
+        .collect();
+    let mut spare = max_tokens.saturating_sub(budgets.iter().sum());
+    for (budget, count) in budgets.iter_mut().zip(&counts) {
+        if *count < *budget {
+            spare += *budget - count;
+            *budget = *count;
+        }
+    }
+    for (budget, count) in budgets.iter_mut().zip(&counts) {
+        let extra = count.saturating_sub(*budget).min(spare);
+        *budget += extra;
+        spare -= extra;
+    }
+    parts
+        .iter()
+        .zip(budgets)
+        .map(|(part, budget)| truncate(tokenizer, part.text, budget, part.keep))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_and_fits() {
+        let tokenizer = EstimatedTokenizer;
+        assert_eq!(tokenizer.count_tokens(""), 0);
+        assert_eq!(tokenizer.count_tokens("def multiply(a, b):"), 9);
+        assert_eq!(tokenizer.count_tokens("12345\n    return"), 5);
+
+        // Multibyte chars are never split
+        let text = "héllo wörld ünïcode";
+        for max_tokens in 0..10 {
+            assert!(text.starts_with(truncate(&tokenizer, text, max_tokens, Keep::Start)));
+            assert!(text.ends_with(truncate(&tokenizer, text, max_tokens, Keep::End)));
+        }
+        assert_eq!(truncate(&tokenizer, "a b c d", 2, Keep::End), " c d");
+
+        // The short suffix leaves its share to the prefix
+        let prefix = "a ".repeat(100);
+        let parts = fit(
+            &tokenizer,
+            60,
+            &[
+                Part {
+                    text: &prefix,
+                    ratio: 1.,
+                    keep: Keep::End,
+                },
+                Part {
+                    text: "b",
+                    ratio: 1.,
+                    keep: Keep::Start,
+                },
+            ],
+        );
+        assert_eq!(tokenizer.count_tokens(parts[0]), 59);
+        assert_eq!(parts[1], "b");
+    }
+}
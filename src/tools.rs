@@ -0,0 +1,515 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Context;
+use lsp_types::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+
+use crate::{
+    config::Config,
+    custom_requests::{agent::ProposedEdit, search::WorkspaceSearchParams},
+    mcp,
+    memory_worker::{self, FileTextRequest, SearchRequest},
+    redaction::redact,
+    splitters::get_outline,
+    workspace_edit::FileChange,
+};
+
+pub const READ_FILE: &str = "read_file";
+pub const SEARCH_WORKSPACE: &str = "search_workspace";
+pub const LIST_SYMBOLS: &str = "list_symbols";
+pub const APPLY_EDIT: &str = "apply_edit";
+
+// The tools the server runs itself
+pub const BUILTIN_TOOLS: &[&str] = &[READ_FILE, SEARCH_WORKSPACE, LIST_SYMBOLS, APPLY_EDIT];
+
+// Tool results are sent back with every later step, so large files are cut
+const MAX_RESULT_CHARS: usize = 20_000;
+
+const fn max_tool_steps_default() -> usize {
+    5
+}
+
+// The params of generations that may call tools. Backends format the definitions of the tools
+// for their API.
+#[derive(Debug, Deserialize)]
+pub struct ToolParams {
+    // The names of the tools the model may call
+    #[serde(default)]
+    pub tools: Vec<String>,
+    // How many responses may call tools before the model has to answer
+    #[serde(default = "max_tool_steps_default")]
+    pub max_tool_steps: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    // A JSON schema
+    pub input_schema: Value,
+}
+
+pub fn get_definition(name: &str) -> anyhow::Result<ToolDefinition> {
+    let path = json!({"type": "string", "description": "The path or file URI of the file"});
+    match name {
+        READ_FILE => Ok(ToolDefinition {
+            name: READ_FILE.to_string(),
+            description: "Read a file of the workspace. Relative paths are resolved against the workspace folders.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {"path": path},
+                "required": ["path"]
+            }),
+        }),
+        SEARCH_WORKSPACE => Ok(ToolDefinition {
+            name: SEARCH_WORKSPACE.to_string(),
+            description: "Search the code and documentation of the workspace for chunks relevant to the query.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "query": {"type": "string", "description": "What to search for, in natural language or code"},
+                    "top_k": {"type": "integer", "description": "How many chunks to return, defaults to 5"}
+                },
+                "required": ["query"]
+            }),
+        }),
+        LIST_SYMBOLS => Ok(ToolDefinition {
+            name: LIST_SYMBOLS.to_string(),
+            description: "List the functions, classes and other definitions of a file with their line numbers, indented by nesting.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {"path": path},
+                "required": ["path"]
+            }),
+        }),
+        APPLY_EDIT => Ok(ToolDefinition {
+            name: APPLY_EDIT.to_string(),
+            description: "Propose replacing `old_text` with `new_text` in a file. `old_text` must be found exactly once, include enough lines around the change. An empty `old_text` creates a new file. The user reviews the edits before they are applied, later edits of a file see the earlier ones.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "path": path,
+                    "old_text": {"type": "string"},
+                    "new_text": {"type": "string"}
+                },
+                "required": ["path", "old_text", "new_text"]
+            }),
+        }),
+        _ => anyhow::bail!(
+            "unknown tool `{name}`, the tools are {}",
+            BUILTIN_TOOLS.join(", ")
+        ),
+    }
+}
+
+// The definitions of the built-in tools, the tools of MCP servers as `{server}__{tool}` and all
+// the tools of a server as its name
+pub async fn get_definitions(
+    names: &[String],
+    config: &Config,
+) -> anyhow::Result<Vec<ToolDefinition>> {
+    let mut definitions = vec![];
+    for name in names {
+        if BUILTIN_TOOLS.contains(&name.as_str()) {
+            definitions.push(get_definition(name)?);
+        } else if config.get_mcp_servers().contains_key(name) {
+            definitions.extend(mcp::get_definitions(config, name, None).await?);
+        } else if let Some((server, tool)) = mcp::split_name(name) {
+            definitions.extend(mcp::get_definitions(config, server, Some(tool)).await?);
+        } else {
+            definitions.push(get_definition(name)?);
+        }
+    }
+    Ok(definitions)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolResult {
+    // The id of the call
+    pub id: String,
+    pub content: String,
+    pub is_error: bool,
+}
+
+// A response of the model that called tools, with the results of the calls
+#[derive(Debug, Clone)]
+pub struct ToolTurn {
+    pub text: String,
+    pub calls: Vec<ToolCall>,
+    pub results: Vec<ToolResult>,
+}
+
+pub struct ToolRequest<'a> {
+    pub tools: &'a [ToolDefinition],
+    // The earlier responses, oldest first
+    pub turns: &'a [ToolTurn],
+    // No more tools may be called, the model has to answer
+    pub must_answer: bool,
+}
+
+#[derive(Deserialize)]
+struct PathInput {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct SearchWorkspaceInput {
+    query: String,
+    #[serde(default = "top_k_default")]
+    top_k: usize,
+}
+
+const fn top_k_default() -> usize {
+    5
+}
+
+#[derive(Deserialize)]
+struct ApplyEditInput {
+    path: String,
+    old_text: String,
+    new_text: String,
+}
+
+fn get_roots(workspace_folders: &[String]) -> Vec<PathBuf> {
+    workspace_folders
+        .iter()
+        .filter_map(|folder| Url::parse(folder).ok()?.to_file_path().ok())
+        .filter_map(|root| root.canonicalize().ok())
+        .collect()
+}
+
+fn to_path(path: &str) -> anyhow::Result<PathBuf> {
+    match Url::parse(path) {
+        Ok(url) if url.scheme() == "file" => url
+            .to_file_path()
+            .map_err(|_| anyhow::anyhow!("`{path}` is not a file path")),
+        _ => Ok(PathBuf::from(path)),
+    }
+}
+
+// Files outside of the workspace folders can not be read
+fn resolve_path(path: &str, workspace_folders: &[String]) -> anyhow::Result<PathBuf> {
+    let roots = get_roots(workspace_folders);
+    let path = to_path(path)?;
+    let candidates: Vec<PathBuf> = if path.is_absolute() {
+        vec![path.clone()]
+    } else {
+        roots.iter().map(|root| root.join(&path)).collect()
+    };
+    candidates
+        .into_iter()
+        .filter_map(|candidate| candidate.canonicalize().ok())
+        .find(|candidate| {
+            candidate.is_file() && roots.iter().any(|root| candidate.starts_with(root))
+        })
+        .with_context(|| format!("`{}` is not a file in the workspace", path.display()))
+}
+
+// Relative paths of new files are in the first workspace folder
+fn resolve_new_path(path: &str, workspace_folders: &[String]) -> anyhow::Result<PathBuf> {
+    let roots = get_roots(workspace_folders);
+    let path = to_path(path)?;
+    if path.components().any(|c| c == Component::ParentDir) {
+        anyhow::bail!("`{}` must not contain `..`", path.display());
+    }
+    let path = if path.is_absolute() {
+        path
+    } else {
+        roots
+            .first()
+            .context("there is no workspace folder to create files in")?
+            .join(path)
+    };
+    // The directories that do not exist yet are created with the file
+    let in_workspace = path
+        .ancestors()
+        .find_map(|ancestor| ancestor.canonicalize().ok())
+        .is_some_and(|ancestor| roots.iter().any(|root| ancestor.starts_with(root)));
+    if !in_workspace {
+        anyhow::bail!("`{}` is not in the workspace", path.display());
+    }
+    Ok(path)
+}
+
+fn truncate(mut text: String) -> String {
+    if let Some((index, _)) = text.char_indices().nth(MAX_RESULT_CHARS) {
+        text.truncate(index);
+        text.push_str("\n[truncated]");
+    }
+    text
+}
+
+fn to_uri(path: &Path) -> anyhow::Result<Url> {
+    Url::from_file_path(path)
+        .map_err(|_| anyhow::anyhow!("`{}` is not a file path", path.display()))
+}
+
+// Replaces the only occurrence of `old_text`, an empty `old_text` only replaces an empty file
+fn replace_once(text: &str, old_text: &str, new_text: &str) -> anyhow::Result<String> {
+    if old_text.is_empty() {
+        if !text.is_empty() {
+            anyhow::bail!("the file exists, `old_text` can only be empty for new files");
+        }
+        return Ok(new_text.to_owned());
+    }
+    match text.matches(old_text).count() {
+        0 => anyhow::bail!("`old_text` was not found in the file"),
+        1 => Ok(text.replacen(old_text, new_text, 1)),
+        count => {
+            anyhow::bail!("`old_text` was found {count} times, include more of the lines around it")
+        }
+    }
+}
+
+// Runs the tools the model calls for one generation. Edits are only proposed and collected.
+pub struct ToolContext<'a> {
+    memory_backend_tx: &'a std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    config: &'a Config,
+    // Tool results leave the machine like the prompt
+    redact: bool,
+    // The text of the files with the edits proposed so far
+    edited: HashMap<PathBuf, String>,
+    // The text of the edited files before the first edit, in the order they were first edited
+    originals: Vec<(PathBuf, Option<String>)>,
+    pub edits: Vec<ProposedEdit>,
+}
+
+impl<'a> ToolContext<'a> {
+    pub fn new(
+        memory_backend_tx: &'a std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+        config: &'a Config,
+        redact: bool,
+    ) -> Self {
+        Self {
+            memory_backend_tx,
+            config,
+            redact,
+            edited: HashMap::new(),
+            originals: vec![],
+            edits: vec![],
+        }
+    }
+
+    async fn get_text(&self, path: &Path) -> anyhow::Result<String> {
+        if let Some(text) = self.edited.get(path) {
+            return Ok(text.clone());
+        }
+        // Opened documents may have changes that are not saved yet
+        let (tx, rx) = oneshot::channel();
+        self.memory_backend_tx
+            .send(memory_worker::WorkerRequest::FileText(
+                FileTextRequest::new(to_uri(path)?.to_string(), tx),
+            ))?;
+        match rx.await? {
+            Some(text) => Ok(text),
+            None => tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("reading `{}`", path.display())),
+        }
+    }
+
+    async fn read_file(&self, input: PathInput) -> anyhow::Result<String> {
+        let path = resolve_path(&input.path, &self.config.get_workspace_folders())?;
+        Ok(truncate(self.get_text(&path).await?))
+    }
+
+    async fn search_workspace(&self, input: SearchWorkspaceInput) -> anyhow::Result<String> {
+        let (tx, rx) = oneshot::channel();
+        self.memory_backend_tx
+            .send(memory_worker::WorkerRequest::SearchMatches(
+                SearchRequest::new(
+                    WorkspaceSearchParams {
+                        query: input.query,
+                        top_k: input.top_k,
+                        language: None,
+                        paths: vec![],
+                    },
+                    tx,
+                ),
+            ))?;
+        let matches = rx.await??;
+        if matches.is_empty() {
+            return Ok("No results".to_string());
+        }
+        let results: Vec<String> = matches
+            .into_iter()
+            .map(|result| match result.range {
+                Some(range) => format!(
+                    "{}:{}-{}\n{}",
+                    result.uri,
+                    range.start.line + 1,
+                    range.end.line,
+                    result.text
+                ),
+                None => format!("{}\n{}", result.uri, result.text),
+            })
+            .collect();
+        Ok(truncate(results.join("\n\n")))
+    }
+
+    async fn list_symbols(&self, input: PathInput) -> anyhow::Result<String> {
+        let path = resolve_path(&input.path, &self.config.get_workspace_folders())?;
+        let uri = to_uri(&path)?;
+        let text = self.get_text(&path).await?;
+        let outline = get_outline(uri.as_str(), &text)
+            .with_context(|| format!("symbols can not be listed for `{}`", path.display()))?;
+        let lines: Vec<String> = outline
+            .into_iter()
+            .map(|symbol| {
+                format!(
+                    "{}{} (line {})",
+                    "  ".repeat(symbol.depth),
+                    symbol.name,
+                    symbol.line + 1
+                )
+            })
+            .collect();
+        Ok(truncate(lines.join("\n")))
+    }
+
+    async fn apply_edit(&mut self, input: ApplyEditInput) -> anyhow::Result<String> {
+        let workspace_folders = self.config.get_workspace_folders();
+        let path = match resolve_path(&input.path, &workspace_folders) {
+            Ok(path) => path,
+            Err(e) => {
+                // Files created by earlier edits are not on disk yet
+                let path = resolve_new_path(&input.path, &workspace_folders)?;
+                if !input.old_text.is_empty() && !self.edited.contains_key(&path) {
+                    return Err(e);
+                }
+                path
+            }
+        };
+        let text = if path.exists() || self.edited.contains_key(&path) {
+            Some(self.get_text(&path).await?)
+        } else {
+            None
+        };
+        let edited = replace_once(
+            text.as_deref().unwrap_or_default(),
+            &input.old_text,
+            &input.new_text,
+        )?;
+        if !self.edited.contains_key(&path) {
+            self.originals.push((path.clone(), text));
+        }
+        self.edited.insert(path.clone(), edited);
+        self.edits.push(ProposedEdit {
+            uri: to_uri(&path)?.to_string(),
+            old_text: input.old_text,
+            new_text: input.new_text,
+        });
+        Ok("The edit was proposed".to_string())
+    }
+
+    // The files the proposed edits change, without the ones they left as they were
+    pub fn take_changes(&mut self) -> anyhow::Result<Vec<FileChange>> {
+        let roots = get_roots(&self.config.get_workspace_folders());
+        let mut changes = vec![];
+        for (path, original) in std::mem::take(&mut self.originals) {
+            let edited = self.edited.remove(&path).unwrap_or_default();
+            if original.as_ref() == Some(&edited) {
+                continue;
+            }
+            let relative = roots
+                .iter()
+                .find_map(|root| path.strip_prefix(root).ok())
+                .unwrap_or(&path);
+            changes.push(FileChange {
+                uri: to_uri(&path)?,
+                path: relative.display().to_string(),
+                original,
+                edited,
+            });
+        }
+        Ok(changes)
+    }
+
+    // Errors are sent to the model as the result so it can try something else
+    pub async fn run(&mut self, call: &ToolCall) -> anyhow::Result<ToolResult> {
+        let input = call.input.clone();
+        let result = match call.name.as_str() {
+            READ_FILE => match serde_json::from_value(input) {
+                Ok(input) => self.read_file(input).await,
+                Err(e) => Err(e.into()),
+            },
+            SEARCH_WORKSPACE => match serde_json::from_value(input) {
+                Ok(input) => self.search_workspace(input).await,
+                Err(e) => Err(e.into()),
+            },
+            LIST_SYMBOLS => match serde_json::from_value(input) {
+                Ok(input) => self.list_symbols(input).await,
+                Err(e) => Err(e.into()),
+            },
+            APPLY_EDIT => match serde_json::from_value(input) {
+                Ok(input) => self.apply_edit(input).await,
+                Err(e) => Err(e.into()),
+            },
+            name => mcp::call_tool(self.config, name, input).await,
+        };
+        let (content, is_error) = match result {
+            Ok(content) => (content, false),
+            Err(e) => (e.to_string(), true),
+        };
+        let content = match &self.config.config.redaction {
+            Some(redaction) if self.redact => redact(&content, redaction),
+            _ => content,
+        };
+        Ok(ToolResult {
+            id: call.id.clone(),
+            content,
+            is_error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_paths_in_the_workspace() -> anyhow::Result<()> {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let workspace_folders = vec![Url::from_directory_path(root).unwrap().to_string()];
+        assert_eq!(
+            resolve_path("src/tools.rs", &workspace_folders)?,
+            root.join("src/tools.rs").canonicalize()?
+        );
+        assert!(resolve_path("../Cargo.toml", &workspace_folders).is_err());
+        assert!(resolve_path("/etc/hostname", &workspace_folders).is_err());
+        assert_eq!(
+            resolve_new_path("src/new/mod.rs", &workspace_folders)?,
+            root.canonicalize()?.join("src/new/mod.rs")
+        );
+        assert!(resolve_new_path("src/../../new.rs", &workspace_folders).is_err());
+        assert!(resolve_new_path("/new.rs", &workspace_folders).is_err());
+        assert_eq!(
+            truncate("a".repeat(MAX_RESULT_CHARS)).len(),
+            MAX_RESULT_CHARS
+        );
+        assert!(truncate("a".repeat(MAX_RESULT_CHARS + 1)).ends_with("[truncated]"));
+        Ok(())
+    }
+
+    #[test]
+    fn replaces_unique_text() -> anyhow::Result<()> {
+        assert_eq!(replace_once("a b c", "b", "d")?, "a d c");
+        assert!(replace_once("a b b", "b", "d").is_err());
+        assert!(replace_once("a b c", "e", "d").is_err());
+        assert_eq!(replace_once("", "", "new")?, "new");
+        assert!(replace_once("a", "", "new").is_err());
+        Ok(())
+    }
+}
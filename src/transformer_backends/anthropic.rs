@@ -3,18 +3,21 @@ use std::collections::HashMap;
 use anyhow::Context;
 use serde::Deserialize;
 use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 
 use crate::{
+    auth::get_auth_token,
     config::{self, ChatMessage},
+    http_client::get_client,
     memory_backends::Prompt,
-    transformer_worker::{
-        DoGenerationResponse, DoGenerationStreamResponse, GenerationStreamRequest,
-    },
+    tools::{ToolCall, ToolRequest, ToolTurn},
+    transformer_worker::{DoGenerationResponse, DoToolGenerationResponse},
+    usage::TokenUsage,
     utils::format_chat_messages,
 };
 
-use super::TransformerBackend;
+use super::{retry, RenderedPrompt, TransformerBackend};
 
 const fn max_tokens_default() -> usize {
     64
@@ -39,6 +42,8 @@ pub struct AnthropicRunParams {
     pub top_p: f32,
     #[serde(default = "temperature_default")]
     pub temperature: f32,
+    #[serde(default)]
+    pub stop: Vec<String>,
 }
 
 pub struct Anthropic {
@@ -46,13 +51,24 @@ pub struct Anthropic {
 }
 
 #[derive(Deserialize)]
-struct AnthropicChatMessage {
-    text: String,
+#[serde(tag = "type")]
+enum AnthropicContentBlock {
+    #[serde(rename = "text")]
+    Text { text: String },
+    #[serde(rename = "tool_use")]
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    #[serde(other)]
+    Other,
 }
 
 #[derive(Deserialize)]
 struct AnthropicChatResponse {
-    content: Option<Vec<AnthropicChatMessage>>,
+    content: Option<Vec<AnthropicContentBlock>>,
+    usage: Option<TokenUsage>,
     error: Option<Value>,
     #[serde(default)]
     #[serde(flatten)]
@@ -67,20 +83,47 @@ impl Anthropic {
     async fn get_chat(
         &self,
         system_prompt: String,
-        messages: Vec<ChatMessage>,
+        messages: Vec<Value>,
         params: AnthropicRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let token = if let Some(env_var_name) = &self.config.auth_token_env_var_name {
-            std::env::var(env_var_name)?
-        } else if let Some(token) = &self.config.auth_token {
-            token.to_string()
-        } else {
-            anyhow::bail!(
-                "Please set `auth_token_env_var_name` or `auth_token` to use an Anthropic"
-            );
-        };
-        let res: AnthropicChatResponse = client
+        tools: Option<&ToolRequest<'_>>,
+    ) -> anyhow::Result<DoToolGenerationResponse> {
+        let client = get_client();
+        let token = get_auth_token(
+            self.config.auth_token_env_var_name.as_deref(),
+            self.config.auth_token.as_deref(),
+            self.config.auth_token_keyring.as_ref(),
+            self.config.auth_token_command.as_deref(),
+        ).await?
+        .context("Please set `auth_token_env_var_name`, `auth_token`, `auth_token_keyring` or `auth_token_command` to use an Anthropic")?;
+        let mut body = json!({
+            "model": self.config.model,
+            "system": system_prompt,
+            "max_tokens": params.max_tokens,
+            "top_p": params.top_p,
+            "temperature": params.temperature,
+            "messages": messages
+        });
+        if !params.stop.is_empty() {
+            body["stop_sequences"] = json!(params.stop);
+        }
+        if let Some(tools) = tools {
+            body["tools"] = tools
+                .tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "name": tool.name,
+                        "description": tool.description,
+                        "input_schema": tool.input_schema
+                    })
+                })
+                .collect();
+            // The tools are still sent as the earlier messages use them
+            if tools.must_answer {
+                body["tool_choice"] = json!({"type": "none"});
+            }
+        }
+        let request = client
             .post(
                 self.config
                     .chat_endpoint
@@ -91,22 +134,30 @@ impl Anthropic {
             .header("anthropic-version", "2023-06-01")
             .header("Content-Type", "application/json")
             .header("Accept", "application/json")
-            .json(&json!({
-                "model": self.config.model,
-                "system": system_prompt,
-                "max_tokens": params.max_tokens,
-                "top_p": params.top_p,
-                "temperature": params.temperature,
-                "messages": messages
-            }))
-            .send()
+            .json(&body);
+        let res: AnthropicChatResponse = retry::send(request, &self.config.retry)
             .await?
             .json()
             .await?;
         if let Some(error) = res.error {
             anyhow::bail!("{:?}", error.to_string())
-        } else if let Some(mut content) = res.content {
-            Ok(std::mem::take(&mut content[0].text))
+        } else if let Some(content) = res.content {
+            let mut generated_text = String::new();
+            let mut calls = vec![];
+            for block in content {
+                match block {
+                    AnthropicContentBlock::Text { text } => generated_text.push_str(&text),
+                    AnthropicContentBlock::ToolUse { id, name, input } => {
+                        calls.push(ToolCall { id, name, input })
+                    }
+                    AnthropicContentBlock::Other => (),
+                }
+            }
+            Ok(DoToolGenerationResponse {
+                generated_text,
+                calls,
+                usage: res.usage,
+            })
         } else {
             anyhow::bail!(
                 "Uknown error while making request to Anthropic: {:?}",
@@ -119,18 +170,68 @@ impl Anthropic {
         &self,
         prompt: &Prompt,
         params: AnthropicRunParams,
-    ) -> anyhow::Result<String> {
-        let mut messages = vec![ChatMessage::new(
-            "system".to_string(),
-            params.system.clone(),
-        )];
-        messages.extend_from_slice(&params.messages);
-        let mut messages = format_chat_messages(&messages, prompt.try_into()?);
+        tools: Option<&ToolRequest<'_>>,
+    ) -> anyhow::Result<DoToolGenerationResponse> {
+        let mut messages = get_messages(prompt, &params)?;
         let system_prompt = messages.remove(0).content;
-        self.get_chat(system_prompt, messages, params).await
+        let mut messages: Vec<Value> = messages
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()?;
+        if let Some(tools) = tools {
+            messages.extend(get_tool_messages(tools.turns));
+        }
+        self.get_chat(system_prompt, messages, params, tools).await
     }
 }
 
+// Each response that called tools is followed by a user message with the results
+fn get_tool_messages(turns: &[ToolTurn]) -> Vec<Value> {
+    turns
+        .iter()
+        .flat_map(|turn| {
+            let text = (!turn.text.is_empty()).then(|| json!({"type": "text", "text": turn.text}));
+            let content: Vec<Value> = text
+                .into_iter()
+                .chain(turn.calls.iter().map(|call| {
+                    json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.name,
+                        "input": call.input
+                    })
+                }))
+                .collect();
+            let results: Vec<Value> = turn
+                .results
+                .iter()
+                .map(|result| {
+                    json!({
+                        "type": "tool_result",
+                        "tool_use_id": result.id,
+                        "content": result.content,
+                        "is_error": result.is_error
+                    })
+                })
+                .collect();
+            [
+                json!({"role": "assistant", "content": content}),
+                json!({"role": "user", "content": results}),
+            ]
+        })
+        .collect()
+}
+
+// The system prompt followed by the messages, with the context and code filled in
+fn get_messages(prompt: &Prompt, params: &AnthropicRunParams) -> anyhow::Result<Vec<ChatMessage>> {
+    let mut messages = vec![ChatMessage::new(
+        "system".to_string(),
+        params.system.clone(),
+    )];
+    messages.extend_from_slice(&params.messages);
+    Ok(format_chat_messages(&messages, prompt.try_into()?))
+}
+
 #[async_trait::async_trait]
 impl TransformerBackend for Anthropic {
     #[instrument(skip(self))]
@@ -138,25 +239,38 @@ impl TransformerBackend for Anthropic {
         &self,
         prompt: &Prompt,
         params: Value,
+        _cancel: &CancellationToken,
     ) -> anyhow::Result<DoGenerationResponse> {
         let params: AnthropicRunParams = serde_json::from_value(params)?;
-        let generated_text = self.do_get_chat(prompt, params).await?;
-        Ok(DoGenerationResponse { generated_text })
+        let response = self.do_get_chat(prompt, params, None).await?;
+        Ok(DoGenerationResponse {
+            generated_text: response.generated_text,
+            usage: response.usage,
+        })
     }
 
-    #[instrument(skip(self))]
-    async fn do_generate_stream(
+    #[instrument(skip(self, tools))]
+    async fn do_generate_with_tools(
         &self,
-        request: &GenerationStreamRequest,
-        _params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse> {
-        anyhow::bail!("GenerationStream is not yet implemented")
+        prompt: &Prompt,
+        params: Value,
+        tools: &ToolRequest<'_>,
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoToolGenerationResponse> {
+        let params: AnthropicRunParams = serde_json::from_value(params)?;
+        self.do_get_chat(prompt, params, Some(tools)).await
+    }
+
+    fn render_prompt(&self, prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+        let params: AnthropicRunParams = serde_json::from_value(params.clone())?;
+        Ok(RenderedPrompt::messages(get_messages(prompt, &params)?))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::tools::ToolResult;
     use serde_json::{from_value, json};
 
     #[tokio::test]
@@ -178,8 +292,38 @@ mod test {
             ],
             "max_tokens": 2
         });
-        let response = anthropic.do_generate(&prompt, run_params).await?;
+        let response = anthropic
+            .do_generate(&prompt, run_params, &CancellationToken::new())
+            .await?;
         assert!(!response.generated_text.is_empty());
         Ok(())
     }
+
+    #[test]
+    fn anthropic_formats_tool_turns() {
+        let turns = vec![ToolTurn {
+            text: String::new(),
+            calls: vec![ToolCall {
+                id: "toolu_1".to_string(),
+                name: "read_file".to_string(),
+                input: json!({"path": "src/main.rs"}),
+            }],
+            results: vec![ToolResult {
+                id: "toolu_1".to_string(),
+                content: "fn main() {}".to_string(),
+                is_error: false,
+            }],
+        }];
+        assert_eq!(
+            get_tool_messages(&turns),
+            vec![
+                json!({"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_1", "name": "read_file", "input": {"path": "src/main.rs"}}
+                ]}),
+                json!({"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "fn main() {}", "is_error": false}
+                ]}),
+            ]
+        );
+    }
 }
@@ -0,0 +1,343 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use candle_transformers::generation::Sampling;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use super::{RenderedPrompt, TransformerBackend};
+use crate::{
+    config::{self, ChatMessage, FIM},
+    memory_backends::Prompt,
+    progress::ProgressReporter,
+    template::apply_chat_template,
+    tokenizer::{EstimatedTokenizer, SharedTokenizer, Tokenizer},
+    transformer_worker::{
+        CompletionCandidate, DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse,
+    },
+    usage::TokenUsage,
+    utils::format_chat_messages,
+};
+
+mod model;
+use model::Model;
+
+const fn max_new_tokens_default() -> usize {
+    32
+}
+
+const fn temperature_default() -> f32 {
+    0.
+}
+
+const fn n_default() -> usize {
+    1
+}
+
+const fn top_k_default() -> usize {
+    40
+}
+
+const fn top_p_default() -> f32 {
+    0.95
+}
+
+const fn repeat_penalty_default() -> f32 {
+    1.
+}
+
+const fn repeat_last_n_default() -> usize {
+    64
+}
+
+// The parameters of llama.cpp that candle supports, with the same names and defaults
+// NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
+#[derive(Debug, Deserialize)]
+pub struct CandleRunParams {
+    pub fim: Option<FIM>,
+    messages: Option<Vec<ChatMessage>>,
+    // A Jinja template, default: the one in tokenizer_config.json
+    chat_template: Option<String>,
+    #[serde(default = "max_new_tokens_default")]
+    pub max_tokens: usize,
+    // A temperature of 0 means greedy sampling
+    #[serde(default = "temperature_default")]
+    pub temperature: f32,
+    // 0 disables top k sampling
+    #[serde(default = "top_k_default")]
+    pub top_k: usize,
+    #[serde(default = "top_p_default")]
+    pub top_p: f32,
+    // A repeat_penalty of 1 disables the penalty
+    #[serde(default = "repeat_penalty_default")]
+    pub repeat_penalty: f32,
+    // How many of the last tokens are considered for the penalty
+    #[serde(default = "repeat_last_n_default")]
+    pub repeat_last_n: usize,
+    pub seed: Option<u64>,
+    // Generation stops before the first occurence of any of these
+    #[serde(default)]
+    pub stop: Vec<String>,
+    // How many completion candidates to sample
+    #[serde(default = "n_default")]
+    pub n: usize,
+}
+
+// The parameters of llama.cpp candle does not support, they fail the request instead of being
+// silently ignored
+const UNSUPPORTED_PARAMS: [&str; 9] = [
+    "chat_format",
+    "min_p",
+    "frequency_penalty",
+    "presence_penalty",
+    "mirostat",
+    "mirostat_tau",
+    "mirostat_eta",
+    "grammar",
+    "grammar_file",
+];
+
+impl CandleRunParams {
+    fn new(params: Value) -> anyhow::Result<Self> {
+        if let Some(name) = UNSUPPORTED_PARAMS
+            .iter()
+            .find(|name| params.get(**name).is_some())
+        {
+            anyhow::bail!("candle does not support the llama.cpp parameter `{name}`")
+        }
+        Ok(serde_json::from_value(params)?)
+    }
+
+    fn get_sampling(&self) -> Sampling {
+        let temperature = f64::from(self.temperature);
+        let p = f64::from(self.top_p);
+        match (self.temperature <= 0., self.top_k) {
+            (true, _) => Sampling::ArgMax,
+            (false, 0) => Sampling::TopP { p, temperature },
+            (false, k) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    }
+
+    // Greedy sampling gives the same completion every time
+    fn get_n(&self) -> usize {
+        if self.temperature <= 0. {
+            1
+        } else {
+            self.n.max(1)
+        }
+    }
+}
+
+pub struct Candle {
+    model: Arc<Model>,
+}
+
+impl Candle {
+    #[instrument]
+    pub fn new(configuration: config::Candle) -> anyhow::Result<Self> {
+        let progress = ProgressReporter::begin("lsp-ai", Some("Loading model".to_string()));
+        let model = Model::new(&configuration);
+        progress.end(Some(match &model {
+            Ok(_) => "Model loaded and ready".to_string(),
+            Err(e) => format!("Error loading the model: {e}"),
+        }));
+        Ok(Self {
+            model: Arc::new(model?),
+        })
+    }
+}
+
+struct ModelTokenizer(Arc<Model>);
+
+impl Tokenizer for ModelTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        self.0
+            .count_tokens(text)
+            .map(|tokens| tokens as usize)
+            .unwrap_or_else(|_| EstimatedTokenizer.count_tokens(text))
+    }
+}
+
+fn get_prompt_string(
+    model: &Model,
+    prompt: &Prompt,
+    params: &CandleRunParams,
+) -> anyhow::Result<String> {
+    match prompt {
+        Prompt::ContextAndCode(context_and_code) => Ok(match &params.messages {
+            Some(completion_messages) => {
+                let chat_messages = format_chat_messages(completion_messages, context_and_code);
+                let chat_template = params
+                    .chat_template
+                    .as_deref()
+                    .or(model.chat_template.as_deref())
+                    .context("the model has no chat template, set `chat_template`")?;
+                apply_chat_template(
+                    chat_template,
+                    chat_messages,
+                    &model.bos_token,
+                    &model.eos_token,
+                )?
+            }
+            None => context_and_code.code.clone(),
+        }),
+        Prompt::FIM(fim) => Ok(match &params.fim {
+            Some(fim_params) => {
+                format!(
+                    "{}{}{}{}{}",
+                    fim_params.start, fim.prompt, fim_params.middle, fim.suffix, fim_params.end
+                )
+            }
+            None => anyhow::bail!("Prompt type is FIM but no FIM parameters provided"),
+        }),
+    }
+}
+
+// Counted with the model's tokenizer so the usage is exact
+fn get_usage(model: &Model, prompt: &str, completion: &str) -> anyhow::Result<TokenUsage> {
+    Ok(TokenUsage::new(
+        model.count_tokens(prompt)?,
+        model.count_tokens(completion)?,
+    ))
+}
+
+#[async_trait::async_trait]
+impl TransformerBackend for Candle {
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn get_tokenizer(&self) -> SharedTokenizer {
+        Arc::new(ModelTokenizer(self.model.clone()))
+    }
+
+    // The chat template is applied, this is the text the model sees
+    fn render_prompt(&self, prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+        let params = CandleRunParams::new(params.clone())?;
+        Ok(RenderedPrompt::prompt(get_prompt_string(
+            &self.model,
+            prompt,
+            &params,
+        )?))
+    }
+
+    #[instrument(skip(self))]
+    async fn do_completion(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoCompletionResponse> {
+        let params = CandleRunParams::new(params)?;
+        let prompt = get_prompt_string(&self.model, prompt, &params)?;
+        let seed = params.seed.unwrap_or_else(rand::random);
+        let candidates = (0..params.get_n() as u64)
+            .map(|i| {
+                self.model
+                    .generate(&prompt, &params, seed.wrapping_add(i), cancel, |_| Ok(()))
+                    .map(|generation| CompletionCandidate {
+                        insert_text: generation.text,
+                        score: Some(generation.logprob),
+                    })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let completion: String = candidates
+            .iter()
+            .map(|candidate| candidate.insert_text.as_str())
+            .collect();
+        let usage = get_usage(&self.model, &prompt, &completion)?;
+        Ok(DoCompletionResponse {
+            candidates,
+            usage: Some(usage),
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let params = CandleRunParams::new(params)?;
+        let prompt = get_prompt_string(&self.model, prompt, &params)?;
+        let seed = params.seed.unwrap_or_else(rand::random);
+        let generated_text = self
+            .model
+            .generate(&prompt, &params, seed, cancel, |_| Ok(()))?
+            .text;
+        let usage = get_usage(&self.model, &prompt, &generated_text)?;
+        Ok(DoGenerationResponse {
+            generated_text,
+            usage: Some(usage),
+        })
+    }
+
+    #[instrument(skip(self, tx))]
+    async fn do_generate_stream(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<String>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        let params = CandleRunParams::new(params)?;
+        let prompt = get_prompt_string(&self.model, prompt, &params)?;
+        let seed = params.seed.unwrap_or_else(rand::random);
+        let generated_text = self
+            .model
+            .generate(&prompt, &params, seed, cancel, |token| {
+                tx.send(token.to_owned())
+                    .map_err(|_| anyhow::anyhow!("sending on channel failed"))
+            })?
+            .text;
+        let usage = get_usage(&self.model, &prompt, &generated_text)?;
+        Ok(DoGenerationStreamResponse {
+            generated_text,
+            usage: Some(usage),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn candle_rejects_unsupported_params() {
+        assert!(CandleRunParams::new(json!({ "max_tokens": 4, "min_p": 0.1 })).is_err());
+        assert!(CandleRunParams::new(json!({ "max_tokens": 4, "other": true })).is_ok());
+    }
+
+    #[tokio::test]
+    async fn candle_do_generate_stream_fim() -> anyhow::Result<()> {
+        let configuration: config::Candle = serde_json::from_value(json!({
+            "repository": "Qwen/Qwen2.5-Coder-0.5B",
+        }))?;
+        let candle = Candle::new(configuration)?;
+        let prompt = Prompt::default_fim();
+        let run_params = json!({
+            "fim": {
+                "start": "<|fim_prefix|>",
+                "middle": "<|fim_suffix|>",
+                "end": "<|fim_middle|>"
+            },
+            "max_tokens": 4
+        });
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let response = candle
+            .do_generate_stream(&prompt, run_params, tx, &CancellationToken::new())
+            .await?;
+        let mut streamed = String::new();
+        while let Some(token) = rx.recv().await {
+            streamed.push_str(&token);
+        }
+        assert!(!response.generated_text.is_empty());
+        assert_eq!(streamed, response.generated_text);
+        Ok(())
+    }
+}
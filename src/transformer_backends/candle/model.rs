@@ -0,0 +1,295 @@
+use std::{collections::BTreeSet, path::PathBuf};
+
+use anyhow::Context;
+use candle_core::{utils, DType, Device, Tensor, D};
+use candle_nn::VarBuilder;
+use candle_transformers::{
+    generation::LogitsProcessor,
+    models::{qwen2, starcoder2},
+};
+use hf_hub::{api::sync::ApiBuilder, Cache, Repo, RepoType};
+use parking_lot::Mutex;
+use serde_json::Value;
+use tokenizers::Tokenizer;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{
+    config::{self, CandleArchitecture, CandleDType, CandleDevice},
+    transformer_backends::is_hf_hub_offline,
+    utils::StopSequenceFilter,
+};
+
+use super::CandleRunParams;
+
+enum Architecture {
+    StarCoder2(starcoder2::Model),
+    Qwen2(qwen2::ModelForCausalLM),
+}
+
+impl Architecture {
+    // The logits of the token after the input
+    fn forward(&mut self, input: &Tensor, offset: usize) -> candle_core::Result<Tensor> {
+        match self {
+            Self::StarCoder2(model) => model.forward(input, offset),
+            Self::Qwen2(model) => model.forward(input, offset),
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        match self {
+            Self::StarCoder2(model) => model.clear_kv_cache(),
+            Self::Qwen2(model) => model.clear_kv_cache(),
+        }
+    }
+}
+
+// From a local directory or the Hugging Face hub, downloaded unless it is already in the cache
+fn get_file(configuration: &config::Candle, name: &str) -> anyhow::Result<PathBuf> {
+    if let Some(directory) = &configuration.directory {
+        let path = PathBuf::from(directory).join(name);
+        anyhow::ensure!(path.is_file(), "model file not found: {}", path.display());
+        return Ok(path);
+    }
+    let repository = configuration
+        .repository
+        .as_deref()
+        .context("To use candle provide either `directory` or `repository`")?;
+    let repo = match &configuration.revision {
+        Some(revision) => {
+            Repo::with_revision(repository.to_owned(), RepoType::Model, revision.clone())
+        }
+        None => Repo::model(repository.to_owned()),
+    };
+    if configuration.offline || is_hf_hub_offline() {
+        return Cache::default().repo(repo).get(name).with_context(|| {
+            format!("offline mode is enabled and {name} of {repository} is not in the Hugging Face cache. Download it first or use `directory`")
+        });
+    }
+    let api = ApiBuilder::new().with_progress(true).build()?;
+    Ok(api.repo(repo).get(name)?)
+}
+
+// Larger models split their weights into shards listed in an index
+fn get_weights(configuration: &config::Candle) -> anyhow::Result<Vec<PathBuf>> {
+    if let Ok(path) = get_file(configuration, "model.safetensors") {
+        return Ok(vec![path]);
+    }
+    let index: Value = serde_json::from_str(&std::fs::read_to_string(get_file(
+        configuration,
+        "model.safetensors.index.json",
+    )?)?)?;
+    let shards: BTreeSet<&str> = index["weight_map"]
+        .as_object()
+        .context("`weight_map` missing from model.safetensors.index.json")?
+        .values()
+        .filter_map(Value::as_str)
+        .collect();
+    shards
+        .into_iter()
+        .map(|shard| get_file(configuration, shard))
+        .collect()
+}
+
+fn get_device(device: CandleDevice, gpu: usize) -> anyhow::Result<Device> {
+    Ok(match device {
+        CandleDevice::Cpu => Device::Cpu,
+        CandleDevice::Cuda => Device::new_cuda(gpu)?,
+        CandleDevice::Metal => Device::new_metal(gpu)?,
+        CandleDevice::Auto if utils::cuda_is_available() => Device::new_cuda(gpu)?,
+        CandleDevice::Auto if utils::metal_is_available() => Device::new_metal(gpu)?,
+        CandleDevice::Auto => Device::Cpu,
+    })
+}
+
+// Special tokens in tokenizer_config.json are either the token or an object with its `content`
+fn get_special_token(tokenizer_config: &Value, name: &str) -> Option<String> {
+    let token = &tokenizer_config[name];
+    token
+        .as_str()
+        .or_else(|| token["content"].as_str())
+        .map(str::to_owned)
+}
+
+pub struct Generation {
+    pub text: String,
+    // The mean log probability of the generated tokens
+    pub logprob: f32,
+}
+
+pub struct Model {
+    // Generations run one at a time on the kv cache of the model
+    architecture: Mutex<Architecture>,
+    tokenizer: Tokenizer,
+    device: Device,
+    eos_token_ids: Vec<u32>,
+    pub bos_token: String,
+    pub eos_token: String,
+    pub chat_template: Option<String>,
+}
+
+impl Model {
+    pub fn new(configuration: &config::Candle) -> anyhow::Result<Self> {
+        let device = get_device(configuration.device, configuration.gpu)?;
+        let dtype = match configuration.dtype {
+            Some(CandleDType::F32) => DType::F32,
+            Some(CandleDType::F16) => DType::F16,
+            Some(CandleDType::BF16) => DType::BF16,
+            None if device.is_cuda() => DType::BF16,
+            None => DType::F32,
+        };
+        let model_config = std::fs::read_to_string(get_file(configuration, "config.json")?)?;
+        let model_json: Value = serde_json::from_str(&model_config)?;
+        let architecture = match configuration.architecture {
+            Some(architecture) => architecture,
+            None => match model_json["model_type"].as_str() {
+                Some("starcoder2") => CandleArchitecture::StarCoder2,
+                Some("qwen2") => CandleArchitecture::Qwen2,
+                model_type => anyhow::bail!(
+                    "candle does not support the model type {model_type:?}, set `architecture` if the model is compatible with a supported one"
+                ),
+            },
+        };
+        let weights = get_weights(configuration)?;
+        info!("loading {} weight files on {device:?}", weights.len());
+        // SAFETY: The weight files are not modified while they are mapped
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&weights, dtype, &device)? };
+        let architecture = match architecture {
+            CandleArchitecture::StarCoder2 => Architecture::StarCoder2(starcoder2::Model::new(
+                &serde_json::from_str(&model_config)?,
+                vb,
+            )?),
+            CandleArchitecture::Qwen2 => Architecture::Qwen2(qwen2::ModelForCausalLM::new(
+                &serde_json::from_str(&model_config)?,
+                vb,
+            )?),
+        };
+
+        let tokenizer = Tokenizer::from_file(get_file(configuration, "tokenizer.json")?)
+            .map_err(anyhow::Error::msg)?;
+        // Not every model has one
+        let tokenizer_config: Value = get_file(configuration, "tokenizer_config.json")
+            .ok()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        let bos_token = get_special_token(&tokenizer_config, "bos_token").unwrap_or_default();
+        let eos_token = get_special_token(&tokenizer_config, "eos_token").unwrap_or_default();
+        // `eos_token_id` in config.json is one id or a list of them
+        let mut eos_token_ids: Vec<u32> = match &model_json["eos_token_id"] {
+            Value::Array(ids) => ids.iter().filter_map(Value::as_u64).collect(),
+            id => id.as_u64().into_iter().collect(),
+        }
+        .into_iter()
+        .filter_map(|id| u32::try_from(id).ok())
+        .collect();
+        eos_token_ids.extend(tokenizer.token_to_id(&eos_token));
+        Ok(Self {
+            architecture: Mutex::new(architecture),
+            tokenizer,
+            device,
+            eos_token_ids,
+            bos_token,
+            eos_token,
+            chat_template: tokenizer_config["chat_template"]
+                .as_str()
+                .map(str::to_owned),
+        })
+    }
+
+    fn tokenize(&self, text: &str) -> anyhow::Result<Vec<u32>> {
+        Ok(self
+            .tokenizer
+            .encode(text, true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec())
+    }
+
+    pub fn count_tokens(&self, text: &str) -> anyhow::Result<u64> {
+        Ok(self.tokenize(text)?.len() as u64)
+    }
+
+    // Calls `on_token` with the text as it is generated, without the stop sequence it stopped at
+    pub fn generate(
+        &self,
+        prompt: &str,
+        params: &CandleRunParams,
+        seed: u64,
+        cancel: &CancellationToken,
+        mut on_token: impl FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Generation> {
+        let mut tokens = self.tokenize(prompt)?;
+        let prompt_len = tokens.len();
+        let mut logits_processor = LogitsProcessor::from_sampling(seed, params.get_sampling());
+        let mut architecture = self.architecture.lock();
+        architecture.clear_kv_cache();
+
+        let mut output = StopSequenceFilter::new(params.stop.clone());
+        // How much of the decoded text went to the filter
+        let mut pushed = 0;
+        let mut logprob_sum = 0.;
+        // The tokens before it are in the kv cache
+        let mut offset = 0;
+        for _ in 0..params.max_tokens {
+            if cancel.is_cancelled() {
+                anyhow::bail!("the generation was cancelled")
+            }
+            let input = Tensor::new(&tokens[offset..], &self.device)?.unsqueeze(0)?;
+            let logits = architecture
+                .forward(&input, offset)?
+                .squeeze(0)?
+                .squeeze(0)?
+                .to_dtype(DType::F32)?;
+            offset = tokens.len();
+            let logits = if params.repeat_penalty == 1. {
+                logits
+            } else {
+                let start = tokens.len().saturating_sub(params.repeat_last_n);
+                candle_transformers::utils::apply_repeat_penalty(
+                    &logits,
+                    params.repeat_penalty,
+                    &tokens[start..],
+                )?
+            };
+            let token = logits_processor.sample(&logits)?;
+            if self.eos_token_ids.contains(&token) {
+                break;
+            }
+            logprob_sum += candle_nn::ops::log_softmax(&logits, D::Minus1)?
+                .get(token as usize)?
+                .to_scalar::<f32>()?;
+            tokens.push(token);
+
+            let decoded = self
+                .tokenizer
+                .decode(&tokens[prompt_len..], true)
+                .map_err(anyhow::Error::msg)?;
+            // A token can end in the middle of a char, it is decoded once the rest comes in
+            if decoded.ends_with('\u{fffd}') {
+                continue;
+            }
+            let text = output.push(decoded.get(pushed..).unwrap_or_default());
+            pushed = decoded.len();
+            if !text.is_empty() {
+                on_token(&text)?;
+            }
+            if output.is_stopped() {
+                break;
+            }
+        }
+        let text = output.finish();
+        if !text.is_empty() {
+            on_token(&text)?;
+        }
+        let generated = tokens.len() - prompt_len;
+        Ok(Generation {
+            text: output.text().to_owned(),
+            logprob: if generated == 0 {
+                0.
+            } else {
+                logprob_sum / generated as f32
+            },
+        })
+    }
+}
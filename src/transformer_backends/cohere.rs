@@ -0,0 +1,301 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use crate::{
+    auth::get_auth_token,
+    config::{self, ChatMessage},
+    http_client::get_client,
+    memory_backends::{ContextAndCodePrompt, Prompt},
+    transformer_worker::DoGenerationResponse,
+    usage::TokenUsage,
+    utils::format_chat_messages,
+};
+
+use super::{retry, RenderedPrompt, TransformerBackend};
+
+const fn max_tokens_default() -> usize {
+    64
+}
+
+const fn top_p_default() -> f32 {
+    0.95
+}
+
+const fn temperature_default() -> f32 {
+    0.1
+}
+
+// NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
+#[derive(Debug, Deserialize)]
+pub struct CohereRunParams {
+    messages: Vec<ChatMessage>,
+    #[serde(default = "max_tokens_default")]
+    pub max_tokens: usize,
+    #[serde(default = "top_p_default")]
+    pub top_p: f32,
+    pub top_k: Option<usize>,
+    #[serde(default = "temperature_default")]
+    pub temperature: f32,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    // Sends the retrieved context as documents the model grounds its answer in, `{CONTEXT}` in
+    // the messages is left empty
+    #[serde(default)]
+    pub documents: bool,
+}
+
+// The file a chunk of the context is from is its title
+#[derive(Debug, Serialize, PartialEq)]
+struct CohereDocumentData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    snippet: String,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct CohereDocument {
+    data: CohereDocumentData,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum CohereContent {
+    #[serde(rename = "text")]
+    Text { text: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct CohereMessage {
+    #[serde(default)]
+    content: Vec<CohereContent>,
+}
+
+#[derive(Deserialize)]
+struct CohereUsage {
+    // What the model saw and generated, `billed_units` leaves out e.g. the system prompt
+    tokens: Option<TokenUsage>,
+}
+
+#[derive(Deserialize)]
+struct CohereChatResponse {
+    message: CohereMessage,
+    usage: Option<CohereUsage>,
+}
+
+pub struct Cohere {
+    config: config::Cohere,
+}
+
+// Chunks of the context start with a line naming their file, e.g. `file:///src/main.rs:`
+fn is_chunk_header(line: &str) -> bool {
+    line.ends_with(':')
+        && line
+            .split_whitespace()
+            .next()
+            .is_some_and(|uri| uri.contains("://"))
+}
+
+// Splits the context back into the chunks it was joined from
+fn get_documents(context: &str) -> Vec<CohereDocument> {
+    let mut chunks: Vec<(Option<&str>, Vec<&str>)> = vec![];
+    let mut previous_blank = true;
+    for line in context.lines() {
+        if previous_blank && is_chunk_header(line) {
+            chunks.push((Some(line.trim_end_matches(':')), vec![]));
+        } else {
+            match chunks.last_mut() {
+                Some((_, lines)) => lines.push(line),
+                None => chunks.push((None, vec![line])),
+            }
+        }
+        previous_blank = line.trim().is_empty();
+    }
+    chunks
+        .into_iter()
+        .filter_map(|(title, lines)| {
+            let snippet = lines.join("\n").trim().to_owned();
+            (!snippet.is_empty()).then(|| CohereDocument {
+                data: CohereDocumentData {
+                    title: title.map(str::to_owned),
+                    snippet,
+                },
+            })
+        })
+        .collect()
+}
+
+// The messages with the context and code filled in, and the documents if the context is sent
+// as documents
+fn get_messages(
+    prompt: &Prompt,
+    params: &CohereRunParams,
+) -> anyhow::Result<(Vec<ChatMessage>, Vec<CohereDocument>)> {
+    let prompt: &ContextAndCodePrompt = prompt.try_into()?;
+    if params.documents {
+        let messages = format_chat_messages(
+            &params.messages,
+            &ContextAndCodePrompt::new(String::new(), prompt.code.clone()),
+        );
+        Ok((messages, get_documents(&prompt.context)))
+    } else {
+        Ok((format_chat_messages(&params.messages, prompt), vec![]))
+    }
+}
+
+impl Cohere {
+    #[instrument]
+    pub fn new(config: config::Cohere) -> Self {
+        Self { config }
+    }
+
+    async fn get_chat(
+        &self,
+        prompt: &Prompt,
+        params: CohereRunParams,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let token = get_auth_token(
+            self.config.auth_token_env_var_name.as_deref(),
+            self.config.auth_token.as_deref(),
+            self.config.auth_token_keyring.as_ref(),
+            self.config.auth_token_command.as_deref(),
+        ).await?
+        .context("set `auth_token_env_var_name`, `auth_token`, `auth_token_keyring` or `auth_token_command` to use Cohere")?;
+        let (messages, documents) = get_messages(prompt, &params)?;
+        let mut body = json!({
+            "model": self.config.model,
+            "messages": messages,
+            "max_tokens": params.max_tokens,
+            "p": params.top_p,
+            "temperature": params.temperature,
+            "stop_sequences": params.stop,
+        });
+        if !documents.is_empty() {
+            body["documents"] = serde_json::to_value(documents)?;
+        }
+        if let Some(top_k) = params.top_k {
+            body["k"] = json!(top_k);
+        }
+        if let Some(frequency_penalty) = params.frequency_penalty {
+            body["frequency_penalty"] = json!(frequency_penalty);
+        }
+        if let Some(presence_penalty) = params.presence_penalty {
+            body["presence_penalty"] = json!(presence_penalty);
+        }
+        if let Some(seed) = params.seed {
+            body["seed"] = json!(seed);
+        }
+        let client = get_client();
+        let request = client
+            .post(
+                self.config
+                    .chat_endpoint
+                    .as_deref()
+                    .unwrap_or("https://api.cohere.com/v2/chat"),
+            )
+            .bearer_auth(token)
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .json(&body);
+        let res = retry::send(request, &self.config.retry).await?;
+        let status = res.status();
+        if !status.is_success() {
+            let error: Value = res.json().await?;
+            anyhow::bail!("Cohere returned {status}: {}", error["message"])
+        }
+        let res: CohereChatResponse = res.json().await?;
+        let generated_text = res
+            .message
+            .content
+            .into_iter()
+            .filter_map(|content| match content {
+                CohereContent::Text { text } => Some(text),
+                CohereContent::Other => None,
+            })
+            .collect();
+        Ok(DoGenerationResponse {
+            generated_text,
+            usage: res.usage.and_then(|usage| usage.tokens),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl TransformerBackend for Cohere {
+    #[instrument(skip(self))]
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let params: CohereRunParams = serde_json::from_value(params)?;
+        self.get_chat(prompt, params).await
+    }
+
+    fn render_prompt(&self, prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+        let params: CohereRunParams = serde_json::from_value(params.clone())?;
+        Ok(RenderedPrompt::messages(get_messages(prompt, &params)?.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::{from_value, json};
+
+    #[tokio::test]
+    async fn cohere_chat_do_generate() -> anyhow::Result<()> {
+        let configuration: config::Cohere = from_value(json!({
+            "model": "command-r",
+            "auth_token_env_var_name": "CO_API_KEY",
+        }))?;
+        let cohere = Cohere::new(configuration);
+        let prompt = Prompt::default_with_cursor();
+        let run_params = json!({
+            "messages": [
+                {
+                    "role": "user",
+                    "content": "Test {CONTEXT} - {CODE}"
+                }
+            ],
+            "max_tokens": 2
+        });
+        let response = cohere
+            .do_generate(&prompt, run_params, &CancellationToken::new())
+            .await?;
+        assert!(!response.generated_text.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn cohere_sends_context_as_documents() -> anyhow::Result<()> {
+        let context =
+            "file:///src/a.rs (add):\nfn add() {}\n\nfn sub() {}\n\nfile:///src/b.rs:\nstruct B;";
+        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt::new(
+            context.to_string(),
+            "fn main() {<CURSOR>}".to_string(),
+        ));
+        let params: CohereRunParams = from_value(json!({
+            "messages": [{"role": "user", "content": "{CONTEXT}{CODE}"}],
+            "documents": true,
+        }))?;
+        let (messages, documents) = get_messages(&prompt, &params)?;
+        assert_eq!(messages[0].content, "fn main() {<CURSOR>}");
+        assert_eq!(
+            serde_json::to_value(documents)?,
+            json!([
+                {"data": {"title": "file:///src/a.rs (add)", "snippet": "fn add() {}\n\nfn sub() {}"}},
+                {"data": {"title": "file:///src/b.rs", "snippet": "struct B;"}}
+            ])
+        );
+        Ok(())
+    }
+}
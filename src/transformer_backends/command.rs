@@ -0,0 +1,268 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{ChildStdin, ChildStdout, Stdio},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, instrument};
+
+use crate::{
+    config,
+    memory_backends::Prompt,
+    transformer_worker::{DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse},
+    usage::TokenUsage,
+};
+
+use super::{
+    process::{self, Supervisor},
+    render_prompt, RenderedPrompt, TransformerBackend,
+};
+
+// Each message is one line of JSON. The program gets one request at a time and answers it with
+// its tokens, if streamed, and one `generation` or `error`. A `cancel` may come in while it runs.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Completion {
+        prompt: RenderedPrompt,
+        params: Value,
+    },
+    Generate {
+        prompt: RenderedPrompt,
+        params: Value,
+    },
+    GenerateStream {
+        prompt: RenderedPrompt,
+        params: Value,
+    },
+    Cancel,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    Token {
+        text: String,
+    },
+    Generation {
+        generated_text: String,
+        usage: Option<TokenUsage>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+fn write_line(writer: &mut impl Write, message: &impl Serialize) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()?;
+    Ok(())
+}
+
+struct Program(config::Command);
+
+impl process::Program for Program {
+    type Request = Request;
+    type Response = Response;
+
+    fn name(&self) -> String {
+        format!("`{}`", self.0.command)
+    }
+
+    fn command(&self) -> anyhow::Result<std::process::Command> {
+        let mut command = std::process::Command::new(&self.0.command);
+        command
+            .args(&self.0.args)
+            .envs(&self.0.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // Shows up in the logs of the editor like the logs of the server
+            .stderr(Stdio::inherit());
+        Ok(command)
+    }
+
+    fn write(&self, stdin: &mut ChildStdin, request: &Request) -> anyhow::Result<()> {
+        write_line(stdin, request)
+    }
+
+    fn read(&self, stdout: &mut BufReader<ChildStdout>) -> anyhow::Result<Option<Response>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stdout.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(response) => return Ok(Some(response)),
+                Err(e) => error!("invalid response from {}: {e}", self.name()),
+            }
+        }
+    }
+
+    fn cancel(&self) -> Request {
+        Request::Cancel
+    }
+
+    fn get_token(&self, response: Response) -> Result<String, Response> {
+        match response {
+            Response::Token { text } => Ok(text),
+            response => Err(response),
+        }
+    }
+}
+
+// Runs the model with a program the user provides. It is started with the first request and
+// again with the next one after it exits.
+pub struct Command {
+    command: String,
+    local: bool,
+    supervisor: Supervisor<Program>,
+}
+
+impl Command {
+    #[instrument]
+    pub fn new(configuration: config::Command) -> Self {
+        Self {
+            command: configuration.command.clone(),
+            local: configuration.local,
+            supervisor: Supervisor::new(Program(configuration)),
+        }
+    }
+
+    async fn request(
+        &self,
+        request: Request,
+        on_token: impl FnMut(String) + Send + 'static,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        match self.supervisor.request(request, on_token, cancel).await? {
+            Response::Generation {
+                generated_text,
+                usage,
+            } => Ok(DoGenerationResponse {
+                generated_text,
+                usage,
+            }),
+            Response::Error { message } => anyhow::bail!(message),
+            response => anyhow::bail!("unexpected response from `{}`: {response:?}", self.command),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TransformerBackend for Command {
+    fn is_local(&self) -> bool {
+        self.local
+    }
+
+    #[instrument(skip(self))]
+    async fn do_completion(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoCompletionResponse> {
+        let request = Request::Completion {
+            prompt: render_prompt(prompt, &params)?,
+            params,
+        };
+        let response = self.request(request, |_| (), cancel).await?;
+        Ok(DoCompletionResponse {
+            usage: response.usage,
+            ..DoCompletionResponse::new(response.generated_text)
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let request = Request::Generate {
+            prompt: render_prompt(prompt, &params)?,
+            params,
+        };
+        self.request(request, |_| (), cancel).await
+    }
+
+    #[instrument(skip(self, tx))]
+    async fn do_generate_stream(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<String>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        let request = Request::GenerateStream {
+            prompt: render_prompt(prompt, &params)?,
+            params,
+        };
+        // A closed channel means nobody waits for the tokens anymore, the request is cancelled
+        let on_token = move |text| {
+            let _ = tx.send(text);
+        };
+        let response = self.request(request, on_token, cancel).await?;
+        Ok(DoGenerationStreamResponse {
+            generated_text: response.generated_text,
+            usage: response.usage,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory_backends::ContextAndCodePrompt;
+    use serde_json::{from_value, json};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_do_generate_stream() -> anyhow::Result<()> {
+        // Answers every request with two tokens
+        let script = r#"while read -r line; do
+            echo '{"type": "token", "text": "Hello"}'
+            echo '{"type": "token", "text": " world"}'
+            echo '{"type": "generation", "generated_text": "Hello world", "usage": {"prompt_tokens": 3, "completion_tokens": 2}}'
+        done"#;
+        let configuration: config::Command = from_value(json!({
+            "command": "sh",
+            "args": ["-c", script]
+        }))?;
+        let command = Command::new(configuration);
+        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt::new(
+            String::new(),
+            "def hello".to_string(),
+        ));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let response = command
+            .do_generate_stream(&prompt, json!({}), tx, &CancellationToken::new())
+            .await?;
+        let mut streamed = String::new();
+        while let Some(chunk) = rx.recv().await {
+            streamed.push_str(&chunk);
+        }
+        assert_eq!(streamed, "Hello world");
+        assert_eq!(response.generated_text, "Hello world");
+        assert_eq!(response.usage, Some(TokenUsage::new(3, 2)));
+
+        let line = serde_json::to_value(Request::Generate {
+            prompt: RenderedPrompt::prompt("def hello".to_string()),
+            params: json!({"max_tokens": 4}),
+        })?;
+        assert_eq!(
+            line,
+            json!({"type": "generate", "prompt": {"prompt": "def hello"}, "params": {"max_tokens": 4}})
+        );
+        Ok(())
+    }
+}
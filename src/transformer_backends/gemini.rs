@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use crate::{
+    auth::get_auth_token,
+    config::{self, ChatMessage, FIM},
+    http_client::get_client,
+    memory_backends::Prompt,
+    transformer_worker::{DoGenerationResponse, DoGenerationStreamResponse},
+    usage::TokenUsage,
+    utils::{format_chat_messages, format_context_code},
+};
+
+use super::{retry, RenderedPrompt, TransformerBackend};
+
+const fn max_tokens_default() -> usize {
+    64
+}
+
+const fn top_p_default() -> f32 {
+    0.95
+}
+
+const fn temperature_default() -> f32 {
+    0.1
+}
+
+// NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
+#[derive(Debug, Deserialize)]
+pub struct GeminiRunParams {
+    pub fim: Option<FIM>,
+    messages: Option<Vec<ChatMessage>>,
+    system_instruction: Option<String>,
+    // Passed through to the API as is
+    #[serde(default)]
+    safety_settings: Vec<Value>,
+    #[serde(default = "max_tokens_default")]
+    pub max_tokens: usize,
+    #[serde(default = "top_p_default")]
+    pub top_p: f32,
+    pub top_k: Option<u32>,
+    #[serde(default = "temperature_default")]
+    pub temperature: f32,
+    #[serde(default)]
+    pub stop: Vec<String>,
+}
+
+pub struct Gemini {
+    configuration: config::Gemini,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct GeminiPart {
+    text: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct GeminiContent {
+    #[serde(default)]
+    role: String,
+    #[serde(default)]
+    parts: Vec<GeminiPart>,
+}
+
+#[derive(Deserialize)]
+struct GeminiCandidate {
+    content: Option<GeminiContent>,
+}
+
+#[derive(Deserialize)]
+struct GeminiGenerateContentResponse {
+    candidates: Option<Vec<GeminiCandidate>>,
+    // Streamed responses report the usage so far with each chunk
+    #[serde(rename = "usageMetadata")]
+    usage_metadata: Option<TokenUsage>,
+    error: Option<Value>,
+    #[serde(default)]
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+impl GeminiGenerateContentResponse {
+    fn into_text(self) -> anyhow::Result<String> {
+        if let Some(error) = self.error {
+            anyhow::bail!("{:?}", error.to_string())
+        } else if let Some(candidates) = self.candidates {
+            Ok(candidates
+                .into_iter()
+                .next()
+                .and_then(|c| c.content)
+                .map(|c| c.parts.into_iter().map(|p| p.text).collect())
+                .unwrap_or_default())
+        } else {
+            anyhow::bail!(
+                "Unknown error while making request to Gemini: {:?}",
+                self.other
+            )
+        }
+    }
+}
+
+// Gemini takes the system prompt separately and calls the assistant role `model`
+fn to_gemini_contents(
+    messages: Vec<ChatMessage>,
+    system_instruction: Option<String>,
+) -> (Vec<GeminiContent>, Option<String>) {
+    let mut system = system_instruction.into_iter().collect::<Vec<String>>();
+    let mut contents = vec![];
+    for message in messages {
+        match message.role.as_str() {
+            "system" => system.push(message.content),
+            role => contents.push(GeminiContent {
+                role: if role == "assistant" {
+                    "model".to_string()
+                } else {
+                    role.to_string()
+                },
+                parts: vec![GeminiPart {
+                    text: message.content,
+                }],
+            }),
+        }
+    }
+    let system = (!system.is_empty()).then(|| system.join("\n"));
+    (contents, system)
+}
+
+impl Gemini {
+    #[instrument]
+    pub fn new(configuration: config::Gemini) -> Self {
+        Self { configuration }
+    }
+
+    async fn get_token(&self) -> anyhow::Result<String> {
+        get_auth_token(
+            self.configuration.auth_token_env_var_name.as_deref(),
+            self.configuration.auth_token.as_deref(),
+            self.configuration.auth_token_keyring.as_ref(),
+            self.configuration.auth_token_command.as_deref(),
+        ).await?
+        .context("set `auth_token_env_var_name`, `auth_token`, `auth_token_keyring` or `auth_token_command` to use Gemini")
+    }
+
+    fn get_url(&self, method: &str) -> String {
+        format!(
+            "{}/models/{}:{method}",
+            self.configuration
+                .endpoint
+                .as_deref()
+                .unwrap_or("https://generativelanguage.googleapis.com/v1beta")
+                .trim_end_matches('/'),
+            self.configuration.model
+        )
+    }
+
+    fn get_messages(
+        &self,
+        prompt: &Prompt,
+        params: &GeminiRunParams,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        match prompt {
+            Prompt::ContextAndCode(code_and_context) => match &params.messages {
+                Some(messages) => Ok(format_chat_messages(messages, code_and_context)),
+                None => Ok(vec![ChatMessage::new(
+                    "user".to_string(),
+                    format_context_code(&code_and_context.context, &code_and_context.code),
+                )]),
+            },
+            Prompt::FIM(fim) => match &params.fim {
+                Some(fim_params) => Ok(vec![ChatMessage::new(
+                    "user".to_string(),
+                    format!(
+                        "{}{}{}{}{}",
+                        fim_params.start, fim.prompt, fim_params.middle, fim.suffix, fim_params.end
+                    ),
+                )]),
+                None => anyhow::bail!("Prompt type is FIM but no FIM parameters provided"),
+            },
+        }
+    }
+
+    fn get_request_body(&self, messages: Vec<ChatMessage>, params: GeminiRunParams) -> Value {
+        let (contents, system_instruction) =
+            to_gemini_contents(messages, params.system_instruction);
+        let mut body = json!({
+            "contents": contents,
+            "safetySettings": params.safety_settings,
+            "generationConfig": {
+                "maxOutputTokens": params.max_tokens,
+                "topP": params.top_p,
+                "topK": params.top_k,
+                "temperature": params.temperature
+            }
+        });
+        if !params.stop.is_empty() {
+            body["generationConfig"]["stopSequences"] = json!(params.stop);
+        }
+        if let Some(system_instruction) = system_instruction {
+            body["systemInstruction"] = json!({
+                "parts": [{ "text": system_instruction }]
+            });
+        }
+        body
+    }
+
+    async fn get_chat(
+        &self,
+        messages: Vec<ChatMessage>,
+        params: GeminiRunParams,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let client = get_client();
+        let token = self.get_token().await?;
+        let request = client
+            .post(self.get_url("generateContent"))
+            .query(&[("key", token)])
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .json(&self.get_request_body(messages, params));
+        let res: GeminiGenerateContentResponse = retry::send(request, &self.configuration.retry)
+            .await?
+            .json()
+            .await?;
+        let usage = res.usage_metadata;
+        Ok(DoGenerationResponse {
+            generated_text: res.into_text()?,
+            usage,
+        })
+    }
+
+    async fn get_chat_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        params: GeminiRunParams,
+        tx: UnboundedSender<String>,
+    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        let client = get_client();
+        let token = self.get_token().await?;
+        let request = client
+            .post(self.get_url("streamGenerateContent"))
+            .query(&[("alt", "sse"), ("key", token.as_str())])
+            .header("Content-Type", "application/json")
+            .json(&self.get_request_body(messages, params));
+        let mut res = retry::send(request, &self.configuration.retry)
+            .await?
+            .error_for_status()?;
+
+        // The response is a stream of server sent events, each holding a partial response
+        // Chunks can end in the middle of a char, only whole lines are decoded
+        let mut buffer = vec![];
+        let mut generated_text = String::new();
+        let mut usage = None;
+        while let Some(chunk) = res.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(index) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=index).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    continue;
+                };
+                let res: GeminiGenerateContentResponse = serde_json::from_str(data.trim())?;
+                usage = res.usage_metadata.or(usage);
+                let text = res.into_text()?;
+                generated_text.push_str(&text);
+                tx.send(text)
+                    .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
+            }
+        }
+        Ok(DoGenerationStreamResponse {
+            generated_text,
+            usage,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl TransformerBackend for Gemini {
+    #[instrument(skip(self))]
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let params: GeminiRunParams = serde_json::from_value(params)?;
+        let messages = self.get_messages(prompt, &params)?;
+        self.get_chat(messages, params).await
+    }
+
+    #[instrument(skip(self, tx))]
+    async fn do_generate_stream(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<String>,
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        let params: GeminiRunParams = serde_json::from_value(params)?;
+        let messages = self.get_messages(prompt, &params)?;
+        self.get_chat_stream(messages, params, tx).await
+    }
+
+    fn render_prompt(&self, prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+        let params: GeminiRunParams = serde_json::from_value(params.clone())?;
+        Ok(RenderedPrompt::messages(
+            self.get_messages(prompt, &params)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::{from_value, json};
+
+    #[test]
+    fn gemini_maps_chat_messages() {
+        let messages = vec![
+            ChatMessage::new("system".to_string(), "Be brief".to_string()),
+            ChatMessage::new("user".to_string(), "Hi".to_string()),
+            ChatMessage::new("assistant".to_string(), "Hello".to_string()),
+        ];
+        let (contents, system) = to_gemini_contents(messages, Some("Test".to_string()));
+        assert_eq!(system.as_deref(), Some("Test\nBe brief"));
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents[0].role, "user");
+        assert_eq!(contents[1].role, "model");
+    }
+
+    #[test]
+    fn gemini_sends_stop_sequences_when_set() -> anyhow::Result<()> {
+        let configuration: config::Gemini = from_value(json!({
+            "model": "gemini-1.5-flash",
+        }))?;
+        let gemini = Gemini::new(configuration);
+        let body = gemini.get_request_body(vec![], from_value(json!({}))?);
+        assert!(body["generationConfig"].get("stopSequences").is_none());
+        let body = gemini.get_request_body(vec![], from_value(json!({"stop": ["\n"]}))?);
+        assert_eq!(body["generationConfig"]["stopSequences"], json!(["\n"]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gemini_chat_do_generate() -> anyhow::Result<()> {
+        let configuration: config::Gemini = from_value(json!({
+            "model": "gemini-1.5-flash",
+            "auth_token_env_var_name": "GEMINI_API_KEY",
+        }))?;
+        let gemini = Gemini::new(configuration);
+        let prompt = Prompt::default_with_cursor();
+        let run_params = json!({
+            "system_instruction": "Test",
+            "messages": [
+                {
+                    "role": "user",
+                    "content": "Test {CONTEXT} - {CODE}"
+                }
+            ],
+            "max_tokens": 2
+        });
+        let response = gemini
+            .do_generate(&prompt, run_params, &CancellationToken::new())
+            .await?;
+        assert!(!response.generated_text.is_empty());
+        Ok(())
+    }
+}
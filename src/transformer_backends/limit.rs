@@ -0,0 +1,165 @@
+use serde_json::Value;
+use tokio::sync::{mpsc::UnboundedSender, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use super::{RenderedPrompt, TransformerBackend};
+use crate::{
+    memory_backends::{Prompt, PromptType},
+    tokenizer::SharedTokenizer,
+    tools::ToolRequest,
+    transformer_worker::{
+        DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse,
+        DoToolGenerationResponse,
+    },
+};
+
+// Sends at most `max_concurrent_requests` requests to the backend at once. The others wait their
+// turn in order and are dropped while waiting when they are cancelled.
+pub struct Limited {
+    backend: Box<dyn TransformerBackend + Send + Sync>,
+    permits: Semaphore,
+}
+
+impl Limited {
+    pub fn new(
+        backend: Box<dyn TransformerBackend + Send + Sync>,
+        max_concurrent_requests: usize,
+    ) -> Self {
+        Self {
+            backend,
+            // No requests at all would never answer any
+            permits: Semaphore::new(max_concurrent_requests.max(1)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TransformerBackend for Limited {
+    async fn do_completion(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoCompletionResponse> {
+        let _permit = self.permits.acquire().await?;
+        self.backend.do_completion(prompt, params, cancel).await
+    }
+
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let _permit = self.permits.acquire().await?;
+        self.backend.do_generate(prompt, params, cancel).await
+    }
+
+    async fn do_generate_stream(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<String>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        let _permit = self.permits.acquire().await?;
+        self.backend
+            .do_generate_stream(prompt, params, tx, cancel)
+            .await
+    }
+
+    async fn do_generate_with_tools(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        tools: &ToolRequest<'_>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoToolGenerationResponse> {
+        let _permit = self.permits.acquire().await?;
+        self.backend
+            .do_generate_with_tools(prompt, params, tools, cancel)
+            .await
+    }
+
+    fn is_local(&self) -> bool {
+        self.backend.is_local()
+    }
+
+    fn caches_prompt_prefixes(&self) -> bool {
+        self.backend.caches_prompt_prefixes()
+    }
+
+    fn get_prompt_type(&self, params: &Value) -> anyhow::Result<PromptType> {
+        self.backend.get_prompt_type(params)
+    }
+
+    fn get_tokenizer(&self) -> SharedTokenizer {
+        self.backend.get_tokenizer()
+    }
+
+    fn render_prompt(&self, prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+        self.backend.render_prompt(prompt, params)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[derive(Default)]
+    struct SlowBackend {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl TransformerBackend for Arc<SlowBackend> {
+        async fn do_generate(
+            &self,
+            _prompt: &Prompt,
+            _params: Value,
+            _cancel: &CancellationToken,
+        ) -> anyhow::Result<DoGenerationResponse> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(DoGenerationResponse {
+                generated_text: String::new(),
+                usage: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_requests() -> anyhow::Result<()> {
+        let backend = Arc::new(SlowBackend::default());
+        let limited = Arc::new(Limited::new(Box::new(backend.clone()), 2));
+        let requests: Vec<_> = (0..6)
+            .map(|_| {
+                let limited = limited.clone();
+                tokio::spawn(async move {
+                    limited
+                        .do_generate(
+                            &Prompt::default_without_cursor(),
+                            Value::Null,
+                            &CancellationToken::new(),
+                        )
+                        .await
+                })
+            })
+            .collect();
+        for request in requests {
+            request.await??;
+        }
+        assert_eq!(backend.max_running.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
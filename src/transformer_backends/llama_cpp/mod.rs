@@ -1,26 +1,75 @@
-use super::TransformerBackend;
+use super::{is_hf_hub_offline, RenderedPrompt, TransformerBackend};
 use crate::{
     config::{self, ChatMessage, FIM},
     memory_backends::Prompt,
+    progress::ProgressReporter,
     template::apply_chat_template,
+    tokenizer::{EstimatedTokenizer, SharedTokenizer, Tokenizer},
     transformer_worker::{
-        DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse,
-        GenerationStreamRequest,
+        CompletionCandidate, DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse,
     },
+    usage::TokenUsage,
     utils::format_chat_messages,
 };
-use hf_hub::api::sync::ApiBuilder;
+use anyhow::Context;
+use hf_hub::{api::sync::ApiBuilder, Cache};
+use parking_lot::Mutex;
 use serde::Deserialize;
 use serde_json::Value;
-use tracing::{error, instrument};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, instrument};
 
 mod model;
+pub mod worker;
 use model::Model;
 
 const fn max_new_tokens_default() -> usize {
     32
 }
 
+const fn temperature_default() -> f32 {
+    0.
+}
+
+const fn n_default() -> usize {
+    1
+}
+
+const fn top_k_default() -> i32 {
+    40
+}
+
+const fn top_p_default() -> f32 {
+    0.95
+}
+
+const fn min_p_default() -> f32 {
+    0.05
+}
+
+const fn repeat_penalty_default() -> f32 {
+    1.
+}
+
+const fn repeat_last_n_default() -> usize {
+    64
+}
+
+const fn mirostat_tau_default() -> f32 {
+    5.
+}
+
+const fn mirostat_eta_default() -> f32 {
+    0.1
+}
+
 // NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
 #[derive(Debug, Deserialize)]
 pub struct LLaMACPPRunParams {
@@ -30,83 +79,380 @@ pub struct LLaMACPPRunParams {
     chat_format: Option<String>,   // The name of a template in llamacpp
     #[serde(default = "max_new_tokens_default")]
     pub max_tokens: usize,
-    // TODO: Explore other arguments
+    // A temperature of 0 means greedy sampling
+    #[serde(default = "temperature_default")]
+    pub temperature: f32,
+    #[serde(default = "top_k_default")]
+    pub top_k: i32,
+    #[serde(default = "top_p_default")]
+    pub top_p: f32,
+    #[serde(default = "min_p_default")]
+    pub min_p: f32,
+    // A repeat_penalty of 1 disables the penalty
+    #[serde(default = "repeat_penalty_default")]
+    pub repeat_penalty: f32,
+    // How many of the last tokens are considered for the penalties
+    #[serde(default = "repeat_last_n_default")]
+    pub repeat_last_n: usize,
+    #[serde(default)]
+    pub frequency_penalty: f32,
+    #[serde(default)]
+    pub presence_penalty: f32,
+    // 0 disables mirostat, 2 enables mirostat v2. Mirostat v1 (1) is not supported as the
+    // llama.cpp bindings do not expose its sampler.
+    #[serde(default)]
+    pub mirostat: u8,
+    #[serde(default = "mirostat_tau_default")]
+    pub mirostat_tau: f32,
+    #[serde(default = "mirostat_eta_default")]
+    pub mirostat_eta: f32,
+    pub seed: Option<u32>,
+    // Generation stops before the first occurence of any of these
+    #[serde(default)]
+    pub stop: Vec<String>,
+    // A GBNF grammar the output must conform to, either inline or as a path to a file
+    pub grammar: Option<String>,
+    pub grammar_file: Option<String>,
+    // How many completion candidates to sample, more than 1 needs a temperature above 0
+    #[serde(default = "n_default")]
+    pub n: usize,
+}
+
+impl LLaMACPPRunParams {
+    // Drafted tokens are only checked against greedy picks of the unmodified distribution
+    fn supports_speculative_decoding(&self) -> bool {
+        self.temperature <= 0.
+            && self.repeat_penalty == 1.
+            && self.frequency_penalty == 0.
+            && self.presence_penalty == 0.
+    }
+
+    fn get_grammar(&self) -> anyhow::Result<Option<String>> {
+        match (&self.grammar, &self.grammar_file) {
+            (Some(_), Some(_)) => anyhow::bail!("specify only one of `grammar` or `grammar_file`"),
+            (Some(grammar), None) => Ok(Some(grammar.clone())),
+            (None, Some(grammar_file)) => Ok(Some(
+                std::fs::read_to_string(grammar_file)
+                    .with_context(|| format!("reading grammar file: {grammar_file}"))?,
+            )),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+// How often the idle time of a model is checked
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Keeps what is needed to load the model again after it was unloaded for being idle
+struct Loader {
+    configuration: config::LLaMACPP,
+    model_path: PathBuf,
+    draft_model_path: Option<PathBuf>,
+    model: Mutex<Option<Arc<Model>>>,
+    last_used: Mutex<Instant>,
+}
+
+impl Loader {
+    fn load(&self) -> anyhow::Result<Model> {
+        Model::new(
+            self.model_path.clone(),
+            self.draft_model_path.clone(),
+            &self.configuration,
+        )
+    }
+
+    fn get_model(&self) -> anyhow::Result<Arc<Model>> {
+        let mut model = self.model.lock();
+        *self.last_used.lock() = Instant::now();
+        if let Some(model) = &*model {
+            return Ok(model.clone());
+        }
+        let progress = ProgressReporter::begin(
+            "lsp-ai",
+            Some(format!("Reloading {}", self.model_path.display())),
+        );
+        let loaded = self.load();
+        progress.end(Some(match &loaded {
+            Ok(_) => "Model loaded and ready".to_string(),
+            Err(e) => format!("Error loading the model: {e}"),
+        }));
+        let loaded = Arc::new(loaded?);
+        *model = Some(loaded.clone());
+        Ok(loaded)
+    }
+}
+
+// Drops the model once no request used it for `idle`. Stops with the backend.
+fn unload_when_idle(loader: Weak<Loader>, idle: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(IDLE_CHECK_INTERVAL.min(idle));
+        let Some(loader) = loader.upgrade() else {
+            return;
+        };
+        let mut model = loader.model.lock();
+        // Requests in flight hold on to the model, it counts as used until they are done
+        let mut last_used = loader.last_used.lock();
+        if model
+            .as_ref()
+            .is_some_and(|model| Arc::strong_count(model) > 1)
+        {
+            *last_used = Instant::now();
+        } else if model.is_some() && last_used.elapsed() >= idle {
+            *model = None;
+            info!(
+                "unloaded {} after it was idle for {} s",
+                loader.model_path.display(),
+                idle.as_secs()
+            );
+        }
+    });
 }
 
 pub struct LLaMACPP {
-    model: Model,
+    loader: Arc<Loader>,
+}
+
+// Counts with the tokenizer of the model while it is loaded. Prompts are not worth reloading a
+// model unloaded for being idle, they are estimated instead.
+struct ModelTokenizer(Arc<Loader>);
+
+impl Tokenizer for ModelTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        let model = self.0.model.lock().clone();
+        model
+            .and_then(|model| model.count_tokens(text).ok())
+            .map(|tokens| tokens as usize)
+            .unwrap_or_else(|| EstimatedTokenizer.count_tokens(text))
+    }
+}
+
+// Reports how much of the model is downloaded, hf-hub calls it as the bytes come in
+struct DownloadProgress<'a> {
+    progress: &'a ProgressReporter,
+    message: String,
+    size: usize,
+    downloaded: usize,
+    // Only reported when it changes
+    percentage: Option<u32>,
+}
+
+impl hf_hub::api::Progress for DownloadProgress<'_> {
+    fn init(&mut self, size: usize, _filename: &str) {
+        self.size = size;
+        self.progress.report(self.message.clone(), Some(0));
+    }
+
+    fn update(&mut self, size: usize) {
+        self.downloaded += size;
+        let percentage = (self.downloaded * 100 / self.size.max(1)).min(100) as u32;
+        if self.percentage != Some(percentage) {
+            self.percentage = Some(percentage);
+            self.progress.report(self.message.clone(), Some(percentage));
+        }
+    }
+
+    fn finish(&mut self) {}
+}
+
+// Downloads the model from Hugging Face unless it is a local file or already in the cache
+fn get_model_path(
+    file_path: Option<&str>,
+    repository: Option<&str>,
+    name: Option<&str>,
+    offline: bool,
+    progress: &ProgressReporter,
+) -> anyhow::Result<PathBuf> {
+    match (file_path, repository, name) {
+        (Some(file_path), _, _) => {
+            let path = PathBuf::from(file_path);
+            anyhow::ensure!(path.is_file(), "model file not found: {file_path}");
+            Ok(path)
+        }
+        (_, Some(repository), Some(name)) if offline || is_hf_hub_offline() => {
+            Cache::default()
+                .model(repository.to_owned())
+                .get(name)
+                .with_context(|| format!("offline mode is enabled and {repository} - {name} is not in the Hugging Face cache. Download it first or use `file_path`"))
+        }
+        (_, Some(repository), Some(name)) => {
+            if let Some(path) = Cache::default().model(repository.to_owned()).get(name) {
+                return Ok(path);
+            }
+            let api = ApiBuilder::new().build()?;
+            error!("Loading in: {} - {}\nIf this model has not been loaded before it may take a few minutes to download it. Please hangtight.", repository, name);
+            let repo = api.model(repository.to_owned());
+            let download = DownloadProgress {
+                progress,
+                message: format!("Downloading {repository} - {name}"),
+                size: 0,
+                downloaded: 0,
+                percentage: None,
+            };
+            Ok(repo.download_with_progress(name, download)?)
+        }
+        _ => anyhow::bail!("To use llama.cpp provide either `file_path` or `repository` and `name`"),
+    }
 }
 
 impl LLaMACPP {
     #[instrument]
     pub fn new(configuration: config::LLaMACPP) -> anyhow::Result<Self> {
-        let model_path = match (
-            &configuration.file_path,
-            &configuration.repository,
-            &configuration.name,
-        ) {
-            (Some(file_path), _, _) => std::path::PathBuf::from(file_path),
-            (_, Some(repository), Some(name)) => {
-                let api = ApiBuilder::new().with_progress(true).build()?;
-                error!("Loading in: {} - {}\nIf this model has not been loaded before it may take a few minutes to download it. Please hangtight.", repository, name);
-                let repo = api.model(repository.clone());
-                repo.get(&name)?
-            }
-            _ => anyhow::bail!(
-                "To use llama.cpp provide either `file_path` or `repository` and `name`"
-            ),
-        };
-        let model = Model::new(model_path, &configuration)?;
-        Ok(Self { model })
+        let progress = ProgressReporter::begin("lsp-ai", Some("Loading model".to_string()));
+        let llama_cpp = Self::load(configuration, &progress);
+        progress.end(Some(match &llama_cpp {
+            Ok(_) => "Model loaded and ready".to_string(),
+            Err(e) => format!("Error loading the model: {e}"),
+        }));
+        llama_cpp
     }
 
-    #[instrument(skip(self))]
-    fn get_prompt_string(
-        &self,
-        prompt: &Prompt,
-        params: &LLaMACPPRunParams,
-    ) -> anyhow::Result<String> {
-        match prompt {
-            Prompt::ContextAndCode(context_and_code) => Ok(match &params.messages {
-                Some(completion_messages) => {
-                    let chat_messages = format_chat_messages(completion_messages, context_and_code);
-                    if let Some(chat_template) = &params.chat_template {
-                        let bos_token = self.model.get_bos_token()?;
-                        let eos_token = self.model.get_eos_token()?;
+    fn load(configuration: config::LLaMACPP, progress: &ProgressReporter) -> anyhow::Result<Self> {
+        let model_path = get_model_path(
+            configuration.file_path.as_deref(),
+            configuration.repository.as_deref(),
+            configuration.name.as_deref(),
+            configuration.offline,
+            progress,
+        )?;
+        let draft_model_path = configuration
+            .draft_model
+            .as_ref()
+            .map(|draft| {
+                get_model_path(
+                    draft.file_path.as_deref(),
+                    draft.repository.as_deref(),
+                    draft.name.as_deref(),
+                    configuration.offline,
+                    progress,
+                )
+                .context("error finding the draft model")
+            })
+            .transpose()?;
+        progress.report(format!("Loading {}", model_path.display()), None);
+        let loader = Arc::new(Loader {
+            configuration,
+            model_path,
+            draft_model_path,
+            model: Mutex::new(None),
+            last_used: Mutex::new(Instant::now()),
+        });
+        let model = loader.load()?;
+        *loader.model.lock() = Some(Arc::new(model));
+        if let Some(idle_secs) = loader.configuration.unload_after_idle_secs {
+            unload_when_idle(Arc::downgrade(&loader), Duration::from_secs(idle_secs));
+        }
+        Ok(Self { loader })
+    }
+
+    #[instrument(skip(self, texts))]
+    pub fn embed(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.loader.get_model()?.embed(texts)
+    }
+}
+
+#[instrument(skip(model))]
+fn get_prompt_string(
+    model: &Model,
+    prompt: &Prompt,
+    params: &LLaMACPPRunParams,
+) -> anyhow::Result<String> {
+    match prompt {
+        Prompt::ContextAndCode(context_and_code) => Ok(match &params.messages {
+            Some(completion_messages) => {
+                let chat_messages = format_chat_messages(completion_messages, context_and_code);
+                let bos_token = model.get_bos_token()?;
+                let eos_token = model.get_eos_token()?;
+                match (&params.chat_template, &params.chat_format) {
+                    (Some(chat_template), _) => {
                         apply_chat_template(chat_template, chat_messages, &bos_token, &eos_token)?
-                    } else {
-                        self.model
-                            .apply_chat_template(chat_messages, params.chat_format.clone())?
                     }
+                    (None, Some(_)) => {
+                        model.apply_chat_template(chat_messages, params.chat_format.clone())?
+                    }
+                    // llama.cpp only knows common templates. The one embedded in the GGUF file
+                    // is rendered as Jinja when llama.cpp does not recognize it.
+                    (None, None) => match model.apply_chat_template(chat_messages.clone(), None) {
+                        Ok(prompt) => prompt,
+                        Err(e) => match model.get_embedded_chat_template() {
+                            Some(chat_template) => apply_chat_template(
+                                chat_template,
+                                chat_messages,
+                                &bos_token,
+                                &eos_token,
+                            )?,
+                            None => return Err(e),
+                        },
+                    },
                 }
-                None => context_and_code.code.clone(),
-            }),
-            Prompt::FIM(fim) => Ok(match &params.fim {
-                Some(fim_params) => {
-                    format!(
-                        "{}{}{}{}{}",
-                        fim_params.start, fim.prompt, fim_params.middle, fim.suffix, fim_params.end
-                    )
-                }
-                None => anyhow::bail!("Prompt type is FIM but no FIM parameters provided"),
-            }),
-        }
+            }
+            None => context_and_code.code.clone(),
+        }),
+        Prompt::FIM(fim) => Ok(match &params.fim {
+            Some(fim_params) => {
+                format!(
+                    "{}{}{}{}{}",
+                    fim_params.start, fim.prompt, fim_params.middle, fim.suffix, fim_params.end
+                )
+            }
+            None => anyhow::bail!("Prompt type is FIM but no FIM parameters provided"),
+        }),
     }
 }
 
+// Counted with the model's tokenizer so the usage is exact
+fn get_usage(model: &Model, prompt: &str, completion: &str) -> anyhow::Result<TokenUsage> {
+    Ok(TokenUsage::new(
+        model.count_tokens(prompt)?,
+        model.count_tokens(completion)?,
+    ))
+}
+
 #[async_trait::async_trait]
 impl TransformerBackend for LLaMACPP {
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn get_tokenizer(&self) -> SharedTokenizer {
+        Arc::new(ModelTokenizer(self.loader.clone()))
+    }
+
+    // The chat template is applied, this is the text the model sees
+    fn render_prompt(&self, prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+        let params: LLaMACPPRunParams = serde_json::from_value(params.clone())?;
+        let model = self.loader.get_model()?;
+        Ok(RenderedPrompt::prompt(get_prompt_string(
+            &model, prompt, &params,
+        )?))
+    }
+
     #[instrument(skip(self))]
     async fn do_completion(
         &self,
         prompt: &Prompt,
         params: Value,
+        cancel: &CancellationToken,
     ) -> anyhow::Result<DoCompletionResponse> {
         let params: LLaMACPPRunParams = serde_json::from_value(params)?;
-        let prompt = self.get_prompt_string(prompt, &params)?;
-        self.model
-            .complete(&prompt, params)
-            .map(|insert_text| DoCompletionResponse { insert_text })
+        let model = self.loader.get_model()?;
+        let prompt = get_prompt_string(&model, prompt, &params)?;
+        let candidates: Vec<CompletionCandidate> = model
+            .complete_candidates(&prompt, params, cancel)?
+            .into_iter()
+            .map(|(insert_text, logprob)| CompletionCandidate {
+                insert_text,
+                score: Some(logprob),
+            })
+            .collect();
+        let completion: String = candidates
+            .iter()
+            .map(|candidate| candidate.insert_text.as_str())
+            .collect();
+        let usage = get_usage(&model, &prompt, &completion)?;
+        Ok(DoCompletionResponse {
+            candidates,
+            usage: Some(usage),
+        })
     }
 
     #[instrument(skip(self))]
@@ -114,21 +460,39 @@ impl TransformerBackend for LLaMACPP {
         &self,
         prompt: &Prompt,
         params: Value,
+        cancel: &CancellationToken,
     ) -> anyhow::Result<DoGenerationResponse> {
         let params: LLaMACPPRunParams = serde_json::from_value(params)?;
-        let prompt = self.get_prompt_string(prompt, &params)?;
-        self.model
-            .complete(&prompt, params)
-            .map(|generated_text| DoGenerationResponse { generated_text })
+        let model = self.loader.get_model()?;
+        let prompt = get_prompt_string(&model, prompt, &params)?;
+        let generated_text = model.complete(&prompt, params, cancel)?;
+        let usage = get_usage(&model, &prompt, &generated_text)?;
+        Ok(DoGenerationResponse {
+            generated_text,
+            usage: Some(usage),
+        })
     }
 
-    #[instrument(skip(self))]
+    #[instrument(skip(self, tx))]
     async fn do_generate_stream(
         &self,
-        _request: &GenerationStreamRequest,
-        _params: Value,
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<String>,
+        cancel: &CancellationToken,
     ) -> anyhow::Result<DoGenerationStreamResponse> {
-        anyhow::bail!("GenerationStream is not yet implemented")
+        let params: LLaMACPPRunParams = serde_json::from_value(params)?;
+        let model = self.loader.get_model()?;
+        let prompt = get_prompt_string(&model, prompt, &params)?;
+        let generated_text = model.complete_stream(&prompt, params, cancel, |token| {
+            tx.send(token.to_owned())
+                .map_err(|_| anyhow::anyhow!("sending on channel failed"))
+        })?;
+        let usage = get_usage(&model, &prompt, &generated_text)?;
+        Ok(DoGenerationStreamResponse {
+            generated_text,
+            usage: Some(usage),
+        })
     }
 }
 
@@ -161,8 +525,10 @@ mod test {
             "chat_format": "llama2",
             "max_tokens": 4
         });
-        let response = llama_cpp.do_completion(&prompt, run_params).await?;
-        assert!(!response.insert_text.is_empty());
+        let response = llama_cpp
+            .do_completion(&prompt, run_params, &CancellationToken::new())
+            .await?;
+        assert!(!response.candidates[0].insert_text.is_empty());
         Ok(())
     }
 
@@ -184,8 +550,10 @@ mod test {
             },
             "max_tokens": 4
         });
-        let response = llama_cpp.do_completion(&prompt, run_params).await?;
-        assert!(!response.insert_text.is_empty());
+        let response = llama_cpp
+            .do_completion(&prompt, run_params, &CancellationToken::new())
+            .await?;
+        assert!(!response.candidates[0].insert_text.is_empty());
         Ok(())
     }
 
@@ -207,8 +575,56 @@ mod test {
             },
             "max_tokens": 4
         });
-        let response = llama_cpp.do_generate(&prompt, run_params).await?;
+        let response = llama_cpp
+            .do_generate(&prompt, run_params, &CancellationToken::new())
+            .await?;
         assert!(!response.generated_text.is_empty());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn llama_cpp_do_generate_stream_fim() -> anyhow::Result<()> {
+        let configuration: config::LLaMACPP = serde_json::from_value(json!({
+            "repository": "stabilityai/stable-code-3b",
+            "name": "stable-code-3b-Q5_K_M.gguf",
+            "n_ctx": 2048,
+            "n_gpu_layers": 35,
+        }))?;
+        let llama_cpp = LLaMACPP::new(configuration).unwrap();
+        let prompt = Prompt::default_fim();
+        let run_params = json!({
+            "fim": {
+                "start": "<fim_prefix>",
+                "middle": "<fim_suffix>",
+                "end": "<fim_middle>"
+            },
+            "max_tokens": 4
+        });
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let response = llama_cpp
+            .do_generate_stream(&prompt, run_params, tx, &CancellationToken::new())
+            .await?;
+        let mut streamed = String::new();
+        while let Some(token) = rx.recv().await {
+            streamed.push_str(&token);
+        }
+        assert!(!response.generated_text.is_empty());
+        assert_eq!(streamed, response.generated_text);
+        Ok(())
+    }
+
+    #[test]
+    fn llama_cpp_embed() -> anyhow::Result<()> {
+        let configuration: config::LLaMACPP = serde_json::from_value(json!({
+            "repository": "nomic-ai/nomic-embed-text-v1.5-GGUF",
+            "name": "nomic-embed-text-v1.5.Q5_K_M.gguf",
+            "n_ctx": 2048,
+            "n_gpu_layers": 1000,
+        }))?;
+        let llama_cpp = LLaMACPP::new(configuration).unwrap();
+        let embeddings = llama_cpp.embed(&["def test():".to_string(), "pass".to_string()])?;
+        assert_eq!(embeddings.len(), 2);
+        assert_eq!(embeddings[0].len(), 768);
+        Ok(())
+    }
 }
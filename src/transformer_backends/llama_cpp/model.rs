@@ -1,59 +1,345 @@
 use anyhow::Context;
 use llama_cpp_2::{
-    context::params::LlamaContextParams,
+    context::{params::LlamaContextParams, LlamaContext},
     ggml_time_us,
+    grammar::LlamaGrammar,
     llama_backend::LlamaBackend,
     llama_batch::LlamaBatch,
     model::{params::LlamaModelParams, AddBos, LlamaChatMessage, LlamaModel, Special},
-    token::data_array::LlamaTokenDataArray,
+    token::{data_array::LlamaTokenDataArray, LlamaToken},
 };
 use once_cell::sync::Lazy;
-use std::{num::NonZeroU32, path::PathBuf, time::Duration};
-use tracing::{debug, info, instrument};
+use parking_lot::Mutex;
+use std::{num::NonZeroU32, path::PathBuf, str::FromStr, time::Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, instrument, warn};
 
-use crate::config::{self, ChatMessage};
+use crate::{
+    config::{self, ChatMessage},
+    utils::StopSequenceFilter,
+};
 
 use super::LLaMACPPRunParams;
 
 static BACKEND: Lazy<LlamaBackend> = Lazy::new(|| LlamaBackend::init().unwrap());
 
+// The context size used when it is not configured and the model was trained on a longer one
+const MAX_DEFAULT_N_CTX: u32 = 8192;
+
+// The buffer size for reading the chat template from the model metadata, some are several KB
+const MAX_CHAT_TEMPLATE_LEN: usize = 64 * 1024;
+
+struct Generation {
+    text: String,
+    // The mean log probability of the sampled tokens
+    logprob: f32,
+}
+
+// A context kept alive between requests along with the tokens currently in its KV cache
+struct CachedContext<'a> {
+    ctx: LlamaContext<'a>,
+    tokens: Vec<LlamaToken>,
+}
+
+// SAFETY: the context is only ever used while holding the `Mutex` in `Model`
+unsafe impl Send for CachedContext<'_> {}
+
+// Creates a context that lives as long as the model, the caller keeps it and drops it first
+fn new_cached_context(
+    model: &LlamaModel,
+    ctx_params: LlamaContextParams,
+) -> anyhow::Result<CachedContext<'static>> {
+    let ctx = model
+        .new_context(&BACKEND, ctx_params)
+        .with_context(|| "unable to create the llama_context")?;
+    // SAFETY: the models are boxed so their address is stable, and the contexts are dropped
+    // before their model (see the field order of `Model` and `Draft`)
+    let ctx = unsafe { std::mem::transmute::<LlamaContext<'_>, LlamaContext<'static>>(ctx) };
+    Ok(CachedContext {
+        ctx,
+        tokens: vec![],
+    })
+}
+
+// Evaluates the tokens the KV cache does not hold yet. The logits of the last token are at the
+// last index of `batch`.
+fn eval_tokens(
+    cached: &mut CachedContext<'_>,
+    tokens_list: &[LlamaToken],
+    batch: &mut LlamaBatch,
+) -> anyhow::Result<()> {
+    // Reuse the longest prefix the KV cache already holds. The last token is always evaluated
+    // again as we need its logits to sample from.
+    let n_past = cached
+        .tokens
+        .iter()
+        .zip(tokens_list.iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(tokens_list.len() - 1);
+    // llama-cpp-2 takes the position as a u16, past it the prompt is evaluated from the start
+    let n_past = match u16::try_from(n_past) {
+        Ok(position) => {
+            cached.ctx.clear_kv_cache_seq(0, Some(position), None);
+            n_past
+        }
+        Err(_) => {
+            cached.ctx.clear_kv_cache();
+            0
+        }
+    };
+    cached.tokens.truncate(n_past);
+    debug!(
+        "reusing {n_past} of {} prompt tokens from the KV cache",
+        tokens_list.len()
+    );
+
+    batch.clear();
+    let last_index: i32 = (tokens_list.len() - 1) as i32;
+    for (i, token) in (0_i32..).zip(tokens_list.iter().copied()).skip(n_past) {
+        // llama_decode will output logits only for the last token of the prompt
+        let is_last = i == last_index;
+        batch.add(token, i, &[0], is_last)?;
+    }
+
+    cached
+        .ctx
+        .decode(batch)
+        .with_context(|| "llama_decode() failed")?;
+    cached.tokens = tokens_list.to_vec();
+    Ok(())
+}
+
+fn greedy_token(logits: &[f32]) -> LlamaToken {
+    let (index, _) = logits
+        .iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (i, logit)| {
+            if *logit > best.1 {
+                (i, *logit)
+            } else {
+                best
+            }
+        });
+    LlamaToken(index as i32)
+}
+
+// Drafts tokens for speculative decoding
+struct Draft {
+    // NOTE: like in `Model`, `cache` must be declared before `model`
+    cache: Mutex<Option<CachedContext<'static>>>,
+    model: Box<LlamaModel>,
+    n_draft: usize,
+}
+
 pub struct Model {
-    model: LlamaModel,
+    // NOTE: `cache` must be declared before `model` so the context is dropped before the model it
+    // borrows from
+    cache: Mutex<Option<CachedContext<'static>>>,
+    draft: Option<Draft>,
+    model: Box<LlamaModel>,
     n_ctx: NonZeroU32,
+    flash_attention: bool,
+    // The Jinja chat template from the GGUF metadata
+    chat_template: Option<String>,
+}
+
+// The text generated so far and the scores of its tokens
+struct Output {
+    text: StopSequenceFilter,
+    logprob_sum: f32,
+    n_sampled: usize,
+}
+
+impl Output {
+    // Returns whether the generation is done
+    fn push(
+        &mut self,
+        model: &LlamaModel,
+        token: LlamaToken,
+        logprob: f32,
+        on_token: &mut impl FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<bool> {
+        self.logprob_sum += logprob;
+        self.n_sampled += 1;
+        if token == model.token_eos() {
+            return Ok(true);
+        }
+        let token = model.token_to_str(token, Special::Tokenize)?;
+        let text = self.text.push(&token);
+        if !text.is_empty() {
+            on_token(&text)?;
+        }
+        Ok(self.text.is_stopped())
+    }
 }
 
 impl Model {
     #[instrument]
-    pub fn new(model_path: PathBuf, config: &config::LLaMACPP) -> anyhow::Result<Self> {
+    pub fn new(
+        model_path: PathBuf,
+        draft_model_path: Option<PathBuf>,
+        config: &config::LLaMACPP,
+    ) -> anyhow::Result<Self> {
         // Initialize the model_params
-        let model_params = LlamaModelParams::default().with_n_gpu_layers(config.n_gpu_layers);
+        let model_params = LlamaModelParams::default()
+            .with_n_gpu_layers(config.n_gpu_layers)
+            .with_main_gpu(config.main_gpu as i32);
 
         // Load the model
         debug!("Loading model at path: {:?}", model_path);
-        let model = LlamaModel::load_from_file(&BACKEND, model_path, &model_params)?;
+        let model = LlamaModel::load_from_file(&BACKEND, &model_path, &model_params)?;
+        // llama.cpp silently runs on the CPU when it was built without GPU support
+        if config.n_gpu_layers > 0 && !BACKEND.supports_gpu_offload() {
+            warn!("this build of llama.cpp cannot offload to a GPU, running on the CPU");
+        }
+        info!(
+            "loaded {} with up to {} layers on GPU {}, flash attention {}",
+            model_path.display(),
+            if BACKEND.supports_gpu_offload() {
+                model_params.n_gpu_layers()
+            } else {
+                0
+            },
+            model_params.main_gpu(),
+            if config.flash_attention { "on" } else { "off" }
+        );
+
+        let draft = match (draft_model_path, &config.draft_model) {
+            (Some(draft_model_path), Some(draft_config)) => {
+                debug!("Loading draft model at path: {:?}", draft_model_path);
+                let draft_params = LlamaModelParams::default()
+                    .with_n_gpu_layers(draft_config.n_gpu_layers)
+                    .with_main_gpu(config.main_gpu as i32);
+                let draft_model =
+                    LlamaModel::load_from_file(&BACKEND, draft_model_path, &draft_params)?;
+                // Drafted tokens are compared by id
+                anyhow::ensure!(
+                    draft_model.n_vocab() == model.n_vocab(),
+                    "the draft model must have the same vocabulary as the model"
+                );
+                anyhow::ensure!(draft_config.n_draft > 0, "`n_draft` must be non zero");
+                Some(Draft {
+                    cache: Mutex::new(None),
+                    model: Box::new(draft_model),
+                    n_draft: draft_config.n_draft,
+                })
+            }
+            _ => None,
+        };
+
+        // Models trained on long contexts would need a lot of memory for the KV cache, so their
+        // context is capped unless it is set explicitly
+        let n_ctx = match config.n_ctx {
+            Some(n_ctx) => n_ctx,
+            None => {
+                let n_ctx = model.n_ctx_train().min(MAX_DEFAULT_N_CTX);
+                info!("using a context size of {n_ctx} from the model metadata");
+                n_ctx
+            }
+        };
+        let chat_template = model.get_chat_template(MAX_CHAT_TEMPLATE_LEN).ok();
+        if chat_template.is_some() {
+            debug!("the model metadata contains a chat template");
+        }
 
         Ok(Model {
-            model,
-            n_ctx: NonZeroU32::new(config.n_ctx).context("`n_ctx` must be non zero")?,
+            cache: Mutex::new(None),
+            draft,
+            model: Box::new(model),
+            n_ctx: NonZeroU32::new(n_ctx).context("`n_ctx` must be non zero")?,
+            flash_attention: config.flash_attention,
+            chat_template,
         })
     }
 
+    fn get_context_params(&self) -> LlamaContextParams {
+        LlamaContextParams::default()
+            .with_n_ctx(Some(self.n_ctx))
+            .with_flash_attention(self.flash_attention)
+    }
+
     #[instrument(skip(self))]
-    pub fn complete(&self, prompt: &str, params: LLaMACPPRunParams) -> anyhow::Result<String> {
-        // initialize the context
-        let ctx_params = LlamaContextParams::default().with_n_ctx(Some(self.n_ctx));
+    pub fn complete(
+        &self,
+        prompt: &str,
+        params: LLaMACPPRunParams,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<String> {
+        self.complete_stream(prompt, params, cancel, |_| Ok(()))
+    }
 
-        let mut ctx = self
-            .model
-            .new_context(&BACKEND, ctx_params)
-            .with_context(|| "unable to create the llama_context")?;
+    // Calls `on_token` with each decoded token as it is sampled and returns the full completion
+    #[instrument(skip(self, on_token))]
+    pub fn complete_stream(
+        &self,
+        prompt: &str,
+        params: LLaMACPPRunParams,
+        cancel: &CancellationToken,
+        on_token: impl FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<String> {
+        let tokens_list = self.prepare(prompt, &params)?;
+        let grammar = params.get_grammar()?;
+        self.with_context(params.seed, |cached| {
+            self.generate(
+                cached,
+                &tokens_list,
+                &params,
+                grammar.as_deref(),
+                cancel,
+                on_token,
+            )
+        })
+        .map(|generation| generation.text)
+    }
+
+    // Samples `params.n` completions one after the other, each with its mean token log
+    // probability. Only the first evaluates the prompt, the rest reuse it from the KV cache.
+    #[instrument(skip(self))]
+    pub fn complete_candidates(
+        &self,
+        prompt: &str,
+        params: LLaMACPPRunParams,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<Vec<(String, f32)>> {
+        // Greedy sampling would produce the same candidate `n` times
+        anyhow::ensure!(
+            params.n <= 1 || params.temperature > 0.,
+            "`n` > 1 needs a `temperature` above 0 to sample different candidates"
+        );
+        let tokens_list = self.prepare(prompt, &params)?;
+        let grammar = params.get_grammar()?;
+        self.with_context(params.seed, |cached| {
+            (0..params.n.max(1))
+                .map(|_| {
+                    self.generate(
+                        cached,
+                        &tokens_list,
+                        &params,
+                        grammar.as_deref(),
+                        cancel,
+                        |_| Ok(()),
+                    )
+                    .map(|generation| (generation.text, generation.logprob))
+                })
+                .collect()
+        })
+    }
+
+    // Validates the params and tokenizes the prompt
+    fn prepare(&self, prompt: &str, params: &LLaMACPPRunParams) -> anyhow::Result<Vec<LlamaToken>> {
+        if params.mirostat != 0 && params.mirostat != 2 {
+            anyhow::bail!(
+                "`mirostat` must be 0 (disabled) or 2 (mirostat v2), mirostat v1 is not supported"
+            )
+        }
 
         let tokens_list = self
             .model
             .str_to_token(prompt, AddBos::Always)
             .with_context(|| format!("failed to tokenize {}", prompt))?;
 
-        let n_cxt = ctx.n_ctx() as usize;
+        let n_cxt = self.n_ctx.get() as usize;
         let n_kv_req = tokens_list.len() + params.max_tokens;
 
         info!(
@@ -69,41 +355,132 @@ impl Model {
             )
         }
 
-        let mut batch = LlamaBatch::new(n_cxt, 1);
+        Ok(tokens_list)
+    }
+
+    // Runs `f` with the cached context, or a fresh one for seeded requests as the seed is fixed
+    // when the context is created
+    fn with_context<T>(
+        &self,
+        seed: Option<u32>,
+        f: impl FnOnce(&mut CachedContext<'_>) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        if let Some(seed) = seed {
+            let ctx_params = self.get_context_params().with_seed(seed);
+            let mut cached = CachedContext {
+                ctx: self
+                    .model
+                    .new_context(&BACKEND, ctx_params)
+                    .with_context(|| "unable to create the llama_context")?,
+                tokens: vec![],
+            };
+            return f(&mut cached);
+        }
 
-        let last_index: i32 = (tokens_list.len() - 1) as i32;
-        for (i, token) in (0_i32..).zip(tokens_list.into_iter()) {
-            // llama_decode will output logits only for the last token of the prompt
-            let is_last = i == last_index;
-            batch.add(token, i, &[0], is_last)?;
+        let mut cache = self.cache.lock();
+        if cache.is_none() {
+            *cache = Some(new_cached_context(&self.model, self.get_context_params())?);
+        }
+        let cached = cache
+            .as_mut()
+            .context("the llama_context was just created")?;
+        let result = f(cached);
+        // We no longer know what is in the KV cache so start over on the next request
+        if result.is_err() {
+            cached.ctx.clear_kv_cache();
+            cached.tokens.clear();
         }
+        result
+    }
 
-        ctx.decode(&mut batch)
-            .with_context(|| "llama_decode() failed")?;
+    fn generate(
+        &self,
+        cached: &mut CachedContext<'_>,
+        tokens_list: &[LlamaToken],
+        params: &LLaMACPPRunParams,
+        grammar: Option<&str>,
+        cancel: &CancellationToken,
+        mut on_token: impl FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Generation> {
+        let mut grammar = grammar
+            .map(|grammar| {
+                LlamaGrammar::from_str(grammar)
+                    .map_err(|e| anyhow::anyhow!("parsing grammar: {e:?}"))
+            })
+            .transpose()?;
+
+        // Greedy sampling can check drafted tokens against the model's own choice
+        if let Some(draft) = &self.draft {
+            if grammar.is_none() && params.supports_speculative_decoding() {
+                return self.generate_speculative(
+                    cached,
+                    draft,
+                    tokens_list,
+                    params,
+                    cancel,
+                    on_token,
+                );
+            }
+        }
+
+        let mut batch = LlamaBatch::new(self.n_ctx.get() as usize, 1);
+        eval_tokens(cached, tokens_list, &mut batch)?;
+        let ctx = &mut cached.ctx;
+
+        // The tokens considered by the repetition penalties
+        let mut last_tokens = tokens_list.to_vec();
 
         // main loop
-        let n_start = batch.n_tokens();
-        let mut output: Vec<String> = vec![];
+        let n_start = cached.tokens.len() as i32;
+        let mut output = StopSequenceFilter::new(params.stop.clone());
         let mut n_cur = n_start;
         let mut n_decode = 0;
+        let mut mirostat_mu = 2. * params.mirostat_tau;
+        let mut logprob_sum = 0.;
+        let mut n_sampled = 0;
         let t_main_start = ggml_time_us();
         while (n_cur as usize) <= (n_start as usize + params.max_tokens) {
+            // stop as soon as the client no longer wants the result
+            if cancel.is_cancelled() {
+                anyhow::bail!("request cancelled")
+            }
+
             // sample the next token
             {
-                let candidates = ctx.candidates_ith(batch.n_tokens() - 1);
-                let candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
+                let logits_index = batch.n_tokens() - 1;
+                let candidates = ctx.candidates_ith(logits_index);
+                let mut candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
 
-                // sample the most likely token
-                let new_token_id = ctx.sample_token_greedy(candidates_p);
+                // restrict the candidates to the tokens the grammar allows
+                if let Some(grammar) = &grammar {
+                    ctx.sample_grammar(&mut candidates_p, grammar);
+                }
+
+                let new_token_id =
+                    sample_token(ctx, candidates_p, params, &last_tokens, &mut mirostat_mu);
+                last_tokens.push(new_token_id);
+                logprob_sum += token_logprob(ctx.get_logits_ith(logits_index), new_token_id);
+                n_sampled += 1;
+                if let Some(grammar) = &mut grammar {
+                    ctx.grammar_accept_token(grammar, new_token_id);
+                }
 
                 // is it an end of stream?
                 if new_token_id == self.model.token_eos() {
                     break;
                 }
 
-                output.push(self.model.token_to_str(new_token_id, Special::Tokenize)?);
+                let token = self.model.token_to_str(new_token_id, Special::Tokenize)?;
+                let text = output.push(&token);
+                if !text.is_empty() {
+                    on_token(&text)?;
+                }
+                if output.is_stopped() {
+                    break;
+                }
                 batch.clear();
                 batch.add(new_token_id, n_cur, &[0], true)?;
+                cached.tokens.push(new_token_id);
             }
             n_cur += 1;
             ctx.decode(&mut batch).with_context(|| "failed to eval")?;
@@ -120,7 +497,195 @@ impl Model {
         );
         info!("{}", ctx.timings());
 
-        Ok(output.join(""))
+        let text = output.finish();
+        if !text.is_empty() {
+            on_token(&text)?;
+        }
+        Ok(Generation {
+            text: output.text().to_owned(),
+            logprob: logprob_sum / n_sampled.max(1) as f32,
+        })
+    }
+
+    // The model evaluates the tokens the draft model guessed in one batch and keeps them up to the
+    // first one it would not have picked itself, along with its own pick there
+    fn generate_speculative(
+        &self,
+        cached: &mut CachedContext<'_>,
+        draft: &Draft,
+        tokens_list: &[LlamaToken],
+        params: &LLaMACPPRunParams,
+        cancel: &CancellationToken,
+        mut on_token: impl FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Generation> {
+        let mut draft_cache = draft.cache.lock();
+        if draft_cache.is_none() {
+            *draft_cache = Some(new_cached_context(&draft.model, self.get_context_params())?);
+        }
+        let draft_cached = draft_cache
+            .as_mut()
+            .context("the draft llama_context was just created")?;
+
+        let mut batch = LlamaBatch::new(self.n_ctx.get() as usize, 1);
+        eval_tokens(cached, tokens_list, &mut batch)?;
+        let mut draft_batch = LlamaBatch::new(self.n_ctx.get() as usize, 1);
+
+        let mut output = Output {
+            text: StopSequenceFilter::new(params.stop.clone()),
+            logprob_sum: 0.,
+            n_sampled: 0,
+        };
+        let mut sequence = tokens_list.to_vec();
+        let (mut n_drafted, mut n_accepted) = (0, 0);
+        let t_main_start = ggml_time_us();
+        let logits = cached.ctx.get_logits_ith(batch.n_tokens() - 1);
+        // The model's own pick, sampled but not evaluated yet
+        let mut next = greedy_token(logits);
+        let mut next_logprob = token_logprob(logits, next);
+        let result = (|| -> anyhow::Result<()> {
+            loop {
+                if cancel.is_cancelled() {
+                    anyhow::bail!("request cancelled")
+                }
+                if output.push(&self.model, next, next_logprob, &mut on_token)?
+                    || output.n_sampled >= params.max_tokens
+                {
+                    return Ok(());
+                }
+                sequence.push(next);
+
+                // The draft model catches up with the accepted tokens and guesses the next ones
+                eval_tokens(draft_cached, &sequence, &mut draft_batch)?;
+                let n_draft = draft.n_draft.min(params.max_tokens - output.n_sampled);
+                let mut drafted = Vec::with_capacity(n_draft);
+                while drafted.len() < n_draft {
+                    let token =
+                        greedy_token(draft_cached.ctx.get_logits_ith(draft_batch.n_tokens() - 1));
+                    drafted.push(token);
+                    if token == draft.model.token_eos() || drafted.len() == n_draft {
+                        break;
+                    }
+                    draft_batch.clear();
+                    draft_batch.add(token, draft_cached.tokens.len() as i32, &[0], true)?;
+                    draft_cached
+                        .ctx
+                        .decode(&mut draft_batch)
+                        .with_context(|| "failed to eval the draft")?;
+                    draft_cached.tokens.push(token);
+                }
+                n_drafted += drafted.len();
+
+                let n_past = cached.tokens.len();
+                batch.clear();
+                for (i, token) in std::iter::once(next)
+                    .chain(drafted.iter().copied())
+                    .enumerate()
+                {
+                    batch.add(token, (n_past + i) as i32, &[0], true)?;
+                }
+                cached
+                    .ctx
+                    .decode(&mut batch)
+                    .with_context(|| "failed to eval")?;
+                cached.tokens.push(next);
+                cached.tokens.extend(&drafted);
+
+                let mut n_kept = 0;
+                let mut done = false;
+                for (i, token) in (0_i32..).zip(drafted.iter().copied()) {
+                    let logits = cached.ctx.get_logits_ith(i);
+                    next = greedy_token(logits);
+                    next_logprob = token_logprob(logits, next);
+                    if next != token {
+                        break;
+                    }
+                    n_kept += 1;
+                    if output.push(&self.model, token, next_logprob, &mut on_token)?
+                        || output.n_sampled >= params.max_tokens
+                    {
+                        done = true;
+                        break;
+                    }
+                    sequence.push(token);
+                }
+                n_accepted += n_kept;
+                if n_kept == drafted.len() && !done {
+                    let logits = cached.ctx.get_logits_ith(drafted.len() as i32);
+                    next = greedy_token(logits);
+                    next_logprob = token_logprob(logits, next);
+                }
+
+                // Drop the rejected tokens from the KV cache
+                let n_valid = n_past + 1 + n_kept;
+                let position = u16::try_from(n_valid).with_context(|| {
+                    format!("speculative decoding supports up to {} tokens", u16::MAX)
+                })?;
+                cached.ctx.clear_kv_cache_seq(0, Some(position), None);
+                cached.tokens.truncate(n_valid);
+                if done {
+                    return Ok(());
+                }
+            }
+        })();
+        // Neither KV cache can be trusted after an error
+        if result.is_err() {
+            draft_cached.ctx.clear_kv_cache();
+            draft_cached.tokens.clear();
+        }
+        result?;
+
+        let duration = Duration::from_micros((ggml_time_us() - t_main_start) as u64);
+        info!(
+            "generated {} tokens in {:.2} s, speed {:.2} t/s, accepted {n_accepted} of {n_drafted} drafted tokens\n",
+            output.n_sampled,
+            duration.as_secs_f32(),
+            output.n_sampled as f32 / duration.as_secs_f32()
+        );
+
+        let text = output.text.finish();
+        if !text.is_empty() {
+            on_token(&text)?;
+        }
+        Ok(Generation {
+            text: output.text.text().to_owned(),
+            logprob: output.logprob_sum / output.n_sampled.max(1) as f32,
+        })
+    }
+
+    // Embeds each text separately with a dedicated context as embeddings have to be enabled when
+    // the context is created
+    #[instrument(skip(self, texts))]
+    pub fn embed(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let ctx_params = self
+            .get_context_params()
+            .with_n_batch(self.n_ctx.get())
+            .with_embeddings(true);
+        let mut ctx = self
+            .model
+            .new_context(&BACKEND, ctx_params)
+            .with_context(|| "unable to create the llama_context")?;
+        let mut batch = LlamaBatch::new(self.n_ctx.get() as usize, 1);
+        texts
+            .iter()
+            .map(|text| {
+                let mut tokens = self.model.str_to_token(text, AddBos::Always)?;
+                // Texts longer than the context are truncated rather than rejected
+                tokens.truncate(self.n_ctx.get() as usize);
+                batch.clear();
+                batch.add_sequence(&tokens, 0, false)?;
+                ctx.clear_kv_cache();
+                ctx.decode(&mut batch).with_context(|| "failed to eval")?;
+                let embedding = ctx
+                    .embeddings_seq_ith(0)
+                    .with_context(|| "the model does not support embeddings")?;
+                // Normalize so the embeddings can be compared using dot products
+                let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+                Ok(embedding
+                    .iter()
+                    .map(|x| x / norm.max(f32::EPSILON))
+                    .collect())
+            })
+            .collect()
     }
 
     #[instrument(skip(self))]
@@ -138,6 +703,10 @@ impl Model {
             .apply_chat_template(template, llama_chat_messages, true)?)
     }
 
+    pub fn get_embedded_chat_template(&self) -> Option<&str> {
+        self.chat_template.as_deref()
+    }
+
     #[instrument(skip(self))]
     pub fn get_eos_token(&self) -> anyhow::Result<String> {
         let token = self.model.token_eos();
@@ -149,4 +718,61 @@ impl Model {
         let token = self.model.token_bos();
         Ok(self.model.token_to_str(token, Special::Tokenize)?)
     }
+
+    pub fn count_tokens(&self, text: &str) -> anyhow::Result<u64> {
+        Ok(self.model.str_to_token(text, AddBos::Never)?.len() as u64)
+    }
+}
+
+// The log probability of `token` under the raw (unfiltered) distribution
+fn token_logprob(logits: &[f32], token: LlamaToken) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|logit| (logit - max).exp()).sum();
+    logits[token.0 as usize] - max - sum.ln()
+}
+
+// Applies the configured samplers in the same order llama.cpp's main example does
+fn sample_token(
+    ctx: &mut LlamaContext,
+    mut candidates: LlamaTokenDataArray,
+    params: &LLaMACPPRunParams,
+    last_tokens: &[LlamaToken],
+    mirostat_mu: &mut f32,
+) -> LlamaToken {
+    if params.repeat_penalty != 1.
+        || params.frequency_penalty != 0.
+        || params.presence_penalty != 0.
+    {
+        let penalty_last_n = params.repeat_last_n.min(last_tokens.len());
+        candidates.sample_repetition_penalty(
+            Some(&mut *ctx),
+            &last_tokens[last_tokens.len() - penalty_last_n..],
+            penalty_last_n,
+            params.repeat_penalty,
+            params.frequency_penalty,
+            params.presence_penalty,
+        );
+    }
+
+    if params.temperature <= 0. {
+        return ctx.sample_token_greedy(candidates);
+    }
+
+    if params.mirostat == 2 {
+        candidates.sample_temp(Some(&mut *ctx), params.temperature);
+        return candidates.sample_token_mirostat_v2(
+            ctx,
+            params.mirostat_tau,
+            params.mirostat_eta,
+            mirostat_mu,
+        );
+    }
+
+    if params.top_k > 0 {
+        candidates.sample_top_k(Some(&mut *ctx), params.top_k, 1);
+    }
+    candidates.sample_top_p(Some(&mut *ctx), params.top_p, 1);
+    candidates.sample_min_p(Some(&mut *ctx), params.min_p, 1);
+    candidates.sample_temp(Some(&mut *ctx), params.temperature);
+    candidates.sample_token(ctx)
 }
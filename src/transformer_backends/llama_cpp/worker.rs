@@ -0,0 +1,421 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use super::LLaMACPP;
+use crate::{
+    config,
+    memory_backends::Prompt,
+    transformer_backends::{
+        process::{self, Supervisor},
+        RenderedPrompt, TransformerBackend,
+    },
+    transformer_worker::{
+        CompletionCandidate, DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse,
+    },
+    usage::TokenUsage,
+};
+
+// The hidden subcommand the server runs itself with to start a worker
+pub const WORKER_COMMAND: &str = "llama-cpp-worker";
+
+// The worker gets the model configuration first and answers with `Ready` once the model is
+// loaded. Then it answers each request with its tokens, if streamed, and one final response.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Completion { prompt: Prompt, params: Value },
+    Generate { prompt: Prompt, params: Value },
+    GenerateStream { prompt: Prompt, params: Value },
+    RenderPrompt { prompt: Prompt, params: Value },
+    // Cancels the request the worker is running
+    Cancel,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    Ready,
+    Token {
+        text: String,
+    },
+    Completion {
+        // The text and score of each candidate
+        candidates: Vec<(String, Option<f32>)>,
+        usage: Option<TokenUsage>,
+    },
+    Generation {
+        generated_text: String,
+        usage: Option<TokenUsage>,
+    },
+    RenderedPrompt {
+        prompt: RenderedPrompt,
+    },
+    Error {
+        message: String,
+    },
+}
+
+// Frames are a little endian u32 length followed by that many bytes of JSON
+fn write_frame(writer: &mut impl Write, message: &impl Serialize) -> anyhow::Result<()> {
+    let bytes = serde_json::to_vec(message)?;
+    writer.write_all(&u32::try_from(bytes.len())?.to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// None when the other side closed the stream between frames
+fn read_frame<T: DeserializeOwned>(reader: &mut impl Read) -> anyhow::Result<Option<T>> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut bytes = vec![0; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+async fn handle_request(
+    llama_cpp: &LLaMACPP,
+    request: Request,
+    stdout: &Arc<Mutex<io::Stdout>>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Response> {
+    match request {
+        Request::Completion { prompt, params } => {
+            let response = llama_cpp.do_completion(&prompt, params, cancel).await?;
+            Ok(Response::Completion {
+                candidates: response
+                    .candidates
+                    .into_iter()
+                    .map(|candidate| (candidate.insert_text, candidate.score))
+                    .collect(),
+                usage: response.usage,
+            })
+        }
+        Request::Generate { prompt, params } => {
+            let response = llama_cpp.do_generate(&prompt, params, cancel).await?;
+            Ok(Response::Generation {
+                generated_text: response.generated_text,
+                usage: response.usage,
+            })
+        }
+        Request::GenerateStream { prompt, params } => {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+            let stdout = stdout.clone();
+            let forward = thread::spawn(move || -> anyhow::Result<()> {
+                while let Some(text) = rx.blocking_recv() {
+                    write_frame(&mut *stdout.lock(), &Response::Token { text })?;
+                }
+                Ok(())
+            });
+            let response = llama_cpp
+                .do_generate_stream(&prompt, params, tx, cancel)
+                .await;
+            // Every token is sent before the final response
+            forward
+                .join()
+                .map_err(|_| anyhow::anyhow!("the token thread panicked"))??;
+            let response = response?;
+            Ok(Response::Generation {
+                generated_text: response.generated_text,
+                usage: response.usage,
+            })
+        }
+        Request::RenderPrompt { prompt, params } => Ok(Response::RenderedPrompt {
+            prompt: llama_cpp.render_prompt(&prompt, &params)?,
+        }),
+        Request::Cancel => anyhow::bail!("nothing to cancel"),
+    }
+}
+
+// The worker's side, talking over stdin and stdout. It exits when the server closes stdin.
+pub fn run() -> anyhow::Result<()> {
+    let mut stdin = BufReader::new(io::stdin());
+    let stdout = Arc::new(Mutex::new(io::stdout()));
+    let configuration: config::LLaMACPP =
+        read_frame(&mut stdin)?.context("the server did not send the model configuration")?;
+    let llama_cpp = match LLaMACPP::new(configuration) {
+        Ok(llama_cpp) => llama_cpp,
+        Err(e) => {
+            write_frame(
+                &mut *stdout.lock(),
+                &Response::Error {
+                    message: format!("{e:?}"),
+                },
+            )?;
+            return Err(e);
+        }
+    };
+    write_frame(&mut *stdout.lock(), &Response::Ready)?;
+
+    // Read on their own thread so cancellations arrive while a request runs
+    let (tx, rx) = crossbeam_channel::unbounded();
+    thread::spawn(move || -> anyhow::Result<()> {
+        let mut current = CancellationToken::new();
+        while let Some(request) = read_frame::<Request>(&mut stdin)? {
+            if let Request::Cancel = request {
+                current.cancel();
+                continue;
+            }
+            let cancel = CancellationToken::new();
+            current = cancel.clone();
+            if tx.send((request, cancel)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    for (request, cancel) in rx {
+        let response = runtime
+            .block_on(handle_request(&llama_cpp, request, &stdout, &cancel))
+            .unwrap_or_else(|e| Response::Error {
+                message: format!("{e:?}"),
+            });
+        write_frame(&mut *stdout.lock(), &response)?;
+    }
+    Ok(())
+}
+
+struct Worker(config::LLaMACPP);
+
+impl process::Program for Worker {
+    type Request = Request;
+    type Response = Response;
+
+    fn name(&self) -> String {
+        "the llama.cpp worker".to_string()
+    }
+
+    fn command(&self) -> anyhow::Result<Command> {
+        let mut command = Command::new(std::env::current_exe()?);
+        command
+            .arg(WORKER_COMMAND)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // The worker logs like the server does
+            .stderr(Stdio::inherit());
+        Ok(command)
+    }
+
+    // Answered once the model is loaded
+    fn handshake(
+        &self,
+        child: &mut Child,
+        stdin: &mut ChildStdin,
+        stdout: &mut BufReader<ChildStdout>,
+    ) -> anyhow::Result<()> {
+        write_frame(stdin, &self.0)?;
+        match read_frame(stdout) {
+            Ok(Some(Response::Ready)) => Ok(()),
+            Ok(Some(Response::Error { message })) => anyhow::bail!(message),
+            Ok(_) => anyhow::bail!(
+                "the llama.cpp worker exited while loading the model ({})",
+                process::get_exit_status(child)
+            ),
+            Err(e) => Err(e.context("error reading from the llama.cpp worker")),
+        }
+    }
+
+    fn write(&self, stdin: &mut ChildStdin, request: &Request) -> anyhow::Result<()> {
+        write_frame(stdin, request)
+    }
+
+    fn read(&self, stdout: &mut BufReader<ChildStdout>) -> anyhow::Result<Option<Response>> {
+        read_frame(stdout)
+    }
+
+    fn cancel(&self) -> Request {
+        Request::Cancel
+    }
+
+    fn get_token(&self, response: Response) -> Result<String, Response> {
+        match response {
+            Response::Token { text } => Ok(text),
+            response => Err(response),
+        }
+    }
+
+    // Loading the model takes long, so it is loaded again before the next request
+    fn restarts_right_away(&self) -> bool {
+        true
+    }
+}
+
+// Runs llama.cpp in a child process. When it crashes the requests in flight fail and it is
+// started again with the same model.
+pub struct Isolated {
+    supervisor: Supervisor<Worker>,
+}
+
+impl Isolated {
+    pub fn new(configuration: config::LLaMACPP) -> anyhow::Result<Self> {
+        let supervisor = Supervisor::new(Worker(configuration));
+        supervisor.start()?;
+        Ok(Self { supervisor })
+    }
+
+    async fn request(
+        &self,
+        request: Request,
+        on_token: impl FnMut(String) + Send + 'static,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<Response> {
+        match self.supervisor.request(request, on_token, cancel).await? {
+            Response::Error { message } => anyhow::bail!(message),
+            response => Ok(response),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TransformerBackend for Isolated {
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    #[instrument(skip(self))]
+    async fn do_completion(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoCompletionResponse> {
+        let request = Request::Completion {
+            prompt: prompt.clone(),
+            params,
+        };
+        match self.request(request, |_| (), cancel).await? {
+            Response::Completion { candidates, usage } => Ok(DoCompletionResponse {
+                candidates: candidates
+                    .into_iter()
+                    .map(|(insert_text, score)| CompletionCandidate { insert_text, score })
+                    .collect(),
+                usage,
+            }),
+            response => {
+                anyhow::bail!("unexpected response from the llama.cpp worker: {response:?}")
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let request = Request::Generate {
+            prompt: prompt.clone(),
+            params,
+        };
+        match self.request(request, |_| (), cancel).await? {
+            Response::Generation {
+                generated_text,
+                usage,
+            } => Ok(DoGenerationResponse {
+                generated_text,
+                usage,
+            }),
+            response => {
+                anyhow::bail!("unexpected response from the llama.cpp worker: {response:?}")
+            }
+        }
+    }
+
+    #[instrument(skip(self, tx))]
+    async fn do_generate_stream(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<String>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        let request = Request::GenerateStream {
+            prompt: prompt.clone(),
+            params,
+        };
+        // A closed channel means nobody waits for the tokens anymore, the request is cancelled
+        let on_token = move |text| {
+            let _ = tx.send(text);
+        };
+        match self.request(request, on_token, cancel).await? {
+            Response::Generation {
+                generated_text,
+                usage,
+            } => Ok(DoGenerationStreamResponse {
+                generated_text,
+                usage,
+            }),
+            response => {
+                anyhow::bail!("unexpected response from the llama.cpp worker: {response:?}")
+            }
+        }
+    }
+
+    fn render_prompt(&self, prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+        let request = Request::RenderPrompt {
+            prompt: prompt.clone(),
+            params: params.clone(),
+        };
+        match self
+            .supervisor
+            .request_blocking(request, |_| (), &CancellationToken::new())?
+        {
+            Response::RenderedPrompt { prompt } => Ok(prompt),
+            Response::Error { message } => anyhow::bail!(message),
+            response => {
+                anyhow::bail!("unexpected response from the llama.cpp worker: {response:?}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory_backends::FIMPrompt;
+    use serde_json::json;
+
+    #[test]
+    fn worker_frames() -> anyhow::Result<()> {
+        let mut bytes = vec![];
+        write_frame(
+            &mut bytes,
+            &Request::Generate {
+                prompt: Prompt::FIM(FIMPrompt::new("def ".to_string(), "\n".to_string())),
+                params: json!({"max_tokens": 4}),
+            },
+        )?;
+        write_frame(&mut bytes, &Request::Cancel)?;
+
+        let mut reader = bytes.as_slice();
+        let Some(Request::Generate { prompt, params }) = read_frame(&mut reader)? else {
+            panic!("expected a generate request");
+        };
+        assert!(matches!(prompt, Prompt::FIM(fim) if fim.prompt == "def "));
+        assert_eq!(params["max_tokens"], 4);
+        assert!(matches!(read_frame(&mut reader)?, Some(Request::Cancel)));
+        assert!(read_frame::<Request>(&mut reader)?.is_none());
+        Ok(())
+    }
+}
@@ -1,15 +1,17 @@
 use anyhow::Context;
 use serde::Deserialize;
 use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 
-use super::{open_ai::OpenAIChatResponse, TransformerBackend};
+use super::{open_ai::OpenAIChatResponse, retry, RenderedPrompt, TransformerBackend};
 use crate::{
-    config::{self},
+    auth::get_auth_token,
+    config::{self, ChatMessage},
+    http_client::get_client,
     memory_backends::{FIMPrompt, Prompt, PromptType},
-    transformer_worker::{
-        DoGenerationResponse, DoGenerationStreamResponse, GenerationStreamRequest,
-    },
+    transformer_worker::DoGenerationResponse,
+    utils::format_chat_messages,
 };
 
 const fn max_tokens_default() -> usize {
@@ -37,6 +39,25 @@ pub struct MistralFIMRunParams {
     pub random_seed: Option<u64>,
     #[serde(default)]
     pub stop: Vec<String>,
+    // Generation requests with messages use the chat endpoint
+    pub messages: Option<Vec<ChatMessage>>,
+}
+
+// The FIM and chat endpoints both respond in the OpenAI chat format
+fn get_response(res: OpenAIChatResponse) -> anyhow::Result<DoGenerationResponse> {
+    if let Some(error) = res.error {
+        anyhow::bail!("{:?}", error.to_string())
+    } else if let Some(choices) = res.choices {
+        Ok(DoGenerationResponse {
+            generated_text: choices[0].message.content.clone().unwrap_or_default(),
+            usage: res.usage,
+        })
+    } else {
+        anyhow::bail!(
+            "Unknown error while making request to MistralFIM: {:?}",
+            res.other
+        );
+    }
 }
 
 pub struct MistralFIM {
@@ -48,31 +69,29 @@ impl MistralFIM {
         Self { config }
     }
 
-    fn get_token(&self) -> anyhow::Result<String> {
-        if let Some(env_var_name) = &self.config.auth_token_env_var_name {
-            Ok(std::env::var(env_var_name)?)
-        } else if let Some(token) = &self.config.auth_token {
-            Ok(token.to_string())
-        } else {
-            anyhow::bail!(
-                "set `auth_token_env_var_name` or `auth_token` to use an MistralFIM compatible API"
-            )
-        }
+    async fn get_token(&self) -> anyhow::Result<String> {
+        get_auth_token(
+            self.config.auth_token_env_var_name.as_deref(),
+            self.config.auth_token.as_deref(),
+            self.config.auth_token_keyring.as_ref(),
+            self.config.auth_token_command.as_deref(),
+        ).await?
+        .context("set `auth_token_env_var_name`, `auth_token`, `auth_token_keyring` or `auth_token_command` to use an MistralFIM compatible API")
     }
 
     async fn do_fim(
         &self,
         prompt: &FIMPrompt,
         params: MistralFIMRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let token = self.get_token()?;
-        let res: OpenAIChatResponse = client
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let client = get_client();
+        let token = self.get_token().await?;
+        let request = client
             .post(
                 self.config
                     .fim_endpoint
-                    .as_ref()
-                    .context("must specify `fim_endpoint` to use fim")?,
+                    .as_deref()
+                    .unwrap_or("https://api.mistral.ai/v1/fim/completions"),
             )
             .bearer_auth(token)
             .header("Content-Type", "application/json")
@@ -87,21 +106,46 @@ impl MistralFIM {
                 "min_tokens": params.min_tokens,
                 "random_seed": params.random_seed,
                 "stop": params.stop
-            }))
-            .send()
+            }));
+        let res: OpenAIChatResponse = retry::send(request, &self.config.retry)
             .await?
             .json()
             .await?;
-        if let Some(error) = res.error {
-            anyhow::bail!("{:?}", error.to_string())
-        } else if let Some(choices) = res.choices {
-            Ok(choices[0].message.content.clone())
-        } else {
-            anyhow::bail!(
-                "Unknown error while making request to MistralFIM: {:?}",
-                res.other
-            );
-        }
+        get_response(res)
+    }
+
+    async fn do_chat(
+        &self,
+        messages: Vec<ChatMessage>,
+        params: MistralFIMRunParams,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let client = get_client();
+        let token = self.get_token().await?;
+        let request = client
+            .post(
+                self.config
+                    .chat_endpoint
+                    .as_deref()
+                    .unwrap_or("https://api.mistral.ai/v1/chat/completions"),
+            )
+            .bearer_auth(token)
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .json(&json!({
+                "messages": messages,
+                "model": self.config.model,
+                "max_tokens": params.max_tokens,
+                "top_p": params.top_p,
+                "temperature": params.temperature,
+                "min_tokens": params.min_tokens,
+                "random_seed": params.random_seed,
+                "stop": params.stop
+            }));
+        let res: OpenAIChatResponse = retry::send(request, &self.config.retry)
+            .await?
+            .json()
+            .await?;
+        get_response(res)
     }
 }
 
@@ -112,23 +156,44 @@ impl TransformerBackend for MistralFIM {
         &self,
         prompt: &Prompt,
         params: Value,
+        _cancel: &CancellationToken,
     ) -> anyhow::Result<DoGenerationResponse> {
         let params: MistralFIMRunParams = serde_json::from_value(params)?;
-        let generated_text = self.do_fim(prompt.try_into()?, params).await?;
-        Ok(DoGenerationResponse { generated_text })
+        match prompt {
+            Prompt::FIM(fim) => self.do_fim(fim, params).await,
+            Prompt::ContextAndCode(context_and_code) => {
+                let messages = format_chat_messages(
+                    params.messages.as_deref().unwrap_or_default(),
+                    context_and_code,
+                );
+                self.do_chat(messages, params).await
+            }
+        }
     }
 
-    #[instrument(skip(self))]
-    async fn do_generate_stream(
-        &self,
-        request: &GenerationStreamRequest,
-        _params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse> {
-        anyhow::bail!("GenerationStream is not yet implemented")
+    fn get_prompt_type(&self, params: &Value) -> anyhow::Result<PromptType> {
+        if params.get("messages").is_some() {
+            Ok(PromptType::ContextAndCode)
+        } else {
+            Ok(PromptType::FIM)
+        }
     }
 
-    fn get_prompt_type(&self, _params: &Value) -> anyhow::Result<PromptType> {
-        Ok(PromptType::FIM)
+    fn render_prompt(&self, prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+        let params: MistralFIMRunParams = serde_json::from_value(params.clone())?;
+        Ok(match prompt {
+            Prompt::FIM(fim) => RenderedPrompt {
+                prompt: Some(fim.prompt.clone()),
+                suffix: Some(fim.suffix.clone()),
+                ..Default::default()
+            },
+            Prompt::ContextAndCode(context_and_code) => {
+                RenderedPrompt::messages(format_chat_messages(
+                    params.messages.as_deref().unwrap_or_default(),
+                    context_and_code,
+                ))
+            }
+        })
     }
 }
 
@@ -149,7 +214,41 @@ mod test {
         let run_params = json!({
             "max_tokens": 2
         });
-        let response = anthropic.do_generate(&prompt, run_params).await?;
+        let response = anthropic
+            .do_generate(&prompt, run_params, &CancellationToken::new())
+            .await?;
+        assert!(!response.generated_text.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mistral_chat_do_generate() -> anyhow::Result<()> {
+        let configuration: config::MistralFIM = from_value(json!({
+            "model": "codestral-latest",
+            "auth_token_env_var_name": "MISTRAL_API_KEY",
+        }))?;
+        let mistral = MistralFIM::new(configuration);
+        let prompt = Prompt::default_with_cursor();
+        let run_params = json!({
+            "messages": [
+                {
+                    "role": "system",
+                    "content": "Test"
+                },
+                {
+                    "role": "user",
+                    "content": "Test {CONTEXT} - {CODE}"
+                }
+            ],
+            "max_tokens": 2
+        });
+        assert!(matches!(
+            mistral.get_prompt_type(&run_params)?,
+            PromptType::ContextAndCode
+        ));
+        let response = mistral
+            .do_generate(&prompt, run_params, &CancellationToken::new())
+            .await?;
         assert!(!response.generated_text.is_empty());
         Ok(())
     }
@@ -1,21 +1,110 @@
+use std::sync::{Arc, Weak};
+
 use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    config::ValidModel,
+    config::{self, ChatMessage, ValidModel},
     memory_backends::{Prompt, PromptType},
+    tokenizer::{EstimatedTokenizer, SharedTokenizer},
+    tools::ToolRequest,
     transformer_worker::{
         DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse,
-        GenerationStreamRequest,
+        DoToolGenerationResponse,
     },
+    utils::{format_chat_messages, format_context_code},
 };
 
 mod anthropic;
+#[cfg(feature = "candle")]
+mod candle;
+mod cohere;
+mod command;
+mod gemini;
+mod limit;
 #[cfg(feature = "llama_cpp")]
-mod llama_cpp;
+pub mod llama_cpp;
 mod mistral_fim;
 mod ollama;
 mod open_ai;
+mod process;
+mod retry;
+mod tgi;
+
+// The prompt as a backend sends it, either chat messages or a completion string
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct RenderedPrompt {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub messages: Option<Vec<ChatMessage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    // For backends that send the text after the cursor separately
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+}
+
+impl RenderedPrompt {
+    pub fn messages(messages: Vec<ChatMessage>) -> Self {
+        Self {
+            messages: Some(messages),
+            ..Default::default()
+        }
+    }
+
+    pub fn prompt(prompt: String) -> Self {
+        Self {
+            prompt: Some(prompt),
+            ..Default::default()
+        }
+    }
+}
+
+// Like the Hugging Face hub, only `1` and `true` enable the offline mode
+#[cfg(any(feature = "llama_cpp", feature = "candle"))]
+fn is_offline_value(value: &str) -> bool {
+    matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true")
+}
+
+// Whether the `HF_HUB_OFFLINE` env var restricts models to the local Hugging Face cache
+#[cfg(any(feature = "llama_cpp", feature = "candle"))]
+fn is_hf_hub_offline() -> bool {
+    std::env::var("HF_HUB_OFFLINE").is_ok_and(|value| is_offline_value(&value))
+}
+
+// The parameters every backend formats prompts with
+#[derive(Deserialize)]
+struct PromptParams {
+    messages: Option<Vec<ChatMessage>>,
+    fim: Option<config::FIM>,
+}
+
+// Chat messages when `messages` is set, otherwise the context and code or the FIM string
+pub fn render_prompt(prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+    let params: PromptParams = serde_json::from_value(params.clone())?;
+    match prompt {
+        Prompt::ContextAndCode(context_and_code) => Ok(match &params.messages {
+            Some(messages) => {
+                RenderedPrompt::messages(format_chat_messages(messages, context_and_code))
+            }
+            None => RenderedPrompt::prompt(format_context_code(
+                &context_and_code.context,
+                &context_and_code.code,
+            )),
+        }),
+        Prompt::FIM(fim) => match &params.fim {
+            Some(fim_params) => Ok(RenderedPrompt::prompt(format!(
+                "{}{}{}{}{}",
+                fim_params.start, fim.prompt, fim_params.middle, fim.suffix, fim_params.end
+            ))),
+            None => anyhow::bail!("Prompt type is FIM but no FIM parameters provided"),
+        },
+    }
+}
 
 #[async_trait::async_trait]
 pub trait TransformerBackend {
@@ -23,11 +112,13 @@ pub trait TransformerBackend {
         &self,
         prompt: &Prompt,
         params: Value,
+        cancel: &CancellationToken,
     ) -> anyhow::Result<DoCompletionResponse> {
-        self.do_generate(prompt, params)
+        self.do_generate(prompt, params, cancel)
             .await
             .map(|x| DoCompletionResponse {
-                insert_text: x.generated_text,
+                usage: x.usage,
+                ..DoCompletionResponse::new(x.generated_text)
             })
     }
 
@@ -35,13 +126,42 @@ pub trait TransformerBackend {
         &self,
         prompt: &Prompt,
         params: Value,
+        cancel: &CancellationToken,
     ) -> anyhow::Result<DoGenerationResponse>;
 
+    // Backends that can not stream token by token send the full generation as a single chunk
     async fn do_generate_stream(
         &self,
-        request: &GenerationStreamRequest,
+        prompt: &Prompt,
         params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse>;
+        tx: UnboundedSender<String>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        let response = self.do_generate(prompt, params, cancel).await?;
+        tx.send(response.generated_text.clone())
+            .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
+        Ok(DoGenerationStreamResponse {
+            generated_text: response.generated_text,
+            usage: response.usage,
+        })
+    }
+
+    // One step of a generation that may call tools, the worker runs the calls and sends their
+    // results with the next step
+    async fn do_generate_with_tools(
+        &self,
+        _prompt: &Prompt,
+        _params: Value,
+        _tools: &ToolRequest<'_>,
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoToolGenerationResponse> {
+        anyhow::bail!("the backend does not support tools")
+    }
+
+    // Prompts for backends that are not local have secrets redacted when redaction is enabled
+    fn is_local(&self) -> bool {
+        false
+    }
 
     fn get_prompt_type(&self, params: &Value) -> anyhow::Result<PromptType> {
         if params
@@ -54,6 +174,65 @@ pub trait TransformerBackend {
             Ok(PromptType::ContextAndCode)
         }
     }
+
+    // Servers that reuse the work done for the start of earlier prompts get prompts whose start
+    // stays the same while typing
+    fn caches_prompt_prefixes(&self) -> bool {
+        false
+    }
+
+    // Used to fit prompts into the context window. Backends that can not tokenize locally estimate.
+    fn get_tokenizer(&self) -> SharedTokenizer {
+        Arc::new(EstimatedTokenizer)
+    }
+
+    // What `do_generate` would send for the prompt, used to debug prompts
+    fn render_prompt(&self, prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+        render_prompt(prompt, params)
+    }
+}
+
+pub type SharedBackend = Arc<Box<dyn TransformerBackend + Send + Sync>>;
+
+// Where the backend of a model is loaded into, loading only holds the lock of its own slot
+type Slot = Arc<Mutex<Weak<Box<dyn TransformerBackend + Send + Sync>>>>;
+
+// The backends of every connected client by model configuration. Clients configuring the same
+// model share its backend so e.g. a llama.cpp model is only loaded once, and it is dropped with
+// the last client using it.
+static SHARED_BACKENDS: Lazy<Mutex<Vec<(ValidModel, Slot)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub fn get_shared(model: ValidModel) -> anyhow::Result<SharedBackend> {
+    let slot = {
+        let mut backends = SHARED_BACKENDS.lock();
+        // A slot someone else holds is being loaded into or waited on
+        backends.retain(|(_, slot)| Arc::strong_count(slot) > 1 || slot.lock().strong_count() > 0);
+        match backends
+            .iter()
+            .find(|(shared_model, _)| *shared_model == model)
+        {
+            Some((_, slot)) => slot.clone(),
+            None => {
+                let slot = Slot::default();
+                backends.push((model.clone(), slot.clone()));
+                slot
+            }
+        }
+    };
+    // Clients asking for a model that is being loaded wait for it instead of loading it again,
+    // other models load at the same time
+    let mut shared = slot.lock();
+    if let Some(backend) = shared.upgrade() {
+        return Ok(backend);
+    }
+    let mut backend: Box<dyn TransformerBackend + Send + Sync> = model.clone().try_into()?;
+    // Clients sharing the backend share its limit
+    if let Some(max_concurrent_requests) = model.max_concurrent_requests() {
+        backend = Box::new(limit::Limited::new(backend, max_concurrent_requests));
+    }
+    let backend: SharedBackend = Arc::new(backend);
+    *shared = Arc::downgrade(&backend);
+    Ok(backend)
 }
 
 impl TryFrom<ValidModel> for Box<dyn TransformerBackend + Send + Sync> {
@@ -61,11 +240,23 @@ impl TryFrom<ValidModel> for Box<dyn TransformerBackend + Send + Sync> {
 
     fn try_from(valid_model: ValidModel) -> Result<Self, Self::Error> {
         match valid_model {
+            #[cfg(feature = "llama_cpp")]
+            ValidModel::LLaMACPP(model_gguf) if model_gguf.isolate => {
+                Ok(Box::new(llama_cpp::worker::Isolated::new(model_gguf)?))
+            }
             #[cfg(feature = "llama_cpp")]
             ValidModel::LLaMACPP(model_gguf) => Ok(Box::new(llama_cpp::LLaMACPP::new(model_gguf)?)),
+            #[cfg(feature = "candle")]
+            ValidModel::Candle(candle) => Ok(Box::new(candle::Candle::new(candle)?)),
             ValidModel::OpenAI(open_ai_config) => {
                 Ok(Box::new(open_ai::OpenAI::new(open_ai_config)))
             }
+            ValidModel::AzureOpenAI(azure_open_ai_config) => {
+                Ok(Box::new(open_ai::OpenAI::new_azure(azure_open_ai_config)))
+            }
+            ValidModel::OpenAICompatible(open_ai_compatible_config) => Ok(Box::new(
+                open_ai::OpenAI::new_compatible(open_ai_compatible_config)?,
+            )),
             ValidModel::Anthropic(anthropic_config) => {
                 Ok(Box::new(anthropic::Anthropic::new(anthropic_config)))
             }
@@ -73,6 +264,60 @@ impl TryFrom<ValidModel> for Box<dyn TransformerBackend + Send + Sync> {
                 Ok(Box::new(mistral_fim::MistralFIM::new(mistral_fim)))
             }
             ValidModel::Ollama(ollama) => Ok(Box::new(ollama::Ollama::new(ollama))),
+            ValidModel::Gemini(gemini) => Ok(Box::new(gemini::Gemini::new(gemini))),
+            ValidModel::Command(command) => Ok(Box::new(command::Command::new(command))),
+            ValidModel::TGI(tgi) => Ok(Box::new(tgi::TGI::new(tgi))),
+            ValidModel::VLLM(vllm) => Ok(Box::new(open_ai::OpenAI::new_vllm(vllm))),
+            ValidModel::Cohere(cohere) => Ok(Box::new(cohere::Cohere::new(cohere))),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory_backends::{ContextAndCodePrompt, FIMPrompt};
+    use serde_json::json;
+
+    #[test]
+    fn renders_prompts() -> anyhow::Result<()> {
+        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt::new(
+            "fn a() {}".to_string(),
+            "fn b() {<CURSOR>}".to_string(),
+        ));
+        let params = json!({
+            "messages": [{"role": "user", "content": "{CONTEXT}\n---\n{CODE}"}],
+            "max_tokens": 64
+        });
+        assert_eq!(
+            render_prompt(&prompt, &params)?,
+            RenderedPrompt::messages(vec![ChatMessage::new(
+                "user".to_string(),
+                "fn a() {}\n---\nfn b() {<CURSOR>}".to_string()
+            )])
+        );
+        assert_eq!(
+            render_prompt(&prompt, &json!({}))?,
+            RenderedPrompt::prompt("fn a() {}\n\nfn b() {<CURSOR>}".to_string())
+        );
+
+        let prompt = Prompt::FIM(FIMPrompt::new("fn a(".to_string(), ") {}".to_string()));
+        let params = json!({"fim": {"start": "<pre>", "middle": "<suf>", "end": "<mid>"}});
+        assert_eq!(
+            serde_json::to_value(render_prompt(&prompt, &params)?)?,
+            json!({"prompt": "<pre>fn a(<suf>) {}<mid>"})
+        );
+        assert!(render_prompt(&prompt, &json!({})).is_err());
+        Ok(())
+    }
+
+    #[cfg(any(feature = "llama_cpp", feature = "candle"))]
+    #[test]
+    fn parses_hf_hub_offline() {
+        assert!(is_offline_value("1"));
+        assert!(is_offline_value("TRUE"));
+        assert!(!is_offline_value("0"));
+        assert!(!is_offline_value("false"));
+        assert!(!is_offline_value(""));
+    }
+}
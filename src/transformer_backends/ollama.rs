@@ -1,18 +1,20 @@
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::HashMap;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 
 use crate::{
     config::{self, ChatMessage, FIM},
+    http_client::get_client,
     memory_backends::Prompt,
-    transformer_worker::{
-        DoGenerationResponse, DoGenerationStreamResponse, GenerationStreamRequest,
-    },
+    transformer_worker::{DoGenerationResponse, DoGenerationStreamResponse},
+    usage::TokenUsage,
     utils::{format_chat_messages, format_context_code},
 };
 
-use super::TransformerBackend;
+use super::{retry, TransformerBackend};
 
 // NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
 #[derive(Debug, Deserialize)]
@@ -24,6 +26,8 @@ pub struct OllamaRunParams {
     system: Option<String>,
     template: Option<String>,
     keep_alive: Option<String>,
+    #[serde(default)]
+    stop: Vec<String>,
 }
 
 pub struct Ollama {
@@ -33,12 +37,37 @@ pub struct Ollama {
 #[derive(Deserialize)]
 struct OllamaCompletionsResponse {
     response: Option<String>,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
     error: Option<Value>,
     #[serde(default)]
     #[serde(flatten)]
     other: HashMap<String, Value>,
 }
 
+// The counts are only sent with the last part of a streamed response
+fn get_usage(prompt_eval_count: Option<u64>, eval_count: Option<u64>) -> Option<TokenUsage> {
+    Some(TokenUsage::new(prompt_eval_count?, eval_count?))
+}
+
+impl OllamaCompletionsResponse {
+    fn into_response(self) -> anyhow::Result<DoGenerationResponse> {
+        if let Some(error) = self.error {
+            anyhow::bail!("{:?}", error.to_string())
+        } else if let Some(response) = self.response {
+            Ok(DoGenerationResponse {
+                generated_text: response,
+                usage: get_usage(self.prompt_eval_count, self.eval_count),
+            })
+        } else {
+            anyhow::bail!(
+                "Uknown error while making request to Ollama: {:?}",
+                self.other
+            )
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct OllamaChatMessage {
     role: String,
@@ -48,12 +77,70 @@ struct OllamaChatMessage {
 #[derive(Deserialize)]
 struct OllamaChatResponse {
     message: Option<OllamaChatMessage>,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
     error: Option<Value>,
     #[serde(default)]
     #[serde(flatten)]
     other: HashMap<String, Value>,
 }
 
+impl OllamaChatResponse {
+    fn into_response(self) -> anyhow::Result<DoGenerationResponse> {
+        if let Some(error) = self.error {
+            anyhow::bail!("{:?}", error.to_string())
+        } else if let Some(message) = self.message {
+            Ok(DoGenerationResponse {
+                generated_text: message.content,
+                usage: get_usage(self.prompt_eval_count, self.eval_count),
+            })
+        } else {
+            anyhow::bail!(
+                "Unknown error while making request to Ollama: {:?}",
+                self.other
+            )
+        }
+    }
+}
+
+// Streamed responses are newline delimited JSON objects, each holding the next part of the text
+async fn read_stream<T: DeserializeOwned>(
+    mut res: reqwest::Response,
+    tx: UnboundedSender<String>,
+    into_response: fn(T) -> anyhow::Result<DoGenerationResponse>,
+) -> anyhow::Result<DoGenerationResponse> {
+    let mut buffer = vec![];
+    let mut generated_text = String::new();
+    let mut usage = None;
+    while let Some(chunk) = res.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(index) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=index).collect();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let response = into_response(serde_json::from_slice(&line)?)?;
+            usage = response.usage.or(usage);
+            generated_text.push_str(&response.generated_text);
+            tx.send(response.generated_text)
+                .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
+        }
+    }
+    Ok(DoGenerationResponse {
+        generated_text,
+        usage,
+    })
+}
+
+// Ollama expects stop sequences inside of `options`
+fn options_with_stop(params: &OllamaRunParams) -> HashMap<String, Value> {
+    let mut options = params.options.clone();
+    if !params.stop.is_empty() {
+        options.insert("stop".to_string(), json!(params.stop));
+    }
+    options
+}
+
 impl Ollama {
     #[instrument]
     pub fn new(configuration: config::Ollama) -> Self {
@@ -64,38 +151,34 @@ impl Ollama {
         &self,
         prompt: &str,
         params: OllamaRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let res: OllamaCompletionsResponse = client
-            .post(self
-                .configuration
-                .generate_endpoint
-                .as_deref()
-                .unwrap_or("http://localhost:11434/api/generate")
+        tx: Option<UnboundedSender<String>>,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let options = options_with_stop(&params);
+        let client = get_client();
+        let request = client
+            .post(
+                self.configuration
+                    .generate_endpoint
+                    .as_deref()
+                    .unwrap_or("http://localhost:11434/api/generate"),
             )
             .header("Content-Type", "application/json")
             .header("Accept", "application/json")
             .json(&json!({
                 "model": self.configuration.model,
                 "prompt": prompt,
-                "options": params.options,
+                "options": options,
                 "keep_alive": params.keep_alive,
                 "raw": true,
-                "stream": false
-            }))
-            .send()
-            .await?
-            .json()
-            .await?;
-        if let Some(error) = res.error {
-            anyhow::bail!("{:?}", error.to_string())
-        } else if let Some(response) = res.response {
-            Ok(response)
-        } else {
-            anyhow::bail!(
-                "Uknown error while making request to Ollama: {:?}",
-                res.other
-            )
+                "stream": tx.is_some()
+            }));
+        let res = retry::send(request, &self.configuration.retry).await?;
+        match tx {
+            Some(tx) => read_stream(res, tx, OllamaCompletionsResponse::into_response).await,
+            None => res
+                .json::<OllamaCompletionsResponse>()
+                .await?
+                .into_response(),
         }
     }
 
@@ -103,14 +186,16 @@ impl Ollama {
         &self,
         messages: Vec<ChatMessage>,
         params: OllamaRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let res: OllamaChatResponse = client
-            .post(self
-                .configuration
-                .chat_endpoint
-                .as_deref()
-                .unwrap_or("http://localhost:11434/api/chat")
+        tx: Option<UnboundedSender<String>>,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let options = options_with_stop(&params);
+        let client = get_client();
+        let request = client
+            .post(
+                self.configuration
+                    .chat_endpoint
+                    .as_deref()
+                    .unwrap_or("http://localhost:11434/api/chat"),
             )
             .header("Content-Type", "application/json")
             .header("Accept", "application/json")
@@ -119,41 +204,35 @@ impl Ollama {
                 "system": params.system,
                 "template": params.template,
                 "messages": messages,
-                "options": params.options,
+                "options": options,
                 "keep_alive": params.keep_alive,
-                "stream": false
-            }))
-            .send()
-            .await?
-            .json()
-            .await?;
-        if let Some(error) = res.error {
-            anyhow::bail!("{:?}", error.to_string())
-        } else if let Some(message) = res.message {
-            Ok(message.content)
-        } else {
-            anyhow::bail!(
-                "Unknown error while making request to Ollama: {:?}",
-                res.other
-            )
+                "stream": tx.is_some()
+            }));
+        let res = retry::send(request, &self.configuration.retry).await?;
+        match tx {
+            Some(tx) => read_stream(res, tx, OllamaChatResponse::into_response).await,
+            None => res.json::<OllamaChatResponse>().await?.into_response(),
         }
     }
 
+    // Streams the response to `tx` when it is set
     async fn do_chat_completion(
         &self,
         prompt: &Prompt,
         params: OllamaRunParams,
-    ) -> anyhow::Result<String> {
+        tx: Option<UnboundedSender<String>>,
+    ) -> anyhow::Result<DoGenerationResponse> {
         match prompt {
             Prompt::ContextAndCode(code_and_context) => match &params.messages {
                 Some(completion_messages) => {
                     let messages = format_chat_messages(completion_messages, code_and_context);
-                    self.get_chat(messages, params).await
+                    self.get_chat(messages, params, tx).await
                 }
                 None => {
                     self.get_completion(
                         &format_context_code(&code_and_context.context, &code_and_context.code),
                         params,
+                        tx,
                     )
                     .await
                 }
@@ -170,6 +249,7 @@ impl Ollama {
                             fim_params.end
                         ),
                         params,
+                        tx,
                     )
                     .await
                 }
@@ -181,25 +261,35 @@ impl Ollama {
 
 #[async_trait::async_trait]
 impl TransformerBackend for Ollama {
+    fn is_local(&self) -> bool {
+        true
+    }
+
     #[instrument(skip(self))]
     async fn do_generate(
         &self,
         prompt: &Prompt,
-
         params: Value,
+        _cancel: &CancellationToken,
     ) -> anyhow::Result<DoGenerationResponse> {
         let params: OllamaRunParams = serde_json::from_value(params)?;
-        let generated_text = self.do_chat_completion(prompt, params).await?;
-        Ok(DoGenerationResponse { generated_text })
+        self.do_chat_completion(prompt, params, None).await
     }
 
-    #[instrument(skip(self))]
+    #[instrument(skip(self, tx))]
     async fn do_generate_stream(
         &self,
-        request: &GenerationStreamRequest,
-        _params: Value,
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<String>,
+        _cancel: &CancellationToken,
     ) -> anyhow::Result<DoGenerationStreamResponse> {
-        anyhow::bail!("GenerationStream is not yet implemented")
+        let params: OllamaRunParams = serde_json::from_value(params)?;
+        let response = self.do_chat_completion(prompt, params, Some(tx)).await?;
+        Ok(DoGenerationStreamResponse {
+            generated_text: response.generated_text,
+            usage: response.usage,
+        })
     }
 }
 
@@ -220,7 +310,9 @@ mod test {
                 "num_predict": 4
             }
         });
-        let response = ollama.do_generate(&prompt, run_params).await?;
+        let response = ollama
+            .do_generate(&prompt, run_params, &CancellationToken::new())
+            .await?;
         assert!(!response.generated_text.is_empty());
         Ok(())
     }
@@ -247,8 +339,41 @@ mod test {
                 "num_predict": 4
             }
         });
-        let response = ollama.do_generate(&prompt, run_params).await?;
+        let response = ollama
+            .do_generate(&prompt, run_params, &CancellationToken::new())
+            .await?;
+        assert!(!response.generated_text.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ollama_chat_do_generate_stream() -> anyhow::Result<()> {
+        let configuration: config::Ollama = from_value(json!({
+            "model": "llama3",
+        }))?;
+        let ollama = Ollama::new(configuration);
+        let prompt = Prompt::default_with_cursor();
+        let run_params = json!({
+            "messages": [
+                {
+                    "role": "user",
+                    "content": "Test {CONTEXT} - {CODE}"
+                }
+            ],
+            "options": {
+                "num_predict": 4
+            }
+        });
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let response = ollama
+            .do_generate_stream(&prompt, run_params, tx, &CancellationToken::new())
+            .await?;
+        let mut streamed = String::new();
+        while let Some(chunk) = rx.recv().await {
+            streamed.push_str(&chunk);
+        }
         assert!(!response.generated_text.is_empty());
+        assert_eq!(streamed, response.generated_text);
         Ok(())
     }
 }
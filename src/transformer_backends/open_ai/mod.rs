@@ -1,20 +1,26 @@
 use std::collections::HashMap;
 
 use anyhow::Context;
+use reqwest::RequestBuilder;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 
 use crate::{
+    auth::get_auth_token,
     config::{self, ChatMessage, FIM},
+    http_client::get_client,
     memory_backends::Prompt,
+    tools::{ToolCall, ToolRequest, ToolTurn},
     transformer_worker::{
-        DoGenerationResponse, DoGenerationStreamResponse, GenerationStreamRequest,
+        CompletionCandidate, DoCompletionResponse, DoGenerationResponse, DoToolGenerationResponse,
     },
+    usage::TokenUsage,
     utils::{format_chat_messages, format_context_code},
 };
 
-use super::TransformerBackend;
+use super::{retry, TransformerBackend};
 
 const fn max_tokens_default() -> usize {
     64
@@ -36,6 +42,10 @@ const fn temperature_default() -> f32 {
     0.1
 }
 
+const fn n_default() -> usize {
+    1
+}
+
 // NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
 #[derive(Debug, Deserialize)]
 pub struct OpenAIRunParams {
@@ -51,40 +61,163 @@ pub struct OpenAIRunParams {
     pub frequency_penalty: f32,
     #[serde(default = "temperature_default")]
     pub temperature: f32,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    // How many completion candidates to request
+    #[serde(default = "n_default")]
+    pub n: usize,
+    // Sent as is, e.g. `{"type": "json_schema", "json_schema": {...}}` for structured outputs
+    pub response_format: Option<Value>,
+    // Scores the completions with the mean log probability of their tokens
+    #[serde(default)]
+    pub logprobs: bool,
+    // Guided decoding of vLLM, the output follows the JSON schema, regex, one of the choices or
+    // the grammar
+    pub guided_json: Option<Value>,
+    pub guided_regex: Option<String>,
+    pub guided_choice: Option<Vec<String>>,
+    pub guided_grammar: Option<String>,
+    // What to do with the reasoning of reasoning models like DeepSeek-R1
+    #[serde(default)]
+    pub reasoning: OpenAIReasoning,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub enum OpenAIReasoning {
+    // Only the answer is used
+    #[default]
+    #[serde(rename = "strip")]
+    Strip,
+    // The reasoning is put before the answer in `<think>` tags
+    #[serde(rename = "surface")]
+    Surface,
+}
+
+impl OpenAIRunParams {
+    // The parameters only vLLM takes
+    fn add_vllm_params(&self, body: &mut Value) {
+        if let Some(guided_json) = &self.guided_json {
+            body["guided_json"] = guided_json.clone();
+        }
+        if let Some(guided_regex) = &self.guided_regex {
+            body["guided_regex"] = json!(guided_regex);
+        }
+        if let Some(guided_choice) = &self.guided_choice {
+            body["guided_choice"] = json!(guided_choice);
+        }
+        if let Some(guided_grammar) = &self.guided_grammar {
+            body["guided_grammar"] = json!(guided_grammar);
+        }
+    }
 }
 
 pub struct OpenAI {
     configuration: config::OpenAI,
+    // The header the token is sent in. Uses bearer auth when None.
+    auth_header_name: Option<String>,
+    // Sends the parameters of vLLM and no auth when no token is set
+    vllm: bool,
+    local: bool,
+}
+
+#[derive(Deserialize)]
+struct OpenAICompletionsLogprobs {
+    // null for tokens of the prompt
+    token_logprobs: Vec<Option<f32>>,
 }
 
 #[derive(Deserialize)]
 struct OpenAICompletionsChoice {
     text: String,
+    logprobs: Option<OpenAICompletionsLogprobs>,
 }
 
 #[derive(Deserialize)]
 struct OpenAICompletionsResponse {
     choices: Option<Vec<OpenAICompletionsChoice>>,
+    usage: Option<TokenUsage>,
     error: Option<Value>,
     #[serde(default)]
     #[serde(flatten)]
     pub other: HashMap<String, Value>,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OpenAIFunctionCall {
+    pub name: String,
+    // JSON encoded
+    pub arguments: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OpenAIToolCall {
+    pub id: String,
+    pub function: OpenAIFunctionCall,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct OpenAIChatMessage {
     pub role: String,
-    pub content: String,
+    // null when the model only calls tools
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<OpenAIToolCall>,
+    // Sent separately from the answer by e.g. DeepSeek and xAI, OpenRouter calls it `reasoning`
+    #[serde(default, alias = "reasoning", skip_serializing)]
+    pub reasoning_content: Option<String>,
+}
+
+impl OpenAIChatMessage {
+    fn take_text(&mut self, reasoning: OpenAIReasoning) -> String {
+        let content = self.content.take().unwrap_or_default();
+        match (reasoning, self.reasoning_content.take()) {
+            (OpenAIReasoning::Strip, _) => strip_reasoning(&content).to_owned(),
+            (OpenAIReasoning::Surface, Some(thoughts)) if !thoughts.is_empty() => {
+                format!("<think>\n{thoughts}\n</think>\n\n{content}")
+            }
+            (OpenAIReasoning::Surface, _) => content,
+        }
+    }
+}
+
+// Models served without a reasoning parser put their reasoning in `<think>` tags before the
+// answer. Nothing is left when the reasoning was cut off by `max_tokens`.
+fn strip_reasoning(text: &str) -> &str {
+    let trimmed = text.trim_start();
+    match trimmed.strip_prefix("<think>") {
+        Some(rest) => rest
+            .find("</think>")
+            .map_or("", |end| rest[end + "</think>".len()..].trim_start()),
+        None => text,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OpenAITokenLogprob {
+    pub logprob: f32,
+}
+
+#[derive(Deserialize)]
+pub struct OpenAIChatLogprobs {
+    pub content: Option<Vec<OpenAITokenLogprob>>,
 }
 
 #[derive(Deserialize)]
 pub struct OpenAIChatChoices {
     pub message: OpenAIChatMessage,
+    pub logprobs: Option<OpenAIChatLogprobs>,
+}
+
+fn mean_logprob(logprobs: impl Iterator<Item = f32>) -> Option<f32> {
+    let (sum, count) = logprobs.fold((0., 0), |(sum, count), logprob| (sum + logprob, count + 1));
+    (count > 0).then(|| sum / count as f32)
 }
 
 #[derive(Deserialize)]
 pub struct OpenAIChatResponse {
     pub choices: Option<Vec<OpenAIChatChoices>>,
+    pub usage: Option<TokenUsage>,
     pub error: Option<Value>,
     #[serde(default)]
     #[serde(flatten)]
@@ -94,55 +227,201 @@ pub struct OpenAIChatResponse {
 impl OpenAI {
     #[instrument]
     pub fn new(configuration: config::OpenAI) -> Self {
-        Self { configuration }
+        Self {
+            configuration,
+            auth_header_name: None,
+            vllm: false,
+            local: false,
+        }
     }
 
-    fn get_token(&self) -> anyhow::Result<String> {
-        if let Some(env_var_name) = &self.configuration.auth_token_env_var_name {
-            Ok(std::env::var(env_var_name)?)
-        } else if let Some(token) = &self.configuration.auth_token {
-            Ok(token.to_string())
-        } else {
-            anyhow::bail!(
-                "set `auth_token_env_var_name` or `auth_token` to use an OpenAI compatible API"
-            )
+    // Azure routes requests by deployment and authenticates with an `api-key` header
+    #[instrument]
+    pub fn new_azure(configuration: config::AzureOpenAI) -> Self {
+        let base = format!(
+            "{}/openai/deployments/{}",
+            configuration.endpoint.trim_end_matches('/'),
+            configuration.deployment
+        );
+        Self {
+            configuration: config::OpenAI {
+                auth_token_env_var_name: configuration.auth_token_env_var_name,
+                auth_token: configuration.auth_token,
+                auth_token_keyring: configuration.auth_token_keyring,
+                auth_token_command: configuration.auth_token_command,
+                completions_endpoint: Some(format!(
+                    "{base}/completions?api-version={}",
+                    configuration.api_version
+                )),
+                chat_endpoint: Some(format!(
+                    "{base}/chat/completions?api-version={}",
+                    configuration.api_version
+                )),
+                max_requests_per_second: configuration.max_requests_per_second,
+                max_concurrent_requests: configuration.max_concurrent_requests,
+                request_timeout_ms: configuration.request_timeout_ms,
+                retry: configuration.retry,
+                model: configuration.deployment,
+            },
+            auth_header_name: Some("api-key".to_string()),
+            vllm: false,
+            local: false,
+        }
+    }
+
+    // Providers that speak the OpenAI API, configured by hand or from a preset
+    pub fn new_compatible(configuration: config::OpenAICompatible) -> anyhow::Result<Self> {
+        let base_url = configuration
+            .base_url
+            .as_deref()
+            .or(configuration.preset.map(|preset| preset.base_url()))
+            .context("set `base_url` or `preset` to use an OpenAI compatible API")?
+            .trim_end_matches('/')
+            .to_owned();
+        // A token set in the config takes precedence over the env var of the preset
+        let has_token = configuration.auth_token.is_some()
+            || configuration.auth_token_keyring.is_some()
+            || configuration.auth_token_command.is_some();
+        let auth_token_env_var_name = match (has_token, configuration.preset) {
+            (false, Some(preset)) => Some(
+                configuration
+                    .auth_token_env_var_name
+                    .unwrap_or_else(|| preset.auth_token_env_var_name().to_string()),
+            ),
+            _ => configuration.auth_token_env_var_name,
+        };
+        Ok(Self {
+            configuration: config::OpenAI {
+                auth_token_env_var_name,
+                auth_token: configuration.auth_token,
+                auth_token_keyring: configuration.auth_token_keyring,
+                auth_token_command: configuration.auth_token_command,
+                completions_endpoint: Some(format!("{base_url}/completions")),
+                chat_endpoint: Some(format!("{base_url}/chat/completions")),
+                max_requests_per_second: configuration.max_requests_per_second,
+                max_concurrent_requests: configuration.max_concurrent_requests,
+                request_timeout_ms: configuration.request_timeout_ms,
+                retry: configuration.retry,
+                model: configuration.model,
+            },
+            auth_header_name: configuration.auth_header_name,
+            vllm: false,
+            local: false,
+        })
+    }
+
+    #[instrument]
+    pub fn new_vllm(configuration: config::VLLM) -> Self {
+        let base_url = configuration
+            .base_url
+            .as_deref()
+            .unwrap_or("http://localhost:8000/v1")
+            .trim_end_matches('/');
+        Self {
+            configuration: config::OpenAI {
+                auth_token_env_var_name: configuration.auth_token_env_var_name,
+                auth_token: configuration.auth_token,
+                auth_token_keyring: configuration.auth_token_keyring,
+                auth_token_command: configuration.auth_token_command,
+                completions_endpoint: Some(format!("{base_url}/completions")),
+                chat_endpoint: Some(format!("{base_url}/chat/completions")),
+                max_requests_per_second: configuration.max_requests_per_second,
+                max_concurrent_requests: configuration.max_concurrent_requests,
+                request_timeout_ms: configuration.request_timeout_ms,
+                retry: configuration.retry,
+                model: configuration.model,
+            },
+            auth_header_name: None,
+            vllm: true,
+            local: configuration.local,
         }
     }
 
+    async fn authorize(&self, request: RequestBuilder) -> anyhow::Result<RequestBuilder> {
+        let token = match self.get_token().await? {
+            Some(token) => token,
+            // vLLM only checks tokens when started with `--api-key`
+            None if self.vllm => return Ok(request),
+            None => anyhow::bail!("set `auth_token_env_var_name`, `auth_token`, `auth_token_keyring` or `auth_token_command` to use an OpenAI compatible API"),
+        };
+        Ok(match &self.auth_header_name {
+            Some(header_name) => request.header(header_name.as_str(), token),
+            None => request.bearer_auth(token),
+        })
+    }
+
+    async fn get_token(&self) -> anyhow::Result<Option<String>> {
+        get_auth_token(
+            self.configuration.auth_token_env_var_name.as_deref(),
+            self.configuration.auth_token.as_deref(),
+            self.configuration.auth_token_keyring.as_ref(),
+            self.configuration.auth_token_command.as_deref(),
+        )
+        .await
+    }
+
     async fn get_completion(
         &self,
         prompt: &str,
         params: OpenAIRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let token = self.get_token()?;
-        let res: OpenAICompletionsResponse = client
-            .post(
-                self.configuration
-                    .completions_endpoint
-                    .as_ref()
-                    .context("specify `completions_endpoint` to use completions. Wanted to use `chat` instead? Please specify `chat_endpoint` and `messages`.")?,
-            )
-            .bearer_auth(token)
+    ) -> anyhow::Result<DoCompletionResponse> {
+        let client = get_client();
+        let request = client.post(
+            self.configuration
+                .completions_endpoint
+                .as_ref()
+                .context("specify `completions_endpoint` to use completions. Wanted to use `chat` instead? Please specify `chat_endpoint` and `messages`.")?,
+        );
+        let mut body = json!({
+            "model": self.configuration.model,
+            "max_tokens": params.max_tokens,
+            "n": params.n,
+            "top_p": params.top_p,
+            "presence_penalty": params.presence_penalty,
+            "frequency_penalty": params.frequency_penalty,
+            "temperature": params.temperature,
+            "echo": false,
+            "prompt": prompt
+        });
+        // Some OpenAI compatible APIs reject an empty list
+        if !params.stop.is_empty() {
+            body["stop"] = json!(params.stop);
+        }
+        if params.logprobs {
+            // How many of the most likely tokens to return with the sampled one
+            body["logprobs"] = json!(1);
+        }
+        if self.vllm {
+            params.add_vllm_params(&mut body);
+        }
+        let request = self
+            .authorize(request)
+            .await?
             .header("Content-Type", "application/json")
             .header("Accept", "application/json")
-            .json(&json!({
-                "model": self.configuration.model,
-                "max_tokens": params.max_tokens,
-                "n": 1,
-                "top_p": params.top_p,
-                "presence_penalty": params.presence_penalty,
-                "frequency_penalty": params.frequency_penalty,
-                "temperature": params.temperature,
-                "echo": false,
-                "prompt": prompt
-            }))
-            .send().await?
-            .json().await?;
+            .json(&body);
+        let res: OpenAICompletionsResponse = retry::send(request, &self.configuration.retry)
+            .await?
+            .json()
+            .await?;
         if let Some(error) = res.error {
             anyhow::bail!("{:?}", error.to_string())
-        } else if let Some(mut choices) = res.choices {
-            Ok(std::mem::take(&mut choices[0].text))
+        } else if let Some(choices) = res.choices {
+            Ok(DoCompletionResponse {
+                candidates: choices
+                    .into_iter()
+                    .map(|choice| CompletionCandidate {
+                        score: choice.logprobs.and_then(|logprobs| {
+                            mean_logprob(logprobs.token_logprobs.into_iter().flatten())
+                        }),
+                        insert_text: match params.reasoning {
+                            OpenAIReasoning::Strip => strip_reasoning(&choice.text).to_owned(),
+                            OpenAIReasoning::Surface => choice.text,
+                        },
+                    })
+                    .collect(),
+                usage: res.usage,
+            })
         } else {
             anyhow::bail!(
                 "Uknown error while making request to OpenAI: {:?}",
@@ -155,37 +434,95 @@ impl OpenAI {
         &self,
         messages: Vec<ChatMessage>,
         params: OpenAIRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let token = self.get_token()?;
-        let res: OpenAIChatResponse = client
-            .post(
-                self.configuration
-                    .chat_endpoint
-                    .as_ref()
-                    .context("must specify `completions_endpoint` to use completions")?,
-            )
-            .bearer_auth(token)
+    ) -> anyhow::Result<DoCompletionResponse> {
+        let messages = messages
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()?;
+        let (choices, usage) = self.send_chat(messages, &params, None).await?;
+        Ok(DoCompletionResponse {
+            candidates: choices
+                .into_iter()
+                .map(|mut choice| CompletionCandidate {
+                    score: choice
+                        .logprobs
+                        .and_then(|logprobs| logprobs.content)
+                        .and_then(|content| mean_logprob(content.into_iter().map(|t| t.logprob))),
+                    insert_text: choice.message.take_text(params.reasoning),
+                })
+                .collect(),
+            usage,
+        })
+    }
+
+    async fn send_chat(
+        &self,
+        messages: Vec<Value>,
+        params: &OpenAIRunParams,
+        tools: Option<&ToolRequest<'_>>,
+    ) -> anyhow::Result<(Vec<OpenAIChatChoices>, Option<TokenUsage>)> {
+        let mut body = json!({
+            "model": self.configuration.model,
+            "max_tokens": params.max_tokens,
+            "n": params.n,
+            "top_p": params.top_p,
+            "presence_penalty": params.presence_penalty,
+            "frequency_penalty": params.frequency_penalty,
+            "temperature": params.temperature,
+            "messages": messages
+        });
+        if !params.stop.is_empty() {
+            body["stop"] = json!(params.stop);
+        }
+        if let Some(response_format) = &params.response_format {
+            body["response_format"] = response_format.clone();
+        }
+        if params.logprobs {
+            body["logprobs"] = json!(true);
+        }
+        if self.vllm {
+            params.add_vllm_params(&mut body);
+        }
+        if let Some(tools) = tools {
+            body["tools"] = tools
+                .tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name,
+                            "description": tool.description,
+                            "parameters": tool.input_schema
+                        }
+                    })
+                })
+                .collect();
+            if tools.must_answer {
+                body["tool_choice"] = json!("none");
+            }
+        }
+        let client = get_client();
+        let request = client.post(
+            self.configuration
+                .chat_endpoint
+                .as_ref()
+                .context("must specify `chat_endpoint` to use chat")?,
+        );
+        let request = self
+            .authorize(request)
+            .await?
             .header("Content-Type", "application/json")
             .header("Accept", "application/json")
-            .json(&json!({
-                "model": self.configuration.model,
-                "max_tokens": params.max_tokens,
-                "n": 1,
-                "top_p": params.top_p,
-                "presence_penalty": params.presence_penalty,
-                "frequency_penalty": params.frequency_penalty,
-                "temperature": params.temperature,
-                "messages": messages
-            }))
-            .send()
+            .json(&body);
+        let res: OpenAIChatResponse = retry::send(request, &self.configuration.retry)
             .await?
             .json()
             .await?;
         if let Some(error) = res.error {
             anyhow::bail!("{:?}", error.to_string())
         } else if let Some(choices) = res.choices {
-            Ok(choices[0].message.content.clone())
+            Ok((choices, res.usage))
         } else {
             anyhow::bail!(
                 "Unknown error while making request to OpenAI: {:?}",
@@ -194,11 +531,12 @@ impl OpenAI {
         }
     }
 
+    // Returns a completion for each of the `n` choices
     async fn do_chat_completion(
         &self,
         prompt: &Prompt,
         params: OpenAIRunParams,
-    ) -> anyhow::Result<String> {
+    ) -> anyhow::Result<DoCompletionResponse> {
         match prompt {
             Prompt::ContextAndCode(code_and_context) => match &params.messages {
                 Some(completion_messages) => {
@@ -234,33 +572,128 @@ impl OpenAI {
     }
 }
 
+// Each response that called tools is followed by a message with the result of each call
+fn get_tool_messages(turns: &[ToolTurn]) -> Vec<Value> {
+    turns
+        .iter()
+        .flat_map(|turn| {
+            let tool_calls: Vec<Value> = turn
+                .calls
+                .iter()
+                .map(|call| {
+                    json!({
+                        "id": call.id,
+                        "type": "function",
+                        "function": {"name": call.name, "arguments": call.input.to_string()}
+                    })
+                })
+                .collect();
+            let content = (!turn.text.is_empty()).then_some(turn.text.as_str());
+            std::iter::once(
+                json!({"role": "assistant", "content": content, "tool_calls": tool_calls}),
+            )
+            .chain(turn.results.iter().map(|result| {
+                // The API has no error flag for results
+                let content = if result.is_error {
+                    format!("Error: {}", result.content)
+                } else {
+                    result.content.clone()
+                };
+                json!({"role": "tool", "tool_call_id": result.id, "content": content})
+            }))
+        })
+        .collect()
+}
+
 #[async_trait::async_trait]
 impl TransformerBackend for OpenAI {
+    fn is_local(&self) -> bool {
+        self.local
+    }
+
+    fn caches_prompt_prefixes(&self) -> bool {
+        self.vllm
+    }
+
     #[instrument(skip(self))]
-    async fn do_generate(
+    async fn do_completion(
         &self,
         prompt: &Prompt,
-
         params: Value,
-    ) -> anyhow::Result<DoGenerationResponse> {
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoCompletionResponse> {
         let params: OpenAIRunParams = serde_json::from_value(params)?;
-        let generated_text = self.do_chat_completion(prompt, params).await?;
-        Ok(DoGenerationResponse { generated_text })
+        self.do_chat_completion(prompt, params).await
     }
 
     #[instrument(skip(self))]
-    async fn do_generate_stream(
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let mut params: OpenAIRunParams = serde_json::from_value(params)?;
+        params.n = 1;
+        let response = self.do_chat_completion(prompt, params).await?;
+        Ok(DoGenerationResponse {
+            generated_text: response
+                .candidates
+                .into_iter()
+                .next()
+                .map(|candidate| candidate.insert_text)
+                .unwrap_or_default(),
+            usage: response.usage,
+        })
+    }
+
+    #[instrument(skip(self, tools))]
+    async fn do_generate_with_tools(
         &self,
-        request: &GenerationStreamRequest,
-        _params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse> {
-        anyhow::bail!("GenerationStream is not yet implemented")
+        prompt: &Prompt,
+        params: Value,
+        tools: &ToolRequest<'_>,
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoToolGenerationResponse> {
+        let mut params: OpenAIRunParams = serde_json::from_value(params)?;
+        params.n = 1;
+        let (Prompt::ContextAndCode(code_and_context), Some(messages)) = (prompt, &params.messages)
+        else {
+            anyhow::bail!("tools need a chat prompt with `messages`")
+        };
+        let mut messages: Vec<Value> = format_chat_messages(messages, code_and_context)
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()?;
+        messages.extend(get_tool_messages(tools.turns));
+        let (choices, usage) = self.send_chat(messages, &params, Some(tools)).await?;
+        let mut message = choices
+            .into_iter()
+            .next()
+            .context("no choices returned by OpenAI")?
+            .message;
+        Ok(DoToolGenerationResponse {
+            generated_text: message.take_text(params.reasoning),
+            calls: message
+                .tool_calls
+                .into_iter()
+                .map(|call| ToolCall {
+                    id: call.id,
+                    name: call.function.name,
+                    // Invalid arguments fail the call and the model is told why
+                    input: serde_json::from_str(&call.function.arguments)
+                        .unwrap_or(Value::String(call.function.arguments)),
+                })
+                .collect(),
+            usage,
+        })
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::tools::ToolResult;
     use serde_json::{from_value, json};
 
     #[tokio::test]
@@ -275,7 +708,9 @@ mod test {
         let run_params = json!({
             "max_tokens": 64
         });
-        let response = open_ai.do_generate(&prompt, run_params).await?;
+        let response = open_ai
+            .do_generate(&prompt, run_params, &CancellationToken::new())
+            .await?;
         assert!(!response.generated_text.is_empty());
         Ok(())
     }
@@ -302,8 +737,153 @@ mod test {
             ],
             "max_tokens": 64
         });
-        let response = open_ai.do_generate(&prompt, run_params).await?;
+        let response = open_ai
+            .do_generate(&prompt, run_params, &CancellationToken::new())
+            .await?;
         assert!(!response.generated_text.is_empty());
         Ok(())
     }
+
+    #[test]
+    fn open_ai_scores_choices_with_logprobs() -> anyhow::Result<()> {
+        let res: OpenAIChatResponse = from_value(json!({
+            "choices": [{
+                "message": {"role": "assistant", "content": "x"},
+                "logprobs": {"content": [
+                    {"token": "x", "logprob": -0.5},
+                    {"token": ";", "logprob": -1.5}
+                ]}
+            }]
+        }))?;
+        let logprobs = res
+            .choices
+            .unwrap()
+            .remove(0)
+            .logprobs
+            .unwrap()
+            .content
+            .unwrap();
+        assert_eq!(
+            mean_logprob(logprobs.into_iter().map(|t| t.logprob)),
+            Some(-1.)
+        );
+        assert_eq!(mean_logprob(std::iter::empty()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn open_ai_formats_tool_turns() {
+        let turns = vec![ToolTurn {
+            text: "Let me look".to_string(),
+            calls: vec![ToolCall {
+                id: "call_1".to_string(),
+                name: "read_file".to_string(),
+                input: json!({"path": "src/main.rs"}),
+            }],
+            results: vec![ToolResult {
+                id: "call_1".to_string(),
+                content: "not found".to_string(),
+                is_error: true,
+            }],
+        }];
+        assert_eq!(
+            get_tool_messages(&turns),
+            vec![
+                json!({"role": "assistant", "content": "Let me look", "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "read_file", "arguments": "{\"path\":\"src/main.rs\"}"}
+                }]}),
+                json!({"role": "tool", "tool_call_id": "call_1", "content": "Error: not found"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn open_ai_compatible_presets() -> anyhow::Result<()> {
+        let configuration: config::OpenAICompatible = from_value(json!({
+            "preset": "together",
+            "model": "codellama/CodeLlama-7b-hf",
+        }))?;
+        let open_ai = OpenAI::new_compatible(configuration)?;
+        assert_eq!(
+            open_ai.configuration.chat_endpoint.as_deref(),
+            Some("https://api.together.xyz/v1/chat/completions")
+        );
+        assert_eq!(
+            open_ai.configuration.auth_token_env_var_name.as_deref(),
+            Some("TOGETHER_API_KEY")
+        );
+
+        let configuration: config::OpenAICompatible = from_value(json!({
+            "model": "local",
+        }))?;
+        assert!(OpenAI::new_compatible(configuration).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_ai_vllm_params() -> anyhow::Result<()> {
+        let configuration: config::VLLM = from_value(json!({
+            "model": "Qwen/Qwen2.5-Coder-7B",
+        }))?;
+        let vllm = OpenAI::new_vllm(configuration);
+        assert_eq!(
+            vllm.configuration.completions_endpoint.as_deref(),
+            Some("http://localhost:8000/v1/completions")
+        );
+        assert!(vllm.caches_prompt_prefixes());
+        // No auth without a token
+        assert!(vllm
+            .authorize(get_client().post("http://localhost"))
+            .await
+            .is_ok());
+
+        let params: OpenAIRunParams = from_value(json!({
+            "guided_regex": "[0-9]+",
+            "guided_choice": ["yes", "no"],
+        }))?;
+        let mut body = json!({"model": "Qwen/Qwen2.5-Coder-7B"});
+        params.add_vllm_params(&mut body);
+        assert_eq!(
+            body,
+            json!({
+                "model": "Qwen/Qwen2.5-Coder-7B",
+                "guided_regex": "[0-9]+",
+                "guided_choice": ["yes", "no"]
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn open_ai_strips_reasoning() -> anyhow::Result<()> {
+        let message = json!({
+            "role": "assistant",
+            "content": "x + 1",
+            "reasoning_content": "The user wants an increment"
+        });
+        let mut strip: OpenAIChatMessage = from_value(message.clone())?;
+        assert_eq!(strip.take_text(OpenAIReasoning::Strip), "x + 1");
+        let mut surface: OpenAIChatMessage = from_value(message)?;
+        assert_eq!(
+            surface.take_text(OpenAIReasoning::Surface),
+            "<think>\nThe user wants an increment\n</think>\n\nx + 1"
+        );
+
+        assert_eq!(strip_reasoning("<think>\nhmm\n</think>\n\nx + 1"), "x + 1");
+        assert_eq!(strip_reasoning("<think>\nhmm, the cursor"), "");
+        assert_eq!(strip_reasoning("  x + 1"), "  x + 1");
+
+        let configuration: config::OpenAICompatible = from_value(json!({
+            "preset": "deepseek",
+            "model": "deepseek-reasoner",
+        }))?;
+        let open_ai = OpenAI::new_compatible(configuration)?;
+        assert_eq!(
+            open_ai.configuration.chat_endpoint.as_deref(),
+            Some("https://api.deepseek.com/v1/chat/completions")
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,245 @@
+use std::{
+    io::BufReader,
+    process::{Child, ChildStdin, ChildStdout},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use parking_lot::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+// How often a request waiting on the program checks whether it was cancelled
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// A program answering requests over its stdin and stdout, one request at a time
+pub trait Program: Send + Sync + 'static {
+    type Request: Send + 'static;
+    type Response: Send + 'static;
+
+    // Used in the logs and errors, e.g. `the llama.cpp worker`
+    fn name(&self) -> String;
+
+    fn command(&self) -> anyhow::Result<std::process::Command>;
+
+    // Runs once the program started and before it gets requests
+    fn handshake(
+        &self,
+        _child: &mut Child,
+        _stdin: &mut ChildStdin,
+        _stdout: &mut BufReader<ChildStdout>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn write(&self, stdin: &mut ChildStdin, request: &Self::Request) -> anyhow::Result<()>;
+
+    // None once the program closed its stdout
+    fn read(&self, stdout: &mut BufReader<ChildStdout>) -> anyhow::Result<Option<Self::Response>>;
+
+    // Sent when the request running is cancelled
+    fn cancel(&self) -> Self::Request;
+
+    // The text of a streamed token, the final response is given back
+    fn get_token(&self, response: Self::Response) -> Result<String, Self::Response>;
+
+    // Programs that take long to start are started again as soon as they exit, the others with
+    // the next request
+    fn restarts_right_away(&self) -> bool {
+        false
+    }
+}
+
+// Kills the program if it still runs
+pub fn get_exit_status(child: &mut Child) -> String {
+    let _ = child.kill();
+    match child.wait() {
+        Ok(status) => status.to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+struct Process<P: Program> {
+    child: Child,
+    stdin: ChildStdin,
+    // Closed when the program exits
+    responses: Receiver<P::Response>,
+}
+
+impl<P: Program> Process<P> {
+    fn spawn(program: &Arc<P>) -> anyhow::Result<Self> {
+        let mut child = program
+            .command()?
+            .spawn()
+            .with_context(|| format!("error starting {}", program.name()))?;
+        let (stdin, mut stdout) = match (child.stdin.take(), child.stdout.take()) {
+            (Some(stdin), Some(stdout)) => (stdin, BufReader::new(stdout)),
+            _ => {
+                get_exit_status(&mut child);
+                anyhow::bail!("{} has no stdin or stdout", program.name())
+            }
+        };
+        let (tx, responses) = crossbeam_channel::unbounded();
+        // Dropping the process kills the child when the handshake fails
+        let mut process = Self {
+            child,
+            stdin,
+            responses,
+        };
+        program.handshake(&mut process.child, &mut process.stdin, &mut stdout)?;
+        let reader_program = program.clone();
+        thread::spawn(move || {
+            while let Ok(Some(response)) = reader_program.read(&mut stdout) {
+                if tx.send(response).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(process)
+    }
+
+    // An error means the program is gone, errors of the request itself are in the response
+    fn exchange(
+        &mut self,
+        program: &P,
+        request: &P::Request,
+        on_token: &mut impl FnMut(String),
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<P::Response> {
+        program.write(&mut self.stdin, request)?;
+        let mut cancel_sent = false;
+        loop {
+            match self.responses.recv_timeout(CANCEL_POLL_INTERVAL) {
+                Ok(response) => match program.get_token(response) {
+                    Ok(text) => on_token(text),
+                    Err(response) => return Ok(response),
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    if cancel.is_cancelled() && !cancel_sent {
+                        program.write(&mut self.stdin, &program.cancel())?;
+                        cancel_sent = true;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => anyhow::bail!("{} exited", program.name()),
+            }
+        }
+    }
+}
+
+impl<P: Program> Drop for Process<P> {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// None until it is first started, while restarting or after starting it failed. Requests then try
+// to start it.
+type Running<P> = Arc<Mutex<Option<Process<P>>>>;
+
+// Starts the program, passes the requests to it and starts it again after it exits. Waiting on
+// the program blocks, so requests run on the blocking threads of the runtime.
+pub struct Supervisor<P: Program> {
+    program: Arc<P>,
+    process: Running<P>,
+}
+
+impl<P: Program> Supervisor<P> {
+    pub fn new(program: P) -> Self {
+        Self {
+            program: Arc::new(program),
+            process: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Starts the program now instead of with the first request
+    #[cfg(feature = "llama_cpp")]
+    pub fn start(&self) -> anyhow::Result<()> {
+        let mut process = self.process.lock();
+        if process.is_none() {
+            *process = Some(Process::spawn(&self.program)?);
+        }
+        Ok(())
+    }
+
+    pub async fn request(
+        &self,
+        request: P::Request,
+        on_token: impl FnMut(String) + Send + 'static,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<P::Response> {
+        let program = self.program.clone();
+        let process = self.process.clone();
+        let cancel = cancel.clone();
+        tokio::task::spawn_blocking(move || {
+            exchange(&program, &process, &request, on_token, &cancel)
+        })
+        .await?
+    }
+
+    // For the callers that can not wait asynchronously
+    #[cfg(feature = "llama_cpp")]
+    pub fn request_blocking(
+        &self,
+        request: P::Request,
+        on_token: impl FnMut(String),
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<P::Response> {
+        exchange(&self.program, &self.process, &request, on_token, cancel)
+    }
+}
+
+fn exchange<P: Program>(
+    program: &Arc<P>,
+    process: &Running<P>,
+    request: &P::Request,
+    mut on_token: impl FnMut(String),
+    cancel: &CancellationToken,
+) -> anyhow::Result<P::Response> {
+    let mut running = process.lock();
+    if running.is_none() {
+        *running = Some(Process::spawn(program)?);
+    }
+    let result = running
+        .as_mut()
+        .with_context(|| format!("{} is not running", program.name()))?
+        .exchange(program, request, &mut on_token, cancel);
+    let Err(e) = result else {
+        return result;
+    };
+    let status = running
+        .take()
+        .map(|mut exited| get_exit_status(&mut exited.child))
+        .unwrap_or_default();
+    drop(running);
+    if program.restarts_right_away() {
+        error!("{} exited ({status}), restarting it", program.name());
+        restart(program.clone(), process.clone());
+    } else {
+        error!(
+            "{} exited ({status}), it is started again with the next request",
+            program.name()
+        );
+    }
+    Err(e.context(format!("error running {}", program.name())))
+}
+
+// Started on its own thread so the request that saw the program exit fails right away
+fn restart<P: Program>(program: Arc<P>, process: Running<P>) {
+    thread::spawn(move || {
+        let mut process = process.lock();
+        if process.is_some() {
+            return;
+        }
+        match Process::spawn(&program) {
+            Ok(restarted) => {
+                info!("restarted {}", program.name());
+                *process = Some(restarted);
+            }
+            Err(e) => error!("error restarting {}: {e:?}", program.name()),
+        }
+    });
+}
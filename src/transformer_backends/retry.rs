@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+use tracing::warn;
+
+use crate::config::Retry;
+
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Only the delay in seconds form, the APIs we talk to do not send dates
+fn get_retry_after(response: &Response) -> Option<Duration> {
+    let secs: f64 = response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+// Exponential backoff with equal jitter so retries of concurrent requests spread out
+fn get_backoff(retry: &Retry, attempt: u32) -> Duration {
+    let max = retry
+        .backoff_base_ms
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(retry.max_backoff_ms);
+    Duration::from_millis(max / 2 + rand::thread_rng().gen_range(0..=max - max / 2))
+}
+
+// Sends the request, retrying transient failures. The response of the last attempt is returned
+// whatever its status so the backend can report the error the API sent.
+pub async fn send(request: RequestBuilder, retry: &Retry) -> anyhow::Result<Response> {
+    let max_backoff = Duration::from_millis(retry.max_backoff_ms);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        // Requests with streamed bodies cannot be cloned and are only sent once
+        let Some(attempt_request) = request.try_clone().filter(|_| attempt < retry.max_attempts)
+        else {
+            return Ok(request.send().await?);
+        };
+        let delay = match attempt_request.send().await {
+            Ok(response) if !is_transient(response.status()) => return Ok(response),
+            Ok(response) => {
+                let delay = get_retry_after(&response)
+                    .map(|delay| delay.min(max_backoff))
+                    .unwrap_or_else(|| get_backoff(retry, attempt));
+                warn!(
+                    "request failed with {}, retrying in {delay:?}",
+                    response.status()
+                );
+                delay
+            }
+            Err(e) if e.is_connect() || e.is_timeout() => {
+                let delay = get_backoff(retry, attempt);
+                warn!("request failed: {e}, retrying in {delay:?}");
+                delay
+            }
+            Err(e) => return Err(e.into()),
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let retry = Retry {
+            max_attempts: 5,
+            backoff_base_ms: 100,
+            max_backoff_ms: 300,
+        };
+        for _ in 0..20 {
+            let first = get_backoff(&retry, 1);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let second = get_backoff(&retry, 2);
+            assert!(second >= Duration::from_millis(100) && second <= Duration::from_millis(200));
+            let capped = get_backoff(&retry, 10);
+            assert!(capped >= Duration::from_millis(150) && capped <= Duration::from_millis(300));
+        }
+        assert!(is_transient(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_transient(StatusCode::BAD_GATEWAY));
+        assert!(!is_transient(StatusCode::UNAUTHORIZED));
+    }
+}
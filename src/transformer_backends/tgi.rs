@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use crate::{
+    auth::get_auth_token,
+    config::{self, ChatMessage, FIM},
+    http_client::get_client,
+    memory_backends::Prompt,
+    transformer_worker::{
+        CompletionCandidate, DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse,
+    },
+    utils::format_context_code,
+};
+
+use super::{retry, TransformerBackend};
+
+const fn max_new_tokens_default() -> usize {
+    64
+}
+
+// NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
+// The other fields are sent as the `parameters` of the request, with the names TGI uses
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TGIRunParams {
+    #[serde(skip_serializing)]
+    pub fim: Option<FIM>,
+    #[serde(skip_serializing)]
+    messages: Option<Vec<ChatMessage>>,
+    #[serde(default = "max_new_tokens_default")]
+    pub max_new_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typical_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub do_sample: Option<bool>,
+    // Samples this many sequences and returns the most likely, the others become completion
+    // candidates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    // Keeps this many tokens from the end of the prompt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncate: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<bool>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    // Sent as is, e.g. `{"type": "json", "value": {...}}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grammar: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TGIToken {
+    text: String,
+    // Not sent for tokens of the prompt
+    logprob: Option<f32>,
+    #[serde(default)]
+    special: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct TGISequence {
+    generated_text: String,
+    #[serde(default)]
+    tokens: Vec<TGIToken>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TGIDetails {
+    #[serde(default)]
+    tokens: Vec<TGIToken>,
+    // The sequences other than the returned one when `best_of` is more than one
+    #[serde(default)]
+    best_of_sequences: Vec<TGISequence>,
+}
+
+#[derive(Deserialize)]
+struct TGIResponse {
+    generated_text: Option<String>,
+    details: Option<TGIDetails>,
+    error: Option<String>,
+    #[serde(default)]
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+// One server sent event of /generate_stream, the last one also has the full text
+#[derive(Deserialize)]
+struct TGIStreamResponse {
+    token: Option<TGIToken>,
+    error: Option<String>,
+}
+
+// The mean log probability of the generated tokens
+fn get_score(tokens: &[TGIToken]) -> Option<f32> {
+    let logprobs: Vec<f32> = tokens.iter().filter_map(|token| token.logprob).collect();
+    (!logprobs.is_empty()).then(|| logprobs.iter().sum::<f32>() / logprobs.len() as f32)
+}
+
+impl TGIResponse {
+    fn into_candidates(self) -> anyhow::Result<Vec<CompletionCandidate>> {
+        if let Some(error) = self.error {
+            anyhow::bail!("{error}")
+        }
+        let Some(generated_text) = self.generated_text else {
+            anyhow::bail!(
+                "Unknown error while making request to TGI: {:?}",
+                self.other
+            )
+        };
+        let (tokens, best_of_sequences) = self
+            .details
+            .map(|details| (details.tokens, details.best_of_sequences))
+            .unwrap_or_default();
+        Ok(std::iter::once(CompletionCandidate {
+            insert_text: generated_text,
+            score: get_score(&tokens),
+        })
+        .chain(
+            best_of_sequences
+                .into_iter()
+                .map(|sequence| CompletionCandidate {
+                    score: get_score(&sequence.tokens),
+                    insert_text: sequence.generated_text,
+                }),
+        )
+        .collect())
+    }
+}
+
+pub struct TGI {
+    configuration: config::TGI,
+}
+
+impl TGI {
+    #[instrument]
+    pub fn new(configuration: config::TGI) -> Self {
+        Self { configuration }
+    }
+
+    fn get_url(&self, route: &str) -> String {
+        format!(
+            "{}/{route}",
+            self.configuration
+                .endpoint
+                .as_deref()
+                .unwrap_or("http://localhost:8080")
+                .trim_end_matches('/')
+        )
+    }
+
+    async fn authorize(&self, request: RequestBuilder) -> anyhow::Result<RequestBuilder> {
+        let token = get_auth_token(
+            self.configuration.auth_token_env_var_name.as_deref(),
+            self.configuration.auth_token.as_deref(),
+            self.configuration.auth_token_keyring.as_ref(),
+            self.configuration.auth_token_command.as_deref(),
+        )
+        .await?;
+        Ok(match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        })
+    }
+
+    // TGI only takes a prompt, chat models are served by its OpenAI compatible chat endpoint
+    fn get_inputs(prompt: &Prompt, params: &TGIRunParams) -> anyhow::Result<String> {
+        if params.messages.is_some() {
+            anyhow::bail!("TGI does not take `messages`, use an `open_ai_compatible` model with the `/v1` endpoint of the server to chat")
+        }
+        match prompt {
+            Prompt::ContextAndCode(code_and_context) => Ok(format_context_code(
+                &code_and_context.context,
+                &code_and_context.code,
+            )),
+            Prompt::FIM(fim) => match &params.fim {
+                Some(fim_params) => Ok(format!(
+                    "{}{}{}{}{}",
+                    fim_params.start, fim.prompt, fim_params.middle, fim.suffix, fim_params.end
+                )),
+                None => anyhow::bail!("Prompt type is FIM but no FIM parameters provided"),
+            },
+        }
+    }
+
+    fn get_body(inputs: String, mut params: TGIRunParams, stream: bool) -> anyhow::Result<Value> {
+        if stream {
+            // Only one sequence can be streamed
+            params.best_of = None;
+        } else if params.best_of.is_some_and(|best_of| best_of > 1) {
+            // TGI rejects `best_of` without sampling
+            params.do_sample.get_or_insert(true);
+        }
+        let mut parameters = serde_json::to_value(&params)?;
+        parameters["details"] = json!(true);
+        parameters["return_full_text"] = json!(false);
+        Ok(json!({
+            "inputs": inputs,
+            "parameters": parameters,
+            "stream": stream
+        }))
+    }
+
+    async fn get_candidates(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+    ) -> anyhow::Result<Vec<CompletionCandidate>> {
+        let params: TGIRunParams = serde_json::from_value(params)?;
+        let inputs = Self::get_inputs(prompt, &params)?;
+        let request = get_client()
+            .post(self.get_url("generate"))
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .json(&Self::get_body(inputs, params, false)?);
+        retry::send(self.authorize(request).await?, &self.configuration.retry)
+            .await?
+            .json::<TGIResponse>()
+            .await?
+            .into_candidates()
+    }
+}
+
+#[async_trait::async_trait]
+impl TransformerBackend for TGI {
+    fn is_local(&self) -> bool {
+        self.configuration.local
+    }
+
+    #[instrument(skip(self))]
+    async fn do_completion(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoCompletionResponse> {
+        Ok(DoCompletionResponse {
+            candidates: self.get_candidates(prompt, params).await?,
+            usage: None,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let candidates = self.get_candidates(prompt, params).await?;
+        Ok(DoGenerationResponse {
+            generated_text: candidates
+                .into_iter()
+                .next()
+                .map(|candidate| candidate.insert_text)
+                .unwrap_or_default(),
+            usage: None,
+        })
+    }
+
+    #[instrument(skip(self, tx))]
+    async fn do_generate_stream(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<String>,
+        _cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        let params: TGIRunParams = serde_json::from_value(params)?;
+        let inputs = Self::get_inputs(prompt, &params)?;
+        let request = get_client()
+            .post(self.get_url("generate_stream"))
+            .header("Content-Type", "application/json")
+            .header("Accept", "text/event-stream")
+            .json(&Self::get_body(inputs, params, true)?);
+        let mut res =
+            retry::send(self.authorize(request).await?, &self.configuration.retry).await?;
+        // Requests TGI rejects are answered with a JSON error instead of a stream
+        let status = res.status();
+        if !status.is_success() {
+            let error = res.json::<TGIResponse>().await?.error.unwrap_or_default();
+            anyhow::bail!("TGI responded with {status}: {error}")
+        }
+
+        // The response is a stream of server sent events, each holding the next token
+        // Chunks can end in the middle of a char, only whole lines are decoded
+        let mut buffer = vec![];
+        let mut generated_text = String::new();
+        while let Some(chunk) = res.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(index) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=index).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    continue;
+                };
+                let res: TGIStreamResponse = serde_json::from_str(data.trim())?;
+                if let Some(error) = res.error {
+                    anyhow::bail!("{error}")
+                }
+                let Some(token) = res.token.filter(|token| !token.special) else {
+                    continue;
+                };
+                generated_text.push_str(&token.text);
+                tx.send(token.text)
+                    .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
+            }
+        }
+        Ok(DoGenerationStreamResponse {
+            generated_text,
+            usage: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::{from_value, json};
+
+    #[test]
+    fn tgi_best_of_candidates() -> anyhow::Result<()> {
+        let params: TGIRunParams = from_value(json!({
+            "best_of": 2,
+            "top_k": 10,
+            "stop": ["\n\n"],
+            "line_mode": "multi"
+        }))?;
+        let body = TGI::get_body("def ".to_string(), params, false)?;
+        assert_eq!(
+            body,
+            json!({
+                "inputs": "def ",
+                "parameters": {
+                    "max_new_tokens": 64,
+                    "top_k": 10,
+                    "do_sample": true,
+                    "best_of": 2,
+                    "stop": ["\n\n"],
+                    "details": true,
+                    "return_full_text": false
+                },
+                "stream": false
+            })
+        );
+
+        let response: TGIResponse = from_value(json!({
+            "generated_text": "hello()",
+            "details": {
+                "finish_reason": "eos_token",
+                "generated_tokens": 2,
+                "tokens": [
+                    {"id": 1, "text": "hello", "logprob": -0.5, "special": false},
+                    {"id": 2, "text": "()", "logprob": -1.5, "special": false}
+                ],
+                "best_of_sequences": [{
+                    "generated_text": "world",
+                    "finish_reason": "length",
+                    "generated_tokens": 1,
+                    "tokens": [{"id": 3, "text": "world", "logprob": -3.0, "special": false}]
+                }]
+            }
+        }))?;
+        let candidates = response.into_candidates()?;
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].insert_text, "hello()");
+        assert_eq!(candidates[0].score, Some(-1.));
+        assert_eq!(candidates[1].score, Some(-3.));
+
+        let error: TGIResponse = from_value(json!({
+            "error": "Input validation error: `best_of` must be > 0",
+            "error_type": "validation"
+        }))?;
+        assert!(error.into_candidates().is_err());
+        Ok(())
+    }
+}
@@ -1,33 +1,99 @@
 use anyhow::Context;
-use lsp_server::{Connection, Message, RequestId, Response};
+use lsp_server::{Connection, ErrorCode, Message, Notification, RequestId, Response};
 use lsp_types::{
     CompletionItem, CompletionItemKind, CompletionList, CompletionParams, CompletionResponse,
-    Position, Range, TextEdit,
+    ExecuteCommandParams, Position, Range, TextDocumentPositionParams, TextEdit,
 };
-use std::collections::HashMap;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::oneshot;
-use tracing::{error, instrument};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, instrument, warn};
 
-use crate::config::{self, Config};
+use crate::agent::{self, Budget};
+use crate::code_actions::{
+    build_action_result, insert_diagnostics, send_action_result, ActionResult, RunActionArguments,
+    RUN_ACTION_COMMAND,
+};
+use crate::commit_message::{
+    build_edit, build_request, format_commit_message, GenerateCommitMessageArguments,
+    GENERATE_COMMIT_MESSAGE_COMMAND,
+};
+use crate::completion_cache::{CacheKey, CompletionCache};
+use crate::completion_rules;
+use crate::config::{
+    self, ActionOutput, AgentConfig, ChatMessage, ConfidenceFilter, Config, CursorRegion, Kwargs,
+    LowConfidenceAction, PostProcess, RequestKind, Route,
+};
+use crate::conversations::Conversations;
+use crate::custom_requests::agent::{AgentParams, AgentResult};
+use crate::custom_requests::chat::{ChatParams, ChatResult, ChatStream, ChatStreamParams};
+use crate::custom_requests::commit_message::{
+    GenerateCommitMessageParams, GenerateCommitMessageResult,
+};
+use crate::custom_requests::completion_feedback::CompletionFeedbackParams;
 use crate::custom_requests::generation::{GenerateResult, GenerationParams};
-use crate::custom_requests::generation_stream::GenerationStreamParams;
-use crate::memory_backends::Prompt;
-use crate::memory_worker::{self, FilterRequest, PromptRequest};
-use crate::transformer_backends::TransformerBackend;
-use crate::utils::ToResponseError;
+use crate::custom_requests::generation_stream::{GenerationStreamParams, GenerationStreamResult};
+use crate::custom_requests::inline_completion::{
+    InlineCompletionItem, InlineCompletionList, InlineCompletionParams, SelectedCompletionInfo,
+};
+use crate::custom_requests::preview_prompt::{
+    PreviewKind, PreviewPromptParams, PreviewPromptResult,
+};
+use crate::custom_requests::raw_generation::{RawGenerationParams, RawGenerationResult};
+use crate::custom_requests::ready::{Ready, ReadyParams};
+use crate::custom_requests::search::WorkspaceSearchParams;
+use crate::git;
+use crate::memory_backends::{ContextAndCodePrompt, FIMPrompt, Prompt, PromptType};
+use crate::memory_worker::{
+    self, FilterRequest, LanguageIdRequest, PromptRequest, SearchRequest, TextRequest,
+};
+use crate::metrics;
+use crate::plugins;
+use crate::post_process::post_process_response;
+use crate::progress;
+use crate::prompt_files::resolve_prompt_files;
+use crate::redaction::redact_prompt;
+use crate::splitters::get_region;
+use crate::structured_output::parse_structured_output;
+use crate::tools::{self, ToolCall, ToolContext, ToolParams, APPLY_EDIT};
+use crate::transformer_backends::{self, SharedBackend, TransformerBackend};
+use crate::udiff::insert_diff_instructions;
+use crate::usage::{log_usage, TokenUsage, TrackedBackend};
+use crate::utils::{
+    position_to_offset, truncate_at_stop_sequence, StopSequenceFilter, ToResponseError,
+};
+use crate::workspace_edit::{build_diff, build_workspace_edit};
 
 #[derive(Clone, Debug)]
 pub struct CompletionRequest {
     id: RequestId,
     params: CompletionParams,
+    cache: Arc<CompletionCache>,
 }
 
 impl CompletionRequest {
-    pub fn new(id: RequestId, params: CompletionParams) -> Self {
-        Self { id, params }
+    pub fn new(id: RequestId, params: CompletionParams, cache: Arc<CompletionCache>) -> Self {
+        Self { id, params, cache }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InlineCompletionRequest {
+    id: RequestId,
+    params: InlineCompletionParams,
+    cache: Arc<CompletionCache>,
+}
+
+impl InlineCompletionRequest {
+    pub fn new(id: RequestId, params: InlineCompletionParams, cache: Arc<CompletionCache>) -> Self {
+        Self { id, params, cache }
     }
 }
 
@@ -43,8 +109,6 @@ impl GenerationRequest {
     }
 }
 
-// The generate stream is not yet ready but we don't want to remove it
-#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct GenerationStreamRequest {
     id: RequestId,
@@ -57,177 +121,643 @@ impl GenerationStreamRequest {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct ChatRequest {
+    id: RequestId,
+    params: ChatParams,
+    conversations: Arc<Conversations>,
+}
+
+impl ChatRequest {
+    pub fn new(id: RequestId, params: ChatParams, conversations: Arc<Conversations>) -> Self {
+        Self {
+            id,
+            params,
+            conversations,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ExecuteCommandRequest {
+    id: RequestId,
+    params: ExecuteCommandParams,
+}
+
+impl ExecuteCommandRequest {
+    pub fn new(id: RequestId, params: ExecuteCommandParams) -> Self {
+        Self { id, params }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CommitMessageRequest {
+    id: RequestId,
+    params: GenerateCommitMessageParams,
+}
+
+impl CommitMessageRequest {
+    pub fn new(id: RequestId, params: GenerateCommitMessageParams) -> Self {
+        Self { id, params }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PreviewPromptRequest {
+    id: RequestId,
+    params: PreviewPromptParams,
+}
+
+impl PreviewPromptRequest {
+    pub fn new(id: RequestId, params: PreviewPromptParams) -> Self {
+        Self { id, params }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RawGenerationRequest {
+    id: RequestId,
+    params: RawGenerationParams,
+}
+
+impl RawGenerationRequest {
+    pub fn new(id: RequestId, params: RawGenerationParams) -> Self {
+        Self { id, params }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AgentRequest {
+    id: RequestId,
+    params: AgentParams,
+}
+
+impl AgentRequest {
+    pub fn new(id: RequestId, params: AgentParams) -> Self {
+        Self { id, params }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum WorkerRequest {
     Completion(CompletionRequest),
+    InlineCompletion(InlineCompletionRequest),
     Generation(GenerationRequest),
     GenerationStream(GenerationStreamRequest),
+    ExecuteCommand(ExecuteCommandRequest),
+    Chat(ChatRequest),
+    CommitMessage(CommitMessageRequest),
+    PreviewPrompt(PreviewPromptRequest),
+    Agent(AgentRequest),
+    RawGeneration(RawGenerationRequest),
+    // Sent when the client sends $/cancelRequest
+    Cancel(RequestId),
 }
 
 impl WorkerRequest {
     fn get_id(&self) -> RequestId {
         match self {
             WorkerRequest::Completion(r) => r.id.clone(),
+            WorkerRequest::InlineCompletion(r) => r.id.clone(),
             WorkerRequest::Generation(r) => r.id.clone(),
             WorkerRequest::GenerationStream(r) => r.id.clone(),
+            WorkerRequest::ExecuteCommand(r) => r.id.clone(),
+            WorkerRequest::Chat(r) => r.id.clone(),
+            WorkerRequest::CommitMessage(r) => r.id.clone(),
+            WorkerRequest::PreviewPrompt(r) => r.id.clone(),
+            WorkerRequest::Agent(r) => r.id.clone(),
+            WorkerRequest::RawGeneration(r) => r.id.clone(),
+            WorkerRequest::Cancel(id) => id.clone(),
+        }
+    }
+
+    // The document completion requests are coalesced by
+    fn get_completion_uri(&self) -> Option<String> {
+        match self {
+            WorkerRequest::Completion(r) => Some(
+                r.params
+                    .text_document_position
+                    .text_document
+                    .uri
+                    .to_string(),
+            ),
+            WorkerRequest::InlineCompletion(r) => Some(
+                r.params
+                    .text_document_position
+                    .text_document
+                    .uri
+                    .to_string(),
+            ),
+            _ => None,
         }
     }
 }
 
-pub struct DoCompletionResponse {
+// A completion request waiting on the debounce or the rate limit
+struct PendingCompletion {
+    request: WorkerRequest,
+    received: Instant,
+}
+
+// How long requests in flight get to finish when the server shuts down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long cancelled requests get to send their response when the server shuts down
+const CANCELLED_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How many chunks of the workspace raw generations get as context
+const WORKSPACE_CONTEXT_CHUNKS: usize = 5;
+
+// The cancellation tokens for requests currently being processed
+type InFlightRequests = Arc<Mutex<HashMap<RequestId, CancellationToken>>>;
+
+// Each backend is reference counted so a config update can reuse the unchanged ones while
+// requests in flight hold on to the ones they started with
+type TransformerBackends = Arc<HashMap<String, SharedBackend>>;
+
+fn cancelled_response(id: RequestId) -> Response {
+    Response::new_err(
+        id,
+        ErrorCode::RequestCanceled as i32,
+        "request cancelled".to_string(),
+    )
+}
+
+#[derive(Clone)]
+pub struct CompletionCandidate {
     pub insert_text: String,
+    // Higher is better. None when the backend cannot score its candidates.
+    pub score: Option<f32>,
+}
+
+impl CompletionCandidate {
+    pub fn new(insert_text: String) -> Self {
+        Self {
+            insert_text,
+            score: None,
+        }
+    }
+}
+
+pub struct DoCompletionResponse {
+    // Ranked best first
+    pub candidates: Vec<CompletionCandidate>,
+    // As counted by the backend, estimated when it does not report it
+    pub usage: Option<TokenUsage>,
+}
+
+impl DoCompletionResponse {
+    pub fn new(insert_text: String) -> Self {
+        Self {
+            candidates: vec![CompletionCandidate::new(insert_text)],
+            usage: None,
+        }
+    }
+
+    // Sorts the scored candidates first keeping the backend's order for ties and drops duplicates
+    fn rank(&mut self) {
+        self.candidates.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let mut seen = HashSet::new();
+        self.candidates
+            .retain(|candidate| seen.insert(candidate.insert_text.clone()));
+    }
+
+    // Candidates without a score are kept as they are
+    fn filter_confidence(&mut self, filter: &ConfidenceFilter) {
+        let confident = |candidate: &CompletionCandidate| {
+            candidate
+                .score
+                .map_or(true, |score| score >= filter.min_logprob)
+        };
+        match filter.action {
+            LowConfidenceAction::Drop => self.candidates.retain(confident),
+            LowConfidenceAction::Demote => self
+                .candidates
+                .sort_by_key(|candidate| !confident(candidate)),
+        }
+    }
 }
 
 pub struct DoGenerationResponse {
     pub generated_text: String,
+    pub usage: Option<TokenUsage>,
+}
+
+pub struct DoToolGenerationResponse {
+    pub generated_text: String,
+    // None of the tools are called when the model answered
+    pub calls: Vec<ToolCall>,
+    pub usage: Option<TokenUsage>,
 }
 
 pub struct DoGenerationStreamResponse {
     pub generated_text: String,
+    pub usage: Option<TokenUsage>,
 }
 
-fn post_process_start(response: String, front: &str) -> String {
-    let mut front_match = response.len();
-    loop {
-        if response.len() == 0 || front.ends_with(&response[..front_match]) {
-            break;
-        } else {
-            front_match -= 1;
+// Plugins transform the prompt and secrets are redacted before prompts leave the machine
+fn prepare_for_backend(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    prompt: &mut Prompt,
+    params: &mut Value,
+    config: &Config,
+) -> anyhow::Result<()> {
+    plugins::pre_prompt(config.get_plugins(), prompt, params)?;
+    if let Some(redaction) = &config.config.redaction {
+        if !transformer_backend.is_local() {
+            redact_prompt(prompt, params, redaction);
         }
     }
-    if front_match > 0 {
-        (&response[front_match..]).to_owned()
-    } else {
-        response
-    }
+    Ok(())
 }
 
-fn post_process_end(response: String, back: &str) -> String {
-    let mut back_match = 0;
-    loop {
-        if back_match == response.len() {
-            break;
-        } else if back.starts_with(&response[back_match..]) {
-            break;
-        } else {
-            back_match += 1;
-        }
+// Generations with `tools` in their params run the tools the model calls and send the results
+// back until it answers
+async fn generate_with_tools(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: &std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    prompt: &Prompt,
+    params: &Value,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> anyhow::Result<DoGenerationResponse> {
+    let tool_params: ToolParams = serde_json::from_value(params.clone())?;
+    if tool_params.tools.is_empty() {
+        return transformer_backend
+            .do_generate(prompt, params.clone(), cancel)
+            .await;
     }
-    if back_match > 0 {
-        (&response[..back_match]).to_owned()
-    } else {
-        response
+    // The edits would be dropped, only lsp-ai/agent returns them
+    if tool_params.tools.iter().any(|name| name == APPLY_EDIT) {
+        anyhow::bail!("`{APPLY_EDIT}` can only be used by lsp-ai/agent");
     }
+    let definitions = tools::get_definitions(&tool_params.tools, config).await?;
+    let budget = Budget {
+        max_steps: tool_params.max_tool_steps,
+        max_tokens: None,
+    };
+    let mut context = ToolContext::new(memory_backend_tx, config, !transformer_backend.is_local());
+    let outcome = agent::run(
+        transformer_backend.as_ref(),
+        prompt,
+        params,
+        &definitions,
+        budget,
+        &mut context,
+        cancel,
+    )
+    .await?;
+    Ok(DoGenerationResponse {
+        generated_text: outcome.answer,
+        usage: outcome.usage,
+    })
 }
 
-// Some basic post processing that will clean up duplicate characters at the front and back
-fn post_process_response(
-    response: String,
-    prompt: &Prompt,
-    config: &config::PostProcess,
-) -> String {
-    match prompt {
-        Prompt::ContextAndCode(context_and_code) => {
-            if context_and_code.code.contains("<CURSOR>") {
-                let mut split = context_and_code.code.split("<CURSOR>");
-                let response = if config.remove_duplicate_start {
-                    post_process_start(response, split.next().unwrap())
-                } else {
-                    response
-                };
-                if config.remove_duplicate_end {
-                    post_process_end(response, split.next().unwrap())
-                } else {
-                    response
-                }
-            } else {
-                if config.remove_duplicate_start {
-                    post_process_start(response, &context_and_code.code)
-                } else {
-                    response
-                }
-            }
-        }
-        Prompt::FIM(fim) => {
-            let response = if config.remove_duplicate_start {
-                post_process_start(response, &fim.prompt)
-            } else {
-                response
-            };
-            if config.remove_duplicate_end {
-                post_process_end(response, &fim.suffix)
-            } else {
-                response
-            }
-        }
+// Stop sequences are also enforced here as not every API honors them
+fn get_stop_sequences(params: &Value) -> Vec<String> {
+    params
+        .get("stop")
+        .and_then(|stop| serde_json::from_value(stop.clone()).ok())
+        .unwrap_or_default()
+}
+
+// Backends that can score their completions with the log probabilities of the tokens only do
+// when asked
+fn request_logprobs(params: &mut Value) {
+    if let Some(params) = params.as_object_mut() {
+        params.entry("logprobs").or_insert(Value::Bool(true));
+    }
+}
+
+// Prompts for servers with a prefix cache are cut where their start stays the same while typing,
+// unless the parameters say otherwise
+fn request_stable_prefix(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    params: &mut Value,
+) {
+    if !transformer_backend.caches_prompt_prefixes() {
+        return;
+    }
+    if let Some(params) = params.as_object_mut() {
+        params.entry("stable_prefix").or_insert(Value::Bool(true));
     }
 }
 
+// `line_mode` in the parameters replaces the one of the post processing config. Only chat models
+// wrap their code in markdown fences, in FIM and completion prompts they are part of the code.
+fn get_post_process(post_process: &PostProcess, params: &Value) -> anyhow::Result<PostProcess> {
+    let line_mode = match params.get("line_mode") {
+        Some(line_mode) => serde_json::from_value(line_mode.clone())?,
+        None => post_process.line_mode,
+    };
+    Ok(PostProcess {
+        extract_code_block: post_process.extract_code_block
+            && params
+                .get("messages")
+                .is_some_and(|messages| !messages.is_null()),
+        line_mode,
+        ..post_process.clone()
+    })
+}
+
 pub fn run(
-    transformer_backends: HashMap<String, Box<dyn TransformerBackend + Send + Sync>>,
     memory_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
     transformer_rx: std::sync::mpsc::Receiver<WorkerRequest>,
+    config_rx: std::sync::mpsc::Receiver<Config>,
     connection: Arc<Connection>,
     config: Config,
 ) {
-    if let Err(e) = do_run(
-        transformer_backends,
-        memory_tx,
-        transformer_rx,
-        connection,
-        config,
-    ) {
+    if let Err(e) = do_run(memory_tx, transformer_rx, config_rx, connection, config) {
         error!("error in transformer worker: {e}")
     }
 }
 
+// Models that fail to load are left out, requests for them fail until their configuration changes
+fn load_transformer_backends(config: &Config) -> TransformerBackends {
+    let transformer_backends = config
+        .config
+        .models
+        .iter()
+        .filter_map(
+            |(name, model)| match transformer_backends::get_shared(model.clone()) {
+                Ok(transformer_backend) => Some((name.clone(), transformer_backend)),
+                Err(e) => {
+                    error!("error loading model {name}: {e}");
+                    None
+                }
+            },
+        )
+        .collect();
+    Arc::new(transformer_backends)
+}
+
+fn send_ready(
+    connection: &Connection,
+    transformer_backends: &TransformerBackends,
+    config: &Config,
+) {
+    let mut models: Vec<String> = transformer_backends.keys().cloned().collect();
+    let mut failed: Vec<String> = config
+        .config
+        .models
+        .keys()
+        .filter(|name| !transformer_backends.contains_key(*name))
+        .cloned()
+        .collect();
+    models.sort();
+    failed.sort();
+    let notification = Notification::new(Ready::METHOD.to_string(), ReadyParams { models, failed });
+    if let Err(e) = connection.sender.send(Message::Notification(notification)) {
+        error!("sending the ready notification: {e}");
+    }
+}
+
+// Requests are answered without a model until the models are loaded. Completions come back
+// incomplete so clients ask again.
+fn warming_up_response(request: &WorkerRequest) -> Response {
+    let id = request.get_id();
+    match request {
+        WorkerRequest::Completion(_) => Response::new_ok(
+            id,
+            CompletionList {
+                is_incomplete: true,
+                items: vec![],
+            },
+        ),
+        WorkerRequest::InlineCompletion(_) => {
+            Response::new_ok(id, InlineCompletionList { items: vec![] })
+        }
+        _ => Response::new_err(
+            id,
+            ErrorCode::RequestFailed as i32,
+            "lsp-ai is warming up, the models are still loading".to_string(),
+        ),
+    }
+}
+
+// Builds the backends for models whose configuration changed and reuses the rest
+fn update_transformer_backends(
+    transformer_backends: &TransformerBackends,
+    config: &Config,
+    new_config: &Config,
+) -> TransformerBackends {
+    let transformer_backends = new_config
+        .config
+        .models
+        .iter()
+        .filter_map(|(name, model)| {
+            let current = transformer_backends.get(name);
+            if config.config.models.get(name) == Some(model) {
+                if let Some(current) = current {
+                    return Some((name.clone(), current.clone()));
+                }
+            }
+            match transformer_backends::get_shared(model.clone()) {
+                Ok(transformer_backend) => Some((name.clone(), transformer_backend)),
+                Err(e) => {
+                    // Keep serving requests with the previous configuration of the model
+                    error!("error loading model {name}: {e}");
+                    current.map(|current| (name.clone(), current.clone()))
+                }
+            }
+        })
+        .collect();
+    Arc::new(transformer_backends)
+}
+
+// Loads the models of the configuration on another thread and sends them with it. The backends
+// of models whose configuration did not change are reused.
+fn start_loading(
+    current: Option<(TransformerBackends, Config)>,
+    config: Config,
+    connection: Arc<Connection>,
+    loaded_tx: std::sync::mpsc::Sender<(TransformerBackends, Config)>,
+) {
+    std::thread::spawn(move || {
+        // Loading the models is reported to this client only
+        progress::enter(&connection);
+        let loaded = match &current {
+            Some((current, current_config)) => {
+                update_transformer_backends(current, current_config, &config)
+            }
+            None => load_transformer_backends(&config),
+        };
+        let _ = loaded_tx.send((loaded, config));
+    });
+}
+
+fn get_max_requests_per_second(config: &Config) -> f32 {
+    // If they have disabled completions, this function will fail. We set it to MIN_POSITIVE to never process a completions request
+    config
+        .get_completion_transformer_max_requests_per_second()
+        .unwrap_or(f32::MIN_POSITIVE)
+}
+
 fn do_run(
-    transformer_backends: HashMap<String, Box<dyn TransformerBackend + Send + Sync>>,
     memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
     transformer_rx: std::sync::mpsc::Receiver<WorkerRequest>,
+    config_rx: std::sync::mpsc::Receiver<Config>,
     connection: Arc<Connection>,
-    config: Config,
+    mut config: Config,
 ) -> anyhow::Result<()> {
-    let transformer_backends = Arc::new(transformer_backends);
+    // Downloading and loading models can take minutes, the client is not kept waiting for them
+    let (loaded_tx, loaded_rx) = std::sync::mpsc::channel();
+    start_loading(None, config.clone(), connection.clone(), loaded_tx.clone());
+    let mut loading = true;
+    let mut ready_sent = false;
+    let mut transformer_backends: Option<TransformerBackends> = None;
+
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(4)
+        .on_thread_start(progress::enter_on_thread_start(&connection))
         .enable_all()
         .build()?;
 
-    // If they have disabled completions, this function will fail. We set it to MIN_POSITIVE to never process a completions request
-    let max_requests_per_second = config
-        .get_completion_transformer_max_requests_per_second()
-        .unwrap_or(f32::MIN_POSITIVE);
+    let mut max_requests_per_second = get_max_requests_per_second(&config);
     let mut last_completion_request_time = SystemTime::now();
-    let mut last_completion_request = None;
-
-    let run_dispatch_request = |request| {
-        let task_connection = connection.clone();
-        let task_transformer_backends = transformer_backends.clone();
-        let task_memory_backend_tx = memory_backend_tx.clone();
-        let task_config = config.clone();
-        runtime.spawn(async move {
-            dispatch_request(
-                request,
-                task_connection,
-                task_transformer_backends,
-                task_memory_backend_tx,
-                task_config,
-            )
-            .await;
-        });
-    };
+    // Only the latest completion request for each document is kept
+    let mut pending_completions: HashMap<String, PendingCompletion> = HashMap::new();
+    // The completion request being generated for each document
+    let mut in_flight_completions: HashMap<String, RequestId> = HashMap::new();
+    let in_flight_requests: InFlightRequests = Arc::new(Mutex::new(HashMap::new()));
+
+    let run_dispatch_request =
+        |request: WorkerRequest, transformer_backends: &TransformerBackends, config: &Config| {
+            let task_connection = connection.clone();
+            let task_transformer_backends = transformer_backends.clone();
+            let task_memory_backend_tx = memory_backend_tx.clone();
+            let task_config = config.clone();
+            let task_in_flight_requests = in_flight_requests.clone();
+            let cancel = CancellationToken::new();
+            in_flight_requests
+                .lock()
+                .insert(request.get_id(), cancel.clone());
+            runtime.spawn(async move {
+                let id = request.get_id();
+                dispatch_request(
+                    request,
+                    task_connection,
+                    task_transformer_backends,
+                    task_memory_backend_tx,
+                    task_config,
+                    cancel,
+                )
+                .await;
+                task_in_flight_requests.lock().remove(&id);
+            });
+        };
+
+    let mut last_usage_log = Instant::now();
 
     loop {
+        if let Some(interval) = config.get_usage_log_interval() {
+            if last_usage_log.elapsed() >= interval {
+                last_usage_log = Instant::now();
+                log_usage();
+            }
+        }
+
+        if let Ok((loaded, loaded_config)) = loaded_rx.try_recv() {
+            let up_to_date = loaded_config.config.models == config.config.models;
+            if up_to_date && !ready_sent {
+                send_ready(&connection, &loaded, &config);
+                ready_sent = true;
+            }
+            // Configuration changes made while loading are loaded next
+            loading = !up_to_date;
+            if loading {
+                start_loading(
+                    Some((loaded.clone(), loaded_config)),
+                    config.clone(),
+                    connection.clone(),
+                    loaded_tx.clone(),
+                );
+            }
+            transformer_backends = Some(loaded);
+        }
+
+        if let Some(new_config) = config_rx.try_iter().last() {
+            // Requests keep being served with the current models until the new ones are loaded
+            if !loading && new_config.config.models != config.config.models {
+                start_loading(
+                    transformer_backends
+                        .clone()
+                        .map(|current| (current, config.clone())),
+                    new_config.clone(),
+                    connection.clone(),
+                    loaded_tx.clone(),
+                );
+                loading = true;
+            }
+            max_requests_per_second = get_max_requests_per_second(&new_config);
+            config = new_config;
+        }
+
         // We want to rate limit completions without dropping the last rate limited request
         let request = transformer_rx.recv_timeout(Duration::from_millis(5));
 
         match request {
             Ok(request) => match request {
-                WorkerRequest::Completion(_) => last_completion_request = Some(request),
-                _ => run_dispatch_request(request),
+                WorkerRequest::Completion(_) | WorkerRequest::InlineCompletion(_) => {
+                    // A newer completion request for the document supersedes the one waiting and
+                    // the one being generated
+                    let uri = request.get_completion_uri().unwrap_or_default();
+                    if let Some(id) = in_flight_completions.remove(&uri) {
+                        if let Some(cancel) = in_flight_requests.lock().get(&id) {
+                            cancel.cancel();
+                        }
+                    }
+                    let pending = PendingCompletion {
+                        request,
+                        received: Instant::now(),
+                    };
+                    if let Some(superseded) = pending_completions.insert(uri, pending) {
+                        send_response(&connection, cancelled_response(superseded.request.get_id()));
+                    }
+                    // A slow model does not pile up requests, the oldest is the least useful
+                    while pending_completions.len()
+                        > config.get_completion_max_queued_requests().max(1)
+                    {
+                        let Some(oldest_uri) = pending_completions
+                            .iter()
+                            .min_by_key(|(_, pending)| pending.received)
+                            .map(|(uri, _)| uri.clone())
+                        else {
+                            break;
+                        };
+                        if let Some(dropped) = pending_completions.remove(&oldest_uri) {
+                            send_response(
+                                &connection,
+                                cancelled_response(dropped.request.get_id()),
+                            );
+                        }
+                    }
+                }
+                WorkerRequest::Cancel(id) => {
+                    let pending_uri = pending_completions
+                        .iter()
+                        .find(|(_, pending)| pending.request.get_id() == id)
+                        .map(|(uri, _)| uri.clone());
+                    if let Some(uri) = pending_uri {
+                        pending_completions.remove(&uri);
+                        send_response(&connection, cancelled_response(id));
+                    } else if let Some(cancel) = in_flight_requests.lock().get(&id) {
+                        cancel.cancel();
+                    }
+                }
+                _ => match &transformer_backends {
+                    Some(transformer_backends) => {
+                        run_dispatch_request(request, transformer_backends, &config)
+                    }
+                    None => send_response(&connection, warming_up_response(&request)),
+                },
             },
-            Err(RecvTimeoutError::Disconnected) => anyhow::bail!("channel disconnected"),
+            // The server is shutting down
+            Err(RecvTimeoutError::Disconnected) => break,
             _ => {}
         }
 
@@ -239,30 +769,91 @@ fn do_run(
             continue;
         }
 
-        if let Some(request) = last_completion_request.take() {
+        // The oldest request nothing superseded during the debounce goes first
+        let debounce = config.get_completion_debounce();
+        let ready_uri = pending_completions
+            .iter()
+            .filter(|(_, pending)| pending.received.elapsed() >= debounce)
+            .min_by_key(|(_, pending)| pending.received)
+            .map(|(uri, _)| uri.clone());
+        if let Some((uri, pending)) =
+            ready_uri.and_then(|uri| pending_completions.remove_entry(&uri))
+        {
+            let Some(transformer_backends) = &transformer_backends else {
+                send_response(&connection, warming_up_response(&pending.request));
+                continue;
+            };
             last_completion_request_time = SystemTime::now();
-            run_dispatch_request(request);
+            in_flight_completions.insert(uri, pending.request.get_id());
+            run_dispatch_request(pending.request, transformer_backends, &config);
+        }
+    }
+
+    // Completions nobody is waiting for anymore are dropped, generations in flight get a while
+    // to finish before they are cancelled
+    for (_, pending) in pending_completions.drain() {
+        send_response(&connection, cancelled_response(pending.request.get_id()));
+    }
+    for id in in_flight_completions.values() {
+        if let Some(cancel) = in_flight_requests.lock().get(id) {
+            cancel.cancel();
+        }
+    }
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while !in_flight_requests.lock().is_empty() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let unfinished = in_flight_requests.lock().len();
+    if unfinished > 0 {
+        info!("cancelling {unfinished} requests that did not finish before the shutdown");
+        for cancel in in_flight_requests.lock().values() {
+            cancel.cancel();
         }
     }
+    // Cancelled requests answer right away, this only waits for them to send their response
+    runtime.shutdown_timeout(CANCELLED_TIMEOUT);
+    Ok(())
+}
+
+fn send_response(connection: &Connection, response: Response) {
+    if let Err(e) = connection.sender.send(Message::Response(response)) {
+        error!("sending response: {e}");
+    }
 }
 
-#[instrument(skip(connection, transformer_backends, memory_backend_tx, config))]
+#[instrument(skip(connection, transformer_backends, memory_backend_tx, config, cancel))]
 async fn dispatch_request(
     request: WorkerRequest,
     connection: Arc<Connection>,
-    transformer_backends: Arc<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>>,
+    transformer_backends: TransformerBackends,
     memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
     config: Config,
+    cancel: CancellationToken,
 ) {
-    let response = match generate_response(
-        request.clone(),
-        transformer_backends,
-        memory_backend_tx,
-        config,
-    )
-    .await
-    {
+    // Dropping the generation future aborts any outstanding HTTP request. Backends that block
+    // (llama.cpp) check the token themselves.
+    let response = tokio::select! {
+        response = generate_response(
+            request.clone(),
+            transformer_backends,
+            memory_backend_tx,
+            connection.clone(),
+            config,
+            &cancel,
+        ) => response,
+        _ = cancel.cancelled() => Ok(cancelled_response(request.get_id())),
+    };
+    let response = match response {
         Ok(response) => response,
+        Err(_) if cancel.is_cancelled() => cancelled_response(request.get_id()),
+        Err(e) if e.is::<TimedOut>() => {
+            warn!("{e}");
+            Response {
+                id: request.get_id(),
+                result: None,
+                error: Some(e.to_response_error(ErrorCode::RequestFailed as i32)),
+            }
+        }
         Err(e) => {
             error!("generating response: {e}");
             Response {
@@ -273,109 +864,984 @@ async fn dispatch_request(
         }
     };
 
-    if let Err(e) = connection.sender.send(Message::Response(response)) {
-        error!("sending response: {e}");
-    }
+    send_response(&connection, response);
 }
 
-async fn generate_response(
-    request: WorkerRequest,
-    transformer_backends: Arc<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>>,
-    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
-    config: Config,
-) -> anyhow::Result<Response> {
-    match request {
-        WorkerRequest::Completion(request) => {
-            let completion_config = config
-                .config
-                .completion
-                .as_ref()
-                .context("Completions is none")?;
-            let transformer_backend = transformer_backends
-                .get(&completion_config.model)
-                .clone()
-                .with_context(|| format!("can't find model: {}", &completion_config.model))?;
-            do_completion(transformer_backend, memory_backend_tx, &request, &config).await
-        }
-        WorkerRequest::Generation(request) => {
-            let transformer_backend = transformer_backends
-                .get(&request.params.model)
-                .clone()
-                .with_context(|| format!("can't find model: {}", &request.params.model))?;
-            do_generate(transformer_backend, memory_backend_tx, &request).await
-        }
-        WorkerRequest::GenerationStream(_) => {
-            anyhow::bail!("Streaming is not yet supported")
-        }
+async fn get_route<'a>(
+    config: &'a Config,
+    request: RequestKind,
+    position: &TextDocumentPositionParams,
+    memory_backend_tx: &std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+) -> anyhow::Result<Option<&'a Route>> {
+    // Only ask the memory backend for the languageId when it is needed
+    if !config.has_routes() {
+        return Ok(None);
     }
+    let uri = position.text_document.uri.to_string();
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::LanguageId(
+        LanguageIdRequest::new(uri.clone(), tx),
+    ))?;
+    let language_id = rx.await?;
+    // Parsing the document is only worth it when a route depends on the region
+    let region = if config.has_region_routes() {
+        let (tx, rx) = oneshot::channel();
+        memory_backend_tx.send(memory_worker::WorkerRequest::Text(TextRequest::new(
+            uri.clone(),
+            None,
+            tx,
+        )))?;
+        let text = rx.await.context("the document is not open")?;
+        get_region(&uri, &text, position_to_offset(&text, position.position))
+            .unwrap_or(CursorRegion::Code)
+    } else {
+        CursorRegion::Code
+    };
+    Ok(config.get_route(request, language_id.as_deref(), region))
 }
 
-async fn do_completion(
-    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
-    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
-    request: &CompletionRequest,
-    config: &Config,
-) -> anyhow::Result<Response> {
-    let params = serde_json::to_value(
-        config
-            .config
-            .completion
-            .as_ref()
-            .context("Completions is None")?
-            .parameters
-            .clone(),
-    )
-    .unwrap();
-
-    // Build the prompt
+async fn get_language_id(
+    position: &TextDocumentPositionParams,
+    memory_backend_tx: &std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+) -> anyhow::Result<Option<String>> {
     let (tx, rx) = oneshot::channel();
-    memory_backend_tx.send(memory_worker::WorkerRequest::Prompt(PromptRequest::new(
-        request.params.text_document_position.clone(),
+    memory_backend_tx.send(memory_worker::WorkerRequest::LanguageId(
+        LanguageIdRequest::new(position.text_document.uri.to_string(), tx),
+    ))?;
+    Ok(rx.await?)
+}
+
+// Clients send it back with lsp-ai/completionAccepted or lsp-ai/completionRejected
+async fn get_feedback_data(
+    model: &str,
+    position: &TextDocumentPositionParams,
+    memory_backend_tx: &std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+) -> anyhow::Result<Value> {
+    Ok(serde_json::to_value(CompletionFeedbackParams {
+        model: model.to_owned(),
+        language_id: get_language_id(position, memory_backend_tx).await?,
+    })?)
+}
+
+// Completions the rules do not allow are answered without asking a model
+async fn is_completion_allowed(
+    config: &Config,
+    position: &TextDocumentPositionParams,
+    memory_backend_tx: &std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+) -> anyhow::Result<bool> {
+    let Some(rules) = config.get_completion_rules() else {
+        return Ok(true);
+    };
+    let uri = position.text_document.uri.to_string();
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::LanguageId(
+        LanguageIdRequest::new(uri.clone(), tx),
+    ))?;
+    let language_id = rx.await?;
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::Text(TextRequest::new(
+        uri.clone(),
+        None,
+        tx,
+    )))?;
+    let text = rx.await.context("the document is not open")?;
+    completion_rules::allows(
+        rules,
+        &uri,
+        language_id.as_deref(),
+        &text,
+        position.position,
+    )
+}
+
+// The model and parameters from the completion config, or from the route matching the request
+async fn get_completion_model<'a>(
+    config: &'a Config,
+    position: &TextDocumentPositionParams,
+    memory_backend_tx: &std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+) -> anyhow::Result<(&'a str, &'a Kwargs)> {
+    let completion_config = config
+        .config
+        .completion
+        .as_ref()
+        .context("Completions is none")?;
+    let route = get_route(config, RequestKind::Completion, position, memory_backend_tx).await?;
+    let model = route.map_or(&completion_config.model, |route| &route.model);
+    let parameters = route
+        .and_then(|route| route.parameters.as_ref())
+        .unwrap_or(&completion_config.parameters);
+    Ok((model, parameters))
+}
+
+// The model explicitly requested, or the one the routes pick
+async fn get_generation_model(
+    model: &Option<String>,
+    config: &Config,
+    position: &TextDocumentPositionParams,
+    memory_backend_tx: &std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+) -> anyhow::Result<String> {
+    if let Some(model) = model {
+        return Ok(model.clone());
+    }
+    get_route(config, RequestKind::Generation, position, memory_backend_tx)
+        .await?
+        .map(|route| route.model.clone())
+        .context("no model was specified and no route matches the request")
+}
+
+// Marks errors after partial results were sent, retrying would send them again
+#[derive(Debug)]
+struct PartialResultsSent;
+
+impl std::fmt::Display for PartialResultsSent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the stream failed after sending partial results")
+    }
+}
+
+// Marks requests that took longer than the timeout of their model and kind of request
+#[derive(Debug)]
+struct TimedOut(Duration);
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the request timed out after {} ms", self.0.as_millis())
+    }
+}
+
+// Runs the request with the model and then with each of its fallbacks until one succeeds. The
+// name of the model is passed along when it is a fallback. Each attempt gets a token that is
+// cancelled when it times out.
+async fn with_fallbacks<F, Fut>(
+    model: &str,
+    kind: RequestKind,
+    config: &Config,
+    transformer_backends: &TransformerBackends,
+    cancel: &CancellationToken,
+    mut run: F,
+) -> anyhow::Result<Response>
+where
+    F: FnMut(
+        Arc<Box<dyn TransformerBackend + Send + Sync>>,
+        Option<String>,
+        CancellationToken,
+    ) -> Fut,
+    Fut: Future<Output = anyhow::Result<Response>>,
+{
+    let fallbacks = config.get_fallbacks(model);
+    let mut last_error = None;
+    for (i, model) in std::iter::once(model)
+        .chain(fallbacks.iter().map(|fallback| fallback.as_str()))
+        .enumerate()
+    {
+        let Some(transformer_backend) = transformer_backends.get(model) else {
+            last_error = Some(anyhow::anyhow!("can't find model: {model}"));
+            continue;
+        };
+        // Usage is recorded under the model that served the request
+        let transformer_backend: Arc<Box<dyn TransformerBackend + Send + Sync>> =
+            Arc::new(Box::new(TrackedBackend::new(
+                transformer_backend.clone(),
+                model,
+                config,
+            )));
+        let timeout = config.get_request_timeout(model, kind);
+        // Cancelling the attempt instead of only dropping it also stops inference that blocks
+        // the thread
+        let attempt_cancel = cancel.child_token();
+        let timer_cancel = attempt_cancel.clone();
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            timer_cancel.cancel();
+        });
+        let served_by = (i > 0).then(|| model.to_owned());
+        let result = tokio::select! {
+            biased;
+            result = run(transformer_backend, served_by, attempt_cancel.clone()) => result,
+            _ = attempt_cancel.cancelled() => Err(anyhow::anyhow!("request cancelled")),
+        };
+        timer.abort();
+        let timed_out = attempt_cancel.is_cancelled() && !cancel.is_cancelled();
+        match result {
+            Ok(response) => return Ok(response),
+            // The time for the request is up, fallbacks would only answer later
+            Err(_) if timed_out => return Err(anyhow::Error::new(TimedOut(timeout))),
+            Err(e) if cancel.is_cancelled() || e.is::<PartialResultsSent>() => return Err(e),
+            Err(e) => {
+                if i < fallbacks.len() {
+                    warn!("model {model} failed, trying the next fallback: {e}");
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("can't find model: {model}")))
+}
+
+async fn generate_response(
+    request: WorkerRequest,
+    transformer_backends: TransformerBackends,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    connection: Arc<Connection>,
+    config: Config,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Response> {
+    match request {
+        WorkerRequest::Completion(request) => {
+            let position = &request.params.text_document_position;
+            if !is_completion_allowed(&config, position, &memory_backend_tx).await? {
+                let result = CompletionList {
+                    is_incomplete: false,
+                    items: vec![],
+                };
+                return Ok(Response::new_ok(request.id.clone(), result));
+            }
+            let (model, parameters) = get_completion_model(
+                &config,
+                &request.params.text_document_position,
+                &memory_backend_tx,
+            )
+            .await?;
+            let (request, config_ref) = (&request, &config);
+            with_fallbacks(
+                model,
+                RequestKind::Completion,
+                &config,
+                &transformer_backends,
+                cancel,
+                |transformer_backend, served_by, cancel| {
+                    let memory_backend_tx = memory_backend_tx.clone();
+                    async move {
+                        do_completion(
+                            &transformer_backend,
+                            memory_backend_tx,
+                            request,
+                            parameters,
+                            config_ref,
+                            model,
+                            served_by,
+                            &cancel,
+                        )
+                        .await
+                    }
+                },
+            )
+            .await
+        }
+        WorkerRequest::InlineCompletion(request) => {
+            let position = &request.params.text_document_position;
+            if !is_completion_allowed(&config, position, &memory_backend_tx).await? {
+                let result = InlineCompletionList { items: vec![] };
+                return Ok(Response::new_ok(request.id.clone(), result));
+            }
+            let (model, parameters) = get_completion_model(
+                &config,
+                &request.params.text_document_position,
+                &memory_backend_tx,
+            )
+            .await?;
+            let (request, config_ref) = (&request, &config);
+            with_fallbacks(
+                model,
+                RequestKind::Completion,
+                &config,
+                &transformer_backends,
+                cancel,
+                |transformer_backend, served_by, cancel| {
+                    let memory_backend_tx = memory_backend_tx.clone();
+                    async move {
+                        let model = served_by.as_deref().unwrap_or(model);
+                        do_inline_completion(
+                            &transformer_backend,
+                            memory_backend_tx,
+                            request,
+                            parameters,
+                            config_ref,
+                            model,
+                            &cancel,
+                        )
+                        .await
+                    }
+                },
+            )
+            .await
+        }
+        WorkerRequest::Generation(mut request) => {
+            resolve_prompt_files(&mut request.params.parameters, &config)?;
+            let model = get_generation_model(
+                &request.params.model,
+                &config,
+                &request.params.text_document_position,
+                &memory_backend_tx,
+            )
+            .await?;
+            let (request, config_ref) = (&request, &config);
+            with_fallbacks(
+                &model,
+                RequestKind::Generation,
+                &config,
+                &transformer_backends,
+                cancel,
+                |transformer_backend, served_by, cancel| {
+                    let memory_backend_tx = memory_backend_tx.clone();
+                    async move {
+                        do_generate(
+                            &transformer_backend,
+                            memory_backend_tx,
+                            request,
+                            config_ref,
+                            served_by,
+                            &cancel,
+                        )
+                        .await
+                    }
+                },
+            )
+            .await
+        }
+        WorkerRequest::GenerationStream(mut request) => {
+            resolve_prompt_files(&mut request.params.parameters, &config)?;
+            let model = get_generation_model(
+                &request.params.model,
+                &config,
+                &request.params.text_document_position,
+                &memory_backend_tx,
+            )
+            .await?;
+            let (request, config_ref) = (&request, &config);
+            with_fallbacks(
+                &model,
+                RequestKind::Generation,
+                &config,
+                &transformer_backends,
+                cancel,
+                |transformer_backend, served_by, cancel| {
+                    let memory_backend_tx = memory_backend_tx.clone();
+                    let connection = connection.clone();
+                    async move {
+                        do_generate_stream(
+                            &transformer_backend,
+                            memory_backend_tx,
+                            request,
+                            config_ref,
+                            connection,
+                            served_by,
+                            &cancel,
+                        )
+                        .await
+                    }
+                },
+            )
+            .await
+        }
+        WorkerRequest::ExecuteCommand(request)
+            if request.params.command == GENERATE_COMMIT_MESSAGE_COMMAND =>
+        {
+            let arguments: GenerateCommitMessageArguments = serde_json::from_value(
+                request
+                    .params
+                    .arguments
+                    .first()
+                    .cloned()
+                    .context("missing the commit message arguments")?,
+            )?;
+            let uri = &arguments.text_document.uri;
+            let dir = git::get_work_tree(uri.as_str())
+                .context("the commit message is not in a local repository")?;
+            generate_commit_message(
+                request.id.clone(),
+                None,
+                &dir,
+                Some(uri),
+                &transformer_backends,
+                &config,
+                connection,
+                cancel,
+            )
+            .await
+        }
+        WorkerRequest::ExecuteCommand(request) => {
+            anyhow::ensure!(
+                request.params.command == RUN_ACTION_COMMAND,
+                "unknown command: {}",
+                request.params.command
+            );
+            let arguments: RunActionArguments = serde_json::from_value(
+                request
+                    .params
+                    .arguments
+                    .first()
+                    .cloned()
+                    .context("missing the action arguments")?,
+            )?;
+            let action = config.get_action(&arguments.title).with_context(|| {
+                format!("`{}` action not found in `actions` config", arguments.title)
+            })?;
+            let (request, arguments, config_ref) = (&request, &arguments, &config);
+            with_fallbacks(
+                &action.model,
+                RequestKind::Generation,
+                &config,
+                &transformer_backends,
+                cancel,
+                |transformer_backend, _, cancel| {
+                    let memory_backend_tx = memory_backend_tx.clone();
+                    let connection = connection.clone();
+                    async move {
+                        do_action(
+                            &transformer_backend,
+                            memory_backend_tx,
+                            request,
+                            arguments,
+                            config_ref,
+                            connection,
+                            &cancel,
+                        )
+                        .await
+                    }
+                },
+            )
+            .await
+        }
+        WorkerRequest::Chat(request) => {
+            let chat_config = config.config.chat.as_ref().context("Chat is none")?;
+            let model = request.params.model.as_ref().unwrap_or(&chat_config.model);
+            let (request, config_ref) = (&request, &config);
+            with_fallbacks(
+                model,
+                RequestKind::Generation,
+                &config,
+                &transformer_backends,
+                cancel,
+                |transformer_backend, served_by, cancel| {
+                    let memory_backend_tx = memory_backend_tx.clone();
+                    let connection = connection.clone();
+                    async move {
+                        do_chat(
+                            &transformer_backend,
+                            memory_backend_tx,
+                            request,
+                            config_ref,
+                            connection,
+                            served_by,
+                            &cancel,
+                        )
+                        .await
+                    }
+                },
+            )
+            .await
+        }
+        WorkerRequest::CommitMessage(request) => {
+            let uri = request
+                .params
+                .uri
+                .as_deref()
+                .or(config.get_root_uri())
+                .context("no uri was specified and the client sent no rootUri")?;
+            let dir = git::get_work_tree(uri).context("the uri is not a local directory")?;
+            generate_commit_message(
+                request.id.clone(),
+                request.params.model.as_deref(),
+                &dir,
+                None,
+                &transformer_backends,
+                &config,
+                connection,
+                cancel,
+            )
+            .await
+        }
+        WorkerRequest::PreviewPrompt(request) => {
+            do_preview_prompt(&transformer_backends, memory_backend_tx, request, &config).await
+        }
+        WorkerRequest::Agent(request) => {
+            let agent_config = config.config.agent.as_ref().context("Agent is none")?;
+            let model = request.params.model.as_ref().unwrap_or(&agent_config.model);
+            let (request, config_ref) = (&request, &config);
+            with_fallbacks(
+                model,
+                RequestKind::Agent,
+                &config,
+                &transformer_backends,
+                cancel,
+                |transformer_backend, served_by, cancel| {
+                    let memory_backend_tx = memory_backend_tx.clone();
+                    async move {
+                        do_agent(
+                            &transformer_backend,
+                            memory_backend_tx,
+                            request,
+                            agent_config,
+                            config_ref,
+                            served_by,
+                            &cancel,
+                        )
+                        .await
+                    }
+                },
+            )
+            .await
+        }
+        WorkerRequest::RawGeneration(mut request) => {
+            resolve_prompt_files(&mut request.params.parameters, &config)?;
+            let (request, config_ref) = (&request, &config);
+            with_fallbacks(
+                &request.params.model,
+                RequestKind::Generation,
+                &config,
+                &transformer_backends,
+                cancel,
+                |transformer_backend, served_by, cancel| {
+                    let memory_backend_tx = memory_backend_tx.clone();
+                    async move {
+                        do_raw_generation(
+                            &transformer_backend,
+                            memory_backend_tx,
+                            request,
+                            config_ref,
+                            served_by,
+                            &cancel,
+                        )
+                        .await
+                    }
+                },
+            )
+            .await
+        }
+        WorkerRequest::Cancel(_) => anyhow::bail!("cancel requests are not dispatched"),
+    }
+}
+
+// The chunks of the workspace most relevant to the query
+async fn get_workspace_context(
+    memory_backend_tx: &std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    query: &str,
+) -> anyhow::Result<String> {
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::SearchMatches(
+        SearchRequest::new(
+            WorkspaceSearchParams {
+                query: query.to_owned(),
+                top_k: WORKSPACE_CONTEXT_CHUNKS,
+                language: None,
+                paths: vec![],
+            },
+            tx,
+        ),
+    ))?;
+    let matches: Vec<String> = rx
+        .await??
+        .into_iter()
+        .map(|m| format!("{}\n{}", m.uri, m.text))
+        .collect();
+    Ok(matches.join("\n\n"))
+}
+
+async fn do_raw_generation(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &RawGenerationRequest,
+    config: &Config,
+    served_by: Option<String>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Response> {
+    let raw = &request.params;
+    let mut params = match &raw.parameters {
+        Value::Null => json!({}),
+        parameters => parameters.clone(),
+    };
+    let query = match (&raw.messages, &raw.prompt) {
+        (Some(messages), _) => messages
+            .iter()
+            .rev()
+            .find(|message| message.role == "user")
+            .map(|message| message.content.clone())
+            .unwrap_or_default(),
+        (None, Some(prompt)) => prompt.clone(),
+        (None, None) => anyhow::bail!("`messages` or `prompt` is required"),
+    };
+    let context = if raw.workspace_context && !query.trim().is_empty() {
+        get_workspace_context(&memory_backend_tx, &query).await?
+    } else {
+        String::new()
+    };
+    let mut prompt = match &raw.messages {
+        Some(messages) => {
+            let mut messages = messages.clone();
+            // `{CONTEXT}` is replaced with the context of the prompt like in configured messages
+            if !context.is_empty() {
+                messages.insert(
+                    0,
+                    ChatMessage::new(
+                        "system".to_string(),
+                        "Code from the workspace that may be relevant:\n\n{CONTEXT}".to_string(),
+                    ),
+                );
+            }
+            params["messages"] = serde_json::to_value(messages)?;
+            Prompt::ContextAndCode(ContextAndCodePrompt::new(context, String::new()))
+        }
+        None => {
+            // FIM markers that are empty send the prompt as is
+            if params.get("fim").is_none() {
+                params["fim"] = json!({"start": "", "middle": "", "end": ""});
+            }
+            let prompt = raw.prompt.clone().unwrap_or_default();
+            let prompt = if context.is_empty() {
+                prompt
+            } else {
+                format!("{context}\n\n{prompt}")
+            };
+            Prompt::FIM(FIMPrompt::new(prompt, String::new()))
+        }
+    };
+    prepare_for_backend(transformer_backend, &mut prompt, &mut params, config)?;
+    let response = transformer_backend
+        .do_generate(&prompt, params, cancel)
+        .await?;
+    let result = RawGenerationResult {
+        generated_text: response.generated_text,
+        usage: response.usage,
+        served_by,
+    };
+    Ok(Response::new_ok(request.id.clone(), result))
+}
+
+// Runs the agent for the goal. The edits are returned for the client to review.
+async fn do_agent(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &AgentRequest,
+    agent_config: &AgentConfig,
+    config: &Config,
+    served_by: Option<String>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Response> {
+    let document = match &request.params.text_document {
+        Some(text_document) => {
+            let uri = text_document.uri.to_string();
+            let (tx, rx) = oneshot::channel();
+            memory_backend_tx.send(memory_worker::WorkerRequest::Text(TextRequest::new(
+                uri.clone(),
+                None,
+                tx,
+            )))?;
+            let text = rx.await.context("the document is not open")?;
+            Some((uri, text))
+        }
+        None => None,
+    };
+    let (mut prompt, mut params) = agent::build_request(
+        agent_config,
+        config,
+        &request.params.goal,
+        document
+            .as_ref()
+            .map(|(uri, text)| (uri.as_str(), text.as_str())),
+    )?;
+    resolve_prompt_files(&mut params, config)?;
+    prepare_for_backend(transformer_backend, &mut prompt, &mut params, config)?;
+    let tools = agent::get_tools(agent_config, config).await?;
+    let budget = Budget {
+        max_steps: agent_config.max_steps,
+        max_tokens: agent_config.max_tokens,
+    };
+    let mut context = ToolContext::new(&memory_backend_tx, config, !transformer_backend.is_local());
+    let outcome = agent::run(
+        transformer_backend.as_ref(),
+        &prompt,
+        &params,
+        &tools,
+        budget,
+        &mut context,
+        cancel,
+    )
+    .await?;
+    let workspace_edit =
+        (!outcome.changes.is_empty()).then(|| build_workspace_edit(&outcome.changes));
+    let result = AgentResult {
+        answer: outcome.answer,
+        edits: outcome.edits,
+        workspace_edit,
+        diff: build_diff(&outcome.changes),
+        steps: outcome.steps,
+        served_by,
+    };
+    Ok(Response::new_ok(request.id.clone(), result))
+}
+
+// Builds the prompt the request would send without sending it
+async fn do_preview_prompt(
+    transformer_backends: &TransformerBackends,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: PreviewPromptRequest,
+    config: &Config,
+) -> anyhow::Result<Response> {
+    let position = &request.params.text_document_position;
+    let (model, mut params) = match request.params.kind {
+        PreviewKind::Completion => {
+            let (model, parameters) =
+                get_completion_model(config, position, &memory_backend_tx).await?;
+            (model.to_owned(), serde_json::to_value(parameters)?)
+        }
+        PreviewKind::Generation => {
+            let model =
+                get_generation_model(&request.params.model, config, position, &memory_backend_tx)
+                    .await?;
+            (model, request.params.parameters.clone())
+        }
+    };
+    resolve_prompt_files(&mut params, config)?;
+    let transformer_backend = transformer_backends
+        .get(&model)
+        .with_context(|| format!("can't find model: {model}"))?;
+
+    request_stable_prefix(transformer_backend, &mut params);
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::Prompt(PromptRequest::new(
+        position.clone(),
         transformer_backend.get_prompt_type(&params)?,
         params.clone(),
+        transformer_backend.get_tokenizer(),
         tx,
     )))?;
-    let prompt = rx.await?;
+    let mut prompt = rx.await?;
+    prepare_for_backend(transformer_backend, &mut prompt, &mut params, config)?;
+
+    let context = match &prompt {
+        Prompt::ContextAndCode(context_and_code) => Some(context_and_code.context.clone()),
+        Prompt::FIM(_) => None,
+    };
+    let result = PreviewPromptResult {
+        prompt: transformer_backend.render_prompt(&prompt, &params)?,
+        model,
+        context,
+    };
+    Ok(Response::new_ok(request.id, result))
+}
+
+// Writes the commit message for the staged changes in `dir`. It is inserted into the commit
+// message buffer at `target` when set and returned otherwise.
+#[allow(clippy::too_many_arguments)]
+async fn generate_commit_message(
+    id: RequestId,
+    model: Option<&str>,
+    dir: &std::path::Path,
+    target: Option<&lsp_types::Url>,
+    transformer_backends: &TransformerBackends,
+    config: &Config,
+    connection: Arc<Connection>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Response> {
+    let commit_message_config = config
+        .config
+        .commit_message
+        .as_ref()
+        .context("Commit message is none")?;
+    let model = model.unwrap_or(&commit_message_config.model);
+    let (prompt, mut params) = build_request(commit_message_config, dir).await?;
+    resolve_prompt_files(&mut params, config)?;
+    let (id, prompt, params) = (&id, &prompt, &params);
+    with_fallbacks(
+        model,
+        RequestKind::Generation,
+        config,
+        transformer_backends,
+        cancel,
+        |transformer_backend, served_by, cancel| {
+            let connection = connection.clone();
+            async move {
+                let (mut prompt, mut params) = (prompt.clone(), params.clone());
+                prepare_for_backend(&transformer_backend, &mut prompt, &mut params, config)?;
+                let response = transformer_backend
+                    .do_generate(&prompt, params, &cancel)
+                    .await?;
+                let message = format_commit_message(&response.generated_text);
+                let result = match target {
+                    Some(uri) => {
+                        let edit = build_edit(uri.clone(), &message);
+                        send_action_result(
+                            &connection,
+                            "Generate commit message",
+                            ActionResult::Edit(edit),
+                        )?;
+                        Value::Null
+                    }
+                    None => {
+                        serde_json::to_value(GenerateCommitMessageResult { message, served_by })
+                            .unwrap()
+                    }
+                };
+                Ok(Response {
+                    id: id.clone(),
+                    result: Some(result),
+                    error: None,
+                })
+            }
+        },
+    )
+    .await
+}
+
+// Returns the post processed and ranked completion candidates along with the filter text
+#[allow(clippy::too_many_arguments)]
+async fn get_completion_candidates(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    position: &TextDocumentPositionParams,
+    parameters: &Kwargs,
+    config: &Config,
+    // The model that serves the completion, a fallback does not get the completions of the one it
+    // stands in for
+    model: &str,
+    cache: &CompletionCache,
+    cancel: &CancellationToken,
+) -> anyhow::Result<(DoCompletionResponse, String)> {
+    let mut params = serde_json::to_value(parameters).unwrap();
+    resolve_prompt_files(&mut params, config)?;
+
+    // Build the prompt
+    request_stable_prefix(transformer_backend, &mut params);
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::Prompt(PromptRequest::new(
+        position.clone(),
+        transformer_backend.get_prompt_type(&params)?,
+        params.clone(),
+        transformer_backend.get_tokenizer(),
+        tx,
+    )))?;
+    let mut prompt = rx.await?;
+    prepare_for_backend(transformer_backend, &mut prompt, &mut params, config)?;
+    // Keyed by what the model is sent, plugins and redaction can change it
+    let cache_key = CacheKey::new(model, &prompt, &params);
 
     // Get the filter text
     let (tx, rx) = oneshot::channel();
     memory_backend_tx.send(memory_worker::WorkerRequest::FilterText(
-        FilterRequest::new(request.params.text_document_position.clone(), tx),
+        FilterRequest::new(position.clone(), tx),
     ))?;
     let filter_text = rx.await?;
 
-    // Get the response
-    let mut response = transformer_backend.do_completion(&prompt, params).await?;
-    eprintln!("\n\n\n\nGOT RESPONSE: {}\n\n\n\n", response.insert_text);
+    let cache_config = config.get_completion_cache();
+    if let Some(cache_config) = cache_config {
+        let candidates = cache.get(cache_key, cache_config);
+        metrics::record_cache(candidates.is_some());
+        if let Some(candidates) = candidates {
+            let response = DoCompletionResponse {
+                candidates,
+                usage: None,
+            };
+            return Ok((response, filter_text));
+        }
+    }
 
-    if let Some(post_process) = config.get_completions_post_process() {
-        response.insert_text = post_process_response(response.insert_text, &prompt, &post_process);
+    // Get the response
+    let language_id = get_language_id(position, &memory_backend_tx).await?;
+    let stop = get_stop_sequences(&params);
+    let post_process = config
+        .get_completions_post_process()
+        .map(|post_process| get_post_process(post_process, &params))
+        .transpose()?;
+    let confidence = config.get_completion_confidence();
+    if confidence.is_some() {
+        request_logprobs(&mut params);
+    }
+    let mut response = transformer_backend
+        .do_completion(&prompt, params, cancel)
+        .await?;
+    for candidate in &mut response.candidates {
+        candidate.insert_text =
+            truncate_at_stop_sequence(std::mem::take(&mut candidate.insert_text), &stop);
+        if let Some(post_process) = &post_process {
+            candidate.insert_text = post_process_response(
+                std::mem::take(&mut candidate.insert_text),
+                &prompt,
+                language_id.as_deref(),
+                post_process,
+            );
+        }
+        candidate.insert_text = plugins::post_completion(
+            config.get_plugins(),
+            std::mem::take(&mut candidate.insert_text),
+            "completion",
+        )?;
+    }
+    response.rank();
+    if let Some(confidence) = confidence {
+        response.filter_confidence(confidence);
+    }
+    if let Some(cache_config) = cache_config {
+        cache.insert(
+            cache_key,
+            position,
+            &prompt,
+            response.candidates.clone(),
+            cache_config,
+        );
     }
+    Ok((response, filter_text))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn do_completion(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &CompletionRequest,
+    parameters: &Kwargs,
+    config: &Config,
+    model: &str,
+    served_by: Option<String>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Response> {
+    let data = get_feedback_data(
+        served_by.as_deref().unwrap_or(model),
+        &request.params.text_document_position,
+        &memory_backend_tx,
+    )
+    .await?;
+    let (response, filter_text) = get_completion_candidates(
+        transformer_backend,
+        memory_backend_tx,
+        &request.params.text_document_position,
+        parameters,
+        config,
+        served_by.as_deref().unwrap_or(model),
+        &request.cache,
+        cancel,
+    )
+    .await?;
 
     // Build and send the response
-    let completion_text_edit = TextEdit::new(
-        Range::new(
-            Position::new(
-                request.params.text_document_position.position.line,
-                request.params.text_document_position.position.character,
-            ),
-            Position::new(
-                request.params.text_document_position.position.line,
-                request.params.text_document_position.position.character,
-            ),
-        ),
-        response.insert_text.clone(),
+    let position = Position::new(
+        request.params.text_document_position.position.line,
+        request.params.text_document_position.position.character,
     );
-    let item = CompletionItem {
-        label: format!("ai - {}", response.insert_text),
-        filter_text: Some(filter_text),
-        text_edit: Some(lsp_types::CompletionTextEdit::Edit(completion_text_edit)),
-        kind: Some(CompletionItemKind::TEXT),
-        ..Default::default()
-    };
+    let ranked = response.candidates.len() > 1;
+    let items = response
+        .candidates
+        .into_iter()
+        .enumerate()
+        .map(|(i, candidate)| CompletionItem {
+            label: format!("ai - {}", candidate.insert_text),
+            filter_text: Some(filter_text.clone()),
+            // Keeps the ranking when the editor sorts the items
+            sort_text: ranked.then(|| format!("{i:04}")),
+            detail: served_by
+                .as_ref()
+                .map(|model| format!("served by fallback model: {model}")),
+            text_edit: Some(lsp_types::CompletionTextEdit::Edit(TextEdit::new(
+                Range::new(position, position),
+                candidate.insert_text,
+            ))),
+            kind: Some(CompletionItemKind::TEXT),
+            data: Some(data.clone()),
+            ..Default::default()
+        })
+        .collect();
     let completion_list = CompletionList {
         is_incomplete: false,
-        items: vec![item],
+        items,
     };
     let result = Some(CompletionResponse::List(completion_list));
     let result = serde_json::to_value(result).unwrap();
@@ -386,31 +1852,393 @@ async fn do_completion(
     })
 }
 
+// Inline completions replace the line up to the cursor so editors keep showing them while the
+// user types the beginning of the completion
+fn build_inline_completion_items(
+    candidates: Vec<CompletionCandidate>,
+    filter_text: &str,
+    position: Position,
+    selected_completion_info: Option<&SelectedCompletionInfo>,
+) -> Vec<InlineCompletionItem> {
+    let range = Range::new(Position::new(position.line, 0), position);
+    // When the editor has a completion item selected, only completions extending it are shown
+    let required_prefix = selected_completion_info.map(|selected| {
+        let start: String = filter_text
+            .chars()
+            .take(selected.range.start.character as usize)
+            .collect();
+        start + &selected.text
+    });
+    candidates
+        .into_iter()
+        .map(|candidate| format!("{filter_text}{}", candidate.insert_text))
+        .filter(|insert_text| {
+            required_prefix
+                .as_ref()
+                .map_or(true, |prefix| insert_text.starts_with(prefix.as_str()))
+        })
+        .map(|insert_text| InlineCompletionItem {
+            insert_text,
+            filter_text: None,
+            range: Some(range),
+            data: None,
+        })
+        .collect()
+}
+
+async fn do_inline_completion(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &InlineCompletionRequest,
+    parameters: &Kwargs,
+    config: &Config,
+    model: &str,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Response> {
+    let position = &request.params.text_document_position;
+    let data = get_feedback_data(model, position, &memory_backend_tx).await?;
+    let (response, filter_text) = get_completion_candidates(
+        transformer_backend,
+        memory_backend_tx,
+        position,
+        parameters,
+        config,
+        model,
+        &request.cache,
+        cancel,
+    )
+    .await?;
+    let mut items = build_inline_completion_items(
+        response.candidates,
+        &filter_text,
+        position.position,
+        request.params.context.selected_completion_info.as_ref(),
+    );
+    for item in &mut items {
+        item.data = Some(data.clone());
+    }
+    let result = serde_json::to_value(InlineCompletionList { items }).unwrap();
+    Ok(Response {
+        id: request.id.clone(),
+        result: Some(result),
+        error: None,
+    })
+}
+
+async fn do_action(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &ExecuteCommandRequest,
+    arguments: &RunActionArguments,
+    config: &Config,
+    connection: Arc<Connection>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Response> {
+    let action = config
+        .get_action(&arguments.title)
+        .context("action not found in `actions` config")?;
+    let uri = arguments.text_document.uri.to_string();
+
+    // The selection is the code and the document it is in the context
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::Text(TextRequest::new(
+        uri.clone(),
+        Some(arguments.range),
+        tx,
+    )))?;
+    let code = rx.await?;
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::Text(TextRequest::new(
+        uri.clone(),
+        None,
+        tx,
+    )))?;
+    let document = rx.await?;
+    let mut prompt = Prompt::ContextAndCode(ContextAndCodePrompt::new(document.clone(), code));
+
+    let mut params = serde_json::to_value(&action.parameters).unwrap();
+    resolve_prompt_files(&mut params, config)?;
+    if action.output == ActionOutput::Fix {
+        insert_diagnostics(&mut params, &arguments.diagnostics);
+    }
+    if action.output == ActionOutput::Diff {
+        insert_diff_instructions(&mut params);
+    }
+    prepare_for_backend(transformer_backend, &mut prompt, &mut params, config)?;
+    let response = generate_with_tools(
+        transformer_backend,
+        &memory_backend_tx,
+        &prompt,
+        &params,
+        config,
+        cancel,
+    )
+    .await?;
+    parse_structured_output(&params, &response.generated_text)?;
+    let convention = if action.output == ActionOutput::Tests {
+        let (tx, rx) = oneshot::channel();
+        memory_backend_tx.send(memory_worker::WorkerRequest::LanguageId(
+            LanguageIdRequest::new(uri, tx),
+        ))?;
+        config.get_test_convention(rx.await?.as_deref())
+    } else {
+        None
+    };
+    let result = build_action_result(
+        action.output,
+        arguments.text_document.uri.clone(),
+        arguments.range,
+        &document,
+        convention,
+        response.generated_text,
+    )?;
+    send_action_result(&connection, &action.title, result)?;
+    Ok(Response {
+        id: request.id.clone(),
+        result: Some(Value::Null),
+        error: None,
+    })
+}
+
+async fn do_chat(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &ChatRequest,
+    config: &Config,
+    connection: Arc<Connection>,
+    served_by: Option<String>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Response> {
+    let chat_config = config.config.chat.as_ref().context("Chat is none")?;
+    let conversation_id = request
+        .params
+        .conversation_id
+        .clone()
+        .unwrap_or_else(Conversations::new_id);
+    let history = request.conversations.get(&conversation_id)?;
+
+    // The configured messages come first, then the end of the conversation and the new message
+    let mut params = serde_json::to_value(&chat_config.parameters).unwrap();
+    resolve_prompt_files(&mut params, config)?;
+    let mut messages: Vec<ChatMessage> = match params.get("messages") {
+        Some(messages) => serde_json::from_value(messages.clone())?,
+        None => vec![],
+    };
+    let skip = history.len().saturating_sub(chat_config.max_history);
+    messages.extend(history.into_iter().skip(skip));
+    let user_message = ChatMessage::new("user".to_string(), request.params.message.clone());
+    messages.push(user_message.clone());
+    params["messages"] = serde_json::to_value(messages)?;
+    request_stable_prefix(transformer_backend, &mut params);
+
+    let mut prompt = match &request.params.text_document_position {
+        Some(position) => {
+            let (tx, rx) = oneshot::channel();
+            memory_backend_tx.send(memory_worker::WorkerRequest::Prompt(PromptRequest::new(
+                position.clone(),
+                PromptType::ContextAndCode,
+                params.clone(),
+                transformer_backend.get_tokenizer(),
+                tx,
+            )))?;
+            rx.await?
+        }
+        None => Prompt::ContextAndCode(ContextAndCodePrompt::new(String::new(), String::new())),
+    };
+    prepare_for_backend(transformer_backend, &mut prompt, &mut params, config)?;
+
+    // Forward the response to the client as it is generated when it asked for it
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let stream_conversation_id = request.params.stream.then(|| conversation_id.clone());
+    let sent_partial_results = Arc::new(AtomicBool::new(false));
+    let task_sent_partial_results = sent_partial_results.clone();
+    let forward_task = tokio::spawn(async move {
+        while let Some(text) = rx.recv().await {
+            let Some(conversation_id) = &stream_conversation_id else {
+                continue;
+            };
+            if text.is_empty() {
+                continue;
+            }
+            task_sent_partial_results.store(true, Ordering::Relaxed);
+            let notification = Notification::new(
+                <ChatStream as lsp_types::notification::Notification>::METHOD.to_string(),
+                ChatStreamParams {
+                    conversation_id: conversation_id.clone(),
+                    text,
+                },
+            );
+            if let Err(e) = connection.sender.send(Message::Notification(notification)) {
+                error!("sending chat stream: {e}");
+            }
+        }
+    });
+    let response = transformer_backend
+        .do_generate_stream(&prompt, params, tx, cancel)
+        .await;
+    forward_task.await?;
+    let response = match response {
+        Err(e) if sent_partial_results.load(Ordering::Relaxed) => {
+            return Err(e.context(PartialResultsSent))
+        }
+        response => response?,
+    };
+
+    request.conversations.append(
+        &conversation_id,
+        vec![
+            user_message,
+            ChatMessage::new("assistant".to_string(), response.generated_text.clone()),
+        ],
+    )?;
+    let result = ChatResult {
+        conversation_id,
+        message: response.generated_text,
+        served_by,
+    };
+    let result = serde_json::to_value(result).unwrap();
+    Ok(Response {
+        id: request.id.clone(),
+        result: Some(result),
+        error: None,
+    })
+}
+
 async fn do_generate(
     transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
     memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
     request: &GenerationRequest,
+    config: &Config,
+    served_by: Option<String>,
+    cancel: &CancellationToken,
 ) -> anyhow::Result<Response> {
-    let params = serde_json::to_value(request.params.parameters.clone()).unwrap();
+    let mut params = serde_json::to_value(request.params.parameters.clone()).unwrap();
 
+    request_stable_prefix(transformer_backend, &mut params);
     let (tx, rx) = oneshot::channel();
     memory_backend_tx.send(memory_worker::WorkerRequest::Prompt(PromptRequest::new(
         request.params.text_document_position.clone(),
         transformer_backend.get_prompt_type(&params)?,
         params.clone(),
+        transformer_backend.get_tokenizer(),
         tx,
     )))?;
-    let prompt = rx.await?;
+    let mut prompt = rx.await?;
+    prepare_for_backend(transformer_backend, &mut prompt, &mut params, config)?;
 
-    let mut response = transformer_backend.do_generate(&prompt, params).await?;
-    response.generated_text = post_process_response(
-        response.generated_text,
+    let stop = get_stop_sequences(&params);
+    let mut response = generate_with_tools(
+        transformer_backend,
+        &memory_backend_tx,
         &prompt,
-        &request.params.post_process,
-    );
+        &params,
+        config,
+        cancel,
+    )
+    .await?;
+    // Post processing would break the JSON
+    let structured_output = parse_structured_output(&params, &response.generated_text)?;
+    if structured_output.is_none() {
+        response.generated_text = truncate_at_stop_sequence(response.generated_text, &stop);
+        let post_process = get_post_process(&request.params.post_process, &params)?;
+        let language_id =
+            get_language_id(&request.params.text_document_position, &memory_backend_tx).await?;
+        response.generated_text = post_process_response(
+            response.generated_text,
+            &prompt,
+            language_id.as_deref(),
+            &post_process,
+        );
+        response.generated_text =
+            plugins::post_completion(config.get_plugins(), response.generated_text, "generation")?;
+    }
 
     let result = GenerateResult {
         generated_text: response.generated_text,
+        structured_output,
+        served_by,
+    };
+    let result = serde_json::to_value(result).unwrap();
+    Ok(Response {
+        id: request.id.clone(),
+        result: Some(result),
+        error: None,
+    })
+}
+
+async fn do_generate_stream(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &GenerationStreamRequest,
+    config: &Config,
+    connection: Arc<Connection>,
+    served_by: Option<String>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Response> {
+    let mut params = request.params.parameters.clone();
+
+    request_stable_prefix(transformer_backend, &mut params);
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::Prompt(PromptRequest::new(
+        request.params.text_document_position.clone(),
+        transformer_backend.get_prompt_type(&params)?,
+        params.clone(),
+        transformer_backend.get_tokenizer(),
+        tx,
+    )))?;
+    let mut prompt = rx.await?;
+    prepare_for_backend(transformer_backend, &mut prompt, &mut params, config)?;
+
+    // Forward partial results to the client as they arrive
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let partial_result_token = request.params.partial_result_token.clone();
+    let stop = get_stop_sequences(&params);
+    let mut stop_filter = StopSequenceFilter::new(stop.clone());
+    let sent_partial_results = Arc::new(AtomicBool::new(false));
+    let task_sent_partial_results = sent_partial_results.clone();
+    let forward_task = tokio::spawn(async move {
+        let send_partial_result = |generated_text: String| {
+            if generated_text.is_empty() {
+                return;
+            }
+            task_sent_partial_results.store(true, Ordering::Relaxed);
+            let value = GenerationStreamResult {
+                generated_text,
+                partial_result_token: partial_result_token.clone(),
+                served_by: None,
+            };
+            let notification = Notification::new(
+                "$/progress".to_string(),
+                json!({
+                    "token": partial_result_token,
+                    "value": value
+                }),
+            );
+            if let Err(e) = connection.sender.send(Message::Notification(notification)) {
+                error!("sending partial result: {e}");
+            }
+        };
+        while let Some(chunk) = rx.recv().await {
+            send_partial_result(stop_filter.push(&chunk));
+        }
+        send_partial_result(stop_filter.finish());
+    });
+
+    let response = transformer_backend
+        .do_generate_stream(&prompt, params, tx, cancel)
+        .await;
+    forward_task.await?;
+    let response = match response {
+        Err(e) if sent_partial_results.load(Ordering::Relaxed) => {
+            return Err(e.context(PartialResultsSent))
+        }
+        response => response?,
+    };
+
+    let result = GenerationStreamResult {
+        generated_text: truncate_at_stop_sequence(response.generated_text, &stop),
+        partial_result_token: request.params.partial_result_token.clone(),
+        served_by,
     };
     let result = serde_json::to_value(result).unwrap();
     Ok(Response {
@@ -423,63 +2251,178 @@ async fn do_generate(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::memory_backends::{ContextAndCodePrompt, FIMPrompt};
 
-    #[test]
-    fn test_post_process_fim() {
-        let config = config::PostProcess::default();
+    struct TestBackend;
 
-        let prompt = Prompt::FIM(FIMPrompt {
-            prompt: "test 1234 ".to_string(),
-            suffix: "ttabc".to_string(),
-        });
-        let response = "4 zz tta".to_string();
-        let new_response = post_process_response(response.clone(), &prompt, &config);
-        assert_eq!(new_response, "zz ");
+    #[async_trait::async_trait]
+    impl TransformerBackend for TestBackend {
+        async fn do_generate(
+            &self,
+            _prompt: &Prompt,
+            _params: Value,
+            _cancel: &CancellationToken,
+        ) -> anyhow::Result<DoGenerationResponse> {
+            anyhow::bail!("not used")
+        }
+    }
 
-        let prompt = Prompt::FIM(FIMPrompt {
-            prompt: "test".to_string(),
-            suffix: "test".to_string(),
-        });
-        let response = "zzzz".to_string();
-        let new_response = post_process_response(response.clone(), &prompt, &config);
-        assert_eq!(new_response, "zzzz");
+    #[tokio::test]
+    async fn test_with_fallbacks() -> anyhow::Result<()> {
+        let mut config = Config::default_with_file_store_without_models();
+        config.config.fallback.insert(
+            "model1".to_string(),
+            vec!["missing".to_string(), "model2".to_string()],
+        );
+        let transformer_backends: TransformerBackends = Arc::new(
+            ["model1", "model2"]
+                .into_iter()
+                .map(|name| {
+                    let transformer_backend: Box<dyn TransformerBackend + Send + Sync> =
+                        Box::new(TestBackend);
+                    (name.to_string(), Arc::new(transformer_backend))
+                })
+                .collect(),
+        );
+        let cancel = CancellationToken::new();
+        let response = with_fallbacks(
+            "model1",
+            RequestKind::Completion,
+            &config,
+            &transformer_backends,
+            &cancel,
+            |_, served_by, _| async move {
+                let served_by = served_by.context("the primary model fails")?;
+                anyhow::Ok(Response::new_ok(RequestId::from(1), served_by))
+            },
+        )
+        .await?;
+        assert_eq!(response.result, Some(json!("model2")));
+
+        // Timed out attempts are cancelled and not retried with the fallbacks
+        config.config.request_timeouts.completion_ms = 10;
+        let error = with_fallbacks(
+            "model1",
+            RequestKind::Completion,
+            &config,
+            &transformer_backends,
+            &cancel,
+            |_, _, attempt_cancel| async move {
+                attempt_cancel.cancelled().await;
+                anyhow::bail!("cancelled by the timeout")
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(error.is::<TimedOut>());
+        Ok(())
     }
 
     #[test]
-    fn test_post_process_context_and_code() {
-        let config = config::PostProcess::default();
+    fn test_get_post_process() -> anyhow::Result<()> {
+        let post_process = PostProcess::default();
+        let chat = json!({ "messages": [], "line_mode": "single" });
+        let chat_post_process = get_post_process(&post_process, &chat)?;
+        assert!(chat_post_process.extract_code_block);
+        assert_eq!(chat_post_process.line_mode, config::LineMode::Single);
+        let fim = json!({ "fim": { "start": "", "middle": "", "end": "" } });
+        assert!(!get_post_process(&post_process, &fim)?.extract_code_block);
+        Ok(())
+    }
 
-        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt {
-            context: "".to_string(),
-            code: "tt ".to_string(),
-        });
-        let response = "tt abc".to_string();
-        let new_response = post_process_response(response.clone(), &prompt, &config);
-        assert_eq!(new_response, "abc");
+    #[test]
+    fn test_build_inline_completion_items() {
+        let candidates = || {
+            vec![
+                CompletionCandidate::new("_numbers(x, y)".to_string()),
+                CompletionCandidate::new("ly(x, y)".to_string()),
+            ]
+        };
+        let position = Position::new(1, 12);
+        let items = build_inline_completion_items(candidates(), "def multiply", position, None);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].insert_text, "def multiply_numbers(x, y)");
+        assert_eq!(
+            items[0].range,
+            Some(Range::new(Position::new(1, 0), position))
+        );
 
-        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt {
-            context: "".to_string(),
-            code: "ff".to_string(),
-        });
-        let response = "zz".to_string();
-        let new_response = post_process_response(response.clone(), &prompt, &config);
-        assert_eq!(new_response, "zz");
+        let selected = SelectedCompletionInfo {
+            range: Range::new(Position::new(1, 4), position),
+            text: "multiply_numbers".to_string(),
+        };
+        let items =
+            build_inline_completion_items(candidates(), "def multiply", position, Some(&selected));
+        let texts: Vec<&str> = items.iter().map(|i| i.insert_text.as_str()).collect();
+        assert_eq!(texts, vec!["def multiply_numbers(x, y)"]);
+    }
 
-        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt {
-            context: "".to_string(),
-            code: "tt <CURSOR> tt".to_string(),
-        });
-        let response = "tt abc tt".to_string();
-        let new_response = post_process_response(response.clone(), &prompt, &config);
-        assert_eq!(new_response, "abc");
+    #[test]
+    fn test_rank_candidates() {
+        let mut response = DoCompletionResponse {
+            candidates: vec![
+                CompletionCandidate::new("a".to_string()),
+                CompletionCandidate {
+                    insert_text: "b".to_string(),
+                    score: Some(-2.),
+                },
+                CompletionCandidate {
+                    insert_text: "c".to_string(),
+                    score: Some(-0.5),
+                },
+                CompletionCandidate {
+                    insert_text: "b".to_string(),
+                    score: Some(-3.),
+                },
+            ],
+            usage: None,
+        };
+        response.rank();
+        let texts: Vec<&str> = response
+            .candidates
+            .iter()
+            .map(|candidate| candidate.insert_text.as_str())
+            .collect();
+        assert_eq!(texts, vec!["c", "b", "a"]);
+    }
 
-        let prompt = Prompt::ContextAndCode(ContextAndCodePrompt {
-            context: "".to_string(),
-            code: "d<CURSOR>d".to_string(),
-        });
-        let response = "zz".to_string();
-        let new_response = post_process_response(response.clone(), &prompt, &config);
-        assert_eq!(new_response, "zz");
+    #[test]
+    fn test_filter_confidence() {
+        let response = || DoCompletionResponse {
+            candidates: vec![
+                CompletionCandidate {
+                    insert_text: "a".to_string(),
+                    score: Some(-2.),
+                },
+                CompletionCandidate::new("b".to_string()),
+                CompletionCandidate {
+                    insert_text: "c".to_string(),
+                    score: Some(-0.5),
+                },
+            ],
+            usage: None,
+        };
+        let texts = |response: DoCompletionResponse| -> Vec<String> {
+            response
+                .candidates
+                .into_iter()
+                .map(|candidate| candidate.insert_text)
+                .collect()
+        };
+        let mut filter = ConfidenceFilter {
+            min_logprob: -1.,
+            action: LowConfidenceAction::Drop,
+        };
+        let mut dropped = response();
+        dropped.filter_confidence(&filter);
+        assert_eq!(texts(dropped), vec!["b", "c"]);
+
+        filter.action = LowConfidenceAction::Demote;
+        let mut demoted = response();
+        demoted.filter_confidence(&filter);
+        assert_eq!(texts(demoted), vec!["b", "c", "a"]);
+
+        let mut params = json!({"max_tokens": 4});
+        request_logprobs(&mut params);
+        assert_eq!(params, json!({"max_tokens": 4, "logprobs": true}));
     }
 }
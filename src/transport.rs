@@ -0,0 +1,349 @@
+use std::{
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::Context;
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use interprocess::local_socket::{prelude::*, GenericFilePath, ListenerOptions};
+use lsp_server::{Connection, Message};
+use tracing::{error, info};
+use tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+};
+
+use crate::proxy::is_local_origin;
+
+// How long the WebSocket thread waits for a client message before checking for outgoing ones
+const WEBSOCKET_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+pub enum Transport {
+    Stdio,
+    // These serve any number of clients at once
+    Tcp(String),
+    WebSocket(String),
+    // A Unix domain socket, or a named pipe like `\\.\pipe\lsp-ai` on Windows
+    Socket(String),
+}
+
+fn is_exit(message: &Message) -> bool {
+    matches!(message, Message::Notification(notification) if notification.method == "exit")
+}
+
+type IoThread = JoinHandle<anyhow::Result<()>>;
+
+fn join_thread(thread: IoThread) -> anyhow::Result<()> {
+    match thread.join() {
+        Ok(result) => result,
+        Err(_) => anyhow::bail!("an io thread panicked"),
+    }
+}
+
+struct IoThreads {
+    // Joined first so everything is sent before the reader is unblocked
+    writer: IoThread,
+    reader: Option<IoThread>,
+    // Shut down to unblock the reader when the client did not disconnect. Local sockets cannot
+    // be shut down, their reader stops when the client sends exit or disconnects.
+    shutdown: Option<TcpStream>,
+}
+
+impl IoThreads {
+    fn join(self) -> anyhow::Result<()> {
+        join_thread(self.writer)?;
+        if let Some(stream) = self.shutdown {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        if let Some(reader) = self.reader {
+            join_thread(reader)?;
+        }
+        Ok(())
+    }
+}
+
+// Messages are framed with Content-Length headers like on stdio
+fn stream_connection(
+    reader: impl Read + Send + 'static,
+    mut writer: impl Write + Send + 'static,
+    shutdown: Option<TcpStream>,
+) -> (Connection, IoThreads) {
+    let (reader_sender, receiver) = crossbeam_channel::bounded::<Message>(0);
+    let (sender, writer_receiver) = crossbeam_channel::bounded::<Message>(0);
+    let mut reader = BufReader::new(reader);
+    let reader = thread::spawn(move || -> anyhow::Result<()> {
+        while let Some(message) = Message::read(&mut reader)? {
+            let exit = is_exit(&message);
+            if reader_sender.send(message).is_err() || exit {
+                break;
+            }
+        }
+        Ok(())
+    });
+    let writer = thread::spawn(move || -> anyhow::Result<()> {
+        for message in writer_receiver {
+            message.write(&mut writer)?;
+        }
+        Ok(())
+    });
+    (
+        Connection { sender, receiver },
+        IoThreads {
+            writer,
+            reader: Some(reader),
+            shutdown,
+        },
+    )
+}
+
+fn tcp_connection(stream: TcpStream) -> io::Result<(Connection, IoThreads)> {
+    let reader = stream.try_clone()?;
+    let shutdown = stream.try_clone()?;
+    Ok(stream_connection(reader, stream, Some(shutdown)))
+}
+
+// Each WebSocket text message is one JSON-RPC message. Reads and writes share the socket so one
+// thread does both.
+fn run_websocket(
+    mut socket: tungstenite::WebSocket<TcpStream>,
+    sender: Sender<Message>,
+    receiver: Receiver<Message>,
+) -> anyhow::Result<()> {
+    socket
+        .get_mut()
+        .set_read_timeout(Some(WEBSOCKET_POLL_INTERVAL))?;
+    loop {
+        loop {
+            match receiver.try_recv() {
+                Ok(message) => {
+                    socket.send(tungstenite::Message::Text(serde_json::to_string(&message)?))?
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = socket.close(None);
+                    let _ = socket.flush();
+                    return Ok(());
+                }
+            }
+        }
+        let message = match socket.read() {
+            Ok(tungstenite::Message::Text(text)) => serde_json::from_str::<Message>(&text)?,
+            Ok(tungstenite::Message::Binary(bytes)) => serde_json::from_slice::<Message>(&bytes)?,
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                continue
+            }
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                return Ok(())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // The server stops reading after exit, the thread keeps running to send what is left
+        let _ = sender.send(message);
+    }
+}
+
+// Any page open in a browser can connect to a local port and the configuration a client sends can
+// run commands, so only pages served from this machine are accepted. Other clients send no Origin.
+fn check_origin(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    match request
+        .headers()
+        .get("Origin")
+        .map(|origin| origin.to_str())
+    {
+        None => Ok(response),
+        Some(Ok(origin)) if is_local_origin(origin) => Ok(response),
+        _ => {
+            let mut error = ErrorResponse::new(Some("only local origins may connect".to_string()));
+            *error.status_mut() = StatusCode::FORBIDDEN;
+            Err(error)
+        }
+    }
+}
+
+fn websocket_connection(stream: TcpStream) -> anyhow::Result<(Connection, IoThreads)> {
+    let socket =
+        tungstenite::accept_hdr(stream, check_origin).map_err(|e| anyhow::anyhow!("{e}"))?;
+    let (reader_sender, receiver) = crossbeam_channel::bounded::<Message>(0);
+    let (sender, writer_receiver) = crossbeam_channel::unbounded::<Message>();
+    let thread = thread::spawn(move || run_websocket(socket, reader_sender, writer_receiver));
+    Ok((
+        Connection { sender, receiver },
+        IoThreads {
+            writer: thread,
+            reader: None,
+            shutdown: None,
+        },
+    ))
+}
+
+// Each client gets its own thread and its own documents, the models and vector indexes they
+// share stay loaded until the last client using them disconnects
+fn serve_clients<S: Send + 'static>(
+    clients: impl Iterator<Item = io::Result<S>>,
+    serve: fn(Connection) -> anyhow::Result<()>,
+    connect: fn(S) -> anyhow::Result<(Connection, IoThreads)>,
+) -> anyhow::Result<()> {
+    let active = Arc::new(AtomicUsize::new(0));
+    for (client, stream) in clients.enumerate() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("error accepting a connection: {e}");
+                continue;
+            }
+        };
+        let active = active.clone();
+        info!(
+            "client {client} connected, {} connected clients",
+            active.fetch_add(1, Ordering::Relaxed) + 1
+        );
+        thread::spawn(move || {
+            match connect(stream) {
+                Ok((connection, io_threads)) => {
+                    if let Err(e) = serve(connection) {
+                        error!("error serving client {client}: {e:?}");
+                    }
+                    if let Err(e) = io_threads.join() {
+                        error!("error in the connection to client {client}: {e}");
+                    }
+                }
+                Err(e) => error!("error connecting to client {client}: {e}"),
+            }
+            info!(
+                "client {client} disconnected, {} connected clients",
+                active.fetch_sub(1, Ordering::Relaxed) - 1
+            );
+        });
+    }
+    Ok(())
+}
+
+fn bind_tcp(address: &str) -> anyhow::Result<TcpListener> {
+    let listener =
+        TcpListener::bind(address).with_context(|| format!("error listening on {address}"))?;
+    info!("listening on {}", listener.local_addr()?);
+    Ok(listener)
+}
+
+fn bind_socket(path: &str) -> anyhow::Result<interprocess::local_socket::Listener> {
+    let listener = ListenerOptions::new()
+        .name(path.to_fs_name::<GenericFilePath>()?)
+        // Replaces the socket file a server that did not exit cleanly left behind
+        .try_overwrite(true)
+        .create_sync()
+        .with_context(|| format!("error listening on {path}"))?;
+    info!("listening on {path}");
+    Ok(listener)
+}
+
+pub fn run(
+    transport: Transport,
+    serve: fn(Connection) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    match transport {
+        Transport::Stdio => {
+            let (connection, io_threads) = Connection::stdio();
+            serve(connection)?;
+            io_threads.join()?;
+            Ok(())
+        }
+        Transport::Tcp(address) => serve_clients(bind_tcp(&address)?.incoming(), serve, |stream| {
+            Ok(tcp_connection(stream)?)
+        }),
+        Transport::WebSocket(address) => {
+            let listener = bind_tcp(&address)?;
+            // Clients are not authenticated, only the processes of this machine may connect
+            anyhow::ensure!(
+                listener.local_addr()?.ip().is_loopback(),
+                "the WebSocket transport only listens on loopback addresses like 127.0.0.1:<port>, not {address}"
+            );
+            serve_clients(listener.incoming(), serve, websocket_connection)
+        }
+        Transport::Socket(path) => serve_clients(bind_socket(&path)?.incoming(), serve, |stream| {
+            let (reader, writer) = stream.split();
+            Ok(stream_connection(reader, writer, None))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_server::Notification;
+    use std::io::BufRead;
+    use tungstenite::{client::IntoClientRequest, http::HeaderValue};
+
+    #[test]
+    fn tcp_transport() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let (connection, io_threads) = tcp_connection(listener.accept()?.0)?;
+
+        Message::Request(lsp_server::Request::new(
+            1.into(),
+            "shutdown".to_string(),
+            (),
+        ))
+        .write(&mut client)?;
+        let Message::Request(request) = connection.receiver.recv()? else {
+            panic!("expected a request");
+        };
+        assert_eq!(request.method, "shutdown");
+
+        connection
+            .sender
+            .send(Message::Notification(Notification::new(
+                "window/logMessage".to_string(),
+                (),
+            )))?;
+        let mut client_reader = BufReader::new(client.try_clone()?);
+        let Some(Message::Notification(notification)) = Message::read(&mut client_reader)? else {
+            panic!("expected a notification");
+        };
+        assert_eq!(notification.method, "window/logMessage");
+
+        // The reader stops after exit and the writer once the server drops the connection
+        Message::Notification(Notification::new("exit".to_string(), ())).write(&mut client)?;
+        assert!(is_exit(&connection.receiver.recv()?));
+        drop(connection);
+        io_threads.join()?;
+        assert!(client_reader.fill_buf()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn websocket_refuses_other_origins() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let connect = |origin: &'static str| {
+            thread::spawn(move || -> anyhow::Result<bool> {
+                let mut request = format!("ws://{address}").into_client_request()?;
+                request
+                    .headers_mut()
+                    .insert("Origin", HeaderValue::from_static(origin));
+                Ok(tungstenite::client(request, TcpStream::connect(address)?).is_ok())
+            })
+        };
+
+        let client = connect("https://example.com");
+        assert!(websocket_connection(listener.accept()?.0).is_err());
+        assert!(!join_thread(client)?);
+
+        let client = connect("http://localhost:3000");
+        let (connection, io_threads) = websocket_connection(listener.accept()?.0)?;
+        assert!(join_thread(client)?);
+        // The client is gone without closing the socket, which ends the thread with an error
+        drop(connection);
+        let _ = io_threads.join();
+        Ok(())
+    }
+}
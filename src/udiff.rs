@@ -0,0 +1,217 @@
+use lsp_types::{Position, Range, TextEdit};
+use serde_json::Value;
+
+use crate::code_actions::get_end_position;
+
+const DIFF_INSTRUCTIONS: &str = "Answer only with a unified diff of the document, with `@@` \
+hunk headers, ` ` before the lines that stay, `-` before the removed lines and `+` before the \
+added lines. Include a few unchanged lines around every change so it can be found.";
+
+// The lines a hunk replaces and the lines it replaces them with
+#[derive(Debug, Default, PartialEq)]
+struct Hunk {
+    // The first line of the hunk in the `@@` header, one based. Models often get it wrong so it
+    // only picks between matches.
+    old_start: Option<usize>,
+    old_lines: Vec<String>,
+    new_lines: Vec<String>,
+}
+
+// Adds the instructions to the system message
+pub fn insert_diff_instructions(params: &mut Value) {
+    // Anthropic takes the system prompt in `system`
+    if let Some(Value::String(system)) = params.get_mut("system") {
+        system.push_str("\n\n");
+        system.push_str(DIFF_INSTRUCTIONS);
+        return;
+    }
+    let Some(messages) = params.get_mut("messages").and_then(Value::as_array_mut) else {
+        return;
+    };
+    match messages.first_mut() {
+        Some(message) if message.get("role").and_then(Value::as_str) == Some("system") => {
+            if let Some(Value::String(content)) = message.get_mut("content") {
+                content.push_str("\n\n");
+                content.push_str(DIFF_INSTRUCTIONS);
+            }
+        }
+        _ => messages.insert(
+            0,
+            serde_json::json!({"role": "system", "content": DIFF_INSTRUCTIONS}),
+        ),
+    }
+}
+
+// `@@ -12,4 +12,5 @@` to 12
+fn parse_old_start(header: &str) -> Option<usize> {
+    let old = header.strip_prefix("@@")?.trim_start().strip_prefix('-')?;
+    let end = old.find([',', ' ']).unwrap_or(old.len());
+    old[..end].parse().ok()
+}
+
+// The hunks of the diff. The file headers, code fences and anything else before the first hunk
+// are skipped.
+fn parse_diff(diff: &str) -> anyhow::Result<Vec<Hunk>> {
+    let mut hunks: Vec<Hunk> = vec![];
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            hunks.push(Hunk {
+                old_start: parse_old_start(line),
+                ..Default::default()
+            });
+            continue;
+        }
+        let Some(hunk) = hunks.last_mut() else {
+            continue;
+        };
+        if line.starts_with("```") || line.starts_with("--- ") || line.starts_with("+++ ") {
+            continue;
+        }
+        match line.chars().next() {
+            Some('+') => hunk.new_lines.push(line[1..].to_owned()),
+            Some('-') => hunk.old_lines.push(line[1..].to_owned()),
+            Some(' ') => {
+                hunk.old_lines.push(line[1..].to_owned());
+                hunk.new_lines.push(line[1..].to_owned());
+            }
+            // `\ No newline at end of file`
+            Some('\\') => (),
+            // Models often drop the space of empty context lines
+            None => {
+                hunk.old_lines.push(String::new());
+                hunk.new_lines.push(String::new());
+            }
+            Some(_) => (),
+        }
+    }
+    if hunks.is_empty() {
+        anyhow::bail!("the response has no diff hunks");
+    }
+    Ok(hunks)
+}
+
+// Where the lines of the hunk are in the document. Exact matches are preferred over ones that
+// only match without the whitespace and the match closest to the header over the others.
+fn find_hunk(lines: &[&str], hunk: &Hunk, from: usize) -> Option<usize> {
+    let old_lines = &hunk.old_lines;
+    if old_lines.len() > lines.len().saturating_sub(from) {
+        return None;
+    }
+    let hint = hunk.old_start.map_or(from, |start| start.saturating_sub(1));
+    let matches_at = |start: usize, same: &dyn Fn(&str, &str) -> bool| {
+        old_lines
+            .iter()
+            .zip(&lines[start..])
+            .all(|(old, line)| same(old, line))
+    };
+    let exact = |old: &str, line: &str| old.trim_end() == line.trim_end();
+    let fuzzy = |old: &str, line: &str| old.trim() == line.trim();
+    let candidates = from..=lines.len() - old_lines.len();
+    for same in [&exact as &dyn Fn(&str, &str) -> bool, &fuzzy] {
+        let closest = candidates
+            .clone()
+            .filter(|&start| matches_at(start, same))
+            .min_by_key(|&start| start.abs_diff(hint));
+        if closest.is_some() {
+            return closest;
+        }
+    }
+    None
+}
+
+// The edits that apply the diff the model answered with to the document
+pub fn diff_to_edits(document: &str, diff: &str) -> anyhow::Result<Vec<TextEdit>> {
+    let lines: Vec<&str> = document.lines().collect();
+    let mut edits = vec![];
+    // Hunks are in the order of the document and can not overlap
+    let mut from = 0;
+    for (i, hunk) in parse_diff(diff)?.iter().enumerate() {
+        let start = if hunk.old_lines.is_empty() {
+            hunk.old_start
+                .map_or(lines.len(), |start| start.min(lines.len()))
+        } else {
+            find_hunk(&lines, hunk, from)
+                .ok_or_else(|| anyhow::anyhow!("hunk {} does not match the document", i + 1))?
+        };
+        // Only the lines between the context lines change
+        let common_start = hunk
+            .old_lines
+            .iter()
+            .zip(&hunk.new_lines)
+            .take_while(|(old, new)| old == new)
+            .count();
+        let common_end = hunk.old_lines[common_start..]
+            .iter()
+            .rev()
+            .zip(hunk.new_lines[common_start..].iter().rev())
+            .take_while(|(old, new)| old == new)
+            .count();
+        let end = start + hunk.old_lines.len() - common_end;
+        let start = start + common_start;
+        from = end;
+        let new_lines = &hunk.new_lines[common_start..hunk.new_lines.len() - common_end];
+        if start == end && new_lines.is_empty() {
+            continue;
+        }
+        let mut new_text: String = new_lines.iter().map(|line| format!("{line}\n")).collect();
+        let end = if end < lines.len() {
+            Position::new(end as u32, 0)
+        } else {
+            // The last line of the document may not end with a newline
+            if !document.is_empty() && !document.ends_with('\n') && !new_text.is_empty() {
+                new_text.pop();
+                if start >= lines.len() {
+                    new_text.insert(0, '\n');
+                }
+            }
+            get_end_position(document)
+        };
+        let start = if start >= lines.len() {
+            get_end_position(document)
+        } else {
+            Position::new(start as u32, 0)
+        };
+        edits.push(TextEdit::new(Range::new(start, end), new_text));
+    }
+    Ok(edits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_diffs_to_edits() -> anyhow::Result<()> {
+        let document = "def add(a, b):\n    return a + b\n\n\ndef sub(a, b):\n    return a - b\n";
+        // The header is wrong and the context lost its indentation
+        let diff = "```diff\n--- a/math.py\n+++ b/math.py\n@@ -1,3 +1,3 @@\n def sub(a, b):\n-return a - b\n+    return b - a\n```";
+        assert_eq!(
+            diff_to_edits(document, diff)?,
+            vec![TextEdit::new(
+                Range::new(Position::new(5, 0), Position::new(6, 0)),
+                "    return b - a\n".to_string()
+            )]
+        );
+        let diff = "@@ -1,2 +1,3 @@\n def add(a, b):\n+    \"\"\"Adds\"\"\"\n     return a + b\n@@ -5,2 +6,1 @@\n-def sub(a, b):\n-    return a - b\n";
+        assert_eq!(
+            diff_to_edits(document, diff)?,
+            vec![
+                TextEdit::new(
+                    Range::new(Position::new(1, 0), Position::new(1, 0)),
+                    "    \"\"\"Adds\"\"\"\n".to_string()
+                ),
+                TextEdit::new(
+                    Range::new(Position::new(4, 0), Position::new(6, 0)),
+                    String::new()
+                ),
+            ]
+        );
+        assert!(diff_to_edits(document, "@@ -1 +1 @@\n-missing\n+line").is_err());
+        assert!(diff_to_edits(document, "no diff").is_err());
+
+        let mut params = serde_json::json!({"messages": [{"role": "user", "content": "{CODE}"}]});
+        insert_diff_instructions(&mut params);
+        assert_eq!(params["messages"][0]["role"], "system");
+        Ok(())
+    }
+}
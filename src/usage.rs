@@ -0,0 +1,603 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, instrument};
+
+use crate::{
+    config::{Budget, Config, Pricing},
+    custom_requests::usage::{Acceptance, UsageReport, UsageResult, UsageTotals},
+    memory_backends::{Prompt, PromptType},
+    metrics,
+    tokenizer::SharedTokenizer,
+    tools::ToolRequest,
+    transformer_backends::{RenderedPrompt, TransformerBackend},
+    transformer_worker::{
+        DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse,
+        DoToolGenerationResponse,
+    },
+};
+
+// The days of usage kept on disk
+const MAX_DAYS: usize = 366;
+// Usage is written to disk at most this often and whenever it is logged
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+static USAGE: Lazy<UsageTracker> =
+    Lazy::new(|| UsageTracker::new(if cfg!(test) { None } else { get_path() }));
+
+// Token counts as the APIs report them, the aliases cover the names Anthropic and Gemini use
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct TokenUsage {
+    #[serde(default, alias = "input_tokens", alias = "promptTokenCount")]
+    pub prompt_tokens: u64,
+    #[serde(default, alias = "output_tokens", alias = "candidatesTokenCount")]
+    pub completion_tokens: u64,
+}
+
+impl TokenUsage {
+    pub fn new(prompt_tokens: u64, completion_tokens: u64) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    // Roughly four characters per token for backends that do not report usage
+    fn estimate(prompt_chars: usize, completion: &str) -> Self {
+        let tokens = |chars: usize| (chars as u64).div_ceil(4);
+        Self::new(tokens(prompt_chars), tokens(completion.chars().count()))
+    }
+}
+
+fn get_path() -> Option<PathBuf> {
+    Some(
+        directories::ProjectDirs::from("", "", "lsp-ai")?
+            .data_dir()
+            .join("usage.json"),
+    )
+}
+
+// The characters of the prompt and of the messages in the parameters
+fn get_prompt_chars(prompt: &Prompt, params: &Value) -> usize {
+    let count = |text: &str| text.chars().count();
+    let prompt_chars = match prompt {
+        Prompt::ContextAndCode(prompt) => count(&prompt.context) + count(&prompt.code),
+        Prompt::FIM(prompt) => count(&prompt.prompt) + count(&prompt.suffix),
+    };
+    let messages_chars: usize = params
+        .get("messages")
+        .and_then(Value::as_array)
+        .map(|messages| {
+            messages
+                .iter()
+                .filter_map(|message| message.get("content")?.as_str())
+                .map(count)
+                .sum()
+        })
+        .unwrap_or_default();
+    prompt_chars + messages_chars
+}
+
+// The UTC date formatted as YYYY-MM-DD, using Howard Hinnant's civil_from_days
+fn get_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400;
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn add_usage(totals: &mut UsageTotals, usage: TokenUsage, pricing: Option<Pricing>) {
+    totals.requests += 1;
+    totals.prompt_tokens += usage.prompt_tokens;
+    totals.completion_tokens += usage.completion_tokens;
+    if let Some(pricing) = pricing {
+        totals.cost += (usage.prompt_tokens as f64 * pricing.prompt_per_million_tokens
+            + usage.completion_tokens as f64 * pricing.completion_per_million_tokens)
+            / 1_000_000.;
+    }
+}
+
+fn add_acceptance(acceptance: &mut Acceptance, accepted: u64, rejected: u64) {
+    acceptance.accepted += accepted;
+    acceptance.rejected += rejected;
+    let feedback = acceptance.accepted + acceptance.rejected;
+    acceptance.rate = (feedback > 0).then(|| acceptance.accepted as f64 / feedback as f64);
+}
+
+fn add_feedback(totals: &mut UsageTotals, language_id: Option<&str>, accepted: bool) {
+    let (accepted, rejected) = if accepted { (1, 0) } else { (0, 1) };
+    add_acceptance(&mut totals.acceptance, accepted, rejected);
+    if let Some(language_id) = language_id {
+        add_acceptance(
+            totals.languages.entry(language_id.to_owned()).or_default(),
+            accepted,
+            rejected,
+        );
+    }
+}
+
+fn get_report(models: &HashMap<String, UsageTotals>) -> UsageReport {
+    let mut total = UsageTotals::default();
+    for totals in models.values() {
+        total.requests += totals.requests;
+        total.prompt_tokens += totals.prompt_tokens;
+        total.completion_tokens += totals.completion_tokens;
+        total.cost += totals.cost;
+        let acceptance = totals.acceptance;
+        add_acceptance(
+            &mut total.acceptance,
+            acceptance.accepted,
+            acceptance.rejected,
+        );
+        for (language_id, acceptance) in &totals.languages {
+            add_acceptance(
+                total.languages.entry(language_id.clone()).or_default(),
+                acceptance.accepted,
+                acceptance.rejected,
+            );
+        }
+    }
+    UsageReport {
+        total,
+        models: models.clone(),
+    }
+}
+
+struct Totals {
+    session: HashMap<String, UsageTotals>,
+    // Keyed by date and then by model key
+    days: BTreeMap<String, HashMap<String, UsageTotals>>,
+    // The requests of the last minute per model with a rate limit
+    recent_requests: HashMap<String, VecDeque<Instant>>,
+    last_saved: Instant,
+    unsaved: bool,
+}
+
+// Usage per model for the session and per day. The days are persisted so they survive restarts,
+// or only kept in memory when there is no data directory.
+struct UsageTracker {
+    path: Option<PathBuf>,
+    totals: Mutex<Totals>,
+}
+
+impl UsageTracker {
+    fn new(path: Option<PathBuf>) -> Self {
+        let days = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|contents| match serde_json::from_slice(&contents) {
+                Ok(days) => Some(days),
+                Err(e) => {
+                    error!("error loading the usage: {e}");
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            path,
+            totals: Mutex::new(Totals {
+                session: HashMap::new(),
+                days,
+                recent_requests: HashMap::new(),
+                last_saved: Instant::now(),
+                unsaved: false,
+            }),
+        }
+    }
+
+    fn record(&self, model: &str, usage: TokenUsage, pricing: Option<Pricing>) {
+        let mut totals = self.totals.lock();
+        let date = get_date(SystemTime::now());
+        add_usage(
+            totals.session.entry(model.to_owned()).or_default(),
+            usage,
+            pricing,
+        );
+        add_usage(
+            totals
+                .days
+                .entry(date)
+                .or_default()
+                .entry(model.to_owned())
+                .or_default(),
+            usage,
+            pricing,
+        );
+        while totals.days.len() > MAX_DAYS {
+            totals.days.pop_first();
+        }
+        totals.unsaved = true;
+        if totals.last_saved.elapsed() >= SAVE_INTERVAL {
+            self.save(&mut totals);
+        }
+    }
+
+    // Feedback counts towards the day it is given, not the day of the completion
+    fn record_feedback(&self, model: &str, language_id: Option<&str>, accepted: bool) {
+        let mut totals = self.totals.lock();
+        let date = get_date(SystemTime::now());
+        add_feedback(
+            totals.session.entry(model.to_owned()).or_default(),
+            language_id,
+            accepted,
+        );
+        add_feedback(
+            totals
+                .days
+                .entry(date)
+                .or_default()
+                .entry(model.to_owned())
+                .or_default(),
+            language_id,
+            accepted,
+        );
+        totals.unsaved = true;
+    }
+
+    // Counts the request against the rate limit when it is within the budget
+    fn check_budget(&self, model: &str, budget: &Budget) -> anyhow::Result<()> {
+        let mut totals = self.totals.lock();
+        if let Some(cap) = budget.daily_spend_cap {
+            let spent = totals
+                .days
+                .get(&get_date(SystemTime::now()))
+                .and_then(|models| models.get(model))
+                .map_or(0., |totals| totals.cost);
+            anyhow::ensure!(
+                spent < cap,
+                "model {model} reached its daily spend cap of {cap}, {spent:.4} was spent today"
+            );
+        }
+        if let Some(max) = budget.max_requests_per_minute {
+            let requests = totals.recent_requests.entry(model.to_owned()).or_default();
+            while requests
+                .front()
+                .is_some_and(|time| time.elapsed() >= Duration::from_secs(60))
+            {
+                requests.pop_front();
+            }
+            anyhow::ensure!(
+                requests.len() < max,
+                "model {model} reached its limit of {max} requests per minute"
+            );
+            requests.push_back(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn save(&self, totals: &mut Totals) {
+        totals.last_saved = Instant::now();
+        let Some(path) = &self.path else {
+            return;
+        };
+        if !totals.unsaved {
+            return;
+        }
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(path, serde_json::to_vec(&totals.days)?));
+        match result {
+            Ok(()) => totals.unsaved = false,
+            Err(e) => error!("error saving the usage to {}: {e}", path.display()),
+        }
+    }
+
+    fn report(&self) -> UsageResult {
+        let totals = self.totals.lock();
+        UsageResult {
+            session: get_report(&totals.session),
+            days: totals
+                .days
+                .iter()
+                .map(|(date, models)| (date.clone(), get_report(models)))
+                .collect(),
+        }
+    }
+
+    fn get_today(&self) -> UsageReport {
+        let totals = self.totals.lock();
+        totals
+            .days
+            .get(&get_date(SystemTime::now()))
+            .map(get_report)
+            .unwrap_or_default()
+    }
+}
+
+pub fn get_usage() -> UsageResult {
+    USAGE.report()
+}
+
+// Whether the user accepted or rejected a completion of the model
+pub fn record_feedback(model: &str, language_id: Option<&str>, accepted: bool) {
+    USAGE.record_feedback(model, language_id, accepted);
+}
+
+pub fn log_usage() {
+    let today = USAGE.get_today().total;
+    info!(
+        "usage today: {} requests, {} prompt tokens, {} completion tokens, cost {:.4}",
+        today.requests, today.prompt_tokens, today.completion_tokens, today.cost
+    );
+    save();
+}
+
+// Writes the days recorded since the last save
+pub fn save() {
+    USAGE.save(&mut USAGE.totals.lock());
+}
+
+// Records the usage of the requests made through the backend under its model key and fails
+// requests over its budget before they are sent
+pub struct TrackedBackend {
+    backend: Arc<Box<dyn TransformerBackend + Send + Sync>>,
+    model: String,
+    pricing: Option<Pricing>,
+    budget: Option<Budget>,
+}
+
+impl TrackedBackend {
+    pub fn new(
+        backend: Arc<Box<dyn TransformerBackend + Send + Sync>>,
+        model: &str,
+        config: &Config,
+    ) -> Self {
+        Self {
+            backend,
+            model: model.to_owned(),
+            pricing: config.get_pricing(model),
+            budget: config.get_budget(model),
+        }
+    }
+
+    fn check_budget(&self) -> anyhow::Result<()> {
+        match &self.budget {
+            Some(budget) => USAGE.check_budget(&self.model, budget),
+            None => Ok(()),
+        }
+    }
+
+    fn record(&self, usage: Option<TokenUsage>, prompt_chars: usize, completion: &str) {
+        let usage = usage.unwrap_or_else(|| TokenUsage::estimate(prompt_chars, completion));
+        USAGE.record(&self.model, usage, self.pricing);
+        metrics::record_tokens(&self.model, usage);
+    }
+}
+
+#[async_trait::async_trait]
+impl TransformerBackend for TrackedBackend {
+    #[instrument(name = "inference", skip_all, fields(model = %self.model))]
+    async fn do_completion(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoCompletionResponse> {
+        let prompt_chars = get_prompt_chars(prompt, &params);
+        self.check_budget()?;
+        let start = Instant::now();
+        let response = self.backend.do_completion(prompt, params, cancel).await;
+        metrics::record_request(&self.model, "completion", start.elapsed(), response.is_ok());
+        let response = response?;
+        let completion: String = response
+            .candidates
+            .iter()
+            .map(|candidate| candidate.insert_text.as_str())
+            .collect();
+        self.record(response.usage, prompt_chars, &completion);
+        Ok(response)
+    }
+
+    #[instrument(name = "inference", skip_all, fields(model = %self.model))]
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let prompt_chars = get_prompt_chars(prompt, &params);
+        self.check_budget()?;
+        let start = Instant::now();
+        let response = self.backend.do_generate(prompt, params, cancel).await;
+        metrics::record_request(&self.model, "generation", start.elapsed(), response.is_ok());
+        let response = response?;
+        self.record(response.usage, prompt_chars, &response.generated_text);
+        Ok(response)
+    }
+
+    #[instrument(name = "inference", skip_all, fields(model = %self.model))]
+    async fn do_generate_stream(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<String>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        let prompt_chars = get_prompt_chars(prompt, &params);
+        self.check_budget()?;
+        let start = Instant::now();
+        let response = self
+            .backend
+            .do_generate_stream(prompt, params, tx, cancel)
+            .await;
+        metrics::record_request(
+            &self.model,
+            "generation_stream",
+            start.elapsed(),
+            response.is_ok(),
+        );
+        let response = response?;
+        self.record(response.usage, prompt_chars, &response.generated_text);
+        Ok(response)
+    }
+
+    #[instrument(name = "inference", skip_all, fields(model = %self.model))]
+    async fn do_generate_with_tools(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        tools: &ToolRequest<'_>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DoToolGenerationResponse> {
+        let prompt_chars = get_prompt_chars(prompt, &params);
+        self.check_budget()?;
+        let start = Instant::now();
+        let response = self
+            .backend
+            .do_generate_with_tools(prompt, params, tools, cancel)
+            .await;
+        metrics::record_request(&self.model, "generation", start.elapsed(), response.is_ok());
+        let response = response?;
+        self.record(response.usage, prompt_chars, &response.generated_text);
+        Ok(response)
+    }
+
+    fn is_local(&self) -> bool {
+        self.backend.is_local()
+    }
+
+    fn caches_prompt_prefixes(&self) -> bool {
+        self.backend.caches_prompt_prefixes()
+    }
+
+    fn get_prompt_type(&self, params: &Value) -> anyhow::Result<PromptType> {
+        self.backend.get_prompt_type(params)
+    }
+
+    fn get_tokenizer(&self) -> SharedTokenizer {
+        self.backend.get_tokenizer()
+    }
+
+    fn render_prompt(&self, prompt: &Prompt, params: &Value) -> anyhow::Result<RenderedPrompt> {
+        self.backend.render_prompt(prompt, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_backends::FIMPrompt;
+    use serde_json::json;
+
+    #[test]
+    fn dates() {
+        assert_eq!(get_date(UNIX_EPOCH), "1970-01-01");
+        let date = |days: u64| get_date(UNIX_EPOCH + Duration::from_secs(days * 86400 + 3600));
+        assert_eq!(date(19723), "2024-01-01");
+        assert_eq!(date(19782), "2024-02-29");
+        assert_eq!(date(20375), "2025-10-14");
+    }
+
+    #[test]
+    fn usage_tracker() {
+        let tracker = UsageTracker::new(None);
+        let pricing = Pricing {
+            prompt_per_million_tokens: 2.,
+            completion_per_million_tokens: 10.,
+        };
+        tracker.record("model1", TokenUsage::new(1000, 100), Some(pricing));
+        tracker.record("model1", TokenUsage::new(500, 50), Some(pricing));
+        tracker.record("model2", TokenUsage::new(10, 1), None);
+
+        let report = tracker.report();
+        let model1 = &report.session.models["model1"];
+        assert_eq!(model1.requests, 2);
+        assert_eq!(model1.prompt_tokens, 1500);
+        assert_eq!(model1.completion_tokens, 150);
+        assert!((model1.cost - 0.0045).abs() < 1e-12);
+        assert_eq!(report.session.total.requests, 3);
+        assert_eq!(report.session.total.prompt_tokens, 1510);
+        assert_eq!(report.days.len(), 1);
+        assert_eq!(tracker.get_today().total.completion_tokens, 151);
+    }
+
+    #[test]
+    fn completion_feedback() {
+        let tracker = UsageTracker::new(None);
+        tracker.record_feedback("model1", Some("rust"), true);
+        tracker.record_feedback("model1", Some("rust"), false);
+        tracker.record_feedback("model1", Some("python"), true);
+        tracker.record_feedback("model2", None, false);
+
+        let report = tracker.report();
+        let model1 = &report.session.models["model1"];
+        assert_eq!(model1.acceptance.accepted, 2);
+        assert_eq!(model1.acceptance.rejected, 1);
+        assert_eq!(model1.languages["rust"].rate, Some(0.5));
+        assert_eq!(model1.languages["python"].rate, Some(1.));
+        assert_eq!(report.session.total.acceptance.rate, Some(0.5));
+        assert_eq!(report.session.total.languages.len(), 2);
+        assert_eq!(report.days.len(), 1);
+        assert_eq!(Acceptance::default().rate, None);
+    }
+
+    #[test]
+    fn budgets() {
+        let tracker = UsageTracker::new(None);
+        let budget = Budget {
+            max_requests_per_minute: Some(2),
+            daily_spend_cap: None,
+        };
+        assert!(tracker.check_budget("model1", &budget).is_ok());
+        assert!(tracker.check_budget("model1", &budget).is_ok());
+        assert!(tracker.check_budget("model1", &budget).is_err());
+        assert!(tracker.check_budget("model2", &budget).is_ok());
+
+        let budget = Budget {
+            max_requests_per_minute: None,
+            daily_spend_cap: Some(0.01),
+        };
+        let pricing = Pricing {
+            prompt_per_million_tokens: 10.,
+            completion_per_million_tokens: 10.,
+        };
+        assert!(tracker.check_budget("model3", &budget).is_ok());
+        tracker.record("model3", TokenUsage::new(900, 0), Some(pricing));
+        assert!(tracker.check_budget("model3", &budget).is_ok());
+        tracker.record("model3", TokenUsage::new(1000, 0), Some(pricing));
+        assert!(tracker.check_budget("model3", &budget).is_err());
+    }
+
+    #[test]
+    fn token_usage() -> anyhow::Result<()> {
+        let open_ai: TokenUsage = serde_json::from_value(
+            json!({ "prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12 }),
+        )?;
+        let anthropic: TokenUsage =
+            serde_json::from_value(json!({ "input_tokens": 10, "output_tokens": 2 }))?;
+        let gemini: TokenUsage = serde_json::from_value(
+            json!({ "promptTokenCount": 10, "candidatesTokenCount": 2, "totalTokenCount": 12 }),
+        )?;
+        assert_eq!(open_ai, TokenUsage::new(10, 2));
+        assert_eq!(anthropic, open_ai);
+        assert_eq!(gemini, open_ai);
+
+        let prompt = Prompt::FIM(FIMPrompt::new("def ".to_string(), "".to_string()));
+        let params = json!({ "messages": [{ "role": "user", "content": "Hi!!" }] });
+        assert_eq!(get_prompt_chars(&prompt, &params), 8);
+        assert_eq!(TokenUsage::estimate(8, "hello"), TokenUsage::new(2, 2));
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use lsp_server::ResponseError;
+use lsp_types::Position;
 
 use crate::{config::ChatMessage, memory_backends::ContextAndCodePrompt};
 
@@ -40,3 +41,139 @@ pub fn format_chat_messages(
 pub fn format_context_code(context: &str, code: &str) -> String {
     format!("{context}\n\n{code}")
 }
+
+// The byte offset of a position counted in characters, positions past the end of their line
+// are at its end
+pub fn position_to_offset(text: &str, position: Position) -> usize {
+    let line_start = match position.line {
+        0 => 0,
+        line => text
+            .match_indices('\n')
+            .nth(line as usize - 1)
+            .map_or(text.len(), |(i, _)| i + 1),
+    };
+    let line = &text[line_start..];
+    let line_end = line.find('\n').unwrap_or(line.len());
+    line_start
+        + line[..line_end]
+            .char_indices()
+            .nth(position.character as usize)
+            .map_or(line_end, |(i, _)| i)
+}
+
+// Returns the byte index of the earliest stop sequence found in `text`
+pub fn find_stop_sequence(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| text.find(s.as_str()))
+        .min()
+}
+
+pub fn truncate_at_stop_sequence(mut text: String, stop: &[String]) -> String {
+    if let Some(index) = find_stop_sequence(&text, stop) {
+        text.truncate(index);
+    }
+    text
+}
+
+// Filters streamed text so that nothing at or after a stop sequence is emitted. Text that could
+// be the start of a stop sequence is held back until we know it is not.
+pub struct StopSequenceFilter {
+    stop: Vec<String>,
+    buffer: String,
+    emitted: usize,
+    stopped: bool,
+}
+
+impl StopSequenceFilter {
+    pub fn new(stop: Vec<String>) -> Self {
+        Self {
+            stop: stop.into_iter().filter(|s| !s.is_empty()).collect(),
+            buffer: String::new(),
+            emitted: 0,
+            stopped: false,
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // Pushes a chunk and returns the text that is safe to emit
+    pub fn push(&mut self, chunk: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.buffer.push_str(chunk);
+        if let Some(index) = find_stop_sequence(&self.buffer, &self.stop) {
+            self.stopped = true;
+            self.buffer.truncate(index.max(self.emitted));
+            return self.take_pending(self.buffer.len());
+        }
+        let safe_len = self.buffer.len() - self.partial_stop_sequence_len();
+        self.take_pending(safe_len.max(self.emitted))
+    }
+
+    // Returns whatever is still being held back
+    pub fn finish(&mut self) -> String {
+        self.take_pending(self.buffer.len())
+    }
+
+    // The full filtered text
+    pub fn text(&self) -> &str {
+        &self.buffer
+    }
+
+    fn take_pending(&mut self, end: usize) -> String {
+        let pending = self.buffer[self.emitted..end].to_owned();
+        self.emitted = end;
+        pending
+    }
+
+    // The length of the longest suffix of the buffer that is a prefix of a stop sequence
+    fn partial_stop_sequence_len(&self) -> usize {
+        let unemitted = &self.buffer[self.emitted..];
+        unemitted
+            .char_indices()
+            .map(|(i, _)| &unemitted[i..])
+            .find(|suffix| self.stop.iter().any(|s| s.starts_with(suffix)))
+            .map(|suffix| suffix.len())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_at_stop_sequence() {
+        let stop = vec!["\n\n".to_string(), "<|end|>".to_string()];
+        assert_eq!(
+            truncate_at_stop_sequence("abc<|end|>def\n\n".to_string(), &stop),
+            "abc"
+        );
+        assert_eq!(truncate_at_stop_sequence("abc".to_string(), &stop), "abc");
+        assert_eq!(truncate_at_stop_sequence("abc".to_string(), &[]), "abc");
+    }
+
+    #[test]
+    fn test_stop_sequence_filter() {
+        let mut filter = StopSequenceFilter::new(vec!["<|end|>".to_string()]);
+        assert_eq!(filter.push("hello "), "hello ");
+        assert_eq!(filter.push("world<|"), "world");
+        assert_eq!(filter.push("en"), "");
+        assert_eq!(filter.push("d|> ignored"), "");
+        assert!(filter.is_stopped());
+        assert_eq!(filter.push("more"), "");
+        assert_eq!(filter.finish(), "");
+        assert_eq!(filter.text(), "hello world");
+
+        let mut filter = StopSequenceFilter::new(vec!["<|end|>".to_string()]);
+        assert_eq!(filter.push("a <"), "a ");
+        assert_eq!(filter.push("b"), "<b");
+        assert_eq!(filter.push("<|e"), "");
+        assert_eq!(filter.finish(), "<|e");
+        assert!(!filter.is_stopped());
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use lsp_types::Url;
+use serde_json::Value;
+use tracing::warn;
+
+// Checked in order at the workspace root
+const FILE_NAMES: [&str; 2] = [".lsp-ai.toml", ".lsp-ai.json"];
+
+// What a workspace file may set unless the user sets `trust_workspace_config`. The other options
+// can run commands, read secrets, send the code elsewhere or lift limits, e.g. `models`,
+// `mcp_servers`, `plugins`, `redaction` and `usage`, and a cloned repository should not get to pick
+// them.
+const SAFE_KEYS: [&str; 8] = [
+    "completion",
+    "routes",
+    "fallback",
+    "actions",
+    "test_conventions",
+    "chat",
+    "commit_message",
+    "request_timeouts",
+];
+
+// The options naming a file the prompt is read from, see prompt_files
+const PROMPT_FILE_KEYS: [&str; 2] = ["content_file", "chat_template_file"];
+
+fn get_root(root_uri: &str) -> Option<PathBuf> {
+    Url::parse(root_uri).ok()?.to_file_path().ok()
+}
+
+pub fn find(root_uri: &str) -> Option<PathBuf> {
+    let root = get_root(root_uri)?;
+    FILE_NAMES
+        .iter()
+        .map(|name| root.join(name))
+        .find(|path| path.is_file())
+}
+
+pub fn load(path: &Path) -> anyhow::Result<Value> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("error reading {}", path.display()))?;
+    let value = if path
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        serde_json::to_value(toml::from_str::<toml::Value>(&contents)?)?
+    } else {
+        serde_json::from_str(&contents)?
+    };
+    anyhow::ensure!(
+        value.is_object(),
+        "{} must contain a table of options",
+        path.display()
+    );
+    Ok(value)
+}
+
+// Only the options of the client can make lsp-ai trust the workspace file
+pub fn is_trusted(user_options: &Value) -> bool {
+    user_options
+        .get("trust_workspace_config")
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+// Prompt files of an untrusted workspace file must be in the workspace, they are replaced with
+// their full path so they are not looked up elsewhere and the others are dropped
+fn confine_prompt_files(path: &Path, root: Option<&Path>, value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for key in PROMPT_FILE_KEYS {
+                let Some(file) = object.get(key).and_then(Value::as_str).map(str::to_owned) else {
+                    continue;
+                };
+                let confined = root.and_then(|root| {
+                    root.join(&file)
+                        .canonicalize()
+                        .ok()
+                        .filter(|file| file.starts_with(root))
+                });
+                match confined {
+                    Some(file) => {
+                        object.insert(key.to_owned(), Value::String(file.display().to_string()));
+                    }
+                    None => {
+                        warn!(
+                            "ignoring `{key}` {file} in {}, it is not a file in the workspace",
+                            path.display()
+                        );
+                        object.remove(key);
+                    }
+                }
+            }
+            for value in object.values_mut() {
+                confine_prompt_files(path, root, value);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                confine_prompt_files(path, root, value);
+            }
+        }
+        _ => (),
+    }
+}
+
+// Drops the options an untrusted workspace file may not set
+pub fn filter(path: &Path, mut options: Value, trusted: bool) -> Value {
+    if trusted {
+        return options;
+    }
+    if let Value::Object(options) = &mut options {
+        options.retain(|key, _| {
+            let safe = SAFE_KEYS.contains(&key.as_str());
+            if !safe {
+                warn!(
+                    "ignoring `{key}` in {}, set `trust_workspace_config` to allow it",
+                    path.display()
+                );
+            }
+            safe
+        });
+    }
+    // Without the workspace root every prompt file is dropped
+    let root = path.parent().and_then(|root| root.canonicalize().ok());
+    confine_prompt_files(path, root.as_deref(), &mut options);
+    options
+}
+
+// Objects are merged key by key, anything else in `overrides` replaces the value in `base`
+pub fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+fn get_modified(root_uri: &str) -> Option<(PathBuf, SystemTime)> {
+    let path = find(root_uri)?;
+    let modified = std::fs::metadata(&path).ok()?.modified().ok()?;
+    Some((path, modified))
+}
+
+// Stops the thread watching the workspace config file when dropped
+pub struct Watcher(Arc<AtomicBool>);
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+// Polls the workspace root so creating, editing and deleting the file are all noticed
+pub fn watch(root_uri: &str, tx: crossbeam_channel::Sender<()>) -> Watcher {
+    let root_uri = root_uri.to_owned();
+    let stopped = Arc::new(AtomicBool::new(false));
+    let thread_stopped = stopped.clone();
+    thread::spawn(move || {
+        let mut last = get_modified(&root_uri);
+        while !thread_stopped.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_secs(1));
+            let current = get_modified(&root_uri);
+            if current != last {
+                last = current;
+                if tx.send(()).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Watcher(stopped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merges_workspace_config() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("lsp-ai-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&root)?;
+        let root_uri = Url::from_file_path(&root).unwrap().to_string();
+        assert!(find(&root_uri).is_none());
+
+        std::fs::write(
+            root.join(".lsp-ai.toml"),
+            "[completion]\nmodel = \"project\"\n\n[completion.parameters]\nmax_tokens = 32\n",
+        )?;
+        let path = find(&root_uri).unwrap();
+        let mut options = json!({
+            "completion": {
+                "model": "user",
+                "parameters": { "max_tokens": 64, "temperature": 0.2 }
+            },
+            "models": {}
+        });
+        merge(&mut options, load(&path)?);
+        assert_eq!(
+            options,
+            json!({
+                "completion": {
+                    "model": "project",
+                    "parameters": { "max_tokens": 32, "temperature": 0.2 }
+                },
+                "models": {}
+            })
+        );
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn filters_untrusted_workspace_config() {
+        let options = json!({
+            "completion": { "model": "project" },
+            "models": { "project": { "type": "command", "command": "curl" } },
+            "trust_workspace_config": true
+        });
+        assert_eq!(
+            filter(Path::new(".lsp-ai.json"), options.clone(), false),
+            json!({ "completion": { "model": "project" } })
+        );
+        assert_eq!(
+            filter(Path::new(".lsp-ai.json"), options.clone(), true),
+            options
+        );
+        assert!(is_trusted(&json!({ "trust_workspace_config": true })));
+        assert!(!is_trusted(&Value::Null));
+    }
+
+    #[test]
+    fn confines_untrusted_prompt_files() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("lsp-ai-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(root.join("prompts"))?;
+        std::fs::write(root.join("prompts/system.md"), "You are a coding assistant")?;
+        let options = json!({
+            "chat": {
+                "parameters": {
+                    "messages": [
+                        { "role": "system", "content_file": "prompts/system.md" },
+                        { "role": "user", "content_file": "/etc/passwd" },
+                        { "role": "user", "content_file": "../secret.md" }
+                    ]
+                }
+            },
+            "usage": { "daily_spend_cap": 1000 }
+        });
+        let filtered = filter(&root.join(".lsp-ai.json"), options, false);
+        let system = root.canonicalize()?.join("prompts/system.md");
+        assert_eq!(
+            filtered,
+            json!({
+                "chat": {
+                    "parameters": {
+                        "messages": [
+                            { "role": "system", "content_file": system.display().to_string() },
+                            { "role": "user" },
+                            { "role": "user" }
+                        ]
+                    }
+                }
+            })
+        );
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
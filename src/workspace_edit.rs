@@ -0,0 +1,192 @@
+use lsp_types::{
+    CreateFile, CreateFileOptions, DocumentChangeOperation, DocumentChanges, OneOf,
+    OptionalVersionedTextDocumentIdentifier, Position, Range, ResourceOp, TextDocumentEdit,
+    TextEdit, Url, WorkspaceEdit,
+};
+
+use crate::code_actions::get_end_position;
+
+// The lines around the changed ones in the diff
+const CONTEXT_LINES: usize = 3;
+
+// A file with all of the edits proposed for it
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileChange {
+    pub uri: Url,
+    // The path shown in the diff, relative to its workspace folder
+    pub path: String,
+    // None when the edits create the file
+    pub original: Option<String>,
+    pub edited: String,
+}
+
+impl FileChange {
+    // The counts of the leading and trailing lines the edits left as they were
+    fn get_common_lines(&self) -> (usize, usize) {
+        let before = self.original_lines();
+        let after: Vec<&str> = self.edited.split_inclusive('\n').collect();
+        let common_start = before
+            .iter()
+            .zip(&after)
+            .take_while(|(b, a)| b == a)
+            .count();
+        let common_end = before[common_start..]
+            .iter()
+            .rev()
+            .zip(after[common_start..].iter().rev())
+            .take_while(|(b, a)| b == a)
+            .count();
+        (common_start, common_end)
+    }
+
+    fn original_lines(&self) -> Vec<&str> {
+        self.original
+            .as_deref()
+            .unwrap_or_default()
+            .split_inclusive('\n')
+            .collect()
+    }
+
+    // One edit replacing the lines that changed
+    fn to_text_edit(&self) -> TextEdit {
+        let original = self.original.as_deref().unwrap_or_default();
+        let before = self.original_lines();
+        let after: Vec<&str> = self.edited.split_inclusive('\n').collect();
+        let (common_start, common_end) = self.get_common_lines();
+        let end = if common_end == 0 {
+            get_end_position(original)
+        } else {
+            Position::new((before.len() - common_end) as u32, 0)
+        };
+        TextEdit::new(
+            Range::new(Position::new(common_start as u32, 0), end),
+            after[common_start..after.len() - common_end].concat(),
+        )
+    }
+
+    // A unified diff of the file with one hunk around the lines that changed
+    pub fn to_diff(&self) -> String {
+        let before = self.original_lines();
+        let after: Vec<&str> = self.edited.split_inclusive('\n').collect();
+        let (common_start, common_end) = self.get_common_lines();
+        let start = common_start.saturating_sub(CONTEXT_LINES);
+        let before_end = (before.len() - common_end + CONTEXT_LINES).min(before.len());
+        let after_end = (after.len() - common_end + CONTEXT_LINES).min(after.len());
+        // Empty ranges start at the line before them
+        let format_range = |lines: usize| match lines {
+            0 => format!("{start},0"),
+            lines => format!("{},{lines}", start + 1),
+        };
+        let mut diff = match self.original {
+            Some(_) => format!("--- a/{}\n", self.path),
+            None => "--- /dev/null\n".to_string(),
+        };
+        diff.push_str(&format!(
+            "+++ b/{}\n@@ -{} +{} @@\n",
+            self.path,
+            format_range(before_end - start),
+            format_range(after_end - start)
+        ));
+        let mut push_line = |prefix: char, line: &str| {
+            diff.push(prefix);
+            diff.push_str(line);
+            if !line.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        };
+        for line in &before[start..common_start] {
+            push_line(' ', line);
+        }
+        for line in &before[common_start..before.len() - common_end] {
+            push_line('-', line);
+        }
+        for line in &after[common_start..after.len() - common_end] {
+            push_line('+', line);
+        }
+        for line in &before[before.len() - common_end..before_end] {
+            push_line(' ', line);
+        }
+        diff
+    }
+}
+
+// Creates the new files and edits the others, clients apply it in one workspace/applyEdit
+pub fn build_workspace_edit(changes: &[FileChange]) -> WorkspaceEdit {
+    let mut operations = vec![];
+    for change in changes {
+        if change.original.is_none() {
+            operations.push(DocumentChangeOperation::Op(ResourceOp::Create(
+                CreateFile {
+                    uri: change.uri.clone(),
+                    options: Some(CreateFileOptions {
+                        overwrite: Some(false),
+                        ignore_if_exists: Some(true),
+                    }),
+                    annotation_id: None,
+                },
+            )));
+        }
+        operations.push(DocumentChangeOperation::Edit(TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier {
+                uri: change.uri.clone(),
+                version: None,
+            },
+            edits: vec![OneOf::Left(change.to_text_edit())],
+        }));
+    }
+    WorkspaceEdit {
+        document_changes: Some(DocumentChanges::Operations(operations)),
+        ..Default::default()
+    }
+}
+
+pub fn build_diff(changes: &[FileChange]) -> String {
+    changes.iter().map(FileChange::to_diff).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_edits_and_diffs_of_changes() -> anyhow::Result<()> {
+        let change = FileChange {
+            uri: Url::parse("file:///code/main.py")?,
+            path: "main.py".to_string(),
+            original: Some("a\nb\nc\nd\ne\nf\n".to_string()),
+            edited: "a\nb\nc\nD\ne\nf\n".to_string(),
+        };
+        assert_eq!(
+            change.to_text_edit(),
+            TextEdit::new(
+                Range::new(Position::new(3, 0), Position::new(4, 0)),
+                "D\n".to_string()
+            )
+        );
+        assert_eq!(
+            change.to_diff(),
+            "--- a/main.py\n+++ b/main.py\n@@ -1,6 +1,6 @@\n a\n b\n c\n-d\n+D\n e\n f\n"
+        );
+
+        let created = FileChange {
+            uri: Url::parse("file:///code/new.py")?,
+            path: "new.py".to_string(),
+            original: None,
+            edited: "x = 1".to_string(),
+        };
+        assert_eq!(
+            created.to_diff(),
+            "--- /dev/null\n+++ b/new.py\n@@ -0,0 +1,1 @@\n+x = 1\n\\ No newline at end of file\n"
+        );
+        let edit = build_workspace_edit(&[change, created]);
+        let Some(DocumentChanges::Operations(operations)) = edit.document_changes else {
+            anyhow::bail!("the edit has no operations");
+        };
+        assert_eq!(operations.len(), 3);
+        assert!(matches!(
+            operations[1],
+            DocumentChangeOperation::Op(ResourceOp::Create(_))
+        ));
+        Ok(())
+    }
+}